@@ -0,0 +1,67 @@
+/*
+ * C API of MusicGPT, available when building the library with the `ffi` feature:
+ *
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ */
+#ifndef MUSICGPT_H
+#define MUSICGPT_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MUSICGPT_JOB_UNKNOWN -1
+#define MUSICGPT_JOB_QUEUED 0
+#define MUSICGPT_JOB_RUNNING 1
+#define MUSICGPT_JOB_DONE 2
+#define MUSICGPT_JOB_FAILED 3
+#define MUSICGPT_JOB_CANCELLED 4
+
+typedef struct MusicGptProcessor MusicGptProcessor;
+
+/* Loads a model, downloading it first if needed. data_path can be NULL for using the
+ * same data directory as the CLI. Returns NULL on failure, see musicgpt_last_error. */
+MusicGptProcessor *musicgpt_processor_create(const char *model, const char *data_path, bool gpu);
+
+/* Cancels all the jobs and releases the processor. */
+void musicgpt_processor_free(MusicGptProcessor *processor);
+
+/* Queues the generation of secs seconds of audio. Returns the job id, or 0 on failure. */
+uint64_t musicgpt_job_submit(const MusicGptProcessor *processor, const char *prompt, uint32_t secs);
+
+/* One of the MUSICGPT_JOB_* statuses. */
+int32_t musicgpt_job_status(const MusicGptProcessor *processor, uint64_t job);
+
+/* Progress from 0 to 1, or a negative number for unknown jobs. */
+float musicgpt_job_progress(const MusicGptProcessor *processor, uint64_t job);
+
+/* Moves up to len of the mono samples generated so far into out, returning how many were
+ * written. Never blocks, so it can be called from an audio thread. The job waits for the
+ * host to fetch its audio once 30 seconds of it are pending. */
+size_t musicgpt_job_fetch_samples(const MusicGptProcessor *processor, uint64_t job, float *out, size_t len);
+
+/* Asks the job to stop, discarding the audio not fetched yet. Its status becomes
+ * MUSICGPT_JOB_CANCELLED once it stopped. */
+void musicgpt_job_cancel(const MusicGptProcessor *processor, uint64_t job);
+
+/* Why the job failed, or NULL. Valid until the job is freed. */
+const char *musicgpt_job_error(const MusicGptProcessor *processor, uint64_t job);
+
+/* Forgets about the job, cancelling it if it is still running. */
+void musicgpt_job_free(const MusicGptProcessor *processor, uint64_t job);
+
+/* Sample rate of the generated audio. */
+uint32_t musicgpt_sample_rate(void);
+
+/* Error of the last failing call in this thread, or NULL. */
+const char *musicgpt_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
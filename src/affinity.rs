@@ -0,0 +1,151 @@
+//! Placement of the inference and post-processing threads on specific cores. On servers
+//! with several sockets, threads that wander between NUMA nodes keep reading memory
+//! attached to the other socket, which slows segments down noticeably.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use tracing::warn;
+
+/// Logical cores, numbered like the OS numbers them, sorted and without repetitions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuSet(Vec<usize>);
+
+impl CpuSet {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The cores of a NUMA node, as listed by the kernel.
+    pub fn numa_node(node: usize) -> Result<Self, String> {
+        let path = format!("/sys/devices/system/node/node{node}/cpulist");
+        let list = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Could not read the cores of NUMA node {node}: {err}"))?;
+        list.trim().parse()
+    }
+
+    /// The value of the `session.intra_op_thread_affinities` option of ONNX Runtime for
+    /// `threads` threads, pinning each one to a core in turn. ONNX Runtime numbers cores
+    /// from 1, and the first thread is the one running the session, which is not pinned.
+    pub fn ort_affinities(&self, threads: usize) -> String {
+        (1..threads)
+            .map(|i| (self.0[i % self.0.len()] + 1).to_string())
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = String;
+
+    /// Parses lists like `0-3,8,10-11`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid list of cores {s:?}, expected something like 0-3,8");
+        let mut cpus = vec![];
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let start = start.trim().parse::<usize>().map_err(|_| invalid())?;
+            let end = end.trim().parse::<usize>().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            cpus.extend(start..=end);
+        }
+        if cpus.is_empty() {
+            return Err(invalid());
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self(cpus))
+    }
+}
+
+impl Display for CpuSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let cpus = self.0.iter().map(usize::to_string).collect::<Vec<_>>();
+        write!(f, "{}", cpus.join(","))
+    }
+}
+
+/// How the threads of each inference session are set up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionThreads {
+    /// Threads each session uses for running an operation. None lets ONNX Runtime decide,
+    /// or uses one per core in `cpus`.
+    pub intra: Option<usize>,
+    /// Cores the threads of each operation are pinned to, one per thread.
+    pub cpus: Option<CpuSet>,
+}
+
+impl SessionThreads {
+    pub fn intra_threads(&self) -> Option<usize> {
+        self.intra.or(self.cpus.as_ref().map(CpuSet::len))
+    }
+}
+
+/// Pins the calling thread to `cpu`.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> Result<(), String> {
+    // SAFETY: the set is a plain bitmask initialized before being passed by reference.
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        return Err(format!(
+            "Could not pin thread to core {cpu}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> Result<(), String> {
+    Err("Pinning threads to cores is only supported on Linux".to_string())
+}
+
+/// Runs the audio post-processing workers on `cpus`, one worker pinned to each core. Must
+/// be called before anything is post-processed.
+pub fn pin_dsp_threads(cpus: &CpuSet) -> Result<(), String> {
+    let cpus = cpus.0.clone();
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cpus.len())
+        .thread_name(|i| format!("dsp-{i}"))
+        .start_handler(move |i| {
+            if let Err(err) = pin_current_thread(cpus[i % cpus.len()]) {
+                warn!("{err}");
+            }
+        })
+        .build_global()
+        .map_err(|err| format!("Could not start the post-processing workers: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lists_of_cores() {
+        let set: CpuSet = "8-11, 0,2-3,2".parse().unwrap();
+        assert_eq!(set.0, vec![0, 2, 3, 8, 9, 10, 11]);
+        assert_eq!(set.to_string(), "0,2,3,8,9,10,11");
+        assert!("".parse::<CpuSet>().is_err());
+        assert!("3-1".parse::<CpuSet>().is_err());
+        assert!("a-b".parse::<CpuSet>().is_err());
+    }
+
+    #[test]
+    fn pins_each_onnx_runtime_thread_to_a_core() {
+        let set: CpuSet = "4-6".parse().unwrap();
+        assert_eq!(set.ort_affinities(3), "6;7");
+        assert_eq!(set.ort_affinities(5), "6;7;5;6");
+        assert_eq!(set.ort_affinities(1), "");
+        let threads = SessionThreads {
+            intra: None,
+            cpus: Some(set),
+        };
+        assert_eq!(threads.intra_threads(), Some(3));
+    }
+}
@@ -0,0 +1,143 @@
+//! AIFF encoding of mono audio, for toolchains that still prefer it to WAV.
+
+/// Text chunks of AIFF files, in the order they are written.
+pub const TEXT_CHUNKS: &[[u8; 4]] = &[*b"NAME", *b"AUTH", *b"(c) ", *b"ANNO"];
+
+/// Encodes mono audio as a 16 or 24 bit big-endian AIFF file, with text chunks like
+/// `NAME` or `(c) ` for the copyright before the samples.
+pub fn encode_aiff(
+    samples: &[f32],
+    sample_rate: u32,
+    bits: u16,
+    text: &[([u8; 4], String)],
+) -> Result<Vec<u8>, String> {
+    if bits != 16 && bits != 24 {
+        return Err(format!(
+            "AIFF files are encoded with 16 or 24 bits, not {bits}"
+        ));
+    }
+    let mut comm = vec![];
+    comm.extend(1u16.to_be_bytes());
+    comm.extend((samples.len() as u32).to_be_bytes());
+    comm.extend(bits.to_be_bytes());
+    comm.extend(extended(sample_rate));
+
+    // The offset and block size of the samples, which are not aligned to blocks.
+    let mut ssnd = vec![0; 8];
+    let max = ((1 << (bits - 1)) - 1) as f32;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * max).round() as i32;
+        ssnd.extend(&value.to_be_bytes()[4 - bits as usize / 8..]);
+    }
+
+    let mut form = b"AIFF".to_vec();
+    push_chunk(&mut form, b"COMM", &comm);
+    for (id, value) in text {
+        push_chunk(&mut form, id, value.as_bytes());
+    }
+    push_chunk(&mut form, b"SSND", &ssnd);
+    let mut aiff = b"FORM".to_vec();
+    aiff.extend((form.len() as u32).to_be_bytes());
+    aiff.extend(form);
+    Ok(aiff)
+}
+
+/// The text chunks carrying the INFO tags of a WAV file, like the ones of
+/// [crate::audio::wav::info_chunk]. Tags without an AIFF counterpart go in annotations.
+pub fn text_from_info(tags: &[([u8; 4], String)]) -> Vec<([u8; 4], String)> {
+    let mut text = tags
+        .iter()
+        .map(|(id, value)| {
+            let id = match id {
+                b"INAM" => *b"NAME",
+                b"IART" | b"ISFT" => *b"AUTH",
+                b"ICOP" => *b"(c) ",
+                _ => *b"ANNO",
+            };
+            (id, value.clone())
+        })
+        .collect::<Vec<_>>();
+    text.sort_by_key(|(id, _)| TEXT_CHUNKS.iter().position(|chunk| chunk == id));
+    text
+}
+
+/// Appends a chunk, like an `ID3 ` one with the cover art, to an encoded AIFF file.
+pub fn append_chunk(aiff: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    push_chunk(aiff, id, data);
+    let form_len = aiff.len() as u32 - 8;
+    aiff[4..8].copy_from_slice(&form_len.to_be_bytes());
+}
+
+/// Chunks are padded to an even length, without counting the padding in their size.
+fn push_chunk(form: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    form.extend(id);
+    form.extend((data.len() as u32).to_be_bytes());
+    form.extend(data);
+    if data.len() % 2 == 1 {
+        form.push(0);
+    }
+}
+
+/// The 80 bit extended precision float the sample rate is written as.
+fn extended(sample_rate: u32) -> [u8; 10] {
+    let mut bytes = [0; 10];
+    if sample_rate == 0 {
+        return bytes;
+    }
+    let exponent = 31 - sample_rate.leading_zeros();
+    let mantissa = (sample_rate as u64) << (63 - exponent);
+    bytes[..2].copy_from_slice(&(16383 + exponent as u16).to_be_bytes());
+    bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_big_endian_samples_after_the_text() -> Result<(), String> {
+        let text = vec![
+            (*b"NAME", "Lofi".to_string()),
+            (*b"ANNO", "odd".to_string()),
+        ];
+        let aiff = encode_aiff(&[0.5, -1.0, 2.0], 32000, 16, &text)?;
+        assert_eq!(&aiff[..4], b"FORM");
+        assert_eq!(
+            u32::from_be_bytes(aiff[4..8].try_into().unwrap()) as usize,
+            aiff.len() - 8
+        );
+        assert_eq!(&aiff[8..16], b"AIFFCOMM");
+        // Mono, 3 frames of 16 bits at 32kHz.
+        assert_eq!(
+            aiff[20..38],
+            [0, 1, 0, 0, 0, 3, 0, 16, 0x40, 0x0D, 0xFA, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(&aiff[38..50], b"NAME\0\0\0\x04Lofi");
+        // Odd chunks are padded.
+        assert_eq!(&aiff[50..62], b"ANNO\0\0\0\x03odd\0");
+        assert_eq!(&aiff[62..74], b"SSND\0\0\0\x0e\0\0\0\0");
+        assert_eq!(aiff[78..], [0x40, 0x00, 0x80, 0x01, 0x7F, 0xFF]);
+
+        let aiff = encode_aiff(&[0.5], 44100, 24, &[])?;
+        assert_eq!(aiff[28..30], [0x40, 0x0E]);
+        // Followed by the padding of the 11 bytes of the chunk.
+        assert_eq!(aiff[aiff.len() - 4..], [0x40, 0x00, 0x00, 0]);
+        assert!(encode_aiff(&[0.5], 44100, 32, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn maps_wav_info_tags_to_text_chunks() {
+        let tags = [
+            (*b"ICMT", "{}".to_string()),
+            (*b"ICOP", "CC-BY-4.0".to_string()),
+            (*b"ISFT", "MusicGPT".to_string()),
+        ];
+        let ids = text_from_info(&tags)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [*b"AUTH", *b"(c) ", *b"ANNO"]);
+    }
+}
@@ -3,10 +3,16 @@ use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{
     ChannelCount, SampleFormat, SampleRate, Stream, SupportedBufferSize, SupportedStreamConfig,
 };
-use std::collections::VecDeque;
+use std::path::Path;
 use std::time::Duration;
 
+use crate::audio::audio_sink::AudioSink;
+use crate::audio::ring_playback::{ring_playback, RingPlayer, UnderrunStrategy};
+use crate::audio::wav::encode_wav;
+
 const DEFAULT_SAMPLING_RATE: u32 = 32000;
+/// Seconds of audio read ahead of playback when playing a file.
+const READ_AHEAD_SECS: u32 = 2;
 
 pub struct AudioManager {
     host: cpal::Host,
@@ -38,85 +44,72 @@ unsafe impl Send for AudioStream {}
 unsafe impl Sync for AudioStream {}
 
 impl AudioManager {
-    pub fn play_from_queue(&self, mut v: VecDeque<f32>) -> anyhow::Result<AudioStream> {
-        let time = 1000 * v.len() / self.sampling_rate as usize;
-        let channels = self.n_channels;
+    pub fn play(&self, samples: Vec<f32>) -> anyhow::Result<AudioStream> {
+        let time = 1000 * samples.len() / self.sampling_rate as usize;
+        let (mut writer, player) = ring_playback(samples.len().max(1), UnderrunStrategy::Silence);
+        writer.push(&samples).map_err(|err| anyhow!(err))?;
+        Ok(AudioStream {
+            stream: self.build_stream(player)?,
+            duration: Duration::from_millis(time as u64),
+        })
+    }
+
+    /// Plays a WAV file at the sampling rate of the manager, reading it as it plays so
+    /// that only a couple of seconds of it are in memory.
+    pub fn play_file(&self, path: &Path) -> anyhow::Result<AudioStream> {
+        let reader = hound::WavReader::open(path)?;
+        let time = 1000 * reader.duration() as u64 / self.sampling_rate as u64;
+        let (mut writer, player) = ring_playback(
+            (READ_AHEAD_SECS * self.sampling_rate) as usize,
+            UnderrunStrategy::Silence,
+        );
+        let stream = self.build_stream(player)?;
+        // Stops once the file is read, or once the stream is dropped.
+        std::thread::spawn(move || {
+            let mut samples = reader.into_samples::<f32>();
+            loop {
+                let chunk = samples
+                    .by_ref()
+                    .take(4096)
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap_or_default();
+                if chunk.is_empty() || writer.push(&chunk).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(AudioStream {
+            stream,
+            duration: Duration::from_millis(time),
+        })
+    }
 
+    fn build_stream(&self, mut player: RingPlayer) -> anyhow::Result<Stream> {
         let config = SupportedStreamConfig::new(
-            ChannelCount::from(channels),
+            ChannelCount::from(self.n_channels),
             SampleRate(self.sampling_rate),
             SupportedBufferSize::Unknown,
             self.sample_format,
         );
-
         let device = match self.host.default_output_device() {
             None => return Err(anyhow!("No audio device")),
             Some(v) => v,
         };
+        // The audio is mono, like the stream.
         let stream = device.build_output_stream(
             &config.into(),
             move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                for frame in output.chunks_mut(channels as usize) {
-                    for sample in frame.iter_mut() {
-                        *sample = v.pop_front().unwrap_or_default()
-                    }
-                }
+                player.fill(output);
             },
             |_err| {},
             None,
         )?;
-
         stream.play()?;
-        Ok(AudioStream {
-            stream,
-            duration: Duration::from_millis(time as u64),
-        })
+        Ok(stream)
     }
 
-    pub fn to_wav(&self, v: VecDeque<f32>) -> hound::Result<Vec<u8>> {
-        let spec = hound::WavSpec {
-            channels: self.n_channels,
-            sample_rate: self.sampling_rate,
-            bits_per_sample: match self.sample_format {
-                SampleFormat::I8 => 8,
-                SampleFormat::I16 => 16,
-                SampleFormat::I32 => 32,
-                SampleFormat::I64 => 64,
-                SampleFormat::U8 => 8,
-                SampleFormat::U16 => 16,
-                SampleFormat::U32 => 32,
-                SampleFormat::U64 => 64,
-                SampleFormat::F32 => 32,
-                SampleFormat::F64 => 64,
-                unknown => panic!("unknown sample format {unknown}"),
-            },
-            sample_format: match self.sample_format {
-                SampleFormat::I8 => hound::SampleFormat::Int,
-                SampleFormat::I16 => hound::SampleFormat::Int,
-                SampleFormat::I32 => hound::SampleFormat::Int,
-                SampleFormat::I64 => hound::SampleFormat::Int,
-                SampleFormat::U8 => hound::SampleFormat::Int,
-                SampleFormat::U16 => hound::SampleFormat::Int,
-                SampleFormat::U32 => hound::SampleFormat::Int,
-                SampleFormat::U64 => hound::SampleFormat::Int,
-                SampleFormat::F32 => hound::SampleFormat::Float,
-                SampleFormat::F64 => hound::SampleFormat::Float,
-                unknown => panic!("unknown sample format {unknown}"),
-            },
-        };
-
-        let mut buffer = vec![];
-        let cursor = std::io::Cursor::new(&mut buffer);
-        let in_memory_file = std::io::BufWriter::new(cursor);
-        {
-            let mut writer = hound::WavWriter::new(in_memory_file, spec)?;
-            for sample in v {
-                writer.write_sample(sample)?;
-            }
-            // <- we need writer to be dropped here.
-        }
-
-        Ok(buffer)
+    pub fn to_wav(&self, samples: &[f32]) -> hound::Result<Vec<u8>> {
+        encode_wav(samples.iter().copied(), self.sampling_rate)
     }
 }
 
@@ -129,11 +122,11 @@ mod tests {
         let wav_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav");
         let audio_manager = AudioManager::default();
         let reader = hound::WavReader::open(wav_path)?;
-        let mut data = VecDeque::new();
+        let mut data = vec![];
         for sample in reader.into_samples::<f32>() {
-            data.push_back(sample?)
+            data.push(sample?)
         }
-        let buff = audio_manager.to_wav(data)?;
+        let buff = audio_manager.to_wav(&data)?;
         let wav_path_content = std::fs::read(wav_path)?;
         assert_eq!(wav_path_content, buff);
         Ok(())
@@ -0,0 +1,395 @@
+//! Destinations for audio that is produced incrementally, so that consumers can start
+//! using it before the whole piece has been generated.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+use tracing::debug_span;
+
+use crate::audio::extended_generation::{AuditionVerdict, SegmentRetry};
+use crate::audio::gain_staging::SegmentGain;
+use crate::audio::markers::SegmentMarker;
+#[cfg(unix)]
+use crate::audio::mmap_wav::MmapWavSink;
+
+/// Receives audio samples in order as they become final.
+pub trait AudioSink: Send {
+    /// Appends a chunk of samples that will not change anymore.
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String>;
+
+    /// Makes the samples pushed so far available to the underlying consumer. Extended
+    /// generation flushes after each segment, and stops there if this fails.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Signals that no more samples will be pushed. Pushing after this is an error.
+    fn finalize(&mut self) -> Result<(), String>;
+
+    /// Notifies that a segment failed and is being generated again. The samples pushed
+    /// so far remain valid. By default, this is ignored.
+    fn retried(&mut self, _retry: &SegmentRetry) {}
+
+    /// Notifies the gain a segment got when normalized. By default, this is ignored.
+    fn normalized(&mut self, _gain: &SegmentGain) {}
+
+    /// Hands over the audio of a completed segment as the model generated it, before it
+    /// was processed and stitched, so that the piece can be stitched again with some of
+    /// its segments replaced. By default, this is ignored.
+    fn generated(&mut self, _segment: usize, _audio: &[f32]) {}
+
+    /// Notifies where a completed segment begins in the piece. By default, this is
+    /// ignored.
+    fn marked(&mut self, _marker: &SegmentMarker) {}
+
+    /// Whether extended generation holds back each completed segment until
+    /// [AudioSink::audition] accepts it. By default, segments are not auditioned.
+    fn auditions(&self) -> bool {
+        false
+    }
+
+    /// Asks whether a completed segment is kept, with its audio as it will be stitched,
+    /// from the beginning of its crossfade. Rejected segments are generated again. By
+    /// default, every segment is accepted.
+    fn audition(&mut self, _segment: usize, _audio: &[f32]) -> Result<AuditionVerdict, String> {
+        Ok(AuditionVerdict::Accept)
+    }
+}
+
+impl<S: AudioSink + ?Sized> AudioSink for Box<S> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        (**self).push(chunk)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        (**self).flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        (**self).finalize()
+    }
+
+    fn retried(&mut self, retry: &SegmentRetry) {
+        (**self).retried(retry)
+    }
+
+    fn normalized(&mut self, gain: &SegmentGain) {
+        (**self).normalized(gain)
+    }
+
+    fn generated(&mut self, segment: usize, audio: &[f32]) {
+        (**self).generated(segment, audio)
+    }
+
+    fn marked(&mut self, marker: &SegmentMarker) {
+        (**self).marked(marker)
+    }
+
+    fn auditions(&self) -> bool {
+        (**self).auditions()
+    }
+
+    fn audition(&mut self, segment: usize, audio: &[f32]) -> Result<AuditionVerdict, String> {
+        (**self).audition(segment, audio)
+    }
+}
+
+/// Keeps all the samples in memory.
+#[derive(Default)]
+pub struct MemorySink {
+    samples: Vec<f32>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<f32> {
+        self.samples
+    }
+}
+
+impl AudioSink for MemorySink {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.samples.extend(chunk);
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Writes mono 32 bit float samples to a WAV file.
+pub struct WavFileSink {
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+}
+
+impl WavFileSink {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<Self, String> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let writer = hound::WavWriter::create(path, spec)
+            .map_err(|err| format!("Could not create {path:?}: {err}"))?;
+        Ok(Self {
+            writer: Some(writer),
+        })
+    }
+
+    fn writer(&mut self) -> Result<&mut hound::WavWriter<BufWriter<File>>, String> {
+        self.writer
+            .as_mut()
+            .ok_or_else(|| "WAV file was already finalized".to_string())
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let _span = debug_span!("export").entered();
+        let writer = self.writer()?;
+        for sample in chunk {
+            writer
+                .write_sample(*sample)
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        // Also updates the WAV header, so the file is playable up to this point.
+        self.writer()?.flush().map_err(|err| err.to_string())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        let _span = debug_span!("export").entered();
+        let Some(writer) = self.writer.take() else {
+            return Err("WAV file was already finalized".to_string());
+        };
+        writer.finalize().map_err(|err| err.to_string())
+    }
+}
+
+/// Writes a WAV file of about `expected_samples` samples in place through a memory
+/// mapping where supported, or with a [WavFileSink] otherwise.
+#[cfg(unix)]
+pub fn wav_file_sink(
+    path: &Path,
+    sample_rate: u32,
+    expected_samples: usize,
+) -> Result<Box<dyn AudioSink>, String> {
+    Ok(Box::new(MmapWavSink::create(
+        path,
+        sample_rate,
+        expected_samples,
+    )?))
+}
+
+#[cfg(not(unix))]
+pub fn wav_file_sink(
+    path: &Path,
+    sample_rate: u32,
+    _expected_samples: usize,
+) -> Result<Box<dyn AudioSink>, String> {
+    Ok(Box::new(WavFileSink::create(path, sample_rate)?))
+}
+
+/// Pushes the same audio into two sinks.
+pub struct TeeSink<A, B>(pub A, pub B);
+
+impl<A: AudioSink, B: AudioSink> AudioSink for TeeSink<A, B> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.0.push(chunk)?;
+        self.1.push(chunk)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.0.finalize()?;
+        self.1.finalize()
+    }
+
+    fn retried(&mut self, retry: &SegmentRetry) {
+        self.0.retried(retry);
+        self.1.retried(retry);
+    }
+
+    fn normalized(&mut self, gain: &SegmentGain) {
+        self.0.normalized(gain);
+        self.1.normalized(gain);
+    }
+
+    fn generated(&mut self, segment: usize, audio: &[f32]) {
+        self.0.generated(segment, audio);
+        self.1.generated(segment, audio);
+    }
+
+    fn marked(&mut self, marker: &SegmentMarker) {
+        self.0.marked(marker);
+        self.1.marked(marker);
+    }
+
+    fn auditions(&self) -> bool {
+        self.0.auditions() || self.1.auditions()
+    }
+
+    /// Only the first of the sinks that audition segments is asked.
+    fn audition(&mut self, segment: usize, audio: &[f32]) -> Result<AuditionVerdict, String> {
+        match self.0.auditions() {
+            true => self.0.audition(segment, audio),
+            false => self.1.audition(segment, audio),
+        }
+    }
+}
+
+/// Pushes the first `len` samples into the inner sink and drops the rest, for cutting
+/// audio generated in whole seconds to the sample.
+pub struct TrimSink<'a> {
+    inner: &'a mut dyn AudioSink,
+    remaining: usize,
+}
+
+impl<'a> TrimSink<'a> {
+    pub fn new(inner: &'a mut dyn AudioSink, len: usize) -> Self {
+        Self {
+            inner,
+            remaining: len,
+        }
+    }
+}
+
+impl AudioSink for TrimSink<'_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let kept = chunk.len().min(self.remaining);
+        self.remaining -= kept;
+        match kept {
+            0 => Ok(()),
+            _ => self.inner.push(&chunk[..kept]),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.inner.finalize()
+    }
+
+    fn retried(&mut self, retry: &SegmentRetry) {
+        self.inner.retried(retry)
+    }
+
+    fn normalized(&mut self, gain: &SegmentGain) {
+        self.inner.normalized(gain)
+    }
+
+    fn generated(&mut self, segment: usize, audio: &[f32]) {
+        self.inner.generated(segment, audio)
+    }
+
+    fn marked(&mut self, marker: &SegmentMarker) {
+        self.inner.marked(marker)
+    }
+
+    fn auditions(&self) -> bool {
+        self.inner.auditions()
+    }
+
+    fn audition(&mut self, segment: usize, audio: &[f32]) -> Result<AuditionVerdict, String> {
+        self.inner.audition(segment, audio)
+    }
+}
+
+/// Sends each chunk through a channel. The channel is closed once the sink is finalized,
+/// so receivers can iterate until the audio is complete.
+pub struct ChannelSink {
+    tx: Option<Sender<Vec<f32>>>,
+}
+
+impl ChannelSink {
+    pub fn new(tx: Sender<Vec<f32>>) -> Self {
+        Self { tx: Some(tx) }
+    }
+}
+
+impl AudioSink for ChannelSink {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let Some(tx) = &self.tx else {
+            return Err("Channel sink was already finalized".to_string());
+        };
+        tx.send(chunk.to_vec())
+            .map_err(|_| "Audio receiver was dropped".to_string())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.tx = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    #[test]
+    fn wav_file_sink_writes_a_readable_file() -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("musicgpt-tests/{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+        let path = dir.join("out.wav");
+
+        let mut sink = WavFileSink::create(&path, 1000)?;
+        sink.push(&[0.1, 0.2])?;
+        sink.flush()?;
+        sink.push(&[0.3])?;
+        sink.finalize()?;
+        assert!(sink.push(&[0.4]).is_err());
+
+        let reader = hound::WavReader::open(&path).map_err(|err| err.to_string())?;
+        assert_eq!(reader.spec().sample_rate, 1000);
+        let samples = reader
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        assert_eq!(samples, vec![0.1, 0.2, 0.3]);
+        Ok(())
+    }
+
+    #[test]
+    fn trim_sink_drops_the_samples_past_its_length() -> Result<(), String> {
+        let mut memory = MemorySink::new();
+        let mut sink = TrimSink::new(&mut memory, 3);
+        sink.push(&[0.1, 0.2])?;
+        sink.push(&[0.3, 0.4])?;
+        sink.push(&[0.5])?;
+        sink.finalize()?;
+        assert_eq!(memory.into_inner(), vec![0.1, 0.2, 0.3]);
+        Ok(())
+    }
+
+    #[test]
+    fn channel_sink_closes_on_finalize() -> Result<(), String> {
+        let (tx, rx) = channel();
+        let mut sink = ChannelSink::new(tx);
+        sink.push(&[1.0, 2.0])?;
+        sink.push(&[3.0])?;
+        sink.finalize()?;
+        assert_eq!(
+            rx.iter().collect::<Vec<_>>(),
+            vec![vec![1.0, 2.0], vec![3.0]]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! DAW projects with the segments of a long piece on separate tracks, each placed where it
+//! was stitched and faded over its neighbours, so producers can fine-tune the joins. They
+//! are written as Reaper projects, which other DAWs can import too.
+
+/// A WAV file placed on the timeline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clip {
+    pub name: String,
+    /// Path of the WAV file, relative to the project.
+    pub file: String,
+    /// Where the clip starts in the timeline, in samples.
+    pub position: usize,
+    /// Samples of the file that are played, from its beginning.
+    pub length: usize,
+    pub fade_in: usize,
+    pub fade_out: usize,
+    pub gain_db: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    pub name: String,
+    pub muted: bool,
+    pub clips: Vec<Clip>,
+}
+
+/// A Reaper project, an `.rpp` file, with the tracks in order.
+pub fn reaper_project(tracks: &[Track], sample_rate: usize) -> String {
+    let secs = |samples: usize| samples as f64 / sample_rate as f64;
+    let quote = |text: &str| text.replace('"', "'");
+    let mut project = format!("<REAPER_PROJECT 0.1 \"6.0\" 0\n  SAMPLERATE {sample_rate} 0 0\n");
+    for track in tracks {
+        project += &format!(
+            "  <TRACK\n    NAME \"{}\"\n    MUTESOLO {} 0 0\n",
+            quote(&track.name),
+            u8::from(track.muted)
+        );
+        for clip in &track.clips {
+            // Fades are linear, with the length in seconds as the second value.
+            project += &format!(
+                "    <ITEM\n      POSITION {:.6}\n      LENGTH {:.6}\n      \
+                 FADEIN 0 {:.6} 0 0 0 0 0\n      FADEOUT 0 {:.6} 0 0 0 0 0\n      \
+                 VOLPAN {:.6} 0 1 -1\n      NAME \"{}\"\n      \
+                 <SOURCE WAVE\n        FILE \"{}\"\n      >\n    >\n",
+                secs(clip.position),
+                secs(clip.length),
+                secs(clip.fade_in),
+                secs(clip.fade_out),
+                10f32.powf(clip.gain_db / 20.0),
+                quote(&clip.name),
+                quote(&clip.file),
+            );
+        }
+        project += "  >\n";
+    }
+    project += ">\n";
+    project
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_reaper_projects() {
+        let project = reaper_project(
+            &[Track {
+                name: "Segment \"2\"".to_string(),
+                muted: false,
+                clips: vec![Clip {
+                    name: "lofi".to_string(),
+                    file: "song.segment-1.wav".to_string(),
+                    position: 52000,
+                    length: 64000,
+                    fade_in: 4000,
+                    fade_out: 0,
+                    gain_db: -6.0,
+                }],
+            }],
+            32000,
+        );
+        assert_eq!(
+            project,
+            "<REAPER_PROJECT 0.1 \"6.0\" 0\n  SAMPLERATE 32000 0 0\n  \
+             <TRACK\n    NAME \"Segment '2'\"\n    MUTESOLO 0 0 0\n    \
+             <ITEM\n      POSITION 1.625000\n      LENGTH 2.000000\n      \
+             FADEIN 0 0.125000 0 0 0 0 0\n      FADEOUT 0 0.000000 0 0 0 0 0\n      \
+             VOLPAN 0.501187 0 1 -1\n      NAME \"lofi\"\n      \
+             <SOURCE WAVE\n        FILE \"song.segment-1.wav\"\n      >\n    >\n  >\n>\n"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Spectral subtraction of the steady buzz that the audio codec leaves in generated
+//! audio. The level of the noise is tracked per frequency as the floor under the music,
+//! and subtracted from short overlapping frames of the audio as it streams.
+
+use std::f32::consts::PI;
+
+use rayon::prelude::*;
+use tracing::debug_span;
+
+use crate::audio::audio_sink::AudioSink;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DenoiseConfig {
+    /// From 0, which leaves the audio untouched, to 1, which subtracts all the noise.
+    pub strength: f32,
+}
+
+impl Default for DenoiseConfig {
+    fn default() -> Self {
+        Self { strength: 0.5 }
+    }
+}
+
+/// Length of the analysed frames, in seconds, rounded up to a power of two samples.
+const FRAME_SECS: f32 = 0.032;
+/// Decibels per second the noise estimate rises while the music is louder than it, so
+/// that it follows a floor that gets louder but not held notes. It drops right away to
+/// a quieter floor.
+const NOISE_RISE_DB: f32 = 3.0;
+/// Seconds over which the subtraction fades in, while the noise estimate settles.
+const WARMUP_SECS: f32 = 1.0;
+/// The estimated noise is subtracted this many times, as the floor under the music is
+/// lower than the average level of the noise.
+const OVERSUBTRACTION: f32 = 2.0;
+/// Lowest gain of a frequency, so that fully subtracting it does not leave holes that
+/// sound like chirps.
+const MIN_GAIN: f32 = 0.1;
+
+/// Denoises a stream, which comes out delayed by one frame. The noise estimate is kept
+/// when restarting the stream, so the segments of a render share it.
+pub struct Denoiser {
+    strength: f32,
+    frame: usize,
+    window: Vec<f32>,
+    /// Audio not processed yet, starting half a frame before the next output.
+    input: Vec<f32>,
+    /// Overlap-added output of the processed frames, starting at the next output.
+    output: Vec<f32>,
+    /// Estimated magnitude of the noise per frequency, None until the first frame.
+    noise: Option<Vec<f32>>,
+    gains: Vec<f32>,
+    rise: f32,
+    frames: usize,
+    warmup_frames: usize,
+    /// Samples at the beginning of the output that precede the stream.
+    lead_in: usize,
+    received: usize,
+    emitted: usize,
+}
+
+impl Denoiser {
+    pub fn new(config: &DenoiseConfig, sample_rate: usize) -> Self {
+        let frame = ((FRAME_SECS * sample_rate as f32) as usize)
+            .next_power_of_two()
+            .max(4);
+        let hop = frame / 2;
+        // Square root of a periodic Hann window, which applied before and after the
+        // processing adds up to unity at half frame overlaps.
+        let window = (0..frame)
+            .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f32 / frame as f32).cos()).sqrt())
+            .collect();
+        Self {
+            strength: config.strength.clamp(0.0, 1.0),
+            frame,
+            window,
+            input: vec![0.0; hop],
+            output: vec![0.0; frame],
+            noise: None,
+            gains: vec![1.0; hop + 1],
+            rise: 10f32.powf(NOISE_RISE_DB * hop as f32 / sample_rate as f32 / 20.0),
+            frames: 0,
+            warmup_frames: (WARMUP_SECS * sample_rate as f32) as usize / hop,
+            lead_in: hop,
+            received: 0,
+            emitted: 0,
+        }
+    }
+
+    /// Starts denoising a new stream, keeping the noise estimate of the previous one.
+    /// Audio of the previous stream that was not returned by [Denoiser::finish] is lost.
+    pub fn restart(&mut self) {
+        let hop = self.frame / 2;
+        self.input = vec![0.0; hop];
+        self.output = vec![0.0; self.frame];
+        self.lead_in = hop;
+        self.received = 0;
+        self.emitted = 0;
+    }
+
+    /// Feeds the next chunk of the stream, returning the audio that got denoised.
+    pub fn process(&mut self, chunk: &[f32]) -> Vec<f32> {
+        self.received += chunk.len();
+        self.input.extend_from_slice(chunk);
+        let mut out = vec![];
+        self.process_frames(&mut out);
+        self.emitted += out.len();
+        out
+    }
+
+    /// Returns the rest of the denoised stream.
+    pub fn finish(&mut self) -> Vec<f32> {
+        let mut out = vec![];
+        while self.emitted + out.len() < self.received {
+            self.input.resize(self.frame.max(self.input.len()), 0.0);
+            self.process_frames(&mut out);
+        }
+        out.truncate(self.received - self.emitted);
+        self.emitted = self.received;
+        out
+    }
+
+    /// Denoises all the frames that the input covers. Only the noise tracking depends on
+    /// the previous frames, so the transforms of the frames run in parallel.
+    fn process_frames(&mut self, out: &mut Vec<f32>) {
+        if self.input.len() < self.frame {
+            return;
+        }
+        let (frame, hop) = (self.frame, self.frame / 2);
+        let count = (self.input.len() - frame) / hop + 1;
+        let (input, window) = (&self.input, &self.window);
+        let mut spectra = (0..count)
+            .into_par_iter()
+            .map(|i| {
+                let mut re = input[i * hop..i * hop + frame]
+                    .iter()
+                    .zip(window)
+                    .map(|(x, w)| x * w)
+                    .collect::<Vec<_>>();
+                let mut im = vec![0.0; frame];
+                fft(&mut re, &mut im, false);
+                (re, im)
+            })
+            .collect::<Vec<_>>();
+        for (re, im) in &mut spectra {
+            self.subtract_noise(re, im);
+        }
+        spectra
+            .par_iter_mut()
+            .for_each(|(re, im)| fft(re, im, true));
+
+        for (re, _) in spectra {
+            for ((output, x), w) in self.output.iter_mut().zip(&re).zip(&self.window) {
+                *output += x * w;
+            }
+            let ready = self.output.drain(..hop).skip(self.lead_in);
+            out.extend(ready);
+            self.lead_in = self.lead_in.saturating_sub(hop);
+            self.output.resize(frame, 0.0);
+        }
+        self.input.drain(..count * hop);
+    }
+
+    /// Applies the gains of the next frame to its spectrum, updating the noise estimate.
+    fn subtract_noise(&mut self, re: &mut [f32], im: &mut [f32]) {
+        let hop = self.frame / 2;
+        let noise = self.noise.get_or_insert_with(|| {
+            (0..=hop)
+                .map(|k| (re[k] * re[k] + im[k] * im[k]).sqrt())
+                .collect()
+        });
+        self.frames += 1;
+        let strength = self.strength * (self.frames as f32 / self.warmup_frames as f32).min(1.0);
+        for k in 0..=hop {
+            let magnitude = (re[k] * re[k] + im[k] * im[k]).sqrt();
+            let noise = &mut noise[k];
+            *noise = (*noise * self.rise).min(magnitude);
+            let subtracted = match magnitude {
+                0.0 => 1.0,
+                _ => (1.0 - OVERSUBTRACTION * *noise / magnitude).max(MIN_GAIN),
+            };
+            // Gains drop halfway to their new value each frame, smoothing the ones that
+            // flicker, but rise right away so that onsets are kept.
+            let gain = subtracted.max((subtracted + self.gains[k]) / 2.0);
+            self.gains[k] = gain;
+            let gain = 1.0 - strength * (1.0 - gain);
+            re[k] *= gain;
+            im[k] *= gain;
+            // The negative frequencies mirror the positive ones.
+            if k > 0 && k < hop {
+                re[self.frame - k] *= gain;
+                im[self.frame - k] *= gain;
+            }
+        }
+    }
+}
+
+/// Denoises the audio pushed into it before passing it to another sink.
+pub struct DenoisingSink<'a> {
+    pub inner: &'a mut dyn AudioSink,
+    pub denoiser: &'a mut Denoiser,
+}
+
+impl AudioSink for DenoisingSink<'_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let denoised = debug_span!("denoise").in_scope(|| self.denoiser.process(chunk));
+        if denoised.is_empty() {
+            return Ok(());
+        }
+        self.inner.push(&denoised)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    /// Pushes the audio held back by the denoiser, and finalizes the inner sink.
+    fn finalize(&mut self) -> Result<(), String> {
+        let rest = self.denoiser.finish();
+        self.inner.push(&rest)?;
+        self.inner.finalize()
+    }
+}
+
+/// In place radix-2 FFT of a power of two length, scaled by 1/n when `inverse`.
+pub(crate) fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+    if inverse {
+        for (re, im) in re.iter_mut().zip(im) {
+            *re /= n as f32;
+            *im /= n as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audio::dsp::rms;
+
+    use super::*;
+
+    /// Notes that alternate with rests every half a second, and a steady buzz.
+    fn buzzy_tone(samples: usize, sample_rate: usize) -> (Vec<f32>, Vec<f32>) {
+        let t = |i: usize| i as f32 / sample_rate as f32;
+        let tone = (0..samples)
+            .map(|i| match (t(i) * 2.0) as usize % 2 {
+                0 => (2.0 * PI * 440.0 * t(i)).sin() * 0.3,
+                _ => 0.0,
+            })
+            .collect::<Vec<_>>();
+        let buzz = (0..samples)
+            .map(|i| ((2.0 * PI * 1500.0 * t(i)).sin() + (2.0 * PI * 2700.0 * t(i)).sin()) * 0.01)
+            .collect::<Vec<_>>();
+        (tone, buzz)
+    }
+
+    fn denoise(audio: &[f32], strength: f32, sample_rate: usize, chunk: usize) -> Vec<f32> {
+        let mut denoiser = Denoiser::new(&DenoiseConfig { strength }, sample_rate);
+        let mut out = audio
+            .chunks(chunk)
+            .flat_map(|chunk| denoiser.process(chunk))
+            .collect::<Vec<_>>();
+        out.extend(denoiser.finish());
+        out
+    }
+
+    #[test]
+    fn reconstructs_the_audio_without_strength() {
+        let (tone, buzz) = buzzy_tone(8000, 8000);
+        let audio = tone
+            .iter()
+            .zip(&buzz)
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>();
+        let out = denoise(&audio, 0.0, 8000, 300);
+        assert_eq!(out.len(), audio.len());
+        assert!(out.iter().zip(&audio).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn subtracts_steady_buzz() {
+        let sample_rate = 8000;
+        let (tone, buzz) = buzzy_tone(5 * sample_rate, sample_rate);
+        let audio = tone
+            .iter()
+            .zip(&buzz)
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>();
+        let out = denoise(&audio, 1.0, sample_rate, 1000);
+        assert_eq!(out.len(), audio.len());
+
+        let settled = 2 * sample_rate..;
+        let error = |audio: &[f32]| {
+            let diff = audio[settled.clone()]
+                .iter()
+                .zip(&tone[settled.clone()])
+                .map(|(a, b)| a - b)
+                .collect::<Vec<_>>();
+            rms(&diff)
+        };
+        // Most of the buzz is gone, and the tone is mostly kept.
+        assert!(error(&out) < error(&audio) / 5.0, "{}", error(&out));
+        assert!(rms(&out[settled.clone()]) > rms(&tone[settled.clone()]) * 0.8);
+    }
+
+    #[test]
+    fn inverts_the_fft() {
+        let mut re = (0..16).map(|i| (i as f32).sin()).collect::<Vec<_>>();
+        let mut im = vec![0.0; 16];
+        let original = re.clone();
+        fft(&mut re, &mut im, false);
+        // The sum of the samples ends up in the first bin.
+        assert!((re[0] - original.iter().sum::<f32>()).abs() < 1e-4);
+        fft(&mut re, &mut im, true);
+        assert!(re.iter().zip(&original).all(|(a, b)| (a - b).abs() < 1e-5));
+    }
+}
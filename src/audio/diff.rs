@@ -0,0 +1,217 @@
+//! Comparison of two renders, for checking that a change to the pipeline did not change
+//! what it generates, and finding where it did when it does.
+
+use std::fmt::Write;
+
+use crate::audio::dsp::{gain_to_db, rms};
+use crate::audio::loop_points::{spectrum, BINS};
+
+/// Frames of each second whose spectra are compared, 50ms long.
+const FRAMES_PER_SEC: usize = 20;
+/// Level given to silence when comparing loudness, so that it stays finite.
+const SILENCE_DB: f32 = -120.0;
+
+/// How different two renders can be while still counting as the same.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffTolerance {
+    /// Largest difference between two samples.
+    pub max_delta: f32,
+    /// Largest [SecondDiff::spectral_distance].
+    pub spectral_distance: f32,
+    /// Largest difference of loudness, in dB.
+    pub loudness_db: f32,
+}
+
+impl Default for DiffTolerance {
+    /// Loose enough for the rounding of 16 bit WAV files.
+    fn default() -> Self {
+        Self {
+            max_delta: 1e-3,
+            spectral_distance: 0.01,
+            loudness_db: 0.1,
+        }
+    }
+}
+
+/// How two renders differ during one second.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecondDiff {
+    pub second: usize,
+    /// Largest difference between two samples.
+    pub max_delta: f32,
+    /// Average distance between the log-compressed spectra of the renders, 0 when they
+    /// sound the same.
+    pub spectral_distance: f32,
+    /// Loudness of the second render minus the one of the first, in dB.
+    pub loudness_db: f32,
+}
+
+impl SecondDiff {
+    pub fn within(&self, tolerance: &DiffTolerance) -> bool {
+        self.max_delta <= tolerance.max_delta
+            && self.spectral_distance <= tolerance.spectral_distance
+            && self.loudness_db.abs() <= tolerance.loudness_db
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioDiff {
+    pub sample_rate: usize,
+    /// Samples in each render.
+    pub lens: (usize, usize),
+    /// Every second of the longest render, with the audio missing from the other one
+    /// taken as silence.
+    pub seconds: Vec<SecondDiff>,
+}
+
+impl AudioDiff {
+    /// The seconds in which the renders differ by more than `tolerance`.
+    pub fn diverging<'a>(
+        &'a self,
+        tolerance: &'a DiffTolerance,
+    ) -> impl Iterator<Item = &'a SecondDiff> + 'a {
+        self.seconds.iter().filter(|s| !s.within(tolerance))
+    }
+
+    pub fn matches(&self, tolerance: &DiffTolerance) -> bool {
+        self.lens.0 == self.lens.1 && self.diverging(tolerance).next().is_none()
+    }
+
+    /// Describes the lengths of the renders and each second in which they diverge.
+    pub fn report(&self, tolerance: &DiffTolerance) -> String {
+        let secs = |samples: usize| samples as f32 / self.sample_rate as f32;
+        let mut out = String::new();
+        if self.lens.0 != self.lens.1 {
+            let _ = writeln!(
+                out,
+                "Lengths differ: {:.2}s and {:.2}s",
+                secs(self.lens.0),
+                secs(self.lens.1)
+            );
+        }
+        let mut diverging = self.diverging(tolerance).peekable();
+        if diverging.peek().is_none() {
+            let _ = writeln!(out, "No second diverges");
+            return out;
+        }
+        let _ = writeln!(
+            out,
+            "{:>6} {:>10} {:>10} {:>10}",
+            "Second", "Max delta", "Spectral", "Loudness"
+        );
+        for second in diverging {
+            let _ = writeln!(
+                out,
+                "{:>6} {:>10.5} {:>10.4} {:>+8.2}dB",
+                second.second, second.max_delta, second.spectral_distance, second.loudness_db
+            );
+        }
+        out
+    }
+}
+
+/// Compares renders `a` and `b`, both at `sample_rate`, one second at a time.
+pub fn diff(a: &[f32], b: &[f32], sample_rate: usize) -> AudioDiff {
+    let len = a.len().max(b.len());
+    let seconds = (0..len.div_ceil(sample_rate))
+        .map(|second| {
+            let range = second * sample_rate..((second + 1) * sample_rate).min(len);
+            let a = padded(a, range.clone());
+            let b = padded(b, range);
+            SecondDiff {
+                second,
+                max_delta: a
+                    .iter()
+                    .zip(&b)
+                    .fold(0.0, |max, (a, b)| f32::max(max, (a - b).abs())),
+                spectral_distance: spectral_distance(&a, &b, sample_rate),
+                loudness_db: level_db(&b) - level_db(&a),
+            }
+        })
+        .collect();
+    AudioDiff {
+        sample_rate,
+        lens: (a.len(), b.len()),
+        seconds,
+    }
+}
+
+/// The samples of `audio` in `range`, with silence past its end.
+fn padded(audio: &[f32], range: std::ops::Range<usize>) -> Vec<f32> {
+    range
+        .map(|i| audio.get(i).copied().unwrap_or(0.0))
+        .collect()
+}
+
+fn level_db(audio: &[f32]) -> f32 {
+    gain_to_db(rms(audio)).max(SILENCE_DB)
+}
+
+fn spectral_distance(a: &[f32], b: &[f32], sample_rate: usize) -> f32 {
+    let frame = (sample_rate / FRAMES_PER_SEC).max(1);
+    let distances = a
+        .chunks(frame)
+        .zip(b.chunks(frame))
+        .map(|(a, b)| {
+            let (a, b) = (spectrum(a, sample_rate), spectrum(b, sample_rate));
+            let squared = a.iter().zip(&b).map(|(a, b)| (a - b).powi(2)).sum::<f32>();
+            (squared / BINS as f32).sqrt()
+        })
+        .collect::<Vec<_>>();
+    distances.iter().sum::<f32>() / distances.len().max(1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    fn tone(hz: f32, secs: usize, sample_rate: usize) -> Vec<f32> {
+        (0..secs * sample_rate)
+            .map(|i| (2.0 * PI * hz * i as f32 / sample_rate as f32).sin() * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn identical_renders_match() {
+        let audio = tone(440.0, 3, 8000);
+        let diff = diff(&audio, &audio, 8000);
+        assert_eq!(diff.seconds.len(), 3);
+        assert!(diff.matches(&DiffTolerance::default()));
+        assert_eq!(
+            diff.report(&DiffTolerance::default()),
+            "No second diverges\n"
+        );
+    }
+
+    #[test]
+    fn finds_where_renders_diverge() {
+        let a = tone(440.0, 4, 8000);
+        let mut b = a.clone();
+        // Quieter during the second second, and a different tone after it.
+        for sample in &mut b[8000..16000] {
+            *sample *= 0.5;
+        }
+        b[16000..].copy_from_slice(&tone(1000.0, 2, 8000)[..]);
+        b.truncate(28000);
+
+        let tolerance = DiffTolerance::default();
+        let diff = diff(&a, &b, 8000);
+        assert!(!diff.matches(&tolerance));
+        let diverging = diff.diverging(&tolerance).collect::<Vec<_>>();
+        assert_eq!(
+            diverging.iter().map(|s| s.second).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert!((diverging[0].loudness_db + 6.02).abs() < 0.01);
+        assert!(diverging[0].spectral_distance < diverging[1].spectral_distance);
+        assert!(diverging[1].loudness_db.abs() < 0.1);
+        // The missing half second counts as silence.
+        assert!(diverging[2].loudness_db < -2.0);
+
+        let report = diff.report(&tolerance);
+        assert!(report.starts_with("Lengths differ: 4.00s and 3.50s\n"));
+        assert_eq!(report.lines().count(), 5);
+    }
+}
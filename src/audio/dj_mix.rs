@@ -0,0 +1,187 @@
+//! DJ mixes, which play finished tracks one after the other without stopping. Every track
+//! is stretched to the tempo of the mix, and each one fades into the next over a long
+//! crossfade that starts on a beat, with the beats of both tracks lined up.
+
+use crate::audio::dsp::{crossfade_into, mix, FadeCurve};
+use crate::audio::ending::{estimate_beat, first_beat, DEFAULT_BEAT};
+use crate::audio::time_stretch::time_stretch;
+use crate::audio::transitions::{Transition, TransitionStyle};
+
+/// Beats each track fades into the next over, unless told otherwise.
+pub const DEFAULT_CROSSFADE_BEATS: usize = 32;
+/// Tracks are never stretched to more or less than this times their length, as further
+/// stretches sound smeared. Tracks further from the tempo of the mix drift from its beats.
+const MAX_STRETCH: f32 = 1.25;
+/// Peak level of the transition elements mixed over the crossfades.
+const TRANSITION_GAIN: f32 = 0.3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixConfig {
+    /// Tempo of the mix. None takes the one of the first track.
+    pub bpm: Option<f32>,
+    pub crossfade_beats: usize,
+    /// An element building up to the middle of each crossfade, if any.
+    pub transition: Option<TransitionStyle>,
+}
+
+impl Default for MixConfig {
+    fn default() -> Self {
+        Self {
+            bpm: None,
+            crossfade_beats: DEFAULT_CROSSFADE_BEATS,
+            transition: None,
+        }
+    }
+}
+
+/// How a track was placed in a mix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixedTrack {
+    /// Where the track starts in the mix, in samples, on its first beat.
+    pub start: usize,
+    /// The tempo detected in the track, if any.
+    pub bpm: Option<f32>,
+    /// How much the track was stretched, 1 being not at all.
+    pub ratio: f32,
+}
+
+/// Mixes `tracks` in order, returning the mix and where each track went in it. The audio
+/// of each track before its first beat is left out, but for the first track.
+pub fn dj_mix(
+    tracks: &[Vec<f32>],
+    config: &MixConfig,
+    sample_rate: usize,
+) -> Result<(Vec<f32>, Vec<MixedTrack>), String> {
+    if tracks.is_empty() {
+        return Err("A mix needs at least one track".to_string());
+    }
+    let beats: Vec<Option<f32>> = tracks
+        .iter()
+        .map(|t| estimate_beat(t, sample_rate))
+        .collect();
+    let beat = match config.bpm {
+        Some(bpm) => 60.0 / bpm,
+        None => beats[0].unwrap_or(DEFAULT_BEAT),
+    };
+    let beat_len = beat * sample_rate as f32;
+
+    let mut mixed: Vec<f32> = vec![];
+    let mut placed = vec![];
+    // Where the beats of the track that is playing start in the mix.
+    let mut grid = 0;
+    for (i, (track, detected)) in tracks.iter().zip(&beats).enumerate() {
+        let ratio = stretch_ratio(beat, *detected);
+        let track = time_stretch(track, ratio, sample_rate);
+        let first = match detected {
+            Some(_) => first_beat(&track, beat, sample_rate),
+            None => 0,
+        };
+        let bpm = detected.map(|beat| 60.0 / beat);
+        if i == 0 {
+            mixed = track;
+            grid = first;
+            placed.push(MixedTrack {
+                start: 0,
+                bpm,
+                ratio,
+            });
+            continue;
+        }
+
+        let track = &track[first..];
+        let fade = (config.crossfade_beats as f32 * beat_len) as usize;
+        let fade = fade.min(track.len() / 2).min((mixed.len() - grid) / 2);
+        // The crossfade starts on the last beat that leaves room for it.
+        let beats_before = ((mixed.len() - fade - grid) as f32 / beat_len) as usize;
+        let start = grid + (beats_before as f32 * beat_len).round() as usize;
+        let start = start.min(mixed.len() - fade);
+        crossfade_into(
+            &mut mixed[start..start + fade],
+            &track[..fade],
+            FadeCurve::EqualPower,
+        );
+        mixed.truncate(start + fade);
+        mixed.extend(&track[fade..]);
+        if let Some(style) = config.transition {
+            let element = Transition {
+                boundary: i - 1,
+                style,
+                duration: fade as f32 / 2.0 / sample_rate as f32,
+                gain: TRANSITION_GAIN,
+            }
+            .synthesize(sample_rate);
+            let at = (start + fade / 2).saturating_sub(element.len());
+            mix(&mut mixed[at..], &element, 1.0);
+        }
+        grid = start;
+        placed.push(MixedTrack { start, bpm, ratio });
+    }
+    Ok((mixed, placed))
+}
+
+/// How much audio whose beat was `detected` is stretched for lining it up with beats of
+/// `beat` seconds. Tempos detected at half or double speed are as good for lining up the
+/// beats.
+pub(crate) fn stretch_ratio(beat: f32, detected: Option<f32>) -> f32 {
+    detected.map_or(1.0, |detected| {
+        [0.5, 1.0, 2.0]
+            .map(|multiple| beat / (detected * multiple))
+            .into_iter()
+            .min_by(|a, b| a.ln().abs().total_cmp(&b.ln().abs()))
+            .expect("Programming error")
+            .clamp(1.0 / MAX_STRETCH, MAX_STRETCH)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    const RATE: usize = 8000;
+
+    /// Clicks every `beat` seconds over a quiet tone, after `lead_in` seconds of the tone.
+    fn track(secs: f32, beat: f32, lead_in: f32) -> Vec<f32> {
+        let period = (beat * RATE as f32) as usize;
+        let lead_in = (lead_in * RATE as f32) as usize;
+        (0..(secs * RATE as f32) as usize)
+            .map(|i| {
+                let tone = 0.2 * (2.0 * PI * 220.0 * (i % RATE) as f32 / RATE as f32).sin();
+                match i.checked_sub(lead_in) {
+                    Some(i) => (-((i % period) as f32) / 50.0).exp() * 0.5 + tone,
+                    None => tone,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mixes_tracks_on_the_beat() {
+        let tracks = [track(20.0, 0.5, 0.0), track(20.0, 0.6, 0.3)];
+        let config = MixConfig {
+            crossfade_beats: 8,
+            transition: Some(TransitionStyle::Riser),
+            ..Default::default()
+        };
+        let (mixed, placed) = dj_mix(&tracks, &config, RATE).unwrap();
+
+        assert_eq!(placed.len(), 2);
+        assert_eq!(placed[0].ratio, 1.0);
+        assert!((placed[0].bpm.unwrap() - 120.0).abs() < 3.0, "{placed:?}");
+        assert!((placed[1].bpm.unwrap() - 100.0).abs() < 3.0, "{placed:?}");
+        assert!((placed[1].ratio - 0.5 / 0.6).abs() < 0.03, "{placed:?}");
+        // The second track starts on a beat of the first, 4 seconds before it ends.
+        let start = placed[1].start;
+        assert_eq!(start % (RATE / 2), 0, "{placed:?}");
+        assert_eq!(start, 20 * RATE - 4 * RATE);
+        // Its beats keep falling on the beats of the mix.
+        let stretched = &mixed[start..];
+        let first = first_beat(&stretched[..4 * RATE], 0.5, RATE) % (RATE / 2);
+        assert!(first.min(RATE / 2 - first) <= 80, "{first}");
+        let expected = start + (20.0 * RATE as f32 * placed[1].ratio) as usize;
+        assert!(mixed.len().abs_diff(expected) < RATE / 2, "{}", mixed.len());
+
+        assert!(dj_mix(&[], &config, RATE).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Primitives used for processing the generated audio, like fades, gain and crossfades.
+//! They work on plain slices of mono samples, so they can be applied to any audio, not
+//! just the one generated by MusicGPT.
+
+use std::f32::consts::PI;
+
+pub use crate::audio::resample::{resample, LinearResampler};
+
+/// Shape of a fade, from silence to full level.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FadeCurve {
+    /// Level proportional to the position in the fade. Crossfading two copies of the same
+    /// audio with it leaves the audio unchanged.
+    #[default]
+    Linear,
+    /// A quarter of a sine, which keeps the loudness steady when crossfading unrelated
+    /// audio.
+    EqualPower,
+    /// Half a cosine, changing slowly at both ends of the fade.
+    Cosine,
+}
+
+impl FadeCurve {
+    /// Gain at `t`, from 0 at the start of a fade in to 1 at its end.
+    pub fn gain(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::EqualPower => (t * PI / 2.0).sin(),
+            FadeCurve::Cosine => 0.5 - 0.5 * (PI * t).cos(),
+        }
+    }
+}
+
+/// Fades in the whole of `audio`, starting from silence.
+pub fn fade_in(audio: &mut [f32], curve: FadeCurve) {
+    let n = audio.len() as f32;
+    for (i, sample) in audio.iter_mut().enumerate() {
+        *sample *= curve.gain(i as f32 / n);
+    }
+}
+
+/// Fades out the whole of `audio`, so that its last sample is silent.
+pub fn fade_out(audio: &mut [f32], curve: FadeCurve) {
+    let n = audio.len() as f32;
+    for (i, sample) in audio.iter_mut().enumerate() {
+        *sample *= curve.gain(1.0 - (i + 1) as f32 / n);
+    }
+}
+
+/// Fades `audio` out while fading `incoming` in, over the length of the shorter one,
+/// leaving the result in `audio`. Neither of them is silent at any point.
+pub fn crossfade_into(audio: &mut [f32], incoming: &[f32], curve: FadeCurve) {
+    let n = audio.len().min(incoming.len()) as f32;
+    for (i, (sample, incoming)) in audio.iter_mut().zip(incoming).enumerate() {
+        let t = (i + 1) as f32 / (n + 1.0);
+        *sample = *sample * curve.gain(1.0 - t) + incoming * curve.gain(t);
+    }
+}
+
+/// Adds `other`, scaled by `gain`, to `audio`, over the length of the shorter one.
+pub fn mix(audio: &mut [f32], other: &[f32], gain: f32) {
+    for (sample, other) in audio.iter_mut().zip(other) {
+        *sample += other * gain;
+    }
+}
+
+pub fn apply_gain(audio: &mut [f32], gain: f32) {
+    for sample in audio {
+        *sample *= gain;
+    }
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+/// Root mean square level of `audio`, 0 when it is empty.
+pub fn rms(audio: &[f32]) -> f32 {
+    if audio.is_empty() {
+        return 0.0;
+    }
+    (audio.iter().map(|s| s * s).sum::<f32>() / audio.len() as f32).sqrt()
+}
+
+/// Largest absolute sample of `audio`.
+pub fn peak(audio: &[f32]) -> f32 {
+    audio.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fades_in_and_out() {
+        for curve in [FadeCurve::Linear, FadeCurve::EqualPower, FadeCurve::Cosine] {
+            assert_eq!(curve.gain(0.0), 0.0);
+            assert!((curve.gain(1.0) - 1.0).abs() < 1e-6);
+            let mut audio = vec![1.0; 100];
+            fade_in(&mut audio[..10], curve);
+            fade_out(&mut audio[90..], curve);
+            assert_eq!(audio[0], 0.0);
+            assert_eq!(audio[99], 0.0);
+            assert!(audio[..10].windows(2).all(|w| w[0] < w[1]), "{curve:?}");
+            assert!(audio[90..].windows(2).all(|w| w[0] > w[1]), "{curve:?}");
+            assert!(audio[10..90].iter().all(|s| *s == 1.0));
+        }
+    }
+
+    #[test]
+    fn crossfades_keeping_the_level() {
+        let mut audio = vec![0.5; 9];
+        crossfade_into(&mut audio, &[0.5; 20], FadeCurve::Linear);
+        assert!(audio.iter().all(|s| (s - 0.5).abs() < 1e-6));
+
+        let mut audio = vec![1.0; 3];
+        crossfade_into(&mut audio, &[0.0; 3], FadeCurve::Linear);
+        assert_eq!(audio, vec![0.75, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn mixes_and_measures_levels() {
+        let mut audio = vec![0.5, -0.5, 0.5, -0.5];
+        mix(&mut audio[2..], &[0.5, 0.5, 0.5], 0.5);
+        assert_eq!(audio, vec![0.5, -0.5, 0.75, -0.25]);
+        apply_gain(&mut audio, 2.0);
+        assert_eq!(peak(&audio), 1.5);
+        assert_eq!(rms(&[0.5, -0.5]), 0.5);
+        assert_eq!(rms(&[]), 0.0);
+        assert!((gain_to_db(db_to_gain(-6.0)) + 6.0).abs() < 1e-5);
+        assert!((db_to_gain(-20.0) - 0.1).abs() < 1e-6);
+    }
+}
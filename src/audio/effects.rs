@@ -0,0 +1,270 @@
+//! Effects applied to the audio of extended renders as it gets released, after the
+//! segments are joined.
+
+use crate::audio::dsp::db_to_gain;
+
+/// The effects applied to a render, in the order in which they run. None of them are
+/// enabled by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Effects {
+    pub noise_gate: Option<NoiseGateConfig>,
+    /// Decibels under full scale that the peaks are limited to.
+    pub headroom_db: Option<f32>,
+}
+
+/// A gentle downward expander that turns down passages whose level stays under the
+/// threshold, suppressing the codec hiss that is noticeable in quiet ambient renders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoiseGateConfig {
+    /// Level under which the audio is turned down, in dBFS.
+    pub threshold_db: f32,
+    /// Decibels the gain drops per decibel the level is under the threshold.
+    pub ratio: f32,
+    /// Maximum attenuation, in decibels, so that quiet passages are softened but not
+    /// muted.
+    pub range_db: f32,
+    /// Milliseconds it takes the gate to open once the level rises.
+    pub attack_ms: f32,
+    /// Milliseconds it takes the gate to close once the level falls.
+    pub release_ms: f32,
+}
+
+impl Default for NoiseGateConfig {
+    fn default() -> Self {
+        Self {
+            threshold_db: -50.0,
+            ratio: 2.0,
+            range_db: 24.0,
+            attack_ms: 5.0,
+            release_ms: 200.0,
+        }
+    }
+}
+
+/// Runs the [Effects] over consecutive chunks of a stream, keeping their state between
+/// chunks.
+#[derive(Clone, Debug, Default)]
+pub struct EffectsChain {
+    noise_gate: Option<NoiseGate>,
+    limiter: Option<Limiter>,
+}
+
+impl EffectsChain {
+    pub fn new(effects: &Effects, sample_rate: usize) -> Self {
+        Self {
+            noise_gate: effects
+                .noise_gate
+                .as_ref()
+                .map(|config| NoiseGate::new(config, sample_rate)),
+            limiter: effects
+                .headroom_db
+                .map(|headroom_db| Limiter::new(headroom_db, sample_rate)),
+        }
+    }
+
+    /// Applies the effects to the next chunk of the stream.
+    pub fn process(&mut self, audio: &mut [f32]) {
+        if let Some(gate) = &mut self.noise_gate {
+            for sample in audio.iter_mut() {
+                *sample *= gate.gain(*sample);
+            }
+        }
+        if let Some(limiter) = &mut self.limiter {
+            for sample in audio {
+                *sample *= limiter.gain(*sample);
+            }
+        }
+    }
+
+    /// Feeds the next chunk of the stream to the effects without modifying it, for audio
+    /// that already went through them, so that the chunks after it are treated as if the
+    /// stream was never interrupted.
+    pub fn skip(&mut self, audio: &[f32]) {
+        if let Some(gate) = &mut self.noise_gate {
+            for sample in audio {
+                gate.gain(*sample);
+            }
+        }
+        if let Some(limiter) = &mut self.limiter {
+            for sample in audio {
+                limiter.gain(*sample);
+            }
+        }
+    }
+}
+
+/// Milliseconds the level detector of the gate takes to fall, short enough for the gate
+/// timing to be decided by its release, but long enough to hold between the peaks of a
+/// low note.
+const DETECTOR_RELEASE_MS: f32 = 20.0;
+
+#[derive(Clone, Debug)]
+struct NoiseGate {
+    threshold: f32,
+    exponent: f32,
+    floor: f32,
+    detector: f32,
+    attack: f32,
+    release: f32,
+    level: f32,
+    gain: f32,
+}
+
+impl NoiseGate {
+    fn new(config: &NoiseGateConfig, sample_rate: usize) -> Self {
+        let coef = |ms: f32| (-1000.0 / (ms.max(0.01) * sample_rate as f32)).exp();
+        Self {
+            threshold: db_to_gain(config.threshold_db),
+            exponent: (config.ratio - 1.0).max(0.0),
+            floor: db_to_gain(-config.range_db.abs()),
+            detector: coef(DETECTOR_RELEASE_MS),
+            attack: coef(config.attack_ms),
+            release: coef(config.release_ms),
+            level: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    /// Follows the level of the audio and returns the gain for `sample`.
+    fn gain(&mut self, sample: f32) -> f32 {
+        self.level = sample.abs().max(self.level * self.detector);
+        let target = if self.level >= self.threshold {
+            1.0
+        } else {
+            (self.level / self.threshold)
+                .powf(self.exponent)
+                .max(self.floor)
+        };
+        let coef = if target > self.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        self.gain = target + coef * (self.gain - target);
+        // Otherwise rounding keeps the gain a hair under unity after the gate opens.
+        if (self.gain - target).abs() < 1e-6 {
+            self.gain = target;
+        }
+        self.gain
+    }
+}
+
+/// Milliseconds it takes the limiter to let the level back up after a peak.
+const LIMITER_RELEASE_MS: f32 = 100.0;
+
+/// Turns down the audio right away when a peak goes over the ceiling, recovering slowly
+/// after it, so that the output never exceeds the ceiling.
+#[derive(Clone, Debug)]
+struct Limiter {
+    ceiling: f32,
+    release: f32,
+    envelope: f32,
+}
+
+impl Limiter {
+    fn new(headroom_db: f32, sample_rate: usize) -> Self {
+        Self {
+            ceiling: db_to_gain(-headroom_db.abs()),
+            release: (-1000.0 / (LIMITER_RELEASE_MS * sample_rate as f32)).exp(),
+            envelope: 0.0,
+        }
+    }
+
+    fn gain(&mut self, sample: f32) -> f32 {
+        self.envelope = sample.abs().max(self.envelope * self.release);
+        if self.envelope <= self.ceiling {
+            return 1.0;
+        }
+        self.ceiling / self.envelope
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use crate::audio::dsp::peak;
+
+    use super::*;
+
+    fn sine(amplitude: f32, samples: usize) -> Vec<f32> {
+        (0..samples)
+            .map(|i| (2.0 * PI * 220.0 * i as f32 / 8000.0).sin() * amplitude)
+            .collect()
+    }
+
+    #[test]
+    fn turns_down_quiet_passages() {
+        let effects = Effects {
+            noise_gate: Some(NoiseGateConfig::default()),
+            ..Default::default()
+        };
+        let mut chain = EffectsChain::new(&effects, 8000);
+        let mut loud = sine(0.5, 8000);
+        chain.process(&mut loud);
+        // Loud audio goes through untouched.
+        assert!(loud
+            .iter()
+            .zip(sine(0.5, 8000))
+            .all(|(gated, s)| (gated - s).abs() < 1e-3));
+
+        // Hiss at -60dB is turned down by 6 to 12dB once the gate closes, and hiss at
+        // -90dB by the whole range.
+        let mut hiss = sine(0.001, 16000);
+        chain.process(&mut hiss);
+        // The gate closes gradually.
+        assert!(peak(&hiss[..100]) > 0.0009);
+        assert!(peak(&hiss[4000..]) < 0.001 * db_to_gain(-6.0));
+        assert!(peak(&hiss[4000..]) > 0.001 * db_to_gain(-12.0));
+        let mut faint = sine(0.00003, 16000);
+        chain.process(&mut faint);
+        let floor = 0.00003 * db_to_gain(-24.0);
+        assert!((peak(&faint[12000..]) / floor - 1.0).abs() < 0.01);
+
+        // And opens quickly when the music comes back.
+        let mut loud = sine(0.5, 8000);
+        chain.process(&mut loud);
+        assert!(peak(&loud[400..]) > 0.49);
+    }
+
+    #[test]
+    fn skipping_keeps_the_state() {
+        let effects = Effects {
+            noise_gate: Some(NoiseGateConfig::default()),
+            ..Default::default()
+        };
+        let audio = [sine(0.5, 4000), sine(0.001, 4000)].concat();
+        let mut whole = audio.clone();
+        EffectsChain::new(&effects, 8000).process(&mut whole);
+
+        let mut chain = EffectsChain::new(&effects, 8000);
+        let mut rest = audio[3000..].to_vec();
+        chain.skip(&audio[..3000]);
+        chain.process(&mut rest);
+        assert_eq!(rest, whole[3000..]);
+    }
+
+    #[test]
+    fn limits_peaks_to_the_headroom() {
+        let effects = Effects {
+            headroom_db: Some(6.0),
+            ..Default::default()
+        };
+        let mut chain = EffectsChain::new(&effects, 8000);
+        let mut quiet = sine(0.4, 4000);
+        chain.process(&mut quiet);
+        assert_eq!(quiet, sine(0.4, 4000));
+
+        let mut loud = sine(1.0, 4000);
+        chain.process(&mut loud);
+        assert!(peak(&loud) <= db_to_gain(-6.0));
+        assert!(peak(&loud) > db_to_gain(-6.0) * 0.99);
+    }
+
+    #[test]
+    fn does_nothing_by_default() {
+        let mut audio = sine(0.001, 1000);
+        EffectsChain::new(&Effects::default(), 8000).process(&mut audio);
+        assert_eq!(audio, sine(0.001, 1000));
+    }
+}
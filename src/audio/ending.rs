@@ -0,0 +1,285 @@
+//! Endings for generated audio that would otherwise stop in the middle of a phrase.
+
+use crate::audio::dsp::{fade_out, rms, FadeCurve};
+
+/// How the end of a render is treated when it stops abruptly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EndingConfig {
+    /// The fade out lasts this many beats of the detected tempo.
+    pub fade_beats: usize,
+    /// Seconds of audio past the target duration that can be kept for a longer decay.
+    pub max_extension: f32,
+    /// The end is abrupt when its level is above this fraction of the level before it.
+    pub abrupt_ratio: f32,
+}
+
+impl Default for EndingConfig {
+    fn default() -> Self {
+        Self {
+            fade_beats: 8,
+            max_extension: 2.0,
+            abrupt_ratio: 0.5,
+        }
+    }
+}
+
+impl EndingConfig {
+    /// Seconds at the end of the audio needed for detecting its tempo and fading over
+    /// it, even at the slowest tempo.
+    pub fn window_secs(&self) -> f32 {
+        self.fade_beats as f32 * MAX_BEAT + 4.0
+    }
+}
+
+/// Moves the end of a render to the closest downbeat, so that it ends on a bar instead of
+/// in the middle of one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownbeatEnd {
+    /// Seconds the end can move earlier or later than the target duration.
+    pub tolerance: f32,
+    pub beats_per_bar: usize,
+}
+
+impl Default for DownbeatEnd {
+    fn default() -> Self {
+        Self {
+            tolerance: 2.0,
+            beats_per_bar: 4,
+        }
+    }
+}
+
+impl DownbeatEnd {
+    /// Seconds before the target duration needed for finding two bars before it, even at
+    /// the slowest tempo, and moving the end earlier.
+    pub fn window_secs(&self) -> f32 {
+        2.0 * self.beats_per_bar as f32 * MAX_BEAT + self.tolerance
+    }
+}
+
+/// Seconds per beat assumed when no tempo can be detected, 120 BPM.
+pub(crate) const DEFAULT_BEAT: f32 = 0.5;
+/// Length of the end whose level decides whether it is abrupt.
+const END_SECS: f32 = 0.25;
+const HOP_SECS: f32 = 0.01;
+/// Seconds per beat at 180 and 60 BPM, the range of tempos that are detected.
+const MIN_BEAT: f32 = 1.0 / 3.0;
+const MAX_BEAT: f32 = 1.0;
+
+/// Whether `audio` stops without decaying.
+pub fn is_abrupt(audio: &[f32], sample_rate: usize, abrupt_ratio: f32) -> bool {
+    let end = ((END_SECS * sample_rate as f32) as usize).min(audio.len());
+    if end == 0 || end == audio.len() {
+        return false;
+    }
+    let (body, tail) = audio.split_at(audio.len() - end);
+    let body_rms = rms(body);
+    body_rms > 0.0 && rms(tail) > body_rms * abrupt_ratio
+}
+
+/// Estimates the seconds per beat of `audio`, between 60 and 180 BPM, from the
+/// autocorrelation of its onsets.
+pub fn estimate_beat(audio: &[f32], sample_rate: usize) -> Option<f32> {
+    let (onsets, hop) = onsets(audio, sample_rate);
+    let hop_secs = hop as f32 / sample_rate as f32;
+    let min_lag = (MIN_BEAT / hop_secs) as usize;
+    let max_lag = ((MAX_BEAT / hop_secs) as usize).min(onsets.len().saturating_sub(1));
+    let scores = (min_lag.max(1)..=max_lag)
+        .map(|lag| {
+            let score = onsets
+                .iter()
+                .zip(&onsets[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f32>();
+            (lag, score / (onsets.len() - lag) as f32)
+        })
+        .collect::<Vec<_>>();
+    let best = scores.iter().map(|(_, score)| *score).fold(0.0, f32::max);
+    // Multiples of the beat correlate as well as the beat itself, so the shortest lag
+    // that is about as good as the best one wins.
+    let (lag, _) = scores
+        .iter()
+        .find(|(_, score)| *score > 0.0 && *score >= best * 0.9)?;
+    Some(*lag as f32 * hop_secs)
+}
+
+/// Where the first beat of `audio` falls, in samples, given its seconds per beat. It's
+/// the offset within the first beat whose multiples fall on the strongest onsets.
+pub fn first_beat(audio: &[f32], beat: f32, sample_rate: usize) -> usize {
+    let (onsets, hop) = onsets(audio, sample_rate);
+    let beat_hops = beat * sample_rate as f32 / hop as f32;
+    let score = |phase: usize| {
+        (0..)
+            .map(|k| (phase as f32 + k as f32 * beat_hops).round() as usize)
+            .take_while(|&i| i < onsets.len())
+            .map(|i| onsets[i])
+            .sum::<f32>()
+    };
+    let phase = (0..(beat_hops.ceil() as usize).max(1))
+        .map(|phase| (phase, score(phase)))
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map_or(0, |(phase, _)| phase);
+    // The onset between two hops is where the second one starts.
+    ((phase + 1) * hop).min(audio.len())
+}
+
+/// The downbeat of `audio` closest to `target`, in samples, if there is one within the
+/// tolerance. Downbeats are the beats in the position of the bar with the strongest onsets.
+pub fn nearest_downbeat(
+    audio: &[f32],
+    target: usize,
+    sample_rate: usize,
+    config: &DownbeatEnd,
+) -> Option<usize> {
+    let beat = estimate_beat(audio, sample_rate)?;
+    let first = first_beat(audio, beat, sample_rate);
+    let (onsets, hop) = onsets(audio, sample_rate);
+    let beats = (0..)
+        .map(|k| first + (k as f32 * beat * sample_rate as f32).round() as usize)
+        .take_while(|&i| i < audio.len())
+        .collect::<Vec<_>>();
+    // The onset at a beat starts the hop it falls on, give or take a hop.
+    let strength = |i: usize| {
+        let hop = (i / hop).checked_sub(1)?;
+        let around = &onsets[hop.saturating_sub(1)..(hop + 2).min(onsets.len())];
+        around.iter().copied().reduce(f32::max)
+    };
+    let bar = config.beats_per_bar.max(1);
+    let score = |offset: usize| {
+        beats
+            .iter()
+            .skip(offset)
+            .step_by(bar)
+            .filter_map(|&i| strength(i))
+            .sum::<f32>()
+    };
+    let offset = (0..bar).max_by(|&a, &b| score(a).total_cmp(&score(b)).then(b.cmp(&a)))?;
+    let tolerance = (config.tolerance * sample_rate as f32) as usize;
+    beats
+        .into_iter()
+        .skip(offset)
+        .step_by(bar)
+        .filter(|i| i.abs_diff(target) <= tolerance)
+        .min_by_key(|i| i.abs_diff(target))
+}
+
+/// How much the level rises from each hop of [HOP_SECS] to the next, and the hop in
+/// samples.
+pub(crate) fn onsets(audio: &[f32], sample_rate: usize) -> (Vec<f32>, usize) {
+    let hop = ((HOP_SECS * sample_rate as f32) as usize).max(1);
+    let energy = audio.chunks(hop).map(rms).collect::<Vec<_>>();
+    let onsets = energy
+        .windows(2)
+        .map(|w| (w[1] - w[0]).max(0.0))
+        .collect::<Vec<_>>();
+    (onsets, hop)
+}
+
+/// Fades out the end of `audio` over a whole number of beats if it stops abruptly, after
+/// appending as much of `extension`, the audio that was generated past its end, as
+/// allowed. Returns how many samples of `extension` were appended.
+pub fn apply_ending(
+    audio: &mut Vec<f32>,
+    extension: &[f32],
+    sample_rate: usize,
+    config: &EndingConfig,
+) -> usize {
+    if !is_abrupt(audio, sample_rate, config.abrupt_ratio) {
+        return 0;
+    }
+    let beat = estimate_beat(audio, sample_rate).unwrap_or(DEFAULT_BEAT);
+    let extended =
+        ((config.max_extension.min(beat) * sample_rate as f32) as usize).min(extension.len());
+    audio.extend(&extension[..extended]);
+
+    let fade = ((config.fade_beats as f32 * beat * sample_rate as f32) as usize).min(audio.len());
+    let start = audio.len() - fade;
+    // Half a cosine, so the level drops slowly at first and settles into silence.
+    fade_out(&mut audio[start..], FadeCurve::Cosine);
+    extended
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    /// Clicks every `beat` seconds, decaying quickly, over a sustained tone.
+    fn clicks(secs: f32, beat: f32, sample_rate: usize) -> Vec<f32> {
+        let period = (beat * sample_rate as f32) as usize;
+        (0..(secs * sample_rate as f32) as usize)
+            .map(|i| {
+                let tone = (2.0 * PI * 220.0 * i as f32 / sample_rate as f32).sin();
+                (-((i % period) as f32) / 50.0).exp() * 0.5 + tone * 0.3
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_the_tempo() {
+        let beat = estimate_beat(&clicks(10.0, 0.6, 8000), 8000).unwrap();
+        assert!((beat - 0.6).abs() < 0.02, "{beat}");
+        assert_eq!(estimate_beat(&vec![0.0; 8000], 8000), None);
+    }
+
+    #[test]
+    fn finds_the_first_beat() {
+        let mut audio = vec![0.1; 2300];
+        audio.extend(clicks(10.0, 0.5, 8000));
+        let first = first_beat(&audio, 0.5, 8000);
+        assert!(first.abs_diff(2300) <= 80, "{first}");
+    }
+
+    #[test]
+    fn finds_the_nearest_downbeat() {
+        // Bars of 4 beats of 0.5s, the first beat of each one accented.
+        let mut audio = vec![0.1; 1000];
+        audio.extend(clicks(12.0, 0.5, 8000));
+        for bar in 0..6 {
+            let start = 1000 + bar * 16_000;
+            for (i, sample) in audio[start..start + 400].iter_mut().enumerate() {
+                *sample += (-(i as f32) / 50.0).exp();
+            }
+        }
+        let config = DownbeatEnd {
+            tolerance: 1.5,
+            beats_per_bar: 4,
+        };
+        let end = nearest_downbeat(&audio, 66_000, 8000, &config).unwrap();
+        assert!(end.abs_diff(65_000) <= 80, "{end}");
+        let end = nearest_downbeat(&audio, 78_000, 8000, &config).unwrap();
+        assert!(end.abs_diff(81_000) <= 80, "{end}");
+        // The downbeats are 8000 samples away, while only 4000 are allowed.
+        let config = DownbeatEnd {
+            tolerance: 0.5,
+            ..config
+        };
+        assert_eq!(nearest_downbeat(&audio, 57_000, 8000, &config), None);
+    }
+
+    #[test]
+    fn fades_out_abrupt_endings_over_whole_beats() {
+        let config = EndingConfig {
+            fade_beats: 4,
+            ..Default::default()
+        };
+        let mut audio = clicks(10.0, 0.5, 8000);
+        let extension = clicks(1.0, 0.5, 8000);
+        assert!(is_abrupt(&audio, 8000, config.abrupt_ratio));
+
+        let extended = apply_ending(&mut audio, &extension, 8000, &config);
+        assert_eq!(extended, 4000);
+        assert_eq!(audio.len(), 84_000);
+        assert!(!is_abrupt(&audio, 8000, config.abrupt_ratio));
+        assert_eq!(audio.last(), Some(&0.0));
+        // The fade starts 4 beats before the end.
+        assert_eq!(audio[84_000 - 16_000 - 1], clicks(10.5, 0.5, 8000)[67_999]);
+        assert!(audio[84_000 - 16_000 + 4000] < clicks(10.5, 0.5, 8000)[72_000]);
+
+        // Endings that already decay are left alone.
+        let mut decayed = audio.clone();
+        assert_eq!(apply_ending(&mut decayed, &extension, 8000, &config), 0);
+        assert_eq!(decayed, audio);
+    }
+}
@@ -1,63 +1,452 @@
-/// Extended audio generation module for creating music longer than 30 seconds
-/// Uses overlapping window technique with crossfading
+//! Extended audio generation module for creating music longer than 30 seconds
+//! Uses overlapping window technique with crossfading
 
-use std::collections::VecDeque;
-use std::sync::Arc;
-use tracing::info;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+use tracing::{debug_span, info, info_span, warn};
+
+use crate::audio::audio_sink::{AudioSink, MemorySink, TrimSink};
+use crate::audio::denoise::{DenoiseConfig, Denoiser, DenoisingSink};
+use crate::audio::effects::{Effects, EffectsChain};
+use crate::audio::ending::{self, DownbeatEnd, EndingConfig};
+use crate::audio::gain_staging::{Normalization, Normalizer, NormalizingSink, SegmentGain};
+use crate::audio::intro_outro::{Envelope, IntroOutro};
+use crate::audio::markers::SegmentMarker;
+use crate::audio::transitions::Transition;
 
 /// Configuration for extended audio generation
 #[derive(Clone, Debug)]
 pub struct ExtendedGenerationConfig {
-    /// Target duration in seconds
-    pub target_duration: usize,
-    /// Duration of each segment (max 30 seconds due to model constraints)
-    pub segment_duration: usize,
+    /// Target duration in seconds, which the audio is cut to the sample to
+    pub target_duration: f32,
+    /// Duration of each segment (max 30 seconds due to model constraints). Fractional
+    /// durations are generated up to the next whole second and cut.
+    pub segment_duration: f32,
     /// Overlap duration between segments for smooth transitions (in seconds)
-    pub overlap_duration: usize,
+    pub overlap_duration: f32,
     /// Crossfade duration for blending segments (in seconds)
     pub crossfade_duration: f32,
+    /// How the end of each segment is blended with the beginning of the next one
+    pub join: JoinStyle,
+    /// Synthesized elements mixed over some of the joins, at most one per join
+    pub transitions: Vec<Transition>,
+    /// How the end is treated if the last segment stops abruptly. None keeps it as is.
+    pub ending: Option<EndingConfig>,
+    /// Moves the end to the closest downbeat around the target duration, before it gets
+    /// its outro or ending. None cuts it at the target duration.
+    pub end_on_downbeat: Option<DownbeatEnd>,
+    /// Treatments for the beginning and the end. An outro replaces the automatic ending.
+    pub intro_outro: IntroOutro,
+    /// Effects applied to the joined audio as it gets released
+    pub effects: Effects,
+    /// Removes the codec buzz from each segment before it is stitched. None leaves the
+    /// segments as generated.
+    pub denoise: Option<DenoiseConfig>,
+    /// Brings each segment to the same level before it is crossfaded. None leaves the
+    /// segments as generated.
+    pub normalize: Option<Normalization>,
+    /// How failed segments are retried before failing the whole generation
+    pub retry: RetryPolicy,
+    /// Time without progress after which a segment is considered stuck, and fails so that
+    /// it can be retried. None waits forever.
+    pub watchdog_timeout: Option<Duration>,
+    /// Generates each segment as a continuation of the previous one, starting with its
+    /// last `crossfade_duration` seconds, instead of from scratch. Generators that cannot
+    /// continue segments ignore it.
+    pub continue_segments: bool,
+    /// Denoises, normalizes and stitches the audio of each segment on another thread
+    /// while the generator keeps producing it, instead of on the generating thread.
+    pub overlap_post: bool,
+    /// How often progress is reported
+    pub progress: ProgressThrottle,
 }
 
 impl Default for ExtendedGenerationConfig {
     fn default() -> Self {
         Self {
-            target_duration: 240, // 4 minutes
-            segment_duration: 28, // Leave buffer below 30s
-            overlap_duration: 4,
+            target_duration: 240.0, // 4 minutes
+            segment_duration: 28.0, // Leave buffer below 30s
+            overlap_duration: 4.0,
             crossfade_duration: 2.0,
+            join: JoinStyle::default(),
+            transitions: vec![],
+            ending: None,
+            end_on_downbeat: None,
+            intro_outro: IntroOutro::default(),
+            effects: Effects::default(),
+            denoise: None,
+            normalize: None,
+            retry: RetryPolicy::default(),
+            watchdog_timeout: Some(Duration::from_secs(120)),
+            continue_segments: false,
+            overlap_post: true,
+            progress: ProgressThrottle::default(),
+        }
+    }
+}
+
+/// Limits how often progress is reported, as decoders can report it thousands of times
+/// per segment. Progress is only reported when it increases, and the completion of the
+/// generation is always reported.
+#[derive(Clone, Debug)]
+pub struct ProgressThrottle {
+    /// Minimum time between two reports
+    pub min_interval: Duration,
+    /// Minimum increase in progress, from 0 to 1, between two reports
+    pub min_delta: f32,
+}
+
+impl Default for ProgressThrottle {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_millis(100),
+            min_delta: 0.001,
+        }
+    }
+}
+
+impl ProgressThrottle {
+    /// Reports all the progress that increases.
+    pub fn none() -> Self {
+        Self {
+            min_interval: Duration::ZERO,
+            min_delta: 0.0,
+        }
+    }
+}
+
+/// How consecutive segments are blended during the crossfade.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum JoinStyle {
+    /// The previous audio fades out while the next segment fades in, replacing it.
+    #[default]
+    Crossfade,
+    /// The previous audio, usually a decaying reverb tail, keeps fading out during the
+    /// whole crossfade, summed under the next segment, which fades in within the first
+    /// `attack` seconds instead.
+    TailRideOut { attack: f32 },
+    /// Splits both sides of the crossfade in low, mid and high bands, fading each of them
+    /// with its own timing.
+    Multiband(MultibandCrossfade),
+}
+
+/// Crossfades each frequency band at its own pace, so that the transients of the high
+/// band switch quickly while the bass blends slowly, instead of smearing two different
+/// rhythms over the whole crossfade.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultibandCrossfade {
+    /// Frequency splitting the low and mid bands (in Hz)
+    pub low_mid_hz: f32,
+    /// Frequency splitting the mid and high bands (in Hz)
+    pub mid_high_hz: f32,
+    pub low: BandFade,
+    pub mid: BandFade,
+    pub high: BandFade,
+}
+
+impl Default for MultibandCrossfade {
+    fn default() -> Self {
+        Self {
+            low_mid_hz: 250.0,
+            mid_high_hz: 4000.0,
+            low: BandFade {
+                start: 0.0,
+                end: 1.0,
+            },
+            mid: BandFade {
+                start: 0.25,
+                end: 0.75,
+            },
+            high: BandFade {
+                start: 0.4,
+                end: 0.6,
+            },
+        }
+    }
+}
+
+/// When a band fades within the crossfade, as fractions of it from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandFade {
+    pub start: f32,
+    pub end: f32,
+}
+
+impl BandFade {
+    /// Gain of the incoming segment at `t`, from 0 to 1, through the crossfade.
+    fn fade_in(&self, t: f32) -> f32 {
+        if t >= self.end {
+            1.0
+        } else if t < self.start {
+            0.0
+        } else {
+            (t - self.start) / (self.end - self.start)
+        }
+    }
+}
+
+/// Retries with exponential backoff, for transient failures like GPU hiccups or running
+/// out of memory after fragmentation.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Times a segment is generated again after failing
+    pub max_retries: usize,
+    /// Wait before the first retry, doubled on each subsequent one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before the given retry, starting from 1.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1) as u32);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// A failed segment that is being generated again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentRetry {
+    pub segment: usize,
+    /// The retry number, starting from 1
+    pub attempt: usize,
+    pub error: String,
+}
+
+/// Whether an auditioned segment is kept, or generated again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditionVerdict {
+    Accept,
+    Reject,
+}
+
+/// How serious a problem found in an [ExtendedGenerationConfig] is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// The configuration cannot be used.
+    Error,
+    /// The configuration works, but produces worse audio or wastes compute.
+    Warning,
+}
+
+/// A problem found in an [ExtendedGenerationConfig].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigDiagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl ConfigDiagnostic {
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
         }
     }
 }
 
+/// Crossfades shorter than this are too abrupt to hide the seam between segments.
+const MIN_SMOOTH_CROSSFADE: f32 = 0.5;
+/// Fraction of each segment that can be spent on overlap before warning about it.
+const MAX_OVERLAP_RATIO: f32 = 0.25;
+
 impl ExtendedGenerationConfig {
-    pub fn validate(&self) -> Result<(), String> {
-        if self.segment_duration > 30 {
-            return Err("Segment duration cannot exceed 30 seconds due to model limitations".to_string());
+    /// Fails with the errors in the configuration, if any. Otherwise, returns its
+    /// warnings, which should be reported before generating anything with it.
+    pub fn validate(&self) -> Result<Vec<ConfigDiagnostic>, String> {
+        let (errors, warnings): (Vec<_>, Vec<_>) = self
+            .diagnostics()
+            .into_iter()
+            .partition(|d| d.severity == Severity::Error);
+        if !errors.is_empty() {
+            let messages = errors.into_iter().map(|d| d.message).collect::<Vec<_>>();
+            return Err(messages.join(", "));
+        }
+        Ok(warnings)
+    }
+
+    /// All the problems found in the configuration, both errors and warnings.
+    pub fn diagnostics(&self) -> Vec<ConfigDiagnostic> {
+        let mut diagnostics = vec![];
+        if self.segment_duration > 30.0 {
+            diagnostics.push(ConfigDiagnostic::error(
+                "Segment duration cannot exceed 30 seconds due to model limitations",
+            ));
         }
         if self.overlap_duration >= self.segment_duration {
-            return Err("Overlap duration must be less than segment duration".to_string());
+            diagnostics.push(ConfigDiagnostic::error(
+                "Overlap duration must be less than segment duration",
+            ));
+            // The rest of the checks rely on segments being longer than their overlap.
+            return diagnostics;
         }
-        if self.crossfade_duration > self.overlap_duration as f32 {
-            return Err("Crossfade duration must be less than or equal to overlap duration".to_string());
+        if self.crossfade_duration > self.overlap_duration {
+            diagnostics.push(ConfigDiagnostic::error(
+                "Crossfade duration must be less than or equal to overlap duration",
+            ));
         }
-        Ok(())
+        match &self.join {
+            JoinStyle::Crossfade => {}
+            JoinStyle::TailRideOut { attack } => {
+                if !(0.0..=self.crossfade_duration).contains(attack) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        "Tail ride-out attack must be between 0 and the crossfade duration",
+                    ));
+                }
+            }
+            JoinStyle::Multiband(bands) => {
+                if !(0.0 < bands.low_mid_hz && bands.low_mid_hz < bands.mid_high_hz) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        "Multiband crossfade splits must be positive and in increasing order",
+                    ));
+                }
+                if [bands.low, bands.mid, bands.high]
+                    .iter()
+                    .any(|fade| !(0.0 <= fade.start && fade.start <= fade.end && fade.end <= 1.0))
+                {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        "Multiband crossfade bands must start and end between 0 and 1, in order",
+                    ));
+                }
+            }
+        }
+        if let Some(downbeat) = &self.end_on_downbeat {
+            if downbeat.tolerance <= 0.0 || downbeat.beats_per_bar == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "Ending on a downbeat needs a tolerance over 0s and at least one beat per bar",
+                ));
+            }
+        }
+
+        for (i, transition) in self.transitions.iter().enumerate() {
+            let join = transition.boundary;
+            if transition.duration <= 0.0 || !(0.0..=1.0).contains(&transition.gain) {
+                diagnostics.push(ConfigDiagnostic::error(format!(
+                    "The transition at join {join} must last more than 0s, with a gain between 0 and 1"
+                )));
+            }
+            if self.transitions[..i].iter().any(|t| t.boundary == join) {
+                diagnostics.push(ConfigDiagnostic::error(format!(
+                    "There are several transitions at join {join}"
+                )));
+            }
+            if join + 1 >= self.num_segments() {
+                diagnostics.push(ConfigDiagnostic::warning(format!(
+                    "The transition at join {join} is unused, there are only {} segments",
+                    self.num_segments()
+                )));
+            }
+            if transition.duration > self.overlap_duration - self.crossfade_duration / 2.0 {
+                diagnostics.push(ConfigDiagnostic::warning(format!(
+                    "The transition at join {join} is longer than the overlap, its beginning is cut"
+                )));
+            }
+        }
+
+        if self.crossfade_duration < MIN_SMOOTH_CROSSFADE {
+            diagnostics.push(ConfigDiagnostic::warning(format!(
+                "A crossfade shorter than {MIN_SMOOTH_CROSSFADE}s may click between segments"
+            )));
+        }
+        let overlap_ratio = self.overlap_duration / self.segment_duration;
+        if self.num_segments() > 1 && overlap_ratio > MAX_OVERLAP_RATIO {
+            diagnostics.push(ConfigDiagnostic::warning(format!(
+                "The overlap between segments wastes {:.0}% of the compute",
+                overlap_ratio * 100.0
+            )));
+        }
+        let generated = self.num_segments() as f32
+            * (self.segment_duration - self.overlap_duration)
+            + self.overlap_duration;
+        // Rounded so that fractional durations are reported the way they were given.
+        let trimmed = ((generated - self.target_duration).max(0.0) * 100.0).round() / 100.0;
+        if trimmed * 2.0 > self.segment_duration {
+            diagnostics.push(ConfigDiagnostic::warning(format!(
+                "{trimmed}s of the last segment are generated only to be trimmed, a target \
+                 duration of {}s would use them",
+                self.target_duration + trimmed
+            )));
+        }
+        diagnostics
     }
 
     pub fn num_segments(&self) -> usize {
         let effective_segment = self.segment_duration - self.overlap_duration;
-        ((self.target_duration + effective_segment - 1) / effective_segment).max(1)
+        ((self.target_duration / effective_segment).ceil() as usize).max(1)
+    }
+}
+
+/// Why an extended generation did not complete.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GenerationError {
+    /// The progress callback asked to stop.
+    Aborted,
+    Failed(String),
+}
+
+impl fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerationError::Aborted => write!(f, "Aborted"),
+            GenerationError::Failed(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+impl From<String> for GenerationError {
+    fn from(err: String) -> Self {
+        GenerationError::Failed(err)
     }
 }
 
 ///Trait for generating audio segments
 pub trait SegmentGenerator: Send + Sync {
+    /// Generates a segment, pushing its audio into `sink` as it gets produced. When
+    /// `on_progress` returns true, the generation should stop and fail.
     fn generate_segment(
         &self,
         prompt: &str,
         duration: usize,
         segment_index: usize,
-        on_progress: Box<dyn Fn(f32) + Send + Sync>,
-    ) -> Result<VecDeque<f32>, String>;
+        on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), String>;
+
+    /// Called after a segment failed, before generating it again, so the generator can
+    /// get rid of any broken state. By default, nothing is done.
+    fn recover(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called when the segment generated by the `worker` thread got stuck, so the
+    /// generator can stop it where it waits. By default, the segment only stops the next
+    /// time it reports progress.
+    fn terminate(&self, _worker: ThreadId) {}
 }
 
 /// Extended audio generator that creates long-form music
@@ -69,126 +458,514 @@ pub struct ExtendedAudioGenerator {
 impl ExtendedAudioGenerator {
     pub fn new(config: ExtendedGenerationConfig, sample_rate: usize) -> Result<Self, String> {
         config.validate()?;
-        Ok(Self { config, sample_rate })
+        Ok(Self {
+            config,
+            sample_rate,
+        })
     }
 
-    /// Generate extended audio by creating and blending multiple segments
-    pub fn generate<G: SegmentGenerator>(
+    /// Generate extended audio by creating and blending multiple segments. Audio is pushed
+    /// into `sink` as soon as the segment generator produces it, except for the tail that
+    /// the next segment still needs to crossfade with. Returning true from `on_progress`
+    /// stops the segment being generated and fails with [GenerationError::Aborted].
+    pub fn generate<G: SegmentGenerator + 'static>(
         &self,
         generator: Arc<G>,
         prompt: &str,
-        on_progress: Arc<dyn Fn(f32) + Send + Sync>,
-    ) -> Result<VecDeque<f32>, String> {
-        let num_segments = self.config.num_segments();
-        info!("Generating {} segments for {}-second audio", num_segments, self.config.target_duration);
+        on_progress: Arc<dyn Fn(f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), GenerationError> {
+        self.resume(generator, prompt, 0, &[], on_progress, sink)
+    }
 
-        let mut final_audio = VecDeque::new();
-        
-        for i in 0..num_segments {
+    /// Continues a generation that stopped after `completed` segments, whose audio is
+    /// `previous`. That audio is pushed into `sink` too, followed by the new segments.
+    pub fn resume<G: SegmentGenerator + 'static>(
+        &self,
+        generator: Arc<G>,
+        prompt: &str,
+        completed: usize,
+        previous: &[f32],
+        on_progress: Arc<dyn Fn(f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), GenerationError> {
+        let mut stitcher = self.stitcher(sink);
+        let aborted = Arc::new(AtomicBool::new(false));
+        let on_progress = Arc::new(ThrottledProgress::new(
+            self.config.progress.clone(),
+            on_progress,
+        ));
+        stitcher.pending.extend(previous);
+        // The previous audio was already released once, with its intro and effects.
+        stitcher.resumed_samples = previous.len();
+
+        // Checkpoints might not include the overlap of their last segment, so some more
+        // segments could be needed to reach the target duration.
+        let segment_samples = self.segment_samples();
+        let missing = stitcher.target_samples.saturating_sub(previous.len());
+        let num_segments = self
+            .config
+            .num_segments()
+            .max(completed + missing.div_ceil(segment_samples - stitcher.crossfade_samples));
+        if completed == 0 {
+            info!(
+                "Generating {} segments for {}-second audio",
+                num_segments, self.config.target_duration
+            );
+        } else {
+            info!(
+                "Resuming {}-second audio from segment {}/{}",
+                self.config.target_duration,
+                completed + 1,
+                num_segments
+            );
+        }
+
+        // Shared by all the segments, which have the same codec buzz.
+        let mut denoiser = self
+            .config
+            .denoise
+            .as_ref()
+            .map(|config| Denoiser::new(config, self.sample_rate));
+        for i in completed..num_segments {
             let segment_progress = i as f32 / num_segments as f32;
-            
+            let transition = match i {
+                0 => vec![],
+                _ => self
+                    .config
+                    .transitions
+                    .iter()
+                    .find(|t| t.boundary == i - 1)
+                    .map(|t| t.synthesize(self.sample_rate))
+                    .unwrap_or_default(),
+            };
+
+            // Tags the logs of the segment with its index, and groups the timings of all
+            // its stages when profiling.
+            let _span = info_span!("segment", segment = i).entered();
+
             // Create varied prompts for different segments to maintain interest
             let segment_prompt = self.create_segment_prompt(prompt, i, num_segments);
-            
-            info!("Generating segment {}/{}: {}", i + 1, num_segments, segment_prompt);
-            
-            // Generate segment with progress callback
-            let on_prog_clone = on_progress.clone();
-            let segment_audio = generator.generate_segment(
-                &segment_prompt,
-                self.config.segment_duration,
-                i,
-                Box::new(move |seg_progress| {
+
+            info!(
+                "Generating segment {}/{}: {}",
+                i + 1,
+                num_segments,
+                segment_prompt
+            );
+
+            // Generate segment with progress callback, crossfading it with the previous
+            // audio as it arrives.
+            let mut resume_from = None;
+            let mut attempt = 0;
+            let mut generated = vec![];
+            let mut marker = None;
+            let mut normalizer = self
+                .config
+                .normalize
+                .as_ref()
+                .map(|config| Normalizer::new(config, self.sample_rate));
+            loop {
+                let on_prog_clone = on_progress.clone();
+                let aborted_clone = aborted.clone();
+                let mut segment_sink = match resume_from {
+                    None => SegmentSink::new(&mut stitcher, i == 0, transition.clone()),
+                    Some(covered) => SegmentSink::resume(&mut stitcher, covered),
+                };
+                // Retries continue the segment where it started.
+                marker = marker.or(Some(segment_sink.marker(i)));
+                let segment_on_progress = Box::new(move |seg_progress| {
                     let total_progress = segment_progress + (seg_progress / num_segments as f32);
-                    on_prog_clone(total_progress);
-                }),
-            )?;
-
-            if i == 0 {
-                // First segment: add everything
-                final_audio.extend(segment_audio);
-            } else {
-                // Subsequent segments: crossfade with previous audio
-                let overlap_samples = (self.config.overlap_duration as f32 * self.sample_rate as f32) as usize;
-                let crossfade_samples = (self.config.crossfade_duration * self.sample_rate as f32) as usize;
-                
-                final_audio = self.crossfade_segments(
-                    final_audio,
-                    segment_audio,
-                    overlap_samples,
-                    crossfade_samples,
+                    let abort = on_prog_clone.report(total_progress);
+                    if abort {
+                        aborted_clone.store(true, Ordering::SeqCst);
+                    }
+                    abort
+                });
+                // The generated audio is denoised, then normalized, then stitched.
+                let mut sink: &mut dyn AudioSink = &mut segment_sink;
+                let mut normalizing;
+                if let Some(normalizer) = &mut normalizer {
+                    normalizer.restart();
+                    normalizing = NormalizingSink {
+                        inner: sink,
+                        normalizer,
+                    };
+                    sink = &mut normalizing;
+                }
+                let mut denoising;
+                if let Some(denoiser) = &mut denoiser {
+                    denoiser.restart();
+                    denoising = DenoisingSink {
+                        inner: sink,
+                        denoiser,
+                    };
+                    sink = &mut denoising;
+                }
+                let mut recording = RecordingSink {
+                    inner: sink,
+                    audio: vec![],
+                };
+                let sink = &mut recording;
+                let result = if self.config.overlap_post {
+                    self.generate_segment_overlapped(
+                        &generator,
+                        &segment_prompt,
+                        i,
+                        segment_on_progress,
+                        sink,
+                    )
+                } else {
+                    self.generate_segment(&generator, &segment_prompt, i, segment_on_progress, sink)
+                }
+                // Pushes the end of the segment, held back by the processing.
+                .and_then(|_| sink.finalize());
+                // Generators that ignore the abort request are not waited for any longer
+                // than the segment they were generating.
+                if aborted.load(Ordering::SeqCst) {
+                    return Err(GenerationError::Aborted);
+                }
+                let Err(err) = result else {
+                    let audio = std::mem::take(&mut recording.audio);
+                    let stitcher = &mut *segment_sink.stitcher;
+                    if stitcher.hold {
+                        let start = segment_sink.start - stitcher.pushed;
+                        let stitched = stitcher.pending[start..].to_vec();
+                        if stitcher.sink.audition(i, &stitched)? == AuditionVerdict::Reject {
+                            info!(
+                                "Segment {}/{} rejected, generating it again",
+                                i + 1,
+                                num_segments
+                            );
+                            resume_from = segment_sink.rollback();
+                            continue;
+                        }
+                        let keep = stitcher.overlap_samples;
+                        stitcher.release(keep)?;
+                    }
+                    generated = audio;
+                    break;
+                };
+                if attempt >= self.config.retry.max_retries {
+                    return Err(err.into());
+                }
+                resume_from = segment_sink.rollback();
+                attempt += 1;
+                warn!(
+                    "Segment {}/{} failed, retrying it ({attempt}/{}): {err}",
+                    i + 1,
+                    num_segments,
+                    self.config.retry.max_retries
+                );
+                stitcher.sink.retried(&SegmentRetry {
+                    segment: i,
+                    attempt,
+                    error: err.clone(),
+                });
+                std::thread::sleep(self.config.retry.backoff(attempt));
+                generator.recover().map_err(|recover_err| {
+                    format!("{err}, and recovering failed: {recover_err}")
+                })?;
+            }
+            if let Some(gain_db) = normalizer.and_then(|n| n.gain_db()) {
+                info!(
+                    "Segment {}/{} normalized by {gain_db:+.1}dB",
+                    i + 1,
+                    num_segments
                 );
+                stitcher.sink.normalized(&SegmentGain {
+                    segment: i,
+                    gain_db,
+                });
+            }
+            stitcher.sink.generated(i, &generated);
+            if let Some(marker) = &marker {
+                stitcher.sink.marked(marker);
+            }
+            // A sink can stop the generation between segments by failing to flush, in
+            // which case all the audio of the completed segments is handed over so that
+            // the generation can be resumed from the next one.
+            if let Err(err) = stitcher.sink.flush() {
+                stitcher.tail_samples = 0;
+                stitcher.release(0)?;
+                return Err(err.into());
             }
         }
 
+        if let Some(downbeat) = &self.config.end_on_downbeat {
+            stitcher.end_on_downbeat(downbeat, self.sample_rate);
+        }
+        if let Some(outro) = &self.config.intro_outro.outro {
+            stitcher.apply_outro(&outro.render(self.sample_rate));
+        } else if let Some(ending) = &self.config.ending {
+            stitcher.apply_ending(ending, self.sample_rate);
+        }
         // Trim to exact target duration
-        let target_samples = self.config.target_duration * self.sample_rate;
-        final_audio.truncate(target_samples);
+        stitcher.release(0)?;
+        stitcher.sink.finalize()?;
+        on_progress.finish();
+
+        info!(
+            "Extended audio generation complete: {} samples",
+            stitcher.pushed
+        );
+        Ok(())
+    }
+
+    /// Generates a segment in its own thread when there is a watchdog timeout, so that a
+    /// generator that got stuck can be abandoned. Its audio is still pushed into `sink`
+    /// from the calling thread.
+    fn generate_segment<G: SegmentGenerator + 'static>(
+        &self,
+        generator: &Arc<G>,
+        prompt: &str,
+        segment_index: usize,
+        on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), String> {
+        let duration = self.config.segment_duration.ceil() as usize;
+        let mut sink = TrimSink::new(sink, self.segment_samples());
+        let sink: &mut dyn AudioSink = &mut sink;
+        let Some(timeout) = self.config.watchdog_timeout else {
+            return generator.generate_segment(prompt, duration, segment_index, on_progress, sink);
+        };
+
+        let (tx, rx) = channel();
+        let prompt = prompt.to_string();
+        let abort = Arc::new(AtomicBool::new(false));
+        let worker_abort = abort.clone();
+        let span = tracing::Span::current();
+        let worker_generator = generator.clone();
+        let worker = std::thread::spawn(move || {
+            let _span = span.entered();
+            let generator = worker_generator;
+            // Once the segment is abandoned, pushing fails and the generator stops.
+            let mut worker_sink = SegmentEventSink(tx.clone());
+            let progress_tx = tx.clone();
+            let result = generator.generate_segment(
+                &prompt,
+                duration,
+                segment_index,
+                Box::new(move |progress| {
+                    let _ = progress_tx.send(SegmentEvent::Progress(progress));
+                    worker_abort.load(Ordering::SeqCst)
+                }),
+                &mut worker_sink,
+            );
+            let _ = tx.send(SegmentEvent::Done(result));
+        });
+
+        let mut progress = 0.0;
+        let mut samples = 0;
+        loop {
+            match rx.recv_timeout(timeout) {
+                Ok(SegmentEvent::Progress(p)) => {
+                    progress = p;
+                    // The worker is abandoned right away, and stops when it reports
+                    // progress again.
+                    if on_progress(p) {
+                        abort.store(true, Ordering::SeqCst);
+                        return Err("Aborted".to_string());
+                    }
+                }
+                Ok(SegmentEvent::Chunk(chunk)) => {
+                    samples += chunk.len();
+                    sink.push(&chunk)?;
+                }
+                Ok(SegmentEvent::Done(result)) => return result,
+                Err(RecvTimeoutError::Timeout) => {
+                    // The worker must not keep running the model next to the retry.
+                    abort.store(true, Ordering::SeqCst);
+                    generator.terminate(worker.thread().id());
+                    return Err(format!(
+                        "Segment {} got stuck, no progress for {}s after reaching {:.0}% and {samples} samples",
+                        segment_index + 1,
+                        timeout.as_secs_f32(),
+                        progress * 100.0
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(format!(
+                        "Segment {} generation crashed at {:.0}%",
+                        segment_index + 1,
+                        progress * 100.0
+                    ))
+                }
+            }
+        }
+    }
 
-        info!("Extended audio generation complete: {} samples", final_audio.len());
-        Ok(final_audio)
+    /// Same as [Self::generate_segment], but the audio is pushed into `sink` from a thread
+    /// of its own, so that processing it overlaps with generating the rest of it. The
+    /// thread is not taken from the rayon pool, which the processing stages use for the
+    /// blocks that are ready.
+    fn generate_segment_overlapped<G: SegmentGenerator + 'static>(
+        &self,
+        generator: &Arc<G>,
+        prompt: &str,
+        segment_index: usize,
+        on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), String> {
+        let (tx, rx) = sync_channel(POST_CAPACITY);
+        let span = tracing::Span::current();
+        std::thread::scope(|scope| {
+            let processing = std::thread::Builder::new()
+                .name("post".to_string())
+                .spawn_scoped(scope, || {
+                    let _span = span.entered();
+                    // Stopping drops the receiver, which makes the generator fail too.
+                    rx.into_iter()
+                        .try_for_each(|chunk: Vec<f32>| sink.push(&chunk))
+                })
+                .map_err(|err| format!("Could not start processing the segment: {err}"))?;
+            let mut post_sink = PostSink(tx);
+            let generated = self.generate_segment(
+                generator,
+                prompt,
+                segment_index,
+                on_progress,
+                &mut post_sink,
+            );
+            // Lets the processing finish once it has the whole segment.
+            drop(post_sink);
+            let processed = processing
+                .join()
+                .map_err(|_| "Processing the segment panicked".to_string())?;
+            // The generator only fails because of the processing when that failed first.
+            processed.and(generated)
+        })
+    }
+
+    /// Samples of audio each segment is cut to.
+    fn segment_samples(&self) -> usize {
+        (self.config.segment_duration * self.sample_rate as f32).round() as usize
+    }
+
+    fn stitcher<'a>(&self, sink: &'a mut dyn AudioSink) -> Stitcher<'a> {
+        let hold = sink.auditions();
+        let attack = match self.config.join {
+            JoinStyle::TailRideOut { attack } => attack,
+            _ => self.config.crossfade_duration,
+        };
+        let bands = match &self.config.join {
+            JoinStyle::Multiband(bands) => Some(BandSplit::new(bands, self.sample_rate)),
+            _ => None,
+        };
+        let ending_secs = self.config.ending.as_ref().map_or(0.0, |e| e.window_secs());
+        let downbeat_secs = (self.config.end_on_downbeat.as_ref()).map_or(0.0, |d| d.window_secs());
+        let tail_secs = ending_secs
+            .max(downbeat_secs)
+            .max(self.config.intro_outro.max_duration());
+        Stitcher {
+            sink,
+            pending: vec![],
+            pushed: 0,
+            target_samples: (self.config.target_duration * self.sample_rate as f32).round()
+                as usize,
+            overlap_samples: (self.config.overlap_duration * self.sample_rate as f32) as usize,
+            crossfade_samples: (self.config.crossfade_duration * self.sample_rate as f32) as usize,
+            attack_samples: (attack * self.sample_rate as f32) as usize,
+            bands,
+            // The end is held back until it gets its ending.
+            tail_samples: (tail_secs * self.sample_rate as f32) as usize,
+            intro: self
+                .config
+                .intro_outro
+                .intro
+                .as_ref()
+                .map(|intro| intro.render(self.sample_rate))
+                .unwrap_or_default(),
+            resumed_samples: 0,
+            effects: EffectsChain::new(&self.config.effects, self.sample_rate),
+            hold,
+        }
+    }
+
+    /// Joins consecutive `segments` like [ExtendedAudioGenerator::generate] would, but
+    /// only renders `context_secs` of audio on each side of each crossfade, for
+    /// auditioning the joins without stitching the whole piece. The segments are joined as
+    /// given, without denoising, normalization, intro nor effects.
+    pub fn preview_joins(&self, segments: &[Vec<f32>], context_secs: f32) -> Vec<JoinPreview> {
+        let context = (context_secs * self.sample_rate as f32) as usize;
+        let mut previews = vec![];
+        // Where the current segment starts in the whole piece.
+        let mut start = 0;
+        for (boundary, pair) in segments.windows(2).enumerate() {
+            let (previous, next) = (&pair[0], &pair[1]);
+            let mut sink = MemorySink::new();
+            let mut stitcher = self.stitcher(&mut sink);
+            let tail = previous.len().min(stitcher.crossfade_samples + context);
+            stitcher.pending.extend(&previous[previous.len() - tail..]);
+            stitcher.pushed = start + previous.len() - tail;
+            stitcher.target_samples = usize::MAX;
+            stitcher.tail_samples = 0;
+            stitcher.intro = Envelope::default();
+            stitcher.effects = EffectsChain::default();
+            let preview_start = stitcher.pushed;
+            let crossfade = stitcher.crossfade_samples;
+            let transition = self
+                .config
+                .transitions
+                .iter()
+                .find(|t| t.boundary == boundary)
+                .map(|t| t.synthesize(self.sample_rate))
+                .unwrap_or_default();
+
+            let mut segment_sink = SegmentSink::new(&mut stitcher, false, transition);
+            let next_start = segment_sink.start;
+            let head = next.len().min(crossfade + context);
+            // Neither of them fail when pushing into memory.
+            let _ = segment_sink.push(&next[..head]);
+            let _ = stitcher.release(0);
+            previews.push(JoinPreview {
+                boundary,
+                start: preview_start,
+                audio: sink.into_inner(),
+            });
+            start = next_start;
+        }
+        previews
+    }
+
+    /// The prompts the segments of a render of `prompt` are generated with, unless it is
+    /// resumed from a checkpoint that needs more segments.
+    pub fn segment_prompts(&self, prompt: &str) -> Vec<String> {
+        let num_segments = self.config.num_segments();
+        (0..num_segments)
+            .map(|i| self.create_segment_prompt(prompt, i, num_segments))
+            .collect()
     }
 
     /// Create contextual prompts for different segments
-    fn create_segment_prompt(&self, base_prompt: &str, segment_index: usize, total_segments: usize) -> String {
+    fn create_segment_prompt(
+        &self,
+        base_prompt: &str,
+        segment_index: usize,
+        total_segments: usize,
+    ) -> String {
         // Add variation keywords based on position in the piece
         match segment_index {
             0 => format!("{} (introduction, opening)", base_prompt),
             i if i == total_segments - 1 => format!("{} (conclusion, ending, outro)", base_prompt),
-            i if i == total_segments / 2 => format!("{} (bridge, development, variation)", base_prompt),
+            i if i == total_segments / 2 => {
+                format!("{} (bridge, development, variation)", base_prompt)
+            }
             i if i < total_segments / 3 => format!("{} (building, developing)", base_prompt),
             _ => base_prompt.to_string(),
         }
     }
 
-    /// Crossfade two audio segments with overlap
-    fn crossfade_segments(
-        &self,
-        mut segment1: VecDeque<f32>,
-        segment2: VecDeque<f32>,
-        overlap_samples: usize,
-        crossfade_samples: usize,
-    ) -> VecDeque<f32> {
-        if segment1.len() < overlap_samples {
-            // Not enough samples to overlap, just concatenate
-            segment1.extend(segment2);
-            return segment1;
-        }
-
-        // Calculate where crossfade starts
-        let crossfade_start = segment1.len().saturating_sub(crossfade_samples);
-        
-        // Apply linear crossfade
-        for i in 0..crossfade_samples.min(segment2.len()) {
-            let fade_position = i as f32 / crossfade_samples as f32;
-            let idx = crossfade_start + i;
-            
-            if idx < segment1.len() && i < segment2.len() {
-                // Linear crossfade: fade out segment1, fade in segment2
-                let fade_out = 1.0 - fade_position;
-                let fade_in = fade_position;
-                
-                segment1[idx] = segment1[idx] * fade_out + segment2[i] * fade_in;
-            }
-        }
-
-        // Remove the overlapped portion and append the rest
-        let skip_samples = crossfade_samples.min(segment2.len());
-        segment1.extend(segment2.iter().skip(skip_samples));
-        
-        segment1
-    }
-
     /// Apply smoothing to avoid clicks and pops
-    pub fn apply_smoothing(audio: &mut VecDeque<f32>, window_size: usize) {
+    pub fn apply_smoothing(audio: &mut [f32], window_size: usize) {
         if audio.len() < window_size * 2 {
             return;
         }
 
         // Smooth the beginning
-        for i in 0..window_size {
+        for (i, sample) in audio.iter_mut().take(window_size).enumerate() {
             let factor = i as f32 / window_size as f32;
-            audio[i] *= factor;
+            *sample *= factor;
         }
 
         // Smooth the end
@@ -200,93 +977,1897 @@ impl ExtendedAudioGenerator {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// A few seconds around the join between two segments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JoinPreview {
+    /// The join between the segments `boundary` and `boundary + 1`.
+    pub boundary: usize,
+    /// Position of the first sample of the audio in the whole piece.
+    pub start: usize,
+    pub audio: Vec<f32>,
+}
 
-    struct DummyGenerator;
-    
-    impl SegmentGenerator for DummyGenerator {
-        fn generate_segment(
-            &self,
-            _prompt: &str,
-            duration: usize,
-            _segment_index: usize,
-            _on_progress: Box<dyn Fn(f32) + Send>,
-        ) -> Result<VecDeque<f32>, String> {
-            // Generate dummy audio (1 second = 1000 samples for test)
-            let samples = duration * 1000;
-            Ok(VecDeque::from(vec![0.5; samples]))
+/// Appends `next` to `previous`, crossfading their first and last `crossfade_samples`
+/// like extended generation does with consecutive segments. Used for stitching segments
+/// that were generated separately.
+pub fn crossfade(
+    previous: &[f32],
+    next: &[f32],
+    overlap_samples: usize,
+    crossfade_samples: usize,
+) -> Vec<f32> {
+    let mut sink = MemorySink::new();
+    let mut stitcher = Stitcher {
+        sink: &mut sink,
+        pending: previous.to_vec(),
+        pushed: 0,
+        target_samples: usize::MAX,
+        overlap_samples,
+        crossfade_samples,
+        attack_samples: crossfade_samples,
+        bands: None,
+        tail_samples: 0,
+        intro: Envelope::default(),
+        resumed_samples: 0,
+        effects: EffectsChain::default(),
+        hold: false,
+    };
+    // Neither of them fail when pushing into memory.
+    let _ = SegmentSink::new(&mut stitcher, false, vec![]).push(next);
+    let _ = stitcher.release(0);
+    sink.into_inner()
+}
+
+/// Forwards progress to a callback as allowed by a [ProgressThrottle].
+struct ThrottledProgress {
+    throttle: ProgressThrottle,
+    on_progress: Arc<dyn Fn(f32) -> bool + Send + Sync>,
+    /// The last progress reported, and when.
+    last: Mutex<Option<(f32, Instant)>>,
+}
+
+impl ThrottledProgress {
+    fn new(
+        throttle: ProgressThrottle,
+        on_progress: Arc<dyn Fn(f32) -> bool + Send + Sync>,
+    ) -> Self {
+        Self {
+            throttle,
+            on_progress,
+            last: Mutex::new(None),
         }
     }
 
-    #[test]
-    fn test_config_validation() {
-        let config = ExtendedGenerationConfig {
-            segment_duration: 35,
-            ..Default::default()
-        };
-        assert!(config.validate().is_err());
+    /// Reports `progress` unless it is too close to the last report, returning whether
+    /// the callback asked to abort. Aborting is only noticed on reported progress.
+    fn report(&self, progress: f32) -> bool {
+        let progress = progress.min(1.0);
+        let mut last = self.last.lock().unwrap();
+        if let Some((last_progress, last_time)) = *last {
+            let throttled = progress < 1.0
+                && (last_time.elapsed() < self.throttle.min_interval
+                    || progress - last_progress < self.throttle.min_delta);
+            if progress <= last_progress || throttled {
+                return false;
+            }
+        }
+        *last = Some((progress, Instant::now()));
+        drop(last);
+        (self.on_progress)(progress)
+    }
 
-        let config = ExtendedGenerationConfig::default();
-        assert!(config.validate().is_ok());
+    /// Reports the completion, if it was not reported already.
+    fn finish(&self) {
+        self.report(1.0);
     }
+}
 
-    #[test]
-    fn test_num_segments() {
-        let config = ExtendedGenerationConfig {
-            target_duration: 240,
-            segment_duration: 28,
-            overlap_duration: 4,
-            ..Default::default()
-        };
-        
-        // Effective segment length is 28 - 4 = 24 seconds
-        // 240 / 24 = 10 segments
-        assert_eq!(config.num_segments(), 10);
+/// What a segment generator running in its own thread reports.
+enum SegmentEvent {
+    Progress(f32),
+    Chunk(Vec<f32>),
+    Done(Result<(), String>),
+}
+
+struct SegmentEventSink(Sender<SegmentEvent>);
+
+impl AudioSink for SegmentEventSink {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.0
+            .send(SegmentEvent::Chunk(chunk.to_vec()))
+            .map_err(|_| "The segment was abandoned".to_string())
     }
 
-    #[test]
-    fn test_extended_generation() {
-        let config = ExtendedGenerationConfig {
-            target_duration: 60,
-            segment_duration: 28,
-            overlap_duration: 4,
-            crossfade_duration: 2.0,
-        };
+    fn finalize(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
 
-        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
-        let result = generator.generate(
-            Arc::new(DummyGenerator),
-            "test prompt",
-            Box::new(|_| {}),
-        );
+/// Chunks that can be waiting to be processed before the generator blocks.
+const POST_CAPACITY: usize = 16;
 
-        assert!(result.is_ok());
-        let audio = result.unwrap();
-        
-        // Should be exactly 60 seconds * 1000 samples/sec = 60000 samples
-        assert_eq!(audio.len(), 60_000);
+/// Hands the audio of a segment over to the task processing it.
+struct PostSink(SyncSender<Vec<f32>>);
+
+impl AudioSink for PostSink {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.0
+            .send(chunk.to_vec())
+            .map_err(|_| "Processing the audio stopped".to_string())
     }
 
-    #[test]
-    fn test_crossfade() {
-        let config = ExtendedGenerationConfig::default();
-        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+    fn finalize(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
 
-        let segment1 = VecDeque::from(vec![1.0; 10000]);
-        let segment2 = VecDeque::from(vec![0.0; 10000]);
+/// Keeps a copy of the audio of a segment as the generator produces it.
+struct RecordingSink<'a> {
+    inner: &'a mut dyn AudioSink,
+    audio: Vec<f32>,
+}
+
+impl AudioSink for RecordingSink<'_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.audio.extend_from_slice(chunk);
+        self.inner.push(chunk)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.inner.finalize()
+    }
+}
+
+/// Joins the audio of consecutive segments, pushing into the output sink the samples that
+/// will not be modified anymore.
+struct Stitcher<'a> {
+    sink: &'a mut dyn AudioSink,
+    /// Audio that was not pushed into the sink yet, as it might still be crossfaded.
+    pending: Vec<f32>,
+    pushed: usize,
+    target_samples: usize,
+    overlap_samples: usize,
+    crossfade_samples: usize,
+    /// Samples it takes the next segment to fade in, which is the crossfade unless the
+    /// previous audio rides out under it.
+    attack_samples: usize,
+    /// How segments are split for crossfading each band separately, if they are.
+    bands: Option<BandSplit>,
+    /// Samples before the target duration that are held back for treating the ending.
+    tail_samples: usize,
+    /// Applied to the beginning of the audio as it gets released.
+    intro: Envelope,
+    /// Samples at the beginning that a previous run already released, with their intro
+    /// and effects.
+    resumed_samples: usize,
+    effects: EffectsChain,
+    /// Holds back the audio of each segment until the sink accepts it.
+    hold: bool,
+}
+
+/// Splits audio in low, mid and high bands with one-pole low-pass filters, in a way that
+/// the bands always add up to the original audio.
+#[derive(Clone)]
+struct BandSplit {
+    fades: [BandFade; 3],
+    low_coef: f32,
+    high_coef: f32,
+    low_state: f32,
+    high_state: f32,
+}
+
+impl BandSplit {
+    fn new(bands: &MultibandCrossfade, sample_rate: usize) -> Self {
+        let coef = |hz: f32| 1.0 - (-2.0 * std::f32::consts::PI * hz / sample_rate as f32).exp();
+        Self {
+            fades: [bands.low, bands.mid, bands.high],
+            low_coef: coef(bands.low_mid_hz),
+            high_coef: coef(bands.mid_high_hz),
+            low_state: 0.0,
+            high_state: 0.0,
+        }
+    }
+
+    fn split(&mut self, sample: f32) -> [f32; 3] {
+        self.low_state += self.low_coef * (sample - self.low_state);
+        self.high_state += self.high_coef * (sample - self.high_state);
+        [
+            self.low_state,
+            self.high_state - self.low_state,
+            sample - self.high_state,
+        ]
+    }
+}
+
+impl Stitcher<'_> {
+    /// Pushes into the sink all the pending audio but the last `keep` samples, without
+    /// exceeding the target duration nor reaching into the held back tail. Returns how
+    /// many samples were pushed.
+    fn release(&mut self, keep: usize) -> Result<usize, String> {
+        let limit = self.target_samples.saturating_sub(self.tail_samples);
+        let n = self
+            .pending
+            .len()
+            .saturating_sub(keep)
+            .min(limit.saturating_sub(self.pushed));
+        if n == 0 {
+            return Ok(0);
+        }
+        let mut chunk: Vec<f32> = self.pending.drain(..n).collect();
+        let skip = self.resumed_samples.saturating_sub(self.pushed).min(n);
+        if self.pushed < self.intro.len() {
+            self.intro.apply(&mut chunk[skip..], self.pushed + skip);
+        }
+        let (resumed, fresh) = chunk.split_at_mut(skip);
+        debug_span!("effects").in_scope(|| {
+            self.effects.skip(resumed);
+            self.effects.process(fresh);
+        });
+        self.pushed += n;
+        self.sink.push(&chunk)?;
+        Ok(n)
+    }
+
+    /// Moves the target duration to the closest downbeat of the pending audio, if there is
+    /// one close enough.
+    fn end_on_downbeat(&mut self, config: &DownbeatEnd, sample_rate: usize) {
+        let target = self.target_samples - self.pushed;
+        if let Some(end) = ending::nearest_downbeat(&self.pending, target, sample_rate, config) {
+            info!(
+                "Moved the end by {} samples to end on a downbeat",
+                end as isize - target as isize
+            );
+            self.target_samples = self.pushed + end;
+        }
+    }
+
+    /// Applies the outro to the end of the pending audio.
+    fn apply_outro(&mut self, outro: &Envelope) {
+        let end = (self.target_samples - self.pushed).min(self.pending.len());
+        let start = end.saturating_sub(outro.len());
+        outro.apply(&mut self.pending[start..end], outro.len() - (end - start));
+        self.tail_samples = 0;
+    }
+
+    /// Gives the pending audio an ending, which might extend it past the target duration
+    /// with audio that would have been trimmed otherwise.
+    fn apply_ending(&mut self, config: &EndingConfig, sample_rate: usize) {
+        let end = (self.target_samples - self.pushed).min(self.pending.len());
+        let extension = self.pending.split_off(end);
+        let extended = ending::apply_ending(&mut self.pending, &extension, sample_rate, config);
+        if extended > 0 {
+            info!("Extended the ending by {extended} samples");
+        }
+        self.target_samples += extended;
+        self.tail_samples = 0;
+    }
+}
+
+/// Receives the audio of a single segment, crossfading its beginning with the end of the
+/// previous audio.
+struct SegmentSink<'a, 'b> {
+    stitcher: &'a mut Stitcher<'b>,
+    /// Position in the pending audio where the crossfade with this segment starts, if any.
+    fade_start: Option<usize>,
+    received: usize,
+    /// Position in the whole output where this segment starts.
+    start: usize,
+    /// The previous audio this segment crossfades with, before being blended.
+    faded_over: Vec<f32>,
+    /// Samples at the beginning of the segment that a previous attempt already stitched.
+    skip: usize,
+    skipped: usize,
+    attack_samples: usize,
+    /// Splits this segment's audio in bands for a multiband crossfade, along with the
+    /// bands of the previous audio it crossfades with.
+    bands: Option<(BandSplit, Vec<[f32; 3]>)>,
+    /// Transition element mixed over the join, and its position in the whole output.
+    transition: Vec<f32>,
+    transition_start: usize,
+    /// The audio before the crossfade that the transition was mixed into, before that.
+    untransitioned: Vec<f32>,
+}
+
+impl<'a, 'b> SegmentSink<'a, 'b> {
+    /// Where the segment takes over from the previous audio in the whole output, halfway
+    /// through their crossfade.
+    fn marker(&self, segment: usize) -> SegmentMarker {
+        let crossfade = match self.fade_start {
+            Some(_) => self.stitcher.crossfade_samples,
+            None => 0,
+        };
+        SegmentMarker {
+            segment,
+            start: self.start + crossfade / 2,
+            crossfade,
+        }
+    }
+
+    /// Starts a segment, mixing `transition` over its join with the previous audio so that
+    /// it peaks in the middle of the crossfade.
+    fn new(stitcher: &'a mut Stitcher<'b>, first: bool, mut transition: Vec<f32>) -> Self {
+        let total = stitcher.pushed + stitcher.pending.len();
+        // Without enough samples to overlap, the segment is just concatenated.
+        let fade_start = if first || total < stitcher.overlap_samples {
+            None
+        } else {
+            Some(
+                stitcher
+                    .pending
+                    .len()
+                    .saturating_sub(stitcher.crossfade_samples),
+            )
+        };
+        let start = stitcher.pushed + fade_start.unwrap_or(stitcher.pending.len());
+        let faded_over = match fade_start {
+            Some(fade_start) => stitcher.pending[fade_start..].to_vec(),
+            None => vec![],
+        };
+        let attack_samples = stitcher.attack_samples;
+        let mut transition_start = 0;
+        let mut untransitioned = vec![];
+        match fade_start {
+            Some(fade_start) if !transition.is_empty() => {
+                // What does not fit in the pending audio is cut from the beginning.
+                let peak = fade_start + stitcher.crossfade_samples / 2;
+                let cut = transition.len().saturating_sub(peak);
+                transition.drain(..cut);
+                let first = peak - transition.len();
+                transition_start = stitcher.pushed + first;
+                // The part over the crossfade is mixed as this segment's audio arrives.
+                let before_fade = stitcher.pending[first..]
+                    .iter_mut()
+                    .take(fade_start.saturating_sub(first));
+                for (sample, transition) in before_fade.zip(&transition) {
+                    untransitioned.push(*sample);
+                    *sample += transition;
+                }
+            }
+            _ => transition.clear(),
+        }
+        // The filters run through the previous audio before the crossfade too, so they
+        // are settled when it starts.
+        let bands = match (&stitcher.bands, fade_start) {
+            (Some(split), Some(fade_start)) => {
+                let mut previous = split.clone();
+                let faded_over_bands = stitcher
+                    .pending
+                    .iter()
+                    .map(|sample| previous.split(*sample))
+                    .skip(fade_start)
+                    .collect();
+                Some((split.clone(), faded_over_bands))
+            }
+            _ => None,
+        };
+        Self {
+            stitcher,
+            fade_start,
+            received: 0,
+            start,
+            faded_over,
+            skip: 0,
+            skipped: 0,
+            attack_samples,
+            bands,
+            transition,
+            transition_start,
+            untransitioned,
+        }
+    }
+
+    /// Continues a segment whose first `covered` samples were already stitched by a
+    /// failed attempt. The new attempt crossfades with the end of the failed one, which
+    /// is the same music, so it always replaces it instead of riding it out.
+    fn resume(stitcher: &'a mut Stitcher<'b>, covered: usize) -> Self {
+        let crossfade_samples = stitcher.crossfade_samples.min(covered);
+        let fade_start = stitcher.pending.len().saturating_sub(crossfade_samples);
+        let start = stitcher.pushed + stitcher.pending.len() - covered;
+        let attack_samples = stitcher.crossfade_samples;
+        Self {
+            stitcher,
+            fade_start: Some(fade_start),
+            received: 0,
+            start,
+            faded_over: vec![],
+            skip: covered - crossfade_samples,
+            skipped: 0,
+            attack_samples,
+            bands: None,
+            transition: vec![],
+            transition_start: 0,
+            untransitioned: vec![],
+        }
+    }
+
+    fn fading(&self) -> bool {
+        self.fade_start.is_some() && self.received < self.stitcher.crossfade_samples
+    }
+
+    /// Undoes what a failed attempt did, if none of its audio was released yet, returning
+    /// None. Otherwise, returns how many samples of the segment are already stitched, for
+    /// resuming from there.
+    fn rollback(self) -> Option<usize> {
+        if self.stitcher.pushed <= self.start && self.skip == 0 {
+            let pushed = self.stitcher.pushed;
+            let pending = &mut self.stitcher.pending;
+            pending.truncate(self.start - pushed);
+            pending.extend(self.faded_over);
+            // The next attempt mixes the transition again, except where it was released.
+            for (i, sample) in self.untransitioned.into_iter().enumerate() {
+                if let Some(idx) = (self.transition_start + i).checked_sub(pushed) {
+                    pending[idx] = sample;
+                }
+            }
+            return None;
+        }
+        Some(self.stitcher.pushed + self.stitcher.pending.len() - self.start)
+    }
+}
+
+impl AudioSink for SegmentSink<'_, '_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let crossfade_samples = self.stitcher.crossfade_samples;
+        let to_skip = (self.skip - self.skipped).min(chunk.len());
+        self.skipped += to_skip;
+        let span = debug_span!("crossfade").entered();
+        for sample in &chunk[to_skip..] {
+            let idx = self.fade_start.map(|start| start + self.received);
+            let fading = self.fading();
+            match (idx, &mut self.bands) {
+                (Some(idx), Some((split, faded_over_bands)))
+                    if fading && idx < self.stitcher.pending.len() =>
+                {
+                    let t = self.received as f32 / crossfade_samples as f32;
+                    let incoming = split.split(*sample);
+                    let previous = faded_over_bands[self.received];
+                    self.stitcher.pending[idx] = (0..3)
+                        .map(|band| {
+                            let fade_in = split.fades[band].fade_in(t);
+                            previous[band] * (1.0 - fade_in) + incoming[band] * fade_in
+                        })
+                        .sum();
+                }
+                (Some(idx), _) if fading && idx < self.stitcher.pending.len() => {
+                    // Linear crossfade: fade out the previous audio, fade in this segment.
+                    // With a shorter attack, the previous audio is summed under this one.
+                    let fade_out = 1.0 - self.received as f32 / crossfade_samples as f32;
+                    let fade_in = match self.attack_samples {
+                        0 => 1.0,
+                        attack => (self.received as f32 / attack as f32).min(1.0),
+                    };
+                    let previous = &mut self.stitcher.pending[idx];
+                    *previous = *previous * fade_out + sample * fade_in;
+                }
+                _ => self.stitcher.pending.push(*sample),
+            }
+            if let Some(transition) = (self.start + self.received)
+                .checked_sub(self.transition_start)
+                .and_then(|i| self.transition.get(i))
+            {
+                let idx = self.start + self.received - self.stitcher.pushed;
+                self.stitcher.pending[idx] += transition;
+            }
+            self.received += 1;
+        }
+        drop(span);
+
+        if self.stitcher.hold {
+            return Ok(());
+        }
+        // The next segment will crossfade with the last overlap_samples, and the ones in
+        // the middle of this segment's crossfade are still to be blended.
+        let mut keep = self.stitcher.overlap_samples;
+        if let (Some(start), true) = (self.fade_start, self.fading()) {
+            keep = keep.max(self.stitcher.pending.len() - start);
+        }
+        let released = self.stitcher.release(keep)?;
+        if let Some(start) = &mut self.fade_start {
+            *start = start.saturating_sub(released);
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audio::audio_sink::{ChannelSink, MemorySink};
+    use crate::audio::effects::NoiseGateConfig;
+    use crate::audio::transitions::TransitionStyle;
+
+    use super::*;
+
+    struct DummyGenerator;
+
+    impl SegmentGenerator for DummyGenerator {
+        fn generate_segment(
+            &self,
+            _prompt: &str,
+            duration: usize,
+            _segment_index: usize,
+            _on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+            sink: &mut dyn AudioSink,
+        ) -> Result<(), String> {
+            // Generate dummy audio (1 second = 1000 samples for test)
+            let samples = duration * 1000;
+            sink.push(&vec![0.5; samples])
+        }
+    }
+
+    /// Produces a different value for each segment, in chunks of `chunk_size` samples.
+    struct ChunkedGenerator {
+        chunk_size: usize,
+    }
+
+    impl SegmentGenerator for ChunkedGenerator {
+        fn generate_segment(
+            &self,
+            _prompt: &str,
+            duration: usize,
+            segment_index: usize,
+            on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+            sink: &mut dyn AudioSink,
+        ) -> Result<(), String> {
+            let samples = vec![segment_index as f32; duration * 1000];
+            let mut generated = 0;
+            for chunk in samples.chunks(self.chunk_size) {
+                sink.push(chunk)?;
+                generated += chunk.len();
+                if on_progress(generated as f32 / samples.len() as f32) {
+                    return Err("Aborted".to_string());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let config = ExtendedGenerationConfig {
+            segment_duration: 35.0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ExtendedGenerationConfig::default();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_config_diagnostics() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 100.0,
+            segment_duration: 20.0,
+            overlap_duration: 10.0,
+            crossfade_duration: 0.2,
+            ..Default::default()
+        };
+        let warnings = config.validate().unwrap();
+        assert_eq!(
+            warnings
+                .iter()
+                .map(|d| d.message.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "A crossfade shorter than 0.5s may click between segments",
+                "The overlap between segments wastes 50% of the compute",
+            ]
+        );
+
+        let config = ExtendedGenerationConfig {
+            target_duration: 250.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate().unwrap()[0].message,
+            "18s of the last segment are generated only to be trimmed, a target duration of 268s would use them"
+        );
+        assert!(ExtendedGenerationConfig::default()
+            .validate()
+            .unwrap()
+            .is_empty());
+
+        let config = ExtendedGenerationConfig {
+            segment_duration: 35.0,
+            crossfade_duration: 5.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "Segment duration cannot exceed 30 seconds due to model limitations, \
+             Crossfade duration must be less than or equal to overlap duration"
+        );
+        assert!(config
+            .diagnostics()
+            .iter()
+            .all(|d| d.severity == Severity::Error));
+    }
+
+    #[test]
+    fn test_num_segments() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 240.0,
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            ..Default::default()
+        };
+
+        // Effective segment length is 28 - 4 = 24 seconds
+        // 240 / 24 = 10 segments
+        assert_eq!(config.num_segments(), 10);
+    }
+
+    #[test]
+    fn test_extended_generation() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 60.0,
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut sink = MemorySink::new();
+        let result = generator.generate(
+            Arc::new(DummyGenerator),
+            "test prompt",
+            Arc::new(|_| false),
+            &mut sink,
+        );
+
+        assert!(result.is_ok());
+        let audio = sink.into_inner();
+
+        // Should be exactly 60 seconds * 1000 samples/sec = 60000 samples
+        assert_eq!(audio.len(), 60_000);
+    }
+
+    #[test]
+    fn test_cuts_fractional_durations_to_the_sample() {
+        struct Segments(MemorySink, Vec<usize>);
+
+        impl AudioSink for Segments {
+            fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+                self.0.push(chunk)
+            }
+
+            fn finalize(&mut self) -> Result<(), String> {
+                self.0.finalize()
+            }
+
+            fn generated(&mut self, _segment: usize, audio: &[f32]) {
+                self.1.push(audio.len());
+            }
+        }
+
+        let config = ExtendedGenerationConfig {
+            target_duration: 87.43,
+            segment_duration: 27.5,
+            overlap_duration: 3.5,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(config.num_segments(), 4);
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut sink = Segments(MemorySink::new(), vec![]);
+        generator
+            .generate(
+                Arc::new(DummyGenerator),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut sink,
+            )
+            .unwrap();
+
+        // Segments are generated for 28 seconds, and cut.
+        assert_eq!(sink.1, vec![27_500; 4]);
+        assert_eq!(sink.0.into_inner().len(), 87_430);
+    }
+
+    #[test]
+    fn test_streams_segments_as_they_complete() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 60.0,
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut sink = ChannelSink::new(tx);
+        generator
+            .generate(
+                Arc::new(DummyGenerator),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut sink,
+            )
+            .unwrap();
+
+        // The overlap of each segment is held back until the next one crossfades with it.
+        let chunks = rx.iter().map(|chunk| chunk.len()).collect::<Vec<_>>();
+        assert_eq!(chunks, vec![24_000, 26_000, 10_000]);
+    }
+
+    #[test]
+    fn test_chunk_size_does_not_change_the_result() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 70.0,
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let generate = |chunk_size| {
+            let mut sink = MemorySink::new();
+            generator
+                .generate(
+                    Arc::new(ChunkedGenerator { chunk_size }),
+                    "test prompt",
+                    Arc::new(|_| false),
+                    &mut sink,
+                )
+                .unwrap();
+            sink.into_inner()
+        };
+
+        let whole = generate(usize::MAX);
+        assert_eq!(whole.len(), 70_000);
+        // The second segment fades in right before the end of the first one.
+        assert_eq!(whole[25_999], 0.0);
+        assert!(whole[27_000] > 0.0 && whole[27_000] < 1.0);
+        assert_eq!(whole[28_000], 1.0);
+        for chunk_size in [1, 640, 1000, 3333] {
+            assert_eq!(generate(chunk_size), whole, "chunk size {chunk_size}");
+        }
+    }
+
+    /// Fails the given segment the first `failures` times, after pushing `fail_after`
+    /// samples of it, like [ChunkedGenerator] with 1000 sample chunks otherwise.
+    struct FlakyGenerator {
+        segment: usize,
+        failures: usize,
+        fail_after: usize,
+        attempts: std::sync::Mutex<usize>,
+        recovered: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FlakyGenerator {
+        fn new(segment: usize, failures: usize, fail_after: usize) -> Self {
+            Self {
+                segment,
+                failures,
+                fail_after,
+                attempts: std::sync::Mutex::new(0),
+                recovered: Default::default(),
+            }
+        }
+    }
+
+    impl SegmentGenerator for FlakyGenerator {
+        fn generate_segment(
+            &self,
+            _prompt: &str,
+            duration: usize,
+            segment_index: usize,
+            _on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+            sink: &mut dyn AudioSink,
+        ) -> Result<(), String> {
+            let samples = vec![segment_index as f32; duration * 1000];
+            let mut attempts = self.attempts.lock().unwrap();
+            let fails = segment_index == self.segment && *attempts < self.failures;
+            if segment_index == self.segment {
+                *attempts += 1;
+            }
+            for (i, chunk) in samples.chunks(1000).enumerate() {
+                if fails && i * 1000 >= self.fail_after {
+                    return Err("GPU hiccup".to_string());
+                }
+                sink.push(chunk)?;
+            }
+            Ok(())
+        }
+
+        fn recover(&self) -> Result<(), String> {
+            self.recovered
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Keeps the audio and the retries it is notified about.
+    #[derive(Default)]
+    struct RetryRecorder {
+        audio: Vec<f32>,
+        retries: Vec<SegmentRetry>,
+    }
+
+    impl AudioSink for RetryRecorder {
+        fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+            self.audio.extend(chunk);
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn retried(&mut self, retry: &SegmentRetry) {
+            self.retries.push(retry.clone());
+        }
+    }
+
+    fn retrying_generator(max_retries: usize) -> ExtendedAudioGenerator {
+        let config = ExtendedGenerationConfig {
+            target_duration: 70.0,
+            retry: RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            ..Default::default()
+        };
+        ExtendedAudioGenerator::new(config, 1000).unwrap()
+    }
+
+    #[test]
+    fn test_retries_failed_segments_from_scratch() {
+        let generator = retrying_generator(2);
+        let mut expected = MemorySink::new();
+        generator
+            .generate(
+                Arc::new(ChunkedGenerator { chunk_size: 1000 }),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut expected,
+            )
+            .unwrap();
+
+        // Fails in the middle of the crossfade, before any of the segment is released.
+        let flaky = Arc::new(FlakyGenerator::new(1, 2, 1000));
+        let mut sink = RetryRecorder::default();
+        generator
+            .generate(flaky.clone(), "test prompt", Arc::new(|_| false), &mut sink)
+            .unwrap();
+        assert_eq!(sink.audio, expected.into_inner());
+        assert_eq!(sink.retries.len(), 2);
+        assert_eq!(
+            sink.retries[1],
+            SegmentRetry {
+                segment: 1,
+                attempt: 2,
+                error: "GPU hiccup".to_string()
+            }
+        );
+        assert_eq!(flaky.recovered.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_resumes_segments_that_were_partially_released() {
+        let generator = retrying_generator(1);
+        let flaky = Arc::new(FlakyGenerator::new(1, 1, 10_000));
+        let mut sink = RetryRecorder::default();
+        generator
+            .generate(flaky, "test prompt", Arc::new(|_| false), &mut sink)
+            .unwrap();
+        assert_eq!(sink.audio.len(), 70_000);
+        assert_eq!(sink.retries.len(), 1);
+        // The audio of the failed attempt is kept, and the retry continues after it.
+        assert_eq!(sink.audio[24_000 + 7_000], 1.0);
+        assert_eq!(sink.audio[24_000 + 28_000 - 1], 1.0);
+    }
+
+    #[test]
+    fn test_fails_after_exhausting_retries() {
+        let generator = retrying_generator(1);
+        let flaky = Arc::new(FlakyGenerator::new(0, 2, 0));
+        let mut sink = RetryRecorder::default();
+        let result = generator.generate(flaky, "test prompt", Arc::new(|_| false), &mut sink);
+        assert_eq!(
+            result,
+            Err(GenerationError::Failed("GPU hiccup".to_string()))
+        );
+        assert_eq!(sink.retries.len(), 1);
+    }
+
+    #[test]
+    fn test_aborting_stops_without_retrying() {
+        for watchdog_timeout in [None, Some(Duration::from_secs(120))] {
+            let config = ExtendedGenerationConfig {
+                target_duration: 70.0,
+                watchdog_timeout,
+                progress: ProgressThrottle::none(),
+                ..Default::default()
+            };
+            let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+            let mut sink = RetryRecorder::default();
+            let result = generator.generate(
+                Arc::new(ChunkedGenerator { chunk_size: 1000 }),
+                "test prompt",
+                Arc::new(|progress| progress > 0.5),
+                &mut sink,
+            );
+            assert_eq!(result, Err(GenerationError::Aborted));
+            assert!(sink.retries.is_empty());
+            // Stopped in the middle of the second segment.
+            assert!(sink.audio.len() < 50_000);
+        }
+    }
+
+    #[test]
+    fn test_tail_rides_out_under_the_next_segment() {
+        let generate = |join| {
+            let config = ExtendedGenerationConfig {
+                target_duration: 50.0,
+                join,
+                ..Default::default()
+            };
+            let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+            let mut sink = MemorySink::new();
+            generator
+                .generate(
+                    Arc::new(DummyGenerator),
+                    "test prompt",
+                    Arc::new(|_| false),
+                    &mut sink,
+                )
+                .unwrap();
+            sink.into_inner()
+        };
+
+        // Crossfading two constant segments keeps the level constant.
+        let crossfaded = generate(JoinStyle::Crossfade);
+        assert_eq!(crossfaded[27_000], 0.5);
+        // Riding out sums what is left of the previous segment over the next one.
+        let ridden_out = generate(JoinStyle::TailRideOut { attack: 0.1 });
+        assert_eq!(ridden_out.len(), crossfaded.len());
+        assert!((ridden_out[26_050] - 0.5 * (0.975 + 0.5)).abs() < 1e-6);
+        assert!((ridden_out[27_000] - 0.75).abs() < 1e-6);
+        assert_eq!(ridden_out[28_500], 0.5);
+
+        let config = ExtendedGenerationConfig {
+            join: JoinStyle::TailRideOut { attack: 3.0 },
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    /// Produces a sine wave of a different frequency for each segment.
+    struct ToneGenerator;
+
+    impl SegmentGenerator for ToneGenerator {
+        fn generate_segment(
+            &self,
+            _prompt: &str,
+            duration: usize,
+            segment_index: usize,
+            _on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+            sink: &mut dyn AudioSink,
+        ) -> Result<(), String> {
+            let hz = 50.0 * (segment_index + 1) as f32;
+            let samples = (0..duration * 1000)
+                .map(|i| (2.0 * std::f32::consts::PI * hz * i as f32 / 1000.0).sin())
+                .collect::<Vec<_>>();
+            for chunk in samples.chunks(700) {
+                sink.push(chunk)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_overlapping_post_processing_does_not_change_the_result() {
+        let generate = |overlap_post| {
+            let config = ExtendedGenerationConfig {
+                target_duration: 70.0,
+                denoise: Some(DenoiseConfig::default()),
+                normalize: Some(Normalization::default()),
+                overlap_post,
+                ..Default::default()
+            };
+            let mut sink = MemorySink::new();
+            ExtendedAudioGenerator::new(config, 1000)
+                .unwrap()
+                .generate(
+                    Arc::new(ToneGenerator),
+                    "test prompt",
+                    Arc::new(|_| false),
+                    &mut sink,
+                )
+                .unwrap();
+            sink.into_inner()
+        };
+        assert_eq!(generate(true), generate(false));
+    }
+
+    /// Fails after being pushed `capacity` samples.
+    struct FullSink {
+        capacity: usize,
+    }
+
+    impl AudioSink for FullSink {
+        fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+            self.capacity = self
+                .capacity
+                .checked_sub(chunk.len())
+                .ok_or("The sink is full")?;
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_overlapped_processing_failures_stop_the_generator() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 70.0,
+            retry: RetryPolicy {
+                max_retries: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = ExtendedAudioGenerator::new(config, 1000)
+            .unwrap()
+            .generate(
+                Arc::new(ToneGenerator),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut FullSink { capacity: 1000 },
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "The sink is full");
+    }
+
+    #[test]
+    fn test_multiband_crossfade() {
+        let generate = |join| {
+            let config = ExtendedGenerationConfig {
+                target_duration: 50.0,
+                join,
+                ..Default::default()
+            };
+            let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+            let mut sink = MemorySink::new();
+            generator
+                .generate(
+                    Arc::new(ToneGenerator),
+                    "test prompt",
+                    Arc::new(|_| false),
+                    &mut sink,
+                )
+                .unwrap();
+            sink.into_inner()
+        };
+
+        // Bands that fade together add up to a plain crossfade.
+        let crossfaded = generate(JoinStyle::Crossfade);
+        let together = BandFade {
+            start: 0.0,
+            end: 1.0,
+        };
+        let multiband = generate(JoinStyle::Multiband(MultibandCrossfade {
+            low_mid_hz: 60.0,
+            mid_high_hz: 120.0,
+            low: together,
+            mid: together,
+            high: together,
+        }));
+        assert_eq!(multiband.len(), crossfaded.len());
+        for (a, b) in multiband.iter().zip(crossfaded.iter()) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+
+        // Fading the band of the second segment's tone first brings it in earlier.
+        let multiband = generate(JoinStyle::Multiband(MultibandCrossfade {
+            low_mid_hz: 60.0,
+            mid_high_hz: 120.0,
+            low: BandFade {
+                start: 0.9,
+                end: 1.0,
+            },
+            mid: BandFade {
+                start: 0.0,
+                end: 0.1,
+            },
+            high: together,
+        }));
+        assert_eq!(multiband.len(), crossfaded.len());
+        let energy = |audio: &[f32]| audio[26_400..26_600].iter().map(|s| s * s).sum::<f32>();
+        assert_ne!(energy(&multiband), energy(&crossfaded));
+        assert_eq!(&multiband[28_000..], &crossfaded[28_000..]);
+
+        let config = ExtendedGenerationConfig {
+            join: JoinStyle::Multiband(MultibandCrossfade {
+                low_mid_hz: 4000.0,
+                mid_high_hz: 250.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_mixes_transitions_over_joins() {
+        let transition = Transition {
+            boundary: 0,
+            style: TransitionStyle::CymbalSwell,
+            duration: 1.5,
+            gain: 0.5,
+        };
+        let generate = |transitions, generator: Arc<FlakyGenerator>| {
+            let config = ExtendedGenerationConfig {
+                target_duration: 50.0,
+                transitions,
+                ..Default::default()
+            };
+            let generator_config = ExtendedAudioGenerator::new(config, 1000).unwrap();
+            let mut sink = RetryRecorder::default();
+            generator_config
+                .generate(generator, "test prompt", Arc::new(|_| false), &mut sink)
+                .unwrap();
+            sink.audio
+        };
+        let plain = generate(vec![], Arc::new(FlakyGenerator::new(1, 0, 0)));
+        let mixed = generate(
+            vec![transition.clone()],
+            Arc::new(FlakyGenerator::new(1, 0, 0)),
+        );
+
+        // It peaks in the middle of the crossfade, at 27s.
+        let element = transition.synthesize(1000);
+        assert_eq!(mixed.len(), plain.len());
+        for (i, (a, b)) in mixed.iter().zip(&plain).enumerate() {
+            let expected = match i {
+                25_500..27_000 => b + element[i - 25_500],
+                _ => *b,
+            };
+            assert!((a - expected).abs() < 1e-6, "{i}: {a} != {expected}");
+        }
+
+        // Retrying the segment after the join does not mix it twice.
+        let retried = generate(vec![transition], Arc::new(FlakyGenerator::new(1, 1, 1000)));
+        assert_eq!(retried, mixed);
+    }
+
+    #[test]
+    fn test_gives_abrupt_renders_an_ending() {
+        let generate = |ending| {
+            let config = ExtendedGenerationConfig {
+                target_duration: 50.0,
+                ending,
+                ..Default::default()
+            };
+            let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+            let mut sink = MemorySink::new();
+            generator
+                .generate(
+                    Arc::new(ToneGenerator),
+                    "test prompt",
+                    Arc::new(|_| false),
+                    &mut sink,
+                )
+                .unwrap();
+            sink.into_inner()
+        };
+
+        let abrupt = generate(None);
+        assert_eq!(abrupt.len(), 50_000);
+        let ended = generate(Some(EndingConfig::default()));
+        // Some of the audio that would have been trimmed is kept for the decay.
+        assert!(ended.len() > 50_000 && ended.len() <= 52_000);
+        assert_eq!(ended[..40_000], abrupt[..40_000]);
+        assert!(ended[ended.len() - 250..].iter().all(|s| s.abs() < 0.01));
+    }
+
+    /// Produces a square wave that is quiet in even segments and loud in odd ones.
+    struct LevelGenerator;
+
+    impl SegmentGenerator for LevelGenerator {
+        fn generate_segment(
+            &self,
+            _prompt: &str,
+            duration: usize,
+            segment_index: usize,
+            _on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+            sink: &mut dyn AudioSink,
+        ) -> Result<(), String> {
+            let level = [0.05, 0.2][segment_index % 2];
+            let samples = (0..duration * 1000)
+                .map(|i| if i % 2 == 0 { level } else { -level })
+                .collect::<Vec<_>>();
+            for chunk in samples.chunks(700) {
+                sink.push(chunk)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_normalizes_segments() {
+        #[derive(Default)]
+        struct GainRecorder {
+            audio: Vec<f32>,
+            gains: Vec<SegmentGain>,
+        }
+
+        impl AudioSink for GainRecorder {
+            fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+                self.audio.extend(chunk);
+                Ok(())
+            }
+
+            fn finalize(&mut self) -> Result<(), String> {
+                Ok(())
+            }
+
+            fn normalized(&mut self, gain: &SegmentGain) {
+                self.gains.push(gain.clone());
+            }
+        }
+
+        let config = ExtendedGenerationConfig {
+            target_duration: 60.0,
+            normalize: Some(Normalization::default()),
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut sink = GainRecorder::default();
+        generator
+            .generate(
+                Arc::new(LevelGenerator),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut sink,
+            )
+            .unwrap();
+
+        assert_eq!(sink.audio.len(), 60_000);
+        let gains = sink
+            .gains
+            .iter()
+            .map(|g| g.gain_db.round())
+            .collect::<Vec<_>>();
+        assert_eq!(gains, vec![6.0, -6.0, 6.0]);
+        assert_eq!(
+            sink.gains.iter().map(|g| g.segment).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        // Both levels end up the same, so the crossfades keep it too.
+        assert!(sink.audio.iter().all(|s| (s.abs() - 0.1).abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_hands_over_the_generated_segments() {
+        #[derive(Default)]
+        struct SegmentRecorder {
+            segments: Vec<(usize, Vec<f32>)>,
+            markers: Vec<SegmentMarker>,
+        }
+
+        impl AudioSink for SegmentRecorder {
+            fn push(&mut self, _chunk: &[f32]) -> Result<(), String> {
+                Ok(())
+            }
+
+            fn finalize(&mut self) -> Result<(), String> {
+                Ok(())
+            }
+
+            fn generated(&mut self, segment: usize, audio: &[f32]) {
+                self.segments.push((segment, audio.to_vec()));
+            }
+
+            fn marked(&mut self, marker: &SegmentMarker) {
+                self.markers.push(marker.clone());
+            }
+        }
+
+        let config = ExtendedGenerationConfig {
+            target_duration: 60.0,
+            normalize: Some(Normalization::default()),
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut sink = SegmentRecorder::default();
+        generator
+            .generate(
+                Arc::new(LevelGenerator),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut sink,
+            )
+            .unwrap();
+
+        // Each segment as generated, before being normalized and crossfaded.
+        assert_eq!(
+            sink.segments.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        for (i, audio) in &sink.segments {
+            let level = [0.05, 0.2][i % 2];
+            assert_eq!(audio.len(), 28_000);
+            assert!(audio.iter().all(|s| s.abs() == level));
+        }
+        // Each segment takes over halfway through its 2s crossfade with the previous one.
+        assert_eq!(
+            sink.markers.iter().map(|m| m.start).collect::<Vec<_>>(),
+            vec![0, 27_000, 53_000]
+        );
+        assert_eq!(
+            sink.markers.iter().map(|m| m.crossfade).collect::<Vec<_>>(),
+            vec![0, 2000, 2000]
+        );
+    }
+
+    #[test]
+    fn test_generates_rejected_segments_again() {
+        /// Pushes the number of the call, so each attempt sounds different.
+        #[derive(Default)]
+        struct CountingGenerator(std::sync::Mutex<usize>);
+
+        impl SegmentGenerator for CountingGenerator {
+            fn generate_segment(
+                &self,
+                _prompt: &str,
+                duration: usize,
+                _segment_index: usize,
+                _on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+                sink: &mut dyn AudioSink,
+            ) -> Result<(), String> {
+                let mut calls = self.0.lock().unwrap();
+                *calls += 1;
+                sink.push(&vec![*calls as f32; duration * 1000])
+            }
+        }
+
+        /// Rejects the first audition of the second segment.
+        #[derive(Default)]
+        struct Auditioner {
+            audio: Vec<f32>,
+            auditions: Vec<(usize, Vec<f32>)>,
+        }
+
+        impl AudioSink for Auditioner {
+            fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+                self.audio.extend(chunk);
+                Ok(())
+            }
+
+            fn finalize(&mut self) -> Result<(), String> {
+                Ok(())
+            }
+
+            fn auditions(&self) -> bool {
+                true
+            }
+
+            fn audition(
+                &mut self,
+                segment: usize,
+                audio: &[f32],
+            ) -> Result<AuditionVerdict, String> {
+                // Nothing of an auditioned segment is released before it is accepted.
+                assert!(self.audio.len() <= segment * 26_000);
+                let rejected = self.auditions.iter().any(|(s, _)| *s == 1);
+                self.auditions.push((segment, audio.to_vec()));
+                match segment == 1 && !rejected {
+                    true => Ok(AuditionVerdict::Reject),
+                    false => Ok(AuditionVerdict::Accept),
+                }
+            }
+        }
+
+        let config = ExtendedGenerationConfig {
+            target_duration: 60.0,
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut sink = Auditioner::default();
+        generator
+            .generate(
+                Arc::new(CountingGenerator::default()),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut sink,
+            )
+            .unwrap();
+
+        assert_eq!(
+            sink.auditions.iter().map(|(s, _)| *s).collect::<Vec<_>>(),
+            vec![0, 1, 1, 2]
+        );
+        // Segments are auditioned from the beginning of their crossfade, as stitched.
+        let (_, rejected) = &sink.auditions[1];
+        assert_eq!(rejected.len(), 28_000);
+        assert_eq!(rejected[0], 1.0);
+        assert!(rejected[1000] > 1.0 && rejected[1000] < 2.0);
+        assert_eq!(rejected[27_999], 2.0);
+        assert_eq!(sink.audio.len(), 60_000);
+        assert_eq!(sink.audio[10_000], 1.0);
+        assert_eq!(sink.audio[40_000], 3.0);
+        assert_eq!(sink.audio[59_000], 4.0);
+    }
+
+    #[test]
+    fn test_denoises_segments_in_place() {
+        let generate = |denoise| {
+            let config = ExtendedGenerationConfig {
+                target_duration: 60.0,
+                denoise,
+                ..Default::default()
+            };
+            let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+            let mut sink = MemorySink::new();
+            generator
+                .generate(
+                    Arc::new(ToneGenerator),
+                    "test prompt",
+                    Arc::new(|_| false),
+                    &mut sink,
+                )
+                .unwrap();
+            sink.into_inner()
+        };
+
+        // The delay of the denoiser does not shift the segments.
+        let plain = generate(None);
+        let untouched = generate(Some(DenoiseConfig { strength: 0.0 }));
+        assert_eq!(untouched.len(), plain.len());
+        for (a, b) in untouched.iter().zip(&plain) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+        // Held tones end up under the noise floor as it rises, so they are turned down.
+        let denoised = generate(Some(DenoiseConfig { strength: 1.0 }));
+        assert_eq!(denoised.len(), plain.len());
+        let energy = |audio: &[f32]| audio.iter().map(|s| s * s).sum::<f32>();
+        assert!(energy(&denoised) < energy(&plain) / 2.0);
+    }
+
+    #[test]
+    fn test_previews_joins() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 80.0,
+            join: JoinStyle::TailRideOut { attack: 0.5 },
+            transitions: vec![Transition {
+                boundary: 1,
+                style: TransitionStyle::Riser,
+                duration: 3.0,
+                gain: 0.5,
+            }],
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config.clone(), 1000).unwrap();
+        let mut full = MemorySink::new();
+        generator
+            .generate(
+                Arc::new(ToneGenerator),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut full,
+            )
+            .unwrap();
+        let full = full.into_inner();
+        let segments = (0..config.num_segments())
+            .map(|i| {
+                let mut sink = MemorySink::new();
+                ToneGenerator
+                    .generate_segment("", 28, i, Box::new(|_| false), &mut sink)
+                    .unwrap();
+                sink.into_inner()
+            })
+            .collect::<Vec<_>>();
+
+        let previews = generator.preview_joins(&segments, 3.0);
+        assert_eq!(previews.len(), segments.len() - 1);
+        for (i, preview) in previews.iter().enumerate() {
+            assert_eq!(preview.boundary, i);
+            // 3 seconds on each side of the 2 seconds crossfade.
+            assert_eq!(preview.audio.len(), 8000);
+            // The last join can be past the target duration.
+            for (a, b) in preview.audio.iter().zip(&full[preview.start..]) {
+                assert!((a - b).abs() < 1e-5, "{a} != {b}");
+            }
+        }
+        assert_eq!(previews[0].start, 23_000);
+        assert_eq!(previews[1].start, 49_000);
+    }
+
+    #[test]
+    fn test_applies_intro_and_outro() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 70.0,
+            intro_outro: IntroOutro::preset("gentle").unwrap(),
+            ending: Some(EndingConfig::default()),
+            // The gate acts on the quiet start of the fade in.
+            effects: Effects {
+                noise_gate: Some(NoiseGateConfig::default()),
+                ..Default::default()
+            },
+            retry: RetryPolicy {
+                initial_backoff: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut full = MemorySink::new();
+        generator
+            .generate(
+                Arc::new(DummyGenerator),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut full,
+            )
+            .unwrap();
+        let full = full.into_inner();
+
+        // The outro replaces the automatic ending, so the duration is kept.
+        assert_eq!(full.len(), 70_000);
+        assert_eq!(full[0], 0.0);
+        assert!(full[2000] > 0.0 && full[2000] < 0.5);
+        assert_eq!(full[35_000], 0.5);
+        assert!(full[67_000] > 0.0 && full[67_000] < 0.5);
+        assert!(full[69_999].abs() < 1e-6);
+
+        // Resuming does not apply the intro nor the effects again to the audio that was already treated.
+        let mut stopped = StoppingSink {
+            audio: vec![],
+            segments_left: 1,
+        };
+        let _ = generator.generate(
+            Arc::new(DummyGenerator),
+            "test prompt",
+            Arc::new(|_| false),
+            &mut stopped,
+        );
+        let mut resumed = MemorySink::new();
+        generator
+            .resume(
+                Arc::new(DummyGenerator),
+                "test prompt",
+                1,
+                &stopped.audio,
+                Arc::new(|_| false),
+                &mut resumed,
+            )
+            .unwrap();
+        assert_eq!(resumed.into_inner(), full);
+    }
+
+    #[test]
+    fn test_throttles_progress() {
+        let generate = |progress| {
+            let config = ExtendedGenerationConfig {
+                target_duration: 70.0,
+                progress,
+                ..Default::default()
+            };
+            let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+            let reported = Arc::new(Mutex::new(vec![]));
+            let reported_clone = reported.clone();
+            generator
+                .generate(
+                    Arc::new(ChunkedGenerator { chunk_size: 100 }),
+                    "test prompt",
+                    Arc::new(move |p| {
+                        reported_clone.lock().unwrap().push(p);
+                        false
+                    }),
+                    &mut MemorySink::new(),
+                )
+                .unwrap();
+            let reported = reported.lock().unwrap().clone();
+            reported
+        };
+
+        // 3 segments of 280 chunks each.
+        assert_eq!(generate(ProgressThrottle::none()).len(), 840);
+
+        let reported = generate(ProgressThrottle {
+            min_interval: Duration::ZERO,
+            min_delta: 0.1,
+        });
+        assert!(reported.len() <= 11, "{reported:?}");
+        assert!(reported
+            .windows(2)
+            .all(|w| w[1] - w[0] >= 0.1 || w[1] == 1.0));
+        assert_eq!(reported.last(), Some(&1.0));
+
+        // The completion is reported even if it comes right after another report.
+        let reported = generate(ProgressThrottle {
+            min_interval: Duration::from_secs(3600),
+            min_delta: 0.0,
+        });
+        assert_eq!(reported.len(), 2);
+        assert_eq!(reported[1], 1.0);
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+        let backoffs = (1..=4)
+            .map(|r| policy.backoff(r).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, vec![1, 2, 4, 5]);
+    }
+
+    /// Hangs in the middle of the first attempt of the second segment.
+    #[derive(Default)]
+    struct StallingGenerator {
+        stalled: std::sync::atomic::AtomicBool,
+    }
+
+    impl SegmentGenerator for StallingGenerator {
+        fn generate_segment(
+            &self,
+            _prompt: &str,
+            duration: usize,
+            segment_index: usize,
+            on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+            sink: &mut dyn AudioSink,
+        ) -> Result<(), String> {
+            let samples = vec![segment_index as f32; duration * 1000];
+            for (i, chunk) in samples.chunks(1000).enumerate() {
+                if segment_index == 1
+                    && i == 14
+                    && !self.stalled.swap(true, std::sync::atomic::Ordering::SeqCst)
+                {
+                    std::thread::sleep(Duration::from_millis(500));
+                }
+                sink.push(chunk)?;
+                on_progress((i + 1) as f32 / duration as f32);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_retries_segments_that_get_stuck() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 70.0,
+            retry: RetryPolicy {
+                max_retries: 1,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            watchdog_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut sink = RetryRecorder::default();
+        generator
+            .generate(
+                Arc::new(StallingGenerator::default()),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut sink,
+            )
+            .unwrap();
+
+        assert_eq!(sink.audio.len(), 70_000);
+        assert_eq!(sink.retries.len(), 1);
+        assert_eq!(
+            sink.retries[0].error,
+            "Segment 2 got stuck, no progress for 0.1s after reaching 50% and 14000 samples"
+        );
+    }
+
+    /// Waits to be terminated in the first attempt of the first segment, then reports
+    /// whether it was its thread that got terminated and whether it had to stop.
+    struct TerminableGenerator {
+        stalled: std::sync::atomic::AtomicBool,
+        terminate: Sender<ThreadId>,
+        terminated: Mutex<std::sync::mpsc::Receiver<ThreadId>>,
+        observed: Sender<(bool, bool)>,
+    }
+
+    impl SegmentGenerator for TerminableGenerator {
+        fn generate_segment(
+            &self,
+            _prompt: &str,
+            duration: usize,
+            _segment_index: usize,
+            on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+            sink: &mut dyn AudioSink,
+        ) -> Result<(), String> {
+            if !self.stalled.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                let worker = self
+                    .terminated
+                    .lock()
+                    .unwrap()
+                    .recv_timeout(Duration::from_secs(5));
+                let aborted = on_progress(0.5);
+                let _ = self
+                    .observed
+                    .send((worker == Ok(std::thread::current().id()), aborted));
+                return Err("Terminated".to_string());
+            }
+            sink.push(&vec![0.0; duration * 1000])?;
+            on_progress(1.0);
+            Ok(())
+        }
+
+        fn terminate(&self, worker: ThreadId) {
+            self.terminate.send(worker).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_terminates_segments_that_get_stuck() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 30.0,
+            retry: RetryPolicy {
+                max_retries: 1,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            watchdog_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (terminate, terminated) = channel();
+        let (observed, observations) = channel();
+        let generator = ExtendedAudioGenerator::new(config, 1000).unwrap();
+        let mut sink = RetryRecorder::default();
+        generator
+            .generate(
+                Arc::new(TerminableGenerator {
+                    stalled: Default::default(),
+                    terminate,
+                    terminated: Mutex::new(terminated),
+                    observed,
+                }),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut sink,
+            )
+            .unwrap();
+
+        assert_eq!(sink.retries.len(), 1);
+        assert_eq!(
+            sink.retries[0].error,
+            "Segment 1 got stuck, no progress for 0.1s after reaching 0% and 0 samples"
+        );
+        // The abandoned worker was the one terminated, and it sees that it has to stop.
+        let observed = observations.recv_timeout(Duration::from_secs(5));
+        assert_eq!(observed, Ok((true, true)));
+    }
+
+    /// Stops the generation after the given number of segments by failing to flush.
+    struct StoppingSink {
+        audio: Vec<f32>,
+        segments_left: usize,
+    }
+
+    impl AudioSink for StoppingSink {
+        fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+            self.audio.extend(chunk);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), String> {
+            self.segments_left -= 1;
+            match self.segments_left {
+                0 => Err("Stopped".to_string()),
+                _ => Ok(()),
+            }
+        }
+
+        fn finalize(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stopping_between_segments_hands_over_their_audio() {
+        let generator = retrying_generator(0);
+        let mut sink = StoppingSink {
+            audio: vec![],
+            segments_left: 2,
+        };
+        let result = generator.generate(
+            Arc::new(ChunkedGenerator { chunk_size: 1000 }),
+            "test prompt",
+            Arc::new(|_| false),
+            &mut sink,
+        );
+
+        assert_eq!(result, Err(GenerationError::Failed("Stopped".to_string())));
+        // Two crossfaded segments, including the overlap the next one would have faded over.
+        assert_eq!(sink.audio.len(), 28_000 + 26_000);
+        assert_eq!(sink.audio.last(), Some(&1.0));
+    }
+
+    #[test]
+    fn test_resuming_produces_the_same_audio() {
+        let generator = retrying_generator(0);
+        let segments = Arc::new(ChunkedGenerator { chunk_size: 1000 });
+        let mut full = MemorySink::new();
+        generator
+            .generate(
+                segments.clone(),
+                "test prompt",
+                Arc::new(|_| false),
+                &mut full,
+            )
+            .unwrap();
+        let full = full.into_inner();
+
+        let mut stopped = StoppingSink {
+            audio: vec![],
+            segments_left: 1,
+        };
+        let _ = generator.generate(
+            segments.clone(),
+            "test prompt",
+            Arc::new(|_| false),
+            &mut stopped,
+        );
+        let mut resumed = MemorySink::new();
+        generator
+            .resume(
+                segments.clone(),
+                "test prompt",
+                1,
+                &stopped.audio,
+                Arc::new(|_| false),
+                &mut resumed,
+            )
+            .unwrap();
+        assert_eq!(resumed.into_inner(), full);
+
+        // Without the overlap of the last segment, the target duration is still reached.
+        let mut resumed = MemorySink::new();
+        generator
+            .resume(
+                segments,
+                "test prompt",
+                2,
+                &full[..50_000],
+                Arc::new(|_| false),
+                &mut resumed,
+            )
+            .unwrap();
+        assert_eq!(resumed.into_inner().len(), 70_000);
+    }
+
+    #[test]
+    fn test_crossfade() {
+        let segment1 = vec![1.0; 10000];
+        let segment2 = vec![0.0; 10000];
+
+        let result = crossfade(&segment1, &segment2, 2000, 1000);
+
+        // Check that crossfade happened
+        assert!(result.len() > 10000);
 
-        let result = generator.crossfade_segments(segment1, segment2, 2000, 1000);
-        
-        // Check that crossfade happened
-        assert!(result.len() > 10000);
-        
         // Values in crossfade region should be between 0.0 and 1.0
         let crossfade_start = 10000 - 1000;
         for i in 0..1000 {
             let val = result[crossfade_start + i];
-            assert!(val >= 0.0 && val <= 1.0, "Value at crossfade should be blended: {}", val);
+            assert!(
+                (0.0..=1.0).contains(&val),
+                "Value at crossfade should be blended: {}",
+                val
+            );
         }
     }
 }
@@ -0,0 +1,213 @@
+//! Acoustic fingerprints in the style of Chromaprint, for finding renders that sound
+//! nearly the same, like the ones generated again with the same seed. Each frame of about
+//! a quarter of a second gets 32 bits saying how the energy of the 12 pitch classes
+//! compares between them and with the frame before, which survives small changes in
+//! volume, noise and encoding but not a different melody or harmony.
+
+use std::f32::consts::PI;
+
+/// Rate the audio is fingerprinted at, pitches above 4kHz say little about the harmony.
+const RATE: usize = 8000;
+const FRAME: usize = 2048;
+const HOP: usize = 1024;
+/// MIDI notes folded into the pitch classes, from C3 to C7.
+const NOTES: std::ops::Range<usize> = 48..96;
+/// Frames two fingerprints are shifted by at most when compared, for renders whose start
+/// is slightly off.
+const MAX_OFFSET: isize = 8;
+
+/// Fingerprints a piece given in order, one chunk at a time.
+#[derive(Clone, Debug)]
+pub struct Fingerprinter {
+    /// Input samples averaged into each sample at [RATE].
+    decimation: usize,
+    /// Rate after decimating, which is [RATE] unless the input rate is not a multiple.
+    rate: f32,
+    sum: f32,
+    summed: usize,
+    frame: Vec<f32>,
+    last_chroma: Option<[f32; 12]>,
+    fingerprint: Vec<u32>,
+}
+
+impl Fingerprinter {
+    pub fn new(sample_rate: usize) -> Self {
+        let decimation = (sample_rate / RATE).max(1);
+        Self {
+            decimation,
+            rate: sample_rate as f32 / decimation as f32,
+            sum: 0.0,
+            summed: 0,
+            frame: Vec::with_capacity(FRAME),
+            last_chroma: None,
+            fingerprint: vec![],
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[f32]) {
+        for sample in chunk {
+            self.sum += sample;
+            self.summed += 1;
+            if self.summed < self.decimation {
+                continue;
+            }
+            self.frame.push(self.sum / self.summed as f32);
+            self.sum = 0.0;
+            self.summed = 0;
+            if self.frame.len() == FRAME {
+                let chroma = chroma(&self.frame, self.rate);
+                if let Some(last) = self.last_chroma {
+                    self.fingerprint.push(subfingerprint(&last, &chroma));
+                }
+                self.last_chroma = Some(chroma);
+                self.frame.drain(..HOP);
+            }
+        }
+    }
+
+    /// The fingerprint of everything pushed, one value per frame after the first.
+    pub fn finish(self) -> Vec<u32> {
+        self.fingerprint
+    }
+}
+
+/// The fingerprint of a whole piece.
+pub fn fingerprint(audio: &[f32], sample_rate: usize) -> Vec<u32> {
+    let mut fingerprinter = Fingerprinter::new(sample_rate);
+    fingerprinter.push(audio);
+    fingerprinter.finish()
+}
+
+/// Energy of each pitch class in a frame, measured at each note with the Goertzel
+/// algorithm like [crate::audio::loop_points] does for its spectrum.
+fn chroma(frame: &[f32], rate: f32) -> [f32; 12] {
+    let n = frame.len() as f32;
+    let mut chroma = [0.0; 12];
+    for note in NOTES {
+        let hz = 440.0 * 2f32.powf((note as f32 - 69.0) / 12.0);
+        let coeff = 2.0 * (2.0 * PI * hz / rate).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for (i, x) in frame.iter().enumerate() {
+            let hann = 0.5 - 0.5 * (2.0 * PI * i as f32 / n).cos();
+            let s = x * hann + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s;
+        }
+        chroma[note % 12] += (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0);
+    }
+    chroma
+}
+
+/// 12 bits for which pitch classes got louder since the last frame, 12 for which ones
+/// are louder than the next semitone, and 8 for which ones are louder than their fifth.
+fn subfingerprint(last: &[f32; 12], chroma: &[f32; 12]) -> u32 {
+    let mut bits = 0u32;
+    for b in 0..12 {
+        bits |= u32::from(chroma[b] > last[b]) << b;
+        bits |= u32::from(chroma[b] > chroma[(b + 1) % 12]) << (12 + b);
+        if b < 8 {
+            bits |= u32::from(chroma[b] > chroma[(b + 7) % 12]) << (24 + b);
+        }
+    }
+    bits
+}
+
+/// How alike two fingerprints are, from 0 to 1, as the share of equal bits where they
+/// overlap best. Unrelated pieces score around 0.5, and the same piece close to 1.
+pub fn similarity(a: &[u32], b: &[u32]) -> f32 {
+    let min_overlap = (a.len().min(b.len()) / 2).max(1);
+    (-MAX_OFFSET..=MAX_OFFSET)
+        .filter_map(|offset| {
+            let (a, b) = match offset {
+                offset if offset < 0 => (a, b.get(offset.unsigned_abs()..)?),
+                offset => (a.get(offset as usize..)?, b),
+            };
+            let overlap = a.len().min(b.len());
+            if overlap < min_overlap {
+                return None;
+            }
+            let differing = a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum::<u32>();
+            Some(1.0 - differing as f32 / (32 * overlap) as f32)
+        })
+        .fold(0.0, f32::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chords changing every half a second, picked from `progression`, with some noise.
+    fn music(progression: &[[f32; 3]], secs: usize, noise: f32, seed: u32) -> Vec<f32> {
+        let rate = 32000.0;
+        let mut state = seed;
+        (0..secs * 32000)
+            .map(|n| {
+                let t = n as f32 / rate;
+                let chord = progression[(t * 2.0) as usize % progression.len()];
+                let tones = chord
+                    .iter()
+                    .map(|freq| (t * freq * std::f32::consts::TAU).sin())
+                    .sum::<f32>();
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let white = (state >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                0.1 * tones + noise * white
+            })
+            .collect()
+    }
+
+    const CHORDS: [[f32; 3]; 4] = [
+        [261.6, 329.6, 392.0],
+        [220.0, 261.6, 329.6],
+        [174.6, 220.0, 261.6],
+        [196.0, 246.9, 293.7],
+    ];
+    const OTHER_CHORDS: [[f32; 3]; 4] = [
+        [293.7, 370.0, 440.0],
+        [415.3, 493.9, 311.1],
+        [233.1, 277.2, 349.2],
+        [185.0, 466.2, 155.6],
+    ];
+
+    #[test]
+    fn fingerprints_the_same_in_chunks() {
+        let audio = music(&CHORDS, 4, 0.01, 1);
+        let mut fingerprinter = Fingerprinter::new(32000);
+        for chunk in audio.chunks(777) {
+            fingerprinter.push(chunk);
+        }
+        let chunked = fingerprinter.finish();
+        assert_eq!(chunked, fingerprint(&audio, 32000));
+        // 8000 samples a second, one frame every 1024 of them.
+        assert_eq!(chunked.len(), (4 * 8000 - FRAME) / HOP);
+    }
+
+    #[test]
+    fn tells_near_duplicates_from_different_pieces() {
+        let original = fingerprint(&music(&CHORDS, 10, 0.01, 1), 32000);
+        assert_eq!(similarity(&original, &original), 1.0);
+
+        let noisier = fingerprint(&music(&CHORDS, 10, 0.03, 2), 32000);
+        let quieter = music(&CHORDS, 10, 0.01, 1)
+            .iter()
+            .map(|s| s * 0.5)
+            .collect::<Vec<_>>();
+        let delayed = [vec![0.0; 3000], music(&CHORDS, 10, 0.01, 1)].concat();
+        for copy in [
+            noisier,
+            fingerprint(&quieter, 32000),
+            fingerprint(&delayed, 32000),
+        ] {
+            let score = similarity(&original, &copy);
+            assert!(score > 0.85, "{score}");
+        }
+
+        let other = fingerprint(&music(&OTHER_CHORDS, 10, 0.01, 1), 32000);
+        let score = similarity(&original, &other);
+        assert!(score < 0.75, "{score}");
+        assert_eq!(similarity(&original, &[]), 0.0);
+    }
+}
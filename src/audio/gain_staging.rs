@@ -0,0 +1,167 @@
+//! Normalization of the segments of extended renders to a common loudness before they are
+//! crossfaded, so that a quiet segment does not get swamped by a loud one at their join.
+
+use rayon::prelude::*;
+use tracing::debug_span;
+
+use crate::audio::audio_sink::AudioSink;
+use crate::audio::dsp::{db_to_gain, gain_to_db, rms};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Normalization {
+    /// RMS level each segment is brought to, in dBFS.
+    pub target_rms_db: f32,
+    /// Segments are never turned up or down by more than these decibels.
+    pub max_gain_db: f32,
+    /// Seconds at the beginning of each segment whose level decides its gain. They are
+    /// held back until the gain is known.
+    pub analysis_secs: f32,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self {
+            target_rms_db: -20.0,
+            max_gain_db: 12.0,
+            analysis_secs: 4.0,
+        }
+    }
+}
+
+/// The gain a segment got when normalized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentGain {
+    pub segment: usize,
+    pub gain_db: f32,
+}
+
+/// Normalizes the audio of one segment as it streams.
+pub struct Normalizer {
+    target_rms: f32,
+    max_gain: f32,
+    analysis_samples: usize,
+    held: Vec<f32>,
+    gain: Option<f32>,
+}
+
+impl Normalizer {
+    pub fn new(config: &Normalization, sample_rate: usize) -> Self {
+        Self {
+            target_rms: db_to_gain(config.target_rms_db),
+            max_gain: db_to_gain(config.max_gain_db.abs()),
+            analysis_samples: ((config.analysis_secs * sample_rate as f32) as usize).max(1),
+            held: vec![],
+            gain: None,
+        }
+    }
+
+    /// The gain of the segment in decibels, once it is known.
+    pub fn gain_db(&self) -> Option<f32> {
+        self.gain.map(gain_to_db)
+    }
+
+    /// Starts the segment over, for generating it again. A gain that was already decided
+    /// is kept, so that all the attempts at a segment sound the same.
+    pub fn restart(&mut self) {
+        self.held.clear();
+    }
+
+    /// Feeds the next chunk of the segment, returning the audio that got normalized.
+    pub fn process(&mut self, chunk: &[f32]) -> Vec<f32> {
+        if let Some(gain) = self.gain {
+            return chunk.iter().map(|s| s * gain).collect();
+        }
+        self.held.extend_from_slice(chunk);
+        if self.held.len() < self.analysis_samples {
+            return vec![];
+        }
+        self.finish()
+    }
+
+    /// Returns the rest of the normalized segment, deciding its gain with the audio
+    /// available if the segment was shorter than the analysed part.
+    pub fn finish(&mut self) -> Vec<f32> {
+        let held = std::mem::take(&mut self.held);
+        let gain = *self.gain.get_or_insert_with(|| match rms(&held) {
+            0.0 => 1.0,
+            rms => (self.target_rms / rms).clamp(1.0 / self.max_gain, self.max_gain),
+        });
+        held.into_par_iter().map(|s| s * gain).collect()
+    }
+}
+
+/// Normalizes the audio pushed into it before passing it to another sink.
+pub struct NormalizingSink<'a> {
+    pub inner: &'a mut dyn AudioSink,
+    pub normalizer: &'a mut Normalizer,
+}
+
+impl AudioSink for NormalizingSink<'_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let normalized = debug_span!("normalize").in_scope(|| self.normalizer.process(chunk));
+        if normalized.is_empty() {
+            return Ok(());
+        }
+        self.inner.push(&normalized)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    /// Pushes the audio held back until its gain was known, and finalizes the inner sink.
+    fn finalize(&mut self) -> Result<(), String> {
+        let rest = self.normalizer.finish();
+        self.inner.push(&rest)?;
+        self.inner.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(normalizer: &mut Normalizer, audio: &[f32], chunk: usize) -> Vec<f32> {
+        let mut out = audio
+            .chunks(chunk)
+            .flat_map(|chunk| normalizer.process(chunk))
+            .collect::<Vec<_>>();
+        out.extend(normalizer.finish());
+        out
+    }
+
+    #[test]
+    fn brings_segments_to_the_target_level() {
+        let config = Normalization {
+            target_rms_db: -20.0,
+            max_gain_db: 12.0,
+            analysis_secs: 1.0,
+        };
+        // A square wave at -26dBFS is turned up by 6dB.
+        let quiet = (0..3000)
+            .map(|i| if i % 2 == 0 { 0.05 } else { -0.05 })
+            .collect::<Vec<_>>();
+        let mut normalizer = Normalizer::new(&config, 1000);
+        let out = normalize(&mut normalizer, &quiet, 300);
+        assert_eq!(out.len(), quiet.len());
+        assert!(out.iter().all(|s| (s.abs() - 0.1).abs() < 1e-3));
+        assert!((normalizer.gain_db().unwrap() - 6.0).abs() < 0.1);
+
+        // Far from the target, the gain is limited.
+        let mut normalizer = Normalizer::new(&config, 1000);
+        let out = normalize(&mut normalizer, &[0.001; 500], 300);
+        assert_eq!(out.len(), 500);
+        assert!((normalizer.gain_db().unwrap() - 12.0).abs() < 1e-3);
+
+        let mut normalizer = Normalizer::new(&config, 1000);
+        assert_eq!(normalize(&mut normalizer, &[0.0; 500], 300), vec![0.0; 500]);
+    }
+
+    #[test]
+    fn keeps_the_gain_when_restarting() {
+        let mut normalizer = Normalizer::new(&Normalization::default(), 1000);
+        normalize(&mut normalizer, &[0.05; 5000], 1000);
+        normalizer.restart();
+        assert!((normalizer.process(&[0.05])[0] - 0.1).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,135 @@
+//! Layered game music, several intensities of the same piece that play at the same time
+//! and are faded up and down by game audio middleware as the action changes. The layers
+//! are stretched to one tempo with their beats lined up, cut to the same length, and
+//! share the same loop points.
+
+use crate::audio::dj_mix::stretch_ratio;
+use crate::audio::dsp::mix;
+use crate::audio::ending::{estimate_beat, first_beat, DEFAULT_BEAT};
+use crate::audio::loop_points::{find_loop_points, LoopConfig, LoopPoints};
+use crate::audio::time_stretch::time_stretch;
+
+/// The name of each layer and how it is described in its prompt, from the calmest one.
+pub const INTENSITIES: [(&str, &str); 3] = [
+    ("calm", "calm, sparse, soft"),
+    ("medium", "steady, moderate energy"),
+    ("combat", "intense, driving, full energy"),
+];
+/// Seconds each layer is generated longer than the piece, for lining up its beats.
+pub const SLACK_SECS: usize = 2;
+
+/// The prompt of each layer in [INTENSITIES].
+pub fn layer_prompts(prompt: &str) -> Vec<String> {
+    INTENSITIES
+        .iter()
+        .map(|(_, description)| format!("{prompt}, {description}"))
+        .collect()
+}
+
+/// The layers, sample-aligned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layers {
+    pub layers: Vec<Vec<f32>>,
+    /// The tempo of the first layer, which the others were stretched to, if one was
+    /// detected.
+    pub bpm: Option<f32>,
+    /// Where all the layers loop the most seamlessly together, if they are long enough.
+    pub loop_points: Option<LoopPoints>,
+}
+
+/// Lines up the beats of `layers` with the ones of the first, starting all of them on
+/// their first beat, and cuts them to `len` samples or to the shortest one.
+pub fn align_layers(
+    layers: &[Vec<f32>],
+    len: usize,
+    config: &LoopConfig,
+    sample_rate: usize,
+) -> Result<Layers, String> {
+    if layers.is_empty() {
+        return Err("There are no layers to align".to_string());
+    }
+    let detected = estimate_beat(&layers[0], sample_rate);
+    let beat = detected.unwrap_or(DEFAULT_BEAT);
+    let mut aligned = vec![];
+    for layer in layers {
+        let layer_beat = estimate_beat(layer, sample_rate);
+        let layer = time_stretch(layer, stretch_ratio(beat, layer_beat), sample_rate);
+        let first = match layer_beat {
+            Some(_) => first_beat(&layer, beat, sample_rate),
+            None => 0,
+        };
+        aligned.push(layer[first..].to_vec());
+    }
+    let len = aligned.iter().map(Vec::len).min().unwrap_or(0).min(len);
+    aligned.iter_mut().for_each(|layer| layer.truncate(len));
+
+    // The loop must be seamless in every mix of the layers, so it is looked for in all of
+    // them at once.
+    let mut sum = vec![0.0; len];
+    for layer in &aligned {
+        mix(&mut sum, layer, 1.0 / aligned.len() as f32);
+    }
+    Ok(Layers {
+        loop_points: find_loop_points(&sum, sample_rate, config),
+        layers: aligned,
+        bpm: detected.map(|beat| 60.0 / beat),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    const RATE: usize = 8000;
+
+    /// Clicks every `beat` seconds over a tone, after `lead_in` seconds of the tone.
+    fn layer(secs: f32, beat: f32, lead_in: f32, click: f32) -> Vec<f32> {
+        let period = (beat * RATE as f32) as usize;
+        let lead_in = (lead_in * RATE as f32) as usize;
+        (0..(secs * RATE as f32) as usize)
+            .map(|i| {
+                let tone = 0.1 * (2.0 * PI * 220.0 * (i % RATE) as f32 / RATE as f32).sin();
+                match i.checked_sub(lead_in) {
+                    Some(i) => (-((i % period) as f32) / 50.0).exp() * click + tone,
+                    None => tone,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aligns_the_beats_of_the_layers() {
+        let layers = [
+            layer(12.0, 0.5, 0.0, 0.2),
+            layer(12.0, 0.5, 0.3, 0.4),
+            layer(12.0, 0.55, 0.1, 0.6),
+        ];
+        let config = LoopConfig {
+            min_secs: 2.0,
+            max_secs: 6.0,
+            ..Default::default()
+        };
+        let aligned = align_layers(&layers, 10 * RATE, &config, RATE).unwrap();
+
+        assert!(
+            (aligned.bpm.unwrap() - 120.0).abs() < 3.0,
+            "{:?}",
+            aligned.bpm
+        );
+        assert_eq!(aligned.layers.len(), 3);
+        for layer in &aligned.layers {
+            assert_eq!(layer.len(), 10 * RATE);
+            // The beats of every layer fall on the ones of the first.
+            let first = first_beat(&layer[4 * RATE..], 0.5, RATE) % (RATE / 2);
+            assert!(first.min(RATE / 2 - first) <= 80, "{first}");
+        }
+        assert!(aligned.loop_points.is_some());
+        assert_eq!(
+            layer_prompts("chiptune")[2],
+            "chiptune, intense, driving, full energy"
+        );
+        assert!(align_layers(&[], RATE, &config, RATE).is_err());
+    }
+}
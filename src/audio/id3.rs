@@ -0,0 +1,170 @@
+//! ID3v2.4 tags, which media players and DAWs read the title and the cover art of WAV and
+//! AIFF files from.
+
+/// An embedded image, like the cover art of a render.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Picture {
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    /// Recognizes PNG, JPEG, GIF and WebP images from their first bytes.
+    pub fn sniff(data: Vec<u8>) -> Option<Self> {
+        let mime = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            "image/png"
+        } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            "image/jpeg"
+        } else if data.starts_with(b"GIF8") {
+            "image/gif"
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            "image/webp"
+        } else {
+            return None;
+        };
+        Some(Self {
+            mime: mime.to_string(),
+            data,
+        })
+    }
+
+    pub fn extension(&self) -> &str {
+        match self.mime.as_str() {
+            "image/jpeg" => "jpg",
+            mime => mime.strip_prefix("image/").unwrap_or("bin"),
+        }
+    }
+}
+
+/// The ID3 text frames carrying the INFO tags of a WAV file. Tags without an ID3
+/// counterpart are left out.
+pub fn frames_from_info(tags: &[([u8; 4], String)]) -> Vec<([u8; 4], String)> {
+    tags.iter()
+        .filter_map(|(id, value)| {
+            let id = match id {
+                b"INAM" => *b"TIT2",
+                b"IART" => *b"TPE1",
+                b"ICOP" => *b"TCOP",
+                b"ISFT" => *b"TSSE",
+                _ => return None,
+            };
+            Some((id, value.clone()))
+        })
+        .collect()
+}
+
+/// An ID3v2.4 tag with UTF-8 text frames like `TIT2` for the title, and `picture` as the
+/// front cover.
+pub fn id3_tag(text: &[([u8; 4], String)], picture: Option<&Picture>) -> Vec<u8> {
+    let mut frames = vec![];
+    for (id, value) in text {
+        let mut data = vec![3];
+        data.extend(value.as_bytes());
+        push_frame(&mut frames, id, &data);
+    }
+    if let Some(picture) = picture {
+        let mut data = vec![3];
+        data.extend(picture.mime.as_bytes());
+        data.push(0);
+        // A front cover, without a description.
+        data.extend([3, 0]);
+        data.extend(&picture.data);
+        push_frame(&mut frames, b"APIC", &data);
+    }
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend(syncsafe(frames.len()));
+    tag.extend(frames);
+    tag
+}
+
+/// The front cover in an ID3v2.4 tag, or else its first picture.
+pub fn picture(tag: &[u8]) -> Option<Picture> {
+    if !tag.starts_with(b"ID3\x04") {
+        return None;
+    }
+    let end = (10 + unsyncsafe(tag.get(6..10)?)).min(tag.len());
+    let mut rest = &tag[10..end];
+    let mut pictures = vec![];
+    while rest.len() >= 10 && rest[0] != 0 {
+        let len = unsyncsafe(&rest[4..8]);
+        let data = rest.get(10..10 + len)?;
+        if &rest[..4] == b"APIC" {
+            let (mime, after) = split_at_nul(data.get(1..)?)?;
+            let kind = *after.first()?;
+            // The description ends with one nul in UTF-8 and ISO-8859-1, two in UTF-16.
+            let nul = if data[0] == 1 || data[0] == 2 { 2 } else { 1 };
+            let description = after[1..]
+                .windows(nul)
+                .position(|w| w.iter().all(|&b| b == 0))?;
+            pictures.push((
+                kind,
+                Picture {
+                    mime: String::from_utf8_lossy(mime).to_string(),
+                    data: after[1 + description + nul..].to_vec(),
+                },
+            ));
+        }
+        rest = &rest[10 + len..];
+    }
+    let front = pictures
+        .iter()
+        .position(|(kind, _)| *kind == 3)
+        .unwrap_or(0);
+    (!pictures.is_empty()).then(|| pictures.swap_remove(front).1)
+}
+
+fn push_frame(frames: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    frames.extend(id);
+    frames.extend(syncsafe(data.len()));
+    frames.extend([0, 0]);
+    frames.extend(data);
+}
+
+/// Sizes are written with 7 bits per byte, so that they never look like a sync signal.
+fn syncsafe(len: usize) -> [u8; 4] {
+    [21, 14, 7, 0].map(|shift| ((len >> shift) & 0x7F) as u8)
+}
+
+fn unsyncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |len, &b| (len << 7) | (b & 0x7F) as usize)
+}
+
+fn split_at_nul(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let nul = bytes.iter().position(|&b| b == 0)?;
+    Some((&bytes[..nul], &bytes[nul + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeds_the_cover_art_in_a_tag() {
+        let cover = Picture::sniff(b"\x89PNG\r\n\x1a\nIHDR".to_vec()).unwrap();
+        assert_eq!(
+            (cover.mime.as_str(), cover.extension()),
+            ("image/png", "png")
+        );
+        assert_eq!(
+            Picture::sniff(vec![0xFF, 0xD8, 0xFF, 0xE0])
+                .unwrap()
+                .extension(),
+            "jpg"
+        );
+        assert_eq!(Picture::sniff(b"not an image".to_vec()), None);
+
+        let text =
+            frames_from_info(&[(*b"INAM", "Rain".to_string()), (*b"ICMT", "{}".to_string())]);
+        assert_eq!(text, [(*b"TIT2", "Rain".to_string())]);
+        let tag = id3_tag(&text, Some(&cover));
+        assert_eq!(&tag[..6], b"ID3\x04\x00\x00");
+        assert_eq!(unsyncsafe(&tag[6..10]), tag.len() - 10);
+        assert_eq!(&tag[10..14], b"TIT2");
+        assert_eq!(&tag[20..25], b"\x03Rain");
+        assert_eq!(picture(&tag), Some(cover));
+        assert_eq!(picture(&id3_tag(&text, None)), None);
+        assert_eq!(syncsafe(300), [0, 0, 2, 44]);
+    }
+}
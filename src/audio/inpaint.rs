@@ -0,0 +1,381 @@
+//! Inpainting, which generates a stretch of a render again without touching the rest of
+//! it, and outpainting, which generates an intro leading into its beginning or an outro
+//! carrying on from its end. The new audio
+//! is generated a bit longer than needed, and the part of it that sounds the most like the
+//! audio at the joins is crossfaded in, brought to the level of the audio around it.
+
+use crate::audio::dsp::{
+    crossfade_into, db_to_gain, fade_in, fade_out, gain_to_db, rms, FadeCurve,
+};
+use crate::audio::loop_points::{cosine, spectrum};
+
+/// Seconds the replacement is crossfaded over at each join, unless told otherwise.
+pub const DEFAULT_CROSSFADE_SECS: f32 = 1.0;
+/// Seconds the replacement is generated longer than needed, for aligning it with the audio
+/// around the stretch.
+const SLACK_SECS: f32 = 2.0;
+/// Spacing of the alignments that are compared, in seconds.
+const HOP_SECS: f32 = 0.05;
+/// Seconds of audio at each side of the stretch whose level the replacement is brought to.
+const LEVEL_SECS: f32 = 3.0;
+/// The replacement is never turned up or down by more than these decibels.
+const MAX_GAIN_DB: f32 = 12.0;
+
+/// A stretch of audio to generate again, in samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InpaintRegion {
+    pub start: usize,
+    pub end: usize,
+    /// Samples the replacement is crossfaded over at each join, centred on it.
+    pub crossfade: usize,
+}
+
+impl InpaintRegion {
+    /// Fails if the region is empty or not within `len` samples of audio.
+    pub fn validate(&self, len: usize) -> Result<(), String> {
+        if self.start >= self.end {
+            return Err("The region to inpaint must end after it starts".to_string());
+        }
+        if self.end > len {
+            return Err(format!(
+                "The region to inpaint ends at sample {}, after the audio, which has {len}",
+                self.end
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where the replacement goes in audio of `len` samples, including the crossfades.
+    fn bounds(&self, len: usize) -> (usize, usize) {
+        let half = self.crossfade / 2;
+        (self.start.saturating_sub(half), (self.end + half).min(len))
+    }
+
+    /// Samples of replacement that need to be generated for audio of `len` samples.
+    pub fn replacement_len(&self, len: usize, sample_rate: usize) -> usize {
+        let (from, to) = self.bounds(len);
+        to - from + (SLACK_SECS * sample_rate as f32) as usize
+    }
+}
+
+/// The audio with a stretch replaced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Inpainted {
+    pub audio: Vec<f32>,
+    /// Samples skipped at the beginning of the replacement for aligning it.
+    pub offset: usize,
+    /// Gain the replacement got for matching the level of the audio around the stretch.
+    pub gain_db: f32,
+}
+
+/// Replaces `region` of `audio` with part of `replacement`, which should be
+/// [InpaintRegion::replacement_len] samples long. Joins at the beginning or end of the
+/// audio are not crossfaded.
+pub fn inpaint(
+    audio: &[f32],
+    region: &InpaintRegion,
+    replacement: &[f32],
+    sample_rate: usize,
+) -> Result<Inpainted, String> {
+    region.validate(audio.len())?;
+    let (from, to) = region.bounds(audio.len());
+    let needed = to - from;
+    if replacement.len() < needed {
+        return Err(format!(
+            "The replacement has {} samples, but the region to inpaint needs {needed}",
+            replacement.len()
+        ));
+    }
+    let fade = region.crossfade.min(needed / 2);
+    let head_fade = if from == 0 { 0 } else { fade };
+    let tail_fade = if to == audio.len() { 0 } else { fade };
+
+    // The audio faded out at both joins is compared with the replacement fading in over it.
+    let joins = [
+        (from, 0, head_fade),
+        (to - tail_fade, needed - tail_fade, tail_fade),
+    ];
+    let offset = best_offset(audio, replacement, needed, &joins, sample_rate);
+    let mut replacement = replacement[offset..offset + needed].to_vec();
+
+    let level_len = (LEVEL_SECS * sample_rate as f32) as usize;
+    let mut around = audio[region.start.saturating_sub(level_len)..region.start].to_vec();
+    around.extend(&audio[region.end..(region.end + level_len).min(audio.len())]);
+    let gain_db = match_level(&mut replacement, &around);
+
+    let mut result = audio.to_vec();
+    crossfade_into(
+        &mut result[from..from + head_fade],
+        &replacement[..head_fade],
+        FadeCurve::EqualPower,
+    );
+    result[from + head_fade..to - tail_fade]
+        .copy_from_slice(&replacement[head_fade..needed - tail_fade]);
+    let mut tail = replacement[needed - tail_fade..].to_vec();
+    crossfade_into(&mut tail, &audio[to - tail_fade..to], FadeCurve::EqualPower);
+    result[to - tail_fade..to].copy_from_slice(&tail);
+    Ok(Inpainted {
+        audio: result,
+        offset,
+        gain_db,
+    })
+}
+
+/// Samples of new audio that need to be generated for an intro of `intro_len` samples
+/// that rides out for `crossfade` samples under the beginning of the audio, or for an
+/// outro of as many samples that rides in under its end.
+pub fn intro_len(intro_len: usize, crossfade: usize, sample_rate: usize) -> usize {
+    intro_len + crossfade + (SLACK_SECS * sample_rate as f32) as usize
+}
+
+/// Prepends `intro_len` samples of `intro`, which should be [self::intro_len] samples long,
+/// to `audio`. The audio is left as is, with the intro fading out under its first
+/// `crossfade` samples.
+pub fn prepend_intro(
+    audio: &[f32],
+    intro: &[f32],
+    intro_len: usize,
+    crossfade: usize,
+    sample_rate: usize,
+) -> Result<Inpainted, String> {
+    let crossfade = crossfade.min(audio.len());
+    let needed = intro_len + crossfade;
+    if intro.len() < needed {
+        return Err(format!(
+            "The intro has {} samples, but {needed} are needed",
+            intro.len()
+        ));
+    }
+    // Without a crossfade, the intro is still compared with the first moments of the audio.
+    let compared = crossfade.max((LEVEL_SECS * sample_rate as f32) as usize / 3);
+    let compared = compared.min(audio.len()).min(intro_len + crossfade);
+    let joins = [(0, needed - compared, compared)];
+    let offset = best_offset(audio, intro, needed, &joins, sample_rate);
+    let mut intro = intro[offset..offset + needed].to_vec();
+
+    let level_len = (LEVEL_SECS * sample_rate as f32) as usize;
+    let gain_db = match_level(&mut intro, &audio[..level_len.min(audio.len())]);
+
+    let mut result = intro[..intro_len].to_vec();
+    let mut ride_out = intro[intro_len..].to_vec();
+    fade_out(&mut ride_out, FadeCurve::EqualPower);
+    result.extend(audio);
+    for (sample, tail) in result[intro_len..].iter_mut().zip(ride_out) {
+        *sample += tail;
+    }
+    Ok(Inpainted {
+        audio: result,
+        offset,
+        gain_db,
+    })
+}
+
+/// Appends `outro_len` samples of `outro`, which should be [self::intro_len] samples long,
+/// to `audio`. The audio is left as is, with the outro fading in under its last
+/// `crossfade` samples.
+pub fn append_outro(
+    audio: &[f32],
+    outro: &[f32],
+    outro_len: usize,
+    crossfade: usize,
+    sample_rate: usize,
+) -> Result<Inpainted, String> {
+    let crossfade = crossfade.min(audio.len());
+    let needed = outro_len + crossfade;
+    if outro.len() < needed {
+        return Err(format!(
+            "The outro has {} samples, but {needed} are needed",
+            outro.len()
+        ));
+    }
+    let compared = crossfade.max((LEVEL_SECS * sample_rate as f32) as usize / 3);
+    let compared = compared.min(audio.len()).min(outro_len + crossfade);
+    let joins = [(audio.len() - compared, 0, compared)];
+    let offset = best_offset(audio, outro, needed, &joins, sample_rate);
+    let mut outro = outro[offset..offset + needed].to_vec();
+
+    let level_len = (LEVEL_SECS * sample_rate as f32) as usize;
+    let gain_db = match_level(&mut outro, &audio[audio.len().saturating_sub(level_len)..]);
+
+    let mut result = audio.to_vec();
+    let mut ride_in = outro[..crossfade].to_vec();
+    fade_in(&mut ride_in, FadeCurve::EqualPower);
+    let start = result.len() - crossfade;
+    for (sample, head) in result[start..].iter_mut().zip(ride_in) {
+        *sample += head;
+    }
+    result.extend(&outro[crossfade..]);
+    Ok(Inpainted {
+        audio: result,
+        offset,
+        gain_db,
+    })
+}
+
+/// Where the `needed` samples of new audio are best taken from, comparing the spectra of
+/// each `(at, from, len)` join, `len` samples of `audio` at `at` with the new audio at
+/// `from`, every [HOP_SECS]. The earliest of equally good offsets is taken.
+pub(crate) fn best_offset(
+    audio: &[f32],
+    generated: &[f32],
+    needed: usize,
+    joins: &[(usize, usize, usize)],
+    sample_rate: usize,
+) -> usize {
+    let hop = ((HOP_SECS * sample_rate as f32) as usize).max(1);
+    let similarity = |offset: usize| {
+        let (mut sum, mut frames) = (0.0, 0);
+        for &(at, from, len) in joins {
+            for frame in (0..len / hop).map(|i| i * hop) {
+                let original = spectrum(&audio[at + frame..at + frame + hop], sample_rate);
+                let start = offset + from + frame;
+                let generated = spectrum(&generated[start..start + hop], sample_rate);
+                sum += cosine(&original, &generated);
+                frames += 1;
+            }
+        }
+        if frames == 0 {
+            return 0.0;
+        }
+        sum / frames as f32
+    };
+    (0..=generated.len() - needed)
+        .step_by(hop)
+        .map(|offset| (offset, similarity(offset)))
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map_or(0, |(offset, _)| offset)
+}
+
+/// Brings `generated` to the level of `around`, returning the gain it got in decibels.
+/// Nothing is done if either of them is silent.
+fn match_level(generated: &mut [f32], around: &[f32]) -> f32 {
+    let gain_db = match (rms(around), rms(generated)) {
+        (target, level) if target > 0.0 && level > 0.0 => {
+            (gain_to_db(target) - gain_to_db(level)).clamp(-MAX_GAIN_DB, MAX_GAIN_DB)
+        }
+        _ => 0.0,
+    };
+    let gain = db_to_gain(gain_db);
+    generated.iter_mut().for_each(|sample| *sample *= gain);
+    gain_db
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: usize = 8000;
+
+    fn tone(hz: f32, amplitude: f32, secs: f32) -> Vec<f32> {
+        (0..(secs * RATE as f32) as usize)
+            .map(|i| amplitude * (2.0 * PI * hz * i as f32 / RATE as f32).sin())
+            .collect()
+    }
+
+    fn region() -> InpaintRegion {
+        InpaintRegion {
+            start: 2 * RATE,
+            end: 3 * RATE,
+            crossfade: RATE / 5,
+        }
+    }
+
+    #[test]
+    fn replaces_the_region_at_the_level_around_it() {
+        let audio = tone(440.0, 0.5, 5.0);
+        let region = region();
+        let len = region.replacement_len(audio.len(), RATE);
+        assert_eq!(len, RATE + RATE / 5 + 2 * RATE);
+        let replacement = tone(660.0, 0.125, len as f32 / RATE as f32);
+        let inpainted = inpaint(&audio, &region, &replacement, RATE).unwrap();
+
+        assert_eq!(inpainted.audio.len(), audio.len());
+        assert!((inpainted.gain_db - 12.0).abs() < 0.1);
+        // Untouched outside the region and its crossfades.
+        let (from, to) = (region.start - RATE / 10, region.end + RATE / 10);
+        assert_eq!(&inpainted.audio[..from], &audio[..from]);
+        assert_eq!(&inpainted.audio[to..], &audio[to..]);
+        // The replacement, at the level of the audio around it.
+        let middle = &inpainted.audio[region.start + RATE / 10..region.end - RATE / 10];
+        assert!((rms(middle) - rms(&audio)).abs() < 0.01);
+        assert!(middle != &audio[region.start + RATE / 10..region.end - RATE / 10]);
+    }
+
+    #[test]
+    fn aligns_the_replacement_with_the_audio_around_it() {
+        let audio = tone(440.0, 0.5, 5.0);
+        let region = region();
+        // Only the end of the replacement sounds like the audio around the region.
+        let len = region.replacement_len(audio.len(), RATE);
+        let mut replacement = tone(1500.0, 0.5, 2.0);
+        replacement.extend(tone(440.0, 0.5, (len - 2 * RATE) as f32 / RATE as f32));
+        let inpainted = inpaint(&audio, &region, &replacement, RATE).unwrap();
+        assert_eq!(inpainted.offset, 2 * RATE);
+    }
+
+    #[test]
+    fn prepends_intros_leading_into_the_audio() {
+        let audio = tone(440.0, 0.5, 3.0);
+        let len = intro_len(2 * RATE, RATE / 2, RATE);
+        assert_eq!(len, 2 * RATE + RATE / 2 + 2 * RATE);
+        // Only a second of the generated audio sounds like the beginning of the track, and
+        // the intro is taken so that it leads into it.
+        let mut intro = tone(1500.0, 0.125, 2.5);
+        intro.extend(tone(440.0, 0.125, 1.0));
+        intro.extend(tone(1500.0, 0.125, 1.0));
+        let prepended = prepend_intro(&audio, &intro, 2 * RATE, RATE / 2, RATE).unwrap();
+
+        assert_eq!(prepended.offset, RATE);
+        assert!((prepended.gain_db - 12.0).abs() < 0.1);
+        assert_eq!(prepended.audio.len(), 2 * RATE + audio.len());
+        // The track is left as is once the intro rode out under it.
+        assert_eq!(&prepended.audio[2 * RATE + RATE / 2..], &audio[RATE / 2..]);
+        let err = prepend_intro(&audio, &intro[..RATE], 2 * RATE, RATE / 2, RATE).unwrap_err();
+        assert!(err.contains("are needed"), "{err}");
+    }
+
+    #[test]
+    fn appends_outros_carrying_on_from_the_audio() {
+        let audio = tone(440.0, 0.5, 3.0);
+        let len = intro_len(2 * RATE, RATE / 2, RATE);
+        // Only a second of the generated audio sounds like the end of the track.
+        let mut outro = tone(1500.0, 0.125, 1.5);
+        outro.extend(tone(440.0, 0.125, 1.0));
+        outro.extend(tone(
+            1500.0,
+            0.125,
+            (len - 5 * RATE / 2) as f32 / RATE as f32,
+        ));
+        let appended = append_outro(&audio, &outro, 2 * RATE, RATE / 2, RATE).unwrap();
+
+        assert_eq!(appended.offset, 3 * RATE / 2);
+        assert!((appended.gain_db - 12.0).abs() < 0.1);
+        assert_eq!(appended.audio.len(), audio.len() + 2 * RATE);
+        // The track is left as is until the outro rides in under it.
+        let start = audio.len() - RATE / 2;
+        assert_eq!(&appended.audio[..start], &audio[..start]);
+        let err = append_outro(&audio, &outro[..RATE], 2 * RATE, RATE / 2, RATE).unwrap_err();
+        assert!(err.contains("are needed"), "{err}");
+    }
+
+    #[test]
+    fn rejects_regions_outside_the_audio() {
+        let audio = tone(440.0, 0.5, 2.0);
+        let err = inpaint(&audio, &region(), &audio, RATE).unwrap_err();
+        assert!(err.contains("after the audio"), "{err}");
+        let empty = InpaintRegion {
+            start: 100,
+            end: 100,
+            crossfade: 0,
+        };
+        assert!(inpaint(&audio, &empty, &audio, RATE).is_err());
+        let short = InpaintRegion {
+            start: 0,
+            end: RATE,
+            crossfade: 0,
+        };
+        let err = inpaint(&audio, &short, &audio[..100], RATE).unwrap_err();
+        assert!(err.contains("needs 8000"), "{err}");
+    }
+}
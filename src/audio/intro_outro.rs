@@ -0,0 +1,222 @@
+//! Procedurally generated treatments for the beginning and the end of long renders, like
+//! a fade from silence or an ambient swell, grouped in presets.
+
+use std::f32::consts::PI;
+
+use crate::audio::dsp::FadeCurve;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntroStyle {
+    /// The music fades in from silence.
+    FadeFromSilence,
+    /// A pad swells from silence and hands over to the music.
+    AmbientSwell,
+    /// An accelerating run of kick drums leads into the music.
+    DrumPickup,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutroStyle {
+    /// The music fades out into silence.
+    FadeToSilence,
+    /// The music fades out under a pad that rings after it and decays.
+    AmbientSwell,
+}
+
+/// A treatment applied to the first or last `duration` seconds of a render.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bookend<S> {
+    pub style: S,
+    pub duration: f32,
+    /// Level of the synthesized elements, from 0 to 1.
+    pub gain: f32,
+}
+
+/// The intro and outro applied to a render, if any.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntroOutro {
+    pub intro: Option<Bookend<IntroStyle>>,
+    pub outro: Option<Bookend<OutroStyle>>,
+}
+
+/// Names of the presets available through [IntroOutro::preset].
+pub const PRESETS: &[&str] = &["gentle", "cinematic", "club"];
+
+impl IntroOutro {
+    /// Looks up one of the [PRESETS].
+    pub fn preset(name: &str) -> Option<Self> {
+        let (intro, outro) = match name {
+            "gentle" => (
+                (IntroStyle::FadeFromSilence, 4.0),
+                (OutroStyle::FadeToSilence, 6.0),
+            ),
+            "cinematic" => (
+                (IntroStyle::AmbientSwell, 8.0),
+                (OutroStyle::AmbientSwell, 10.0),
+            ),
+            "club" => (
+                (IntroStyle::DrumPickup, 4.0),
+                (OutroStyle::FadeToSilence, 8.0),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            intro: Some(Bookend {
+                style: intro.0,
+                duration: intro.1,
+                gain: 0.5,
+            }),
+            outro: Some(Bookend {
+                style: outro.0,
+                duration: outro.1,
+                gain: 0.5,
+            }),
+        })
+    }
+
+    /// Longest of the intro and the outro, in seconds.
+    pub fn max_duration(&self) -> f32 {
+        let intro = self.intro.as_ref().map_or(0.0, |b| b.duration);
+        let outro = self.outro.as_ref().map_or(0.0, |b| b.duration);
+        intro.max(outro)
+    }
+}
+
+/// How each sample of a stretch of audio is treated: scaled by `gain`, and summed with
+/// `overlay`.
+#[derive(Clone, Debug, Default)]
+pub struct Envelope {
+    pub gain: Vec<f32>,
+    pub overlay: Vec<f32>,
+}
+
+impl Envelope {
+    pub fn len(&self) -> usize {
+        self.gain.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gain.is_empty()
+    }
+
+    /// Treats `audio`, which starts at the given position of the envelope.
+    pub fn apply(&self, audio: &mut [f32], offset: usize) {
+        let treated = self.gain.iter().zip(&self.overlay).skip(offset);
+        for (sample, (gain, overlay)) in audio.iter_mut().zip(treated) {
+            *sample = *sample * gain + overlay;
+        }
+    }
+}
+
+impl Bookend<IntroStyle> {
+    pub fn render(&self, sample_rate: usize) -> Envelope {
+        let n = (self.duration * sample_rate as f32) as usize;
+        let t = |i: usize| i as f32 / n as f32;
+        let (gain, overlay) = match self.style {
+            IntroStyle::FadeFromSilence => (
+                (0..n).map(|i| FadeCurve::EqualPower.gain(t(i))).collect(),
+                vec![0.0; n],
+            ),
+            IntroStyle::AmbientSwell => (
+                // The music comes in during the second half, while the pad fades away.
+                (0..n)
+                    .map(|i| FadeCurve::EqualPower.gain((t(i) * 2.0 - 1.0).max(0.0)))
+                    .collect(),
+                (0..n)
+                    .map(|i| pad(i, sample_rate) * (PI * t(i)).sin() * self.gain)
+                    .collect(),
+            ),
+            IntroStyle::DrumPickup => {
+                // Kicks at the start of the second half, getting closer to each other.
+                let mut overlay = vec![0.0; n];
+                for hit in [0.5, 0.625, 0.75, 0.8125, 0.875, 0.9375] {
+                    let start = (hit * n as f32) as usize;
+                    let velocity = 0.6 + 0.4 * hit;
+                    for (i, sample) in overlay[start..].iter_mut().enumerate() {
+                        *sample += kick(i, sample_rate) * velocity * self.gain;
+                    }
+                }
+                let gain = (0..n)
+                    .map(|i| if t(i) < 0.9375 { 0.0 } else { 1.0 })
+                    .collect();
+                (gain, overlay)
+            }
+        };
+        Envelope { gain, overlay }
+    }
+}
+
+impl Bookend<OutroStyle> {
+    pub fn render(&self, sample_rate: usize) -> Envelope {
+        let n = (self.duration * sample_rate as f32) as usize;
+        let t = |i: usize| (i + 1) as f32 / n as f32;
+        let gain = (0..n)
+            .map(|i| FadeCurve::EqualPower.gain(1.0 - t(i)))
+            .collect();
+        let overlay = match self.style {
+            OutroStyle::FadeToSilence => vec![0.0; n],
+            OutroStyle::AmbientSwell => (0..n)
+                .map(|i| pad(i, sample_rate) * (PI * t(i)).sin() * self.gain)
+                .collect(),
+        };
+        Envelope { gain, overlay }
+    }
+}
+
+/// An A major chord of slowly beating sines, normalized to 1.
+fn pad(i: usize, sample_rate: usize) -> f32 {
+    let secs = i as f32 / sample_rate as f32;
+    let voices = [110.0, 138.59, 164.81, 220.0];
+    let sum: f32 = voices
+        .iter()
+        .map(|hz| (2.0 * PI * hz * secs).sin() + (2.0 * PI * (hz + 0.7) * secs).sin())
+        .sum();
+    sum / (voices.len() * 2) as f32
+}
+
+/// A kick drum hit, pitching down from 120Hz to 50Hz while it decays.
+fn kick(i: usize, sample_rate: usize) -> f32 {
+    let secs = i as f32 / sample_rate as f32;
+    let hz = 50.0 + 70.0 * (-secs * 30.0).exp();
+    (2.0 * PI * hz * secs).sin() * (-secs * 12.0).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_fade_in_and_out() {
+        for name in PRESETS {
+            let preset = IntroOutro::preset(name).unwrap();
+            let intro = preset.intro.unwrap().render(1000);
+            assert_eq!(intro.gain[0], 0.0, "{name}");
+            assert!(*intro.gain.last().unwrap() > 0.99, "{name}");
+            let outro = preset.outro.unwrap().render(1000);
+            assert!(outro.gain[0] > 0.99, "{name}");
+            assert!(outro.gain.last().unwrap().abs() < 1e-6, "{name}");
+            assert!(outro.overlay.last().unwrap().abs() < 1e-3, "{name}");
+        }
+        assert_eq!(IntroOutro::preset("unknown"), None);
+    }
+
+    #[test]
+    fn drum_pickup_leads_into_the_music() {
+        let intro = Bookend {
+            style: IntroStyle::DrumPickup,
+            duration: 4.0,
+            gain: 0.5,
+        }
+        .render(1000);
+        let mut audio = vec![0.25; 4000];
+        intro.apply(&mut audio, 0);
+        assert!(audio[..2000].iter().all(|s| *s == 0.0));
+        assert!(audio[2000..2100].iter().any(|s| s.abs() > 0.1));
+        assert_eq!(audio[3999] - intro.overlay[3999], 0.25);
+
+        // Applying from an offset continues where it left.
+        let mut rest = vec![0.25; 1000];
+        intro.apply(&mut rest, 3000);
+        assert_eq!(rest, audio[3000..]);
+    }
+}
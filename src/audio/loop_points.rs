@@ -0,0 +1,255 @@
+//! Search for the pair of points in a render between which it loops most seamlessly, and
+//! export the audio between them as a loop that can be repeated without a seam.
+
+use std::f32::consts::PI;
+
+use crate::audio::dsp::{crossfade_into, FadeCurve};
+
+/// Constraints on the loops that are looked for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopConfig {
+    /// Shortest loop, in seconds.
+    pub min_secs: f32,
+    /// Longest loop, in seconds.
+    pub max_secs: f32,
+    /// Seconds over which the end of the loop is crossfaded into the audio that leads to
+    /// its start.
+    pub crossfade_secs: f32,
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        Self {
+            min_secs: 4.0,
+            max_secs: 30.0,
+            crossfade_secs: 0.05,
+        }
+    }
+}
+
+/// A loop from `start` to `end`, in samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopPoints {
+    pub start: usize,
+    pub end: usize,
+    /// How similar the audio around both points is, 1 being identical.
+    pub score: f32,
+}
+
+/// Spacing of the points that are compared spectrally, in seconds.
+const HOP_SECS: f32 = 0.05;
+/// Frequencies whose levels describe the spectrum of a frame, log-spaced from 60Hz.
+pub(crate) const BINS: usize = 16;
+/// Frames on each side of a point that are compared, so that points match when the audio
+/// around them does and not only at one instant.
+const CONTEXT: usize = 4;
+/// The best spectral matches that are refined comparing their waveforms.
+const CANDIDATES: usize = 8;
+/// Shortest stretch of waveform compared around each point, in seconds.
+const MIN_WAVEFORM_SECS: f32 = 0.01;
+
+/// Finds the loop points within `config` where the audio around the start sounds the
+/// most like the audio around the end, or None if `audio` is too short for any loop.
+///
+/// Pairs of points are first ranked by the similarity of their spectra, and the best of
+/// them are then aligned to the sample comparing their waveforms.
+pub fn find_loop_points(
+    audio: &[f32],
+    sample_rate: usize,
+    config: &LoopConfig,
+) -> Option<LoopPoints> {
+    let hop = ((HOP_SECS * sample_rate as f32) as usize).max(1);
+    let window =
+        ((config.crossfade_secs.max(MIN_WAVEFORM_SECS) * sample_rate as f32) as usize).max(1);
+    let min_frames = ((config.min_secs * sample_rate as f32) as usize)
+        .div_ceil(hop)
+        .max(1);
+    let max_frames = (config.max_secs * sample_rate as f32) as usize / hop;
+
+    let features = audio
+        .chunks_exact(hop)
+        .map(|frame| spectrum(frame, sample_rate))
+        .collect::<Vec<_>>();
+    // Points must leave room for comparing the audio around them, and for moving the end
+    // by half a frame when aligning the waveforms.
+    let first = CONTEXT.max(window.div_ceil(hop));
+    let last = features
+        .len()
+        .saturating_sub(CONTEXT)
+        .min(audio.len().saturating_sub(window + hop / 2) / hop);
+
+    let mut candidates = vec![];
+    for start in first..last {
+        for end in (start + min_frames)..=(start + max_frames).min(last) {
+            let similarity = (0..CONTEXT * 2)
+                .map(|c| cosine(&features[start + c - CONTEXT], &features[end + c - CONTEXT]))
+                .sum::<f32>()
+                / (CONTEXT * 2) as f32;
+            candidates.push((similarity, start, end));
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    candidates.truncate(CANDIDATES);
+
+    candidates
+        .into_iter()
+        .filter_map(|(similarity, start, end)| {
+            let start = start * hop;
+            let (end, correlation) = (end * hop - hop / 2..=end * hop + hop / 2)
+                .map(|end| {
+                    let around = |p: usize| &audio[p - window..p + window];
+                    // The normalized cross-correlation of both stretches.
+                    (end, cosine(around(start), around(end)))
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))?;
+            Some(LoopPoints {
+                start,
+                end,
+                score: (similarity + correlation.max(0.0)) / 2.0,
+            })
+        })
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+}
+
+/// The audio between the loop points, with its end crossfaded over `crossfade` samples
+/// into the audio that precedes the start, so that it continues into its own beginning.
+pub fn export_loop(audio: &[f32], points: &LoopPoints, crossfade: usize) -> Vec<f32> {
+    let crossfade = crossfade.min(points.start).min(points.end - points.start);
+    let mut looped = audio[points.start..points.end].to_vec();
+    let tail = looped.len() - crossfade;
+    let lead_in = &audio[points.start - crossfade..points.start];
+    crossfade_into(&mut looped[tail..], lead_in, FadeCurve::Linear);
+    looped
+}
+
+/// A `smpl` chunk with the loop points, for placing after the samples of a WAV file, which
+/// samplers and game audio middleware loop between.
+pub fn smpl_chunk(points: &LoopPoints, sample_rate: usize) -> Vec<u8> {
+    let mut smpl = vec![];
+    // Manufacturer and product.
+    smpl.extend(0u32.to_le_bytes());
+    smpl.extend(0u32.to_le_bytes());
+    // Sample period in nanoseconds, MIDI unity note and pitch fraction.
+    smpl.extend((1_000_000_000 / sample_rate as u32).to_le_bytes());
+    smpl.extend(60u32.to_le_bytes());
+    smpl.extend(0u32.to_le_bytes());
+    // SMPTE format and offset.
+    smpl.extend(0u32.to_le_bytes());
+    smpl.extend(0u32.to_le_bytes());
+    // One loop, and no sampler specific data.
+    smpl.extend(1u32.to_le_bytes());
+    smpl.extend(0u32.to_le_bytes());
+    // A forward loop, whose end is the last sample played, repeated forever.
+    smpl.extend(0u32.to_le_bytes());
+    smpl.extend(0u32.to_le_bytes());
+    smpl.extend((points.start as u32).to_le_bytes());
+    smpl.extend((points.end as u32 - 1).to_le_bytes());
+    smpl.extend(0u32.to_le_bytes());
+    smpl.extend(0u32.to_le_bytes());
+    let mut chunk = b"smpl".to_vec();
+    chunk.extend((smpl.len() as u32).to_le_bytes());
+    chunk.extend(smpl);
+    chunk
+}
+
+/// Log-compressed levels of the frame at [BINS] frequencies, measured with the Goertzel
+/// algorithm over a Hann window.
+pub(crate) fn spectrum(frame: &[f32], sample_rate: usize) -> [f32; BINS] {
+    let lowest = 60.0;
+    let highest = (sample_rate as f32 / 2.5).min(8000.0);
+    let n = frame.len() as f32;
+    let mut levels = [0.0; BINS];
+    for (k, level) in levels.iter_mut().enumerate() {
+        let hz = lowest * (highest / lowest).powf(k as f32 / (BINS - 1) as f32);
+        let coeff = 2.0 * (2.0 * PI * hz / sample_rate as f32).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for (i, x) in frame.iter().enumerate() {
+            let hann = 0.5 - 0.5 * (2.0 * PI * i as f32 / n).cos();
+            let s = x * hann + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s;
+        }
+        let power = (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0);
+        *level = (1.0 + 100.0 * power.sqrt() / n).ln();
+    }
+    levels
+}
+
+pub(crate) fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norms = a.iter().map(|a| a * a).sum::<f32>() * b.iter().map(|b| b * b).sum::<f32>();
+    if norms == 0.0 {
+        return 0.0;
+    }
+    dot / norms.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A second of noise followed by a riff of four decaying notes that repeats every
+    /// two seconds.
+    fn riff(secs: f32, sample_rate: usize) -> Vec<f32> {
+        let mut seed = 1u32;
+        (0..(secs * sample_rate as f32) as usize)
+            .map(|i| {
+                if i < sample_rate {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    return (seed >> 16) as f32 / 32768.0 - 1.0;
+                }
+                let note = i / (sample_rate / 2);
+                let in_note = (i % (sample_rate / 2)) as f32 / sample_rate as f32;
+                let hz = [220.0, 330.0, 262.0, 294.0][note % 4];
+                (2.0 * PI * hz * in_note).sin() * (-in_note * 4.0).exp() * 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_seamless_loops() {
+        let sample_rate = 4000;
+        let audio = riff(14.0, sample_rate);
+        let config = LoopConfig {
+            min_secs: 3.0,
+            max_secs: 7.0,
+            ..Default::default()
+        };
+        let points = find_loop_points(&audio, sample_rate, &config).unwrap();
+        assert!(points.start >= sample_rate, "{points:?}");
+        let length = points.end - points.start;
+        assert!(
+            length == 4 * sample_rate || length == 6 * sample_rate,
+            "{points:?}"
+        );
+        assert!(points.score > 0.99, "{points:?}");
+
+        let looped = export_loop(&audio, &points, 200);
+        assert_eq!(looped.len(), length);
+        // Repeating the loop continues the riff as if it was never cut.
+        let seam = (looped[looped.len() - 1] - looped[0]).abs();
+        assert!(seam <= (audio[points.start - 1] - audio[points.start]).abs() + 1e-3);
+    }
+
+    #[test]
+    fn writes_smpl_chunks() {
+        let points = LoopPoints {
+            start: 4000,
+            end: 20000,
+            score: 1.0,
+        };
+        let chunk = smpl_chunk(&points, 4000);
+        assert_eq!(&chunk[..4], b"smpl");
+        assert_eq!(chunk.len(), 8 + 36 + 24);
+        let field = |at: usize| u32::from_le_bytes(chunk[at..at + 4].try_into().unwrap());
+        assert_eq!(field(8 + 8), 250_000);
+        assert_eq!(field(8 + 28), 1);
+        assert_eq!((field(8 + 44), field(8 + 48)), (4000, 19999));
+    }
+
+    #[test]
+    fn needs_enough_audio() {
+        let audio = riff(2.0, 4000);
+        assert_eq!(find_loop_points(&audio, 4000, &LoopConfig::default()), None);
+    }
+}
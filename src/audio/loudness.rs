@@ -0,0 +1,162 @@
+//! Loudness measurements of renders, as in ITU-R BS.1770, for the reports delivered along
+//! with them.
+
+use crate::audio::dsp::{gain_to_db, peak, rms};
+
+/// How loud a render is, in the units delivery specs are written in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoudnessReport {
+    /// Gated integrated loudness in LUFS, or nothing if the render is silent or shorter
+    /// than a block.
+    pub integrated_lufs: Option<f32>,
+    /// Loudness of the loudest 3 second window, in LUFS.
+    pub max_short_term_lufs: Option<f32>,
+    pub sample_peak_dbfs: f32,
+    pub rms_dbfs: f32,
+    pub secs: f32,
+}
+
+/// Levels under this one are treated as silence.
+const FLOOR_DB: f32 = -100.0;
+
+impl LoudnessReport {
+    pub fn measure(audio: &[f32], sample_rate: usize) -> Self {
+        let weighted = k_weighted(audio, sample_rate);
+        let powers = |secs: f32| {
+            let len = (secs * sample_rate as f32) as usize;
+            // Windows overlap by 75%, as the standard asks for gating blocks.
+            let hop = (len / 4).max(1);
+            (0..)
+                .map(|k| k * hop)
+                .take_while(|start| len > 0 && start + len <= weighted.len())
+                .map(|start| {
+                    weighted[start..start + len]
+                        .iter()
+                        .map(|x| x * x)
+                        .sum::<f32>()
+                })
+                .map(|sum| sum / len as f32)
+                .collect::<Vec<_>>()
+        };
+        let short_term = powers(3.0)
+            .into_iter()
+            .map(lufs)
+            .fold(None, |max, l| Some(max.map_or(l, |max: f32| max.max(l))));
+        Self {
+            integrated_lufs: gated(&powers(0.4)),
+            max_short_term_lufs: short_term.filter(|&l| l > -70.0),
+            sample_peak_dbfs: gain_to_db(peak(audio)).max(FLOOR_DB),
+            rms_dbfs: gain_to_db(rms(audio)).max(FLOOR_DB),
+            secs: audio.len() as f32 / sample_rate as f32,
+        }
+    }
+}
+
+fn lufs(power: f32) -> f32 {
+    -0.691 + 10.0 * power.max(1e-12).log10()
+}
+
+/// The loudness of the blocks louder than -70 LUFS, and then of the ones at most 10 LU
+/// under that.
+fn gated(blocks: &[f32]) -> Option<f32> {
+    let mean = |blocks: &[f32]| blocks.iter().sum::<f32>() / blocks.len() as f32;
+    let audible = blocks
+        .iter()
+        .copied()
+        .filter(|&p| lufs(p) > -70.0)
+        .collect::<Vec<_>>();
+    if audible.is_empty() {
+        return None;
+    }
+    let threshold = lufs(mean(&audible)) - 10.0;
+    let kept = audible
+        .into_iter()
+        .filter(|&p| lufs(p) > threshold)
+        .collect::<Vec<_>>();
+    Some(lufs(mean(&kept)))
+}
+
+/// The audio through the K-weighting filter: a high shelf modelling the head, followed by
+/// a high-pass filter, both designed for the sample rate.
+fn k_weighted(audio: &[f32], sample_rate: usize) -> Vec<f32> {
+    let fs = sample_rate as f64;
+    let shelf = {
+        let k = (std::f64::consts::PI * 1_681.974_450_955_533 / fs).tan();
+        let q = 0.707_175_236_955_419_6;
+        let vh = 10f64.powf(3.999_843_853_973_347 / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+        Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    };
+    let high_pass = {
+        let k = (std::f64::consts::PI * 38.135_470_876_024_44 / fs).tan();
+        let q = 0.500_327_037_323_877_3;
+        let a0 = 1.0 + k / q + k * k;
+        Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    };
+    let mut filters = [shelf, high_pass];
+    audio
+        .iter()
+        .map(|&x| filters.iter_mut().fold(x as f64, |x, f| f.process(x)) as f32)
+        .collect()
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn measures_the_loudness_of_a_sine() {
+        // A 1kHz sine peaking at -20 dBFS measures -23 LUFS, the broadcast target.
+        let sine = (0..48000 * 4)
+            .map(|i| (2.0 * PI * 997.0 * i as f32 / 48000.0).sin() * 0.1)
+            .collect::<Vec<_>>();
+        let report = LoudnessReport::measure(&sine, 48000);
+        let integrated = report.integrated_lufs.unwrap();
+        assert!((integrated + 23.0).abs() < 0.1, "{integrated}");
+        assert!((report.max_short_term_lufs.unwrap() - integrated).abs() < 0.1);
+        assert!((report.sample_peak_dbfs + 20.0).abs() < 0.05);
+        assert!((report.rms_dbfs + 23.01).abs() < 0.01);
+        assert_eq!(report.secs, 4.0);
+
+        // Silence is gated out entirely.
+        let silence = LoudnessReport::measure(&vec![0.0; 48000], 48000);
+        assert_eq!(silence.integrated_lufs, None);
+        assert_eq!(silence.sample_peak_dbfs, FLOOR_DB);
+    }
+}
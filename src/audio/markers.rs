@@ -0,0 +1,130 @@
+//! Markers where each section of a long piece begins, written into WAV files as cue points
+//! with labels, which DAWs show as markers, and as CUE sheets next to them, which players
+//! show as chapters.
+
+/// Where a segment of an extended render begins in the whole piece, halfway through its
+/// crossfade with the previous one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentMarker {
+    pub segment: usize,
+    /// In samples from the beginning of the piece.
+    pub start: usize,
+    /// Samples the segment crossfades with the previous audio, 0 if it doesn't.
+    pub crossfade: usize,
+}
+
+/// A labelled position in a piece.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Marker {
+    /// In samples from the beginning of the piece.
+    pub start: usize,
+    pub label: String,
+}
+
+/// A `cue ` chunk with a point for each marker, followed by a LIST chunk with their
+/// labels, for placing after the samples of a WAV file.
+pub fn cue_chunks(markers: &[Marker]) -> Vec<u8> {
+    let mut cue = (markers.len() as u32).to_le_bytes().to_vec();
+    let mut labels = b"adtl".to_vec();
+    for (i, marker) in markers.iter().enumerate() {
+        // Cue point ids start at 1, some DAWs ignore the ones that are 0.
+        let id = i as u32 + 1;
+        let start = marker.start as u32;
+        cue.extend(id.to_le_bytes());
+        cue.extend(start.to_le_bytes());
+        cue.extend(b"data");
+        cue.extend(0u32.to_le_bytes());
+        cue.extend(0u32.to_le_bytes());
+        cue.extend(start.to_le_bytes());
+
+        // Labels are null terminated, and chunks padded to an even length.
+        let len = 4 + marker.label.len() + 1;
+        labels.extend(b"labl");
+        labels.extend((len as u32).to_le_bytes());
+        labels.extend(id.to_le_bytes());
+        labels.extend(marker.label.as_bytes());
+        labels.push(0);
+        if len % 2 == 1 {
+            labels.push(0);
+        }
+    }
+    let mut chunks = b"cue ".to_vec();
+    chunks.extend((cue.len() as u32).to_le_bytes());
+    chunks.extend(cue);
+    chunks.extend(b"LIST");
+    chunks.extend((labels.len() as u32).to_le_bytes());
+    chunks.extend(labels);
+    chunks
+}
+
+/// A CUE sheet with a track for each marker of the WAV file `file_name`.
+pub fn cue_sheet(file_name: &str, markers: &[Marker], sample_rate: usize) -> String {
+    let mut sheet = format!("FILE \"{}\" WAVE\n", file_name.replace('"', "'"));
+    for (i, marker) in markers.iter().enumerate() {
+        // Positions are in minutes, seconds and frames, 75 of them a second.
+        let frames = marker.start * 75 / sample_rate;
+        sheet += &format!(
+            "  TRACK {:02} AUDIO\n    TITLE \"{}\"\n    INDEX 01 {:02}:{:02}:{:02}\n",
+            i + 1,
+            marker.label.replace('"', "'"),
+            frames / 75 / 60,
+            frames / 75 % 60,
+            frames % 75,
+        );
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::wav::{append_chunk, decode_wav, encode_wav};
+
+    fn markers() -> Vec<Marker> {
+        vec![
+            Marker {
+                start: 0,
+                label: "Intro".to_string(),
+            },
+            Marker {
+                start: 32000 * 75 + 16000,
+                label: "Bridge \"B\"".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn writes_cue_points_that_keep_the_file_valid() {
+        let chunks = cue_chunks(&markers());
+        assert_eq!(&chunks[..4], b"cue ");
+        assert_eq!(
+            u32::from_le_bytes(chunks[4..8].try_into().unwrap()),
+            4 + 2 * 24
+        );
+        // The second point, after the count and the first point.
+        let second = &chunks[8 + 4 + 24..8 + 4 + 48];
+        assert_eq!(u32::from_le_bytes(second[..4].try_into().unwrap()), 2);
+        assert_eq!(
+            u32::from_le_bytes(second[20..24].try_into().unwrap()),
+            32000 * 75 + 16000
+        );
+        let labels = &chunks[8 + 4 + 48..];
+        assert_eq!(&labels[..4], b"LIST");
+        assert_eq!(&labels[8..12], b"adtl");
+        assert_eq!(labels.len() % 2, 0);
+
+        let mut wav = encode_wav(vec![0.5; 100], 32000).unwrap();
+        append_chunk(&mut wav, &chunks);
+        assert_eq!(decode_wav(&wav).unwrap(), vec![0.5; 100]);
+    }
+
+    #[test]
+    fn writes_cue_sheets() {
+        assert_eq!(
+            cue_sheet("song.wav", &markers(), 32000),
+            "FILE \"song.wav\" WAVE\n  \
+             TRACK 01 AUDIO\n    TITLE \"Intro\"\n    INDEX 01 00:00:00\n  \
+             TRACK 02 AUDIO\n    TITLE \"Bridge 'B'\"\n    INDEX 01 01:15:37\n"
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Transcription of audio into notes, and Standard MIDI Files with them, so the generated
+//! material can be edited symbolically. Notes come out of frame by frame activations of
+//! the 88 piano keys, either predicted by a basic-pitch model or estimated here with the
+//! Goertzel algorithm, which is rough but good enough for clear monophonic lines.
+
+use std::f32::consts::PI;
+
+use crate::audio::resample::resample;
+
+/// MIDI note of the lowest piano key, A0.
+pub const LOWEST_NOTE: u8 = 21;
+pub const KEYS: usize = 88;
+
+/// Activation a key needs to be held.
+const FRAME_THRESHOLD: f32 = 0.3;
+/// Activation an onset needs to start a note.
+const ONSET_THRESHOLD: f32 = 0.5;
+/// Frames a held note may drop below [FRAME_THRESHOLD] without ending, for vibrato and
+/// tremolo.
+const TOLERANCE: usize = 3;
+/// Shorter notes are dropped, they are usually transients of longer ones.
+const MIN_NOTE_SECS: f32 = 0.08;
+
+/// Rate the audio is analysed at by [estimate_activations], the highest key is at 4186Hz.
+const RATE: u32 = 16000;
+const FRAME: usize = 2048;
+const HOP: usize = 256;
+/// Levels in dBFS mapped to activations 0 and 1 by [estimate_activations].
+const FLOOR_DB: f32 = -40.0;
+const FULL_DB: f32 = -10.0;
+
+/// A note, with its times in seconds from the beginning of the piece.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoteEvent {
+    pub pitch: u8,
+    pub start: f32,
+    pub end: f32,
+    pub velocity: u8,
+}
+
+/// How active each key is in each frame, and how likely it is that it was struck in it,
+/// both from 0 to 1.
+#[derive(Clone, Debug, Default)]
+pub struct Activations {
+    pub frame_rate: f32,
+    pub notes: Vec<[f32; KEYS]>,
+    pub onsets: Vec<[f32; KEYS]>,
+}
+
+/// Estimates the activations of the keys from the level of their pitch in each frame,
+/// without a model. A key is only active if it's louder than the keys next to it and than
+/// the octave below, whose harmonic it could be, and struck when it becomes active.
+pub fn estimate_activations(audio: &[f32], sample_rate: u32) -> Activations {
+    let mut audio = resample(audio, sample_rate, RATE);
+    // Frames are centred on their time, so the first one is half padding.
+    audio.splice(0..0, std::iter::repeat_n(0.0, FRAME / 2));
+    let window: Vec<f32> = (0..FRAME)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FRAME as f32).cos())
+        .collect();
+    let window_sum: f32 = window.iter().sum();
+    let coeffs: Vec<f32> = (0..KEYS)
+        .map(|key| {
+            let hz = 440.0 * 2f32.powf((key as f32 + LOWEST_NOTE as f32 - 69.0) / 12.0);
+            2.0 * (2.0 * PI * hz / RATE as f32).cos()
+        })
+        .collect();
+
+    let mut activations = Activations {
+        frame_rate: RATE as f32 / HOP as f32,
+        ..Default::default()
+    };
+    let frames = (audio.len() - FRAME / 2).div_ceil(HOP);
+    audio.resize(frames * HOP + FRAME, 0.0);
+    for frame in 0..frames {
+        let samples = &audio[frame * HOP..frame * HOP + FRAME];
+        let mut levels = [0.0; KEYS];
+        for (level, coeff) in levels.iter_mut().zip(&coeffs) {
+            let (mut s1, mut s2) = (0.0, 0.0);
+            for (x, w) in samples.iter().zip(&window) {
+                let s = x * w + coeff * s1 - s2;
+                s2 = s1;
+                s1 = s;
+            }
+            let amplitude = 2.0 * (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0).sqrt();
+            *level = amplitude / window_sum;
+        }
+
+        let mut notes = [0.0; KEYS];
+        for key in 0..KEYS {
+            let level = levels[key];
+            let louder = |other: Option<usize>| other.and_then(|k| levels.get(k)) > Some(&level);
+            if louder(key.checked_sub(1)) || louder(Some(key + 1)) || louder(key.checked_sub(12)) {
+                continue;
+            }
+            let db = 20.0 * level.max(1e-6).log10();
+            notes[key] = ((db - FLOOR_DB) / (FULL_DB - FLOOR_DB)).clamp(0.0, 1.0);
+        }
+        let last = activations.notes.last().copied().unwrap_or([0.0; KEYS]);
+        let onsets = std::array::from_fn(|key| {
+            match notes[key] >= FRAME_THRESHOLD && last[key] < FRAME_THRESHOLD {
+                true => 1.0,
+                false => 0.0,
+            }
+        });
+        activations.notes.push(notes);
+        activations.onsets.push(onsets);
+    }
+    activations
+}
+
+/// The notes in some activations. A note starts at a peak of its onset, and lasts while
+/// the key stays active or until it's struck again.
+pub fn notes_from_activations(activations: &Activations) -> Vec<NoteEvent> {
+    let Activations {
+        frame_rate,
+        notes,
+        onsets,
+    } = activations;
+    let min_frames = (MIN_NOTE_SECS * frame_rate).ceil() as usize;
+    let mut events = vec![];
+    for key in 0..KEYS {
+        let onset = |frame: usize| onsets.get(frame).map_or(0.0, |o| o[key]);
+        // The frame where the held note started, and the last one it was active in.
+        let mut held: Option<(usize, usize)> = None;
+        let mut end_note = |(start, last): (usize, usize)| {
+            if last + 1 - start < min_frames {
+                return;
+            }
+            let level = notes[start..=last].iter().map(|n| n[key]).sum::<f32>();
+            let level = level / (last + 1 - start) as f32;
+            events.push(NoteEvent {
+                pitch: LOWEST_NOTE + key as u8,
+                start: start as f32 / frame_rate,
+                end: (last + 1) as f32 / frame_rate,
+                velocity: (level * 127.0).round().clamp(1.0, 127.0) as u8,
+            });
+        };
+        for (frame, active) in notes.iter().enumerate() {
+            let active = active[key] >= FRAME_THRESHOLD;
+            let struck = onset(frame) >= ONSET_THRESHOLD
+                && frame.checked_sub(1).map_or(0.0, onset) <= onset(frame)
+                && onset(frame + 1) <= onset(frame)
+                && active;
+            if struck {
+                if let Some(note) = held.take() {
+                    end_note(note);
+                }
+                held = Some((frame, frame));
+            } else if let Some((start, last)) = held {
+                if active {
+                    held = Some((start, frame));
+                } else if frame - last > TOLERANCE {
+                    end_note((start, last));
+                    held = None;
+                }
+            }
+        }
+        if let Some(note) = held {
+            end_note(note);
+        }
+    }
+    events.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.pitch.cmp(&b.pitch)));
+    events
+}
+
+/// Ticks in a quarter note, at 120 beats per minute there are twice as many in a second.
+const TICKS_PER_QUARTER: u16 = 480;
+const TICKS_PER_SEC: f32 = TICKS_PER_QUARTER as f32 * 2.0;
+
+/// A Standard MIDI File with a single track of notes at 120 beats per minute, which keeps
+/// their times in seconds intact without knowing the tempo of the piece.
+pub fn write_midi(notes: &[NoteEvent]) -> Vec<u8> {
+    let ticks = |secs: f32| (secs.max(0.0) * TICKS_PER_SEC).round() as u32;
+    // Note offs go before note ons at the same tick, so repeated notes don't cut each other.
+    let mut events: Vec<(u32, bool, &NoteEvent)> = notes
+        .iter()
+        .flat_map(|note| {
+            [
+                (ticks(note.start), true, note),
+                (ticks(note.end), false, note),
+            ]
+        })
+        .collect();
+    events.sort_by_key(|(tick, on, note)| (*tick, *on, note.pitch));
+
+    // A tempo of 500000 microseconds per quarter note, which is 120 beats per minute.
+    let mut track = vec![0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20];
+    let mut last_tick = 0;
+    for (tick, on, note) in events {
+        write_varlen(&mut track, tick - last_tick);
+        last_tick = tick;
+        match on {
+            true => track.extend([0x90, note.pitch & 0x7F, note.velocity.clamp(1, 127)]),
+            false => track.extend([0x80, note.pitch & 0x7F, 0]),
+        }
+    }
+    track.extend([0x00, 0xFF, 0x2F, 0x00]);
+
+    let mut midi = b"MThd".to_vec();
+    midi.extend(6u32.to_be_bytes());
+    midi.extend(0u16.to_be_bytes());
+    midi.extend(1u16.to_be_bytes());
+    midi.extend(TICKS_PER_QUARTER.to_be_bytes());
+    midi.extend(b"MTrk");
+    midi.extend((track.len() as u32).to_be_bytes());
+    midi.extend(track);
+    midi
+}
+
+/// Delta times are written 7 bits a byte, most significant first, with the top bit set
+/// in all bytes but the last.
+fn write_varlen(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(pitch: u8, secs: f32, sample_rate: u32) -> Vec<f32> {
+        let hz = 440.0 * 2f32.powf((pitch as f32 - 69.0) / 12.0);
+        (0..(secs * sample_rate as f32) as usize)
+            .map(|i| 0.5 * (2.0 * PI * hz * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn transcribes_a_melody() {
+        let mut audio = tone(69, 0.5, 32000);
+        audio.extend(tone(72, 0.5, 32000));
+        audio.extend(vec![0.0; 16000]);
+        let notes = notes_from_activations(&estimate_activations(&audio, 32000));
+
+        assert_eq!(
+            notes.iter().map(|n| n.pitch).collect::<Vec<_>>(),
+            vec![69, 72]
+        );
+        for (note, start) in notes.iter().zip([0.0, 0.5]) {
+            assert!((note.start - start).abs() < 0.1, "{note:?}");
+            assert!((note.end - start - 0.5).abs() < 0.1, "{note:?}");
+        }
+    }
+
+    #[test]
+    fn splits_notes_struck_again() {
+        let mut key = [0.0; KEYS];
+        key[60 - LOWEST_NOTE as usize] = 0.8;
+        let mut onset = [0.0; KEYS];
+        onset[60 - LOWEST_NOTE as usize] = 0.9;
+        let activations = Activations {
+            frame_rate: 100.0,
+            notes: vec![key; 40],
+            onsets: (0..40)
+                .map(|i| {
+                    if i == 0 || i == 20 {
+                        onset
+                    } else {
+                        [0.0; KEYS]
+                    }
+                })
+                .collect(),
+        };
+        let notes = notes_from_activations(&activations);
+        assert_eq!(
+            notes,
+            vec![
+                NoteEvent {
+                    pitch: 60,
+                    start: 0.0,
+                    end: 0.2,
+                    velocity: 102
+                },
+                NoteEvent {
+                    pitch: 60,
+                    start: 0.2,
+                    end: 0.4,
+                    velocity: 102
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_standard_midi_files() {
+        let midi = write_midi(&[NoteEvent {
+            pitch: 60,
+            start: 0.5,
+            end: 1.5,
+            velocity: 100,
+        }]);
+        assert_eq!(&midi[..8], b"MThd\0\0\0\x06");
+        assert_eq!(&midi[8..14], &[0, 0, 0, 1, 0x01, 0xE0]);
+        assert_eq!(&midi[14..18], b"MTrk");
+        let track = &midi[22..];
+        assert_eq!(
+            u32::from_be_bytes(midi[18..22].try_into().unwrap()) as usize,
+            track.len()
+        );
+        // 480 ticks to the note on, and 960 more to its off, both 2 byte delta times.
+        assert_eq!(
+            &track[7..],
+            &[0x83, 0x60, 0x90, 60, 100, 0x87, 0x40, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00]
+        );
+    }
+}
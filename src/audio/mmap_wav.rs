@@ -0,0 +1,222 @@
+//! WAV export that maps the output file into memory and writes the samples in place as
+//! they are stitched, so that the final render is never copied into a buffer of its own
+//! before reaching the disk.
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use tracing::debug_span;
+
+use crate::audio::audio_sink::AudioSink;
+use crate::audio::wav::wav_header;
+
+const BYTES_PER_SAMPLE: usize = std::mem::size_of::<f32>();
+
+/// A shared writable mapping of a whole file.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only accessed through the sink that owns it.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> Result<Self, String> {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: the file is open for reading and writing and is at least `len` bytes
+        // long, the mapping is unmapped when dropped.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!(
+                "Could not map the output file: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as self.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    fn sync(&self) -> Result<(), String> {
+        // SAFETY: the range is the whole mapping.
+        let result = unsafe { libc::msync(self.ptr as *mut libc::c_void, self.len, libc::MS_SYNC) };
+        if result != 0 {
+            return Err(format!(
+                "Could not write the output file: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the pointer and length are the ones mmap returned.
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Writes mono 32 bit float samples to a WAV file through a memory mapping. The file is
+/// sized for the expected number of samples up front, grown if more are pushed, and
+/// truncated to the samples actually written when finalized.
+pub struct MmapWavSink {
+    file: File,
+    map: Option<Mapping>,
+    sample_rate: u32,
+    header_len: usize,
+    capacity: usize,
+    written: usize,
+}
+
+impl MmapWavSink {
+    pub fn create(path: &Path, sample_rate: u32, expected_samples: usize) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|err| format!("Could not create {path:?}: {err}"))?;
+        let header_len = wav_header(0, sample_rate)
+            .map_err(|err| err.to_string())?
+            .len();
+        let mut sink = Self {
+            file,
+            map: None,
+            sample_rate,
+            header_len,
+            capacity: 0,
+            written: 0,
+        };
+        sink.remap(expected_samples.max(1))?;
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn byte_len(&self, samples: usize) -> usize {
+        self.header_len + samples * BYTES_PER_SAMPLE
+    }
+
+    /// Resizes the file to hold `capacity` samples and maps it again.
+    fn remap(&mut self, capacity: usize) -> Result<(), String> {
+        self.map = None;
+        let len = self.byte_len(capacity);
+        self.file
+            .set_len(len as u64)
+            .map_err(|err| format!("Could not resize the output file: {err}"))?;
+        self.map = Some(Mapping::new(&self.file, len)?);
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    fn map(&mut self) -> Result<&mut Mapping, String> {
+        self.map
+            .as_mut()
+            .ok_or_else(|| "WAV file was already finalized".to_string())
+    }
+
+    /// Makes the header account for the samples written so far.
+    fn write_header(&mut self) -> Result<(), String> {
+        let header = wav_header(self.written, self.sample_rate).map_err(|err| err.to_string())?;
+        self.map()?.bytes()[..header.len()].copy_from_slice(&header);
+        Ok(())
+    }
+}
+
+impl AudioSink for MmapWavSink {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let _span = debug_span!("export").entered();
+        self.map()?;
+        let needed = self.written + chunk.len();
+        if needed > self.capacity {
+            self.remap(needed.max(self.capacity * 2))?;
+        }
+        let start = self.byte_len(self.written);
+        let bytes = &mut self.map()?.bytes()[start..start + chunk.len() * BYTES_PER_SAMPLE];
+        for (dst, sample) in bytes.chunks_exact_mut(BYTES_PER_SAMPLE).zip(chunk) {
+            dst.copy_from_slice(&sample.to_le_bytes());
+        }
+        self.written = needed;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        // The file is playable up to this point, followed by the space not written yet.
+        self.write_header()?;
+        self.map()?.sync()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        let _span = debug_span!("export").entered();
+        self.write_header()?;
+        self.map()?.sync()?;
+        self.map = None;
+        self.file
+            .set_len(self.byte_len(self.written) as u64)
+            .map_err(|err| format!("Could not resize the output file: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::wav::encode_wav;
+
+    #[test]
+    fn writes_the_same_file_as_encoding_in_memory() -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("musicgpt-tests/mmap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+        let path = dir.join("out.wav");
+        let samples = (0..1000)
+            .map(|i| (i as f32 / 100.0).sin())
+            .collect::<Vec<_>>();
+
+        // Fewer samples than pushed are expected, so the file has to grow.
+        let mut sink = MmapWavSink::create(&path, 16000, 300)?;
+        for chunk in samples.chunks(128) {
+            sink.push(chunk)?;
+            sink.flush()?;
+        }
+        sink.finalize()?;
+        assert!(sink.push(&[0.0]).is_err());
+
+        let written = std::fs::read(&path).map_err(|err| err.to_string())?;
+        let encoded = encode_wav(samples, 16000).map_err(|err| err.to_string())?;
+        assert_eq!(written, encoded);
+        Ok(())
+    }
+
+    #[test]
+    fn flushed_files_are_readable() -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("musicgpt-tests/mmap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+        let path = dir.join("partial.wav");
+
+        let mut sink = MmapWavSink::create(&path, 1000, 10)?;
+        sink.push(&[0.1, 0.2, 0.3])?;
+        sink.flush()?;
+        let bytes = std::fs::read(&path).map_err(|err| err.to_string())?;
+        assert_eq!(crate::audio::wav::decode_wav(&bytes)?, vec![0.1, 0.2, 0.3]);
+        Ok(())
+    }
+}
@@ -1,4 +1,42 @@
+pub mod aiff;
+#[cfg(feature = "onnx")]
 mod audio_manager;
+pub mod audio_sink;
+pub mod daw_project;
+pub mod denoise;
+pub mod diff;
+pub mod dj_mix;
+pub mod dsp;
+pub mod effects;
+pub mod ending;
 pub mod extended_generation;
+pub mod fingerprint;
+pub mod gain_staging;
+pub mod game_layers;
+pub mod id3;
+pub mod inpaint;
+pub mod intro_outro;
+pub mod loop_points;
+pub mod loudness;
+pub mod markers;
+pub mod midi;
+#[cfg(unix)]
+pub mod mmap_wav;
+#[cfg(feature = "onnx")]
+pub mod opus_stream;
+pub mod pipeline;
+pub mod prompt_hints;
+pub mod remix;
+pub mod resample;
+pub mod ring_playback;
+pub mod slicer;
+pub mod spectrogram;
+pub mod spill_buffer;
+pub mod time_stretch;
+pub mod transitions;
+pub mod upmix;
+pub mod watermark;
+pub mod wav;
 
+#[cfg(feature = "onnx")]
 pub use audio_manager::{AudioManager, AudioStream};
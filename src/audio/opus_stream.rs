@@ -0,0 +1,172 @@
+//! Incremental Ogg/Opus encoding, for streaming audio to browsers while it is generated.
+
+use ogg::{PacketWriteEndInfo, PacketWriter};
+use opus_rs::{Application, OpusEncoder};
+
+use crate::audio::resample::LinearResampler;
+
+/// Opus only supports a few sample rates, audio is resampled to this one.
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// 20 ms frames.
+const FRAME_SIZE: usize = 960;
+/// Samples the decoder must discard at the beginning, the encoder's lookahead at 48 kHz.
+const PRE_SKIP: u16 = 312;
+const BITRATE: i32 = 96_000;
+const SERIAL: u32 = 0x4d47_5054;
+const MAX_PACKET_SIZE: usize = 4000;
+
+/// Encodes mono audio into an Ogg/Opus stream chunk by chunk. Each call returns the
+/// bytes of the Ogg pages completed so far, so they can be sent right away.
+pub struct OggOpusEncoder {
+    encoder: OpusEncoder,
+    resampler: LinearResampler,
+    /// Resampled audio that does not fill a whole frame yet.
+    pending: Vec<f32>,
+    writer: PacketWriter<'static, Vec<u8>>,
+    /// Resampled samples already encoded, used for the granule positions.
+    encoded: u64,
+}
+
+impl OggOpusEncoder {
+    pub fn new(sample_rate: u32) -> Result<Self, String> {
+        let mut encoder = OpusEncoder::new(OPUS_SAMPLE_RATE as i32, 1, Application::Audio)?;
+        encoder.bitrate_bps = BITRATE;
+
+        let mut writer = PacketWriter::new(vec![]);
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // Version.
+        head.push(1); // Channels.
+        head.extend(PRE_SKIP.to_le_bytes());
+        head.extend(sample_rate.to_le_bytes());
+        head.extend(0i16.to_le_bytes()); // Output gain.
+        head.push(0); // Mapping family.
+        writer
+            .write_packet(head, SERIAL, PacketWriteEndInfo::EndPage, 0)
+            .map_err(|err| err.to_string())?;
+
+        let vendor = format!("MusicGPT {}", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend((vendor.len() as u32).to_le_bytes());
+        tags.extend(vendor.as_bytes());
+        tags.extend(0u32.to_le_bytes()); // No user comments.
+        writer
+            .write_packet(tags, SERIAL, PacketWriteEndInfo::EndPage, 0)
+            .map_err(|err| err.to_string())?;
+
+        Ok(Self {
+            encoder,
+            resampler: LinearResampler::new(sample_rate, OPUS_SAMPLE_RATE),
+            pending: vec![],
+            writer,
+            encoded: 0,
+        })
+    }
+
+    /// Encodes the given samples. Audio that does not fill a whole Opus frame is kept
+    /// for the next call.
+    pub fn push(&mut self, samples: &[f32]) -> Result<Vec<u8>, String> {
+        let resampled = self.resampler.process(samples);
+        self.pending.extend(resampled);
+        let frames = self.pending.len() / FRAME_SIZE;
+        let pending = std::mem::take(&mut self.pending);
+        for (i, frame) in pending.chunks_exact(FRAME_SIZE).enumerate() {
+            let end = match i + 1 == frames {
+                true => PacketWriteEndInfo::EndPage,
+                false => PacketWriteEndInfo::NormalPacket,
+            };
+            self.write_frame(frame, FRAME_SIZE, end)?;
+        }
+        self.pending = pending[frames * FRAME_SIZE..].to_vec();
+        Ok(self.take_bytes())
+    }
+
+    /// Encodes the remaining audio, padding the last frame with silence, and ends the stream.
+    pub fn finish(mut self) -> Result<Vec<u8>, String> {
+        let mut bytes = self.push(&[])?;
+        let rest = self.resampler.flush();
+        self.pending.extend(rest);
+        let pending = std::mem::take(&mut self.pending);
+        let mut frames = pending.chunks(FRAME_SIZE).peekable();
+        if frames.peek().is_none() {
+            // Streams need at least one audio packet for carrying the end of stream flag.
+            self.write_frame(&[], 0, PacketWriteEndInfo::EndStream)?;
+        }
+        while let Some(frame) = frames.next() {
+            let end = match frames.peek() {
+                None => PacketWriteEndInfo::EndStream,
+                Some(_) => PacketWriteEndInfo::NormalPacket,
+            };
+            self.write_frame(frame, frame.len(), end)?;
+        }
+        bytes.extend(self.take_bytes());
+        Ok(bytes)
+    }
+
+    /// Encodes a frame, where only the first `len` samples are real audio.
+    fn write_frame(
+        &mut self,
+        frame: &[f32],
+        len: usize,
+        end: PacketWriteEndInfo,
+    ) -> Result<(), String> {
+        let mut input = frame.to_vec();
+        input.resize(FRAME_SIZE, 0.0);
+        let mut packet = vec![0; MAX_PACKET_SIZE];
+        let size = self.encoder.encode(&input, FRAME_SIZE, &mut packet)?;
+        packet.truncate(size);
+        self.encoded += len as u64;
+        // The granule position of the last page tells the decoder where the audio ends.
+        let granule = PRE_SKIP as u64 + self.encoded;
+        self.writer
+            .write_packet(packet, SERIAL, end, granule)
+            .map_err(|err| err.to_string())
+    }
+
+    fn take_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer.inner_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ogg::PacketReader;
+    use opus_rs::OpusDecoder;
+
+    use super::*;
+
+    #[test]
+    fn encodes_a_decodable_stream() -> Result<(), String> {
+        let sine: Vec<f32> = (0..32000)
+            .map(|i| 0.5 * (i as f32 * 440.0 * 2.0 * std::f32::consts::PI / 32000.0).sin())
+            .collect();
+        let mut encoder = OggOpusEncoder::new(32000)?;
+        let mut bytes = vec![];
+        for chunk in sine.chunks(6400) {
+            bytes.extend(encoder.push(chunk)?);
+        }
+        bytes.extend(encoder.finish()?);
+
+        let mut reader = PacketReader::new(Cursor::new(bytes));
+        let head = reader.read_packet_expected().map_err(|e| e.to_string())?;
+        assert!(head.data.starts_with(b"OpusHead"));
+        assert_eq!(&head.data[12..16], &32000u32.to_le_bytes());
+        let tags = reader.read_packet_expected().map_err(|e| e.to_string())?;
+        assert!(tags.data.starts_with(b"OpusTags"));
+
+        let mut decoder = OpusDecoder::new(48000, 1)?;
+        let mut decoded: Vec<f32> = vec![];
+        let mut last_granule = 0;
+        while let Some(packet) = reader.read_packet().map_err(|e| e.to_string())? {
+            let mut pcm = vec![0.0; FRAME_SIZE];
+            let n = decoder.decode(&packet.data, FRAME_SIZE, &mut pcm)?;
+            decoded.extend(&pcm[..n]);
+            last_granule = packet.absgp_page();
+        }
+        assert_eq!(last_granule, PRE_SKIP as u64 + 48000);
+        let energy = decoded.iter().map(|s| s * s).sum::<f32>() / decoded.len() as f32;
+        assert!(energy > 0.05, "decoded audio is too quiet: {energy}");
+        Ok(())
+    }
+}
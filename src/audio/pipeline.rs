@@ -0,0 +1,315 @@
+//! Bounded streaming pipeline between the audio generation, post-processing stages and
+//! sinks. Each step runs in its own thread, connected to the next one by a channel that
+//! holds at most a fixed number of chunks, so a slow consumer makes the producer block
+//! instead of piling up audio in memory.
+
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+use crate::audio::audio_sink::AudioSink;
+use crate::audio::extended_generation::{AuditionVerdict, SegmentRetry};
+use crate::audio::gain_staging::SegmentGain;
+use crate::audio::markers::SegmentMarker;
+
+/// Chunks that can be waiting between two steps of the pipeline before the previous
+/// step blocks.
+pub const DEFAULT_CAPACITY: usize = 16;
+
+type Stage = Box<dyn FnMut(Vec<f32>) -> Vec<f32> + Send>;
+
+/// Anything but audio that is done to the sink, in order with the audio.
+type Call = Box<dyn FnOnce(&mut dyn AudioSink) + Send>;
+
+enum Msg {
+    Chunk(Vec<f32>),
+    Call(Call),
+}
+
+pub struct StreamPipeline {
+    capacity: usize,
+    stages: Vec<Stage>,
+}
+
+impl StreamPipeline {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            stages: vec![],
+        }
+    }
+
+    /// Adds a post-processing step that transforms each chunk before it reaches the sink.
+    pub fn stage(mut self, stage: impl FnMut(Vec<f32>) -> Vec<f32> + Send + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Starts the pipeline threads. Audio pushed into the returned [PipelineInput] ends
+    /// up in `sink`, which is given back by [PipelineHandle::join] once finalized.
+    pub fn spawn<S: AudioSink + 'static>(self, sink: S) -> (PipelineInput, PipelineHandle<S>) {
+        let (tx, mut rx) = sync_channel(self.capacity);
+        for mut stage in self.stages {
+            let (stage_tx, stage_rx) = sync_channel(self.capacity);
+            std::thread::spawn(move || {
+                for msg in rx {
+                    let msg = match msg {
+                        Msg::Chunk(chunk) => Msg::Chunk(stage(chunk)),
+                        call => call,
+                    };
+                    // The next step stopped, dropping rx propagates that upstream.
+                    if stage_tx.send(msg).is_err() {
+                        return;
+                    }
+                }
+            });
+            rx = stage_rx;
+        }
+        let auditions = sink.auditions();
+        let handle = std::thread::spawn(move || consume(rx, sink));
+        let input = PipelineInput {
+            tx: Some(tx),
+            auditions,
+        };
+        (input, PipelineHandle { handle })
+    }
+}
+
+fn consume<S: AudioSink>(rx: Receiver<Msg>, mut sink: S) -> Result<S, String> {
+    for msg in rx {
+        match msg {
+            Msg::Chunk(chunk) => sink.push(&chunk)?,
+            Msg::Call(call) => call(&mut sink),
+        }
+    }
+    sink.finalize()?;
+    Ok(sink)
+}
+
+/// Entry point of a pipeline. Pushing blocks while the pipeline is full. Flushing and
+/// auditioning wait for the sink, so that their result is the one of the sink.
+pub struct PipelineInput {
+    tx: Option<SyncSender<Msg>>,
+    auditions: bool,
+}
+
+impl PipelineInput {
+    fn send(&self, msg: Msg) -> Result<(), String> {
+        let Some(tx) = &self.tx else {
+            return Err("Pipeline was already finalized".to_string());
+        };
+        tx.send(msg)
+            .map_err(|_| "Audio pipeline stopped consuming audio".to_string())
+    }
+
+    /// Runs `call` on the sink once the audio pushed before reached it, without waiting.
+    fn call(&self, call: impl FnOnce(&mut dyn AudioSink) + Send + 'static) {
+        // A pipeline that stopped reports why when pushing or joining.
+        let _ = self.send(Msg::Call(Box::new(call)));
+    }
+
+    /// Runs `call` on the sink once the audio pushed before reached it, and returns what
+    /// it returned.
+    fn call_sync<T: Send + 'static>(
+        &self,
+        call: impl FnOnce(&mut dyn AudioSink) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let (tx, rx) = sync_channel(1);
+        self.send(Msg::Call(Box::new(move |sink| {
+            let _ = tx.send(call(sink));
+        })))?;
+        rx.recv()
+            .map_err(|_| "Audio pipeline stopped consuming audio".to_string())?
+    }
+}
+
+impl AudioSink for PipelineInput {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.send(Msg::Chunk(chunk.to_vec()))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.call_sync(|sink| sink.flush())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.tx = None;
+        Ok(())
+    }
+
+    fn retried(&mut self, retry: &SegmentRetry) {
+        let retry = retry.clone();
+        self.call(move |sink| sink.retried(&retry));
+    }
+
+    fn normalized(&mut self, gain: &SegmentGain) {
+        let gain = gain.clone();
+        self.call(move |sink| sink.normalized(&gain));
+    }
+
+    fn generated(&mut self, segment: usize, audio: &[f32]) {
+        let audio = audio.to_vec();
+        self.call(move |sink| sink.generated(segment, &audio));
+    }
+
+    fn marked(&mut self, marker: &SegmentMarker) {
+        let marker = marker.clone();
+        self.call(move |sink| sink.marked(&marker));
+    }
+
+    fn auditions(&self) -> bool {
+        self.auditions
+    }
+
+    fn audition(&mut self, segment: usize, audio: &[f32]) -> Result<AuditionVerdict, String> {
+        let audio = audio.to_vec();
+        self.call_sync(move |sink| sink.audition(segment, &audio))
+    }
+}
+
+pub struct PipelineHandle<S> {
+    handle: JoinHandle<Result<S, String>>,
+}
+
+impl<S> PipelineHandle<S> {
+    /// Waits for all the audio to reach the sink. The input must have been finalized or
+    /// dropped before, otherwise this blocks forever.
+    pub fn join(self) -> Result<S, String> {
+        self.handle
+            .join()
+            .map_err(|_| "Audio pipeline panicked".to_string())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::audio::audio_sink::MemorySink;
+
+    use super::*;
+
+    /// Consumes audio slowly, keeping track of how many chunks it received.
+    struct SlowSink {
+        received: Arc<AtomicUsize>,
+    }
+
+    impl AudioSink for SlowSink {
+        fn push(&mut self, _chunk: &[f32]) -> Result<(), String> {
+            std::thread::sleep(Duration::from_millis(5));
+            self.received.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn applies_stages_in_order() -> Result<(), String> {
+        let (mut input, handle) = StreamPipeline::new(2)
+            .stage(|chunk| chunk.into_iter().map(|s| s * 2.0).collect())
+            .stage(|chunk| chunk.into_iter().map(|s| s + 1.0).collect())
+            .spawn(MemorySink::new());
+        for i in 0..10 {
+            input.push(&[i as f32])?;
+        }
+        input.flush()?;
+        input.finalize()?;
+        let audio = handle.join()?.into_inner();
+        assert_eq!(
+            audio,
+            (0..10).map(|i| i as f32 * 2.0 + 1.0).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_the_producer_when_full() -> Result<(), String> {
+        let received = Arc::new(AtomicUsize::new(0));
+        let (mut input, handle) = StreamPipeline::new(1).stage(|chunk| chunk).spawn(SlowSink {
+            received: received.clone(),
+        });
+        for i in 0..50 {
+            input.push(&[0.0; 100])?;
+            // At most one chunk waits in each channel, plus one in each thread.
+            assert!(i + 1 - received.load(Ordering::SeqCst) <= 4);
+        }
+        input.finalize()?;
+        handle.join()?;
+        assert_eq!(received.load(Ordering::SeqCst), 50);
+        Ok(())
+    }
+
+    #[test]
+    fn forwards_flushes_and_auditions_in_order() -> Result<(), String> {
+        /// Fails flushing once it has 2 samples, and rejects segments with 3.
+        struct Picky(Vec<f32>, usize);
+        impl AudioSink for Picky {
+            fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+                self.0.extend_from_slice(chunk);
+                Ok(())
+            }
+            fn flush(&mut self) -> Result<(), String> {
+                match self.0.len() {
+                    2 => Err("interrupted".to_string()),
+                    _ => Ok(()),
+                }
+            }
+            fn finalize(&mut self) -> Result<(), String> {
+                Ok(())
+            }
+            fn generated(&mut self, _segment: usize, audio: &[f32]) {
+                self.1 += audio.len();
+            }
+            fn auditions(&self) -> bool {
+                true
+            }
+            fn audition(&mut self, _: usize, _: &[f32]) -> Result<AuditionVerdict, String> {
+                match self.0.len() {
+                    3 => Ok(AuditionVerdict::Reject),
+                    _ => Ok(AuditionVerdict::Accept),
+                }
+            }
+        }
+
+        let (mut input, handle) = StreamPipeline::new(1)
+            .stage(|chunk| chunk)
+            .spawn(Picky(vec![], 0));
+        assert!(input.auditions());
+        input.push(&[1.0])?;
+        input.flush()?;
+        input.push(&[2.0])?;
+        assert_eq!(input.flush(), Err("interrupted".to_string()));
+        input.push(&[3.0])?;
+        assert_eq!(input.audition(0, &[])?, AuditionVerdict::Reject);
+        input.generated(0, &[0.0; 5]);
+        input.finalize()?;
+        let sink = handle.join()?;
+        assert_eq!((sink.0, sink.1), (vec![1.0, 2.0, 3.0], 5));
+        Ok(())
+    }
+
+    #[test]
+    fn fails_when_the_sink_fails() {
+        struct FailingSink;
+        impl AudioSink for FailingSink {
+            fn push(&mut self, _chunk: &[f32]) -> Result<(), String> {
+                Err("disk full".to_string())
+            }
+            fn finalize(&mut self) -> Result<(), String> {
+                Ok(())
+            }
+        }
+
+        let (mut input, handle) = StreamPipeline::new(1).spawn(FailingSink);
+        let mut pushed = Ok(());
+        for _ in 0..10 {
+            pushed = input.push(&[0.0]);
+        }
+        assert!(pushed.is_err());
+        assert_eq!(handle.join().err(), Some("disk full".to_string()));
+    }
+}
@@ -0,0 +1,290 @@
+//! Reads how long a piece should be and how it should start and end from the wording of
+//! its prompt, like "a three and a half minute track that fades out".
+
+use std::fmt;
+
+use crate::audio::extended_generation::ExtendedGenerationConfig;
+use crate::audio::intro_outro::{Bookend, IntroStyle, OutroStyle};
+
+/// What a prompt says about the shape of the piece, besides its music.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PromptHints {
+    /// Duration in seconds.
+    pub secs: Option<f32>,
+    pub intro: Option<IntroStyle>,
+    pub outro: Option<OutroStyle>,
+    /// The piece stops dead instead of getting an automatic ending.
+    pub cold_ending: bool,
+}
+
+const INTROS: &[(&str, IntroStyle)] = &[
+    ("fade in", IntroStyle::FadeFromSilence),
+    ("fades in", IntroStyle::FadeFromSilence),
+    ("fading in", IntroStyle::FadeFromSilence),
+    ("swells in", IntroStyle::AmbientSwell),
+    ("ambient intro", IntroStyle::AmbientSwell),
+    ("drum intro", IntroStyle::DrumPickup),
+    ("drum pickup", IntroStyle::DrumPickup),
+    ("intro", IntroStyle::FadeFromSilence),
+];
+
+const OUTROS: &[(&str, OutroStyle)] = &[
+    ("fade out", OutroStyle::FadeToSilence),
+    ("fades out", OutroStyle::FadeToSilence),
+    ("fading out", OutroStyle::FadeToSilence),
+    ("ambient outro", OutroStyle::AmbientSwell),
+    ("rings out", OutroStyle::AmbientSwell),
+    ("outro", OutroStyle::FadeToSilence),
+];
+
+const COLD_ENDINGS: &[&str] = &["cold ending", "abrupt ending", "ends abruptly", "hard stop"];
+
+impl PromptHints {
+    pub fn parse(prompt: &str) -> Self {
+        let words = words(prompt);
+        let text = format!(" {} ", words.join(" "));
+        let has = |phrase: &str| text.contains(&format!(" {phrase} "));
+        Self {
+            secs: duration(&words),
+            intro: INTROS.iter().find(|(p, _)| has(p)).map(|(_, s)| *s),
+            outro: OUTROS.iter().find(|(p, _)| has(p)).map(|(_, s)| *s),
+            cold_ending: COLD_ENDINGS.iter().any(|p| has(p)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets the intro, outro and ending of `config`. The duration is left to the caller,
+    /// as it may be overridden explicitly.
+    pub fn apply(&self, config: &mut ExtendedGenerationConfig) {
+        if let Some(style) = self.intro {
+            let duration = match style {
+                IntroStyle::AmbientSwell => 8.0,
+                _ => 4.0,
+            };
+            config.intro_outro.intro = Some(Bookend {
+                style,
+                duration,
+                gain: 0.5,
+            });
+        }
+        if let Some(style) = self.outro {
+            let duration = match style {
+                OutroStyle::AmbientSwell => 10.0,
+                _ => 6.0,
+            };
+            config.intro_outro.outro = Some(Bookend {
+                style,
+                duration,
+                gain: 0.5,
+            });
+        }
+        if self.cold_ending {
+            config.ending = None;
+            config.intro_outro.outro = None;
+        }
+    }
+}
+
+/// The interpretation, like "3m30s, fading in, with a cold ending".
+impl fmt::Display for PromptHints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(secs) = self.secs {
+            let minutes = (secs / 60.0).floor();
+            let rest = ((secs - minutes * 60.0) * 100.0).round() / 100.0;
+            parts.push(match (minutes, rest) {
+                (0.0, _) => format!("{rest}s"),
+                (_, 0.0) => format!("{minutes}m"),
+                _ => format!("{minutes}m{rest}s"),
+            });
+        }
+        match self.intro {
+            Some(IntroStyle::FadeFromSilence) => parts.push("fading in".into()),
+            Some(IntroStyle::AmbientSwell) => parts.push("swelling in".into()),
+            Some(IntroStyle::DrumPickup) => parts.push("with a drum pickup".into()),
+            None => {}
+        }
+        match (self.outro, self.cold_ending) {
+            (_, true) => parts.push("with a cold ending".into()),
+            (Some(OutroStyle::FadeToSilence), _) => parts.push("fading out".into()),
+            (Some(OutroStyle::AmbientSwell), _) => parts.push("ringing out".into()),
+            (None, _) => {}
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Lowercase words, with hyphens as spaces and numbers split from glued units, like
+/// "30sec" or "2min". Single letter units are left out, so that "80s" stays a decade.
+fn words(prompt: &str) -> Vec<String> {
+    let mut words = vec![];
+    for word in prompt
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '-' || c == ',')
+    {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+        let split = word
+            .find(|c: char| c.is_alphabetic())
+            .filter(|&i| i > 0 && word[..i].parse::<f32>().is_ok());
+        match split {
+            Some(i) => words.extend([word[..i].to_string(), word[i..].to_string()]),
+            None if !word.is_empty() => words.push(word.to_string()),
+            None => {}
+        }
+    }
+    words
+}
+
+/// The first duration in `words`, summing parts like "2 minutes and 30 seconds".
+fn duration(words: &[String]) -> Option<f32> {
+    let mut total = None;
+    let mut i = 0;
+    while i < words.len() {
+        if let Some(secs) = clock(&words[i]) {
+            return Some(secs);
+        }
+        match quantity(words, i) {
+            Some((secs, next)) => {
+                total = Some(total.unwrap_or(0.0) + secs);
+                i = next;
+                if words.get(i).is_some_and(|w| w == "and") && quantity(words, i + 1).is_some() {
+                    i += 1;
+                }
+            }
+            None if total.is_some() => break,
+            None => i += 1,
+        }
+    }
+    total
+}
+
+/// A "2:30" duration.
+fn clock(word: &str) -> Option<f32> {
+    let (minutes, secs) = word.split_once(':')?;
+    let secs = secs.parse::<u32>().ok().filter(|&s| s < 60)?;
+    Some(minutes.parse::<u32>().ok()? as f32 * 60.0 + secs as f32)
+}
+
+/// A number followed by a unit, like "three and a half minutes", starting at `i`.
+/// Returns the seconds and where it stops.
+fn quantity(words: &[String], i: usize) -> Option<(f32, usize)> {
+    let (mut n, mut i) = number(words, i)?;
+    if words[i..].starts_with(&["and".into(), "a".into(), "half".into()]) {
+        n += 0.5;
+        i += 3;
+    }
+    let unit = match words.get(i)?.as_str() {
+        "hour" | "hours" | "hr" | "hrs" => 3600.0,
+        "minute" | "minutes" | "min" | "mins" => 60.0,
+        "second" | "seconds" | "sec" | "secs" => 1.0,
+        _ => return None,
+    };
+    Some((n * unit, i + 1))
+}
+
+/// A number in digits or words starting at `i`, and where it stops.
+fn number(words: &[String], i: usize) -> Option<(f32, usize)> {
+    let word = words.get(i)?;
+    if let Ok(n) = word.parse::<f32>() {
+        return Some((n, i + 1));
+    }
+    if word == "a" || word == "an" {
+        return Some((1.0, i + 1));
+    }
+    if word == "half" && words.get(i + 1).is_some_and(|w| w == "a" || w == "an") {
+        return Some((0.5, i + 2));
+    }
+    let mut n = small_number(word)?;
+    let mut i = i + 1;
+    if n >= 20.0 && n % 10.0 == 0.0 {
+        if let Some(units) = words
+            .get(i)
+            .and_then(|w| small_number(w))
+            .filter(|&u| u < 10.0)
+        {
+            n += units;
+            i += 1;
+        }
+    }
+    Some((n, i))
+}
+
+fn small_number(word: &str) -> Option<f32> {
+    const NUMBERS: &[&str] = &[
+        "zero",
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+    ];
+    const TENS: &[&str] = &[
+        "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+    ];
+    if let Some(n) = NUMBERS.iter().position(|&w| w == word) {
+        return Some(n as f32);
+    }
+    TENS.iter()
+        .position(|&w| w == word)
+        .map(|n| (n + 2) as f32 * 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_durations_and_structure_from_prompts() {
+        let secs = |prompt| PromptHints::parse(prompt).secs;
+        assert_eq!(secs("a three and a half minute track"), Some(210.0));
+        assert_eq!(secs("lofi beat, 90 seconds long"), Some(90.0));
+        assert_eq!(secs("a 1.5-minute jingle"), Some(90.0));
+        assert_eq!(secs("2 minutes and 30 seconds of jazz"), Some(150.0));
+        assert_eq!(secs("twenty-five seconds of rain"), Some(25.0));
+        assert_eq!(secs("a 2:30 ballad"), Some(150.0));
+        assert_eq!(secs("half a minute of drums"), Some(30.0));
+        assert_eq!(secs("45sec synthwave"), Some(45.0));
+        assert_eq!(secs("80s synthwave"), None);
+        assert_eq!(secs("a minute of piano"), Some(60.0));
+        assert_eq!(secs("a calm track with 2 guitars"), None);
+
+        let hints = PromptHints::parse("a four minute ambient piece that fades in and rings out");
+        assert_eq!(hints.intro, Some(IntroStyle::FadeFromSilence));
+        assert_eq!(hints.outro, Some(OutroStyle::AmbientSwell));
+        assert_eq!(hints.to_string(), "4m, fading in, ringing out");
+
+        let hints =
+            PromptHints::parse("drum and bass with a drum intro and a cold ending, 75 seconds");
+        assert_eq!(
+            hints.to_string(),
+            "1m15s, with a drum pickup, with a cold ending"
+        );
+        let mut config = ExtendedGenerationConfig::default();
+        hints.apply(&mut config);
+        assert_eq!(
+            config.intro_outro.intro.unwrap().style,
+            IntroStyle::DrumPickup
+        );
+        assert_eq!(config.intro_outro.outro, None);
+        assert_eq!(config.ending, None);
+
+        assert!(PromptHints::parse("happy rock").is_empty());
+    }
+}
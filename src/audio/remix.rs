@@ -0,0 +1,291 @@
+//! Remixes, which generate a track again from a new prompt while keeping its structure.
+//! The track is split into sections where its sound changes, each one is generated from
+//! the new prompt with a description of its energy, and the new audio is made to follow
+//! the loudness of the track.
+
+use std::ops::Range;
+
+use crate::audio::dsp::{crossfade_into, db_to_gain, gain_to_db, rms, FadeCurve};
+use crate::audio::inpaint::best_offset;
+use crate::audio::loop_points::{cosine, spectrum, BINS};
+
+/// Longest section, in seconds, so that it can be generated at once with its crossfade.
+pub const MAX_SECTION_SECS: f32 = 26.0;
+/// Shortest section, in seconds. Changes closer to each other are one section.
+const MIN_SECTION_SECS: f32 = 8.0;
+/// Length of the frames the track is analysed in, in seconds.
+const FRAME_SECS: f32 = 0.5;
+/// Seconds at each side of a frame that are compared for telling how much the sound
+/// changes there.
+const CONTEXT_SECS: f32 = 4.0;
+/// Least change of sound that starts a section.
+const MIN_NOVELTY: f32 = 0.05;
+/// Seconds each section is generated longer than needed, for aligning it with the one
+/// before.
+const SLACK_SECS: f32 = 2.0;
+/// Length of the windows whose loudness the remix follows, in seconds.
+const ENVELOPE_SECS: f32 = 1.0;
+/// The remix is never turned up or down by more than these decibels.
+const MAX_GAIN_DB: f32 = 12.0;
+
+/// A section of a track, in samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub start: usize,
+    pub end: usize,
+    /// Loudness of the section, in dBFS.
+    pub level_db: f32,
+}
+
+/// Splits a track into sections, starting new ones where its spectrum or its loudness
+/// changes the most. Sections longer than [MAX_SECTION_SECS] are split evenly.
+pub fn sections(audio: &[f32], sample_rate: usize) -> Vec<Section> {
+    let frame = ((FRAME_SECS * sample_rate as f32) as usize).max(1);
+    let frames = audio.len() / frame;
+    let spectra: Vec<[f32; BINS]> = (0..frames)
+        .map(|i| spectrum(&audio[i * frame..(i + 1) * frame], sample_rate))
+        .collect();
+    let levels: Vec<f32> = (0..frames)
+        .map(|i| gain_to_db(rms(&audio[i * frame..(i + 1) * frame]).max(1e-5)))
+        .collect();
+    let mean = |range: Range<usize>| {
+        let n = range.len() as f32;
+        let mut spectrum = [0.0; BINS];
+        for frame in &spectra[range.clone()] {
+            spectrum
+                .iter_mut()
+                .zip(frame)
+                .for_each(|(sum, bin)| *sum += bin / n);
+        }
+        (spectrum, levels[range].iter().sum::<f32>() / n)
+    };
+
+    // How much the sound changes at each frame, comparing the frames before and after it,
+    // with a change of 20dB counting as much as an unrelated spectrum.
+    let context = (CONTEXT_SECS / FRAME_SECS) as usize;
+    let mut novelty: Vec<(usize, f32)> = (context..(frames + 1).saturating_sub(context))
+        .map(|i| {
+            let (before, level_before) = mean(i - context..i);
+            let (after, level_after) = mean(i..i + context);
+            let change = 1.0 - cosine(&before, &after) + (level_after - level_before).abs() / 20.0;
+            (i, change)
+        })
+        .collect();
+    novelty.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    let min_frames = (MIN_SECTION_SECS / FRAME_SECS) as usize;
+    let mut boundaries = vec![0, frames];
+    for (i, change) in novelty {
+        if change >= MIN_NOVELTY && boundaries.iter().all(|b| b.abs_diff(i) >= min_frames) {
+            boundaries.push(i);
+        }
+    }
+    boundaries.sort();
+
+    let max_len = (MAX_SECTION_SECS * sample_rate as f32) as usize;
+    let mut edges: Vec<usize> = boundaries.iter().map(|b| b * frame).collect();
+    *edges.last_mut().expect("Programming error") = audio.len();
+    let mut sections = vec![];
+    for pair in edges.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let parts = (end - start).div_ceil(max_len).max(1);
+        for part in 0..parts {
+            let (start, end) = (
+                start + (end - start) * part / parts,
+                start + (end - start) * (part + 1) / parts,
+            );
+            if start < end {
+                sections.push(Section {
+                    start,
+                    end,
+                    level_db: gain_to_db(rms(&audio[start..end]).max(1e-5)),
+                });
+            }
+        }
+    }
+    sections
+}
+
+/// The prompt each section is generated with: the new prompt, with how loud the section
+/// is next to the loudest one, and whether it gets louder or quieter than the one before.
+pub fn section_prompts(prompt: &str, sections: &[Section]) -> Vec<String> {
+    let loudest = sections
+        .iter()
+        .map(|section| section.level_db)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let mut previous: Option<f32> = None;
+    sections
+        .iter()
+        .map(|section| {
+            let mut words = vec![match loudest - section.level_db {
+                quieter if quieter <= 3.0 => "intense, full energy",
+                quieter if quieter <= 9.0 => "steady energy",
+                _ => "quiet, sparse",
+            }];
+            match previous.map(|level| section.level_db - level) {
+                Some(change) if change > 3.0 => words.push("building up"),
+                Some(change) if change < -3.0 => words.push("winding down"),
+                _ => {}
+            }
+            previous = Some(section.level_db);
+            format!("{prompt} ({})", words.join(", "))
+        })
+        .collect()
+}
+
+/// Samples that need to be generated for a section that is crossfaded over the next one
+/// for `crossfade` samples.
+pub fn section_len(section: &Section, crossfade: usize, sample_rate: usize) -> usize {
+    section.end - section.start + crossfade + (SLACK_SECS * sample_rate as f32) as usize
+}
+
+/// Stitches the audio generated for each section, which should be [section_len] samples
+/// long, into a remix as long as `audio`. Each section is taken from where it sounds the
+/// most like the end of the one before, and the remix follows the loudness of `audio`.
+pub fn remix(
+    audio: &[f32],
+    sections: &[Section],
+    generated: &[Vec<f32>],
+    crossfade: usize,
+    sample_rate: usize,
+) -> Result<Vec<f32>, String> {
+    if sections.len() != generated.len() {
+        return Err(format!(
+            "There are {} sections, but audio was generated for {}",
+            sections.len(),
+            generated.len()
+        ));
+    }
+    let mut remix: Vec<f32> = Vec::with_capacity(audio.len() + crossfade);
+    for (section, generated) in sections.iter().zip(generated) {
+        let needed = section.end - section.start + crossfade;
+        if generated.len() < needed {
+            return Err(format!(
+                "The section at sample {} has {} samples, but {needed} are needed",
+                section.start,
+                generated.len()
+            ));
+        }
+        // The end of the section before rides past its end, and this one fades in over it.
+        let overlap = remix.len().saturating_sub(section.start);
+        let joins = [(section.start, 0, overlap)];
+        let offset = best_offset(&remix, generated, needed, &joins, sample_rate);
+        let generated = &generated[offset..offset + needed];
+        crossfade_into(
+            &mut remix[section.start..],
+            &generated[..overlap],
+            FadeCurve::EqualPower,
+        );
+        remix.extend(&generated[overlap..]);
+    }
+    remix.truncate(audio.len());
+    follow_loudness(&mut remix, audio, sample_rate);
+    Ok(remix)
+}
+
+/// Turns `audio` up or down to the loudness of `reference` in each window of
+/// [ENVELOPE_SECS], ramping the gain between the middles of the windows.
+fn follow_loudness(audio: &mut [f32], reference: &[f32], sample_rate: usize) {
+    let window = ((ENVELOPE_SECS * sample_rate as f32) as usize).max(1);
+    let gains: Vec<f32> = (0..audio.len().div_ceil(window))
+        .map(|i| {
+            let range = i * window..((i + 1) * window).min(audio.len());
+            let target =
+                rms(&reference[range.start.min(reference.len())..range.end.min(reference.len())]);
+            match rms(&audio[range]) {
+                level if level > 0.0 => (gain_to_db(target.max(1e-6)) - gain_to_db(level))
+                    .clamp(-MAX_GAIN_DB, MAX_GAIN_DB),
+                _ => 0.0,
+            }
+        })
+        .collect();
+    for (i, sample) in audio.iter_mut().enumerate() {
+        let at = (i as f32 / window as f32 - 0.5).max(0.0);
+        let before = (at as usize).min(gains.len() - 1);
+        let after = (before + 1).min(gains.len() - 1);
+        let t = at - before as f32;
+        *sample *= db_to_gain(gains[before] * (1.0 - t) + gains[after] * t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    const RATE: usize = 8000;
+
+    /// Tones of whole hertz, which repeat every second, so the phase stays exact in long
+    /// tracks.
+    fn tone(hz: f32, amplitude: f32, secs: f32) -> Vec<f32> {
+        (0..(secs * RATE as f32) as usize)
+            .map(|i| amplitude * (2.0 * PI * hz * (i % RATE) as f32 / RATE as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn splits_tracks_where_their_sound_changes() {
+        let mut audio = tone(220.0, 0.1, 12.0);
+        audio.extend(tone(1500.0, 0.4, 12.0));
+        let found = sections(&audio, RATE);
+        assert_eq!(found.len(), 2, "{found:?}");
+        assert_eq!(found[0].start, 0);
+        assert_eq!(found[0].end, found[1].start);
+        assert!(found[0].end.abs_diff(12 * RATE) <= RATE / 2, "{found:?}");
+        assert_eq!(found[1].end, audio.len());
+        assert!((found[1].level_db - found[0].level_db - 12.0).abs() < 0.5);
+
+        // A track that doesn't change is split evenly into sections the model can generate.
+        let found = sections(&tone(440.0, 0.3, 70.0), RATE);
+        let lens: Vec<usize> = found.iter().map(|s| s.end - s.start).collect();
+        assert_eq!(lens, vec![186666, 186667, 186667]);
+    }
+
+    #[test]
+    fn describes_the_energy_of_sections() {
+        let section = |level_db| Section {
+            start: 0,
+            end: 1,
+            level_db,
+        };
+        let prompts = section_prompts("jazz", &[section(-30.0), section(-16.0), section(-20.0)]);
+        assert_eq!(
+            prompts,
+            vec![
+                "jazz (quiet, sparse)",
+                "jazz (intense, full energy, building up)",
+                "jazz (steady energy, winding down)",
+            ]
+        );
+    }
+
+    #[test]
+    fn stitches_sections_at_the_loudness_of_the_track() {
+        let mut audio = tone(220.0, 0.1, 10.0);
+        audio.extend(tone(220.0, 0.4, 10.0));
+        let found = vec![
+            Section {
+                start: 0,
+                end: 10 * RATE,
+                level_db: -23.0,
+            },
+            Section {
+                start: 10 * RATE,
+                end: 20 * RATE,
+                level_db: -11.0,
+            },
+        ];
+        let generated: Vec<Vec<f32>> = found
+            .iter()
+            .map(|s| tone(660.0, 0.2, section_len(s, RATE, RATE) as f32 / RATE as f32))
+            .collect();
+        let remixed = remix(&audio, &found, &generated, RATE, RATE).unwrap();
+
+        assert_eq!(remixed.len(), audio.len());
+        let level = |range: Range<usize>| rms(&remixed[range]);
+        assert!((level(2 * RATE..8 * RATE) - 0.1 / 2f32.sqrt()).abs() < 0.005);
+        assert!((level(13 * RATE..18 * RATE) - 0.4 / 2f32.sqrt()).abs() < 0.02);
+        let err = remix(&audio, &found, &generated[..1], RATE, RATE).unwrap_err();
+        assert!(err.contains("There are 2 sections"), "{err}");
+    }
+}
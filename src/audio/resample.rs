@@ -0,0 +1,79 @@
+//! Sample rate conversion.
+
+/// Converts the sample rate of a whole piece of audio.
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    let mut resampler = LinearResampler::new(from, to);
+    let mut out = resampler.process(samples);
+    out.extend(resampler.flush());
+    out
+}
+
+/// Streaming linear interpolation resampler.
+pub struct LinearResampler {
+    /// Input samples per output sample.
+    step: f64,
+    /// Position of the next output sample, relative to the start of `buffer`.
+    pos: f64,
+    buffer: Vec<f32>,
+}
+
+impl LinearResampler {
+    pub fn new(from: u32, to: u32) -> Self {
+        Self {
+            step: from as f64 / to as f64,
+            pos: 0.0,
+            buffer: vec![],
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        self.buffer.extend(input);
+        let mut out = vec![];
+        while self.pos + 1.0 < self.buffer.len() as f64 {
+            let i = self.pos as usize;
+            let frac = (self.pos - i as f64) as f32;
+            out.push(self.buffer[i] * (1.0 - frac) + self.buffer[i + 1] * frac);
+            self.pos += self.step;
+        }
+        let consumed = (self.pos as usize).min(self.buffer.len());
+        self.buffer.drain(..consumed);
+        self.pos -= consumed as f64;
+        out
+    }
+
+    /// Produces the output for the last input samples, which have nothing to be
+    /// interpolated with.
+    pub fn flush(&mut self) -> Vec<f32> {
+        let mut out = vec![];
+        while self.pos < self.buffer.len() as f64 {
+            out.push(self.buffer[self.pos as usize]);
+            self.pos += self.step;
+        }
+        self.buffer.clear();
+        self.pos = 0.0;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resamples_to_the_target_rate() {
+        let mut resampler = LinearResampler::new(32000, 48000);
+        let mut out = vec![];
+        for chunk in vec![1.0; 32000].chunks(333) {
+            out.extend(resampler.process(chunk));
+        }
+        out.extend(resampler.flush());
+        assert_eq!(out.len(), 48000);
+        assert!(out.iter().all(|s| *s == 1.0));
+    }
+
+    #[test]
+    fn resamples_whole_pieces() {
+        let out = resample(&[0.0, 1.0, 2.0, 3.0], 2, 1);
+        assert_eq!(out, vec![0.0, 2.0]);
+    }
+}
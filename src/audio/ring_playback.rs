@@ -0,0 +1,222 @@
+//! Real-time playback of audio that is still being generated, for hosts like game engines
+//! that pull audio from their own audio thread. Generation pushes into a lock-free ring
+//! buffer and the audio thread drains it without allocating or locking, covering for
+//! generation falling behind with an [UnderrunStrategy].
+
+use std::time::Duration;
+
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::audio::audio_sink::AudioSink;
+
+/// What the player outputs when the ring buffer runs out of audio before generation
+/// finished.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnderrunStrategy {
+    /// Output silence until audio is available again.
+    Silence,
+    /// Keep repeating the last `samples` that were played, like looping the last bar.
+    LoopLast { samples: usize },
+    /// Keep repeating the last `samples` that were played while fading them out, so the
+    /// music does not stop abruptly.
+    FadeToSilence { samples: usize },
+}
+
+impl UnderrunStrategy {
+    /// Loops the last bar of music with the given tempo.
+    pub fn loop_last_bar(bpm: f32, beats_per_bar: usize, sample_rate: usize) -> Self {
+        let samples = (60.0 / bpm * beats_per_bar as f32 * sample_rate as f32) as usize;
+        Self::LoopLast { samples }
+    }
+
+    fn history_len(&self) -> usize {
+        match self {
+            UnderrunStrategy::Silence => 0,
+            UnderrunStrategy::LoopLast { samples } => *samples,
+            UnderrunStrategy::FadeToSilence { samples } => *samples,
+        }
+    }
+}
+
+/// Creates a ring buffer that holds up to `capacity` samples.
+pub fn ring_playback(capacity: usize, strategy: UnderrunStrategy) -> (RingWriter, RingPlayer) {
+    let (producer, consumer) = RingBuffer::new(capacity);
+    let history_len = strategy.history_len();
+    let player = RingPlayer {
+        consumer,
+        strategy,
+        history: vec![0.0; history_len],
+        history_pos: 0,
+        underrun_pos: 0,
+        underruns: 0,
+    };
+    (RingWriter { producer }, player)
+}
+
+/// Generation side of the ring buffer. Pushing waits for the player to make room, so
+/// generation never gets too far ahead of playback.
+pub struct RingWriter {
+    producer: Producer<f32>,
+}
+
+impl AudioSink for RingWriter {
+    fn push(&mut self, mut chunk: &[f32]) -> Result<(), String> {
+        while !chunk.is_empty() {
+            let (_, rest) = self.producer.push_partial_slice(chunk);
+            if rest.len() == chunk.len() {
+                if self.producer.is_abandoned() {
+                    return Err("Audio player was dropped".to_string());
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            chunk = rest;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        // The player notices the end once the writer is dropped and the buffer is empty.
+        Ok(())
+    }
+}
+
+/// Playback side of the ring buffer, meant to be drained from an audio thread.
+pub struct RingPlayer {
+    consumer: Consumer<f32>,
+    strategy: UnderrunStrategy,
+    /// The last played samples, circular, used for covering underruns.
+    history: Vec<f32>,
+    history_pos: usize,
+    /// Samples output since the current underrun started.
+    underrun_pos: usize,
+    underruns: usize,
+}
+
+impl RingPlayer {
+    /// Fills `out` with the next samples. Never blocks nor allocates. Returns how many of
+    /// them are actual generated audio, the rest being covered by the underrun strategy.
+    pub fn fill(&mut self, out: &mut [f32]) -> usize {
+        let read_len = self.read(out);
+        let rest = &mut out[read_len..];
+        if rest.is_empty() {
+            return read_len;
+        }
+
+        if self.is_finished() {
+            rest.fill(0.0);
+            return read_len;
+        }
+        if self.underrun_pos == 0 {
+            self.underruns += 1;
+        }
+        for sample in rest.iter_mut() {
+            *sample = self.conceal();
+            self.underrun_pos += 1;
+        }
+        read_len
+    }
+
+    /// Moves the samples that are available into the beginning of `out`, returning how
+    /// many. Never blocks nor allocates, and leaves the rest of `out` untouched.
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        let (read, _) = self.consumer.pop_partial_slice(out);
+        if !read.is_empty() {
+            self.underrun_pos = 0;
+        }
+        if !self.history.is_empty() {
+            for sample in read.iter() {
+                self.history[self.history_pos] = *sample;
+                self.history_pos = (self.history_pos + 1) % self.history.len();
+            }
+        }
+        read.len()
+    }
+
+    fn conceal(&self) -> f32 {
+        let looped = || {
+            // Starts from the oldest sample in the history.
+            let len = self.history.len();
+            self.history[(self.history_pos + self.underrun_pos) % len]
+        };
+        match self.strategy {
+            UnderrunStrategy::Silence => 0.0,
+            UnderrunStrategy::LoopLast { samples: 0 } => 0.0,
+            UnderrunStrategy::LoopLast { .. } => looped(),
+            UnderrunStrategy::FadeToSilence { samples } if self.underrun_pos >= samples => 0.0,
+            UnderrunStrategy::FadeToSilence { samples } => {
+                looped() * (1.0 - self.underrun_pos as f32 / samples as f32)
+            }
+        }
+    }
+
+    /// How many times the player ran out of audio while generation was still running.
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
+    /// Whether generation finished and all its audio was played.
+    pub fn is_finished(&self) -> bool {
+        self.consumer.is_abandoned() && self.consumer.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_generated_audio_and_covers_underruns() -> Result<(), String> {
+        let (mut writer, mut player) = ring_playback(16, UnderrunStrategy::Silence);
+        writer.push(&[1.0, 2.0, 3.0])?;
+        let mut out = [9.0; 5];
+        assert_eq!(player.fill(&mut out), 3);
+        assert_eq!(out, [1.0, 2.0, 3.0, 0.0, 0.0]);
+        assert_eq!(player.underruns(), 1);
+        assert!(!player.is_finished());
+
+        drop(writer);
+        assert_eq!(player.fill(&mut out), 0);
+        assert!(player.is_finished());
+        assert_eq!(player.underruns(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn loops_the_last_samples() -> Result<(), String> {
+        let (mut writer, mut player) = ring_playback(16, UnderrunStrategy::LoopLast { samples: 3 });
+        writer.push(&[1.0, 2.0, 3.0, 4.0])?;
+        let mut out = [0.0; 9];
+        assert_eq!(player.fill(&mut out), 4);
+        assert_eq!(out, [1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 2.0, 3.0]);
+
+        writer.push(&[5.0])?;
+        let mut out = [0.0; 2];
+        assert_eq!(player.fill(&mut out), 1);
+        assert_eq!(out, [5.0, 3.0]);
+        assert_eq!(player.underruns(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn fades_to_silence() -> Result<(), String> {
+        let strategy = UnderrunStrategy::FadeToSilence { samples: 4 };
+        let (mut writer, mut player) = ring_playback(16, strategy);
+        writer.push(&[1.0; 4])?;
+        let mut out = [0.0; 10];
+        player.fill(&mut out);
+        assert_eq!(out, [1.0, 1.0, 1.0, 1.0, 1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn writer_waits_for_the_player() {
+        let (mut writer, mut player) = ring_playback(4, UnderrunStrategy::Silence);
+        let generation = std::thread::spawn(move || writer.push(&[1.0; 20]));
+        let mut played = 0;
+        let mut out = [0.0; 3];
+        while played < 20 {
+            played += player.fill(&mut out);
+        }
+        assert!(generation.join().unwrap().is_ok());
+    }
+}
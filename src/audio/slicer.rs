@@ -0,0 +1,183 @@
+//! Slicing of renders into the one-shot hits and one-bar loops of a sample pack.
+
+use crate::audio::dsp::{fade_in, fade_out, FadeCurve};
+use crate::audio::ending::{estimate_beat, first_beat, nearest_downbeat, onsets, DownbeatEnd};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SliceConfig {
+    /// Onsets are transients when they rise over this fraction of the strongest one.
+    pub threshold: f32,
+    /// Seconds between two transients, at least.
+    pub min_gap: f32,
+    /// Hits are cut at the next transient, or after this many seconds.
+    pub max_hit: f32,
+    pub max_hits: usize,
+    pub beats_per_bar: usize,
+}
+
+impl Default for SliceConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.3,
+            min_gap: 0.08,
+            max_hit: 1.0,
+            max_hits: 32,
+            beats_per_bar: 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SliceKind {
+    Hit,
+    Loop,
+}
+
+/// A stretch of a render, in samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slice {
+    pub kind: SliceKind,
+    pub start: usize,
+    pub len: usize,
+}
+
+/// Milliseconds faded at the edges of slices, so that they do not click.
+const EDGE_MS: f32 = 3.0;
+
+/// The strongest transients of `audio` as hits, in order, each one lasting until the next
+/// one or [SliceConfig::max_hit].
+pub fn hits(audio: &[f32], sample_rate: usize, config: &SliceConfig) -> Vec<Slice> {
+    let (onsets, hop) = onsets(audio, sample_rate);
+    let strongest = onsets.iter().copied().fold(0.0, f32::max);
+    if strongest <= 0.0 {
+        return vec![];
+    }
+    let min_gap = ((config.min_gap * sample_rate as f32) as usize / hop).max(1);
+    // Local maxima over the threshold, the strongest first, and the ones too close to a
+    // stronger one left out.
+    let mut peaks = (0..onsets.len())
+        .filter(|&i| onsets[i] >= strongest * config.threshold)
+        .filter(|&i| i == 0 || onsets[i] >= onsets[i - 1])
+        .filter(|&i| i + 1 == onsets.len() || onsets[i] > onsets[i + 1])
+        .collect::<Vec<_>>();
+    peaks.sort_by(|&a, &b| onsets[b].total_cmp(&onsets[a]));
+    let mut kept: Vec<usize> = vec![];
+    for peak in peaks {
+        if kept.len() < config.max_hits && kept.iter().all(|&k| k.abs_diff(peak) >= min_gap) {
+            kept.push(peak);
+        }
+    }
+    kept.sort();
+    // The onset between two hops is where the second one starts.
+    let starts = kept.iter().map(|i| (i + 1) * hop).collect::<Vec<_>>();
+    let max_hit = (config.max_hit * sample_rate as f32) as usize;
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = starts.get(i + 1).copied().unwrap_or(audio.len());
+            Slice {
+                kind: SliceKind::Hit,
+                start,
+                len: (end - start).min(max_hit),
+            }
+        })
+        .collect()
+}
+
+/// Every whole bar of `audio` from its first downbeat as a loop, along with the detected
+/// tempo in BPM, or nothing if there is no tempo.
+pub fn bar_loops(audio: &[f32], sample_rate: usize, config: &SliceConfig) -> (Vec<Slice>, f32) {
+    let Some(beat) = estimate_beat(audio, sample_rate) else {
+        return (vec![], 0.0);
+    };
+    let downbeat = DownbeatEnd {
+        tolerance: beat * config.beats_per_bar as f32,
+        beats_per_bar: config.beats_per_bar,
+    };
+    let first = nearest_downbeat(audio, 0, sample_rate, &downbeat)
+        .unwrap_or_else(|| first_beat(audio, beat, sample_rate));
+    let bar = beat * config.beats_per_bar as f32 * sample_rate as f32;
+    let loops = (0..)
+        .map(|k| {
+            (
+                first + (k as f32 * bar).round() as usize,
+                bar.round() as usize,
+            )
+        })
+        .take_while(|(start, len)| start + len <= audio.len())
+        .map(|(start, len)| Slice {
+            kind: SliceKind::Loop,
+            start,
+            len,
+        })
+        .collect();
+    (loops, 60.0 / beat)
+}
+
+/// The audio of a slice, with its edges faded, and its hits faded out over their last
+/// quarter so that they ring out instead of stopping.
+pub fn extract(audio: &[f32], slice: &Slice, sample_rate: usize) -> Vec<f32> {
+    let mut samples = audio[slice.start..slice.start + slice.len].to_vec();
+    let edge = ((EDGE_MS / 1000.0 * sample_rate as f32) as usize).min(samples.len() / 2);
+    fade_in(&mut samples[..edge], FadeCurve::Linear);
+    let tail = match slice.kind {
+        SliceKind::Hit => samples.len() / 4,
+        SliceKind::Loop => edge,
+    };
+    let len = samples.len();
+    fade_out(&mut samples[len - tail..], FadeCurve::Cosine);
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    /// Decaying hits every `beat` seconds, the first of each bar of 4 louder.
+    fn groove(secs: f32, beat: f32, sample_rate: usize) -> Vec<f32> {
+        let period = (beat * sample_rate as f32) as usize;
+        (0..(secs * sample_rate as f32) as usize)
+            .map(|i| {
+                let accent = if (i / period).is_multiple_of(4) {
+                    0.9
+                } else {
+                    0.5
+                };
+                let t = (i % period) as f32;
+                (2.0 * PI * 100.0 * t / sample_rate as f32).sin() * accent * (-t / 200.0).exp()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn slices_hits_at_transients_and_loops_at_bars() {
+        // Transients at the very start are not detected, as nothing comes before them.
+        let mut audio = vec![0.0; 800];
+        audio.extend(groove(8.0, 0.5, 8000));
+        let config = SliceConfig::default();
+        let hits = hits(&audio, 8000, &config);
+        assert_eq!(hits.len(), 16);
+        for (i, hit) in hits.iter().enumerate() {
+            assert!(hit.start.abs_diff(800 + i * 4000) <= 80, "{hit:?}");
+            assert!(hit.len.abs_diff(4000) <= 160, "{hit:?}");
+        }
+        let limited = SliceConfig {
+            max_hits: 4,
+            ..config.clone()
+        };
+        assert_eq!(super::hits(&audio, 8000, &limited).len(), 4);
+
+        let (loops, bpm) = bar_loops(&audio, 8000, &config);
+        assert!((bpm - 120.0).abs() < 3.0, "{bpm}");
+        assert!(loops.len() >= 3, "{loops:?}");
+        assert!(loops[0].len.abs_diff(16_000) <= 320);
+
+        let hit = extract(&audio, &hits[1], 8000);
+        assert_eq!(hit[0], 0.0);
+        assert_eq!(hit.last(), Some(&0.0));
+        assert!(super::hits(&vec![0.0; 8000], 8000, &config).is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+//! Spectrogram images of renders, for seeing at a glance what a delivered file contains.
+
+use std::f32::consts::PI;
+
+use crate::audio::denoise::fft;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpectrogramConfig {
+    /// Columns of the image, spread evenly over the audio.
+    pub width: usize,
+    /// Rows of the image, spread over a logarithmic frequency scale.
+    pub height: usize,
+    /// Levels this many dB under the loudest one are black.
+    pub range_db: f32,
+}
+
+impl Default for SpectrogramConfig {
+    fn default() -> Self {
+        Self {
+            width: 1024,
+            height: 256,
+            range_db: 80.0,
+        }
+    }
+}
+
+/// Samples of the frames each column is computed from.
+const FRAME: usize = 2048;
+/// The lowest frequency of the image, in Hz.
+const LOWEST_HZ: f32 = 30.0;
+
+/// The levels of `audio` from 0 to 1, column by column, each one going from the lowest
+/// frequency to the highest.
+pub fn spectrogram(audio: &[f32], sample_rate: usize, config: &SpectrogramConfig) -> Vec<Vec<f32>> {
+    let nyquist = sample_rate as f32 / 2.0;
+    // The FFT bins each row covers, so that high rows merge many bins and low ones share
+    // the same.
+    let bin = |row: f32| {
+        let hz = LOWEST_HZ * (nyquist / LOWEST_HZ).powf(row / config.height as f32);
+        ((hz / nyquist * (FRAME / 2) as f32) as usize).min(FRAME / 2 - 1)
+    };
+    let rows = (0..config.height)
+        .map(|row| (bin(row as f32), bin(row as f32 + 1.0)))
+        .collect::<Vec<_>>();
+    let hop = audio.len() as f32 / config.width as f32;
+    let mut columns = (0..config.width)
+        .map(|column| {
+            let start = (column as f32 * hop) as usize;
+            let mut re = (0..FRAME)
+                .map(|i| {
+                    let hann = 0.5 - 0.5 * (2.0 * PI * i as f32 / FRAME as f32).cos();
+                    audio.get(start + i).copied().unwrap_or(0.0) * hann
+                })
+                .collect::<Vec<_>>();
+            let mut im = vec![0.0; FRAME];
+            fft(&mut re, &mut im, false);
+            rows.iter()
+                .map(|&(from, to)| {
+                    (from..=to.max(from))
+                        .map(|k| re[k].hypot(im[k]))
+                        .fold(0.0, f32::max)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let loudest = columns.iter().flatten().copied().fold(0.0, f32::max);
+    if loudest <= 0.0 {
+        return columns;
+    }
+    for level in columns.iter_mut().flatten() {
+        let db = 20.0 * (*level / loudest).max(1e-9).log10();
+        *level = (1.0 + db / config.range_db).clamp(0.0, 1.0);
+    }
+    columns
+}
+
+/// A 24 bit BMP image of the columns of [spectrogram], with the lowest frequencies at the
+/// bottom and the levels going from black through purple and orange to white.
+pub fn encode_bmp(columns: &[Vec<f32>]) -> Vec<u8> {
+    let width = columns.len();
+    let height = columns.first().map_or(0, |column| column.len());
+    // Rows are padded to 4 bytes.
+    let stride = (width * 3).div_ceil(4) * 4;
+    let size = 54 + stride * height;
+    let mut bmp = b"BM".to_vec();
+    bmp.extend((size as u32).to_le_bytes());
+    bmp.extend(0u32.to_le_bytes());
+    bmp.extend(54u32.to_le_bytes());
+    bmp.extend(40u32.to_le_bytes());
+    bmp.extend((width as i32).to_le_bytes());
+    // A positive height stores the rows from the bottom up.
+    bmp.extend((height as i32).to_le_bytes());
+    bmp.extend(1u16.to_le_bytes());
+    bmp.extend(24u16.to_le_bytes());
+    bmp.extend([0; 24]);
+    for row in 0..height {
+        let start = bmp.len();
+        for column in columns {
+            let [r, g, b] = color(column[row]);
+            bmp.extend([b, g, r]);
+        }
+        bmp.resize(start + stride, 0);
+    }
+    bmp
+}
+
+fn color(level: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [90.0, 20.0, 130.0],
+        [220.0, 60.0, 70.0],
+        [250.0, 170.0, 30.0],
+        [255.0, 255.0, 230.0],
+    ];
+    let position = level.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (position as usize).min(STOPS.len() - 2);
+    let t = position - i as f32;
+    let channel = |c: usize| (STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * t).round() as u8;
+    [channel(0), channel(1), channel(2)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_a_tone_as_a_bright_line() {
+        let sample_rate = 16000;
+        let tone = (0..sample_rate * 2)
+            .map(|i| (2.0 * PI * 1000.0 * i as f32 / sample_rate as f32).sin() * 0.5)
+            .collect::<Vec<_>>();
+        let config = SpectrogramConfig {
+            width: 10,
+            height: 64,
+            ..Default::default()
+        };
+        let columns = spectrogram(&tone, sample_rate, &config);
+        assert_eq!(columns.len(), 10);
+        // 1kHz is about 0.6 of the way up from 30Hz to 8kHz on a logarithmic scale.
+        let brightest = (0..64)
+            .max_by(|&a, &b| columns[3][a].total_cmp(&columns[3][b]))
+            .unwrap();
+        assert!(brightest.abs_diff(40) <= 2, "{brightest}");
+        assert!(columns[3][brightest] > 0.99);
+        assert_eq!(columns[3][0], 0.0);
+
+        let bmp = encode_bmp(&columns);
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(
+            u32::from_le_bytes(bmp[2..6].try_into().unwrap()) as usize,
+            bmp.len()
+        );
+        // 10 pixels of 3 bytes, padded to 32 bytes per row.
+        assert_eq!(bmp.len(), 54 + 32 * 64);
+        assert_eq!(color(0.0), [0, 0, 0]);
+        assert_eq!(color(1.0), [255, 255, 230]);
+    }
+}
@@ -0,0 +1,221 @@
+//! Audio buffer that moves its older samples to a temporary file once it grows too big,
+//! so that renders of hours do not need to fit in memory next to the model. Extended
+//! generation only keeps the crossfade window it is working on, the rest of the audio is
+//! final and can live on disk.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::audio::audio_sink::AudioSink;
+
+/// Samples kept in memory before spilling, 64 MiB of audio.
+pub const DEFAULT_MEMORY_LIMIT: usize = 1 << 24;
+
+const BYTES_PER_SAMPLE: usize = std::mem::size_of::<f32>();
+
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Append-only samples, the first ones on disk and the last ones in memory.
+#[derive(Debug)]
+pub struct SpillBuffer {
+    memory: Vec<f32>,
+    memory_limit: usize,
+    dir: PathBuf,
+    spill: Option<SpillFile>,
+}
+
+/// Samples that were moved to disk. The file is removed when dropped.
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    len: usize,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Default for SpillBuffer {
+    fn default() -> Self {
+        Self::new(std::env::temp_dir(), DEFAULT_MEMORY_LIMIT)
+    }
+}
+
+impl SpillBuffer {
+    /// Spills into a new file in `dir` once there are more than `memory_limit` samples
+    /// in memory.
+    pub fn new(dir: impl Into<PathBuf>, memory_limit: usize) -> Self {
+        Self {
+            memory: vec![],
+            memory_limit,
+            dir: dir.into(),
+            spill: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.spilled() + self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many of the samples are on disk.
+    pub fn spilled(&self) -> usize {
+        self.spill.as_ref().map_or(0, |spill| spill.len)
+    }
+
+    /// Reads the samples in `range`, which must be within the buffer.
+    pub fn read(&self, range: Range<usize>) -> Result<Vec<f32>, String> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(format!(
+                "Range {range:?} is out of the {} samples of the buffer",
+                self.len()
+            ));
+        }
+        let mut samples = Vec::with_capacity(range.len());
+        let spilled = self.spilled();
+        if let Some(spill) = self.spill.as_ref().filter(|_| range.start < spilled) {
+            let end = range.end.min(spilled);
+            let mut bytes = vec![0; (end - range.start) * BYTES_PER_SAMPLE];
+            let mut file = &spill.file;
+            file.seek(SeekFrom::Start((range.start * BYTES_PER_SAMPLE) as u64))
+                .and_then(|_| file.read_exact(&mut bytes))
+                .map_err(|err| format!("Could not read {:?}: {err}", spill.path))?;
+            samples.extend(
+                bytes
+                    .chunks_exact(BYTES_PER_SAMPLE)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            );
+        }
+        let start = range.start.max(spilled) - spilled;
+        samples.extend(&self.memory[start..range.end.max(spilled) - spilled]);
+        Ok(samples)
+    }
+
+    /// Reads all the samples, in pieces of `size` samples.
+    pub fn chunks(&self, size: usize) -> impl Iterator<Item = Result<Vec<f32>, String>> + '_ {
+        (0..self.len())
+            .step_by(size.max(1))
+            .map(move |start| self.read(start..(start + size).min(self.len())))
+    }
+
+    /// Reads all the samples into memory.
+    pub fn to_vec(&self) -> Result<Vec<f32>, String> {
+        self.read(0..self.len())
+    }
+
+    fn spill_file(&mut self) -> Result<&mut SpillFile, String> {
+        if self.spill.is_none() {
+            std::fs::create_dir_all(&self.dir)
+                .map_err(|err| format!("Could not create {:?}: {err}", self.dir))?;
+            let path = spill_path(&self.dir);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map_err(|err| format!("Could not create {path:?}: {err}"))?;
+            self.spill = Some(SpillFile { path, file, len: 0 });
+        }
+        Ok(self.spill.as_mut().unwrap())
+    }
+
+    fn spill(&mut self) -> Result<(), String> {
+        let bytes = self
+            .memory
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        let samples = self.memory.len();
+        let spill = self.spill_file()?;
+        let mut file = &spill.file;
+        file.seek(SeekFrom::End(0))
+            .and_then(|_| file.write_all(&bytes))
+            .map_err(|err| format!("Could not write {:?}: {err}", spill.path))?;
+        spill.len += samples;
+        self.memory.clear();
+        Ok(())
+    }
+}
+
+impl AudioSink for SpillBuffer {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.memory.extend(chunk);
+        if self.memory.len() > self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn spill_path(dir: &Path) -> PathBuf {
+    let n = SPILL_FILES.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!("musicgpt-{}-{n}.f32", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "musicgpt-tests/spill-{}",
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    #[test]
+    fn spills_to_disk_and_reads_back() -> Result<(), String> {
+        let dir = test_dir();
+        let mut buffer = SpillBuffer::new(&dir, 5);
+        let samples = (0..11).map(|i| i as f32).collect::<Vec<_>>();
+        for chunk in samples.chunks(3) {
+            buffer.push(chunk)?;
+        }
+        assert_eq!(buffer.len(), 11);
+        assert_eq!(buffer.spilled(), 6);
+        assert_eq!(buffer.to_vec()?, samples);
+        assert_eq!(buffer.read(4..8)?, vec![4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(buffer.read(7..9)?, vec![7.0, 8.0]);
+        assert_eq!(buffer.read(2..2)?, Vec::<f32>::new());
+        assert!(buffer.read(10..12).is_err());
+        assert_eq!(
+            buffer.chunks(4).collect::<Result<Vec<_>, _>>()?.concat(),
+            samples
+        );
+
+        let files = std::fs::read_dir(&dir)
+            .map_err(|err| err.to_string())?
+            .count();
+        assert_eq!(files, 1);
+        drop(buffer);
+        let files = std::fs::read_dir(&dir)
+            .map_err(|err| err.to_string())?
+            .count();
+        assert_eq!(files, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn stays_in_memory_under_the_limit() -> Result<(), String> {
+        let dir = test_dir();
+        let mut buffer = SpillBuffer::new(&dir, 4);
+        buffer.push(&[1.0, 2.0, 3.0, 4.0])?;
+        assert_eq!(buffer.spilled(), 0);
+        assert_eq!(buffer.to_vec()?, vec![1.0, 2.0, 3.0, 4.0]);
+        assert!(!dir.exists());
+        Ok(())
+    }
+}
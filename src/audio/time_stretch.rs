@@ -0,0 +1,86 @@
+//! Time stretching, which changes the tempo of audio without changing its pitch, with
+//! waveform similarity overlap-add (WSOLA). Frames are taken from the input at the pace of
+//! the new tempo, each one shifted a little to where it continues the one before best.
+
+use std::f32::consts::PI;
+
+/// Length of the frames that are overlapped, in seconds.
+const FRAME_SECS: f32 = 0.04;
+/// How far a frame may be shifted from where the new tempo puts it, in seconds.
+const TOLERANCE_SECS: f32 = 0.005;
+/// Only every this many samples are compared when looking for the best shift.
+const STRIDE: usize = 4;
+
+/// Stretches `audio` by `ratio` without changing its pitch, a ratio of 2 making it twice
+/// as long, at half the tempo.
+pub fn time_stretch(audio: &[f32], ratio: f32, sample_rate: usize) -> Vec<f32> {
+    if (ratio - 1.0).abs() < 1e-3 || audio.is_empty() || ratio <= 0.0 {
+        return audio.to_vec();
+    }
+    let frame = ((FRAME_SECS * sample_rate as f32) as usize).max(4);
+    let hop = frame / 2;
+    let tolerance = (TOLERANCE_SECS * sample_rate as f32) as usize;
+    let window: Vec<f32> = (0..frame)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / frame as f32).cos())
+        .collect();
+    let sample = |i: usize| audio.get(i).copied().unwrap_or(0.0);
+
+    let len = (audio.len() as f32 * ratio) as usize;
+    let mut out = vec![0.0; len + frame];
+    let mut weights = vec![0.0; len + frame];
+    let mut previous: Option<usize> = None;
+    for (k, at) in (0..len).step_by(hop).enumerate() {
+        let nominal = (k as f32 * hop as f32 / ratio) as usize;
+        // The frame that best matches how the previous one would have continued.
+        let start = match previous {
+            None => nominal,
+            Some(previous) => {
+                let natural = previous + hop;
+                let similarity = |start: usize| {
+                    (0..hop)
+                        .step_by(STRIDE)
+                        .map(|i| sample(start + i) * sample(natural + i))
+                        .sum::<f32>()
+                };
+                (nominal.saturating_sub(tolerance)..=nominal + tolerance)
+                    .map(|start| (start, similarity(start)))
+                    .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+                    .map_or(nominal, |(start, _)| start)
+            }
+        };
+        for (i, w) in window.iter().enumerate() {
+            out[at + i] += sample(start + i) * w;
+            weights[at + i] += w;
+        }
+        previous = Some(start);
+    }
+    out.truncate(len);
+    for (sample, weight) in out.iter_mut().zip(weights) {
+        if weight > 1e-3 {
+            *sample /= weight;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_the_tempo_but_not_the_pitch() {
+        let audio: Vec<f32> = (0..16000)
+            .map(|i| (2.0 * PI * 440.0 * (i % 8000) as f32 / 8000.0).sin())
+            .collect();
+        let crossings = |audio: &[f32]| {
+            let crossings = audio.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0);
+            crossings.count() as f32 / audio.len() as f32 * 8000.0
+        };
+        for ratio in [0.8, 1.25] {
+            let stretched = time_stretch(&audio, ratio, 8000);
+            assert_eq!(stretched.len(), (16000.0 * ratio) as usize);
+            let hz = crossings(&stretched[400..stretched.len() - 400]);
+            assert!((hz - 440.0).abs() < 5.0, "{ratio}: {hz}");
+        }
+    }
+}
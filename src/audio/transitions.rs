@@ -0,0 +1,106 @@
+//! Synthesized transition elements, like risers or cymbal swells, that are mixed over the
+//! joins between segments so that structural changes sound intentional.
+
+use std::f32::consts::PI;
+
+/// The kind of sound building up to a join.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransitionStyle {
+    /// A sine sweep rising in pitch and volume.
+    Riser,
+    /// White noise that gets louder and brighter.
+    NoiseCrescendo,
+    /// Bright noise swelling like a reversed cymbal.
+    CymbalSwell,
+}
+
+/// A transition element mixed over the join that follows the given segment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    /// The join, 0 being the one between the first and the second segments.
+    pub boundary: usize,
+    pub style: TransitionStyle,
+    /// Duration of the build-up in seconds, peaking in the middle of the crossfade.
+    pub duration: f32,
+    /// Peak level, from 0 to 1.
+    pub gain: f32,
+}
+
+impl Transition {
+    /// Renders the element. The same transition always produces the same audio.
+    pub fn synthesize(&self, sample_rate: usize) -> Vec<f32> {
+        let n = (self.duration * sample_rate as f32) as usize;
+        let sr = sample_rate as f32;
+        let mut noise = Noise(0x9E37_79B9 ^ self.boundary as u32);
+        let mut lowpass = 0.0;
+        let mut phase = 0.0;
+        (0..n)
+            .map(|i| {
+                let t = i as f32 / n as f32;
+                let sample = match self.style {
+                    TransitionStyle::Riser => {
+                        // Exponential sweep from 200Hz up to 2kHz.
+                        let hz = 200.0 * 10f32.powf(t);
+                        phase += 2.0 * PI * hz / sr;
+                        phase.sin() * t * t
+                    }
+                    TransitionStyle::NoiseCrescendo => {
+                        // The filter opens up from 200Hz to 8kHz, capped by Nyquist.
+                        let hz = (200.0 * 40f32.powf(t)).min(sr / 2.0);
+                        lowpass += one_pole(hz, sr) * (noise.next() - lowpass);
+                        lowpass * t * t
+                    }
+                    TransitionStyle::CymbalSwell => {
+                        let white = noise.next();
+                        lowpass += one_pole(sr / 8.0, sr) * (white - lowpass);
+                        (white - lowpass) * (-5.0 * (1.0 - t)).exp()
+                    }
+                };
+                sample * self.gain
+            })
+            .collect()
+    }
+}
+
+fn one_pole(hz: f32, sample_rate: f32) -> f32 {
+    1.0 - (-2.0 * PI * hz / sample_rate).exp()
+}
+
+/// Xorshift white noise, between -1 and 1.
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_up_to_the_join() {
+        for style in [
+            TransitionStyle::Riser,
+            TransitionStyle::NoiseCrescendo,
+            TransitionStyle::CymbalSwell,
+        ] {
+            let transition = Transition {
+                boundary: 0,
+                style,
+                duration: 2.0,
+                gain: 0.5,
+            };
+            let audio = transition.synthesize(16000);
+            assert_eq!(audio.len(), 32000);
+            assert_eq!(audio, transition.synthesize(16000));
+            let peak = |range: &[f32]| range.iter().fold(0f32, |max, s| max.max(s.abs()));
+            assert!(peak(&audio[..8000]) < peak(&audio[24000..]), "{style:?}");
+            assert!(peak(&audio) <= 0.5, "{style:?}");
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Upmixing of mono or stereo renders into 5.1 surround, for video projects that need
+//! surround deliverables.
+
+/// How a render is spread over the 5.1 channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpmixConfig {
+    /// Frequencies under this one go to the LFE channel instead of the others.
+    pub lfe_hz: f32,
+    /// Level of the center channel, which carries what both sides have in common.
+    pub center_gain: f32,
+    /// Level of the decorrelated copies of the center in the surround channels.
+    pub surround_gain: f32,
+    /// Delay of the left surround channel behind the front. The right one is delayed by
+    /// half as much again, so that they do not sound like a single source.
+    pub surround_delay_ms: f32,
+}
+
+impl Default for UpmixConfig {
+    fn default() -> Self {
+        Self {
+            lfe_hz: 120.0,
+            center_gain: 0.7,
+            surround_gain: 0.5,
+            surround_delay_ms: 12.0,
+        }
+    }
+}
+
+/// The 5.1 channels, in the order of WAV files: front left, front right, center, LFE,
+/// surround left and surround right.
+pub type Surround = [Vec<f32>; 6];
+
+/// Upmixes a stereo render, or a mono one passed as both sides, into 5.1. The front
+/// keeps the sides, the center gets what they have in common and the surrounds get
+/// delayed and all-pass filtered copies of it, along with their difference. Everything
+/// under [UpmixConfig::lfe_hz] goes to the LFE channel.
+pub fn upmix(left: &[f32], right: &[f32], sample_rate: usize, config: &UpmixConfig) -> Surround {
+    let len = left.len().min(right.len());
+    let mut crossovers = [Crossover::new(config.lfe_hz, sample_rate); 3];
+    let delay = (config.surround_delay_ms / 1000.0 * sample_rate as f32) as usize;
+    let mut surrounds = [
+        Decorrelator::new(delay, 0.6),
+        Decorrelator::new(delay * 3 / 2, -0.6),
+    ];
+    let mut channels: Surround = Default::default();
+    for channel in &mut channels {
+        channel.reserve(len);
+    }
+    for i in 0..len {
+        let mid = (left[i] + right[i]) / 2.0;
+        let side = (left[i] - right[i]) / 2.0;
+        let (low, high_mid) = crossovers[0].split(mid);
+        let (_, high_left) = crossovers[1].split(left[i]);
+        let (_, high_right) = crossovers[2].split(right[i]);
+        let front = std::f32::consts::FRAC_1_SQRT_2;
+        let ambience = [
+            surrounds[0].process(high_mid) * config.surround_gain + side,
+            surrounds[1].process(high_mid) * config.surround_gain - side,
+        ];
+        let samples = [
+            high_left * front,
+            high_right * front,
+            high_mid * config.center_gain,
+            low,
+            ambience[0],
+            ambience[1],
+        ];
+        for (channel, sample) in channels.iter_mut().zip(samples) {
+            channel.push(sample);
+        }
+    }
+    channels
+}
+
+/// Splits audio in the lows and the highs with two one-pole filters in a row for each.
+#[derive(Clone, Copy)]
+struct Crossover {
+    coef: f32,
+    low: [f32; 2],
+    high: [f32; 2],
+}
+
+impl Crossover {
+    fn new(hz: f32, sample_rate: usize) -> Self {
+        let coef = 1.0 - (-2.0 * std::f32::consts::PI * hz / sample_rate as f32).exp();
+        Self {
+            coef,
+            low: [0.0; 2],
+            high: [0.0; 2],
+        }
+    }
+
+    fn split(&mut self, sample: f32) -> (f32, f32) {
+        self.low[0] += self.coef * (sample - self.low[0]);
+        self.low[1] += self.coef * (self.low[0] - self.low[1]);
+        // High-passing is taking out what a low-pass filter lets through.
+        self.high[0] += self.coef * (sample - self.high[0]);
+        let high = sample - self.high[0];
+        self.high[1] += self.coef * (high - self.high[1]);
+        (self.low[1], high - self.high[1])
+    }
+}
+
+/// A delay followed by a first order all-pass filter, which shifts the phase of each
+/// frequency differently.
+struct Decorrelator {
+    delay: Vec<f32>,
+    position: usize,
+    coef: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+impl Decorrelator {
+    fn new(delay: usize, coef: f32) -> Self {
+        Self {
+            delay: vec![0.0; delay.max(1)],
+            position: 0,
+            coef,
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let delayed = std::mem::replace(&mut self.delay[self.position], sample);
+        self.position = (self.position + 1) % self.delay.len();
+        let out = self.coef * delayed + self.last_in - self.coef * self.last_out;
+        self.last_in = delayed;
+        self.last_out = out;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::audio::dsp::rms;
+
+    fn sine(hz: f32, secs: f32, sample_rate: usize) -> Vec<f32> {
+        (0..(secs * sample_rate as f32) as usize)
+            .map(|i| (2.0 * PI * hz * i as f32 / sample_rate as f32).sin() * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn splits_the_lows_into_the_lfe_and_decorrelates_the_surrounds() {
+        let config = UpmixConfig::default();
+        let bass = sine(40.0, 1.0, 16000);
+        let [left, right, center, lfe, ..] = upmix(&bass, &bass, 16000, &config);
+        assert_eq!(lfe.len(), 16000);
+        assert!(rms(&lfe) > rms(&center) * 4.0);
+        assert!(rms(&lfe) > rms(&left) * 4.0);
+        assert_eq!(left, right);
+
+        let lead = sine(2000.0, 1.0, 16000);
+        let [left, _, center, lfe, surround_left, surround_right] =
+            upmix(&lead, &lead, 16000, &config);
+        assert!(rms(&lfe) < rms(&center) / 10.0);
+        assert!(rms(&left) > 0.2);
+        assert!(rms(&surround_left) > 0.1);
+        assert_ne!(surround_left, surround_right);
+    }
+}
@@ -0,0 +1,286 @@
+//! Inaudible watermark labelling renders as generated by AI, for users who must mark
+//! generated content. The payload is spread over pseudo-random noise well under the level
+//! of the music, one bit every [FRAME] samples, and repeated for the whole piece. It is
+//! found again in copies that keep the samples aligned, even if their volume changed, but
+//! not in cropped or resampled ones.
+
+use crate::audio::audio_sink::AudioSink;
+use crate::audio::extended_generation::SegmentRetry;
+use crate::audio::gain_staging::SegmentGain;
+use crate::audio::markers::SegmentMarker;
+
+/// Samples each bit of the payload is spread over.
+const FRAME: usize = 1024;
+/// Level of the watermark relative to the music around it, -30 dB.
+const STRENGTH: f32 = 0.032;
+/// Samples over which the level of the music is followed.
+const ENVELOPE_SAMPLES: f32 = 1600.0;
+/// Seed of the noise, which must never change so that older renders are still detected.
+const KEY: u64 = 0x6d75_7369_6367_7074;
+
+const FLAG_AI_GENERATED: u8 = 1;
+const FLAG_JOB_ID: u8 = 2;
+/// Flags, job id and checksum.
+const PAYLOAD_BYTES: usize = 1 + 16 + 2;
+const PAYLOAD_BITS: usize = PAYLOAD_BYTES * 8;
+
+/// What a watermark says about the audio it is embedded in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatermarkPayload {
+    pub ai_generated: bool,
+    /// The job that generated the audio, if it was generated by one.
+    pub job_id: Option<u128>,
+}
+
+impl WatermarkPayload {
+    fn to_bits(self) -> Vec<bool> {
+        let mut bytes = Vec::with_capacity(PAYLOAD_BYTES);
+        let mut flags = 0;
+        if self.ai_generated {
+            flags |= FLAG_AI_GENERATED;
+        }
+        if self.job_id.is_some() {
+            flags |= FLAG_JOB_ID;
+        }
+        bytes.push(flags);
+        bytes.extend(self.job_id.unwrap_or_default().to_be_bytes());
+        bytes.extend(crc16(&bytes).to_be_bytes());
+        bytes
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+            .collect()
+    }
+
+    fn from_bits(bits: &[bool]) -> Option<Self> {
+        let bytes = bits
+            .chunks(8)
+            .map(|bits| {
+                bits.iter()
+                    .fold(0u8, |byte, &bit| byte << 1 | u8::from(bit))
+            })
+            .collect::<Vec<_>>();
+        let (data, checksum) = bytes.split_at(PAYLOAD_BYTES - 2);
+        if crc16(data).to_be_bytes() != checksum {
+            return None;
+        }
+        let flags = data[0];
+        let job_id = u128::from_be_bytes(data[1..].try_into().ok()?);
+        if flags & !(FLAG_AI_GENERATED | FLAG_JOB_ID) != 0
+            || (flags & FLAG_JOB_ID == 0 && job_id != 0)
+        {
+            return None;
+        }
+        Some(Self {
+            ai_generated: flags & FLAG_AI_GENERATED != 0,
+            job_id: Some(job_id).filter(|_| flags & FLAG_JOB_ID != 0),
+        })
+    }
+}
+
+/// CRC-16/CCITT-FALSE.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The noise at sample `n` of a piece, +1 or -1.
+fn chip(n: usize) -> f32 {
+    // splitmix64, so that any sample can be computed without the ones before.
+    let mut z = (n as u64 ^ KEY).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    if z >> 63 == 1 {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
+/// Embeds a payload into a piece given in order, one chunk at a time.
+#[derive(Clone, Debug)]
+pub struct Watermarker {
+    bits: Vec<bool>,
+    position: usize,
+    /// Mean square of the music so far.
+    power: f32,
+}
+
+impl Watermarker {
+    pub fn new(payload: WatermarkPayload) -> Self {
+        Self {
+            bits: payload.to_bits(),
+            position: 0,
+            power: 0.0,
+        }
+    }
+
+    /// Embeds the payload into the next samples of the piece.
+    pub fn apply(&mut self, chunk: &mut [f32]) {
+        for sample in chunk.iter_mut() {
+            self.power += (*sample * *sample - self.power) / ENVELOPE_SAMPLES;
+            let n = self.position;
+            let sign = match self.bits[n / FRAME % self.bits.len()] {
+                true => 1.0,
+                false => -1.0,
+            };
+            *sample += STRENGTH * self.power.sqrt() * sign * chip(n);
+            self.position += 1;
+        }
+    }
+}
+
+/// Looks for a watermark in a whole piece. Pieces shorter than one repetition of the
+/// payload, about 5 seconds at 32kHz, are not long enough to carry it.
+pub fn detect(audio: &[f32]) -> Option<WatermarkPayload> {
+    if audio.len() < PAYLOAD_BITS * FRAME {
+        return None;
+    }
+    // Correlating the differences between consecutive samples leaves out most of the
+    // music, which is concentrated in the low frequencies, but not the noise.
+    let mut scores = vec![0.0f64; PAYLOAD_BITS];
+    let mut last_chip = chip(0);
+    for (n, pair) in audio.windows(2).enumerate() {
+        let n = n + 1;
+        let chip = chip(n);
+        scores[n / FRAME % PAYLOAD_BITS] += ((pair[1] - pair[0]) * (chip - last_chip)) as f64;
+        last_chip = chip;
+    }
+    let bits = scores.iter().map(|score| *score > 0.0).collect::<Vec<_>>();
+    WatermarkPayload::from_bits(&bits)
+}
+
+/// Watermarks the audio before pushing it into the wrapped sink.
+pub struct WatermarkSink<S> {
+    inner: S,
+    watermarker: Watermarker,
+}
+
+impl<S: AudioSink> WatermarkSink<S> {
+    pub fn new(inner: S, payload: WatermarkPayload) -> Self {
+        Self {
+            inner,
+            watermarker: Watermarker::new(payload),
+        }
+    }
+}
+
+impl<S: AudioSink> AudioSink for WatermarkSink<S> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let mut chunk = chunk.to_vec();
+        self.watermarker.apply(&mut chunk);
+        self.inner.push(&chunk)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.inner.finalize()
+    }
+
+    fn retried(&mut self, retry: &SegmentRetry) {
+        self.inner.retried(retry)
+    }
+
+    fn normalized(&mut self, gain: &SegmentGain) {
+        self.inner.normalized(gain)
+    }
+
+    fn generated(&mut self, segment: usize, audio: &[f32]) {
+        self.inner.generated(segment, audio)
+    }
+
+    fn marked(&mut self, marker: &SegmentMarker) {
+        self.inner.marked(marker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A few seconds of chords with some noise, standing in for music.
+    fn music(secs: usize) -> Vec<f32> {
+        let rate = 32000.0;
+        (0..secs * 32000)
+            .map(|n| {
+                let t = n as f32 / rate;
+                let swell = 0.6 + 0.4 * (t * 0.7).sin();
+                let tones = [110.0, 220.0, 277.2, 329.6, 880.0]
+                    .iter()
+                    .map(|freq| (t * freq * std::f32::consts::TAU).sin())
+                    .sum::<f32>();
+                swell * 0.08 * tones + 0.01 * chip(n * 7 + 3)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_payloads() {
+        let payload = WatermarkPayload {
+            ai_generated: true,
+            job_id: Some(0x0123_4567_89ab_cdef_0011_2233_4455_6677),
+        };
+        assert_eq!(
+            WatermarkPayload::from_bits(&payload.to_bits()),
+            Some(payload)
+        );
+        let mut bits = payload.to_bits();
+        bits[20] = !bits[20];
+        assert_eq!(WatermarkPayload::from_bits(&bits), None);
+    }
+
+    #[test]
+    fn detects_watermarks_in_chunked_and_rescaled_audio() {
+        let payload = WatermarkPayload {
+            ai_generated: true,
+            job_id: Some(42),
+        };
+        let original = music(10);
+        let mut audio = original.clone();
+        let mut watermarker = Watermarker::new(payload);
+        for chunk in audio.chunks_mut(3000) {
+            watermarker.apply(chunk);
+        }
+        assert_eq!(detect(&audio), Some(payload));
+        let quieter = audio.iter().map(|s| s * 0.5).collect::<Vec<_>>();
+        assert_eq!(detect(&quieter), Some(payload));
+
+        assert_eq!(detect(&original), None);
+        // Well under the music, like -30 dB.
+        let noise = audio
+            .iter()
+            .zip(&original)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f32>();
+        let signal = original.iter().map(|s| s.powi(2)).sum::<f32>();
+        assert!(10.0 * (noise / signal).log10() < -28.0);
+    }
+
+    #[test]
+    fn watermarks_through_a_sink() -> Result<(), String> {
+        let payload = WatermarkPayload {
+            ai_generated: true,
+            job_id: None,
+        };
+        let mut sink = WatermarkSink::new(crate::audio::audio_sink::MemorySink::new(), payload);
+        for chunk in music(6).chunks(1000) {
+            sink.push(chunk)?;
+        }
+        sink.finalize()?;
+        assert_eq!(detect(&sink.inner.into_inner()), Some(payload));
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+//! WAV encoding and decoding of mono audio, and of several channels for surround.
+
+use std::fs::OpenOptions;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Encodes mono audio as a 32 bit float WAV file.
+pub fn encode_wav(
+    samples: impl IntoIterator<Item = f32>,
+    sample_rate: u32,
+) -> hound::Result<Vec<u8>> {
+    let _span = tracing::debug_span!("export").entered();
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut buffer = vec![];
+    {
+        let mut writer = hound::WavWriter::new(Cursor::new(&mut buffer), spec)?;
+        for sample in samples {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+    }
+    Ok(buffer)
+}
+
+/// Encodes audio with several channels, like the ones of a surround upmix, as a 32 bit
+/// float WAV file. The channels are cut to the shortest one.
+pub fn encode_wav_channels(channels: &[Vec<f32>], sample_rate: u32) -> hound::Result<Vec<u8>> {
+    let spec = hound::WavSpec {
+        channels: channels.len() as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let len = channels.iter().map(Vec::len).min().unwrap_or(0);
+    let mut buffer = vec![];
+    {
+        let mut writer = hound::WavWriter::new(Cursor::new(&mut buffer), spec)?;
+        for i in 0..len {
+            for channel in channels {
+                writer.write_sample(channel[i])?;
+            }
+        }
+        writer.finalize()?;
+    }
+    Ok(buffer)
+}
+
+/// The header [encode_wav] writes before `samples` samples, for streaming the samples
+/// after it.
+pub fn wav_header(samples: usize, sample_rate: u32) -> hound::Result<Vec<u8>> {
+    wav_header_with_chunks(samples, sample_rate, 0)
+}
+
+/// Same as [wav_header], for files with `chunks_len` bytes of other chunks, like the ones
+/// of [info_chunk], after the samples.
+pub fn wav_header_with_chunks(
+    samples: usize,
+    sample_rate: u32,
+    chunks_len: usize,
+) -> hound::Result<Vec<u8>> {
+    let mut header = encode_wav([], sample_rate)?;
+    let data_len = (samples * std::mem::size_of::<f32>()) as u32;
+    let riff_len = header.len() as u32 - 8 + data_len + chunks_len as u32;
+    header[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    let len = header.len();
+    header[len - 4..].copy_from_slice(&data_len.to_le_bytes());
+    Ok(header)
+}
+
+/// A LIST chunk with the given INFO tags, like `ICOP` for the copyright, for placing
+/// after the samples of a WAV file.
+pub fn info_chunk(tags: &[([u8; 4], &str)]) -> Vec<u8> {
+    let mut info = b"INFO".to_vec();
+    for (id, value) in tags {
+        // Values are null terminated, and chunks padded to an even length.
+        let len = value.len() + 1;
+        info.extend(id);
+        info.extend((len as u32).to_le_bytes());
+        info.extend(value.as_bytes());
+        info.push(0);
+        if len % 2 == 1 {
+            info.push(0);
+        }
+    }
+    let mut chunk = b"LIST".to_vec();
+    chunk.extend((info.len() as u32).to_le_bytes());
+    chunk.extend(info);
+    chunk
+}
+
+/// The INFO tags in the LIST chunks of a WAV file, like the ones of [info_chunk].
+pub fn info_tags(wav: &[u8]) -> Vec<([u8; 4], String)> {
+    let mut tags = vec![];
+    for (id, data) in chunks(wav.get(12..).unwrap_or_default()) {
+        if id == b"LIST" && data.starts_with(b"INFO") {
+            for (id, value) in chunks(&data[4..]) {
+                let value = value.split(|&b| b == 0).next().unwrap_or_default();
+                tags.push((*id, String::from_utf8_lossy(value).to_string()));
+            }
+        }
+    }
+    tags
+}
+
+/// The ids and data of consecutive RIFF chunks, padded to an even length.
+fn chunks(mut bytes: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let id = bytes.get(..4)?.try_into().ok()?;
+        let len = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?) as usize;
+        let data = bytes.get(8..8 + len)?;
+        bytes = bytes.get(8 + len + len % 2..).unwrap_or_default();
+        Some((id, data))
+    })
+}
+
+/// The broadcast extension of a WAV file, which asset management systems of broadcasters
+/// read for ingesting it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bext {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+    /// Like 2025-10-09.
+    pub origination_date: String,
+    /// Like 08:53:20.
+    pub origination_time: String,
+    /// Samples since midnight of the first sample, for placing it on a timeline.
+    pub time_reference: u64,
+    /// Lines like `A=PCM,W=32,M=mono,T=MusicGPT` telling how the audio was produced.
+    pub coding_history: Vec<String>,
+}
+
+/// A version 1 `bext` chunk, for placing after the samples of a WAV file. Fields longer
+/// than their space in the chunk are cut.
+pub fn bext_chunk(bext: &Bext) -> Vec<u8> {
+    let fixed = |value: &str, len: usize| {
+        let mut bytes = value.as_bytes()[..value.len().min(len)].to_vec();
+        bytes.resize(len, 0);
+        bytes
+    };
+    let mut data = fixed(&bext.description, 256);
+    data.extend(fixed(&bext.originator, 32));
+    data.extend(fixed(&bext.originator_reference, 32));
+    data.extend(fixed(&bext.origination_date, 10));
+    data.extend(fixed(&bext.origination_time, 8));
+    data.extend(bext.time_reference.to_le_bytes());
+    data.extend(1u16.to_le_bytes());
+    // The UMID and the reserved bytes.
+    data.extend([0; 64 + 190]);
+    for line in &bext.coding_history {
+        data.extend(line.as_bytes());
+        data.extend(b"\r\n");
+    }
+    let mut chunk = b"bext".to_vec();
+    chunk.extend((data.len() as u32).to_le_bytes());
+    chunk.extend(&data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+/// An `id3 ` chunk with an ID3 tag like the ones of [crate::audio::id3::id3_tag], for
+/// placing after the samples of a WAV file.
+pub fn id3_chunk(tag: &[u8]) -> Vec<u8> {
+    let mut chunk = b"id3 ".to_vec();
+    chunk.extend((tag.len() as u32).to_le_bytes());
+    chunk.extend(tag);
+    if tag.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+/// The ID3 tag of a WAV file, like the one of [id3_chunk].
+pub fn id3_tag(wav: &[u8]) -> Option<&[u8]> {
+    chunks(wav.get(12..).unwrap_or_default())
+        .find(|(id, _)| id.eq_ignore_ascii_case(b"id3 "))
+        .map(|(_, data)| data)
+}
+
+/// Appends a chunk to an encoded WAV file, after its samples.
+pub fn append_chunk(wav: &mut Vec<u8>, chunk: &[u8]) {
+    wav.extend(chunk);
+    let riff_len = wav.len() as u32 - 8;
+    wav[4..8].copy_from_slice(&riff_len.to_le_bytes());
+}
+
+/// Same as [append_chunk], for a WAV file that was already written to `path`.
+pub fn append_chunk_to_file(path: &Path, chunk: &[u8]) -> Result<(), String> {
+    let err = |err: std::io::Error| format!("Could not write {path:?}: {err}");
+    let mut file = OpenOptions::new().write(true).open(path).map_err(err)?;
+    let len = file.seek(SeekFrom::End(0)).map_err(err)?;
+    file.write_all(chunk).map_err(err)?;
+    let riff_len = (len + chunk.len() as u64 - 8) as u32;
+    file.seek(SeekFrom::Start(4)).map_err(err)?;
+    file.write_all(&riff_len.to_le_bytes()).map_err(err)
+}
+
+/// Decodes a 32 bit float WAV file, like the ones produced by [encode_wav].
+pub fn decode_wav(bytes: &[u8]) -> Result<Vec<f32>, String> {
+    decode_wav_with_sample_rate(bytes).map(|(samples, _)| samples)
+}
+
+/// Like [decode_wav], also returning the sample rate of the file.
+pub fn decode_wav_with_sample_rate(bytes: &[u8]) -> Result<(Vec<f32>, u32), String> {
+    let reader = hound::WavReader::new(Cursor::new(bytes)).map_err(|err| err.to_string())?;
+    let sample_rate = reader.spec().sample_rate;
+    let samples = reader
+        .into_samples::<f32>()
+        .collect::<Result<_, _>>()
+        .map_err(|err| err.to_string())?;
+    Ok((samples, sample_rate))
+}
+
+/// Decodes a 32 bit float WAV file with any number of channels, returning each channel
+/// and the sample rate.
+pub fn decode_wav_channels(bytes: &[u8]) -> Result<(Vec<Vec<f32>>, u32), String> {
+    let reader = hound::WavReader::new(Cursor::new(bytes)).map_err(|err| err.to_string())?;
+    let spec = reader.spec();
+    let mut channels = vec![vec![]; spec.channels as usize];
+    for (i, sample) in reader.into_samples::<f32>().enumerate() {
+        let sample = sample.map_err(|err| err.to_string())?;
+        channels[i % spec.channels as usize].push(sample);
+    }
+    Ok((channels, spec.sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips() -> Result<(), String> {
+        let samples = vec![0.0, 0.5, -0.25, 1.0];
+        let bytes = encode_wav(samples.clone(), 32000).map_err(|err| err.to_string())?;
+        assert_eq!(decode_wav(&bytes)?, samples);
+        assert_eq!(decode_wav_with_sample_rate(&bytes)?.1, 32000);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_bext_chunks() {
+        let chunk = bext_chunk(&Bext {
+            description: "Lofi".to_string(),
+            origination_date: "2025-10-09".to_string(),
+            origination_time: "08:53:20".to_string(),
+            time_reference: 32000,
+            coding_history: vec!["A=PCM,T=MusicGPT".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(&chunk[..8], b"bext\x6c\x02\0\0");
+        assert_eq!(&chunk[8..12], b"Lofi");
+        assert_eq!(&chunk[328..346], b"2025-10-0908:53:20");
+        assert_eq!(chunk[346..356], [0, 0x7d, 0, 0, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(&chunk[610..], b"A=PCM,T=MusicGPT\r\n");
+
+        let mut wav = encode_wav([0.5], 32000).unwrap();
+        append_chunk(&mut wav, &chunk);
+        assert_eq!(decode_wav(&wav).unwrap(), [0.5]);
+    }
+
+    #[test]
+    fn roundtrips_several_channels() -> Result<(), String> {
+        let channels = vec![vec![0.0, 0.5, 0.25], vec![-0.5, 1.0]];
+        let bytes = encode_wav_channels(&channels, 48000).map_err(|err| err.to_string())?;
+        let (decoded, sample_rate) = decode_wav_channels(&bytes)?;
+        assert_eq!(decoded, [vec![0.0, 0.5], vec![-0.5, 1.0]]);
+        assert_eq!(sample_rate, 48000);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_info_and_id3_tags() -> Result<(), String> {
+        let mut bytes = encode_wav([0.0, 0.5, 0.25], 32000).map_err(|err| err.to_string())?;
+        append_chunk(
+            &mut bytes,
+            &info_chunk(&[(*b"ISFT", "MusicGPT"), (*b"ICOP", "CC0")]),
+        );
+        let tags = info_tags(&bytes);
+        assert_eq!(
+            tags,
+            [
+                (*b"ISFT", "MusicGPT".to_string()),
+                (*b"ICOP", "CC0".to_string())
+            ]
+        );
+        assert_eq!(id3_tag(&bytes), None);
+        append_chunk(&mut bytes, &id3_chunk(b"ID3"));
+        assert_eq!(id3_tag(&bytes), Some(&b"ID3"[..]));
+        assert_eq!(decode_wav(&bytes)?, [0.0, 0.5, 0.25]);
+        Ok(())
+    }
+
+    #[test]
+    fn header_matches_the_encoded_file() -> Result<(), String> {
+        let samples: Vec<f32> = vec![0.0, 0.5, -0.25];
+        let mut bytes = wav_header(samples.len(), 16000).map_err(|err| err.to_string())?;
+        bytes.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+        let encoded = encode_wav(samples, 16000).map_err(|err| err.to_string())?;
+        assert_eq!(bytes, encoded);
+        Ok(())
+    }
+
+    #[test]
+    fn files_with_info_chunks_still_decode() -> Result<(), String> {
+        let samples: Vec<f32> = vec![0.0, 0.5, -0.25];
+        let chunk = info_chunk(&[(*b"ICOP", "CC-BY-4.0"), (*b"ISFT", "MusicGPT")]);
+        assert_eq!(chunk.len() % 2, 0);
+        assert_eq!(&chunk[12..16], b"ICOP");
+
+        let mut streamed = wav_header_with_chunks(samples.len(), 16000, chunk.len())
+            .map_err(|err| err.to_string())?;
+        streamed.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+        streamed.extend(&chunk);
+        let mut encoded = encode_wav(samples.clone(), 16000).map_err(|err| err.to_string())?;
+        append_chunk(&mut encoded, &chunk);
+        assert_eq!(streamed, encoded);
+        assert_eq!(decode_wav(&encoded)?, samples);
+
+        let path = std::env::temp_dir().join(format!("musicgpt-info-{}.wav", std::process::id()));
+        let written = encode_wav(samples, 16000).map_err(|err| err.to_string())?;
+        std::fs::write(&path, written).map_err(|err| err.to_string())?;
+        append_chunk_to_file(&path, &chunk)?;
+        let appended = std::fs::read(&path).map_err(|err| err.to_string())?;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(appended, encoded);
+        Ok(())
+    }
+}
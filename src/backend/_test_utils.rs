@@ -1,4 +1,5 @@
-use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use async_trait::async_trait;
@@ -6,14 +7,18 @@ use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
 use crate::backend::audio_generation_backend::{
-    AudioGenerationRequest, BackendOutboundMsg, JobProcessor,
+    AudioGenerationRequest, BackendInboundMsg, BackendOutboundMsg, JobProcessor,
 };
 use crate::backend::audio_generation_fanout::{
     AudioGenerationError, AudioGenerationProgress, AudioGenerationResult, AudioGenerationStart,
     GenerationMessage,
 };
+use crate::backend::model_registry::{
+    ModelCapabilities, ModelEntry, ModelRegistry, SwappableJobProcessor,
+};
 use crate::backend::music_gpt_chat::{Chat, ChatEntry};
-use crate::backend::music_gpt_ws_handler::{Info, OutboundMsg};
+use crate::backend::music_gpt_ws_handler::{Info, MusicGptWsHandler, OutboundMsg};
+use crate::backend::usage::Quotas;
 use crate::storage::AppFs;
 
 impl OutboundMsg {
@@ -65,12 +70,26 @@ impl OutboundMsg {
             _ => panic!("msg was not GenerationMessage::Chat, it was {self:?}"),
         }
     }
+
+    pub(crate) fn models(self) -> Vec<ModelEntry> {
+        match self {
+            OutboundMsg::Models(p) => p,
+            _ => panic!("msg was not OutboundMsg::Models, it was {self:?}"),
+        }
+    }
+
+    pub(crate) fn unwrap_error(self) -> String {
+        match self {
+            OutboundMsg::Error(p) => p,
+            _ => panic!("msg was not OutboundMsg::Error, it was {self:?}"),
+        }
+    }
 }
 
 impl BackendOutboundMsg {
     pub(crate) fn unwrap_start(self) -> AudioGenerationRequest {
         match self {
-            BackendOutboundMsg::Start(p) => p,
+            BackendOutboundMsg::Start((p, _)) => p,
             _ => panic!("msg was not Progress, it was {self:?}"),
         }
     }
@@ -82,9 +101,16 @@ impl BackendOutboundMsg {
         }
     }
 
-    pub(crate) fn unwrap_response(self) -> (String, VecDeque<f32>) {
+    pub(crate) fn unwrap_chunk(self) -> (String, Vec<f32>) {
+        match self {
+            BackendOutboundMsg::Chunk(p) => p,
+            _ => panic!("msg was not Chunk, it was {self:?}"),
+        }
+    }
+
+    pub(crate) fn unwrap_response(self) -> (String, Vec<f32>) {
         match self {
-            BackendOutboundMsg::Response(p) => p,
+            BackendOutboundMsg::Response((id, audio)) => (id, audio.to_vec().unwrap()),
             _ => panic!("msg was not Response, it was {self:?}"),
         }
     }
@@ -115,14 +141,14 @@ impl JobProcessor for DummyJobProcessor {
         prompt: &str,
         secs: usize,
         on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
-        let mut result = VecDeque::new();
+    ) -> ort::Result<Vec<f32>> {
+        let mut result = vec![];
         for i in 0..secs {
             if prompt == format!("fail at {i}") {
                 return Err(ort::Error::new(format!("Failed at {i}")));
             }
             std::thread::sleep(self.wait_scale);
-            result.push_back(i as f32);
+            result.push(i as f32);
             let should_exit = on_progress(result.len() as f32, secs as f32);
             if should_exit {
                 return Err(ort::Error::new("Aborted"));
@@ -133,6 +159,75 @@ impl JobProcessor for DummyJobProcessor {
     }
 }
 
+pub struct DummyModelRegistry;
+
+#[async_trait]
+impl ModelRegistry for DummyModelRegistry {
+    async fn list(&self) -> anyhow::Result<Vec<ModelEntry>> {
+        let capabilities = ModelCapabilities {
+            max_secs: 30,
+            stereo: false,
+            melody: false,
+        };
+        Ok(vec![
+            ModelEntry {
+                name: "dummy".to_string(),
+                display_name: "Dummy".to_string(),
+                installed: true,
+                size_bytes: 1024,
+                capabilities: capabilities.clone(),
+            },
+            ModelEntry {
+                name: "dummy-2".to_string(),
+                display_name: "Dummy 2".to_string(),
+                installed: true,
+                size_bytes: 2048,
+                capabilities: capabilities.clone(),
+            },
+            ModelEntry {
+                name: "dummy-3".to_string(),
+                display_name: "Dummy 3".to_string(),
+                installed: false,
+                size_bytes: 0,
+                capabilities,
+            },
+        ])
+    }
+
+    async fn load(&self, _name: &str) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        Ok(Arc::new(DummyJobProcessor::default()))
+    }
+}
+
+/// A handler for the connection of `user` with a [DummyJobProcessor], and the receiving end
+/// of the jobs it queues.
+pub fn dummy_ws_handler(
+    storage: &AppFs,
+    user: &str,
+    quotas: Quotas,
+) -> (MusicGptWsHandler<AppFs>, Receiver<BackendInboundMsg>) {
+    let (ai_tx, ai_rx) = channel();
+    let handler = MusicGptWsHandler {
+        storage: storage.clone(),
+        ai_broadcast_tx: tokio::sync::broadcast::channel(10).0,
+        ai_tx,
+        info: Arc::new(RwLock::new(Info {
+            model: "dummy".to_string(),
+            selection_reason: "".to_string(),
+            device: "Cpu".to_string(),
+        })),
+        info_broadcast_tx: tokio::sync::broadcast::channel(10).0,
+        processor: SwappableJobProcessor::new(Arc::new(DummyJobProcessor::default())),
+        registry: Arc::new(DummyModelRegistry),
+        limits: Default::default(),
+        quotas,
+        maintenance: Default::default(),
+        user: user.to_string(),
+        admin: false,
+    };
+    (handler, ai_rx)
+}
+
 pub fn rand_string() -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
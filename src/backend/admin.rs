@@ -0,0 +1,469 @@
+//! HTTP routes for operating a shared server: seeing the jobs of every user, cancelling or
+//! reordering them, draining the queue and rejecting new jobs during maintenance.
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{BackendInboundMsg, JobQueueView};
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::queue_estimates::JobQueuePosition;
+use crate::backend::usage::{ApiTokens, TokenQuery};
+
+/// Whether the server takes new jobs. Jobs that were already queued keep running.
+#[derive(Clone, Debug, Default)]
+pub struct Maintenance {
+    status: Arc<RwLock<MaintenanceStatus>>,
+}
+
+impl Maintenance {
+    pub fn status(&self) -> MaintenanceStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    pub fn set(&self, status: MaintenanceStatus) {
+        *self.status.write().unwrap() = status;
+    }
+
+    /// Fails while in maintenance, for rejecting new jobs.
+    pub fn admit(&self) -> anyhow::Result<()> {
+        let status = self.status.read().unwrap();
+        if !status.enabled {
+            return Ok(());
+        }
+        match &status.message {
+            Some(message) => Err(anyhow!("The server is under maintenance: {message}")),
+            None => Err(anyhow!("The server is under maintenance, try again later")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Type, Serialize, Deserialize, PartialEq)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    /// Shown to the users whose jobs are rejected.
+    pub message: Option<String>,
+}
+
+/// A job of any user, as seen by admins.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct AdminJob {
+    /// Who requested the job, if known.
+    pub user: Option<String>,
+    pub queue: JobQueuePosition,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct PrioritizeRequest {
+    /// 1 for making the job the next one to start.
+    pub position: usize,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct DrainReport {
+    /// Jobs removed from the queue.
+    pub dropped: usize,
+}
+
+#[derive(Clone)]
+struct AdminRoutesState {
+    queue: JobQueueView,
+    ai_tx: Sender<BackendInboundMsg>,
+    tokens: ApiTokens,
+    maintenance: Maintenance,
+}
+
+/// HTTP routes for admins to manage the jobs of everyone.
+pub fn admin_routes(
+    queue: JobQueueView,
+    ai_tx: Sender<BackendInboundMsg>,
+    tokens: ApiTokens,
+    maintenance: Maintenance,
+) -> Router {
+    Router::new()
+        .route("/admin/jobs", get(list_jobs))
+        .route("/admin/jobs/:id/cancel", post(cancel_job))
+        .route("/admin/jobs/:id/prioritize", post(prioritize_job))
+        .route("/admin/drain", post(drain))
+        .route(
+            "/admin/maintenance",
+            get(get_maintenance).put(set_maintenance),
+        )
+        .with_state(AdminRoutesState {
+            queue,
+            ai_tx,
+            tokens,
+            maintenance,
+        })
+}
+
+/// The admin making the request, or why it is rejected.
+fn authorize(
+    state: &AdminRoutesState,
+    headers: &HeaderMap,
+    query: &TokenQuery,
+) -> Result<String, (StatusCode, &'static str)> {
+    let token = query.token(headers);
+    if state.tokens.authenticate(token).is_none() {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid API token"));
+    }
+    state
+        .tokens
+        .authenticate_admin(token)
+        .ok_or((StatusCode::FORBIDDEN, "Only admins can manage jobs"))
+}
+
+fn admin_jobs(state: &AdminRoutesState) -> Vec<AdminJob> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let snapshot = state.queue.snapshot();
+    let users = snapshot
+        .jobs
+        .iter()
+        .map(|job| (job.id.clone(), job.user.clone()))
+        .collect::<HashMap<_, _>>();
+    snapshot
+        .positions(now)
+        .into_iter()
+        .map(|queue| AdminJob {
+            user: users
+                .get(&IdPair(queue.chat_id, queue.id).to_string())
+                .cloned()
+                .flatten(),
+            queue,
+        })
+        .collect()
+}
+
+/// The running job and the ones waiting after it, with who requested each.
+async fn list_jobs(
+    State(state): State<AdminRoutesState>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers, &query) {
+        return rejection.into_response();
+    }
+    Json(admin_jobs(&state)).into_response()
+}
+
+/// Stops a running job, or removes a waiting one from the queue.
+async fn cancel_job(
+    State(state): State<AdminRoutesState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+) -> Response {
+    let admin = match authorize(&state, &headers, &query) {
+        Ok(admin) => admin,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let Some(job) = admin_jobs(&state)
+        .into_iter()
+        .find(|job| job.queue.id == id)
+    else {
+        return (StatusCode::NOT_FOUND, format!("Render {id} is not queued")).into_response();
+    };
+    let job_id = IdPair(job.queue.chat_id, id).to_string();
+    info!(job_id, admin, "Cancelling job");
+    let reason = "Cancelled by an administrator".to_string();
+    match state
+        .ai_tx
+        .send(BackendInboundMsg::Cancel((job_id, reason)))
+    {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Moves a waiting job to another position of the queue.
+async fn prioritize_job(
+    State(state): State<AdminRoutesState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Json(req): Json<PrioritizeRequest>,
+) -> Response {
+    let admin = match authorize(&state, &headers, &query) {
+        Ok(admin) => admin,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if req.position == 0 {
+        return (StatusCode::BAD_REQUEST, "position must > 0").into_response();
+    }
+    let Some(job) = admin_jobs(&state)
+        .into_iter()
+        .find(|job| job.queue.id == id)
+    else {
+        return (StatusCode::NOT_FOUND, format!("Render {id} is not queued")).into_response();
+    };
+    if job.queue.position == 0 {
+        return (
+            StatusCode::CONFLICT,
+            format!("Render {id} is already running"),
+        )
+            .into_response();
+    }
+    let job_id = IdPair(job.queue.chat_id, id).to_string();
+    info!(job_id, admin, position = req.position, "Moving job");
+    match state
+        .ai_tx
+        .send(BackendInboundMsg::Prioritize((job_id, req.position)))
+    {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Removes all the jobs waiting for the running one, which is left to finish.
+async fn drain(
+    State(state): State<AdminRoutesState>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+) -> Response {
+    let admin = match authorize(&state, &headers, &query) {
+        Ok(admin) => admin,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let dropped = admin_jobs(&state)
+        .iter()
+        .filter(|job| job.queue.position > 0)
+        .count();
+    info!(admin, dropped, "Draining the queue");
+    let reason = "Removed from the queue by an administrator".to_string();
+    match state.ai_tx.send(BackendInboundMsg::Drain(reason)) {
+        Ok(()) => Json(DrainReport { dropped }).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+async fn get_maintenance(
+    State(state): State<AdminRoutesState>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers, &query) {
+        return rejection.into_response();
+    }
+    Json(state.maintenance.status()).into_response()
+}
+
+/// Starts or ends maintenance, during which new jobs are rejected.
+async fn set_maintenance(
+    State(state): State<AdminRoutesState>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Json(status): Json<MaintenanceStatus>,
+) -> Response {
+    let admin = match authorize(&state, &headers, &query) {
+        Ok(admin) => admin,
+        Err(rejection) => return rejection.into_response(),
+    };
+    info!(admin, enabled = status.enabled, "Setting maintenance mode");
+    state.maintenance.set(status.clone());
+    Json(status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    use axum::body::to_bytes;
+    use axum::http::header::AUTHORIZATION;
+
+    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::audio_generation_backend::{
+        AudioGenerationBackend, AudioGenerationRequest, BackendOutboundMsg,
+    };
+
+    use super::*;
+
+    fn state() -> anyhow::Result<(AdminRoutesState, Receiver<BackendOutboundMsg>)> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(100)));
+        let queue = backend.queue();
+        let (ai_tx, ai_rx) = backend.run();
+        let tokens = ApiTokens::parse(&["alice:s3cret".to_string(), "bob:hunter2".to_string()])?
+            .with_admins(&["alice".to_string()])?;
+        let state = AdminRoutesState {
+            queue,
+            ai_tx,
+            tokens,
+            maintenance: Maintenance::default(),
+        };
+        Ok((state, ai_rx))
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    async fn jobs(state: &AdminRoutesState) -> anyhow::Result<Vec<(Uuid, Option<String>)>> {
+        let response = list_jobs(
+            State(state.clone()),
+            bearer("s3cret"),
+            Query(TokenQuery::default()),
+        )
+        .await;
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        let jobs: Vec<AdminJob> = serde_json::from_slice(&body)?;
+        Ok(jobs
+            .into_iter()
+            .map(|job| (job.queue.id, job.user))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn only_admins_can_manage_jobs() -> anyhow::Result<()> {
+        let (state, _rx) = state()?;
+        let response = drain(
+            State(state.clone()),
+            bearer("nope"),
+            Query(TokenQuery::default()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = drain(
+            State(state.clone()),
+            bearer("hunter2"),
+            Query(TokenQuery::default()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let status = MaintenanceStatus {
+            enabled: true,
+            message: Some("Upgrading the model".to_string()),
+        };
+        let response = set_maintenance(
+            State(state.clone()),
+            bearer("s3cret"),
+            Query(TokenQuery::default()),
+            Json(status),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let err = state.maintenance.admit().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The server is under maintenance: Upgrading the model"
+        );
+        state.maintenance.set(MaintenanceStatus::default());
+        assert!(state.maintenance.admit().is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reorders_cancels_and_drains_jobs() -> anyhow::Result<()> {
+        let (state, rx) = state()?;
+        let ids = [0, 1, 2, 3].map(|_| (Uuid::new_v4(), Uuid::new_v4()));
+        for (i, (chat_id, id)) in ids.iter().enumerate() {
+            state
+                .ai_tx
+                .send(BackendInboundMsg::Request(AudioGenerationRequest {
+                    id: IdPair(*chat_id, *id).to_string(),
+                    prompt: "".to_string(),
+                    secs: 4,
+                    user: Some(["alice", "bob"][i % 2].to_string()),
+                }))?;
+        }
+        rx.recv()?.unwrap_start();
+        let queued = |i: usize| ids[i].1;
+        assert_eq!(
+            jobs(&state).await?,
+            vec![
+                (queued(0), Some("alice".to_string())),
+                (queued(1), Some("bob".to_string())),
+                (queued(2), Some("alice".to_string())),
+                (queued(3), Some("bob".to_string())),
+            ]
+        );
+
+        let admin = || (State(state.clone()), bearer("s3cret"));
+        let (s, h) = admin();
+        let response = prioritize_job(
+            s,
+            Path(queued(3)),
+            h,
+            Query(TokenQuery::default()),
+            Json(PrioritizeRequest { position: 1 }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let (s, h) = admin();
+        let response = prioritize_job(
+            s,
+            Path(queued(0)),
+            h,
+            Query(TokenQuery::default()),
+            Json(PrioritizeRequest { position: 2 }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let (s, h) = admin();
+        let response = cancel_job(s, Path(queued(1)), h, Query(TokenQuery::default())).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        // The messages are handled by the backend in the background.
+        let dropped = loop {
+            if let BackendOutboundMsg::Dropped((req, _, reason)) = rx.recv()? {
+                break (req.id, reason);
+            }
+        };
+        assert_eq!(
+            dropped,
+            (
+                IdPair(ids[1].0, ids[1].1).to_string(),
+                "Cancelled by an administrator".to_string()
+            )
+        );
+        let order = jobs(&state).await?.into_iter().map(|(id, _)| id);
+        assert_eq!(
+            order.collect::<Vec<_>>(),
+            vec![queued(0), queued(3), queued(2)]
+        );
+
+        let (s, h) = admin();
+        let response = drain(s, h, Query(TokenQuery::default())).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(
+            serde_json::from_slice::<DrainReport>(&body)?,
+            DrainReport { dropped: 2 }
+        );
+        let (s, h) = admin();
+        let response = cancel_job(s, Path(Uuid::new_v4()), h, Query(TokenQuery::default())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The running job is left to finish.
+        let mut dropped = vec![];
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Dropped((req, _, _)) => dropped.push(req.id),
+                BackendOutboundMsg::Response((id, _)) => {
+                    assert_eq!(id, IdPair(ids[0].0, ids[0].1).to_string());
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(dropped.len(), 2);
+        Ok(())
+    }
+}
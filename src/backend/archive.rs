@@ -0,0 +1,314 @@
+//! Content-addressed archival of the finished renders, so that a team can reference the
+//! generated assets by hash across projects and know they never change. Each render is
+//! archived as its audio and a record of its manifest, both named after their SHA-256,
+//! and optionally pinned to IPFS.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::extract::{Path, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use specta::Type;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::render_manifest::{RenderManifest, RenderStatus};
+use crate::storage::Storage;
+
+/// How renders are archived.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ArchiveConfig {
+    /// Kubo RPC API the archived files are added to and pinned on, like
+    /// http://127.0.0.1:5001.
+    pub ipfs_api: Option<String>,
+}
+
+/// What an archived render is referenced by, stored in `archive/renders/{id}.json`.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ArchiveRef {
+    pub id: Uuid,
+    /// SHA-256 of the record, which names the audio by its own hash.
+    pub record: String,
+    pub audio: String,
+    /// IPFS content identifiers, if pinned.
+    pub record_cid: Option<String>,
+    pub audio_cid: Option<String>,
+}
+
+/// The immutable description of a render: its manifest as it was when it completed, and
+/// the audio it produced.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ArchiveRecord {
+    pub manifest: RenderManifest,
+    pub audio_sha256: String,
+    pub audio_cid: Option<String>,
+}
+
+fn blob_path(hash: &str) -> String {
+    format!("archive/sha256/{hash}")
+}
+
+fn ref_path(id: Uuid) -> String {
+    format!("archive/renders/{id}.json")
+}
+
+#[derive(Clone)]
+pub struct Archive<S: Storage> {
+    storage: S,
+    config: ArchiveConfig,
+}
+
+impl<S: Storage> Archive<S> {
+    pub fn new(storage: S, config: ArchiveConfig) -> Self {
+        Self { storage, config }
+    }
+
+    /// Stores `content` under its hash, unless it already is, and pins it if configured.
+    /// Returns its hash and content identifier.
+    async fn put(&self, content: &[u8], name: &str) -> anyhow::Result<(String, Option<String>)> {
+        let hash = format!("{:x}", Sha256::digest(content));
+        let path = blob_path(&hash);
+        if !self.storage.exists(&path).await? {
+            // Written aside first, so that a file named after a hash always matches it.
+            let partial = format!("{path}.partial");
+            self.storage.write(&partial, content).await?;
+            self.storage.mv(&partial, &path).await?;
+        }
+        let cid = match &self.config.ipfs_api {
+            Some(api) => Some(ipfs_add(api, name, content).await?),
+            None => None,
+        };
+        Ok((hash, cid))
+    }
+
+    /// Archives a completed render, returning how it is referenced.
+    pub async fn archive(&self, id: Uuid) -> anyhow::Result<ArchiveRef> {
+        let manifest = RenderManifest::load(&self.storage, id)
+            .await?
+            .ok_or_else(|| anyhow!("Render {id} not found"))?;
+        if manifest.status != RenderStatus::Completed {
+            return Err(anyhow!("Render {id} did not complete"));
+        }
+        let audio = self
+            .storage
+            .read(&format!("audios/{id}.wav"))
+            .await?
+            .ok_or_else(|| anyhow!("The audio of render {id} was deleted"))?;
+        let (audio, audio_cid) = self.put(&audio, &format!("{id}.wav")).await?;
+        let record = ArchiveRecord {
+            manifest,
+            audio_sha256: audio.clone(),
+            audio_cid: audio_cid.clone(),
+        };
+        let record_json = serde_json::to_vec_pretty(&record)?;
+        let (record, record_cid) = self.put(&record_json, &format!("{id}.json")).await?;
+        let archived = ArchiveRef {
+            id,
+            record,
+            audio,
+            record_cid,
+            audio_cid,
+        };
+        self.storage
+            .write(&ref_path(id), serde_json::to_vec(&archived)?)
+            .await?;
+        Ok(archived)
+    }
+
+    /// Archives the renders completed while the server was down, and then each one that
+    /// completes, until the server stops.
+    pub fn spawn(
+        self,
+        mut rx: tokio::sync::broadcast::Receiver<GenerationMessage>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            match RenderManifest::load_all(&self.storage).await {
+                Ok(manifests) => {
+                    for manifest in manifests {
+                        let archived = self.storage.exists(&ref_path(manifest.id)).await;
+                        if manifest.status != RenderStatus::Completed || archived.unwrap_or(true) {
+                            continue;
+                        }
+                        self.archive_logged(manifest.id).await;
+                    }
+                }
+                Err(err) => warn!("Could not list the renders to archive: {err}"),
+            }
+            loop {
+                match rx.recv().await {
+                    Ok(GenerationMessage::Result(result)) => self.archive_logged(result.id).await,
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("{missed} renders ended without being archived");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+
+    async fn archive_logged(&self, id: Uuid) {
+        match self.archive(id).await {
+            Ok(archived) => info!(job_id = %id, "Archived as {}", archived.record),
+            Err(err) => warn!(job_id = %id, "Could not archive the render: {err}"),
+        }
+    }
+}
+
+/// Adds a file to an IPFS node through its RPC API, pinning it, and returns its CID.
+async fn ipfs_add(api: &str, name: &str, content: &[u8]) -> anyhow::Result<String> {
+    let boundary = format!("musicgpt-{}", Uuid::new_v4().simple());
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend(content);
+    body.extend(format!("\r\n--{boundary}--\r\n").as_bytes());
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/api/v0/add?pin=true&cid-version=1",
+            api.trim_end_matches('/')
+        ))
+        .header(
+            "Content-Type",
+            format!("multipart/form-data; boundary={boundary}"),
+        )
+        .timeout(Duration::from_secs(300))
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("The IPFS node answered with {}", response.status()));
+    }
+    #[derive(Deserialize)]
+    struct Added {
+        #[serde(rename = "Hash")]
+        hash: String,
+    }
+    let added: Added = serde_json::from_slice(&response.bytes().await?)?;
+    Ok(added.hash)
+}
+
+/// HTTP routes serving the archive: `/archive/{sha256}` for the files, which never
+/// change, and `/archive/renders/{id}` for what a render was archived as.
+pub fn archive_routes<S: Storage>(storage: S) -> Router {
+    Router::new()
+        .route("/archive/:hash", get(get_blob::<S>))
+        .route("/archive/renders/:id", get(get_ref::<S>))
+        .with_state(storage)
+}
+
+async fn get_blob<S: Storage>(State(storage): State<S>, Path(hash): Path<String>) -> Response {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return (StatusCode::BAD_REQUEST, "Not a SHA-256").into_response();
+    }
+    match storage.read(&blob_path(&hash.to_ascii_lowercase())).await {
+        Ok(Some(content)) => {
+            let content_type = match content.starts_with(b"RIFF") {
+                true => "audio/wav",
+                false => "application/json",
+            };
+            (
+                [
+                    (CONTENT_TYPE, content_type),
+                    (CACHE_CONTROL, "public, max-age=31536000, immutable"),
+                ],
+                content,
+            )
+                .into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, format!("{hash} is not archived")).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+async fn get_ref<S: Storage>(State(storage): State<S>, Path(id): Path<Uuid>) -> Response {
+    match storage.read(&ref_path(id)).await {
+        Ok(Some(content)) => match serde_json::from_slice::<ArchiveRef>(&content) {
+            Ok(archived) => Json(archived).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        },
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            format!("Render {id} is not archived"),
+        )
+            .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Bytes};
+    use axum::routing::post;
+
+    use crate::audio::wav::encode_wav;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn archives_renders_by_hash() -> anyhow::Result<()> {
+        let app = Router::new().route(
+            "/api/v0/add",
+            post(|body: Bytes| async move {
+                let cid = match body.windows(4).any(|w| w == b"RIFF") {
+                    true => "bafyaudio",
+                    false => "bafyrecord",
+                };
+                Json(serde_json::json!({ "Name": "file", "Hash": cid, "Size": "1" }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let host = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let storage = AppFs::new_tmp();
+        let mut manifest =
+            RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), "rain".to_string(), 10, None);
+        manifest.status = RenderStatus::Completed;
+        manifest.save(&storage).await?;
+        let wav = encode_wav(vec![0.25; 3200], 32000)?;
+        storage
+            .write(&format!("audios/{}.wav", manifest.id), &wav)
+            .await?;
+
+        let archive = Archive::new(
+            storage.clone(),
+            ArchiveConfig {
+                ipfs_api: Some(format!("http://{host}")),
+            },
+        );
+        let archived = archive.archive(manifest.id).await?;
+        assert_eq!(archived.audio, format!("{:x}", Sha256::digest(&wav)));
+        assert_eq!(archived.audio_cid.as_deref(), Some("bafyaudio"));
+        assert_eq!(archived.record_cid.as_deref(), Some("bafyrecord"));
+        let record = storage.read(&blob_path(&archived.record)).await?.unwrap();
+        assert_eq!(archived.record, format!("{:x}", Sha256::digest(&record)));
+        let record: ArchiveRecord = serde_json::from_slice(&record)?;
+        assert_eq!(record.manifest, manifest);
+        assert_eq!(record.audio_sha256, archived.audio);
+        // Archiving again names the same files.
+        assert_eq!(archive.archive(manifest.id).await?, archived);
+
+        let response = get_blob(State(storage.clone()), Path(archived.audio.clone())).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "audio/wav");
+        assert_eq!(to_bytes(response.into_body(), usize::MAX).await?, wav);
+        let response = get_ref(State(storage.clone()), Path(manifest.id)).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(serde_json::from_slice::<ArchiveRef>(&body)?, archived);
+        let response = get_blob(State(storage), Path("../audios".to_string())).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+//! Cover art for renders, from an image generator plugged in by the user. The art is
+//! embedded in the ID3 tags of the written files and in their delivery packages.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::audio::id3::{self, Picture};
+
+/// What the cover art is generated from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ArtworkRequest {
+    pub prompt: String,
+    pub secs: f32,
+    /// The model and version the audio was generated with, if known.
+    pub model: Option<String>,
+    pub license: Option<String>,
+}
+
+impl ArtworkRequest {
+    /// An ID3 tag with `cover` as the front cover, and the prompt as the title.
+    pub fn id3_tag(&self, cover: &Picture) -> Vec<u8> {
+        let mut text = vec![
+            (*b"TIT2", self.prompt.clone()),
+            (*b"TSSE", "MusicGPT".to_string()),
+        ];
+        if let Some(license) = &self.license {
+            text.push((*b"TCOP", license.clone()));
+        }
+        id3::id3_tag(&text, Some(cover))
+    }
+}
+
+/// Something that generates cover art, like an image generation model.
+#[async_trait]
+pub trait ArtworkProvider: Send + Sync {
+    async fn artwork(&self, request: &ArtworkRequest) -> anyhow::Result<Picture>;
+}
+
+/// Runs a program with the request as JSON in its stdin and the prompt in the
+/// `MUSICGPT_PROMPT` variable, and reads the image from its stdout.
+pub struct CommandArtwork {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+#[async_trait]
+impl ArtworkProvider for CommandArtwork {
+    async fn artwork(&self, request: &ArtworkRequest) -> anyhow::Result<Picture> {
+        let mut child = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .env("MUSICGPT_PROMPT", &request.prompt)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| anyhow!("Could not run {:?}: {err}", self.program))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Programs that only read the variable may exit without reading it.
+            let _ = stdin.write_all(&serde_json::to_vec(request)?).await;
+        }
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(anyhow!("{:?} exited with {}", self.program, output.status));
+        }
+        Picture::sniff(output.stdout)
+            .ok_or_else(|| anyhow!("{:?} did not output an image", self.program))
+    }
+}
+
+/// Posts the request as JSON to an HTTP endpoint, which answers with the image.
+pub struct HttpArtwork {
+    pub url: String,
+}
+
+#[async_trait]
+impl ArtworkProvider for HttpArtwork {
+    async fn artwork(&self, request: &ArtworkRequest) -> anyhow::Result<Picture> {
+        let response = reqwest::Client::new()
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(request)?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "The artwork endpoint answered with {}",
+                response.status()
+            ));
+        }
+        Picture::sniff(response.bytes().await?.to_vec())
+            .ok_or_else(|| anyhow!("The artwork endpoint did not answer with an image"))
+    }
+}
+
+/// How cover art is generated.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ArtworkConfig {
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    Http {
+        url: String,
+    },
+}
+
+impl ArtworkConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Could not read the artwork provider {path:?}: {err}"))?;
+        serde_json::from_str(&content)
+            .map_err(|err| anyhow!("Invalid artwork provider {path:?}: {err}"))
+    }
+
+    pub fn build(&self) -> Box<dyn ArtworkProvider> {
+        match self {
+            ArtworkConfig::Command { program, args } => Box::new(CommandArtwork {
+                program: program.clone(),
+                args: args.clone(),
+            }),
+            ArtworkConfig::Http { url } => Box::new(HttpArtwork { url: url.clone() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_the_art_a_command_outputs() -> anyhow::Result<()> {
+        let config: ArtworkConfig = serde_json::from_str(
+            r#"{"Command": {"program": "sh", "args": ["-c", "cat > /dev/null; printf '\\211PNG\\r\\n\\032\\n%s' \"$MUSICGPT_PROMPT\""]}}"#,
+        )?;
+        let request = ArtworkRequest {
+            prompt: "rain".to_string(),
+            secs: 10.0,
+            ..Default::default()
+        };
+        let picture = config.build().artwork(&request).await?;
+        assert_eq!(picture.mime, "image/png");
+        assert!(picture.data.ends_with(b"rain"));
+
+        let text = CommandArtwork {
+            program: "echo".to_string(),
+            args: vec!["no image".to_string()],
+        };
+        let err = text.artwork(&request).await.unwrap_err();
+        assert_eq!(err.to_string(), "\"echo\" did not output an image");
+        Ok(())
+    }
+}
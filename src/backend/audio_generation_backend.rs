@@ -1,41 +1,173 @@
 use std::collections::VecDeque;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{JoinHandle, ThreadId};
+use std::time::{Duration, Instant};
 
 use tokio_util::sync::CancellationToken;
+use tracing::{field, info, info_span};
+
+use crate::audio::audio_sink::{AudioSink, MemorySink, TrimSink};
+use crate::audio::extended_generation::{AuditionVerdict, SegmentRetry};
+use crate::audio::gain_staging::SegmentGain;
+use crate::audio::inpaint::{inpaint, InpaintRegion};
+use crate::audio::markers::SegmentMarker;
+use crate::audio::pipeline::{StreamPipeline, DEFAULT_CAPACITY};
+use crate::audio::spill_buffer::{SpillBuffer, DEFAULT_MEMORY_LIMIT};
+use crate::backend::job_limits::JobEstimate;
+use crate::backend::model_registry::ModelVersion;
+use crate::backend::queue_estimates::{QueueSnapshot, QueuedJob, RtfMeter, RunningJob};
+use crate::backend::render_manifest::PostChain;
+use crate::cli::SAMPLING_RATE;
+use crate::musicgen::Continuation;
 
 #[derive(Clone, Debug)]
 pub struct AudioGenerationRequest {
     pub id: String,
     pub prompt: String,
     pub secs: usize,
+    /// Who requested the job, for accounting its usage.
+    pub user: Option<String>,
 }
 
 #[derive(Clone, Debug)]
 pub enum BackendInboundMsg {
     Request(AudioGenerationRequest),
+    /// Continues a job that was interrupted, instead of starting it from scratch.
+    Resume((AudioGenerationRequest, JobCheckpoint)),
+    /// Generates a render again with one of its segments replaced.
+    Edit((AudioGenerationRequest, SegmentEdit)),
+    /// Generates a render again with a stretch of its audio replaced.
+    Inpaint((AudioGenerationRequest, Inpainting)),
+    /// Generates a render holding back each of its segments until a verdict on it
+    /// arrives, continuing from the checkpoint if any.
+    Audition((AudioGenerationRequest, Option<JobCheckpoint>)),
+    /// Keeps or generates again the given segment of the job with the given id, which is
+    /// waiting for it.
+    Verdict((String, usize, AuditionVerdict)),
     Abort(String),
+    /// Stops the job with the given id, or removes it from the queue with the given reason
+    /// if it did not start yet.
+    Cancel((String, String)),
+    /// Moves a queued job to the given position, 1 being the next one to start.
+    Prioritize((String, usize)),
+    /// Removes all the jobs that did not start yet with the given reason.
+    Drain(String),
+    /// Stops processing jobs once the running one can be resumed later, which for
+    /// extended renders means when its current segment completes.
+    Shutdown,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum BackendOutboundMsg {
-    Start(AudioGenerationRequest),
-    Response((String, VecDeque<f32>)),
+    Start((AudioGenerationRequest, JobOrigin)),
+    /// The job finished with this audio, which may be on disk if it is long.
+    Response((String, SpillBuffer)),
     Failure((String, String)),
     Progress((String, f32)),
+    /// A piece of the job's audio, sent as soon as it is generated.
+    Chunk((String, Vec<f32>)),
+    /// A segment of the job failed and is being generated again.
+    Retry((String, SegmentRetry)),
+    /// A segment of the job was normalized with the given gain.
+    Normalized((String, SegmentGain)),
+    /// The audio the given segment of the job was generated with, before being processed.
+    Segment((String, usize, Vec<f32>)),
+    /// Where a segment of the job begins in its audio.
+    Marker((String, SegmentMarker)),
+    /// A segment of an auditioned job as it will be stitched, from the beginning of its
+    /// crossfade, waiting for a verdict.
+    Audition((String, usize, Vec<f32>)),
+    /// The job completed the given number of segments, and could be resumed from there.
+    Checkpoint((String, usize)),
+    /// The running job was stopped by a shutdown, with what is needed for resuming it.
+    Interrupted((AudioGenerationRequest, JobCheckpoint)),
+    /// A job that was still queued when shutting down.
+    Pending((AudioGenerationRequest, JobOrigin)),
+    /// A job that was removed from the queue before starting, and why.
+    Dropped((AudioGenerationRequest, JobOrigin, String)),
+}
+
+/// What a job is generated with, recorded so that it can be reproduced.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JobOrigin {
+    /// The exact model, if known.
+    pub model: Option<ModelVersion>,
+    /// The prompt the model is given for each segment.
+    pub segment_prompts: Vec<String>,
+    /// The seed each segment is sampled with instead of the configured one, for renders
+    /// with edited segments.
+    pub segment_seeds: Vec<Option<u64>>,
+}
+
+/// A segment of a render to generate again, with everything needed for stitching it
+/// with the others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentEdit {
+    pub segment: usize,
+    /// The prompt of each segment, with the one of the edited segment changed.
+    pub prompts: Vec<String>,
+    /// The seed of each segment, with the one of the edited segment changed.
+    pub seeds: Vec<Option<u64>>,
+    /// The audio each segment was generated with, before being processed. The one of
+    /// the edited segment is ignored.
+    pub audio: Vec<Vec<f32>>,
+}
+
+/// A stretch of a render to generate again, with the audio it is crossfaded into.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Inpainting {
+    /// The prompt the replacement is generated from.
+    pub prompt: String,
+    pub audio: Vec<f32>,
+    pub region: InpaintRegion,
+}
+
+/// How far a job got before being interrupted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobCheckpoint {
+    /// Segments that were completely generated.
+    pub segments: usize,
+    /// The audio of those segments.
+    pub audio: Vec<f32>,
 }
 
 #[derive(Clone, Debug)]
 struct Job {
     req: AudioGenerationRequest,
+    checkpoint: Option<JobCheckpoint>,
+    edit: Option<SegmentEdit>,
+    inpainting: Option<Inpainting>,
+    auditions: Option<Auditions>,
     abort_token: CancellationToken,
 }
 
+/// Where the verdicts on the segments of an auditioned job arrive.
+#[derive(Clone, Debug)]
+struct Auditions {
+    tx: Sender<(usize, AuditionVerdict)>,
+    rx: Arc<Mutex<Receiver<(usize, AuditionVerdict)>>>,
+}
+
+impl Auditions {
+    fn new() -> Self {
+        let (tx, rx) = channel();
+        Self {
+            tx,
+            rx: Arc::new(Mutex::new(rx)),
+        }
+    }
+}
+
 impl Job {
-    fn new(req: AudioGenerationRequest) -> Self {
+    fn new(req: AudioGenerationRequest, checkpoint: Option<JobCheckpoint>) -> Self {
         Self {
             req,
+            checkpoint,
+            edit: None,
+            inpainting: None,
+            auditions: None,
             abort_token: CancellationToken::new(),
         }
     }
@@ -47,14 +179,443 @@ pub trait JobProcessor: Send + Sync {
         prompt: &str,
         secs: usize,
         on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>>;
+    ) -> ort::Result<Vec<f32>>;
+
+    /// Same as [JobProcessor::process], but pushes the audio into `sink` while it is
+    /// being generated instead of returning it at the end. By default, all the audio is
+    /// pushed at once.
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let audio = self.process(prompt, secs, on_progress)?;
+        sink.push(&audio).map_err(ort::Error::new)?;
+        sink.finalize().map_err(ort::Error::new)
+    }
+
+    /// Same as [JobProcessor::process_streaming], but for `secs` that need not be whole,
+    /// with the audio cut to the sample. By default, whole seconds are generated and the
+    /// audio past `secs` is dropped.
+    fn process_exact(
+        &self,
+        prompt: &str,
+        secs: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let mut sink = TrimSink::new(sink, (secs * SAMPLING_RATE as f32).round() as usize);
+        self.process_streaming(prompt, secs.ceil() as usize, on_progress, &mut sink)
+    }
+
+    /// Same as [JobProcessor::process_streaming], but continues from the segments in
+    /// `checkpoint`, pushing their audio into `sink` first. By default, the whole audio is
+    /// generated again.
+    fn resume_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        _checkpoint: &JobCheckpoint,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.process_streaming(prompt, secs, on_progress, sink)
+    }
+
+    /// Same as [JobProcessor::process_streaming], but samples the model with `seed`
+    /// instead of the configured one. By default, the seed is ignored.
+    fn process_seeded(
+        &self,
+        prompt: &str,
+        secs: usize,
+        _seed: u64,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.process_streaming(prompt, secs, on_progress, sink)
+    }
+
+    /// Same as [JobProcessor::process_streaming], but continues the generation that
+    /// stopped at `from`: the first `context` seconds repeat the end of its audio, and the
+    /// rest follows it. Returns where this generation stopped, for continuing it in turn.
+    /// By default, nothing can be continued and `from` is ignored.
+    fn process_continuing(
+        &self,
+        prompt: &str,
+        secs: usize,
+        _from: Option<Continuation>,
+        _context: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<Option<Continuation>> {
+        self.process_streaming(prompt, secs, on_progress, sink)?;
+        Ok(None)
+    }
+
+    /// Generates one variation of `prompt` per seed, in the order of the seeds. The
+    /// progress counts the frames of all of them. By default, they are generated one
+    /// after the other.
+    fn process_variations(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seeds: &[u64],
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<Vec<f32>>> {
+        let on_progress: Arc<dyn Fn(f32, f32) -> bool + Sync + Send> = Arc::from(on_progress);
+        let n = seeds.len() as f32;
+        let mut variations = vec![];
+        for (i, &seed) in seeds.iter().enumerate() {
+            let on_progress = on_progress.clone();
+            let mut sink = MemorySink::new();
+            self.process_seeded(
+                prompt,
+                secs,
+                seed,
+                Box::new(move |elapsed, total| on_progress(i as f32 * total + elapsed, n * total)),
+                &mut sink,
+            )?;
+            variations.push(sink.into_inner());
+        }
+        Ok(variations)
+    }
+
+    /// Same as [JobProcessor::process_streaming], but only generates the segment in
+    /// `edit`, reusing the audio of the others. By default, editing is not supported.
+    fn edit_streaming(
+        &self,
+        _prompt: &str,
+        _secs: usize,
+        _edit: &SegmentEdit,
+        _on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        _sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        Err(ort::Error::new("Segments of this model cannot be edited"))
+    }
+
+    /// The exact model used for processing jobs, if known.
+    fn model_version(&self) -> Option<ModelVersion> {
+        None
+    }
+
+    /// The prompts the model is given for a job of `secs` seconds, one per segment. By
+    /// default, the job is generated at once from its prompt.
+    fn segment_prompts(&self, prompt: &str, _secs: usize) -> Vec<String> {
+        vec![prompt.to_string()]
+    }
+
+    /// What a job of `secs` seconds is expected to need, if known.
+    fn estimate(&self, _secs: usize) -> Option<JobEstimate> {
+        None
+    }
+
+    /// Re-creates the inference sessions after a failure, so that retries do not run
+    /// into the same broken state. By default, nothing is done.
+    fn recover(&self) -> ort::Result<()> {
+        Ok(())
+    }
+
+    /// Stops the job that the `worker` thread got stuck in, so that it returns instead of
+    /// waiting forever. The sessions may need a [`JobProcessor::recover`] afterwards. By
+    /// default, the job runs until it finishes by itself.
+    fn terminate(&self, _worker: ThreadId) {}
+
+    /// Jobs that can be processed at the same time without waiting for each other. By
+    /// default, jobs are processed one by one.
+    fn max_concurrent_jobs(&self) -> usize {
+        1
+    }
+
+    /// A processor applying `post` to extended renders instead, sharing the sessions of
+    /// this one. By default, the processing cannot be changed.
+    fn with_post_chain(&self, _post: &PostChain) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        Err(anyhow::anyhow!(
+            "The processing of this model cannot be changed"
+        ))
+    }
+}
+
+impl<T: JobProcessor + ?Sized> JobProcessor for Arc<T> {
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<f32>> {
+        (**self).process(prompt, secs, on_progress)
+    }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        (**self).process_streaming(prompt, secs, on_progress, sink)
+    }
+
+    fn process_exact(
+        &self,
+        prompt: &str,
+        secs: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        (**self).process_exact(prompt, secs, on_progress, sink)
+    }
+
+    fn resume_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        checkpoint: &JobCheckpoint,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        (**self).resume_streaming(prompt, secs, checkpoint, on_progress, sink)
+    }
+
+    fn process_seeded(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seed: u64,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        (**self).process_seeded(prompt, secs, seed, on_progress, sink)
+    }
+
+    fn process_continuing(
+        &self,
+        prompt: &str,
+        secs: usize,
+        from: Option<Continuation>,
+        context: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<Option<Continuation>> {
+        (**self).process_continuing(prompt, secs, from, context, on_progress, sink)
+    }
+
+    fn process_variations(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seeds: &[u64],
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<Vec<f32>>> {
+        (**self).process_variations(prompt, secs, seeds, on_progress)
+    }
+
+    fn edit_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        edit: &SegmentEdit,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        (**self).edit_streaming(prompt, secs, edit, on_progress, sink)
+    }
+
+    fn model_version(&self) -> Option<ModelVersion> {
+        (**self).model_version()
+    }
+
+    fn segment_prompts(&self, prompt: &str, secs: usize) -> Vec<String> {
+        (**self).segment_prompts(prompt, secs)
+    }
+
+    fn estimate(&self, secs: usize) -> Option<JobEstimate> {
+        (**self).estimate(secs)
+    }
+
+    fn recover(&self) -> ort::Result<()> {
+        (**self).recover()
+    }
+
+    fn terminate(&self, worker: ThreadId) {
+        (**self).terminate(worker)
+    }
+
+    fn max_concurrent_jobs(&self) -> usize {
+        (**self).max_concurrent_jobs()
+    }
+
+    fn with_post_chain(&self, post: &PostChain) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        (**self).with_post_chain(post)
+    }
+}
+
+/// Forwards the audio of a job to the outbound channel as it gets generated, while also
+/// keeping all of it for the final response.
+struct JobSink {
+    id: String,
+    tx: Sender<BackendOutboundMsg>,
+    audio: SpillBuffer,
+    shutdown_token: CancellationToken,
+    /// Stop waiting for verdicts on the segments of an auditioned job.
+    abort_tokens: [CancellationToken; 2],
+    auditions: Option<Auditions>,
+    segments: usize,
+    interrupted: bool,
+}
+
+impl AudioSink for JobSink {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.audio.push(chunk)?;
+        let _ = self
+            .tx
+            .send(BackendOutboundMsg::Chunk((self.id.clone(), chunk.to_vec())));
+        Ok(())
+    }
+
+    /// Called after each segment, which is where extended renders stop on shutdown.
+    fn flush(&mut self) -> Result<(), String> {
+        self.segments += 1;
+        if self.shutdown_token.is_cancelled() {
+            self.interrupted = true;
+            return Err("Interrupted by shutdown".to_string());
+        }
+        let _ = self.tx.send(BackendOutboundMsg::Checkpoint((
+            self.id.clone(),
+            self.segments,
+        )));
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn retried(&mut self, retry: &SegmentRetry) {
+        let _ = self
+            .tx
+            .send(BackendOutboundMsg::Retry((self.id.clone(), retry.clone())));
+    }
+
+    fn normalized(&mut self, gain: &SegmentGain) {
+        let _ = self.tx.send(BackendOutboundMsg::Normalized((
+            self.id.clone(),
+            gain.clone(),
+        )));
+    }
+
+    fn generated(&mut self, segment: usize, audio: &[f32]) {
+        let _ = self.tx.send(BackendOutboundMsg::Segment((
+            self.id.clone(),
+            segment,
+            audio.to_vec(),
+        )));
+    }
+
+    fn marked(&mut self, marker: &SegmentMarker) {
+        let _ = self.tx.send(BackendOutboundMsg::Marker((
+            self.id.clone(),
+            marker.clone(),
+        )));
+    }
+
+    fn auditions(&self) -> bool {
+        self.auditions.is_some()
+    }
+
+    /// Waits for the verdict for as long as the job is not aborted. A shutdown interrupts
+    /// the job, which can be resumed from the segments accepted so far.
+    fn audition(&mut self, segment: usize, audio: &[f32]) -> Result<AuditionVerdict, String> {
+        let Some(auditions) = &self.auditions else {
+            return Ok(AuditionVerdict::Accept);
+        };
+        let _ = self.tx.send(BackendOutboundMsg::Audition((
+            self.id.clone(),
+            segment,
+            audio.to_vec(),
+        )));
+        let rx = auditions.rx.lock().unwrap();
+        loop {
+            if self.shutdown_token.is_cancelled() {
+                self.interrupted = true;
+                return Err("Interrupted by shutdown".to_string());
+            }
+            if self.abort_tokens.iter().any(|token| token.is_cancelled()) {
+                return Err("Aborted while auditioning".to_string());
+            }
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok((verdict_segment, verdict)) if verdict_segment == segment => return Ok(verdict),
+                // Verdicts on segments that were already decided are late duplicates.
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("The verdicts stopped arriving".to_string())
+                }
+            }
+        }
+    }
+}
+
+/// A job being processed, and how far it got.
+struct RunningState {
+    id: String,
+    started: Instant,
+    progress: f32,
 }
 
 #[derive(Clone)]
 pub struct AudioGenerationBackend {
     processor: Arc<dyn JobProcessor>,
     job_queue: Arc<RwLock<VecDeque<Job>>>,
+    /// In the order they started, which is the order they are in the queue.
+    running: Arc<RwLock<Vec<RunningState>>>,
+    rtf: Arc<RwLock<RtfMeter>>,
     abort_token: CancellationToken,
+    shutdown_token: CancellationToken,
+    spill_dir: PathBuf,
+}
+
+/// Read-only access to the queue of a running backend, for telling clients when their
+/// jobs start.
+#[derive(Clone)]
+pub struct JobQueueView {
+    backend: AudioGenerationBackend,
+}
+
+impl JobQueueView {
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let backend = &self.backend;
+        let jobs = backend
+            .job_queue
+            .read()
+            .unwrap()
+            .iter()
+            .map(|job| QueuedJob {
+                id: job.req.id.clone(),
+                prompt: job.req.prompt.clone(),
+                secs: job.req.secs,
+                user: job.req.user.clone(),
+            })
+            .collect();
+        let running = backend
+            .running
+            .read()
+            .unwrap()
+            .iter()
+            .map(|running| RunningJob {
+                id: running.id.clone(),
+                progress: running.progress,
+                elapsed: running.started.elapsed(),
+            })
+            .collect();
+        let model = backend.processor.model_version().map(|model| model.name);
+        QueueSnapshot {
+            jobs,
+            running,
+            rtf: backend.rtf.read().unwrap().rtf(model.as_deref()),
+            slots: backend.processor.max_concurrent_jobs(),
+        }
+    }
 }
 
 impl AudioGenerationBackend {
@@ -62,50 +623,292 @@ impl AudioGenerationBackend {
         Self {
             processor: Arc::new(processor),
             job_queue: Arc::new(RwLock::new(VecDeque::new())),
+            running: Arc::new(RwLock::new(vec![])),
+            rtf: Arc::new(RwLock::new(RtfMeter::default())),
             abort_token: CancellationToken::new(),
+            shutdown_token: CancellationToken::new(),
+            spill_dir: std::env::temp_dir(),
+        }
+    }
+
+    /// Where the audio of long jobs is moved to while they run, instead of the
+    /// temporary directory of the system.
+    pub fn with_spill_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.spill_dir = dir.into();
+        self
+    }
+
+    pub fn queue(&self) -> JobQueueView {
+        JobQueueView {
+            backend: self.clone(),
         }
     }
 
+    fn origin(&self, job: &Job) -> JobOrigin {
+        let model = self.processor.model_version();
+        match (&job.edit, &job.inpainting) {
+            (Some(edit), _) => JobOrigin {
+                model,
+                segment_prompts: edit.prompts.clone(),
+                segment_seeds: edit.seeds.clone(),
+            },
+            // Like edited renders, inpainted ones cannot be replayed from their recipe.
+            (None, Some(inpainting)) => JobOrigin {
+                model,
+                segment_prompts: vec![inpainting.prompt.clone()],
+                segment_seeds: vec![None],
+            },
+            (None, None) => JobOrigin {
+                model,
+                segment_prompts: self
+                    .processor
+                    .segment_prompts(&job.req.prompt, job.req.secs),
+                segment_seeds: vec![],
+            },
+        }
+    }
+
+    /// Starts the jobs in the queue, as many at the same time as the processor can take.
     fn job_processing_loop(self, outbound_tx: Sender<BackendOutboundMsg>) {
+        let mut workers: Vec<JoinHandle<()>> = vec![];
         loop {
-            let front = {
-                // Immediately drop jq so that the lock is released.
+            workers.retain(|worker| !worker.is_finished());
+            if self.shutdown_token.is_cancelled() {
+                // Running jobs stop on their own once they can be resumed.
+                for worker in workers {
+                    let _ = worker.join();
+                }
+                let queue = std::mem::take(&mut *self.job_queue.write().unwrap());
+                for job in queue {
+                    let origin = self.origin(&job);
+                    let _ = outbound_tx.send(BackendOutboundMsg::Pending((job.req, origin)));
+                }
+                return;
+            }
+            let next = {
+                // Immediately drop jq so that the lock is released. The job is marked as
+                // running while holding it, so that it is not moved from its position.
                 let jq = self.job_queue.read().unwrap();
-                jq.front().cloned()
+                let mut running = self.running.write().unwrap();
+                let next = jq
+                    .get(first_waiting(&jq, &running))
+                    .filter(|_| running.len() < self.processor.max_concurrent_jobs().max(1))
+                    .cloned();
+                if let Some(job) = &next {
+                    running.push(RunningState {
+                        id: job.req.id.clone(),
+                        started: Instant::now(),
+                        progress: 0.0,
+                    });
+                }
+                next
             };
-            let Some(job) = front else {
-                if self.abort_token.is_cancelled() {
+            let Some(job) = next else {
+                if self.abort_token.is_cancelled() && workers.is_empty() {
                     return;
                 }
                 std::thread::sleep(Duration::from_millis(10));
                 continue;
             };
+            let backend = self.clone();
+            let outbound_tx = outbound_tx.clone();
+            workers.push(std::thread::spawn(move || {
+                backend.run_job(job, outbound_tx)
+            }));
+        }
+    }
 
-            let _ = outbound_tx.send(BackendOutboundMsg::Start(job.req.clone()));
+    /// Processes a job that was marked as running, removing it from the queue once done.
+    fn run_job(&self, job: Job, outbound_tx: Sender<BackendOutboundMsg>) {
+        let origin = self.origin(&job);
+        // Everything logged while processing the job, including the segments of
+        // extended renders, is tagged with its id and model.
+        let span = info_span!("job", job_id = %job.req.id, model = field::Empty);
+        if let Some(model) = &origin.model {
+            span.record("model", model.name.as_str());
+        }
+        let _span = span.entered();
+        let model = origin.model.as_ref().map(|model| model.name.clone());
+        let _ = outbound_tx.send(BackendOutboundMsg::Start((job.req.clone(), origin)));
+        let started = Instant::now();
 
-            let output_tx_clone = outbound_tx.clone();
-            let abort_token = self.abort_token.clone();
-            let job_id = job.req.id.clone();
-            let cbk = Box::new(move |elapsed, total| {
-                let msg = BackendOutboundMsg::Progress((job_id.clone(), elapsed / total));
-                let _ = output_tx_clone.send(msg);
-                abort_token.is_cancelled() || job.abort_token.is_cancelled()
-            });
+        let output_tx_clone = outbound_tx.clone();
+        let abort_token = self.abort_token.clone();
+        let job_abort_token = job.abort_token.clone();
+        let job_id = job.req.id.clone();
+        let running = self.running.clone();
+        let cbk = Box::new(move |elapsed, total| {
+            let msg = BackendOutboundMsg::Progress((job_id.clone(), elapsed / total));
+            let mut running = running.write().unwrap();
+            if let Some(running) = running.iter_mut().find(|running| running.id == job_id) {
+                running.progress = elapsed / total;
+            }
+            let _ = output_tx_clone.send(msg);
+            abort_token.is_cancelled() || job_abort_token.is_cancelled()
+        });
 
-            let msg = match self.processor.process(&job.req.prompt, job.req.secs, cbk) {
-                Ok(filepath) => BackendOutboundMsg::Response((job.req.id, filepath)),
-                Err(err) => BackendOutboundMsg::Failure((job.req.id, err.to_string())),
-            };
-            let _ = outbound_tx.send(msg);
-            self.job_queue.write().unwrap().pop_front();
+        let sink = JobSink {
+            id: job.req.id.clone(),
+            tx: outbound_tx.clone(),
+            audio: SpillBuffer::new(&self.spill_dir, DEFAULT_MEMORY_LIMIT),
+            shutdown_token: self.shutdown_token.clone(),
+            abort_tokens: [self.abort_token.clone(), job.abort_token.clone()],
+            auditions: job.auditions.clone(),
+            segments: job.checkpoint.as_ref().map_or(0, |c| c.segments),
+            interrupted: false,
+        };
+        // The audio is kept and forwarded from another thread, so that generating does
+        // not wait for it unless it falls behind.
+        let (mut input, handle) = StreamPipeline::new(DEFAULT_CAPACITY).spawn(sink);
+        let result = match (&job.checkpoint, &job.edit, &job.inpainting) {
+            (Some(checkpoint), _, _) => self.processor.resume_streaming(
+                &job.req.prompt,
+                job.req.secs,
+                checkpoint,
+                cbk,
+                &mut input,
+            ),
+            (None, Some(edit), _) => {
+                self.processor
+                    .edit_streaming(&job.req.prompt, job.req.secs, edit, cbk, &mut input)
+            }
+            (None, None, Some(inpainting)) => self.inpaint(inpainting, cbk, &mut input),
+            (None, None, None) => {
+                self.processor
+                    .process_streaming(&job.req.prompt, job.req.secs, cbk, &mut input)
+            }
+        };
+        // Dropping the input lets the pipeline finish even if the generation failed.
+        drop(input);
+        let joined = handle.join();
+        let id = job.req.id.clone();
+        // Resumed, edited and inpainted jobs only generate part of their audio, they
+        // would make the model look faster than it is.
+        let partial = job.checkpoint.is_some() || job.edit.is_some() || job.inpainting.is_some();
+        if result.is_ok() && !partial {
+            self.rtf
+                .write()
+                .unwrap()
+                .record(model.as_deref(), job.req.secs, started.elapsed());
         }
+        let msg = match (result, joined) {
+            (_, Err(err)) => BackendOutboundMsg::Failure((job.req.id, err)),
+            (Ok(()), Ok(sink)) => BackendOutboundMsg::Response((job.req.id, sink.audio)),
+            (Err(_), Ok(sink)) if sink.interrupted => match sink.audio.to_vec() {
+                Ok(audio) => {
+                    let checkpoint = JobCheckpoint {
+                        segments: sink.segments,
+                        audio,
+                    };
+                    BackendOutboundMsg::Interrupted((job.req, checkpoint))
+                }
+                Err(err) => BackendOutboundMsg::Failure((job.req.id, err)),
+            },
+            (Err(err), Ok(_)) => BackendOutboundMsg::Failure((job.req.id, err.to_string())),
+        };
+        let _ = outbound_tx.send(msg);
+        let mut queue = self.job_queue.write().unwrap();
+        if let Some(i) = queue.iter().position(|job| job.req.id == id) {
+            queue.remove(i);
+        }
+        self.running
+            .write()
+            .unwrap()
+            .retain(|running| running.id != id);
+    }
+
+    /// Generates the replacement of the inpainted stretch, and pushes the whole audio
+    /// with it crossfaded in.
+    fn inpaint(
+        &self,
+        inpainting: &Inpainting,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let Inpainting {
+            prompt,
+            audio,
+            region,
+        } = inpainting;
+        let secs = region
+            .replacement_len(audio.len(), SAMPLING_RATE)
+            .div_ceil(SAMPLING_RATE);
+        let mut replacement = MemorySink::new();
+        self.processor
+            .process_streaming(prompt, secs, on_progress, &mut replacement)?;
+        let inpainted = inpaint(audio, region, &replacement.into_inner(), SAMPLING_RATE)
+            .map_err(ort::Error::new)?;
+        info!(
+            "Inpainted {secs}s of replacement, shifted by {:.2}s and turned by {:+.1}dB",
+            inpainted.offset as f32 / SAMPLING_RATE as f32,
+            inpainted.gain_db
+        );
+        sink.push(&inpainted.audio).map_err(ort::Error::new)?;
+        sink.finalize().map_err(ort::Error::new)
+    }
+
+    /// Index in the queue of the first job that did not start yet.
+    fn first_waiting(&self, queue: &VecDeque<Job>) -> usize {
+        first_waiting(queue, &self.running.read().unwrap())
     }
 
-    fn msg_processing_loop(self, inbound_rx: Receiver<BackendInboundMsg>) {
+    /// Removes the job from the queue, telling clients why it will not run.
+    fn drop_job(&self, job: Job, reason: &str, outbound_tx: &Sender<BackendOutboundMsg>) {
+        let origin = self.origin(&job);
+        let _ = outbound_tx.send(BackendOutboundMsg::Dropped((
+            job.req,
+            origin,
+            reason.to_string(),
+        )));
+    }
+
+    fn msg_processing_loop(
+        self,
+        inbound_rx: Receiver<BackendInboundMsg>,
+        outbound_tx: Sender<BackendOutboundMsg>,
+    ) {
+        // Dropped on shutdown, so that the outbound channel closes once the processing loop
+        // reports the jobs left in the queue.
+        let mut outbound_tx = Some(outbound_tx);
         while let Ok(msg) = inbound_rx.recv() {
             match msg {
                 BackendInboundMsg::Request(req) => {
-                    self.job_queue.write().unwrap().push_back(Job::new(req));
+                    self.job_queue
+                        .write()
+                        .unwrap()
+                        .push_back(Job::new(req, None));
+                }
+                BackendInboundMsg::Resume((req, checkpoint)) => {
+                    let job = Job::new(req, Some(checkpoint));
+                    self.job_queue.write().unwrap().push_back(job);
+                }
+                BackendInboundMsg::Edit((req, edit)) => {
+                    let job = Job {
+                        edit: Some(edit),
+                        ..Job::new(req, None)
+                    };
+                    self.job_queue.write().unwrap().push_back(job);
+                }
+                BackendInboundMsg::Inpaint((req, inpainting)) => {
+                    let job = Job {
+                        inpainting: Some(inpainting),
+                        ..Job::new(req, None)
+                    };
+                    self.job_queue.write().unwrap().push_back(job);
+                }
+                BackendInboundMsg::Audition((req, checkpoint)) => {
+                    let job = Job {
+                        auditions: Some(Auditions::new()),
+                        ..Job::new(req, checkpoint)
+                    };
+                    self.job_queue.write().unwrap().push_back(job);
+                }
+                BackendInboundMsg::Verdict((id, segment, verdict)) => {
+                    let queue = self.job_queue.read().unwrap();
+                    let job = queue.iter().find(|job| job.req.id == id);
+                    if let Some(auditions) = job.and_then(|job| job.auditions.as_ref()) {
+                        let _ = auditions.tx.send((segment, verdict));
+                    }
                 }
                 BackendInboundMsg::Abort(id) => {
                     let mut queue = self.job_queue.write().unwrap();
@@ -121,6 +924,46 @@ impl AudioGenerationBackend {
                         queue.remove(to_remove);
                     }
                 }
+                BackendInboundMsg::Cancel((id, reason)) => {
+                    let Some(outbound_tx) = &outbound_tx else {
+                        continue;
+                    };
+                    let mut queue = self.job_queue.write().unwrap();
+                    let first = self.first_waiting(&queue);
+                    let Some(i) = queue.iter().position(|job| job.req.id == id) else {
+                        continue;
+                    };
+                    if i < first {
+                        queue[i].abort_token.cancel();
+                    } else if let Some(job) = queue.remove(i) {
+                        self.drop_job(job, &reason, outbound_tx);
+                    }
+                }
+                BackendInboundMsg::Prioritize((id, position)) => {
+                    let mut queue = self.job_queue.write().unwrap();
+                    let first = self.first_waiting(&queue);
+                    let Some(i) = queue.iter().skip(first).position(|job| job.req.id == id) else {
+                        continue;
+                    };
+                    if let Some(job) = queue.remove(first + i) {
+                        let to = (first + position.max(1) - 1).min(queue.len());
+                        queue.insert(to, job);
+                    }
+                }
+                BackendInboundMsg::Drain(reason) => {
+                    let Some(outbound_tx) = &outbound_tx else {
+                        continue;
+                    };
+                    let mut queue = self.job_queue.write().unwrap();
+                    let first = self.first_waiting(&queue);
+                    for job in queue.split_off(first) {
+                        self.drop_job(job, &reason, outbound_tx);
+                    }
+                }
+                BackendInboundMsg::Shutdown => {
+                    outbound_tx = None;
+                    self.shutdown_token.cancel()
+                }
             }
         }
         self.abort_token.cancel()
@@ -132,20 +975,33 @@ impl AudioGenerationBackend {
 
         // Job processing loop.
         let self_clone = self.clone();
-        std::thread::spawn(move || self_clone.job_processing_loop(outbound_tx));
+        let outbound_tx_clone = outbound_tx.clone();
+        std::thread::spawn(move || self_clone.job_processing_loop(outbound_tx_clone));
 
         // Communications processing loop.
-        std::thread::spawn(move || self.msg_processing_loop(inbound_rx));
+        std::thread::spawn(move || self.msg_processing_loop(inbound_rx, outbound_tx));
 
         (inbound_tx, outbound_rx)
     }
 }
 
+/// Running jobs are always the first ones in the queue, as they start in order.
+fn first_waiting(queue: &VecDeque<Job>, running: &[RunningState]) -> usize {
+    queue
+        .iter()
+        .take_while(|job| running.iter().any(|running| running.id == job.req.id))
+        .count()
+}
+
 #[cfg(test)]
 mod tests {
     use uuid::Uuid;
 
+    use crate::audio::extended_generation::ExtendedGenerationConfig;
     use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::extended_audio_backend::ExtendedJobProcessor;
+    use crate::backend::music_gpt_ws_handler::IdPair;
+    use crate::backend::SessionPool;
 
     use super::*;
 
@@ -160,6 +1016,7 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 4,
+            user: None,
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -167,11 +1024,91 @@ mod tests {
         assert_eq!(rx.recv()?.unwrap_progress().1, 0.5);
         assert_eq!(rx.recv()?.unwrap_progress().1, 0.75);
         assert_eq!(rx.recv()?.unwrap_progress().1, 1.0);
-        assert_eq!(
-            rx.recv()?.unwrap_response().1,
-            VecDeque::from([0.0, 1.0, 2.0, 3.0])
-        );
+        assert_eq!(rx.recv()?.unwrap_chunk().1, vec![0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(rx.recv()?.unwrap_response().1, vec![0.0, 1.0, 2.0, 3.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_the_queue_with_the_measured_rtf() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(20)));
+        let queue = backend.queue();
+        let (tx, rx) = backend.run();
+        let request = |id: &str, secs| {
+            BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.to_string(),
+                prompt: "".to_string(),
+                secs,
+                user: None,
+            })
+        };
+
+        // Until a job finishes, there is nothing to estimate from.
+        let first = IdPair(Uuid::new_v4(), Uuid::new_v4()).to_string();
+        tx.send(request(&first, 2))?;
+        rx.recv()?.unwrap_start();
+        assert_eq!(queue.snapshot().rtf, None);
+        while !matches!(rx.recv()?, BackendOutboundMsg::Response(_)) {}
+        assert!(queue.snapshot().rtf.is_some_and(|rtf| rtf >= 0.02));
+
+        let running = IdPair(Uuid::new_v4(), Uuid::new_v4());
+        let waiting = IdPair(Uuid::new_v4(), Uuid::new_v4());
+        tx.send(request(&running.to_string(), 10))?;
+        tx.send(request(&waiting.to_string(), 4))?;
+        rx.recv()?.unwrap_start();
+        rx.recv()?.unwrap_progress();
+
+        let positions = queue.snapshot().positions(0);
+        assert_eq!(positions.len(), 2);
+        assert_eq!((positions[0].id, positions[0].position), (running.1, 0));
+        assert_eq!((positions[1].id, positions[1].position), (waiting.1, 1));
+        assert!(positions[1].starts_in_secs.is_some_and(|secs| secs > 0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn runs_jobs_at_the_same_time_up_to_the_pool_size() -> anyhow::Result<()> {
+        let processor = || Arc::new(DummyJobProcessor::new(Duration::from_millis(50)));
+        let pool = SessionPool::new(processor(), 2, move || Ok(processor()));
+        let backend = AudioGenerationBackend::new(pool);
+        let queue = backend.queue();
+        let (tx, rx) = backend.run();
+
+        let ids = [0, 1, 2].map(|_| IdPair(Uuid::new_v4(), Uuid::new_v4()));
+        for id in &ids {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.to_string(),
+                prompt: "".to_string(),
+                secs: 4,
+                user: None,
+            }))?;
+        }
+        let mut started = vec![];
+        while started.len() < 2 {
+            if let BackendOutboundMsg::Start((req, _)) = rx.recv()? {
+                started.push(req.id);
+            }
+        }
+        assert_eq!(started, vec![ids[0].to_string(), ids[1].to_string()]);
+        let positions = queue.snapshot().positions(0);
+        let positions = positions.iter().map(|p| p.position).collect::<Vec<_>>();
+        assert_eq!(positions, vec![0, 0, 1]);
 
+        // The third one starts once one of the others finishes.
+        let mut finished = 0;
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Response(_) => finished += 1,
+                BackendOutboundMsg::Start((req, _)) => {
+                    assert_eq!(req.id, ids[2].to_string());
+                    assert!(finished >= 1);
+                    break;
+                }
+                _ => {}
+            }
+        }
         Ok(())
     }
 
@@ -186,6 +1123,7 @@ mod tests {
             id: id.clone(),
             prompt: "fail at 2".to_string(),
             secs: 4,
+            user: None,
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -210,6 +1148,7 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 4,
+            user: None,
         }))?;
 
         tokio::time::sleep(Duration::from_millis(50)).await;
@@ -224,6 +1163,7 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 1,
+            user: None,
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -231,4 +1171,186 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn inpaints_a_stretch_of_a_render() -> anyhow::Result<()> {
+        struct Level;
+
+        impl JobProcessor for Level {
+            fn process(
+                &self,
+                _prompt: &str,
+                secs: usize,
+                _on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+            ) -> ort::Result<Vec<f32>> {
+                Ok(vec![0.25; secs * SAMPLING_RATE])
+            }
+        }
+
+        let backend = AudioGenerationBackend::new(Level);
+        let (tx, rx) = backend.run();
+
+        let id = Uuid::new_v4().to_string();
+        let inpainting = Inpainting {
+            prompt: "drums".to_string(),
+            audio: vec![0.0; 4 * SAMPLING_RATE],
+            region: InpaintRegion {
+                start: SAMPLING_RATE,
+                end: 2 * SAMPLING_RATE,
+                crossfade: SAMPLING_RATE / 5,
+            },
+        };
+        let req = AudioGenerationRequest {
+            id: id.clone(),
+            prompt: "lofi".to_string(),
+            secs: 4,
+            user: None,
+        };
+        tx.send(BackendInboundMsg::Inpaint((req, inpainting)))?;
+
+        let BackendOutboundMsg::Start((_, origin)) = rx.recv()? else {
+            panic!("msg was not Start");
+        };
+        assert_eq!(origin.segment_prompts, vec!["drums".to_string()]);
+        let (_, chunk) = rx.recv()?.unwrap_chunk();
+        let (_, audio) = rx.recv()?.unwrap_response();
+        assert_eq!(chunk, audio);
+        assert_eq!(audio.len(), 4 * SAMPLING_RATE);
+        // Nothing around the stretch to match the level of, so the replacement is as is.
+        assert_eq!(audio[SAMPLING_RATE / 2], 0.0);
+        assert_eq!(audio[3 * SAMPLING_RATE / 2], 0.25);
+        assert_eq!(audio[5 * SAMPLING_RATE / 2], 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn interrupts_and_resumes_extended_jobs_between_segments() -> anyhow::Result<()> {
+        // One sample per second, so that each 28 second segment takes 280ms.
+        let base = DummyJobProcessor::new(Duration::from_millis(10));
+        let config = ExtendedGenerationConfig {
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+        let processor = Arc::new(ExtendedJobProcessor::new(Arc::new(base), config, 1).unwrap());
+        let backend = AudioGenerationBackend::new(processor.clone());
+
+        let (tx, rx) = backend.run();
+
+        let running = Uuid::new_v4().to_string();
+        let queued = Uuid::new_v4().to_string();
+        for id in [&running, &queued] {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 60,
+                user: None,
+            }))?;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        tx.send(BackendInboundMsg::Shutdown)?;
+
+        assert_eq!(rx.recv()?.unwrap_start().id, running);
+        let mut msgs = rx.iter().filter(|msg| {
+            !matches!(
+                msg,
+                BackendOutboundMsg::Progress(_)
+                    | BackendOutboundMsg::Chunk(_)
+                    | BackendOutboundMsg::Segment(_)
+                    | BackendOutboundMsg::Marker(_)
+            )
+        });
+        let (req, checkpoint) = match msgs.next() {
+            Some(BackendOutboundMsg::Interrupted(p)) => p,
+            msg => panic!("msg was not Interrupted, it was {msg:?}"),
+        };
+        assert_eq!(req.id, running);
+        assert_eq!(checkpoint.segments, 1);
+        assert_eq!(checkpoint.audio.len(), 28);
+        match msgs.next() {
+            Some(BackendOutboundMsg::Pending((req, _))) => assert_eq!(req.id, queued),
+            msg => panic!("msg was not Pending, it was {msg:?}"),
+        }
+        // The backend stops after shutting down.
+        assert!(msgs.next().is_none());
+
+        // Resuming it only generates the remaining segments.
+        let (tx, rx) = AudioGenerationBackend::new(processor).run();
+        tx.send(BackendInboundMsg::Resume((req, checkpoint.clone())))?;
+        assert_eq!(rx.recv()?.unwrap_start().id, running);
+        let mut msgs = rx.iter().filter(|msg| {
+            !matches!(
+                msg,
+                BackendOutboundMsg::Progress(_)
+                    | BackendOutboundMsg::Chunk(_)
+                    | BackendOutboundMsg::Segment(_)
+                    | BackendOutboundMsg::Marker(_)
+            )
+        });
+        for segments in [2, 3] {
+            match msgs.next() {
+                Some(BackendOutboundMsg::Checkpoint((_, n))) => assert_eq!(n, segments),
+                msg => panic!("msg was not Checkpoint, it was {msg:?}"),
+            }
+        }
+        let audio = msgs.next().unwrap().unwrap_response().1;
+        assert_eq!(audio.len(), 60);
+        // All but the end the next segment crossfaded with.
+        assert!(audio
+            .iter()
+            .zip(&checkpoint.audio[..26])
+            .all(|(a, b)| a == b));
+
+        Ok(())
+    }
+
+    #[test]
+    fn holds_back_auditioned_segments_until_they_are_accepted() -> anyhow::Result<()> {
+        let config = ExtendedGenerationConfig {
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+        let base = Arc::new(DummyJobProcessor::default());
+        let processor = ExtendedJobProcessor::new(base, config, 1).unwrap();
+        let (tx, rx) = AudioGenerationBackend::new(processor).run();
+
+        let id = Uuid::new_v4().to_string();
+        let req = AudioGenerationRequest {
+            id: id.clone(),
+            prompt: "".to_string(),
+            secs: 50,
+            user: None,
+        };
+        tx.send(BackendInboundMsg::Audition((req, None)))?;
+
+        let mut auditions = vec![];
+        let audio = loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Audition((job_id, segment, _)) => {
+                    assert_eq!(job_id, id);
+                    // The first take of the second segment is rejected.
+                    let verdict = match auditions.iter().filter(|s| **s == 1).count() {
+                        0 if segment == 1 => AuditionVerdict::Reject,
+                        _ => AuditionVerdict::Accept,
+                    };
+                    auditions.push(segment);
+                    tx.send(BackendInboundMsg::Verdict((id.clone(), segment, verdict)))?;
+                }
+                BackendOutboundMsg::Chunk((_, chunk)) => {
+                    // Nothing is released before it was accepted.
+                    assert!(!auditions.is_empty());
+                    assert!(!chunk.is_empty());
+                }
+                BackendOutboundMsg::Response((_, audio)) => break audio,
+                BackendOutboundMsg::Failure((_, err)) => panic!("{err}"),
+                _ => {}
+            }
+        };
+        assert_eq!(auditions, vec![0, 1, 1, 2]);
+        assert_eq!(audio.len(), 50);
+        Ok(())
+    }
 }
@@ -1,12 +1,29 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use tracing::info;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+use crate::audio::daw_project::reaper_project;
+use crate::audio::fingerprint::Fingerprinter;
+use crate::audio::markers::{cue_chunks, cue_sheet, Marker};
+use crate::audio::spill_buffer::SpillBuffer;
+use crate::audio::watermark::{WatermarkPayload, Watermarker};
+use crate::audio::wav::wav_header_with_chunks;
 use crate::audio::AudioManager;
 use crate::backend::audio_generation_backend::BackendOutboundMsg;
+use crate::backend::live_renders::LiveRenders;
 use crate::backend::music_gpt_chat::ChatEntry;
 use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::provenance::Provenance;
+use crate::backend::render_manifest::{
+    RenderCheckpoint, RenderManifest, RenderRecipe, RenderSettings, RenderStatus,
+};
+use crate::backend::usage::{today, UsageTotals, UserUsage};
+use crate::cli::SAMPLING_RATE;
 use crate::storage::Storage;
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -31,6 +48,13 @@ pub struct AudioGenerationError {
     pub error: String,
 }
 
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AudioGenerationWarning {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub warning: String,
+}
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct AudioGenerationResult {
     pub id: Uuid,
@@ -38,30 +62,77 @@ pub struct AudioGenerationResult {
     pub relpath: String,
 }
 
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AudioGenerationAudition {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub segment: usize,
+    pub relpath: String,
+}
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub enum GenerationMessage {
     Start(AudioGenerationStart),
     Progress(AudioGenerationProgress),
     Error(AudioGenerationError),
     Result(AudioGenerationResult),
+    /// Sent before a job starts, for each problem that will not prevent it from running,
+    /// and before its result, for a result that is likely not what was wanted.
+    Warning(AudioGenerationWarning),
+    /// Sent for each segment of an auditioned job, which waits for a verdict on it.
+    Audition(AudioGenerationAudition),
 }
 
+/// Persists and broadcasts the messages from the backend. The returned task finishes once
+/// the backend stops and everything it sent was saved. The manifest of each render records
+/// the `settings` it was produced with, which are the latest ones when it starts.
 pub fn audio_generation_fanout<S: Storage + 'static>(
     ai_rx: std::sync::mpsc::Receiver<BackendOutboundMsg>,
     storage: S,
-) -> tokio::sync::broadcast::Sender<GenerationMessage> {
+    live_renders: LiveRenders,
+    settings: tokio::sync::watch::Receiver<RenderSettings>,
+) -> (
+    tokio::sync::broadcast::Sender<GenerationMessage>,
+    tokio::task::JoinHandle<()>,
+) {
     let (ai_broadcast_tx, _) = tokio::sync::broadcast::channel(1000); // Arbitrary number.
 
     let mut ai_rx = std_to_tokio_receiver(ai_rx);
     let ai_broadcast_tx_clone = ai_broadcast_tx.clone();
     let audio_manager = AudioManager::default();
-    tokio::spawn(async move {
+    let handle = tokio::spawn(async move {
+        // When each running render started, for accounting its processing time.
+        let mut started = HashMap::new();
         while let Some(msg) = ai_rx.recv().await {
+            let settings = settings.borrow().clone();
             let outbound_msg = match msg {
-                BackendOutboundMsg::Start(msg) => {
+                BackendOutboundMsg::Start((msg, origin)) => {
                     let IdPair(chat_id, id) = msg.id.into();
-                    let entry = ChatEntry::new_user(chat_id, id, msg.prompt.clone());
-                    let _ = entry.save(&storage).await;
+                    // Renders that are resumed already have their prompt in the chat.
+                    if let Ok(Some(mut manifest)) = RenderManifest::load(&storage, id).await {
+                        manifest.status = RenderStatus::Running;
+                        manifest.error = None;
+                        let _ = manifest.save(&storage).await;
+                    } else {
+                        let entry = ChatEntry::new_user(chat_id, id, msg.prompt.clone());
+                        let _ = entry.save(&storage).await;
+                        let recipe = RenderRecipe {
+                            segment_seeds: origin.segment_seeds,
+                            ..settings.recipe(origin.model.as_ref(), origin.segment_prompts)
+                        };
+                        let mut manifest = RenderManifest::new(
+                            id,
+                            chat_id,
+                            msg.prompt.clone(),
+                            msg.secs,
+                            origin.model,
+                        );
+                        manifest.recipe = Some(recipe);
+                        manifest.user = msg.user;
+                        let _ = manifest.save(&storage).await;
+                    }
+                    live_renders.start(id);
+                    started.insert(id, Instant::now());
                     GenerationMessage::Start(AudioGenerationStart {
                         id,
                         chat_id,
@@ -69,39 +140,92 @@ pub fn audio_generation_fanout<S: Storage + 'static>(
                         secs: msg.secs,
                     })
                 }
-                BackendOutboundMsg::Response((id, queue)) => {
-                    info!("Audio generated successfully");
+                BackendOutboundMsg::Response((id, audio)) => {
+                    info!(job_id = %id, "Audio generated successfully");
                     let IdPair(chat_id, id) = id.into();
-                    let relpath = format!("audios/{}.wav", id);
-                    let save_audio = || async {
-                        let bytes = audio_manager.to_wav(queue)?;
-                        storage.write(&relpath, bytes).await?;
-                        Ok::<(), anyhow::Error>(())
+                    live_renders.finish(id);
+                    let usage = UsageTotals {
+                        renders: 1,
+                        secs: audio.len() / SAMPLING_RATE,
+                        gpu_secs: elapsed_secs(started.remove(&id)),
                     };
+                    record_usage(&storage, id, usage).await;
+                    let relpath = format!("audios/{}.wav", id);
                     // If audio failed to be saved, do not count as a success.
-                    if let Err(err) = save_audio().await {
-                        let entry = ChatEntry::new_ai_err(chat_id, id, err.to_string());
-                        let _ = entry.save(&storage).await;
-                        GenerationMessage::Error(AudioGenerationError {
-                            id,
-                            chat_id,
-                            error: err.to_string(),
-                        })
-                    } else {
-                        let entry = ChatEntry::new_ai_success(chat_id, id, relpath.clone());
-                        let _ = entry.save(&storage).await;
-                        GenerationMessage::Result(AudioGenerationResult {
-                            id,
-                            chat_id,
-                            relpath,
-                        })
+                    let watermark = settings.watermark.then_some(WatermarkPayload {
+                        ai_generated: true,
+                        job_id: Some(id.as_u128()),
+                    });
+                    let manifest = RenderManifest::load(&storage, id).await.ok().flatten();
+                    let provenance = manifest.as_ref().map(|manifest| {
+                        Provenance::new(
+                            manifest.model.as_ref(),
+                            &manifest.prompt,
+                            manifest.created_at,
+                            settings.license.clone(),
+                        )
+                    });
+                    let markers = manifest
+                        .as_ref()
+                        .map(|manifest| manifest.section_markers())
+                        .unwrap_or_default();
+                    let tracks = manifest
+                        .map(|manifest| manifest.daw_tracks(audio.len()))
+                        .unwrap_or_default();
+                    let saved =
+                        write_wav(&storage, &relpath, &audio, watermark, provenance, &markers);
+                    let fingerprint = match saved.await {
+                        Ok(fingerprint) => fingerprint,
+                        Err(err) => {
+                            let entry = ChatEntry::new_ai_err(chat_id, id, err.to_string());
+                            let _ = entry.save(&storage).await;
+                            let error = err.to_string();
+                            let _ = RenderManifest::finish(&storage, id, Some(error.clone())).await;
+                            let _ = ai_broadcast_tx.send(GenerationMessage::Error(
+                                AudioGenerationError { id, chat_id, error },
+                            ));
+                            continue;
+                        }
+                    };
+                    // Next to the segments, so the project finds their files.
+                    if !tracks.is_empty() {
+                        let project = reaper_project(&tracks, SAMPLING_RATE);
+                        let saved = storage.write(&format!("audios/{id}.rpp"), project).await;
+                        if let Err(err) = saved {
+                            warn!(job_id = %id, "Could not save the DAW project: {err}");
+                        }
+                    }
+                    let entry = ChatEntry::new_ai_success(chat_id, id, relpath.clone());
+                    let _ = entry.save(&storage).await;
+                    let _ = RenderManifest::finish(&storage, id, None).await;
+                    if let Some(warning) = near_duplicate(&storage, id, fingerprint).await {
+                        warn!(job_id = %id, "{warning}");
+                        let _ = ai_broadcast_tx.send(GenerationMessage::Warning(
+                            AudioGenerationWarning {
+                                id,
+                                chat_id,
+                                warning,
+                            },
+                        ));
                     }
+                    GenerationMessage::Result(AudioGenerationResult {
+                        id,
+                        chat_id,
+                        relpath,
+                    })
                 }
                 BackendOutboundMsg::Failure((id, error)) => {
-                    info!("Error generating audio {error}");
+                    info!(job_id = %id, "Error generating audio {error}");
                     let IdPair(chat_id, id) = id.into();
+                    live_renders.finish(id);
+                    let usage = UsageTotals {
+                        gpu_secs: elapsed_secs(started.remove(&id)),
+                        ..Default::default()
+                    };
+                    record_usage(&storage, id, usage).await;
                     let entry = ChatEntry::new_ai_err(chat_id, id, error.clone());
                     let _ = entry.save(&storage).await;
+                    let _ = RenderManifest::finish(&storage, id, Some(error.clone())).await;
                     GenerationMessage::Error(AudioGenerationError { id, chat_id, error })
                 }
                 BackendOutboundMsg::Progress((id, progress)) => {
@@ -112,12 +236,233 @@ pub fn audio_generation_fanout<S: Storage + 'static>(
                         progress,
                     })
                 }
+                // Partial audio is not broadcast to chat clients, it is served by the job routes.
+                BackendOutboundMsg::Chunk((id, chunk)) => {
+                    let IdPair(_, id) = id.into();
+                    live_renders.push(id, &chunk);
+                    continue;
+                }
+                // Saved so that clients can listen to the segment before deciding on it.
+                BackendOutboundMsg::Audition((job_id, segment, audio)) => {
+                    let IdPair(chat_id, id) = job_id.clone().into();
+                    let relpath = format!("auditions/{id}/segment-{segment}.wav");
+                    let save_audition = || async {
+                        let bytes = audio_manager.to_wav(&audio)?;
+                        storage.write(&relpath, bytes).await?;
+                        anyhow::Ok(())
+                    };
+                    if let Err(err) = save_audition().await {
+                        warn!(%job_id, "Could not save the audition of segment {segment}: {err}");
+                        continue;
+                    }
+                    GenerationMessage::Audition(AudioGenerationAudition {
+                        id,
+                        chat_id,
+                        segment,
+                        relpath,
+                    })
+                }
+                BackendOutboundMsg::Retry((id, retry)) => {
+                    let IdPair(_, id) = id.into();
+                    let _ = RenderManifest::record_retry(&storage, id, retry.into()).await;
+                    continue;
+                }
+                BackendOutboundMsg::Normalized((id, gain)) => {
+                    let IdPair(_, id) = id.into();
+                    let _ = RenderManifest::record_gain(&storage, id, gain.into()).await;
+                    continue;
+                }
+                BackendOutboundMsg::Marker((id, marker)) => {
+                    let IdPair(_, id) = id.into();
+                    let _ = RenderManifest::record_marker(&storage, id, marker.into()).await;
+                    continue;
+                }
+                // Saved so that the segment can be edited once the render finishes.
+                BackendOutboundMsg::Segment((job_id, segment, audio)) => {
+                    let IdPair(_, id) = job_id.clone().into();
+                    let save_segment = || async {
+                        let bytes = audio_manager.to_wav(&audio)?;
+                        RenderManifest::record_segment(&storage, id, segment, bytes).await
+                    };
+                    if let Err(err) = save_segment().await {
+                        warn!(%job_id, "Could not save segment {segment}: {err}");
+                    }
+                    continue;
+                }
+                // Saved so that the render can be resumed if the process dies.
+                BackendOutboundMsg::Checkpoint((job_id, segments)) => {
+                    let IdPair(_, id) = job_id.clone().into();
+                    let Some(render) = live_renders.get(id) else {
+                        continue;
+                    };
+                    let save_checkpoint = || async {
+                        let audio = render.snapshot().map_err(|err| anyhow::anyhow!(err))?;
+                        let checkpoint = RenderCheckpoint::new(id, segments, audio.len());
+                        let bytes = audio_manager.to_wav(&audio)?;
+                        storage.write(&checkpoint.relpath, bytes).await?;
+                        RenderManifest::record_checkpoint(&storage, id, checkpoint).await
+                    };
+                    if let Err(err) = save_checkpoint().await {
+                        warn!(%job_id, "Could not save the checkpoint: {err}");
+                    }
+                    continue;
+                }
+                // Shutting down, clients will get the results once the render is resumed.
+                BackendOutboundMsg::Interrupted((msg, checkpoint)) => {
+                    info!(
+                        job_id = %msg.id,
+                        "Interrupted after {} segments", checkpoint.segments
+                    );
+                    let IdPair(_, id) = msg.id.into();
+                    live_renders.finish(id);
+                    let usage = UsageTotals {
+                        gpu_secs: elapsed_secs(started.remove(&id)),
+                        ..Default::default()
+                    };
+                    record_usage(&storage, id, usage).await;
+                    let render_checkpoint =
+                        RenderCheckpoint::new(id, checkpoint.segments, checkpoint.audio.len());
+                    let save_checkpoint = || async {
+                        let bytes = audio_manager.to_wav(&checkpoint.audio)?;
+                        storage.write(&render_checkpoint.relpath, bytes).await?;
+                        RenderManifest::suspend(&storage, id, render_checkpoint.clone()).await
+                    };
+                    if let Err(err) = save_checkpoint().await {
+                        let error = format!("Could not save the render for resuming it: {err}");
+                        let _ = RenderManifest::finish(&storage, id, Some(error)).await;
+                    }
+                    continue;
+                }
+                BackendOutboundMsg::Pending((msg, origin)) => {
+                    let IdPair(chat_id, id) = msg.id.into();
+                    let entry = ChatEntry::new_user(chat_id, id, msg.prompt.clone());
+                    let _ = entry.save(&storage).await;
+                    let recipe = RenderRecipe {
+                        segment_seeds: origin.segment_seeds,
+                        ..settings.recipe(origin.model.as_ref(), origin.segment_prompts)
+                    };
+                    let mut manifest =
+                        RenderManifest::new(id, chat_id, msg.prompt, msg.secs, origin.model);
+                    manifest.status = RenderStatus::Pending;
+                    manifest.recipe = Some(recipe);
+                    manifest.user = msg.user;
+                    let _ = manifest.save(&storage).await;
+                    continue;
+                }
+                BackendOutboundMsg::Dropped((msg, origin, error)) => {
+                    info!(job_id = %msg.id, "Removed from the queue: {error}");
+                    let IdPair(chat_id, id) = msg.id.into();
+                    // Renders resumed after a restart already have their prompt in the chat.
+                    if !matches!(RenderManifest::load(&storage, id).await, Ok(Some(_))) {
+                        let entry = ChatEntry::new_user(chat_id, id, msg.prompt.clone());
+                        let _ = entry.save(&storage).await;
+                        let recipe = RenderRecipe {
+                            segment_seeds: origin.segment_seeds,
+                            ..settings.recipe(origin.model.as_ref(), origin.segment_prompts)
+                        };
+                        let mut manifest =
+                            RenderManifest::new(id, chat_id, msg.prompt, msg.secs, origin.model);
+                        manifest.recipe = Some(recipe);
+                        manifest.user = msg.user;
+                        let _ = manifest.save(&storage).await;
+                    }
+                    let entry = ChatEntry::new_ai_err(chat_id, id, error.clone());
+                    let _ = entry.save(&storage).await;
+                    let _ = RenderManifest::finish(&storage, id, Some(error.clone())).await;
+                    GenerationMessage::Error(AudioGenerationError { id, chat_id, error })
+                }
             };
             let _ = ai_broadcast_tx.send(outbound_msg);
         }
     });
 
-    ai_broadcast_tx_clone
+    (ai_broadcast_tx_clone, handle)
+}
+
+/// Similarity above which a render is reported as a near-duplicate of an older one.
+const NEAR_DUPLICATE: f32 = 0.9;
+
+/// Saves the fingerprint of a render, and warns if an older one sounds nearly the same,
+/// which happens when a prompt is generated again with the same seed.
+async fn near_duplicate<S: Storage>(
+    storage: &S,
+    id: Uuid,
+    fingerprint: Vec<u32>,
+) -> Option<String> {
+    let similar =
+        RenderManifest::find_similar(storage, id, &fingerprint, NEAR_DUPLICATE, None).await;
+    if let Err(err) = RenderManifest::record_fingerprint(storage, id, fingerprint).await {
+        warn!(job_id = %id, "Could not save the fingerprint: {err}");
+    }
+    let similar = similar.ok()?.into_iter().next()?;
+    Some(format!(
+        "Sounds nearly the same as render {} ({:.0}% similar)",
+        similar.id,
+        similar.similarity * 100.0
+    ))
+}
+
+/// Samples read at once from the audio of a job when saving it.
+const WAV_CHUNK: usize = 1 << 16;
+
+/// Saves `audio` as a WAV file with `watermark` and `provenance` embedded, if any,
+/// without reading all of it into memory at once. Returns the fingerprint of the audio.
+async fn write_wav<S: Storage>(
+    storage: &S,
+    relpath: &str,
+    audio: &SpillBuffer,
+    watermark: Option<WatermarkPayload>,
+    provenance: Option<Provenance>,
+    markers: &[Marker],
+) -> anyhow::Result<Vec<u32>> {
+    let mut metadata = provenance.map(|p| p.wav_chunks()).unwrap_or_default();
+    // Besides the cue points, the markers go in a CUE sheet next to the file, for the
+    // players that show chapters but do not read them.
+    if !markers.is_empty() {
+        metadata.extend(cue_chunks(markers));
+        let file_name = relpath.rsplit('/').next().unwrap_or(relpath);
+        let sheet = cue_sheet(file_name, markers, SAMPLING_RATE);
+        storage
+            .write(&relpath.replace(".wav", ".cue"), sheet)
+            .await?;
+    }
+    let header = wav_header_with_chunks(audio.len(), SAMPLING_RATE as u32, metadata.len())?;
+    let mut file = storage.create(relpath).await?;
+    file.write_all(&header).await?;
+    let mut watermarker = watermark.map(Watermarker::new);
+    let mut fingerprinter = Fingerprinter::new(SAMPLING_RATE);
+    for chunk in audio.chunks(WAV_CHUNK) {
+        let mut chunk = chunk.map_err(|err| anyhow::anyhow!(err))?;
+        fingerprinter.push(&chunk);
+        if let Some(watermarker) = &mut watermarker {
+            watermarker.apply(&mut chunk);
+        }
+        let bytes = chunk
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        file.write_all(&bytes).await?;
+    }
+    file.write_all(&metadata).await?;
+    file.flush().await?;
+    Ok(fingerprinter.finish())
+}
+
+fn elapsed_secs(started: Option<Instant>) -> f64 {
+    started.map_or(0.0, |started| started.elapsed().as_secs_f64())
+}
+
+/// Accounts `usage` to the user who requested render `id`, if known.
+async fn record_usage<S: Storage>(storage: &S, id: Uuid, usage: UsageTotals) {
+    let Ok(Some(RenderManifest {
+        user: Some(user), ..
+    })) = RenderManifest::load(storage, id).await
+    else {
+        return;
+    };
+    if let Err(err) = UserUsage::record(storage, &user, today(), usage).await {
+        warn!(render = %id, "Could not record the usage of {user}: {err}");
+    }
 }
 
 fn std_to_tokio_receiver<T: Send + 'static>(
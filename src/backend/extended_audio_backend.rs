@@ -1,21 +1,88 @@
-/// Integration between extended audio generation and MusicGPT backend
+//! Integration between extended audio generation and MusicGPT backend
 
-use std::collections::VecDeque;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
 
+use crate::audio::audio_sink::{AudioSink, MemorySink};
 use crate::audio::extended_generation::{
     ExtendedAudioGenerator, ExtendedGenerationConfig, SegmentGenerator,
 };
-use crate::backend::audio_generation_backend::JobProcessor;
+use crate::audio::prompt_hints::PromptHints;
+use crate::backend::audio_generation_backend::{JobCheckpoint, JobProcessor, SegmentEdit};
+use crate::backend::job_limits::JobEstimate;
+use crate::backend::model_registry::ModelVersion;
+use crate::backend::render_manifest::PostChain;
+use crate::cli::INPUT_IDS_BATCH_PER_SECOND;
+use crate::musicgen::Continuation;
 
-/// Adapter that wraps a JobProcessor to work as a SegmentGenerator
+/// Adapter that wraps a JobProcessor to work as a SegmentGenerator. Each segment is a
+/// fresh generation from its prompt, unless segments are continued.
 pub struct MusicGPTSegmentGenerator {
     processor: Arc<dyn JobProcessor>,
+    continuing: Option<Continuing>,
+}
+
+/// How segments continue the previous one.
+struct Continuing {
+    /// Seconds at the beginning of each segment that repeat the end of the previous one.
+    context: f32,
+    /// Frames of each segment that are kept, the rest of it is cut.
+    kept_frames: usize,
+    /// Where the last segment stopped, along with its index and the call that generated
+    /// it, so that a segment abandoned by the watchdog is never continued.
+    last: Mutex<(usize, Option<(usize, Continuation)>)>,
 }
 
 impl MusicGPTSegmentGenerator {
-    pub fn new(processor: Arc<dyn JobProcessor>) -> Self {
-        Self { processor }
+    /// Generates each segment of `config` as a continuation of the previous one, if it
+    /// continues segments.
+    pub fn new(processor: Arc<dyn JobProcessor>, config: &ExtendedGenerationConfig) -> Self {
+        let continuing = config.continue_segments.then(|| Continuing {
+            context: config.crossfade_duration,
+            kept_frames: (config.segment_duration * INPUT_IDS_BATCH_PER_SECOND as f32) as usize,
+            last: Mutex::new((0, None)),
+        });
+        Self {
+            processor,
+            continuing,
+        }
+    }
+
+    fn continue_segment(
+        &self,
+        continuing: &Continuing,
+        prompt: &str,
+        duration: usize,
+        segment_index: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        // Retries and resumed renders start over, their previous segment is gone.
+        let (call, from) = {
+            let mut last = continuing.last.lock().unwrap();
+            last.0 += 1;
+            let from = match last.1.take() {
+                Some((index, from)) if index + 1 == segment_index => Some(from),
+                _ => None,
+            };
+            (last.0, from)
+        };
+        let continuation = self.processor.process_continuing(
+            prompt,
+            duration,
+            from,
+            continuing.context,
+            on_progress,
+            sink,
+        )?;
+        if let Some(mut continuation) = continuation {
+            continuation.truncate(continuing.kept_frames)?;
+            let mut last = continuing.last.lock().unwrap();
+            if last.0 == call {
+                last.1 = Some((segment_index, continuation));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -25,30 +92,102 @@ impl SegmentGenerator for MusicGPTSegmentGenerator {
         prompt: &str,
         duration: usize,
         segment_index: usize,
-        on_progress: Box<dyn Fn(f32) + Send + Sync>,
-    ) -> Result<VecDeque<f32>, String> {
+        on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), String> {
         // Cap duration at 30 seconds (model limitation)
         let safe_duration = duration.min(30);
-        
-        let result = self.processor.process(
-            prompt,
-            safe_duration,
-            Box::new({
-                move |elapsed, total| {
-                    on_progress(elapsed / total);
-                    false // Don't abort
-                }
-            }),
-        );
+        let on_progress = Box::new(move |elapsed, total| on_progress(elapsed / total));
+
+        let result = match &self.continuing {
+            Some(continuing) => self.continue_segment(
+                continuing,
+                prompt,
+                safe_duration,
+                segment_index,
+                on_progress,
+                sink,
+            ),
+            None => self
+                .processor
+                .process_streaming(prompt, safe_duration, on_progress, sink),
+        };
 
         result.map_err(|e| format!("Segment {} generation failed: {}", segment_index, e))
     }
+
+    fn recover(&self) -> Result<(), String> {
+        self.processor.recover().map_err(|e| e.to_string())
+    }
+
+    fn terminate(&self, worker: ThreadId) {
+        self.processor.terminate(worker)
+    }
 }
 
+/// Generates only the edited segment of a render, pushing the audio the other segments
+/// were generated with instead of generating them again.
+struct EditingSegmentGenerator {
+    processor: Arc<dyn JobProcessor>,
+    edit: SegmentEdit,
+}
+
+impl SegmentGenerator for EditingSegmentGenerator {
+    fn generate_segment(
+        &self,
+        prompt: &str,
+        duration: usize,
+        segment_index: usize,
+        on_progress: Box<dyn Fn(f32) -> bool + Send + Sync>,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), String> {
+        if segment_index != self.edit.segment {
+            let Some(audio) = self.edit.audio.get(segment_index) else {
+                return Err(format!("The audio of segment {segment_index} is missing"));
+            };
+            sink.push(audio)?;
+            on_progress(1.0);
+            return Ok(());
+        }
+
+        let prompt = self
+            .edit
+            .prompts
+            .get(segment_index)
+            .map_or(prompt, String::as_str);
+        let safe_duration = duration.min(30);
+        let on_progress = Box::new(move |elapsed, total| on_progress(elapsed / total));
+        let result = match self.edit.seeds.get(segment_index).copied().flatten() {
+            Some(seed) => {
+                self.processor
+                    .process_seeded(prompt, safe_duration, seed, on_progress, sink)
+            }
+            None => self
+                .processor
+                .process_streaming(prompt, safe_duration, on_progress, sink),
+        };
+        result.map_err(|e| format!("Segment {} generation failed: {}", segment_index, e))
+    }
+
+    fn recover(&self) -> Result<(), String> {
+        self.processor.recover().map_err(|e| e.to_string())
+    }
+
+    fn terminate(&self, worker: ThreadId) {
+        self.processor.terminate(worker)
+    }
+}
+
+/// Copies of a job's audio kept in memory at the same time: the one being generated, the
+/// one streamed to clients while it runs, and its WAV encoding once it finishes.
+const AUDIO_COPIES: u64 = 3;
+const WAV_HEADER_BYTES: u64 = 68;
+
 /// Extended job processor that generates longer audio by stitching segments
 pub struct ExtendedJobProcessor {
     base_processor: Arc<dyn JobProcessor>,
-    generator: ExtendedAudioGenerator,
+    config: ExtendedGenerationConfig,
+    sample_rate: usize,
 }
 
 impl ExtendedJobProcessor {
@@ -57,31 +196,121 @@ impl ExtendedJobProcessor {
         config: ExtendedGenerationConfig,
         sample_rate: usize,
     ) -> Result<Self, String> {
-        let generator = ExtendedAudioGenerator::new(config, sample_rate)?;
+        config.validate()?;
         Ok(Self {
             base_processor,
-            generator,
+            config,
+            sample_rate,
         })
     }
 
-    /// Generate extended audio using the configured strategy
+    /// Generate `secs` seconds of extended audio using the configured strategy
     pub fn generate_extended(
         &self,
         prompt: &str,
+        secs: usize,
         on_progress: Box<dyn Fn(f32, f32) -> bool + Send + Sync + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
-        let segment_gen = Arc::new(MusicGPTSegmentGenerator::new(self.base_processor.clone()));
+    ) -> ort::Result<Vec<f32>> {
+        let mut sink = MemorySink::new();
+        self.generate_extended_into(prompt, secs, on_progress, &mut sink)?;
+        Ok(sink.into_inner())
+    }
+
+    /// Same as [ExtendedJobProcessor::generate_extended], but pushes the audio into `sink`
+    /// as each segment gets generated.
+    pub fn generate_extended_into(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Send + Sync + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let empty = JobCheckpoint {
+            segments: 0,
+            audio: vec![],
+        };
+        self.resume_extended_into(prompt, secs, &empty, on_progress, sink)
+    }
+
+    /// Same as [ExtendedJobProcessor::generate_extended_into], but continues from the
+    /// segments in `checkpoint`.
+    pub fn resume_extended_into(
+        &self,
+        prompt: &str,
+        secs: usize,
+        checkpoint: &JobCheckpoint,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Send + Sync + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let segment_gen = Arc::new(MusicGPTSegmentGenerator::new(
+            self.base_processor.clone(),
+            &self.job_config(prompt, secs as f32),
+        ));
+        self.stitch_extended_into(
+            segment_gen,
+            prompt,
+            secs as f32,
+            checkpoint,
+            on_progress,
+            sink,
+        )
+    }
+
+    /// Same as [ExtendedJobProcessor::generate_extended_into], but only generates the
+    /// segment in `edit`, stitching it with the audio of the others.
+    pub fn edit_extended_into(
+        &self,
+        prompt: &str,
+        secs: usize,
+        edit: &SegmentEdit,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Send + Sync + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let segment_gen = Arc::new(EditingSegmentGenerator {
+            processor: self.base_processor.clone(),
+            edit: edit.clone(),
+        });
+        let empty = JobCheckpoint {
+            segments: 0,
+            audio: vec![],
+        };
+        self.stitch_extended_into(segment_gen, prompt, secs as f32, &empty, on_progress, sink)
+    }
+
+    /// The configuration of a job of `secs` seconds, with the hints of its prompt.
+    fn job_config(&self, prompt: &str, secs: f32) -> ExtendedGenerationConfig {
+        let mut config = ExtendedGenerationConfig {
+            target_duration: secs,
+            ..self.config.clone()
+        };
+        PromptHints::parse(prompt).apply(&mut config);
+        config
+    }
+
+    fn stitch_extended_into<G: SegmentGenerator + 'static>(
+        &self,
+        segment_gen: Arc<G>,
+        prompt: &str,
+        secs: f32,
+        checkpoint: &JobCheckpoint,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Send + Sync + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let generator =
+            ExtendedAudioGenerator::new(self.job_config(prompt, secs), self.sample_rate)
+                .map_err(ort::Error::new)?;
         let on_progress = Arc::new(on_progress);
-        
-        self.generator
-            .generate(
+
+        generator
+            .resume(
                 segment_gen,
                 prompt,
-                Arc::new(move |progress| {
-                    (*on_progress)(progress, 1.0);
-                }),
+                checkpoint.segments,
+                &checkpoint.audio,
+                Arc::new(move |progress| (*on_progress)(progress, 1.0)),
+                sink,
             )
-            .map_err(|e| ort::Error::new(e))
+            .map_err(|err| ort::Error::new(err.to_string()))
     }
 }
 
@@ -91,20 +320,190 @@ impl JobProcessor for ExtendedJobProcessor {
         prompt: &str,
         secs: usize,
         on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
+    ) -> ort::Result<Vec<f32>> {
         // If requested duration is <= 30 seconds, use base processor
         if secs <= 30 {
             return self.base_processor.process(prompt, secs, on_progress);
         }
 
         // Otherwise, use extended generation
-        self.generate_extended(prompt, on_progress)
+        self.generate_extended(prompt, secs, on_progress)
+    }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        if secs <= 30 {
+            return self
+                .base_processor
+                .process_streaming(prompt, secs, on_progress, sink);
+        }
+        self.generate_extended_into(prompt, secs, on_progress, sink)
+    }
+
+    /// Extended renders are stitched up to the exact duration, so that their ending is
+    /// not cut.
+    fn process_exact(
+        &self,
+        prompt: &str,
+        secs: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        if secs <= 30.0 {
+            return self
+                .base_processor
+                .process_exact(prompt, secs, on_progress, sink);
+        }
+        let segment_gen = Arc::new(MusicGPTSegmentGenerator::new(
+            self.base_processor.clone(),
+            &self.job_config(prompt, secs),
+        ));
+        let empty = JobCheckpoint {
+            segments: 0,
+            audio: vec![],
+        };
+        self.stitch_extended_into(segment_gen, prompt, secs, &empty, on_progress, sink)
+    }
+
+    fn resume_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        checkpoint: &JobCheckpoint,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        if secs <= 30 {
+            return self
+                .base_processor
+                .process_streaming(prompt, secs, on_progress, sink);
+        }
+        self.resume_extended_into(prompt, secs, checkpoint, on_progress, sink)
+    }
+
+    fn process_variations(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seeds: &[u64],
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<Vec<f32>>> {
+        if secs <= 30 {
+            return self
+                .base_processor
+                .process_variations(prompt, secs, seeds, on_progress);
+        }
+        Err(ort::Error::new(
+            "Variations can only be generated for up to 30 seconds",
+        ))
+    }
+
+    fn edit_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        edit: &SegmentEdit,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        // Renders of a single segment are generated again as a whole.
+        if secs <= 30 {
+            let generator = EditingSegmentGenerator {
+                processor: self.base_processor.clone(),
+                edit: edit.clone(),
+            };
+            return generator
+                .generate_segment(
+                    prompt,
+                    secs,
+                    0,
+                    Box::new(move |progress| on_progress(progress, 1.0)),
+                    sink,
+                )
+                .map_err(ort::Error::new);
+        }
+        self.edit_extended_into(prompt, secs, edit, on_progress, sink)
+    }
+
+    fn model_version(&self) -> Option<ModelVersion> {
+        self.base_processor.model_version()
+    }
+
+    fn segment_prompts(&self, prompt: &str, secs: usize) -> Vec<String> {
+        if secs <= 30 {
+            return self.base_processor.segment_prompts(prompt, secs);
+        }
+        let config = ExtendedGenerationConfig {
+            target_duration: secs as f32,
+            ..self.config.clone()
+        };
+        match ExtendedAudioGenerator::new(config, self.sample_rate) {
+            Ok(generator) => generator.segment_prompts(prompt),
+            Err(_) => vec![],
+        }
+    }
+
+    fn estimate(&self, secs: usize) -> Option<JobEstimate> {
+        let (segments, warnings) = match secs {
+            0..=30 => (1, vec![]),
+            _ => {
+                let config = ExtendedGenerationConfig {
+                    target_duration: secs as f32,
+                    ..self.config.clone()
+                };
+                let warnings = match config.validate() {
+                    Ok(warnings) => warnings.into_iter().map(|d| d.message).collect(),
+                    Err(err) => vec![err],
+                };
+                (config.num_segments(), warnings)
+            }
+        };
+        let audio_bytes = (secs * self.sample_rate * size_of::<f32>()) as u64;
+        // Renders are saved as WAV files, and checkpoints are too, until they complete.
+        let wav_bytes = audio_bytes + WAV_HEADER_BYTES;
+        let checkpoint_bytes = if segments > 1 { wav_bytes } else { 0 };
+        Some(JobEstimate {
+            segments,
+            // Segments are generated one after the other.
+            concurrent_segments: 1,
+            memory_bytes: audio_bytes * AUDIO_COPIES,
+            disk_bytes: wav_bytes + checkpoint_bytes,
+            warnings,
+        })
+    }
+
+    fn recover(&self) -> ort::Result<()> {
+        self.base_processor.recover()
+    }
+
+    fn terminate(&self, worker: ThreadId) {
+        self.base_processor.terminate(worker)
+    }
+
+    fn max_concurrent_jobs(&self) -> usize {
+        self.base_processor.max_concurrent_jobs()
+    }
+
+    fn with_post_chain(&self, post: &PostChain) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        let processor = ExtendedJobProcessor::new(
+            self.base_processor.clone(),
+            post.apply(self.config.clone()),
+            self.sample_rate,
+        )
+        .map_err(|err| anyhow::anyhow!(err))?;
+        Ok(Arc::new(processor))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::time::Duration;
 
     struct DummyProcessor;
@@ -115,7 +514,7 @@ mod tests {
             _prompt: &str,
             secs: usize,
             on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-        ) -> ort::Result<VecDeque<f32>> {
+        ) -> ort::Result<Vec<f32>> {
             let samples = secs * 1000; // Simulate 1000 samples/sec
             for i in 0..10 {
                 let progress = (i + 1) as f32 / 10.0;
@@ -124,17 +523,18 @@ mod tests {
                 }
                 std::thread::sleep(Duration::from_millis(10));
             }
-            Ok(VecDeque::from(vec![0.5; samples]))
+            Ok(vec![0.5; samples])
         }
     }
 
     #[test]
     fn test_short_duration_uses_base_processor() {
         let config = ExtendedGenerationConfig {
-            target_duration: 120,
-            segment_duration: 28,
-            overlap_duration: 4,
+            target_duration: 120.0,
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
             crossfade_duration: 2.0,
+            ..Default::default()
         };
 
         let extended = ExtendedJobProcessor::new(Arc::new(DummyProcessor), config, 1000).unwrap();
@@ -147,19 +547,166 @@ mod tests {
     #[test]
     fn test_long_duration_uses_extended_generation() {
         let config = ExtendedGenerationConfig {
-            target_duration: 60,
-            segment_duration: 28,
-            overlap_duration: 4,
+            target_duration: 60.0,
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
             crossfade_duration: 2.0,
+            ..Default::default()
         };
 
         let extended = ExtendedJobProcessor::new(Arc::new(DummyProcessor), config, 1000).unwrap();
 
         let result = extended.process("test", 60, Box::new(|_, _| false));
         assert!(result.is_ok());
-        
+
         let audio = result.unwrap();
         // Should generate approximately 60 seconds worth
         assert!(audio.len() >= 55_000 && audio.len() <= 65_000);
     }
+
+    #[test]
+    fn test_aborts_extended_generation() {
+        let config = ExtendedGenerationConfig::default();
+        let extended = ExtendedJobProcessor::new(Arc::new(DummyProcessor), config, 1000).unwrap();
+
+        let result = extended.process("test", 60, Box::new(|progress, _| progress > 0.2));
+        assert_eq!(result.unwrap_err().to_string(), "Aborted");
+    }
+
+    #[test]
+    fn test_estimates_jobs() {
+        let config = ExtendedGenerationConfig {
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            ..Default::default()
+        };
+        let extended = ExtendedJobProcessor::new(Arc::new(DummyProcessor), config, 1000).unwrap();
+
+        let estimate = extended.estimate(20).unwrap();
+        assert_eq!(estimate.segments, 1);
+        let estimate = extended.estimate(240).unwrap();
+        assert_eq!(estimate.segments, 10);
+        assert_eq!(estimate.concurrent_segments, 1);
+        assert_eq!(estimate.memory_bytes, 240 * 1000 * 4 * 3);
+        assert_eq!(estimate.disk_bytes, (240 * 1000 * 4 + 68) * 2);
+        assert!(estimate.warnings.is_empty());
+        let estimate = extended.estimate(250).unwrap();
+        assert_eq!(estimate.warnings.len(), 1);
+    }
+
+    /// Generates each prompt as a level of its own, counting the calls.
+    #[derive(Default)]
+    struct PromptLevelProcessor {
+        calls: AtomicUsize,
+    }
+
+    impl JobProcessor for PromptLevelProcessor {
+        fn process(
+            &self,
+            prompt: &str,
+            secs: usize,
+            _on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        ) -> ort::Result<Vec<f32>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let level = prompt.len() as f32 / 100.0;
+            Ok(vec![level; secs * 1000])
+        }
+    }
+
+    /// Keeps the audio and the segments it was stitched from.
+    #[derive(Default)]
+    struct SegmentRecorder {
+        audio: Vec<f32>,
+        segments: Vec<Vec<f32>>,
+    }
+
+    impl AudioSink for SegmentRecorder {
+        fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+            self.audio.extend(chunk);
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn generated(&mut self, _segment: usize, audio: &[f32]) {
+            self.segments.push(audio.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_edits_a_single_segment() {
+        let config = ExtendedGenerationConfig {
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+        let processor = Arc::new(PromptLevelProcessor::default());
+        let extended = ExtendedJobProcessor::new(processor.clone(), config, 1000).unwrap();
+        let mut original = SegmentRecorder::default();
+        extended
+            .process_streaming("test", 60, Box::new(|_, _| false), &mut original)
+            .unwrap();
+        assert_eq!(original.segments.len(), 3);
+
+        let mut prompts = extended.segment_prompts("test", 60);
+        prompts[1] = "a much longer prompt".to_string();
+        let edit = SegmentEdit {
+            segment: 1,
+            prompts,
+            seeds: vec![None; 3],
+            audio: original.segments.clone(),
+        };
+        let calls = processor.calls.load(Ordering::SeqCst);
+        let mut edited = SegmentRecorder::default();
+        extended
+            .edit_streaming("test", 60, &edit, Box::new(|_, _| false), &mut edited)
+            .unwrap();
+
+        // Only the edited segment is generated, the others are stitched as they were.
+        assert_eq!(processor.calls.load(Ordering::SeqCst), calls + 1);
+        assert_eq!(edited.audio.len(), original.audio.len());
+        assert_eq!(edited.segments[0], original.segments[0]);
+        assert_eq!(edited.segments[1], vec![0.2; 28_000]);
+        assert_eq!(edited.segments[2], original.segments[2]);
+        assert_eq!(edited.audio[..20_000], original.audio[..20_000]);
+        assert_ne!(edited.audio[30_000], original.audio[30_000]);
+
+        // Without the audio of the other segments, there is nothing to stitch with.
+        let edit = SegmentEdit {
+            audio: vec![],
+            ..edit
+        };
+        let err = extended
+            .edit_streaming("test", 60, &edit, Box::new(|_, _| false), &mut edited)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("The audio of segment 0 is missing"));
+    }
+
+    #[test]
+    fn test_streams_extended_generation() {
+        let config = ExtendedGenerationConfig {
+            target_duration: 60.0,
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..Default::default()
+        };
+        let extended = ExtendedJobProcessor::new(Arc::new(DummyProcessor), config, 1000).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut sink = crate::audio::audio_sink::ChannelSink::new(tx);
+        extended
+            .process_streaming("test", 60, Box::new(|_, _| false), &mut sink)
+            .unwrap();
+
+        // Audio is streamed as segments complete, not all at once at the end.
+        let chunks = rx.iter().map(|chunk| chunk.len()).collect::<Vec<_>>();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.iter().sum::<usize>(), 60_000);
+    }
 }
@@ -0,0 +1,322 @@
+//! A [JobProcessor] that generates the audio of a [MockJobProcessor], but misbehaves on
+//! the calls it is told to, for testing how retries, the watchdog and checkpoints deal
+//! with failing models. Enabled by the `mock-backend` feature.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::audio::audio_sink::{AudioSink, MemorySink};
+use crate::backend::audio_generation_backend::JobProcessor;
+use crate::backend::mock_backend::MockJobProcessor;
+
+/// Misbehavior of a single call to the processor.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Fails once `after_secs` seconds of audio were generated.
+    Fail { after_secs: usize },
+    /// Stops making progress for `duration` once `after_secs` seconds of audio were
+    /// generated, then continues as usual.
+    Stall {
+        after_secs: usize,
+        duration: Duration,
+    },
+    /// Succeeds after generating only `secs` seconds of audio.
+    Short { secs: usize },
+    /// Generates NaN instead of the audio after the first `after_secs` seconds.
+    Nan { after_secs: usize },
+}
+
+/// Counts the calls to [JobProcessor::process] and [JobProcessor::process_streaming],
+/// starting from 0, and injects the fault registered for each of them. Extended renders
+/// call the processor once per attempt at a segment, so unless a segment was retried
+/// before, call K generates segment K.
+#[derive(Debug)]
+pub struct FaultInjectingJobProcessor {
+    inner: MockJobProcessor,
+    faults: Vec<(usize, Fault)>,
+    calls: AtomicUsize,
+}
+
+impl FaultInjectingJobProcessor {
+    pub fn new(inner: MockJobProcessor) -> Self {
+        Self {
+            inner,
+            faults: vec![],
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    /// Injects `fault` into the call number `call`, which otherwise behaves as usual.
+    pub fn with_fault(mut self, call: usize, fault: Fault) -> Self {
+        self.faults.push((call, fault));
+        self
+    }
+
+    /// Calls made so far, including the ones that failed.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+impl JobProcessor for FaultInjectingJobProcessor {
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<f32>> {
+        let mut sink = MemorySink::new();
+        self.process_streaming(prompt, secs, on_progress, &mut sink)?;
+        Ok(sink.into_inner())
+    }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst);
+        let Some((_, fault)) = self.faults.iter().find(|(c, _)| *c == call) else {
+            return self
+                .inner
+                .process_streaming(prompt, secs, on_progress, sink);
+        };
+        let secs = match fault {
+            Fault::Short { secs: short } => secs.min(*short),
+            _ => secs,
+        };
+        let mut sink = FaultySink {
+            inner: sink,
+            fault: fault.clone(),
+            sample_rate: self.inner.sample_rate(),
+            pushed: 0,
+        };
+        self.inner
+            .process_streaming(prompt, secs, on_progress, &mut sink)
+    }
+}
+
+/// Applies a [Fault] to the audio on its way to the sink of the job.
+struct FaultySink<'a> {
+    inner: &'a mut dyn AudioSink,
+    fault: Fault,
+    sample_rate: usize,
+    pushed: usize,
+}
+
+impl AudioSink for FaultySink<'_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        let start = self.pushed;
+        self.pushed += chunk.len();
+        match self.fault {
+            Fault::Fail { after_secs } if self.pushed > after_secs * self.sample_rate => {
+                Err(format!("Injected failure after {after_secs}s"))
+            }
+            Fault::Stall {
+                after_secs,
+                duration,
+            } if (start..self.pushed).contains(&(after_secs * self.sample_rate)) => {
+                std::thread::sleep(duration);
+                self.inner.push(chunk)
+            }
+            Fault::Nan { after_secs } => {
+                let valid = (after_secs * self.sample_rate).saturating_sub(start);
+                let chunk = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(i, sample)| if i < valid { *sample } else { f32::NAN })
+                    .collect::<Vec<_>>();
+                self.inner.push(&chunk)
+            }
+            _ => self.inner.push(chunk),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.inner.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use crate::audio::extended_generation::{ExtendedGenerationConfig, RetryPolicy};
+    use crate::backend::audio_generation_backend::{
+        AudioGenerationBackend, AudioGenerationRequest, BackendInboundMsg, BackendOutboundMsg,
+        JobCheckpoint,
+    };
+    use crate::backend::extended_audio_backend::ExtendedJobProcessor;
+
+    use super::*;
+
+    const SAMPLE_RATE: usize = 100;
+
+    fn processor() -> FaultInjectingJobProcessor {
+        FaultInjectingJobProcessor::new(MockJobProcessor::new(1).with_sample_rate(SAMPLE_RATE))
+    }
+
+    fn config(max_retries: usize) -> ExtendedGenerationConfig {
+        ExtendedGenerationConfig {
+            target_duration: 6.0,
+            segment_duration: 3.0,
+            overlap_duration: 1.0,
+            crossfade_duration: 0.5,
+            retry: RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            watchdog_timeout: None,
+            ..Default::default()
+        }
+    }
+
+    fn render(
+        processor: &Arc<FaultInjectingJobProcessor>,
+        config: ExtendedGenerationConfig,
+    ) -> ort::Result<Vec<f32>> {
+        ExtendedJobProcessor::new(processor.clone(), config, SAMPLE_RATE)
+            .unwrap()
+            .generate_extended("", 6, Box::new(|_, _| false))
+    }
+
+    /// Retried segments are crossfaded again, which can round differently.
+    fn assert_same_audio(audio: &[f32], expected: &[f32]) {
+        assert_eq!(audio.len(), expected.len());
+        assert!(audio
+            .iter()
+            .zip(expected)
+            .all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn injects_faults_into_the_given_calls() -> anyhow::Result<()> {
+        let processor = processor()
+            .with_fault(1, Fault::Short { secs: 2 })
+            .with_fault(2, Fault::Nan { after_secs: 1 })
+            .with_fault(3, Fault::Fail { after_secs: 1 });
+        let generate = |secs| processor.process("", secs, Box::new(|_, _| false));
+
+        assert_eq!(generate(3)?.len(), 300);
+        assert_eq!(generate(3)?.len(), 200);
+        let audio = generate(3)?;
+        assert!(audio[..100].iter().all(|s| s.is_finite()));
+        assert!(audio[100..].iter().all(|s| s.is_nan()));
+        let err = generate(3).unwrap_err();
+        assert!(err.to_string().contains("Injected failure after 1s"));
+        assert_eq!(
+            generate(3)?,
+            MockJobProcessor::new(1)
+                .with_sample_rate(SAMPLE_RATE)
+                .process("", 3, Box::new(|_, _| false))?
+        );
+        assert_eq!(processor.calls(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn retries_failed_segments() -> anyhow::Result<()> {
+        let clean = Arc::new(processor());
+        let expected = render(&clean, config(1))?;
+
+        let faulty = Arc::new(processor().with_fault(1, Fault::Fail { after_secs: 2 }));
+        let audio = render(&faulty, config(1))?;
+        assert_same_audio(&audio, &expected);
+        assert_eq!(faulty.calls(), clean.calls() + 1);
+
+        let faulty = Arc::new(processor().with_fault(1, Fault::Fail { after_secs: 2 }));
+        let err = render(&faulty, config(0)).unwrap_err();
+        assert!(err.to_string().contains("Injected failure after 2s"));
+        Ok(())
+    }
+
+    #[test]
+    fn abandons_stalled_segments() -> anyhow::Result<()> {
+        let clean = Arc::new(processor());
+        let expected = render(&clean, config(1))?;
+
+        let stall = Fault::Stall {
+            after_secs: 1,
+            duration: Duration::from_millis(500),
+        };
+        let faulty = Arc::new(processor().with_fault(1, stall));
+        let config = ExtendedGenerationConfig {
+            watchdog_timeout: Some(Duration::from_millis(50)),
+            ..config(1)
+        };
+        let audio = render(&faulty, config)?;
+        assert_same_audio(&audio, &expected);
+        assert_eq!(faulty.calls(), clean.calls() + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn resumes_failed_jobs_from_their_last_checkpoint() -> anyhow::Result<()> {
+        let config = ExtendedGenerationConfig {
+            segment_duration: 28.0,
+            overlap_duration: 4.0,
+            crossfade_duration: 2.0,
+            ..config(0)
+        };
+        let clean = Arc::new(processor());
+        let expected = ExtendedJobProcessor::new(clean, config.clone(), SAMPLE_RATE)
+            .map_err(anyhow::Error::msg)?
+            .process("", 60, Box::new(|_, _| false))?;
+
+        let faulty = processor().with_fault(1, Fault::Fail { after_secs: 10 });
+        let extended = ExtendedJobProcessor::new(Arc::new(faulty), config.clone(), SAMPLE_RATE)
+            .map_err(anyhow::Error::msg)?;
+        let (tx, rx) = AudioGenerationBackend::new(extended).run();
+        let req = AudioGenerationRequest {
+            id: Uuid::new_v4().to_string(),
+            prompt: "".to_string(),
+            secs: 60,
+            user: None,
+        };
+        tx.send(BackendInboundMsg::Request(req.clone()))?;
+
+        // Everything streamed until the checkpoint is what the job resumes from.
+        let mut streamed = vec![];
+        let mut checkpoint = None;
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Chunk((_, chunk)) => streamed.extend(chunk),
+                BackendOutboundMsg::Checkpoint((_, segments)) => {
+                    checkpoint = Some(JobCheckpoint {
+                        segments,
+                        audio: streamed.clone(),
+                    })
+                }
+                BackendOutboundMsg::Failure((_, err)) => {
+                    assert!(err.contains("Injected failure after 10s"));
+                    break;
+                }
+                BackendOutboundMsg::Response(_) => panic!("The job did not fail"),
+                _ => {}
+            }
+        }
+        let checkpoint = checkpoint.unwrap();
+        assert_eq!(checkpoint.segments, 1);
+
+        let extended = ExtendedJobProcessor::new(Arc::new(processor()), config, SAMPLE_RATE)
+            .map_err(anyhow::Error::msg)?;
+        let (tx, rx) = AudioGenerationBackend::new(extended).run();
+        tx.send(BackendInboundMsg::Resume((req, checkpoint)))?;
+        let audio = loop {
+            if let BackendOutboundMsg::Response((_, audio)) = rx.recv()? {
+                break audio;
+            }
+        };
+        assert_eq!(audio.len(), expected.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! Regression tests that render fixed prompts with the [MockJobProcessor] through the
+//! whole extended pipeline, and compare the audio with the renders stored in
+//! `assets/golden`. After a deliberate change to the audio, regenerate them with:
+//!
+//! ```sh
+//! MUSICGPT_UPDATE_GOLDEN=1 cargo test --lib golden_audio
+//! ```
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::audio::denoise::DenoiseConfig;
+use crate::audio::effects::{Effects, NoiseGateConfig};
+use crate::audio::extended_generation::{
+    BandFade, ExtendedGenerationConfig, JoinStyle, MultibandCrossfade,
+};
+use crate::audio::gain_staging::Normalization;
+use crate::audio::resample::LinearResampler;
+use crate::backend::extended_audio_backend::ExtendedJobProcessor;
+use crate::backend::mock_backend::MockJobProcessor;
+
+const SAMPLE_RATE: usize = 4000;
+const SEED: u64 = 42;
+const PROMPT: &str = "Create a relaxing LoFi song";
+/// The golden renders are stored as 16 bit WAV files, whose rounding is well under this.
+const TOLERANCE: f32 = 1e-3;
+
+fn config() -> ExtendedGenerationConfig {
+    ExtendedGenerationConfig {
+        target_duration: 6.0,
+        segment_duration: 3.0,
+        overlap_duration: 1.0,
+        crossfade_duration: 0.5,
+        watchdog_timeout: None,
+        ..Default::default()
+    }
+}
+
+fn render(config: ExtendedGenerationConfig) -> Vec<f32> {
+    let secs = config.target_duration as usize;
+    let processor = MockJobProcessor::new(SEED).with_sample_rate(SAMPLE_RATE);
+    ExtendedJobProcessor::new(Arc::new(processor), config, SAMPLE_RATE)
+        .unwrap()
+        .generate_extended(PROMPT, secs, Box::new(|_, _| false))
+        .unwrap()
+}
+
+/// Compares `audio` with the golden render `name`, or replaces the golden render with it
+/// when `MUSICGPT_UPDATE_GOLDEN` is set.
+fn assert_golden(name: &str, audio: &[f32], sample_rate: usize) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("assets/golden")
+        .join(format!("{name}.wav"));
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    if std::env::var_os("MUSICGPT_UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in audio {
+            writer
+                .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        return;
+    }
+
+    let reader = hound::WavReader::open(&path).unwrap_or_else(|err| {
+        panic!("Could not open {path:?}, set MUSICGPT_UPDATE_GOLDEN to create it: {err}")
+    });
+    assert_eq!(reader.spec(), spec, "{name}");
+    let golden = reader
+        .into_samples::<i16>()
+        .map(|sample| sample.unwrap() as f32 / i16::MAX as f32)
+        .collect::<Vec<_>>();
+    assert_eq!(audio.len(), golden.len(), "{name} changed its length");
+    let mismatch = audio
+        .iter()
+        .zip(&golden)
+        .position(|(a, b)| (a - b).abs() > TOLERANCE);
+    if let Some(i) = mismatch {
+        panic!(
+            "{name} differs at {:.3}s: {} instead of {}",
+            i as f32 / sample_rate as f32,
+            audio[i],
+            golden[i]
+        );
+    }
+}
+
+#[test]
+fn crossfade() {
+    assert_golden("crossfade", &render(config()), SAMPLE_RATE);
+}
+
+#[test]
+fn tail_ride_out() {
+    let config = ExtendedGenerationConfig {
+        join: JoinStyle::TailRideOut { attack: 0.2 },
+        ..config()
+    };
+    assert_golden("tail-ride-out", &render(config), SAMPLE_RATE);
+}
+
+#[test]
+fn multiband() {
+    let config = ExtendedGenerationConfig {
+        join: JoinStyle::Multiband(MultibandCrossfade {
+            low_mid_hz: 250.0,
+            mid_high_hz: 1000.0,
+            high: BandFade {
+                start: 0.6,
+                end: 0.8,
+            },
+            ..Default::default()
+        }),
+        ..config()
+    };
+    assert_golden("multiband", &render(config), SAMPLE_RATE);
+}
+
+#[test]
+fn processed() {
+    let config = ExtendedGenerationConfig {
+        denoise: Some(DenoiseConfig::default()),
+        normalize: Some(Normalization {
+            analysis_secs: 1.0,
+            ..Default::default()
+        }),
+        effects: Effects {
+            noise_gate: Some(NoiseGateConfig::default()),
+            headroom_db: Some(1.0),
+        },
+        ..config()
+    };
+    assert_golden("processed", &render(config), SAMPLE_RATE);
+}
+
+#[test]
+fn resampled() {
+    let audio = render(config());
+    let mut resampler = LinearResampler::new(SAMPLE_RATE as u32, 3000);
+    let mut resampled = audio
+        .chunks(1000)
+        .flat_map(|chunk| resampler.process(chunk))
+        .collect::<Vec<_>>();
+    resampled.extend(resampler.flush());
+    assert_golden("resampled", &resampled, 3000);
+}
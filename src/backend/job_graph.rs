@@ -0,0 +1,1071 @@
+//! Jobs made of several steps that depend on each other, like generating stems, mixing
+//! them, mastering the mix and uploading it. Steps run as soon as the ones they need
+//! finish, so independent renders wait in the queue together, and each step gets the
+//! audio of the ones it needs as paths in the storage instead of copies of it.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::audio::dsp::{apply_gain, db_to_gain, mix, peak, rms};
+use crate::audio::effects::{Effects, EffectsChain, NoiseGateConfig};
+use crate::audio::watermark::{WatermarkPayload, Watermarker};
+use crate::audio::wav::{decode_wav, encode_wav, encode_wav_channels};
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::music_gpt_chat::Chat;
+use crate::backend::music_gpt_ws_handler::{GenerateAudioRequest, MusicGptWsHandler};
+use crate::backend::render_manifest::{RenderManifest, RenderStatus};
+use crate::backend::usage::{ApiTokens, TokenQuery};
+use crate::backend::JobProcessor;
+use crate::cli::SAMPLING_RATE;
+use crate::storage::Storage;
+
+/// What a step does with the audio of the steps it needs.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub enum StepAction {
+    /// Renders a prompt through the queue. The steps it needs only delay it.
+    Generate { prompt: String, secs: usize },
+    /// Sums the audio of the steps it needs, each one with its gain in decibels, in the
+    /// same order, or unchanged if there are less gains than steps.
+    Mix {
+        #[serde(default)]
+        gains_db: Vec<f32>,
+    },
+    /// Brings the audio of the step it needs to an RMS level, lowering it further if its
+    /// peaks would go over the ceiling.
+    Master { rms_db: f32, ceiling_db: f32 },
+    /// Runs the audio of the step it needs through a noise gate closing under a level in
+    /// dBFS, then through a limiter keeping the peaks under the headroom, each one if set.
+    Effects {
+        #[serde(default)]
+        noise_gate_db: Option<f32>,
+        #[serde(default)]
+        headroom_db: Option<f32>,
+    },
+    /// Sends the audio of the step it needs as a WAV file in a PUT request. Only in
+    /// `musicgpt run`, like `Export`.
+    Upload { url: String },
+    /// Writes the audio of the step it needs to a WAV file. Only in `musicgpt run`, as
+    /// the server does not write files outside its data directory.
+    Export { path: PathBuf },
+    /// Writes the audio of the steps it needs to a directory as stems of the same length,
+    /// along with their stereo mixdown and a `bundle.json` with the gains, pans from -1 to
+    /// 1 and how the mixdown is made from them, in the same order. Its own audio is their
+    /// mono mix. Only in `musicgpt run`, like `Export`.
+    Bundle {
+        path: PathBuf,
+        #[serde(default)]
+        gains_db: Vec<f32>,
+        #[serde(default)]
+        pans: Vec<f32>,
+    },
+}
+
+/// The `bundle.json` of a stem bundle, enough for reproducing its mixdown elsewhere.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct StemBundle {
+    pub sample_rate: usize,
+    /// Length of every stem, and of the mixdown.
+    pub samples: usize,
+    pub stems: Vec<BundledStem>,
+    pub mixdown: String,
+    /// How the mixdown is made from the stems.
+    pub recipe: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BundledStem {
+    /// The step the stem comes from.
+    pub name: String,
+    pub file: String,
+    pub gain_db: f32,
+    pub pan: f32,
+}
+
+const BUNDLE_RECIPE: &str = "Each stem is multiplied by 10^(gain_db / 20), then sent to the \
+    left channel multiplied by cos((pan + 1) * pi / 4) and to the right one multiplied by \
+    sin((pan + 1) * pi / 4), and the stems are summed.";
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct GraphStep {
+    /// Unique within the graph, referenced by the steps that need this one.
+    pub name: String,
+    #[serde(default)]
+    pub needs: Vec<String>,
+    pub action: StepAction,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct JobGraph {
+    /// Name of the chat the renders of the graph go to.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub steps: Vec<GraphStep>,
+}
+
+impl JobGraph {
+    /// Reads a graph from a YAML file, or a JSON one, as JSON is also YAML. The paths it
+    /// exports to are relative to the directory of the file.
+    pub fn read(path: &std::path::Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Could not read the pipeline {path:?}: {err}"))?;
+        // Through JSON, so that actions are written as maps like in `POST /pipelines`
+        // instead of with YAML tags.
+        let mut graph: Self = serde_yaml::from_str::<serde_json::Value>(&content)
+            .map_err(anyhow::Error::from)
+            .and_then(|value| Ok(serde_json::from_value(value)?))
+            .map_err(|err| anyhow!("Invalid pipeline {path:?}: {err}"))?;
+        let root = path.parent().unwrap_or(std::path::Path::new("."));
+        for step in &mut graph.steps {
+            if let StepAction::Export { path } | StepAction::Bundle { path, .. } = &mut step.action
+            {
+                *path = root.join(&*path);
+            }
+        }
+        graph.validate()?;
+        Ok(graph)
+    }
+
+    /// Checks that the steps can run: their names are unique, what they need exists, they
+    /// get as much audio as their action takes, and they do not need each other in a loop.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        for step in &self.steps {
+            if step.name.is_empty() {
+                return Err(anyhow!("Steps must have a name"));
+            }
+            if !names.insert(step.name.as_str()) {
+                return Err(anyhow!("There is more than one step named {:?}", step.name));
+            }
+        }
+        for step in &self.steps {
+            if let Some(missing) = step
+                .needs
+                .iter()
+                .find(|need| !names.contains(need.as_str()))
+            {
+                return Err(anyhow!(
+                    "Step {:?} needs {missing:?}, which does not exist",
+                    step.name
+                ));
+            }
+            let inputs = step.needs.len();
+            match &step.action {
+                StepAction::Generate { secs, .. } if *secs < 1 => {
+                    return Err(anyhow!("The secs of step {:?} must > 0", step.name));
+                }
+                StepAction::Mix { .. } | StepAction::Bundle { .. } if inputs < 1 => {
+                    return Err(anyhow!(
+                        "Step {:?} mixes nothing, it must need other steps",
+                        step.name
+                    ));
+                }
+                StepAction::Bundle { pans, .. }
+                    if pans.iter().any(|pan| !(-1.0..=1.0).contains(pan)) =>
+                {
+                    return Err(anyhow!(
+                        "The pans of step {:?} must be between -1 and 1",
+                        step.name
+                    ));
+                }
+                StepAction::Master { .. }
+                | StepAction::Effects { .. }
+                | StepAction::Upload { .. }
+                | StepAction::Export { .. }
+                    if inputs != 1 =>
+                {
+                    return Err(anyhow!(
+                        "Step {:?} must need exactly one step, the one with its audio",
+                        step.name
+                    ));
+                }
+                _ => {}
+            }
+        }
+        let mut done = HashSet::new();
+        while done.len() < self.steps.len() {
+            let ready = self
+                .steps
+                .iter()
+                .filter(|step| !done.contains(step.name.as_str()))
+                .filter(|step| step.needs.iter().all(|need| done.contains(need.as_str())))
+                .map(|step| step.name.as_str())
+                .collect::<Vec<_>>();
+            if ready.is_empty() {
+                return Err(anyhow!("The steps need each other in a loop"));
+            }
+            done.extend(ready);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub enum StepState {
+    Waiting,
+    Running,
+    Completed,
+    Failed,
+    /// Not run because a step it needs failed.
+    Skipped,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct StepStatus {
+    pub name: String,
+    pub state: StepState,
+    /// The render of generate steps, whose manifest is in `GET /jobs/:id`.
+    pub job: Option<Uuid>,
+    /// Where the audio of the step is in the storage, once it completes.
+    pub artifact: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A graph being run or that ran, saved so that its progress can be followed.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct GraphRun {
+    pub id: Uuid,
+    pub graph: JobGraph,
+    pub steps: Vec<StepStatus>,
+    pub created_at: u128,
+}
+
+impl GraphRun {
+    pub fn new(graph: JobGraph) -> Self {
+        let steps = graph
+            .steps
+            .iter()
+            .map(|step| StepStatus {
+                name: step.name.clone(),
+                state: StepState::Waiting,
+                job: None,
+                artifact: None,
+                error: None,
+            })
+            .collect();
+        Self {
+            id: Uuid::new_v4(),
+            graph,
+            steps,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+        }
+    }
+
+    fn path(id: Uuid) -> String {
+        format!("pipelines/{id}/run.json")
+    }
+
+    pub async fn load<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<Option<Self>> {
+        match storage.read(&Self::path(id)).await? {
+            Some(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        Ok(storage
+            .write(&Self::path(self.id), serde_json::to_vec_pretty(self)?)
+            .await?)
+    }
+
+    /// Marks the steps of runs that were interrupted by a restart as failed, as the
+    /// graphs are not resumed.
+    pub async fn fail_unfinished<S: Storage>(storage: &S) -> anyhow::Result<()> {
+        for path in storage.list("pipelines").await? {
+            let Some(id) = path
+                .strip_prefix("pipelines/")
+                .and_then(|id| Uuid::parse_str(id).ok())
+            else {
+                continue;
+            };
+            let Ok(Some(mut run)) = Self::load(storage, id).await else {
+                continue;
+            };
+            let mut interrupted = false;
+            for step in &mut run.steps {
+                if matches!(step.state, StepState::Waiting | StepState::Running) {
+                    step.state = StepState::Failed;
+                    step.error = Some("Interrupted by a restart".to_string());
+                    interrupted = true;
+                }
+            }
+            if interrupted {
+                run.save(storage).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn step_mut(&mut self, name: &str) -> &mut StepStatus {
+        self.steps
+            .iter_mut()
+            .find(|step| step.name == name)
+            .unwrap()
+    }
+
+    fn state(&self, name: &str) -> &StepState {
+        &self
+            .steps
+            .iter()
+            .find(|step| step.name == name)
+            .unwrap()
+            .state
+    }
+}
+
+/// Where the audio of generate steps comes from.
+#[async_trait]
+pub trait Generator: Send + Sync {
+    /// Queues a render, returning its id.
+    async fn submit(&self, prompt: &str, secs: usize) -> anyhow::Result<Uuid>;
+    /// Waits for a render to finish, returning where its audio is in the storage.
+    async fn wait(&self, id: Uuid) -> anyhow::Result<String>;
+}
+
+/// Runs the steps of `run` as the ones they need complete, saving its progress after
+/// each change. Steps that fail make the ones that need them be skipped, while the rest
+/// of the graph carries on.
+pub async fn run_graph<S: Storage>(
+    storage: &S,
+    generator: &dyn Generator,
+    mut run: GraphRun,
+) -> anyhow::Result<GraphRun> {
+    let graph = run.graph.clone();
+    let mut running = FuturesUnordered::new();
+    loop {
+        // Skipping first, so that what needs a skipped step is skipped in the same pass.
+        for step in &graph.steps {
+            let failed = step
+                .needs
+                .iter()
+                .any(|need| matches!(run.state(need), StepState::Failed | StepState::Skipped));
+            if failed && *run.state(&step.name) == StepState::Waiting {
+                run.step_mut(&step.name).state = StepState::Skipped;
+            }
+        }
+        for step in &graph.steps {
+            let ready = step
+                .needs
+                .iter()
+                .all(|need| *run.state(need) == StepState::Completed);
+            if !ready || *run.state(&step.name) != StepState::Waiting {
+                continue;
+            }
+            let inputs = step
+                .needs
+                .iter()
+                .map(|need| run.steps.iter().find(|s| &s.name == need).unwrap())
+                .map(|need| need.artifact.clone().unwrap_or_default())
+                .collect::<Vec<_>>();
+            let status = run.step_mut(&step.name);
+            status.state = StepState::Running;
+            if let StepAction::Generate { prompt, secs } = &step.action {
+                match generator.submit(prompt, *secs).await {
+                    Ok(id) => status.job = Some(id),
+                    Err(err) => {
+                        status.state = StepState::Failed;
+                        status.error = Some(err.to_string());
+                        continue;
+                    }
+                }
+            }
+            let job = status.job;
+            let run_id = run.id;
+            running.push(async move {
+                let result = run_step(storage, generator, run_id, step, job, inputs).await;
+                (step.name.as_str(), result)
+            });
+        }
+        run.save(storage).await?;
+        let Some((name, result)) = running.next().await else {
+            break;
+        };
+        let status = run.step_mut(name);
+        match result {
+            Ok(artifact) => {
+                status.state = StepState::Completed;
+                status.artifact = Some(artifact);
+            }
+            Err(err) => {
+                warn!(step = name, "Step failed: {err}");
+                status.state = StepState::Failed;
+                status.error = Some(err.to_string());
+            }
+        }
+    }
+    Ok(run)
+}
+
+/// Runs a step whose inputs are ready, returning where its audio is.
+async fn run_step<S: Storage>(
+    storage: &S,
+    generator: &dyn Generator,
+    run_id: Uuid,
+    step: &GraphStep,
+    job: Option<Uuid>,
+    inputs: Vec<String>,
+) -> anyhow::Result<String> {
+    let read = |relpath: String| async move {
+        let Some(bytes) = storage.read(&relpath).await? else {
+            return Err(anyhow!("The audio {relpath} is missing"));
+        };
+        decode_wav(&bytes).map_err(|err| anyhow!("Invalid audio {relpath}: {err}"))
+    };
+    let relpath = format!("pipelines/{run_id}/{}.wav", step.name);
+    let audio = match &step.action {
+        StepAction::Generate { .. } => {
+            let job = job.ok_or_else(|| anyhow!("The render was not submitted"))?;
+            return generator.wait(job).await;
+        }
+        StepAction::Mix { gains_db } => {
+            let mut mixed = vec![];
+            for (i, input) in inputs.into_iter().enumerate() {
+                let audio = read(input).await?;
+                if mixed.len() < audio.len() {
+                    mixed.resize(audio.len(), 0.0);
+                }
+                mix(
+                    &mut mixed,
+                    &audio,
+                    db_to_gain(gains_db.get(i).copied().unwrap_or(0.0)),
+                );
+            }
+            mixed
+        }
+        StepAction::Master { rms_db, ceiling_db } => {
+            let mut audio = read(inputs[0].clone()).await?;
+            let level = rms(&audio);
+            if level > 0.0 {
+                let gain = db_to_gain(*rms_db) / level;
+                let ceiling = db_to_gain(*ceiling_db) / peak(&audio).max(f32::EPSILON);
+                apply_gain(&mut audio, gain.min(ceiling));
+            }
+            audio
+        }
+        StepAction::Effects {
+            noise_gate_db,
+            headroom_db,
+        } => {
+            let mut audio = read(inputs[0].clone()).await?;
+            let effects = Effects {
+                noise_gate: noise_gate_db.map(|threshold_db| NoiseGateConfig {
+                    threshold_db,
+                    ..Default::default()
+                }),
+                headroom_db: *headroom_db,
+            };
+            EffectsChain::new(&effects, SAMPLING_RATE).process(&mut audio);
+            audio
+        }
+        StepAction::Export { path } => {
+            let Some(bytes) = storage.read(&inputs[0]).await? else {
+                return Err(anyhow!("The audio {} is missing", inputs[0]));
+            };
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(path, bytes)
+                .await
+                .map_err(|err| anyhow!("Could not export to {path:?}: {err}"))?;
+            info!(step = step.name, "Exported to {path:?}");
+            return Ok(inputs[0].clone());
+        }
+        StepAction::Bundle {
+            path,
+            gains_db,
+            pans,
+        } => {
+            let mut stems = vec![];
+            for input in inputs {
+                stems.push(read(input).await?);
+            }
+            let samples = stems.iter().map(Vec::len).max().unwrap_or(0);
+            let mut mono = vec![0.0; samples];
+            let mut mixdown = [vec![0.0; samples], vec![0.0; samples]];
+            let mut bundle = StemBundle {
+                sample_rate: SAMPLING_RATE,
+                samples,
+                stems: vec![],
+                mixdown: "mixdown.wav".to_string(),
+                recipe: BUNDLE_RECIPE.to_string(),
+            };
+            tokio::fs::create_dir_all(path)
+                .await
+                .map_err(|err| anyhow!("Could not export to {path:?}: {err}"))?;
+            for (i, (name, mut audio)) in step.needs.iter().zip(stems).enumerate() {
+                // Sample-aligned, so that they can be dropped into a DAW at the same time.
+                audio.resize(samples, 0.0);
+                let gain_db = gains_db.get(i).copied().unwrap_or(0.0);
+                let pan = pans.get(i).copied().unwrap_or(0.0);
+                let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+                mix(&mut mono, &audio, db_to_gain(gain_db));
+                mix(&mut mixdown[0], &audio, db_to_gain(gain_db) * angle.cos());
+                mix(&mut mixdown[1], &audio, db_to_gain(gain_db) * angle.sin());
+                let file = format!("{name}.wav");
+                tokio::fs::write(path.join(&file), encode_wav(audio, SAMPLING_RATE as u32)?)
+                    .await?;
+                bundle.stems.push(BundledStem {
+                    name: name.clone(),
+                    file,
+                    gain_db,
+                    pan,
+                });
+            }
+            let stereo = encode_wav_channels(&mixdown, SAMPLING_RATE as u32)?;
+            tokio::fs::write(path.join(&bundle.mixdown), stereo).await?;
+            tokio::fs::write(
+                path.join("bundle.json"),
+                serde_json::to_vec_pretty(&bundle)?,
+            )
+            .await?;
+            info!(
+                step = step.name,
+                "Bundled {} stems in {path:?}",
+                step.needs.len()
+            );
+            mono
+        }
+        StepAction::Upload { url } => {
+            let Some(bytes) = storage.read(&inputs[0]).await? else {
+                return Err(anyhow!("The audio {} is missing", inputs[0]));
+            };
+            let response = reqwest::Client::new()
+                .put(url)
+                .header("Content-Type", "audio/wav")
+                .body(bytes)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(anyhow!(
+                    "Uploading to {url} failed with {}",
+                    response.status()
+                ));
+            }
+            info!(step = step.name, "Uploaded to {url}");
+            // What was uploaded is the audio of the step it needs, which is not copied.
+            return Ok(inputs[0].clone());
+        }
+    };
+    storage
+        .write(&relpath, encode_wav(audio, SAMPLING_RATE as u32)?)
+        .await?;
+    Ok(relpath)
+}
+
+/// Submits the renders of a graph to the queue of the server, all in the same chat.
+struct QueueGenerator<S: Storage> {
+    handler: MusicGptWsHandler<S>,
+    chat_id: Uuid,
+}
+
+#[async_trait]
+impl<S: Storage> Generator for QueueGenerator<S> {
+    async fn submit(&self, prompt: &str, secs: usize) -> anyhow::Result<Uuid> {
+        let req = GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: self.chat_id,
+            prompt: prompt.to_string(),
+            secs,
+            notify: vec![],
+            audition: false,
+        };
+        let id = req.id;
+        self.handler.request_generation(req).await?;
+        Ok(id)
+    }
+
+    async fn wait(&self, id: Uuid) -> anyhow::Result<String> {
+        // Subscribing first, so that nothing is missed if the render finishes meanwhile.
+        let mut rx = self.handler.ai_broadcast_tx.subscribe();
+        let manifest = RenderManifest::load(&self.handler.storage, id).await?;
+        match manifest.map(|manifest| (manifest.status, manifest.error)) {
+            Some((RenderStatus::Completed, _)) => return Ok(format!("audios/{id}.wav")),
+            Some((RenderStatus::Failed, error)) => return Err(anyhow!(error.unwrap_or_default())),
+            _ => {}
+        }
+        loop {
+            match rx.recv().await {
+                Ok(GenerationMessage::Result(msg)) if msg.id == id => return Ok(msg.relpath),
+                Ok(GenerationMessage::Error(msg)) if msg.id == id => {
+                    return Err(anyhow!(msg.error))
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return Err(anyhow!("The server is shutting down")),
+            }
+        }
+    }
+}
+
+/// Renders the generate steps of a graph with a model loaded in this process, one at a
+/// time, for running graphs without a server.
+pub struct ProcessorGenerator<S: Storage> {
+    storage: S,
+    processor: Arc<dyn JobProcessor>,
+    watermark: Option<WatermarkPayload>,
+    submitted: std::sync::Mutex<HashMap<Uuid, (String, usize)>>,
+    /// Held while rendering, so that renders do not compete for the model.
+    busy: tokio::sync::Mutex<()>,
+}
+
+impl<S: Storage> ProcessorGenerator<S> {
+    pub fn new(
+        storage: S,
+        processor: Arc<dyn JobProcessor>,
+        watermark: Option<WatermarkPayload>,
+    ) -> Self {
+        Self {
+            storage,
+            processor,
+            watermark,
+            submitted: Default::default(),
+            busy: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Generator for ProcessorGenerator<S> {
+    async fn submit(&self, prompt: &str, secs: usize) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        let job = (prompt.to_string(), secs);
+        self.submitted.lock().unwrap().insert(id, job);
+        Ok(id)
+    }
+
+    async fn wait(&self, id: Uuid) -> anyhow::Result<String> {
+        let Some((prompt, secs)) = self.submitted.lock().unwrap().remove(&id) else {
+            return Err(anyhow!("Render {id} was not submitted"));
+        };
+        let _busy = self.busy.lock().await;
+        info!("Generating {secs}s of \"{prompt}\"");
+        let processor = self.processor.clone();
+        let mut audio = tokio::task::spawn_blocking(move || {
+            processor.process(&prompt, secs, Box::new(|_, _| false))
+        })
+        .await??;
+        if let Some(payload) = self.watermark {
+            Watermarker::new(payload).apply(&mut audio);
+        }
+        let relpath = format!("pipelines/renders/{id}.wav");
+        self.storage
+            .write(&relpath, encode_wav(audio, SAMPLING_RATE as u32)?)
+            .await?;
+        Ok(relpath)
+    }
+}
+
+#[derive(Clone)]
+struct GraphRoutesState<S: Storage> {
+    handler: MusicGptWsHandler<S>,
+    tokens: ApiTokens,
+}
+
+/// HTTP routes for running job graphs on the queue and following them.
+pub fn job_graph_routes<S: Storage>(handler: MusicGptWsHandler<S>, tokens: ApiTokens) -> Router {
+    Router::new()
+        .route("/pipelines", post(submit_graph::<S>))
+        .route("/pipelines/:id", get(get_graph_run::<S>))
+        .with_state(GraphRoutesState { handler, tokens })
+}
+
+/// Starts running a graph, whose renders are accounted to the caller, and returns its id.
+async fn submit_graph<S: Storage>(
+    State(state): State<GraphRoutesState<S>>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Json(graph): Json<JobGraph>,
+) -> Response {
+    let Some(user) = state.tokens.authenticate(query.token(&headers)) else {
+        return (StatusCode::UNAUTHORIZED, "Invalid API token").into_response();
+    };
+    if let Err(err) = graph.validate() {
+        return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+    }
+    // Uploads would have the server send requests to any URL its users give, like the
+    // ones of its own network.
+    for step in &graph.steps {
+        let what = match step.action {
+            StepAction::Export { .. } | StepAction::Bundle { .. } => "exports to files",
+            StepAction::Upload { .. } => "uploads to a URL",
+            _ => continue,
+        };
+        let error = format!(
+            "Step {:?} {what}, which only `musicgpt run` does",
+            step.name
+        );
+        return (StatusCode::BAD_REQUEST, error).into_response();
+    }
+    let storage = state.handler.storage.clone();
+    let run = GraphRun::new(graph);
+    let chat = Chat {
+        chat_id: Uuid::new_v4(),
+        name: run
+            .graph
+            .name
+            .clone()
+            .unwrap_or(format!("Pipeline {}", run.id)),
+        created_at: run.created_at,
+    };
+    let saved = async {
+        chat.save(&storage).await?;
+        run.save(&storage).await
+    };
+    if let Err(err) = saved.await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+    }
+    let id = run.id;
+    let generator = QueueGenerator {
+        handler: MusicGptWsHandler {
+            user,
+            ..state.handler
+        },
+        chat_id: chat.chat_id,
+    };
+    tokio::spawn(async move {
+        if let Err(err) = run_graph(&storage, &generator, run).await {
+            warn!(pipeline = %id, "Could not run the pipeline: {err}");
+        }
+    });
+    Json(id).into_response()
+}
+
+/// The state of each step of a graph, and where their audio is.
+async fn get_graph_run<S: Storage>(
+    State(state): State<GraphRoutesState<S>>,
+    Path(id): Path<Uuid>,
+) -> Response {
+    match GraphRun::load(&state.handler.storage, id).await {
+        Ok(Some(run)) => Json(run).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, format!("Pipeline {id} not found")).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::body::to_bytes;
+
+    use crate::audio::wav::decode_wav_channels;
+    use crate::backend::_test_utils::dummy_ws_handler;
+    use crate::backend::usage::LOCAL_USER;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn step(name: &str, needs: &[&str], action: StepAction) -> GraphStep {
+        GraphStep {
+            name: name.to_string(),
+            needs: needs.iter().map(|need| need.to_string()).collect(),
+            action,
+        }
+    }
+
+    fn generate(prompt: &str) -> StepAction {
+        StepAction::Generate {
+            prompt: prompt.to_string(),
+            secs: 1,
+        }
+    }
+
+    /// Generates a constant level per prompt right away, failing for "fail".
+    struct InstantGenerator {
+        storage: AppFs,
+        submitted: Mutex<HashMap<Uuid, String>>,
+    }
+
+    #[async_trait]
+    impl Generator for InstantGenerator {
+        async fn submit(&self, prompt: &str, _secs: usize) -> anyhow::Result<Uuid> {
+            let id = Uuid::new_v4();
+            self.submitted
+                .lock()
+                .unwrap()
+                .insert(id, prompt.to_string());
+            Ok(id)
+        }
+
+        async fn wait(&self, id: Uuid) -> anyhow::Result<String> {
+            let prompt = self.submitted.lock().unwrap()[&id].clone();
+            let level = match prompt.as_str() {
+                "fail" => return Err(anyhow!("Model failed")),
+                "loud" => 0.5,
+                _ => 0.1,
+            };
+            let relpath = format!("audios/{id}.wav");
+            let audio = vec![level; SAMPLING_RATE];
+            self.storage
+                .write(&relpath, encode_wav(audio, SAMPLING_RATE as u32)?)
+                .await?;
+            Ok(relpath)
+        }
+    }
+
+    #[test]
+    fn validates_graphs() {
+        let graph = |steps| JobGraph { name: None, steps };
+        let valid = graph(vec![
+            step("drums", &[], generate("drums")),
+            step("mix", &["drums"], StepAction::Mix { gains_db: vec![] }),
+        ]);
+        assert!(valid.validate().is_ok());
+
+        let errors = [
+            graph(vec![
+                step("a", &[], generate("a")),
+                step("a", &[], generate("b")),
+            ]),
+            graph(vec![step("a", &["b"], generate("a"))]),
+            graph(vec![step("mix", &[], StepAction::Mix { gains_db: vec![] })]),
+            graph(vec![
+                step("a", &[], generate("a")),
+                step(
+                    "stems",
+                    &["a"],
+                    StepAction::Bundle {
+                        path: PathBuf::from("stems"),
+                        gains_db: vec![],
+                        pans: vec![1.5],
+                    },
+                ),
+            ]),
+            graph(vec![
+                step("a", &["b"], StepAction::Mix { gains_db: vec![] }),
+                step("b", &["a"], StepAction::Mix { gains_db: vec![] }),
+            ]),
+            graph(vec![
+                step("a", &[], generate("a")),
+                step("b", &[], generate("b")),
+                step(
+                    "up",
+                    &["a", "b"],
+                    StepAction::Upload {
+                        url: "http://x".to_string(),
+                    },
+                ),
+            ]),
+        ];
+        for graph in errors {
+            assert!(graph.validate().is_err(), "{graph:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn only_runs_steps_that_stay_on_the_server() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (handler, ai_rx) = dummy_ws_handler(&storage, LOCAL_USER, Default::default());
+        let state = GraphRoutesState {
+            handler,
+            tokens: ApiTokens::default(),
+        };
+        let submit = |action| {
+            let graph = JobGraph {
+                name: None,
+                steps: vec![
+                    step("drums", &[], generate("drums")),
+                    step("out", &["drums"], action),
+                ],
+            };
+            submit_graph(
+                State(state.clone()),
+                HeaderMap::new(),
+                Query(TokenQuery::default()),
+                Json(graph),
+            )
+        };
+
+        let upload = StepAction::Upload {
+            url: "http://169.254.169.254/latest".to_string(),
+        };
+        let export = StepAction::Export {
+            path: PathBuf::from("song.wav"),
+        };
+        for action in [upload, export] {
+            let response = submit(action).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body = to_bytes(response.into_body(), usize::MAX).await?;
+            assert!(String::from_utf8(body.to_vec())?.contains("only `musicgpt run`"));
+        }
+        assert!(ai_rx.try_recv().is_err());
+
+        let response = submit(StepAction::Effects {
+            noise_gate_db: None,
+            headroom_db: None,
+        })
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_steps_after_the_ones_they_need() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let generator = InstantGenerator {
+            storage: storage.clone(),
+            submitted: Mutex::default(),
+        };
+        let graph = JobGraph {
+            name: Some("Song".to_string()),
+            steps: vec![
+                step(
+                    "master",
+                    &["mix"],
+                    StepAction::Master {
+                        rms_db: -20.0,
+                        ceiling_db: -1.0,
+                    },
+                ),
+                step(
+                    "mix",
+                    &["drums", "bass"],
+                    StepAction::Mix {
+                        gains_db: vec![0.0, -6.0],
+                    },
+                ),
+                step("drums", &[], generate("loud")),
+                step("bass", &[], generate("quiet")),
+                step("broken", &[], generate("fail")),
+                step(
+                    "after-broken",
+                    &["broken"],
+                    StepAction::Mix { gains_db: vec![] },
+                ),
+            ],
+        };
+        graph.validate()?;
+        let run = run_graph(&storage, &generator, GraphRun::new(graph)).await?;
+        assert_eq!(GraphRun::load(&storage, run.id).await?, Some(run.clone()));
+
+        let status = |name: &str| run.steps.iter().find(|s| s.name == name).unwrap().clone();
+        assert_eq!(status("broken").state, StepState::Failed);
+        assert_eq!(status("after-broken").state, StepState::Skipped);
+        assert!(status("drums").job.is_some());
+        // The renders are passed on where they are, not copied.
+        assert_eq!(
+            status("drums").artifact,
+            Some(format!("audios/{}.wav", status("drums").job.unwrap()))
+        );
+
+        let read = |relpath: Option<String>| async {
+            let bytes = storage.read(&relpath.unwrap()).await?.unwrap();
+            decode_wav(&bytes).map_err(|err| anyhow!(err))
+        };
+        let mix = read(status("mix").artifact).await?;
+        assert!((mix[0] - (0.5 + 0.1 * db_to_gain(-6.0))).abs() < 1e-3);
+        assert_eq!(status("master").state, StepState::Completed);
+        let master = read(status("master").artifact).await?;
+        assert!((rms(&master) - db_to_gain(-20.0)).abs() < 1e-3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_pipelines_read_from_yaml() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let generator = InstantGenerator {
+            storage: storage.clone(),
+            submitted: Mutex::default(),
+        };
+        std::fs::create_dir_all(&storage.root)?;
+        let path = storage.root.join("song.yaml");
+        std::fs::write(
+            &path,
+            r#"
+name: Song
+steps:
+  - name: drums
+    action:
+      Generate: { prompt: loud, secs: 1 }
+  - name: clean
+    needs: [drums]
+    action:
+      Effects: { noise_gate_db: -40, headroom_db: 3 }
+  - name: export
+    needs: [clean]
+    action:
+      Export: { path: out/song.wav }
+"#,
+        )?;
+        let graph = JobGraph::read(&path)?;
+        assert_eq!(
+            graph.steps[2].action,
+            StepAction::Export {
+                path: storage.root.join("out/song.wav")
+            }
+        );
+        let run = run_graph(&storage, &generator, GraphRun::new(graph)).await?;
+        assert!(run.steps.iter().all(|s| s.state == StepState::Completed));
+
+        let exported = decode_wav(&std::fs::read(storage.root.join("out/song.wav"))?)
+            .map_err(|err| anyhow!(err))?;
+        assert!(peak(&exported) <= db_to_gain(-3.0) + 1e-3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bundles_aligned_stems_with_their_mixdown() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let generator = InstantGenerator {
+            storage: storage.clone(),
+            submitted: Mutex::default(),
+        };
+        let dir = storage.root.join("stems");
+        let graph = JobGraph {
+            name: None,
+            steps: vec![
+                step("drums", &[], generate("loud")),
+                step("keys", &[], generate("quiet")),
+                step(
+                    "bundle",
+                    &["drums", "keys"],
+                    StepAction::Bundle {
+                        path: dir.clone(),
+                        gains_db: vec![-6.0],
+                        pans: vec![-1.0, 0.5],
+                    },
+                ),
+            ],
+        };
+        graph.validate()?;
+        let run = run_graph(&storage, &generator, GraphRun::new(graph)).await?;
+        assert!(run.steps.iter().all(|s| s.state == StepState::Completed));
+
+        let bundle: StemBundle = serde_json::from_slice(&std::fs::read(dir.join("bundle.json"))?)?;
+        assert_eq!(bundle.samples, SAMPLING_RATE);
+        let stems = bundle
+            .stems
+            .iter()
+            .map(|stem| (stem.file.as_str(), stem.gain_db, stem.pan))
+            .collect::<Vec<_>>();
+        assert_eq!(stems, [("drums.wav", -6.0, -1.0), ("keys.wav", 0.0, 0.5)]);
+        let keys = decode_wav(&std::fs::read(dir.join("keys.wav"))?).map_err(|e| anyhow!(e))?;
+        assert_eq!(keys.len(), bundle.samples);
+
+        // The drums are hard left, so only the keys are on the right.
+        let (mixdown, _) = decode_wav_channels(&std::fs::read(dir.join(&bundle.mixdown))?)
+            .map_err(|e| anyhow!(e))?;
+        let angle = 1.5 * std::f32::consts::FRAC_PI_4;
+        assert!((mixdown[0][0] - (0.5 * db_to_gain(-6.0) + 0.1 * angle.cos())).abs() < 1e-4);
+        assert!((mixdown[1][0] - 0.1 * angle.sin()).abs() < 1e-4);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::anyhow;
+
+/// What a job is expected to need, as estimated by its [JobProcessor](crate::backend::JobProcessor)
+/// before running it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobEstimate {
+    /// Segments generated for the job.
+    pub segments: usize,
+    /// Segments generated at the same time.
+    pub concurrent_segments: usize,
+    /// Peak memory used by the job itself, on top of the memory of the loaded model.
+    pub memory_bytes: u64,
+    /// Disk space taken by the job's output and the checkpoints written while it runs.
+    pub disk_bytes: u64,
+    /// Problems with how the job would be generated that do not prevent running it, to
+    /// be reported before it starts.
+    pub warnings: Vec<String>,
+}
+
+/// Caps on what a single job can request, so that one request cannot monopolize a shared
+/// server. Limits that are not set are not enforced.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JobLimits {
+    pub max_secs: Option<usize>,
+    pub max_concurrent_segments: Option<usize>,
+    pub max_memory_bytes: Option<u64>,
+}
+
+impl JobLimits {
+    /// Decides whether a job of `secs` seconds is accepted. Jobs without an estimate are
+    /// only checked against the duration limit.
+    pub fn admit(&self, secs: usize, estimate: Option<&JobEstimate>) -> anyhow::Result<()> {
+        if let Some(max_secs) = self.max_secs {
+            if secs > max_secs {
+                return Err(anyhow!(
+                    "Requested {secs}s of audio, but at most {max_secs}s are allowed per job"
+                ));
+            }
+        }
+        let Some(estimate) = estimate else {
+            return Ok(());
+        };
+        if let Some(max) = self.max_concurrent_segments {
+            if estimate.concurrent_segments > max {
+                return Err(anyhow!(
+                    "The job would generate {} segments at the same time, but at most {max} are allowed",
+                    estimate.concurrent_segments
+                ));
+            }
+        }
+        if let Some(max) = self.max_memory_bytes {
+            if estimate.memory_bytes > max {
+                return Err(anyhow!(
+                    "The job would need {} MB of memory, but at most {} MB are allowed",
+                    estimate.memory_bytes / MB,
+                    max / MB
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+const MB: u64 = 1024 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate(concurrent_segments: usize, memory_bytes: u64) -> JobEstimate {
+        JobEstimate {
+            segments: 10,
+            concurrent_segments,
+            memory_bytes,
+            disk_bytes: 0,
+            warnings: vec![],
+        }
+    }
+
+    #[test]
+    fn admits_jobs_within_limits() {
+        let limits = JobLimits {
+            max_secs: Some(300),
+            max_concurrent_segments: Some(1),
+            max_memory_bytes: Some(100 * MB),
+        };
+        assert!(limits.admit(300, Some(&estimate(1, 100 * MB))).is_ok());
+        assert!(limits.admit(300, None).is_ok());
+
+        let err = limits.admit(21600, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Requested 21600s of audio, but at most 300s are allowed per job"
+        );
+        assert!(limits.admit(60, Some(&estimate(2, MB))).is_err());
+        let err = limits.admit(60, Some(&estimate(1, 200 * MB))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The job would need 200 MB of memory, but at most 100 MB are allowed"
+        );
+
+        let unlimited = JobLimits::default();
+        assert!(unlimited
+            .admit(21600, Some(&estimate(4, 100_000 * MB)))
+            .is_ok());
+    }
+}
@@ -0,0 +1,846 @@
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tower_http::services::ServeFile;
+use uuid::Uuid;
+
+use crate::audio::opus_stream::OggOpusEncoder;
+use crate::audio::wav::decode_wav;
+use crate::audio::AudioManager;
+use crate::backend::audio_generation_backend::JobQueueView;
+use crate::backend::audio_generation_fanout::{
+    AudioGenerationError, AudioGenerationResult, GenerationMessage,
+};
+use crate::backend::live_renders::LiveRenders;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::queue_estimates::JobQueuePosition;
+use crate::backend::render_manifest::{RenderManifest, RenderStatus};
+use crate::backend::usage::{require_tokens, ApiTokens, Caller, LOCAL_USER};
+use crate::cli::SAMPLING_RATE;
+use crate::storage::Storage;
+
+/// Length of the fade out applied where partial audio is cut, so it does not end in a click.
+const CUT_FADE_SECS: f32 = 0.05;
+
+#[derive(Clone)]
+struct JobRoutesState<S: Storage> {
+    live_renders: LiveRenders,
+    storage: S,
+    generations: broadcast::Sender<GenerationMessage>,
+    queue: JobQueueView,
+}
+
+/// HTTP routes for listing renders and consuming their audio and progress while they run.
+/// Callers only see their own renders, unless they are admins.
+pub fn job_routes<S: Storage>(
+    live_renders: LiveRenders,
+    storage: S,
+    generations: broadcast::Sender<GenerationMessage>,
+    queue: JobQueueView,
+    tokens: ApiTokens,
+) -> Router {
+    let routes = Router::new()
+        .route("/jobs", get(list_jobs::<S>))
+        .route("/jobs/queue", get(list_queue::<S>))
+        .route("/jobs/:id", get(get_job::<S>))
+        .route("/jobs/:id/queue", get(get_queue_position::<S>))
+        .route("/jobs/:id/similar", get(similar_jobs::<S>))
+        .route("/jobs/:id/events", get(job_events::<S>))
+        .route("/jobs/:id/stream.opus", get(stream_opus::<S>))
+        .route("/jobs/:id/audio", get(partial_audio::<S>))
+        .with_state(JobRoutesState {
+            live_renders,
+            storage,
+            generations,
+            queue,
+        });
+    require_tokens(routes, tokens)
+}
+
+/// The manifest of a render the caller can see. The renders of others are reported as not
+/// found, so that their ids cannot be probed.
+async fn visible_manifest<S: Storage>(
+    state: &JobRoutesState<S>,
+    caller: &Caller,
+    id: Uuid,
+) -> Result<RenderManifest, Response> {
+    match RenderManifest::load(&state.storage, id).await {
+        Ok(Some(manifest)) if caller.can_see(manifest.owner()) => Ok(manifest),
+        Ok(_) => Err((StatusCode::NOT_FOUND, format!("Render {id} not found")).into_response()),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()),
+    }
+}
+
+/// The manifests of the caller's renders, newest first, for the job history of the web app.
+async fn list_jobs<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+) -> Response {
+    match RenderManifest::load_all(&state.storage).await {
+        Ok(mut manifests) => {
+            manifests.retain(|manifest| caller.can_see(manifest.owner()));
+            manifests.reverse();
+            Json(manifests).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// The manifest of a single render, for the segment timeline of the web app.
+async fn get_job<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+) -> Response {
+    match visible_manifest(&state, &caller, id).await {
+        Ok(manifest) => Json(manifest).into_response(),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+struct SimilarJobsQuery {
+    /// From 0 to 1, defaults to [DEFAULT_MIN_SIMILARITY].
+    min_similarity: Option<f32>,
+    limit: Option<usize>,
+}
+
+/// Similarity above which renders are listed as similar if the query does not say.
+const DEFAULT_MIN_SIMILARITY: f32 = 0.75;
+const DEFAULT_SIMILAR_LIMIT: usize = 10;
+
+/// The renders that sound most like a finished one, by their acoustic fingerprints.
+async fn similar_jobs<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<SimilarJobsQuery>,
+) -> Response {
+    let manifest = match visible_manifest(&state, &caller, id).await {
+        Ok(manifest) => manifest,
+        Err(response) => return response,
+    };
+    if manifest.fingerprint.is_empty() {
+        let error = format!("Render {id} has no fingerprint, it is not finished or is too old");
+        return (StatusCode::CONFLICT, error).into_response();
+    }
+    let min_similarity = query.min_similarity.unwrap_or(DEFAULT_MIN_SIMILARITY);
+    let owner = (!caller.admin).then_some(caller.user.as_str());
+    let similar = RenderManifest::find_similar(
+        &state.storage,
+        id,
+        &manifest.fingerprint,
+        min_similarity,
+        owner,
+    );
+    match similar.await {
+        Ok(mut similar) => {
+            similar.truncate(query.limit.unwrap_or(DEFAULT_SIMILAR_LIMIT));
+            Json(similar).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// The positions of the jobs the caller can see. They keep their place in the whole queue,
+/// so that the jobs of others still count as ahead of them.
+fn queue_positions<S: Storage>(
+    state: &JobRoutesState<S>,
+    caller: &Caller,
+) -> Vec<JobQueuePosition> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let snapshot = state.queue.snapshot();
+    let users = snapshot
+        .jobs
+        .iter()
+        .map(|job| (job.id.clone(), job.user.clone()))
+        .collect::<HashMap<_, _>>();
+    snapshot
+        .positions(now)
+        .into_iter()
+        .filter(|job| {
+            let user = users.get(&IdPair(job.chat_id, job.id).to_string());
+            caller.can_see(user.cloned().flatten().as_deref().unwrap_or(LOCAL_USER))
+        })
+        .collect()
+}
+
+/// The running job and the ones waiting after it, with when each is expected to start.
+/// Only the jobs of the caller are listed, unless they are an admin.
+async fn list_queue<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+) -> Response {
+    Json(queue_positions(&state, &caller)).into_response()
+}
+
+/// Where a job is in the queue, for clients to show how long until it starts.
+async fn get_queue_position<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = visible_manifest(&state, &caller, id).await {
+        return response;
+    }
+    match queue_positions(&state, &caller)
+        .into_iter()
+        .find(|job| job.id == id)
+    {
+        Some(position) => Json(position).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("Render {id} is not queued")).into_response(),
+    }
+}
+
+/// Follows a render as server-sent events, for clients without a WebSocket, like curl.
+/// Each generation message of the render is sent as an event named after it, with the
+/// message as JSON data. The stream ends with the `result` or `error` event, which is sent
+/// right away for renders that already finished.
+async fn job_events<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+) -> Response {
+    // Subscribing first, so that nothing is missed if the render finishes meanwhile.
+    let mut rx = state.generations.subscribe();
+    let manifest = match visible_manifest(&state, &caller, id).await {
+        Ok(manifest) => manifest,
+        Err(response) => return response,
+    };
+    let finished = match manifest.status {
+        RenderStatus::Completed => Some(GenerationMessage::Result(AudioGenerationResult {
+            id,
+            chat_id: manifest.chat_id,
+            relpath: format!("audios/{id}.wav"),
+        })),
+        RenderStatus::Failed => Some(GenerationMessage::Error(AudioGenerationError {
+            id,
+            chat_id: manifest.chat_id,
+            error: manifest.error.unwrap_or_default(),
+        })),
+        RenderStatus::Running | RenderStatus::Pending => None,
+    };
+    let stream = async_stream::stream! {
+        if let Some(msg) = finished {
+            yield Ok::<_, Infallible>(generation_event(&msg));
+            return;
+        }
+        loop {
+            let msg = match rx.recv().await {
+                Ok(msg) => msg,
+                // Only progress is lost when falling behind, the next one catches up.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            if generation_id(&msg) != id {
+                continue;
+            }
+            yield Ok(generation_event(&msg));
+            if matches!(msg, GenerationMessage::Result(_) | GenerationMessage::Error(_)) {
+                return;
+            }
+        }
+    };
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn generation_id(msg: &GenerationMessage) -> Uuid {
+    match msg {
+        GenerationMessage::Start(msg) => msg.id,
+        GenerationMessage::Progress(msg) => msg.id,
+        GenerationMessage::Error(msg) => msg.id,
+        GenerationMessage::Result(msg) => msg.id,
+        GenerationMessage::Warning(msg) => msg.id,
+        GenerationMessage::Audition(msg) => msg.id,
+    }
+}
+
+fn generation_event(msg: &GenerationMessage) -> Event {
+    let (name, data) = match msg {
+        GenerationMessage::Start(msg) => ("start", serde_json::to_string(msg)),
+        GenerationMessage::Progress(msg) => ("progress", serde_json::to_string(msg)),
+        GenerationMessage::Error(msg) => ("error", serde_json::to_string(msg)),
+        GenerationMessage::Result(msg) => ("result", serde_json::to_string(msg)),
+        GenerationMessage::Warning(msg) => ("warning", serde_json::to_string(msg)),
+        GenerationMessage::Audition(msg) => ("audition", serde_json::to_string(msg)),
+    };
+    Event::default()
+        .event(name)
+        .data(data.expect("Could not serialize msg"))
+}
+
+/// Streams a running render as Ogg/Opus, starting from its beginning and following it
+/// until it finishes, so browsers can play it progressively.
+async fn stream_opus<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = visible_manifest(&state, &caller, id).await {
+        return response;
+    }
+    let Some(render) = state.live_renders.get(id) else {
+        return (StatusCode::NOT_FOUND, format!("Render {id} is not running")).into_response();
+    };
+    let mut encoder = match OggOpusEncoder::new(SAMPLING_RATE as u32) {
+        Ok(encoder) => encoder,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    };
+    let stream = async_stream::stream! {
+        // The headers are sent right away, before any audio is available.
+        yield encoder.push(&[]).map(Bytes::from).map_err(std::io::Error::other);
+        let mut offset = 0;
+        while let Some(chunk) = render.read_from(offset).await {
+            offset += chunk.len();
+            match encoder.push(&chunk) {
+                Ok(bytes) => yield Ok(Bytes::from(bytes)),
+                Err(err) => {
+                    yield Err(std::io::Error::other(err));
+                    return;
+                }
+            }
+        }
+        yield encoder.finish().map(Bytes::from).map_err(std::io::Error::other);
+    };
+    (
+        [(CONTENT_TYPE, "audio/ogg"), (CACHE_CONTROL, "no-cache")],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct PartialAudioQuery {
+    /// Either "now", for all the audio generated so far, or a number of seconds.
+    upto: Option<String>,
+}
+
+/// Returns the audio of a render as a WAV file. For running renders, that is all the
+/// contiguous audio generated so far, faded out at the cut. Finished renders are served
+/// from their file as is, supporting range requests so that players can seek in long
+/// renders and downloads can be resumed.
+async fn partial_audio<S: Storage>(
+    State(state): State<JobRoutesState<S>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<PartialAudioQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = visible_manifest(&state, &caller, id).await {
+        return response;
+    }
+    let limit = match parse_upto(query.upto.as_deref()) {
+        Ok(limit) => limit,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let relpath = format!("audios/{id}.wav");
+    if limit.is_none() && state.live_renders.get(id).is_none() {
+        return match state.storage.exists(&relpath).await {
+            Ok(true) => serve_file(&state.storage, &relpath, method, headers).await,
+            Ok(false) => (StatusCode::NOT_FOUND, format!("Render {id} not found")).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        };
+    }
+    let (mut samples, mut complete) = match state.live_renders.get(id) {
+        Some(render) => match render.snapshot() {
+            Ok(samples) => (samples, false),
+            Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+        },
+        None => match state.storage.read(&relpath).await {
+            Ok(Some(bytes)) => match decode_wav(&bytes) {
+                Ok(samples) => (samples, true),
+                Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+            },
+            Ok(None) => {
+                return (StatusCode::NOT_FOUND, format!("Render {id} not found")).into_response()
+            }
+            Err(err) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            }
+        },
+    };
+    if let Some(limit) = limit {
+        if samples.len() > limit {
+            samples.truncate(limit);
+            complete = false;
+        }
+    }
+    if !complete {
+        fade_out(
+            &mut samples,
+            (CUT_FADE_SECS * SAMPLING_RATE as f32) as usize,
+        );
+    }
+    match AudioManager::default().to_wav(&samples) {
+        Ok(bytes) => ([(CONTENT_TYPE, "audio/wav")], bytes).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Serves a stored file with the content type of its extension, answering range and
+/// conditional requests the same way the `/files` routes do.
+async fn serve_file<S: Storage>(
+    storage: &S,
+    relpath: &str,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    serve_path(storage.path_buf(relpath), method, headers).await
+}
+
+/// Like [serve_file], for a path in the file system.
+pub(crate) async fn serve_path(path: PathBuf, method: Method, headers: HeaderMap) -> Response {
+    let mut req = Request::new(Body::empty());
+    *req.method_mut() = method;
+    *req.headers_mut() = headers;
+    match ServeFile::new(path).try_call(req).await {
+        Ok(response) => response.map(Body::new),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Parses the `upto` query parameter into a max amount of samples, if any.
+fn parse_upto(upto: Option<&str>) -> Result<Option<usize>, String> {
+    match upto {
+        None | Some("now") => Ok(None),
+        Some(secs) => match secs.parse::<f32>() {
+            Ok(secs) if secs >= 0.0 => Ok(Some((secs * SAMPLING_RATE as f32) as usize)),
+            _ => Err(format!(
+                "Invalid upto={secs}, expected \"now\" or a number of seconds"
+            )),
+        },
+    }
+}
+
+fn fade_out(samples: &mut [f32], len: usize) {
+    let len = len.min(samples.len());
+    let start = samples.len() - len;
+    for (i, sample) in samples[start..].iter_mut().enumerate() {
+        *sample *= 1.0 - (i + 1) as f32 / len as f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::to_bytes;
+    use axum::http::header::{ACCEPT_RANGES, CONTENT_RANGE, RANGE};
+
+    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::audio_generation_backend::{
+        AudioGenerationBackend, AudioGenerationRequest, BackendInboundMsg,
+    };
+    use crate::backend::audio_generation_fanout::AudioGenerationProgress;
+    use crate::backend::render_manifest::SimilarRender;
+    use crate::backend::usage::LOCAL_USER;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn state(live_renders: &LiveRenders) -> State<JobRoutesState<AppFs>> {
+        State(JobRoutesState {
+            live_renders: live_renders.clone(),
+            storage: AppFs::new_tmp(),
+            generations: broadcast::channel(10).0,
+            queue: AudioGenerationBackend::new(DummyJobProcessor::default()).queue(),
+        })
+    }
+
+    fn caller(user: &str, admin: bool) -> Extension<Caller> {
+        Extension(Caller {
+            user: user.to_string(),
+            admin,
+        })
+    }
+
+    fn local() -> Extension<Caller> {
+        caller(LOCAL_USER, false)
+    }
+
+    async fn save_manifest(storage: &AppFs, id: Uuid) -> anyhow::Result<()> {
+        RenderManifest::new(id, Uuid::new_v4(), "".to_string(), 10, None)
+            .save(storage)
+            .await
+    }
+
+    #[tokio::test]
+    async fn lists_jobs_newest_first() -> anyhow::Result<()> {
+        let state = state(&LiveRenders::default());
+        let mut ids = vec![];
+        for created_at in [2, 1, 3] {
+            let mut manifest =
+                RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), "".to_string(), 10, None);
+            manifest.created_at = created_at;
+            manifest.save(&state.storage).await?;
+            ids.push(manifest.id);
+        }
+        state.storage.write("audios/unrelated.json", "{}").await?;
+
+        let response = list_jobs(state, local()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        let manifests: Vec<RenderManifest> = serde_json::from_slice(&body)?;
+        let listed = manifests.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(listed, vec![ids[2], ids[0], ids[1]]);
+        Ok(())
+    }
+
+    async fn listed_ids(
+        state: State<JobRoutesState<AppFs>>,
+        caller: Extension<Caller>,
+    ) -> Vec<Uuid> {
+        let response = list_jobs(state, caller).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let manifests: Vec<RenderManifest> = serde_json::from_slice(&body).unwrap();
+        manifests.iter().map(|m| m.id).collect()
+    }
+
+    #[tokio::test]
+    async fn only_shows_the_jobs_of_the_caller() -> anyhow::Result<()> {
+        let state = state(&LiveRenders::default());
+        let mut ids = vec![];
+        for user in ["alice", "bob"] {
+            let mut manifest =
+                RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), "".to_string(), 10, None);
+            manifest.user = Some(user.to_string());
+            manifest.save(&state.storage).await?;
+            ids.push(manifest.id);
+        }
+
+        assert_eq!(
+            listed_ids(state.clone(), caller("alice", false)).await,
+            vec![ids[0]]
+        );
+        assert_eq!(
+            listed_ids(state.clone(), caller("carol", false)).await,
+            vec![]
+        );
+        assert_eq!(
+            listed_ids(state.clone(), caller("carol", true)).await.len(),
+            2
+        );
+
+        let response = get_job(state.clone(), caller("alice", false), Path(ids[0])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get_job(state.clone(), caller("alice", false), Path(ids[1])).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = job_events(state.clone(), caller("alice", false), Path(ids[1])).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get_job(state, caller("carol", true), Path(ids[1])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_similar_jobs() -> anyhow::Result<()> {
+        let state = state(&LiveRenders::default());
+        let mut ids = vec![];
+        for fingerprint in [
+            vec![0u32; 8],
+            vec![0x0000_000f; 8],
+            vec![0x0000_00ff; 8],
+            vec![],
+        ] {
+            let mut manifest =
+                RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), "".to_string(), 10, None);
+            manifest.fingerprint = fingerprint;
+            manifest.save(&state.storage).await?;
+            ids.push(manifest.id);
+        }
+
+        let query = |min_similarity, limit| {
+            Query(SimilarJobsQuery {
+                min_similarity,
+                limit,
+            })
+        };
+        let response =
+            similar_jobs(state.clone(), local(), Path(ids[0]), query(None, Some(1))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        let similar: Vec<SimilarRender> = serde_json::from_slice(&body)?;
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].id, ids[1]);
+        assert_eq!(similar[0].similarity, 0.875);
+
+        let response =
+            similar_jobs(state.clone(), local(), Path(ids[0]), query(Some(0.9), None)).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(serde_json::from_slice::<Vec<SimilarRender>>(&body)?, vec![]);
+
+        let response = similar_jobs(state.clone(), local(), Path(ids[3]), query(None, None)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = similar_jobs(state, local(), Path(Uuid::new_v4()), query(None, None)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_jobs_that_are_not_queued() -> anyhow::Result<()> {
+        let state = state(&LiveRenders::default());
+        let response = list_queue(state.clone(), local()).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(
+            serde_json::from_slice::<Vec<JobQueuePosition>>(&body)?,
+            vec![]
+        );
+
+        let response = get_queue_position(state, local(), Path(Uuid::new_v4())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_lists_the_queued_jobs_of_the_caller() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(100)));
+        let state = State(JobRoutesState {
+            queue: backend.queue(),
+            ..state(&LiveRenders::default()).0
+        });
+        let (ai_tx, ai_rx) = backend.run();
+        let ids = [0, 1, 2].map(|_| (Uuid::new_v4(), Uuid::new_v4()));
+        for (i, (chat_id, id)) in ids.iter().enumerate() {
+            ai_tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: IdPair(*chat_id, *id).to_string(),
+                prompt: "".to_string(),
+                secs: 4,
+                user: Some(["alice", "bob"][i % 2].to_string()),
+            }))?;
+        }
+        ai_rx.recv()?.unwrap_start();
+
+        let queue = |caller| {
+            let state = state.clone();
+            async move {
+                let response = list_queue(state, caller).await;
+                let body = to_bytes(response.into_body(), usize::MAX).await?;
+                let queue: Vec<JobQueuePosition> = serde_json::from_slice(&body)?;
+                anyhow::Ok(
+                    queue
+                        .into_iter()
+                        .map(|job| (job.id, job.position))
+                        .collect::<Vec<_>>(),
+                )
+            }
+        };
+        assert_eq!(
+            queue(caller("alice", false)).await?,
+            vec![(ids[0].1, 0), (ids[2].1, 2)]
+        );
+        assert_eq!(queue(caller("bob", false)).await?, vec![(ids[1].1, 1)]);
+        assert_eq!(queue(caller("carol", false)).await?, vec![]);
+        assert_eq!(queue(caller("carol", true)).await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_progress_as_server_sent_events() -> anyhow::Result<()> {
+        let state = state(&LiveRenders::default());
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        RenderManifest::new(id, chat_id, "".to_string(), 10, None)
+            .save(&state.storage)
+            .await?;
+        let response = job_events(state.clone(), local(), Path(id)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+
+        let progress = |id, progress| {
+            GenerationMessage::Progress(AudioGenerationProgress {
+                id,
+                chat_id,
+                progress,
+            })
+        };
+        state.generations.send(progress(id, 0.5))?;
+        state.generations.send(progress(Uuid::new_v4(), 0.7))?;
+        state
+            .generations
+            .send(GenerationMessage::Result(AudioGenerationResult {
+                id,
+                chat_id,
+                relpath: format!("audios/{id}.wav"),
+            }))?;
+        // The stream ends with the result.
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body = String::from_utf8(body.to_vec())?;
+        let events = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec!["progress", "result"]);
+        assert!(body.contains("\"progress\":0.5"));
+
+        // Renders that already finished get their outcome right away.
+        let mut manifest = RenderManifest::new(id, chat_id, "".to_string(), 10, None);
+        manifest.status = RenderStatus::Failed;
+        manifest.error = Some("boom".to_string());
+        manifest.save(&state.storage).await?;
+        let response = job_events(state, local(), Path(id)).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        let body = String::from_utf8(body.to_vec())?;
+        assert!(body.starts_with("event: error\n"));
+        assert!(body.contains("\"error\":\"boom\""));
+        Ok(())
+    }
+
+    async fn wav_samples(response: Response) -> anyhow::Result<Vec<f32>> {
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        decode_wav(&body).map_err(|err| anyhow::anyhow!(err))
+    }
+
+    #[tokio::test]
+    async fn streams_running_renders() -> anyhow::Result<()> {
+        let live_renders = LiveRenders::default();
+        let id = Uuid::new_v4();
+        live_renders.start(id);
+        live_renders.push(id, &vec![0.1; SAMPLING_RATE]);
+
+        let state = state(&live_renders);
+        save_manifest(&state.storage, id).await?;
+        let response = stream_opus(state.clone(), local(), Path(Uuid::new_v4())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = stream_opus(state, local(), Path(id)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "audio/ogg");
+        live_renders.push(id, &vec![0.1; SAMPLING_RATE]);
+        live_renders.finish(id);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        assert!(body.starts_with(b"OggS"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_partial_audio_faded_at_the_cut() -> anyhow::Result<()> {
+        let live_renders = LiveRenders::default();
+        let id = Uuid::new_v4();
+        live_renders.start(id);
+        live_renders.push(id, &vec![1.0; 2 * SAMPLING_RATE]);
+        let state = state(&live_renders);
+        save_manifest(&state.storage, id).await?;
+        let query = |upto: &str| {
+            Query(PartialAudioQuery {
+                upto: Some(upto.to_string()),
+            })
+        };
+
+        let samples = wav_samples(
+            partial_audio(
+                state.clone(),
+                local(),
+                Path(id),
+                query("now"),
+                Method::GET,
+                HeaderMap::new(),
+            )
+            .await,
+        )
+        .await?;
+        assert_eq!(samples.len(), 2 * SAMPLING_RATE);
+        assert_eq!(samples[0], 1.0);
+        assert_eq!(*samples.last().unwrap(), 0.0);
+
+        let samples = wav_samples(
+            partial_audio(
+                state.clone(),
+                local(),
+                Path(id),
+                query("0.5"),
+                Method::GET,
+                HeaderMap::new(),
+            )
+            .await,
+        )
+        .await?;
+        assert_eq!(samples.len(), SAMPLING_RATE / 2);
+
+        let response = partial_audio(
+            state.clone(),
+            local(),
+            Path(id),
+            query("soon"),
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = partial_audio(
+            state,
+            local(),
+            Path(Uuid::new_v4()),
+            query("now"),
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_ranges_of_finished_renders() -> anyhow::Result<()> {
+        let state = state(&LiveRenders::default());
+        let id = Uuid::new_v4();
+        save_manifest(&state.storage, id).await?;
+        let wav = AudioManager::default().to_wav(&[0.5; SAMPLING_RATE])?;
+        state
+            .storage
+            .write(&format!("audios/{id}.wav"), &wav)
+            .await?;
+        let get = |range: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(range) = range {
+                headers.insert(RANGE, range.parse().unwrap());
+            }
+            partial_audio(
+                state.clone(),
+                local(),
+                Path(id),
+                Query(PartialAudioQuery { upto: None }),
+                Method::GET,
+                headers,
+            )
+        };
+
+        let response = get(None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "audio/wav");
+        assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+        assert_eq!(to_bytes(response.into_body(), usize::MAX).await?, wav);
+
+        let response = get(Some("bytes=100-199")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[CONTENT_RANGE],
+            format!("bytes 100-199/{}", wav.len())
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(body, wav[100..200]);
+
+        let response = get(Some(&format!("bytes={}-", wav.len() + 10))).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::audio::audio_sink::AudioSink;
+use crate::audio::spill_buffer::{SpillBuffer, DEFAULT_MEMORY_LIMIT};
+
+/// Most samples handed to a reader at once, so that late readers of long renders do not
+/// load all of it.
+const READ_CHUNK: usize = 1 << 16;
+
+/// Audio of a render that is still running, growing as the backend generates it.
+pub struct LiveRender {
+    state: Mutex<LiveRenderState>,
+    updated: Notify,
+}
+
+struct LiveRenderState {
+    samples: SpillBuffer,
+    finished: bool,
+}
+
+impl LiveRender {
+    fn new(spill_dir: PathBuf) -> Self {
+        let state = LiveRenderState {
+            samples: SpillBuffer::new(spill_dir, DEFAULT_MEMORY_LIMIT),
+            finished: false,
+        };
+        Self {
+            state: Mutex::new(state),
+            updated: Notify::new(),
+        }
+    }
+
+    /// Waits until there is audio after `offset` and returns some of it. Returns None once
+    /// the render finished and all its audio was read.
+    pub async fn read_from(&self, offset: usize) -> Option<Vec<f32>> {
+        loop {
+            // Created before checking the state so that updates in between are not missed.
+            let updated = self.updated.notified();
+            {
+                let state = self.state.lock().unwrap();
+                let len = state.samples.len();
+                if len > offset {
+                    match state.samples.read(offset..len.min(offset + READ_CHUNK)) {
+                        Ok(samples) => return Some(samples),
+                        Err(err) => {
+                            warn!("Could not read a live render: {err}");
+                            return None;
+                        }
+                    }
+                }
+                if state.finished {
+                    return None;
+                }
+            }
+            updated.await;
+        }
+    }
+
+    /// All the audio generated so far.
+    pub fn snapshot(&self) -> Result<Vec<f32>, String> {
+        self.state.lock().unwrap().samples.to_vec()
+    }
+
+    fn push(&self, chunk: &[f32]) {
+        if let Err(err) = self.state.lock().unwrap().samples.push(chunk) {
+            warn!("Could not keep the audio of a live render: {err}");
+        }
+        self.updated.notify_waiters();
+    }
+
+    fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.updated.notify_waiters();
+    }
+}
+
+/// The renders that are currently running, so that their audio can be consumed before
+/// they finish.
+#[derive(Clone, Default)]
+pub struct LiveRenders {
+    renders: Arc<Mutex<HashMap<Uuid, Arc<LiveRender>>>>,
+    spill_dir: Option<PathBuf>,
+}
+
+impl LiveRenders {
+    /// Moves the audio of long renders to `dir` instead of the temporary directory of the
+    /// system.
+    pub fn with_spill_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+
+    pub fn start(&self, id: Uuid) {
+        let spill_dir = self.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+        let mut renders = self.renders.lock().unwrap();
+        renders.insert(id, Arc::new(LiveRender::new(spill_dir)));
+    }
+
+    pub fn push(&self, id: Uuid, chunk: &[f32]) {
+        if let Some(render) = self.get(id) {
+            render.push(chunk);
+        }
+    }
+
+    /// Stops tracking a render. Readers that already had it still get all its audio.
+    pub fn finish(&self, id: Uuid) {
+        if let Some(render) = self.renders.lock().unwrap().remove(&id) {
+            render.finish();
+        }
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Arc<LiveRender>> {
+        self.renders.lock().unwrap().get(&id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn readers_wait_for_new_audio() {
+        let renders = LiveRenders::default();
+        let id = Uuid::new_v4();
+        renders.start(id);
+        renders.push(id, &[1.0, 2.0]);
+        let render = renders.get(id).unwrap();
+        assert_eq!(render.read_from(0).await, Some(vec![1.0, 2.0]));
+
+        let reader = tokio::spawn(async move {
+            let mut read = vec![];
+            while let Some(chunk) = render.read_from(2 + read.len()).await {
+                read.extend(chunk);
+            }
+            read
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        renders.push(id, &[3.0]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        renders.push(id, &[4.0, 5.0]);
+        renders.finish(id);
+
+        assert_eq!(reader.await.unwrap(), vec![3.0, 4.0, 5.0]);
+        assert!(renders.get(id).is_none());
+    }
+}
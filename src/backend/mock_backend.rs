@@ -0,0 +1,253 @@
+//! A [JobProcessor] that makes up audio instead of running the models, for testing the
+//! extended pipeline, the web server and the exporters without downloading any of them.
+//! Enabled by the `mock-backend` feature.
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::audio::audio_sink::{AudioSink, MemorySink};
+use crate::backend::audio_generation_backend::JobProcessor;
+use crate::backend::model_registry::{ModelCapabilities, ModelEntry, ModelRegistry};
+
+/// Sample rate of the audio generated by the models.
+const SAMPLE_RATE: usize = 32000;
+const TONE_AMPLITUDE: f32 = 0.3;
+const NOISE_AMPLITUDE: f32 = 0.05;
+
+/// Generates a sine tone with some noise under it, all decided by the seed and the
+/// prompt, so that the same job always produces the same audio. Jobs finish right away,
+/// reporting progress after each second of audio.
+#[derive(Clone, Debug)]
+pub struct MockJobProcessor {
+    seed: u64,
+    sample_rate: usize,
+}
+
+impl MockJobProcessor {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+
+    /// Generates audio at another sample rate than the one of the models.
+    pub fn with_sample_rate(mut self, sample_rate: usize) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub(crate) fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Frequency of the tone of `prompt`, between 110 and 880Hz.
+    fn frequency(&self, prompt: &str) -> f32 {
+        let octaves = (hash(self.seed, prompt) % 3000) as f32 / 1000.0;
+        110.0 * 2f32.powf(octaves)
+    }
+}
+
+impl JobProcessor for MockJobProcessor {
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<f32>> {
+        let mut sink = MemorySink::new();
+        self.process_streaming(prompt, secs, on_progress, &mut sink)?;
+        Ok(sink.into_inner())
+    }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let step = 2.0 * PI * self.frequency(prompt) / self.sample_rate as f32;
+        let mut noise = Noise(hash(self.seed, prompt) | 1);
+        for sec in 0..secs {
+            let chunk = (sec * self.sample_rate..(sec + 1) * self.sample_rate)
+                .map(|i| {
+                    let tone = (step * i as f32).sin();
+                    tone * TONE_AMPLITUDE + noise.next() * NOISE_AMPLITUDE
+                })
+                .collect::<Vec<_>>();
+            sink.push(&chunk).map_err(ort::Error::new)?;
+            if on_progress((sec + 1) as f32, secs as f32) {
+                return Err(ort::Error::new("Aborted"));
+            }
+        }
+        sink.finalize().map_err(ort::Error::new)
+    }
+
+    fn process_seeded(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seed: u64,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let seeded = Self {
+            seed,
+            ..self.clone()
+        };
+        seeded.process_streaming(prompt, secs, on_progress, sink)
+    }
+}
+
+/// Lists a single installed model, which loads a [MockJobProcessor] with the given seed.
+#[derive(Clone, Debug, Default)]
+pub struct MockModelRegistry {
+    pub seed: u64,
+}
+
+#[async_trait]
+impl ModelRegistry for MockModelRegistry {
+    async fn list(&self) -> anyhow::Result<Vec<ModelEntry>> {
+        Ok(vec![ModelEntry {
+            name: "mock".to_string(),
+            display_name: "Mock".to_string(),
+            installed: true,
+            size_bytes: 0,
+            capabilities: ModelCapabilities {
+                max_secs: 30,
+                stereo: false,
+                melody: false,
+            },
+        }])
+    }
+
+    async fn load(&self, name: &str) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        match name {
+            "mock" => Ok(Arc::new(MockJobProcessor::new(self.seed))),
+            _ => Err(anyhow::anyhow!("Unknown model {name}")),
+        }
+    }
+}
+
+/// FNV-1a hash of the seed and the prompt, which unlike the hashers of the standard
+/// library is the same across builds.
+fn hash(seed: u64, prompt: &str) -> u64 {
+    seed.to_le_bytes()
+        .iter()
+        .chain(prompt.as_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// Xorshift generator of white noise between -1 and 1.
+struct Noise(u64);
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn generate(processor: &MockJobProcessor, prompt: &str, secs: usize) -> Vec<f32> {
+        processor
+            .process(prompt, secs, Box::new(|_, _| false))
+            .unwrap()
+    }
+
+    #[test]
+    fn generates_the_same_audio_for_the_same_job() {
+        let processor = MockJobProcessor::new(7).with_sample_rate(8000);
+        let audio = generate(&processor, "lofi beats", 3);
+        assert_eq!(audio.len(), 3 * 8000);
+        assert!(audio
+            .iter()
+            .all(|s| s.abs() <= TONE_AMPLITUDE + NOISE_AMPLITUDE));
+        assert_eq!(audio, generate(&processor, "lofi beats", 3));
+        // Shorter jobs are the beginning of the longer ones.
+        assert_eq!(generate(&processor, "lofi beats", 1), audio[..8000]);
+
+        assert_ne!(audio, generate(&processor, "ambient pads", 3));
+        let reseeded = MockJobProcessor::new(8).with_sample_rate(8000);
+        assert_ne!(audio, generate(&reseeded, "lofi beats", 3));
+    }
+
+    #[test]
+    fn reports_progress_and_aborts() {
+        let processor = MockJobProcessor::new(0).with_sample_rate(100);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let progress_calls = calls.clone();
+        let result = processor.process(
+            "",
+            5,
+            Box::new(move |elapsed, total| {
+                assert_eq!(total, 5.0);
+                progress_calls.fetch_add(1, Ordering::SeqCst);
+                elapsed >= 2.0
+            }),
+        );
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn generates_one_variation_per_seed() {
+        let processor = MockJobProcessor::new(0).with_sample_rate(100);
+        let progress = Arc::new(std::sync::Mutex::new(vec![]));
+        let reported = progress.clone();
+        let variations = processor
+            .process_variations(
+                "lofi beats",
+                2,
+                &[4, 5],
+                Box::new(move |elapsed, total| {
+                    reported.lock().unwrap().push((elapsed, total));
+                    false
+                }),
+            )
+            .unwrap();
+        assert_eq!(variations.len(), 2);
+        assert_eq!(
+            variations[0],
+            generate(
+                &MockJobProcessor::new(4).with_sample_rate(100),
+                "lofi beats",
+                2
+            )
+        );
+        assert_eq!(
+            variations[1],
+            generate(
+                &MockJobProcessor::new(5).with_sample_rate(100),
+                "lofi beats",
+                2
+            )
+        );
+        assert_eq!(
+            *progress.lock().unwrap(),
+            vec![(1.0, 4.0), (2.0, 4.0), (3.0, 4.0), (4.0, 4.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_the_mock_model() -> anyhow::Result<()> {
+        let registry = MockModelRegistry { seed: 3 };
+        let processor = registry.load(&registry.list().await?[0].name).await?;
+        let audio = processor.process("", 1, Box::new(|_, _| false))?;
+        assert_eq!(audio, generate(&MockJobProcessor::new(3), "", 1));
+        assert!(registry.load("medium").await.is_err());
+        Ok(())
+    }
+}
@@ -1,15 +1,64 @@
+pub use archive::ArchiveConfig;
+pub use artwork::{ArtworkConfig, ArtworkProvider, ArtworkRequest};
 pub use audio_generation_backend::JobProcessor;
-pub use extended_audio_backend::{ExtendedJobProcessor, MusicGPTSegmentGenerator};
+pub use extended_audio_backend::ExtendedJobProcessor;
+#[cfg(feature = "mock-backend")]
+pub use fault_injection::{Fault, FaultInjectingJobProcessor};
+pub use job_graph::{run_graph, GraphRun, JobGraph, ProcessorGenerator, StepState};
+pub use job_limits::JobLimits;
+#[cfg(feature = "mock-backend")]
+pub use mock_backend::{MockJobProcessor, MockModelRegistry};
+pub use model_registry::{
+    verify_model, ModelCapabilities, ModelEntry, ModelRegistry, ModelVersion,
+};
+pub use notifier::NotifierConfig;
+pub use provenance::Provenance;
+pub use proxy::ProxyConfig;
+pub use reload::LiveSettings;
+pub use remote_sync::SyncConfig;
+pub use render_manifest::{PostChain, RenderManifest, RenderSettings};
+pub use scheduler::Schedule;
 pub use server::*;
+pub use session_pool::SessionPool;
+pub use tls::TlsConfig;
+pub use usage::{ApiTokens, Quotas};
 
 #[cfg(test)]
-mod _test_utils;
+pub(crate) mod _test_utils;
+mod admin;
+mod archive;
+mod artwork;
 mod audio_generation_backend;
 mod audio_generation_fanout;
 mod extended_audio_backend;
+#[cfg(any(test, feature = "mock-backend"))]
+mod fault_injection;
+#[cfg(test)]
+mod golden_audio;
+mod job_graph;
+mod job_limits;
+mod job_routes;
+mod live_renders;
+#[cfg(any(test, feature = "mock-backend"))]
+mod mock_backend;
+mod model_registry;
 mod music_gpt_chat;
 mod music_gpt_ws_handler;
+mod notifier;
+mod openapi;
+mod podcast;
+mod provenance;
+mod proxy;
+mod queue_estimates;
+mod reload;
+mod remote_sync;
+mod render_manifest;
+mod s3;
+mod scheduler;
 mod server;
+mod session_pool;
+mod tls;
+mod usage;
 mod ws_handler;
 
 #[cfg(test)]
@@ -18,9 +67,9 @@ mod tests {
     use std::path::{Path, PathBuf};
     use std::time::Duration;
 
-    use crate::backend::RunWebServerOptions;
-    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::_test_utils::{DummyJobProcessor, DummyModelRegistry};
     use crate::backend::server::run_web_server;
+    use crate::backend::{JobLimits, RunWebServerOptions};
     use crate::storage::AppFs;
 
     #[ignore]
@@ -31,11 +80,32 @@ mod tests {
         let options = RunWebServerOptions {
             device: "Cpu".to_string(),
             name: "Dummy".to_string(),
+            selection_reason: "Dummy selected for testing".to_string(),
             port: 8642,
             auto_open: false,
             expose: false,
+            limits: JobLimits::default(),
+            render_settings: Default::default(),
+            tokens: Default::default(),
+            quotas: Default::default(),
+            proxy: Default::default(),
+            tls: None,
+            schedules: vec![],
+            notifiers: vec![],
+            podcast_url: None,
+            sync: None,
+            archive: None,
+            api_docs: false,
+            reloads: None,
         };
-        run_web_server(storage.root.clone(), storage, processor, options).await
+        run_web_server(
+            storage.root.clone(),
+            storage,
+            processor,
+            DummyModelRegistry,
+            options,
+        )
+        .await
     }
 
     #[ignore]
@@ -0,0 +1,319 @@
+use std::sync::{Arc, RwLock};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::audio::audio_sink::AudioSink;
+use crate::backend::audio_generation_backend::{JobCheckpoint, JobProcessor, SegmentEdit};
+use crate::backend::job_limits::JobEstimate;
+use crate::backend::render_manifest::PostChain;
+use crate::musicgen::Continuation;
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ModelCapabilities {
+    /// Max seconds of audio that the model can produce in a single inference pass.
+    pub max_secs: usize,
+    pub stereo: bool,
+    pub melody: bool,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ModelEntry {
+    pub name: String,
+    pub display_name: String,
+    pub installed: bool,
+    pub size_bytes: u64,
+    pub capabilities: ModelCapabilities,
+}
+
+/// Identifies the exact model files used for generating audio.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ModelVersion {
+    pub name: String,
+    /// Human-readable version, like the revision a model was downloaded from.
+    pub revision: String,
+    /// SHA-256 derived from the contents of all the model files.
+    pub hash: String,
+}
+
+impl ModelVersion {
+    pub fn short_hash(&self) -> &str {
+        &self.hash[..self.hash.len().min(12)]
+    }
+}
+
+/// Knows which models can be used for generating audio and how to load them.
+#[async_trait]
+pub trait ModelRegistry: Send + Sync + 'static {
+    async fn list(&self) -> anyhow::Result<Vec<ModelEntry>>;
+    async fn load(&self, name: &str) -> anyhow::Result<Arc<dyn JobProcessor>>;
+}
+
+/// Outcome of loading a model and generating a short sample with it.
+#[derive(Clone, Debug)]
+pub struct ModelVerification {
+    pub name: String,
+    pub load_time: Duration,
+    pub generation_time: Duration,
+    pub error: Option<String>,
+}
+
+/// Loads a model and runs a 1 second generation with it, for catching broken downloads
+/// or incompatible models before queueing real work.
+pub async fn verify_model(registry: &dyn ModelRegistry, name: &str) -> ModelVerification {
+    let mut result = ModelVerification {
+        name: name.to_string(),
+        load_time: Duration::ZERO,
+        generation_time: Duration::ZERO,
+        error: None,
+    };
+    let start = Instant::now();
+    let processor = match registry.load(name).await {
+        Ok(processor) => processor,
+        Err(err) => {
+            result.error = Some(format!("Could not load the model: {err}"));
+            return result;
+        }
+    };
+    result.load_time = start.elapsed();
+
+    let start = Instant::now();
+    let generated = tokio::task::spawn_blocking(move || {
+        processor.process("Smoke test", 1, Box::new(|_, _| false))
+    })
+    .await;
+    result.generation_time = start.elapsed();
+    result.error = match generated {
+        Ok(Ok(samples)) if samples.is_empty() => Some("No audio was generated".to_string()),
+        Ok(Ok(samples)) if samples.iter().any(|s| !s.is_finite()) => {
+            Some("The generated audio contains invalid samples".to_string())
+        }
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => Some(format!("Generation failed: {err}")),
+        Err(err) => Some(format!("Generation panicked: {err}")),
+    };
+    result
+}
+
+/// [JobProcessor] whose underlying implementation can be replaced at runtime.
+/// Jobs that are already running when the swap happens finish with the old processor.
+#[derive(Clone)]
+pub struct SwappableJobProcessor {
+    inner: Arc<RwLock<Arc<dyn JobProcessor>>>,
+}
+
+impl SwappableJobProcessor {
+    pub fn new(processor: Arc<dyn JobProcessor>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(processor)),
+        }
+    }
+
+    pub fn swap(&self, processor: Arc<dyn JobProcessor>) {
+        *self.inner.write().unwrap() = processor;
+    }
+}
+
+impl JobProcessor for SwappableJobProcessor {
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<f32>> {
+        // Immediately drop the lock so that swapping does not wait for the job to finish.
+        let processor = self.inner.read().unwrap().clone();
+        processor.process(prompt, secs, on_progress)
+    }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.process_streaming(prompt, secs, on_progress, sink)
+    }
+
+    fn process_exact(
+        &self,
+        prompt: &str,
+        secs: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.process_exact(prompt, secs, on_progress, sink)
+    }
+
+    fn resume_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        checkpoint: &JobCheckpoint,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.resume_streaming(prompt, secs, checkpoint, on_progress, sink)
+    }
+
+    fn process_seeded(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seed: u64,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.process_seeded(prompt, secs, seed, on_progress, sink)
+    }
+
+    fn process_continuing(
+        &self,
+        prompt: &str,
+        secs: usize,
+        from: Option<Continuation>,
+        context: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<Option<Continuation>> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.process_continuing(prompt, secs, from, context, on_progress, sink)
+    }
+
+    fn process_variations(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seeds: &[u64],
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<Vec<f32>>> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.process_variations(prompt, secs, seeds, on_progress)
+    }
+
+    fn edit_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        edit: &SegmentEdit,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.edit_streaming(prompt, secs, edit, on_progress, sink)
+    }
+
+    fn model_version(&self) -> Option<ModelVersion> {
+        self.inner.read().unwrap().model_version()
+    }
+
+    fn segment_prompts(&self, prompt: &str, secs: usize) -> Vec<String> {
+        self.inner.read().unwrap().segment_prompts(prompt, secs)
+    }
+
+    fn estimate(&self, secs: usize) -> Option<JobEstimate> {
+        self.inner.read().unwrap().estimate(secs)
+    }
+
+    fn recover(&self) -> ort::Result<()> {
+        let processor = self.inner.read().unwrap().clone();
+        processor.recover()
+    }
+
+    fn terminate(&self, worker: ThreadId) {
+        let processor = self.inner.read().unwrap().clone();
+        processor.terminate(worker)
+    }
+
+    fn max_concurrent_jobs(&self) -> usize {
+        self.inner.read().unwrap().max_concurrent_jobs()
+    }
+
+    fn with_post_chain(&self, post: &PostChain) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        self.inner.read().unwrap().with_post_chain(post)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::_test_utils::{DummyJobProcessor, DummyModelRegistry};
+
+    use super::*;
+
+    struct ConstProcessor(f32);
+
+    impl JobProcessor for ConstProcessor {
+        fn process(
+            &self,
+            _prompt: &str,
+            secs: usize,
+            _on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        ) -> ort::Result<Vec<f32>> {
+            Ok(vec![self.0; secs])
+        }
+    }
+
+    #[test]
+    fn swaps_the_processor() -> anyhow::Result<()> {
+        let processor = SwappableJobProcessor::new(Arc::new(ConstProcessor(1.0)));
+        let result = processor.process("", 2, Box::new(|_, _| false))?;
+        assert_eq!(result, vec![1.0, 1.0]);
+
+        processor.swap(Arc::new(ConstProcessor(2.0)));
+        let result = processor.process("", 2, Box::new(|_, _| false))?;
+        assert_eq!(result, vec![2.0, 2.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn running_jobs_finish_with_the_old_processor() -> anyhow::Result<()> {
+        let processor =
+            SwappableJobProcessor::new(Arc::new(DummyJobProcessor::new(Duration::from_millis(50))));
+        let processor_clone = processor.clone();
+        let handle =
+            std::thread::spawn(move || processor_clone.process("", 4, Box::new(|_, _| false)));
+
+        std::thread::sleep(Duration::from_millis(20));
+        processor.swap(Arc::new(ConstProcessor(2.0)));
+
+        let result = handle.join().unwrap()?;
+        assert_eq!(result, vec![0.0, 1.0, 2.0, 3.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifies_models() {
+        let result = verify_model(&DummyModelRegistry, "dummy").await;
+        assert_eq!(result.name, "dummy");
+        assert_eq!(result.error, None);
+
+        struct BrokenRegistry;
+
+        #[async_trait]
+        impl ModelRegistry for BrokenRegistry {
+            async fn list(&self) -> anyhow::Result<Vec<ModelEntry>> {
+                Ok(vec![])
+            }
+
+            async fn load(&self, name: &str) -> anyhow::Result<Arc<dyn JobProcessor>> {
+                match name {
+                    "nan" => Ok(Arc::new(ConstProcessor(f32::NAN))),
+                    _ => Err(anyhow::anyhow!("corrupted file")),
+                }
+            }
+        }
+
+        let result = verify_model(&BrokenRegistry, "nan").await;
+        assert!(result.error.unwrap().contains("invalid samples"));
+        let result = verify_model(&BrokenRegistry, "other").await;
+        assert!(result.error.unwrap().contains("corrupted file"));
+    }
+}
@@ -1,18 +1,34 @@
 use std::fmt::{Display, Formatter};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use tracing::{error, info};
+use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 
-use crate::backend::audio_generation_backend::{AudioGenerationRequest, BackendInboundMsg};
-use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::audio::extended_generation::AuditionVerdict;
+use crate::audio::inpaint::{InpaintRegion, DEFAULT_CROSSFADE_SECS};
+use crate::audio::prompt_hints::PromptHints;
+use crate::audio::wav::decode_wav;
+use crate::backend::admin::Maintenance;
+use crate::backend::audio_generation_backend::{
+    AudioGenerationRequest, BackendInboundMsg, Inpainting, JobProcessor, SegmentEdit,
+};
+use crate::backend::audio_generation_fanout::{AudioGenerationWarning, GenerationMessage};
+use crate::backend::job_limits::JobLimits;
+use crate::backend::model_registry::{ModelEntry, ModelRegistry, SwappableJobProcessor};
 use crate::backend::music_gpt_chat::{Chat, ChatEntry};
+use crate::backend::notifier;
+use crate::backend::render_manifest::{RenderManifest, RenderStatus};
+use crate::backend::usage::{today, Quotas, UserUsage};
 use crate::backend::ws_handler::WsHandler;
+use crate::cli::SAMPLING_RATE;
+use crate::disk_space;
 use crate::storage::Storage;
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -25,7 +41,16 @@ pub struct GenerateAudioRequest {
     pub id: Uuid,
     pub chat_id: Uuid,
     pub prompt: String,
+    /// 0 reads the duration from the prompt, like "a three and a half minute track". The
+    /// start of the render echoes the one used.
     pub secs: usize,
+    /// Notifiers told when the render ends, on top of the global ones.
+    #[serde(default)]
+    pub notify: Vec<String>,
+    /// Holds back each segment of extended renders until an `AuditionVerdict` on it
+    /// arrives.
+    #[serde(default)]
+    pub audition: bool,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -34,12 +59,56 @@ pub struct AbortGenerationRequest {
     pub chat_id: Uuid,
 }
 
+/// Generates a finished render again as a new one, with one of its segments generated
+/// from another prompt or seed and the others reused as they were.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct RegenerateSegmentRequest {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    /// The render whose segment is replaced.
+    pub render: Uuid,
+    pub segment: usize,
+    pub prompt: String,
+    /// Seed the segment is sampled with, or the configured one if none.
+    pub seed: Option<u64>,
+}
+
+/// Generates a finished render again as a new one, with the audio between `start_secs`
+/// and `end_secs` generated anew and crossfaded back in.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct InpaintRequest {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    /// The render whose audio is inpainted.
+    pub render: Uuid,
+    pub start_secs: f32,
+    pub end_secs: f32,
+    /// Prompt of the replacement, or the one of the render if none.
+    pub prompt: Option<String>,
+}
+
+/// Keeps the segment of an auditioned render, or generates it again.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AuditionVerdictRequest {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub segment: usize,
+    pub accept: bool,
+}
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct Info {
     pub model: String,
+    /// Why this model is the one in use, e.g. it was automatically picked based on the hardware.
+    pub selection_reason: String,
     pub device: String,
 }
 
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct UseModelRequest {
+    pub name: String,
+}
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct SetChatMetadataRequest {
     pub chat_id: Uuid,
@@ -53,9 +122,14 @@ pub enum InboundMsg {
     GenerateAudioNewChat(GenerateAudioRequest),
     GenerateAudio(GenerateAudioRequest),
     AbortGeneration(AbortGenerationRequest),
+    RegenerateSegment(RegenerateSegmentRequest),
+    Inpaint(InpaintRequest),
+    AuditionVerdict(AuditionVerdictRequest),
     GetChat(ChatRequest),
     SetChatMetadata(SetChatMetadataRequest),
     DelChat(ChatRequest),
+    ListModels,
+    UseModel(UseModelRequest),
 }
 
 // === Outbound ===
@@ -66,6 +140,7 @@ pub enum OutboundMsg {
     Info(Info),
     Chat((Chat, Vec<ChatEntry>)),
     Chats(Vec<Chat>),
+    Models(Vec<ModelEntry>),
     Error(String),
 }
 
@@ -74,7 +149,246 @@ pub struct MusicGptWsHandler<S: Storage> {
     pub storage: S,
     pub ai_broadcast_tx: tokio::sync::broadcast::Sender<GenerationMessage>,
     pub ai_tx: Sender<BackendInboundMsg>,
-    pub info: Info,
+    pub info: Arc<RwLock<Info>>,
+    pub info_broadcast_tx: tokio::sync::broadcast::Sender<Info>,
+    pub processor: SwappableJobProcessor,
+    pub registry: Arc<dyn ModelRegistry>,
+    /// Shared by every connection, and replaced when the settings are reloaded.
+    pub limits: Arc<RwLock<JobLimits>>,
+    pub quotas: Quotas,
+    /// Rejects new jobs while the server is under maintenance.
+    pub maintenance: Maintenance,
+    /// The user of the connection, who new renders are accounted to.
+    pub user: String,
+    /// Whether the user administers the server, which lets them switch the model of
+    /// everyone.
+    pub admin: bool,
+}
+
+impl<S: Storage> MusicGptWsHandler<S> {
+    /// Logs under the same job id as the backend, so that a render can be followed from
+    /// its request until its audio is saved.
+    #[instrument(skip_all, fields(job_id = %IdPair(req.chat_id, req.id)))]
+    pub(crate) async fn request_generation(
+        &self,
+        mut req: GenerateAudioRequest,
+    ) -> anyhow::Result<()> {
+        self.maintenance.admit()?;
+        // Requesting a render of the same user that did not finish resumes it, with the
+        // prompt and length it was admitted with. Ids of any other render are taken.
+        let existing = RenderManifest::load(&self.storage, req.id).await?;
+        if let Some(manifest) = &existing {
+            if manifest.owner() != self.user || manifest.status == RenderStatus::Completed {
+                return Err(anyhow!("Render {} already exists", req.id));
+            }
+            req.prompt = manifest.prompt.clone();
+            req.secs = manifest.secs;
+        }
+        if req.secs == 0 {
+            let hints = PromptHints::parse(&req.prompt);
+            let Some(secs) = hints.secs else {
+                return Err(anyhow!("The prompt does not say how long the render is"));
+            };
+            info!("Read from the prompt: {hints}");
+            req.secs = secs.ceil() as usize;
+        }
+        let estimate = self.processor.estimate(req.secs);
+        self.limits
+            .read()
+            .unwrap()
+            .admit(req.secs, estimate.as_ref())?;
+        if let Some(estimate) = &estimate {
+            disk_space::ensure_space(&self.storage, estimate.disk_bytes).await?;
+            for warning in &estimate.warnings {
+                warn!("{warning}");
+                let _ =
+                    self.ai_broadcast_tx
+                        .send(GenerationMessage::Warning(AudioGenerationWarning {
+                            id: req.id,
+                            chat_id: req.chat_id,
+                            warning: warning.clone(),
+                        }));
+            }
+        }
+        // Resuming is only allowed with the model the render started with, and was
+        // already admitted by the quotas when it was first requested.
+        let checkpoint = match existing {
+            Some(manifest) => {
+                manifest.check_resume(self.processor.model_version().as_ref())?;
+                match manifest.load_checkpoint(&self.storage).await {
+                    Ok(checkpoint) => checkpoint,
+                    Err(err) => {
+                        warn!("Starting from scratch: {err}");
+                        None
+                    }
+                }
+            }
+            None => {
+                let usage = UserUsage::load(&self.storage, &self.user).await?;
+                self.quotas.admit(&usage, today(), req.secs)?;
+                None
+            }
+        };
+        if !req.notify.is_empty() {
+            notifier::subscribe(&self.storage, req.id, &req.notify).await?;
+        }
+        let audition = req.audition;
+        let req = AudioGenerationRequest {
+            id: IdPair(req.chat_id, req.id).to_string(),
+            prompt: req.prompt,
+            secs: req.secs,
+            user: Some(self.user.clone()),
+        };
+        self.ai_tx.send(match checkpoint {
+            _ if audition => BackendInboundMsg::Audition((req, checkpoint)),
+            Some(checkpoint) => BackendInboundMsg::Resume((req, checkpoint)),
+            None => BackendInboundMsg::Request(req),
+        })?;
+        Ok(())
+    }
+
+    /// Queues a render of the same prompt as `req.render`, generating only the edited
+    /// segment and stitching it with the audio the others were generated with.
+    #[instrument(skip_all, fields(job_id = %IdPair(req.chat_id, req.id)))]
+    pub(crate) async fn request_segment_edit(
+        &self,
+        req: RegenerateSegmentRequest,
+    ) -> anyhow::Result<()> {
+        self.maintenance.admit()?;
+        let Some(source) = RenderManifest::load(&self.storage, req.render).await? else {
+            return Err(anyhow!("Render {} not found", req.render));
+        };
+        if source.status != RenderStatus::Completed {
+            return Err(anyhow!(
+                "Render {} did not finish, its segments cannot be edited",
+                source.id
+            ));
+        }
+        source.check_resume(self.processor.model_version().as_ref())?;
+        let Some(recipe) = &source.recipe else {
+            return Err(anyhow!(
+                "Render {} does not record its segments, they cannot be edited",
+                source.id
+            ));
+        };
+        let mut prompts = recipe.segment_prompts.clone();
+        if req.segment >= prompts.len() {
+            return Err(anyhow!(
+                "Render {} has {} segments, there is no segment {}",
+                source.id,
+                prompts.len(),
+                req.segment
+            ));
+        }
+        // Renders of a single segment are generated again as a whole.
+        let audio = source.load_segments(&self.storage).await?;
+        if prompts.len() > 1 && audio.len() < prompts.len() {
+            return Err(anyhow!(
+                "Render {} did not keep the audio of its segments, they cannot be edited",
+                source.id
+            ));
+        }
+        let estimate = self.processor.estimate(source.secs);
+        self.limits
+            .read()
+            .unwrap()
+            .admit(source.secs, estimate.as_ref())?;
+        let usage = UserUsage::load(&self.storage, &self.user).await?;
+        self.quotas.admit(&usage, today(), source.secs)?;
+
+        let mut seeds = recipe.segment_seeds.clone();
+        seeds.resize(prompts.len(), None);
+        seeds[req.segment] = req.seed;
+        prompts[req.segment] = req.prompt;
+        let edit = SegmentEdit {
+            segment: req.segment,
+            prompts,
+            seeds,
+            audio,
+        };
+        let job = AudioGenerationRequest {
+            id: IdPair(req.chat_id, req.id).to_string(),
+            prompt: source.prompt,
+            secs: source.secs,
+            user: Some(self.user.clone()),
+        };
+        self.ai_tx.send(BackendInboundMsg::Edit((job, edit)))?;
+        Ok(())
+    }
+
+    /// Queues a render of the same prompt as `req.render`, generating only the stretch
+    /// of audio in `req` and crossfading it into the rest.
+    #[instrument(skip_all, fields(job_id = %IdPair(req.chat_id, req.id)))]
+    pub(crate) async fn request_inpainting(&self, req: InpaintRequest) -> anyhow::Result<()> {
+        self.maintenance.admit()?;
+        let Some(source) = RenderManifest::load(&self.storage, req.render).await? else {
+            return Err(anyhow!("Render {} not found", req.render));
+        };
+        if source.status != RenderStatus::Completed {
+            return Err(anyhow!(
+                "Render {} did not finish, it cannot be inpainted",
+                source.id
+            ));
+        }
+        source.check_resume(self.processor.model_version().as_ref())?;
+        let relpath = format!("audios/{}.wav", source.id);
+        let Some(bytes) = self.storage.read(&relpath).await? else {
+            return Err(anyhow!("The audio of render {} is missing", source.id));
+        };
+        let audio = decode_wav(&bytes).map_err(|err| anyhow!(err))?;
+        if !(0.0..req.end_secs).contains(&req.start_secs) {
+            return Err(anyhow!("The region to inpaint must end after it starts"));
+        }
+        let region = InpaintRegion {
+            start: (req.start_secs * SAMPLING_RATE as f32) as usize,
+            end: (req.end_secs * SAMPLING_RATE as f32) as usize,
+            crossfade: (DEFAULT_CROSSFADE_SECS * SAMPLING_RATE as f32) as usize,
+        };
+        region.validate(audio.len()).map_err(|err| anyhow!(err))?;
+        let secs = region
+            .replacement_len(audio.len(), SAMPLING_RATE)
+            .div_ceil(SAMPLING_RATE);
+        let estimate = self.processor.estimate(secs);
+        self.limits.read().unwrap().admit(secs, estimate.as_ref())?;
+        let usage = UserUsage::load(&self.storage, &self.user).await?;
+        self.quotas.admit(&usage, today(), secs)?;
+
+        let inpainting = Inpainting {
+            prompt: req.prompt.unwrap_or_else(|| source.prompt.clone()),
+            audio,
+            region,
+        };
+        let job = AudioGenerationRequest {
+            id: IdPair(req.chat_id, req.id).to_string(),
+            prompt: source.prompt,
+            secs: source.secs,
+            user: Some(self.user.clone()),
+        };
+        self.ai_tx
+            .send(BackendInboundMsg::Inpaint((job, inpainting)))?;
+        Ok(())
+    }
+
+    /// Requeues the renders that were running or pending when the process stopped, so
+    /// they continue from their last completed segment.
+    pub(crate) async fn resume_unfinished(&self) -> anyhow::Result<()> {
+        for manifest in RenderManifest::load_unfinished(&self.storage).await? {
+            info!("Resuming render {}", manifest.id);
+            let req = GenerateAudioRequest {
+                id: manifest.id,
+                chat_id: manifest.chat_id,
+                prompt: manifest.prompt,
+                secs: manifest.secs,
+                notify: vec![],
+                audition: false,
+            };
+            if let Err(err) = self.request_generation(req).await {
+                warn!("Could not resume render {}: {err}", manifest.id);
+                RenderManifest::finish(&self.storage, manifest.id, Some(err.to_string())).await?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -85,7 +399,7 @@ impl<S: Storage> WsHandler for MusicGptWsHandler<S> {
     async fn handle_init(&self) -> Vec<OutboundMsg> {
         let chats = Chat::load_all(&self.storage).await.unwrap_or_default();
         vec![
-            OutboundMsg::Info(self.info.clone()),
+            OutboundMsg::Info(self.info.read().unwrap().clone()),
             OutboundMsg::Chats(chats),
         ]
     }
@@ -104,31 +418,52 @@ impl<S: Storage> WsHandler for MusicGptWsHandler<S> {
                             .as_millis(),
                     };
                     chat.save(&self.storage).await?;
-                    self.ai_tx
-                        .send(BackendInboundMsg::Request(AudioGenerationRequest {
-                            id: IdPair(req.chat_id, req.id).to_string(),
-                            prompt: req.prompt.clone(),
-                            secs: req.secs,
-                        }))?;
+                    self.request_generation(req).await?;
                     let chats = Chat::load_all(&self.storage).await?;
                     Some(OutboundMsg::Chats(chats))
                 }
                 InboundMsg::GenerateAudio(req) => {
                     info!("Generating audio for existing chat");
-                    self.ai_tx
-                        .send(BackendInboundMsg::Request(AudioGenerationRequest {
-                            id: IdPair(req.chat_id, req.id).to_string(),
-                            prompt: req.prompt.clone(),
-                            secs: req.secs,
-                        }))?;
+                    self.request_generation(req).await?;
                     None
                 }
                 InboundMsg::AbortGeneration(req) => {
-                    info!("Aborting audio generation");
                     let id = IdPair(req.chat_id, req.id).to_string();
+                    info!(job_id = id, "Aborting audio generation");
                     self.ai_tx.send(BackendInboundMsg::Abort(id))?;
                     None
                 }
+                InboundMsg::RegenerateSegment(req) => {
+                    info!(render = %req.render, segment = req.segment, "Regenerating segment");
+                    self.request_segment_edit(req).await?;
+                    None
+                }
+                InboundMsg::Inpaint(req) => {
+                    info!(
+                        render = %req.render,
+                        "Inpainting {}s to {}s",
+                        req.start_secs,
+                        req.end_secs
+                    );
+                    self.request_inpainting(req).await?;
+                    None
+                }
+                InboundMsg::AuditionVerdict(req) => {
+                    let id = IdPair(req.chat_id, req.id).to_string();
+                    let verdict = match req.accept {
+                        true => AuditionVerdict::Accept,
+                        false => AuditionVerdict::Reject,
+                    };
+                    info!(
+                        job_id = id,
+                        segment = req.segment,
+                        ?verdict,
+                        "Audition verdict"
+                    );
+                    self.ai_tx
+                        .send(BackendInboundMsg::Verdict((id, req.segment, verdict)))?;
+                    None
+                }
                 InboundMsg::GetChat(req) => {
                     let chat = Chat::load(&self.storage, req.chat_id).await?;
                     let history = Chat::load_entries(&self.storage, req.chat_id).await?;
@@ -147,6 +482,44 @@ impl<S: Storage> WsHandler for MusicGptWsHandler<S> {
                     let chats = Chat::load_all(&self.storage).await?;
                     Some(OutboundMsg::Chats(chats))
                 }
+                InboundMsg::ListModels => {
+                    let models = self.registry.list().await?;
+                    Some(OutboundMsg::Models(models))
+                }
+                InboundMsg::UseModel(req) => {
+                    if !self.admin {
+                        return Err(anyhow!("Only admins can switch the model"));
+                    }
+                    info!(model = req.name, "Switching model");
+                    let entry = self
+                        .registry
+                        .list()
+                        .await?
+                        .into_iter()
+                        .find(|e| e.name == req.name)
+                        .ok_or_else(|| anyhow::anyhow!("Unknown model {}", req.name))?;
+                    // Loading an uninstalled model would download gigabytes while this
+                    // connection waits, so it has to be downloaded beforehand.
+                    if !entry.installed {
+                        return Err(anyhow::anyhow!(
+                            "{} is not installed, download it with `musicgpt --model {}` first",
+                            entry.display_name,
+                            entry.name
+                        ));
+                    }
+                    let processor = self.registry.load(&entry.name).await?;
+                    self.processor.swap(processor);
+                    let info = {
+                        let mut info = self.info.write().unwrap();
+                        info.selection_reason =
+                            format!("{} selected at runtime", entry.display_name);
+                        info.model = entry.display_name;
+                        info.clone()
+                    };
+                    // All the connected clients get notified through their subscriptions.
+                    let _ = self.info_broadcast_tx.send(info);
+                    None
+                }
             };
             Ok::<Option<OutboundMsg>, anyhow::Error>(res)
         }
@@ -160,9 +533,19 @@ impl<S: Storage> WsHandler for MusicGptWsHandler<S> {
 
     fn handle_subscription(&self) -> impl StreamExt<Item = OutboundMsg> + Send + 'static {
         let mut rx = self.ai_broadcast_tx.subscribe();
+        let mut info_rx = self.info_broadcast_tx.subscribe();
         async_stream::stream! {
-            while let Ok(msg) = rx.recv().await {
-                yield OutboundMsg::Generation(msg)
+            loop {
+                tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) => yield OutboundMsg::Generation(msg),
+                        Err(_) => break,
+                    },
+                    msg = info_rx.recv() => match msg {
+                        Ok(msg) => yield OutboundMsg::Info(msg),
+                        Err(_) => break,
+                    },
+                }
             }
         }
     }
@@ -187,3 +570,92 @@ impl From<String> for IdPair {
         serde_json::from_str(value.as_str()).expect("Could not deserialize IdPair")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::_test_utils::dummy_ws_handler;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn request(id: Uuid, secs: usize) -> GenerateAudioRequest {
+        GenerateAudioRequest {
+            id,
+            chat_id: Uuid::new_v4(),
+            prompt: "something else".to_string(),
+            secs,
+            notify: vec![],
+            audition: false,
+        }
+    }
+
+    async fn save(storage: &AppFs, user: &str, status: RenderStatus) -> anyhow::Result<Uuid> {
+        let mut manifest =
+            RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), "rain".to_string(), 30, None);
+        manifest.user = Some(user.to_string());
+        manifest.status = status;
+        manifest.save(storage).await?;
+        Ok(manifest.id)
+    }
+
+    #[tokio::test]
+    async fn only_resumes_unfinished_renders_of_the_same_user() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let quotas = Quotas {
+            daily_secs: Some(10),
+            ..Default::default()
+        };
+        let (handler, ai_rx) = dummy_ws_handler(&storage, "alice", quotas);
+
+        let of_bob = save(&storage, "bob", RenderStatus::Running).await?;
+        let err = handler.request_generation(request(of_bob, 5)).await;
+        assert!(err.unwrap_err().to_string().contains("already exists"));
+        let completed = save(&storage, "alice", RenderStatus::Completed).await?;
+        let err = handler.request_generation(request(completed, 5)).await;
+        assert!(err.unwrap_err().to_string().contains("already exists"));
+        assert!(ai_rx.try_recv().is_err());
+
+        // Resumed as first requested, which the quota already admitted.
+        let running = save(&storage, "alice", RenderStatus::Running).await?;
+        handler.request_generation(request(running, 5)).await?;
+        let BackendInboundMsg::Request(job) = ai_rx.try_recv()? else {
+            panic!("Expected a request");
+        };
+        assert_eq!((job.prompt.as_str(), job.secs), ("rain", 30));
+        assert_eq!(job.user.as_deref(), Some("alice"));
+
+        // New renders go through the quota.
+        let err = handler
+            .request_generation(request(Uuid::new_v4(), 30))
+            .await;
+        assert!(err.unwrap_err().to_string().contains("quota"));
+        handler
+            .request_generation(request(Uuid::new_v4(), 10))
+            .await?;
+        assert!(ai_rx.try_recv().is_ok());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn only_admins_switch_the_model() {
+        let storage = AppFs::new_tmp();
+        let (handler, _ai_rx) = dummy_ws_handler(&storage, "alice", Quotas::default());
+        let use_model = || {
+            InboundMsg::UseModel(UseModelRequest {
+                name: "dummy-2".to_string(),
+            })
+        };
+
+        let Some(OutboundMsg::Error(err)) = handler.handle_inbound_msg(use_model()).await else {
+            panic!("Expected an error");
+        };
+        assert_eq!(err, "Only admins can switch the model");
+        assert_eq!(handler.info.read().unwrap().model, "dummy");
+
+        let admin = MusicGptWsHandler {
+            admin: true,
+            ..handler
+        };
+        assert!(admin.handle_inbound_msg(use_model()).await.is_none());
+        assert_eq!(admin.info.read().unwrap().model, "Dummy 2");
+    }
+}
@@ -0,0 +1,464 @@
+//! Messages sent when renders complete or fail, for people who leave batches of renders
+//! running overnight. Notifiers are configured once for the server, and each one either
+//! hears about every render or only about the ones that asked for it by name.
+
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::render_manifest::RenderManifest;
+use crate::storage::Storage;
+
+/// How a render ended, as told to the notifiers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobEvent {
+    pub id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    pub user: Option<String>,
+    /// Where the audio is in the data directory, or why the render failed.
+    pub outcome: Result<String, String>,
+}
+
+impl JobEvent {
+    pub fn subject(&self) -> String {
+        match &self.outcome {
+            Ok(_) => format!("MusicGPT render {} completed", self.id),
+            Err(_) => format!("MusicGPT render {} failed", self.id),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        let render = format!("The {}s render of \"{}\"", self.secs, self.prompt);
+        match &self.outcome {
+            Ok(relpath) => format!("{render} completed, its audio is in {relpath}"),
+            Err(error) => format!("{render} failed: {error}"),
+        }
+    }
+}
+
+/// Somewhere renders are reported to.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, event: &JobEvent) -> anyhow::Result<()>;
+}
+
+/// Posts to a Slack incoming webhook.
+pub struct SlackNotifier {
+    pub webhook_url: String,
+}
+
+#[async_trait]
+impl Notifier for SlackNotifier {
+    async fn notify(&self, event: &JobEvent) -> anyhow::Result<()> {
+        let body = serde_json::json!({ "text": event.message() });
+        post_json(&self.webhook_url, &body).await
+    }
+}
+
+/// Posts to a Discord webhook.
+pub struct DiscordNotifier {
+    pub webhook_url: String,
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    async fn notify(&self, event: &JobEvent) -> anyhow::Result<()> {
+        let body = serde_json::json!({ "content": event.message() });
+        post_json(&self.webhook_url, &body).await
+    }
+}
+
+async fn post_json(url: &str, body: &serde_json::Value) -> anyhow::Result<()> {
+    let response = reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(body)?)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("The webhook answered with {}", response.status()));
+    }
+    Ok(())
+}
+
+/// How the connection to the SMTP server is secured.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum SmtpSecurity {
+    /// Upgraded with STARTTLS, on port 587 by default.
+    #[default]
+    StartTls,
+    /// TLS from the start, on port 465 by default.
+    Tls,
+    /// Plain text, on port 25 by default, only for relays on the same machine or network.
+    None,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct EmailConfig {
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Sends emails through an SMTP server.
+pub struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl EmailNotifier {
+    pub fn new(config: &EmailConfig) -> anyhow::Result<Self> {
+        let mut transport = match config.security {
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+            }
+        };
+        if let Some(port) = config.port {
+            transport = transport.port(port);
+        }
+        if let Some(username) = &config.username {
+            let password = config.password.clone().unwrap_or_default();
+            transport = transport.credentials(Credentials::new(username.clone(), password));
+        }
+        let mailbox = |address: &String| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|err| anyhow!("Invalid email address {address:?}: {err}"))
+        };
+        if config.to.is_empty() {
+            return Err(anyhow!("Email notifiers must have at least one recipient"));
+        }
+        Ok(Self {
+            transport: transport.build(),
+            from: mailbox(&config.from)?,
+            to: config
+                .to
+                .iter()
+                .map(mailbox)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    async fn notify(&self, event: &JobEvent) -> anyhow::Result<()> {
+        let mut email = Message::builder()
+            .from(self.from.clone())
+            .subject(event.subject());
+        for to in &self.to {
+            email = email.to(to.clone());
+        }
+        self.transport.send(email.body(event.message())?).await?;
+        Ok(())
+    }
+}
+
+/// Where a notifier sends its messages.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Channel {
+    Email(EmailConfig),
+    Slack { webhook_url: String },
+    Discord { webhook_url: String },
+}
+
+impl Channel {
+    fn build(&self) -> anyhow::Result<Box<dyn Notifier>> {
+        Ok(match self {
+            Channel::Email(config) => Box::new(EmailNotifier::new(config)?),
+            Channel::Slack { webhook_url } => Box::new(SlackNotifier {
+                webhook_url: webhook_url.clone(),
+            }),
+            Channel::Discord { webhook_url } => Box::new(DiscordNotifier {
+                webhook_url: webhook_url.clone(),
+            }),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct NotifierConfig {
+    /// What jobs name to be notified through this notifier, so it must be unique.
+    pub name: String,
+    pub channel: Channel,
+    /// Whether every render is reported, instead of only the ones that name it.
+    #[serde(default)]
+    pub global: bool,
+    #[serde(default)]
+    pub only_failures: bool,
+}
+
+impl NotifierConfig {
+    /// Reads a JSON list of notifiers.
+    pub fn load_all(path: &Path) -> anyhow::Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Could not read the notifiers {path:?}: {err}"))?;
+        let configs: Vec<Self> = serde_json::from_str(&content)
+            .map_err(|err| anyhow!("Invalid notifiers {path:?}: {err}"))?;
+        build_notifiers(&configs)?;
+        Ok(configs)
+    }
+}
+
+/// The notifiers a render asked for by name, saved when it is requested.
+fn subscription_path(id: Uuid) -> String {
+    format!("notifications/{id}.json")
+}
+
+/// Notifies the notifiers in `names` about the render `id` once it ends, on top of the
+/// global ones.
+pub async fn subscribe<S: Storage>(storage: &S, id: Uuid, names: &[String]) -> anyhow::Result<()> {
+    storage
+        .write(&subscription_path(id), serde_json::to_vec(names)?)
+        .await?;
+    Ok(())
+}
+
+type Notifiers = Vec<(NotifierConfig, Box<dyn Notifier>)>;
+
+fn build_notifiers(configs: &[NotifierConfig]) -> anyhow::Result<Notifiers> {
+    let mut notifiers = Notifiers::new();
+    for config in configs {
+        if notifiers.iter().any(|(other, _)| other.name == config.name) {
+            return Err(anyhow!(
+                "There is more than one notifier named {:?}",
+                config.name
+            ));
+        }
+        let notifier = config
+            .channel
+            .build()
+            .map_err(|err| anyhow!("Invalid notifier {:?}: {err}", config.name))?;
+        notifiers.push((config.clone(), notifier));
+    }
+    Ok(notifiers)
+}
+
+/// Sends the results of the renders to the configured notifiers.
+#[derive(Clone)]
+pub struct Notifications<S> {
+    storage: S,
+    /// Replaced when the settings of the server are reloaded.
+    notifiers: Arc<RwLock<Arc<Notifiers>>>,
+}
+
+impl<S: Storage> Notifications<S> {
+    pub fn new(storage: S, configs: &[NotifierConfig]) -> anyhow::Result<Self> {
+        Ok(Self {
+            storage,
+            notifiers: Arc::new(RwLock::new(Arc::new(build_notifiers(configs)?))),
+        })
+    }
+
+    /// Notifies through `configs` from now on, unless they are invalid. Renders already
+    /// being notified about keep the previous notifiers.
+    pub fn replace(&self, configs: &[NotifierConfig]) -> anyhow::Result<()> {
+        let notifiers = Arc::new(build_notifiers(configs)?);
+        *self.notifiers.write().unwrap() = notifiers;
+        Ok(())
+    }
+
+    /// Reports how the render `id` ended to the global notifiers and the ones it asked
+    /// for.
+    async fn dispatch(&self, id: Uuid, outcome: Result<String, String>) -> anyhow::Result<()> {
+        let subscribed: Vec<String> = match self.storage.read(&subscription_path(id)).await? {
+            Some(content) => serde_json::from_slice(&content)?,
+            None => vec![],
+        };
+        let manifest = RenderManifest::load(&self.storage, id).await?;
+        let notifiers = self.notifiers.read().unwrap().clone();
+        let event = JobEvent {
+            id,
+            prompt: manifest
+                .as_ref()
+                .map(|m| m.prompt.clone())
+                .unwrap_or_default(),
+            secs: manifest.as_ref().map_or(0, |m| m.secs),
+            user: manifest.and_then(|m| m.user),
+            outcome,
+        };
+        for name in &subscribed {
+            if !notifiers.iter().any(|(config, _)| &config.name == name) {
+                warn!(job_id = %id, "There is no notifier named {name:?}");
+            }
+        }
+        for (config, notifier) in notifiers.iter() {
+            if !config.global && !subscribed.contains(&config.name) {
+                continue;
+            }
+            if config.only_failures && event.outcome.is_ok() {
+                continue;
+            }
+            match notifier.notify(&event).await {
+                Ok(()) => info!(job_id = %id, "Notified {}", config.name),
+                Err(err) => warn!(job_id = %id, "Could not notify {}: {err}", config.name),
+            }
+        }
+        self.storage.rm(&subscription_path(id)).await?;
+        Ok(())
+    }
+
+    /// Notifies about the renders that end, until the server stops.
+    pub fn spawn(
+        self,
+        mut rx: tokio::sync::broadcast::Receiver<GenerationMessage>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let (id, outcome) = match rx.recv().await {
+                    Ok(GenerationMessage::Result(result)) => (result.id, Ok(result.relpath)),
+                    Ok(GenerationMessage::Error(error)) => (error.id, Err(error.error)),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("{missed} renders ended without being notified");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                // A slow SMTP server does not hold back the notifications of other renders.
+                let notifications = self.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = notifications.dispatch(id, outcome).await {
+                        warn!(job_id = %id, "Could not send the notifications: {err}");
+                    }
+                });
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use tokio::sync::mpsc;
+
+    use crate::backend::audio_generation_fanout::{AudioGenerationError, AudioGenerationResult};
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[test]
+    fn validates_notifiers() {
+        let email = |to: &[&str]| NotifierConfig {
+            name: "mail".to_string(),
+            channel: Channel::Email(EmailConfig {
+                host: "smtp.example.com".to_string(),
+                port: None,
+                security: SmtpSecurity::StartTls,
+                username: None,
+                password: None,
+                from: "MusicGPT <musicgpt@example.com>".to_string(),
+                to: to.iter().map(|to| to.to_string()).collect(),
+            }),
+            global: true,
+            only_failures: false,
+        };
+        let ok = [email(&["me@example.com"])];
+        assert!(build_notifiers(&ok).is_ok());
+        assert!(build_notifiers(&[email(&[])]).is_err());
+        assert!(build_notifiers(&[email(&["not an address"])]).is_err());
+        let duplicated = [email(&["me@example.com"]), email(&["you@example.com"])];
+        assert!(build_notifiers(&duplicated).is_err());
+    }
+
+    #[tokio::test]
+    async fn notifies_global_and_subscribed_notifiers() -> anyhow::Result<()> {
+        let (tx, mut received) = mpsc::unbounded_channel::<(String, serde_json::Value)>();
+        let hook = |name: &'static str| {
+            let tx = tx.clone();
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                let _ = tx.send((name.to_string(), body));
+            })
+        };
+        let app = Router::new()
+            .route("/slack", hook("slack"))
+            .route("/discord", hook("discord"));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let host = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let storage = AppFs::new_tmp();
+        let configs = [
+            NotifierConfig {
+                name: "team".to_string(),
+                channel: Channel::Slack {
+                    webhook_url: format!("http://{host}/slack"),
+                },
+                global: true,
+                only_failures: true,
+            },
+            NotifierConfig {
+                name: "me".to_string(),
+                channel: Channel::Discord {
+                    webhook_url: format!("http://{host}/discord"),
+                },
+                global: false,
+                only_failures: false,
+            },
+        ];
+        let notifications = Notifications::new(storage.clone(), &configs)?;
+        let (broadcast_tx, broadcast_rx) = tokio::sync::broadcast::channel(10);
+        let task = notifications.spawn(broadcast_rx);
+
+        let chat_id = Uuid::new_v4();
+        let mut manifest =
+            RenderManifest::new(Uuid::new_v4(), chat_id, "Lofi".to_string(), 30, None);
+        manifest.save(&storage).await?;
+        subscribe(&storage, manifest.id, &["me".to_string()]).await?;
+        broadcast_tx.send(GenerationMessage::Result(AudioGenerationResult {
+            id: manifest.id,
+            chat_id,
+            relpath: "audios/a.wav".to_string(),
+        }))?;
+        let (name, body) = received.recv().await.unwrap();
+        assert_eq!(name, "discord");
+        assert_eq!(
+            body["content"],
+            "The 30s render of \"Lofi\" completed, its audio is in audios/a.wav"
+        );
+
+        manifest.id = Uuid::new_v4();
+        manifest.save(&storage).await?;
+        broadcast_tx.send(GenerationMessage::Error(AudioGenerationError {
+            id: manifest.id,
+            chat_id,
+            error: "Out of memory".to_string(),
+        }))?;
+        let (name, body) = received.recv().await.unwrap();
+        assert_eq!(name, "slack");
+        assert_eq!(
+            body["text"],
+            "The 30s render of \"Lofi\" failed: Out of memory"
+        );
+
+        drop(broadcast_tx);
+        task.await?;
+        assert!(received.try_recv().is_err());
+        Ok(())
+    }
+}
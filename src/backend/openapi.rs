@@ -0,0 +1,908 @@
+//! OpenAPI 3 description of the HTTP routes of the server, so that clients of the job API
+//! can be generated instead of written by hand. The schemas come from the same specta types
+//! the TypeScript bindings of the web app are exported from, so they cannot drift apart.
+
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::{json, Map, Value};
+use specta::{
+    DataType, DefOpts, EnumRepr, EnumType, EnumVariant, ExportError, LiteralType,
+    NamedDataTypeItem, ObjectType, PrimitiveType, TupleType, Type, TypeDefs,
+};
+
+use crate::backend::admin::{AdminJob, DrainReport, MaintenanceStatus, PrioritizeRequest};
+use crate::backend::archive::ArchiveRef;
+use crate::backend::audio_generation_fanout::{
+    AudioGenerationError, AudioGenerationProgress, AudioGenerationResult, AudioGenerationStart,
+    AudioGenerationWarning,
+};
+use crate::backend::job_graph::{GraphRun, JobGraph};
+use crate::backend::music_gpt_ws_handler::{InboundMsg, OutboundMsg};
+use crate::backend::queue_estimates::JobQueuePosition;
+use crate::backend::render_manifest::{RenderManifest, SimilarRender};
+use crate::backend::scheduler::ScheduleStatus;
+use crate::backend::usage::UsageReport;
+
+/// HTTP routes serving the OpenAPI document, and a Swagger UI for browsing it if `docs`.
+pub fn openapi_routes(docs: bool) -> Router {
+    let routes = Router::new().route("/openapi.json", get(get_openapi));
+    match docs {
+        true => routes.route("/docs", get(swagger_ui)),
+        false => routes,
+    }
+}
+
+async fn get_openapi() -> Response {
+    match openapi_document() {
+        Ok(document) => Json(document).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Swagger UI is loaded from a CDN rather than bundled, so it is only served when asked
+/// for, as the page then depends on unpkg.com.
+async fn swagger_ui() -> Html<&'static str> {
+    Html(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8"/>
+  <title>MusicGPT API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css"/>
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>"##,
+    )
+}
+
+/// Builds the OpenAPI document of the server.
+pub fn openapi_document() -> Result<Value, ExportError> {
+    let mut types = TypeDefs::new();
+    let manifest = reference::<RenderManifest>(&mut types)?;
+    let usage = reference::<UsageReport>(&mut types)?;
+    let queue_position = reference::<JobQueuePosition>(&mut types)?;
+    let similar_render = reference::<SimilarRender>(&mut types)?;
+    let schedule = reference::<ScheduleStatus>(&mut types)?;
+    let job_graph = reference::<JobGraph>(&mut types)?;
+    let graph_run = reference::<GraphRun>(&mut types)?;
+    let archive_ref = reference::<ArchiveRef>(&mut types)?;
+    let events = [
+        ("start", reference::<AudioGenerationStart>(&mut types)?),
+        (
+            "progress",
+            reference::<AudioGenerationProgress>(&mut types)?,
+        ),
+        ("warning", reference::<AudioGenerationWarning>(&mut types)?),
+        ("result", reference::<AudioGenerationResult>(&mut types)?),
+        ("error", reference::<AudioGenerationError>(&mut types)?),
+    ];
+    let inbound = reference::<InboundMsg>(&mut types)?;
+    let outbound = reference::<OutboundMsg>(&mut types)?;
+    let admin_paths = admin_paths(&mut types)?;
+
+    let mut schemas = Map::new();
+    for named in types.into_values().flatten() {
+        let schema = match named.item {
+            NamedDataTypeItem::Object(object) => object_schema(&object),
+            NamedDataTypeItem::Enum(enum_type) => enum_schema(&enum_type),
+            NamedDataTypeItem::Tuple(tuple) => tuple_schema(&tuple),
+        };
+        schemas.insert(named.name.to_string(), described(schema, named.comments));
+    }
+
+    let id = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": "Id of the render, the same as the id of the AI chat entry it belongs to.",
+        "schema": { "type": "string", "format": "uuid" }
+    });
+    let not_found = json!({ "description": "There is no such render of the caller" });
+    let security = json!([{ "bearer": [] }, { "token": [] }]);
+    let unauthorized = json!({ "description": "Invalid API token" });
+    let event_names = events.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    let event_schemas = events
+        .iter()
+        .map(|(_, schema)| schema.clone())
+        .collect::<Vec<_>>();
+
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "MusicGPT",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Renders music from text prompts. Renders are requested through the \
+                WebSocket at /ws, and followed and downloaded through the HTTP routes."
+        },
+        "paths": {
+            "/jobs": {
+                "get": {
+                    "operationId": "listJobs",
+                    "summary": "The manifests of the caller's renders, or of everyone's for admins, newest first",
+                    "security": security,
+                    "responses": {
+                        "200": {
+                            "description": "The manifests",
+                            "content": { "application/json": { "schema": {
+                                "type": "array",
+                                "items": manifest
+                            } } }
+                        },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/jobs/queue": {
+                "get": {
+                    "operationId": "listQueue",
+                    "summary": "The running and waiting jobs of the caller, or of everyone for admins, with when each is expected to start",
+                    "security": security,
+                    "responses": {
+                        "200": {
+                            "description": "The jobs, in the order they are processed in",
+                            "content": { "application/json": { "schema": {
+                                "type": "array",
+                                "items": queue_position
+                            } } }
+                        },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/jobs/{id}/queue": {
+                "get": {
+                    "operationId": "getQueuePosition",
+                    "summary": "Where a job is in the queue, and when it is expected to start",
+                    "security": security,
+                    "parameters": [id],
+                    "responses": {
+                        "200": {
+                            "description": "The position of the job",
+                            "content": { "application/json": { "schema": queue_position } }
+                        },
+                        "404": { "description": "The job is not running nor waiting" },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/jobs/{id}/similar": {
+                "get": {
+                    "operationId": "listSimilarJobs",
+                    "summary": "The renders that sound most like a finished one, the most similar first",
+                    "security": security,
+                    "parameters": [id, {
+                        "name": "min_similarity",
+                        "in": "query",
+                        "required": false,
+                        "description": "From 0 to 1, unrelated renders are around 0.5. Defaults to 0.75.",
+                        "schema": { "type": "number" }
+                    }, {
+                        "name": "limit",
+                        "in": "query",
+                        "required": false,
+                        "description": "How many renders to return at most. Defaults to 10.",
+                        "schema": { "type": "integer" }
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The similar renders",
+                            "content": { "application/json": { "schema": {
+                                "type": "array",
+                                "items": similar_render
+                            } } }
+                        },
+                        "404": not_found,
+                        "409": { "description": "The render is not finished, or older than fingerprints" },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/jobs/{id}": {
+                "get": {
+                    "operationId": "getJob",
+                    "summary": "The manifest of a render",
+                    "security": security,
+                    "parameters": [id],
+                    "responses": {
+                        "200": {
+                            "description": "The manifest",
+                            "content": { "application/json": { "schema": manifest } }
+                        },
+                        "404": not_found,
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/jobs/{id}/events": {
+                "get": {
+                    "operationId": "followJob",
+                    "summary": "Follows a render as server-sent events",
+                    "description": format!(
+                        "Each message of the render is sent as an event named {}, with the \
+                        message as JSON data. The stream ends with the result or error event, \
+                        which is sent right away for renders that already finished.",
+                        event_names.join(", ")
+                    ),
+                    "security": security,
+                    "parameters": [id],
+                    "responses": {
+                        "200": {
+                            "description": "The events of the render",
+                            "content": { "text/event-stream": { "schema": {
+                                "oneOf": event_schemas
+                            } } }
+                        },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/jobs/{id}/stream.opus": {
+                "get": {
+                    "operationId": "streamJob",
+                    "summary": "Streams a running render as Ogg/Opus, until it finishes",
+                    "security": security,
+                    "parameters": [id],
+                    "responses": {
+                        "200": {
+                            "description": "The audio, from the beginning of the render",
+                            "content": { "audio/ogg": { "schema": {
+                                "type": "string",
+                                "format": "binary"
+                            } } }
+                        },
+                        "404": { "description": "The render is not running" },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/jobs/{id}/audio": {
+                "get": {
+                    "operationId": "getJobAudio",
+                    "summary": "The audio of a render as WAV, so far for running renders",
+                    "security": security,
+                    "parameters": [id, {
+                        "name": "upto",
+                        "in": "query",
+                        "required": false,
+                        "description": "Either \"now\", for all the audio generated so far, or a number of seconds.",
+                        "schema": { "type": "string" }
+                    }, {
+                        "name": "Range",
+                        "in": "header",
+                        "required": false,
+                        "description": "Bytes of a finished render to return, like bytes=0-1023.",
+                        "schema": { "type": "string" }
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The audio, faded out where it is cut",
+                            "content": { "audio/wav": { "schema": {
+                                "type": "string",
+                                "format": "binary"
+                            } } }
+                        },
+                        "206": {
+                            "description": "The requested bytes of a finished render",
+                            "content": { "audio/wav": { "schema": {
+                                "type": "string",
+                                "format": "binary"
+                            } } }
+                        },
+                        "400": { "description": "Invalid upto" },
+                        "416": { "description": "The range is outside of the audio" },
+                        "404": not_found,
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/pipelines": {
+                "post": {
+                    "operationId": "runPipeline",
+                    "summary": "Runs a graph of steps, each one once the steps it needs complete",
+                    "description": "Generate steps are rendered through the queue, and the \
+                        other steps get the audio of the steps they need through the storage.",
+                    "security": security,
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": job_graph } }
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The id of the pipeline",
+                            "content": { "application/json": { "schema": {
+                                "type": "string",
+                                "format": "uuid"
+                            } } }
+                        },
+                        "400": { "description": "The steps cannot run" },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/pipelines/{id}": {
+                "get": {
+                    "operationId": "getPipeline",
+                    "summary": "The state of each step of a pipeline, and where their audio is",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string", "format": "uuid" }
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The pipeline",
+                            "content": { "application/json": { "schema": graph_run } }
+                        },
+                        "404": { "description": "There is no such pipeline" }
+                    }
+                }
+            },
+            "/files/{path}": {
+                "get": {
+                    "operationId": "getFile",
+                    "summary": "A file of the server, like audios/{id}.wav for the audio of a finished render",
+                    "security": security,
+                    "parameters": [{
+                        "name": "path",
+                        "in": "path",
+                        "required": true,
+                        "description": "Path of the file in the data directory.",
+                        "schema": { "type": "string" }
+                    }, {
+                        "name": "Range",
+                        "in": "header",
+                        "required": false,
+                        "description": "Bytes of the file to return, like bytes=0-1023.",
+                        "schema": { "type": "string" }
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The file",
+                            "content": { "application/octet-stream": { "schema": {
+                                "type": "string",
+                                "format": "binary"
+                            } } }
+                        },
+                        "206": { "description": "The requested bytes of the file" },
+                        "401": unauthorized,
+                        "404": { "description": "There is no such file, or it is not served" }
+                    }
+                }
+            },
+            "/podcast.xml": {
+                "get": {
+                    "operationId": "getPodcastFeed",
+                    "summary": "The latest finished renders of the caller as an RSS podcast feed, of everyone for admins",
+                    "description": "Only served when the server was started with --ui-podcast-url.",
+                    "security": security,
+                    "responses": {
+                        "200": {
+                            "description": "The feed",
+                            "content": { "application/rss+xml": { "schema": { "type": "string" } } }
+                        },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/archive/{hash}": {
+                "get": {
+                    "operationId": "getArchivedFile",
+                    "summary": "An archived audio or record, by its SHA-256, which never changes",
+                    "description": "Only served when the server was started with --archive.",
+                    "security": security,
+                    "parameters": [{
+                        "name": "hash",
+                        "in": "path",
+                        "required": true,
+                        "description": "Hex SHA-256 of the file.",
+                        "schema": { "type": "string" }
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The file, WAV audio or the JSON record of a render",
+                            "content": {
+                                "audio/wav": { "schema": { "type": "string", "format": "binary" } },
+                                "application/json": { "schema": {} }
+                            }
+                        },
+                        "400": { "description": "Not a SHA-256" },
+                        "404": { "description": "No file with this hash is archived" },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/archive/renders/{id}": {
+                "get": {
+                    "operationId": "getArchivedRender",
+                    "summary": "The hashes a render was archived as",
+                    "description": "Only served when the server was started with --archive.",
+                    "security": security,
+                    "parameters": [id],
+                    "responses": {
+                        "200": {
+                            "description": "The hashes of the record and the audio of the render",
+                            "content": { "application/json": { "schema": archive_ref } }
+                        },
+                        "404": { "description": "The render is not archived" },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/schedules": {
+                "get": {
+                    "operationId": "listSchedules",
+                    "summary": "The jobs submitted on a schedule, with their last and next runs",
+                    "security": security,
+                    "responses": {
+                        "200": {
+                            "description": "The schedules, in the order they are configured in",
+                            "content": { "application/json": { "schema": {
+                                "type": "array",
+                                "items": schedule
+                            } } }
+                        },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/usage": {
+                "get": {
+                    "operationId": "getUsage",
+                    "summary": "What the caller generated, and how much of it the quotas allow",
+                    "security": security,
+                    "responses": {
+                        "200": {
+                            "description": "The usage of the caller",
+                            "content": { "application/json": { "schema": usage } }
+                        },
+                        "401": unauthorized
+                    }
+                }
+            },
+            "/ws": {
+                "get": {
+                    "operationId": "connect",
+                    "summary": "WebSocket for chatting and requesting renders",
+                    "description": "Clients send InboundMsg and receive OutboundMsg, both as \
+                        JSON text frames.",
+                    "security": [{ "bearer": [] }, { "token": [] }],
+                    "responses": {
+                        "101": {
+                            "description": "Switching to the WebSocket protocol",
+                            "content": { "application/json": { "schema": {
+                                "oneOf": [inbound, outbound]
+                            } } }
+                        },
+                        "401": { "description": "Invalid API token" }
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "token": { "type": "apiKey", "in": "query", "name": "token" }
+            }
+        }
+    });
+    if let (Some(paths), Value::Object(admin_paths)) =
+        (document["paths"].as_object_mut(), admin_paths)
+    {
+        paths.extend(admin_paths);
+    }
+    Ok(document)
+}
+
+/// The routes for admins, which need the API token of one on servers with API tokens.
+fn admin_paths(types: &mut TypeDefs) -> Result<Value, ExportError> {
+    let jobs = reference::<AdminJob>(types)?;
+    let prioritize = reference::<PrioritizeRequest>(types)?;
+    let drain = reference::<DrainReport>(types)?;
+    let maintenance = reference::<MaintenanceStatus>(types)?;
+    let id = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": "Id of the render.",
+        "schema": { "type": "string", "format": "uuid" }
+    });
+    let security = json!([{ "bearer": [] }, { "token": [] }]);
+    let unauthorized = json!({ "description": "Invalid API token" });
+    let forbidden = json!({ "description": "The API token is not of an admin" });
+    let not_queued = json!({ "description": "The job is not running nor waiting" });
+    let accepted = json!({ "description": "The backend will handle it shortly" });
+    Ok(json!({
+        "/admin/jobs": {
+            "get": {
+                "operationId": "adminListJobs",
+                "summary": "The running job and the ones waiting after it, with who requested each",
+                "security": security,
+                "responses": {
+                    "200": {
+                        "description": "The jobs, in the order they are processed in",
+                        "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": jobs
+                        } } }
+                    },
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            }
+        },
+        "/admin/jobs/{id}/cancel": {
+            "post": {
+                "operationId": "adminCancelJob",
+                "summary": "Stops a running job, or removes a waiting one from the queue",
+                "security": security,
+                "parameters": [id],
+                "responses": {
+                    "202": accepted,
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": not_queued
+                }
+            }
+        },
+        "/admin/jobs/{id}/prioritize": {
+            "post": {
+                "operationId": "adminPrioritizeJob",
+                "summary": "Moves a waiting job to another position of the queue",
+                "security": security,
+                "parameters": [id],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": prioritize } }
+                },
+                "responses": {
+                    "202": accepted,
+                    "400": { "description": "Invalid position" },
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": not_queued,
+                    "409": { "description": "The job is already running" }
+                }
+            }
+        },
+        "/admin/drain": {
+            "post": {
+                "operationId": "adminDrain",
+                "summary": "Removes all the jobs waiting for the running one",
+                "security": security,
+                "responses": {
+                    "200": {
+                        "description": "How many jobs were removed",
+                        "content": { "application/json": { "schema": drain } }
+                    },
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            }
+        },
+        "/admin/maintenance": {
+            "get": {
+                "operationId": "adminGetMaintenance",
+                "summary": "Whether new jobs are rejected",
+                "security": security,
+                "responses": {
+                    "200": {
+                        "description": "The maintenance status",
+                        "content": { "application/json": { "schema": maintenance } }
+                    },
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            },
+            "put": {
+                "operationId": "adminSetMaintenance",
+                "summary": "Starts or ends maintenance, during which new jobs are rejected",
+                "security": security,
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": maintenance } }
+                },
+                "responses": {
+                    "200": {
+                        "description": "The new maintenance status",
+                        "content": { "application/json": { "schema": maintenance } }
+                    },
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            }
+        }
+    }))
+}
+
+/// The schema referencing `T`, registering it and the types it depends on in `types`.
+fn reference<T: Type>(types: &mut TypeDefs) -> Result<Value, ExportError> {
+    let data_type = T::reference(
+        DefOpts {
+            parent_inline: false,
+            type_map: types,
+        },
+        &[],
+    )?;
+    Ok(schema(&data_type))
+}
+
+fn described(mut schema: Value, comments: &[&str]) -> Value {
+    if let (Value::Object(object), false) = (&mut schema, comments.is_empty()) {
+        let description = comments.iter().map(|c| c.trim()).collect::<Vec<_>>();
+        object.insert("description".to_string(), description.join("\n").into());
+    }
+    schema
+}
+
+fn schema(data_type: &DataType) -> Value {
+    match data_type {
+        DataType::Any | DataType::Generic(_) => json!({}),
+        DataType::Primitive(primitive) => primitive_schema(primitive),
+        DataType::Literal(literal) => literal_schema(literal),
+        DataType::List(item) => json!({ "type": "array", "items": schema(item) }),
+        DataType::Nullable(inner) => nullable(schema(inner)),
+        DataType::Record(entry) => json!({
+            "type": "object",
+            "additionalProperties": schema(&entry.1)
+        }),
+        DataType::Named(named) => match &named.item {
+            NamedDataTypeItem::Object(object) => object_schema(object),
+            NamedDataTypeItem::Enum(enum_type) => enum_schema(enum_type),
+            NamedDataTypeItem::Tuple(tuple) => tuple_schema(tuple),
+        },
+        DataType::Object(object) => object_schema(object),
+        DataType::Enum(enum_type) => enum_schema(enum_type),
+        DataType::Tuple(tuple) => tuple_schema(tuple),
+        DataType::Reference(reference) => {
+            json!({ "$ref": format!("#/components/schemas/{}", reference.name) })
+        }
+    }
+}
+
+fn primitive_schema(primitive: &PrimitiveType) -> Value {
+    use PrimitiveType::*;
+    match primitive {
+        i8 | i16 | i32 => json!({ "type": "integer", "format": "int32" }),
+        i64 | i128 | isize => json!({ "type": "integer", "format": "int64" }),
+        u8 | u16 | u32 => json!({ "type": "integer", "format": "int32", "minimum": 0 }),
+        u64 | u128 | usize => json!({ "type": "integer", "format": "int64", "minimum": 0 }),
+        f32 => json!({ "type": "number", "format": "float" }),
+        f64 => json!({ "type": "number", "format": "double" }),
+        bool => json!({ "type": "boolean" }),
+        char | String => json!({ "type": "string" }),
+    }
+}
+
+fn literal_schema(literal: &LiteralType) -> Value {
+    match literal {
+        LiteralType::i8(v) => json!({ "type": "integer", "enum": [v] }),
+        LiteralType::i16(v) => json!({ "type": "integer", "enum": [v] }),
+        LiteralType::i32(v) => json!({ "type": "integer", "enum": [v] }),
+        LiteralType::u8(v) => json!({ "type": "integer", "enum": [v] }),
+        LiteralType::u16(v) => json!({ "type": "integer", "enum": [v] }),
+        LiteralType::u32(v) => json!({ "type": "integer", "enum": [v] }),
+        LiteralType::f32(v) => json!({ "type": "number", "enum": [v] }),
+        LiteralType::f64(v) => json!({ "type": "number", "enum": [v] }),
+        LiteralType::bool(v) => json!({ "type": "boolean", "enum": [v] }),
+        LiteralType::String(v) => json!({ "type": "string", "enum": [v] }),
+        LiteralType::None => json!({ "nullable": true, "enum": [null] }),
+    }
+}
+
+/// OpenAPI 3.0 has no null type, nullable references have to be wrapped to be marked.
+fn nullable(schema: Value) -> Value {
+    match schema {
+        Value::Object(mut object) if !object.contains_key("$ref") => {
+            object.insert("nullable".to_string(), true.into());
+            Value::Object(object)
+        }
+        schema => json!({ "allOf": [schema], "nullable": true }),
+    }
+}
+
+fn object_schema(object: &ObjectType) -> Value {
+    let mut properties = Map::new();
+    let mut required = vec![];
+    let mut flattened = vec![];
+    if let Some(tag) = object.tag {
+        properties.insert(tag.to_string(), json!({ "type": "string" }));
+        required.push(tag);
+    }
+    for field in &object.fields {
+        if field.flatten {
+            flattened.push(schema(&field.ty));
+            continue;
+        }
+        properties.insert(field.key.to_string(), schema(&field.ty));
+        if !field.optional {
+            required.push(field.key);
+        }
+    }
+    let mut object = json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        object["required"] = json!(required);
+    }
+    if flattened.is_empty() {
+        object
+    } else {
+        flattened.push(object);
+        json!({ "allOf": flattened })
+    }
+}
+
+fn tuple_schema(tuple: &TupleType) -> Value {
+    match tuple.fields.as_slice() {
+        [] => json!({ "nullable": true, "enum": [null] }),
+        [field] => schema(field),
+        fields => json!({
+            "type": "array",
+            "items": { "anyOf": fields.iter().map(schema).collect::<Vec<_>>() },
+            "minItems": fields.len(),
+            "maxItems": fields.len()
+        }),
+    }
+}
+
+fn variant_schema(variant: &EnumVariant) -> Value {
+    match variant {
+        EnumVariant::Unit => json!({ "nullable": true, "enum": [null] }),
+        EnumVariant::Named(object) => object_schema(object),
+        EnumVariant::Unnamed(tuple) => tuple_schema(tuple),
+    }
+}
+
+fn enum_schema(enum_type: &EnumType) -> Value {
+    let variants = match enum_type {
+        EnumType::Untagged { variants, .. } => variants.iter().map(variant_schema).collect(),
+        EnumType::Tagged { variants, repr, .. } => {
+            let name = |name: &str| json!({ "type": "string", "enum": [name] });
+            let units = variants
+                .iter()
+                .filter(|(_, variant)| matches!(variant, EnumVariant::Unit))
+                .map(|(n, _)| *n)
+                .collect::<Vec<_>>();
+            // Enums of unit variants only, like statuses, are serialized as plain strings.
+            if units.len() == variants.len() && matches!(repr, EnumRepr::External) {
+                return json!({ "type": "string", "enum": units });
+            }
+            variants
+                .iter()
+                .map(|(n, variant)| match (repr, variant) {
+                    (EnumRepr::External, EnumVariant::Unit) => name(n),
+                    (EnumRepr::External, variant) => json!({
+                        "type": "object",
+                        "properties": { *n: variant_schema(variant) },
+                        "required": [n]
+                    }),
+                    (EnumRepr::Internal { tag }, EnumVariant::Unit) => json!({
+                        "type": "object",
+                        "properties": { *tag: name(n) },
+                        "required": [tag]
+                    }),
+                    (EnumRepr::Internal { tag }, variant) => json!({
+                        "allOf": [
+                            {
+                                "type": "object",
+                                "properties": { *tag: name(n) },
+                                "required": [tag]
+                            },
+                            variant_schema(variant)
+                        ]
+                    }),
+                    (EnumRepr::Adjacent { tag, .. }, EnumVariant::Unit) => json!({
+                        "type": "object",
+                        "properties": { *tag: name(n) },
+                        "required": [tag]
+                    }),
+                    (EnumRepr::Adjacent { tag, content }, variant) => json!({
+                        "type": "object",
+                        "properties": { *tag: name(n), *content: variant_schema(variant) },
+                        "required": [tag, content]
+                    }),
+                })
+                .collect()
+        }
+    };
+    json!({ "oneOf": Value::Array(variants) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_routes_and_their_schemas() -> anyhow::Result<()> {
+        let document = openapi_document()?;
+        for path in [
+            "/jobs",
+            "/jobs/{id}",
+            "/jobs/{id}/events",
+            "/jobs/{id}/audio",
+            "/jobs/{id}/queue",
+            "/jobs/{id}/similar",
+            "/schedules",
+            "/pipelines/{id}",
+            "/usage",
+        ] {
+            assert!(document["paths"][path]["get"].is_object(), "{path}");
+        }
+        assert!(document["paths"]["/admin/drain"]["post"].is_object());
+        assert!(document["paths"]["/pipelines"]["post"].is_object());
+        assert_eq!(
+            document["paths"]["/jobs/{id}"]["get"]["responses"]["200"]["content"]
+                ["application/json"]["schema"],
+            json!({ "$ref": "#/components/schemas/RenderManifest" })
+        );
+
+        let schemas = &document["components"]["schemas"];
+        let manifest = &schemas["RenderManifest"];
+        assert_eq!(manifest["type"], "object");
+        assert_eq!(manifest["properties"]["id"], json!({ "type": "string" }));
+        assert_eq!(
+            manifest["properties"]["status"],
+            json!({ "$ref": "#/components/schemas/RenderStatus" })
+        );
+        assert_eq!(schemas["RenderStatus"]["type"], "string");
+        assert!(schemas["RenderRecipe"].is_object());
+        assert!(schemas["UsageTotals"].is_object());
+        assert!(schemas["InboundMsg"]["oneOf"].is_array());
+
+        // Every reference points to a schema of the document.
+        let text = document.to_string();
+        for reference in text.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(schemas[name].is_object(), "{name} is not defined");
+        }
+        Ok(())
+    }
+
+    /// The paths of all the routes defined in the backend, outside of tests, in OpenAPI
+    /// syntax.
+    fn routed_paths() -> anyhow::Result<Vec<String>> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/backend");
+        let mut paths = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let source = std::fs::read_to_string(entry?.path())?;
+            let source = source.split("#[cfg(test)]").next().unwrap_or_default();
+            for route in source.split(".route(").skip(1) {
+                let Some(path) = route.trim_start().strip_prefix('"') else {
+                    continue;
+                };
+                let path = &path[..path.find('"').unwrap()];
+                let segments =
+                    path.split('/')
+                        .map(|segment| match segment.strip_prefix([':', '*']) {
+                            Some(param) => format!("{{{param}}}"),
+                            None => segment.to_string(),
+                        });
+                paths.push(segments.collect::<Vec<_>>().join("/"));
+            }
+        }
+        Ok(paths)
+    }
+
+    #[test]
+    fn describes_every_route() -> anyhow::Result<()> {
+        let document = openapi_document()?;
+        let paths = routed_paths()?;
+        assert!(paths.contains(&"/archive/renders/{id}".to_string()));
+        for path in paths {
+            // The document itself and its UI are not part of the API.
+            if path == "/openapi.json" || path == "/docs" {
+                continue;
+            }
+            assert!(document["paths"][&path].is_object(), "{path} is missing");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+//! A podcast feed of the finished renders, so that nightly batches of tracks can be
+//! listened to in any podcast player.
+
+use axum::extract::{Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Router};
+use reqwest::Url;
+use std::fmt::Write;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+use crate::backend::render_manifest::{RenderManifest, RenderStatus};
+use crate::backend::usage::{Caller, TokenQuery};
+use crate::storage::Storage;
+
+/// Renders listed in the feed, the newest ones.
+const MAX_EPISODES: usize = 100;
+
+#[derive(Clone)]
+struct PodcastState<S: Storage> {
+    storage: S,
+    /// Where clients reach the server, like https://music.example.com, which the links
+    /// of the feed are under.
+    public_url: String,
+}
+
+/// HTTP route serving the finished renders as an RSS podcast feed at `/podcast.xml`, to be
+/// guarded with [crate::backend::usage::require_tokens]. Each user gets a feed of their
+/// own renders, admins get everyone's.
+pub fn podcast_routes<S: Storage>(storage: S, public_url: &str) -> Router {
+    Router::new()
+        .route("/podcast.xml", get(podcast_feed::<S>))
+        .with_state(PodcastState {
+            storage,
+            public_url: public_url.trim_end_matches('/').to_string(),
+        })
+}
+
+async fn podcast_feed<S: Storage>(
+    State(state): State<PodcastState<S>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let manifests = match RenderManifest::load_all(&state.storage).await {
+        Ok(manifests) => manifests,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+    let mut episodes = vec![];
+    for manifest in manifests.into_iter().rev() {
+        if manifest.status != RenderStatus::Completed || !caller.can_see(manifest.owner()) {
+            continue;
+        }
+        // Renders whose audio was deleted are left out.
+        let path = state
+            .storage
+            .path_buf(&format!("audios/{}.wav", manifest.id));
+        let Ok(reader) = hound::WavReader::open(&path) else {
+            continue;
+        };
+        let secs = reader.duration() as f32 / reader.spec().sample_rate as f32;
+        let len = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        episodes.push(Episode {
+            manifest,
+            secs,
+            len,
+        });
+        if episodes.len() == MAX_EPISODES {
+            break;
+        }
+    }
+    (
+        [(CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+        rss(&episodes, &state.public_url, query.token(&headers)),
+    )
+        .into_response()
+}
+
+struct Episode {
+    manifest: RenderManifest,
+    /// Actual duration of the audio, which extended renders may stretch past the one asked.
+    secs: f32,
+    /// Bytes of the audio file.
+    len: u64,
+}
+
+/// An RSS 2.0 feed with the iTunes tags podcast players expect, with an episode per render
+/// enclosing its WAV file. The links to the files carry the `token` the feed was fetched
+/// with, as players do not send any other.
+fn rss(episodes: &[Episode], public_url: &str, token: Option<&str>) -> String {
+    let mut xml = String::new();
+    xml.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push_str(
+        r#"<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"><channel>"#,
+    );
+    let _ = write!(
+        xml,
+        "<title>MusicGPT renders</title><link>{}/</link>\
+         <description>Music generated by MusicGPT.</description>\
+         <itunes:author>MusicGPT</itunes:author><itunes:explicit>false</itunes:explicit>",
+        escape(public_url)
+    );
+    for Episode {
+        manifest,
+        secs,
+        len,
+    } in episodes
+    {
+        let published =
+            OffsetDateTime::from_unix_timestamp_nanos(manifest.created_at as i128 * 1_000_000)
+                .ok()
+                .and_then(|date| date.format(&Rfc2822).ok())
+                .unwrap_or_default();
+        let model = manifest
+            .model
+            .as_ref()
+            .map(|model| format!(" with {} {}", model.name, model.revision))
+            .unwrap_or_default();
+        let secs = secs.round() as u64;
+        let _ = write!(
+            xml,
+            "<item><title>{prompt}</title>\
+             <description>{prompt}, generated{model}.</description>\
+             <guid isPermaLink=\"false\">{id}</guid><pubDate>{published}</pubDate>\
+             <enclosure url=\"{audio_url}\" length=\"{len}\" type=\"audio/wav\"/>\
+             <itunes:duration>{:02}:{:02}:{:02}</itunes:duration></item>",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            prompt = escape(&manifest.prompt),
+            model = escape(&model),
+            id = manifest.id,
+            audio_url = escape(&audio_url(public_url, manifest, token)),
+        );
+    }
+    xml.push_str("</channel></rss>");
+    xml
+}
+
+fn audio_url(public_url: &str, manifest: &RenderManifest, token: Option<&str>) -> String {
+    let url = format!("{public_url}/files/audios/{}.wav", manifest.id);
+    match (token, Url::parse(&url)) {
+        (Some(token), Ok(mut url)) => {
+            url.query_pairs_mut().append_pair("token", token);
+            url.to_string()
+        }
+        _ => url,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use axum::http::header::AUTHORIZATION;
+    use uuid::Uuid;
+
+    use crate::audio::wav::encode_wav;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_finished_renders_as_episodes() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let mut ids = vec![];
+        for (prompt, status, user) in [
+            ("Rock & roll", RenderStatus::Completed, "alice"),
+            ("Still running", RenderStatus::Running, "alice"),
+            ("Someone else's", RenderStatus::Completed, "bob"),
+        ] {
+            let mut manifest =
+                RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), prompt.to_string(), 10, None);
+            manifest.status = status;
+            manifest.user = Some(user.to_string());
+            manifest.created_at = 1_760_000_000_000;
+            manifest.save(&storage).await?;
+            let wav = encode_wav(vec![0.0; 32000 * 75], 32000)?;
+            storage
+                .write(&format!("audios/{}.wav", manifest.id), &wav)
+                .await?;
+            ids.push((manifest.id, wav.len()));
+        }
+
+        let state = State(PodcastState {
+            storage,
+            public_url: "https://music.example.com".to_string(),
+        });
+        let caller = |user: &str, admin| {
+            Extension(Caller {
+                user: user.to_string(),
+                admin,
+            })
+        };
+        let feed = |caller, token: Option<&str>| {
+            let state = state.clone();
+            let mut headers = HeaderMap::new();
+            if let Some(token) = token {
+                headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+            }
+            async move {
+                let query = Query(TokenQuery::default());
+                let response = podcast_feed(state, caller, query, headers).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = to_bytes(response.into_body(), usize::MAX).await?;
+                anyhow::Ok(String::from_utf8(body.to_vec())?)
+            }
+        };
+
+        let alice = feed(caller("alice", false), Some("s3cret&x")).await?;
+        assert_eq!(alice.matches("<item>").count(), 1);
+        assert!(alice.contains("<title>Rock &amp; roll</title>"));
+        let (id, len) = ids[0];
+        assert!(alice.contains(&format!(
+            "<enclosure url=\"https://music.example.com/files/audios/{id}.wav?token=s3cret%26x\" length=\"{len}\" type=\"audio/wav\"/>"
+        )));
+        let admin = feed(caller("carol", true), None).await?;
+        assert_eq!(admin.matches("<item>").count(), 2);
+        let feed = alice;
+        assert!(feed.contains("<itunes:duration>00:01:15</itunes:duration>"));
+        assert!(feed.contains("<pubDate>Thu, 09 Oct 2025 08:53:20 +0000</pubDate>"));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Origin of a render, embedded into the files it is exported to in the spirit of C2PA
+//! manifests, so that generated assets shipped to clients carry it with them.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::audio::wav::{self, Bext};
+use crate::backend::model_registry::ModelVersion;
+
+/// What is recorded about how a render was generated. The prompt itself is not, only its
+/// hash, so that it can be checked without being disclosed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Provenance {
+    pub claim_generator: String,
+    pub ai_generated: bool,
+    pub model: Option<String>,
+    pub model_version: Option<String>,
+    pub prompt_sha256: String,
+    /// RFC 3339 time the render was generated at.
+    pub created: String,
+    pub license: Option<String>,
+}
+
+impl Provenance {
+    /// Provenance of a render of `prompt` generated with `model` at `created_at`, in Unix
+    /// milliseconds.
+    pub fn new(
+        model: Option<&ModelVersion>,
+        prompt: &str,
+        created_at: u128,
+        license: Option<String>,
+    ) -> Self {
+        let created = OffsetDateTime::from_unix_timestamp_nanos(created_at as i128 * 1_000_000)
+            .ok()
+            .and_then(|time| time.format(&Rfc3339).ok())
+            .unwrap_or_default();
+        Self {
+            claim_generator: format!("MusicGPT {}", env!("CARGO_PKG_VERSION")),
+            ai_generated: true,
+            model: model.map(|model| model.name.clone()),
+            model_version: model.map(|model| model.hash.clone()),
+            prompt_sha256: format!("{:x}", Sha256::digest(prompt.as_bytes())),
+            created,
+            license,
+        }
+    }
+
+    /// Same as [Provenance::new], for a render generated right now.
+    pub fn generated_now(
+        model: Option<&ModelVersion>,
+        prompt: &str,
+        license: Option<String>,
+    ) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        Self::new(model, prompt, now, license)
+    }
+
+    /// The chunks of a WAV file carrying this provenance. The whole of it goes in the
+    /// comment of an INFO chunk as JSON, and the software, date and license also in their
+    /// own tags, which is what most players show. A broadcast extension chunk carries the
+    /// date and the model for broadcast asset management systems.
+    pub fn wav_chunks(&self) -> Vec<u8> {
+        let comment = serde_json::to_string(self).unwrap_or_default();
+        let mut tags = vec![
+            (*b"ISFT", self.claim_generator.as_str()),
+            (*b"ICRD", self.created.as_str()),
+            (*b"ICMT", comment.as_str()),
+        ];
+        if let Some(license) = &self.license {
+            tags.push((*b"ICOP", license.as_str()));
+        }
+        let mut chunks = wav::info_chunk(&tags);
+        chunks.extend(wav::bext_chunk(&self.bext()));
+        chunks
+    }
+
+    fn bext(&self) -> Bext {
+        let model = match (&self.model, &self.model_version) {
+            (Some(model), Some(version)) => format!("{model} {version}"),
+            (Some(model), None) => model.clone(),
+            _ => "an unknown model".to_string(),
+        };
+        Bext {
+            description: format!("AI-generated with {model}"),
+            originator: self.claim_generator.clone(),
+            originator_reference: self.prompt_sha256.clone(),
+            // Like 2025-10-09T08:53:20Z.
+            origination_date: self.created.get(..10).unwrap_or_default().to_string(),
+            origination_time: self.created.get(11..19).unwrap_or_default().to_string(),
+            time_reference: 0,
+            coding_history: vec![format!(
+                "A=PCM,W=32,M=mono,T={}; {model}",
+                self.claim_generator
+            )],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_the_origin_without_the_prompt() {
+        let model = ModelVersion {
+            name: "small".to_string(),
+            revision: "main".to_string(),
+            hash: "abc123".to_string(),
+        };
+        let provenance = Provenance::new(
+            Some(&model),
+            "Create a relaxing LoFi song",
+            1_760_000_000_000,
+            Some("CC-BY-4.0".to_string()),
+        );
+        assert_eq!(provenance.created, "2025-10-09T08:53:20Z");
+        assert_eq!(provenance.model_version.as_deref(), Some("abc123"));
+        assert_eq!(provenance.prompt_sha256.len(), 64);
+
+        let chunks = provenance.wav_chunks();
+        let text = String::from_utf8_lossy(&chunks);
+        assert!(text.contains("CC-BY-4.0"));
+        assert!(text.contains("2025-10-0908:53:20"));
+        assert!(text.contains(&format!("T={}; small abc123", provenance.claim_generator)));
+        assert!(text.contains(&provenance.prompt_sha256));
+        assert!(!text.contains("LoFi"));
+    }
+}
@@ -0,0 +1,319 @@
+//! Settings for serving the app behind a reverse proxy like nginx or Traefik, possibly under
+//! a subpath, and for calling its API from web pages served elsewhere.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, Method};
+use axum::Router;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// A proxy address, or a range of them in CIDR notation, like 172.16.0.0/12.
+#[derive(Clone, Debug, PartialEq)]
+struct TrustedProxy {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl TrustedProxy {
+    fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = match spec.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (spec, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("Trusted proxy {spec:?} must be an IP address or range"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => match len.parse() {
+                Ok(len) if len <= max_len => len,
+                _ => {
+                    return Err(anyhow!(
+                        "Trusted proxy {spec:?} has an invalid prefix length"
+                    ))
+                }
+            },
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        // Proxies in the same host often connect through IPv4 mapped addresses.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(range) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(range) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// How the server is reached when it sits behind a reverse proxy. The default serves
+/// everything at the root and ignores forwarded headers.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyConfig {
+    /// Path all the routes are served under, like /musicgpt, or empty for the root.
+    base_path: String,
+    /// Proxies whose X-Forwarded-For and X-Forwarded-Prefix headers are believed.
+    trusted_proxies: Vec<TrustedProxy>,
+    /// Origins of the web pages allowed to call the HTTP API, `*` for any.
+    cors_origins: Vec<HeaderValue>,
+}
+
+impl ProxyConfig {
+    /// Parses the settings as given in the command line.
+    pub fn parse(
+        base_path: Option<&str>,
+        trusted_proxies: &[String],
+        cors_origins: &[String],
+    ) -> anyhow::Result<Self> {
+        let base_path = match base_path.map(|path| path.trim_end_matches('/')) {
+            None | Some("") => String::new(),
+            Some(path) if !path.starts_with('/') => {
+                return Err(anyhow!("Base path {path:?} must start with /"))
+            }
+            Some(path)
+                if !path
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && c != '?' && c != '#') =>
+            {
+                return Err(anyhow!("Base path {path:?} contains invalid characters"))
+            }
+            Some(path) => path.to_string(),
+        };
+        let trusted_proxies = trusted_proxies
+            .iter()
+            .map(|spec| TrustedProxy::parse(spec))
+            .collect::<anyhow::Result<_>>()?;
+        let mut origins = vec![];
+        for origin in cors_origins {
+            let origin = origin.trim_end_matches('/');
+            let valid = origin == "*"
+                || ["http://", "https://"].iter().any(|scheme| {
+                    origin
+                        .strip_prefix(scheme)
+                        .is_some_and(|host| !host.is_empty() && !host.contains('/'))
+                });
+            if !valid {
+                return Err(anyhow!(
+                    "CORS origin {origin:?} must be like https://example.com, or *"
+                ));
+            }
+            origins.push(HeaderValue::from_str(origin)?);
+        }
+        Ok(Self {
+            base_path,
+            trusted_proxies,
+            cors_origins: origins,
+        })
+    }
+
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(addr))
+    }
+
+    /// The address of the client behind the request. Requests from trusted proxies are
+    /// followed back through their X-Forwarded-For header, up to the first hop that was
+    /// not made by another trusted proxy.
+    pub fn client_addr(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.ip();
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let hops = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            let Ok(addr) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = addr;
+            if !self.is_trusted(addr) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// The path the browser reaches the app at. Proxies that strip a prefix of their own
+    /// before forwarding requests announce it in X-Forwarded-Prefix.
+    pub fn public_base_path(&self, peer: SocketAddr, headers: &HeaderMap) -> String {
+        let forwarded = headers
+            .get(X_FORWARDED_PREFIX)
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| self.is_trusted(peer.ip()))
+            .filter(|prefix| prefix.starts_with('/'))
+            .unwrap_or_default();
+        format!("{}{}", forwarded.trim_end_matches('/'), self.base_path)
+    }
+
+    /// Serves `app` under the base path, answering CORS requests from the allowed origins.
+    pub fn apply(&self, app: Router) -> Router {
+        let app = if self.base_path.is_empty() {
+            app
+        } else {
+            Router::new().nest(&self.base_path, app)
+        };
+        if self.cors_origins.is_empty() {
+            return app;
+        }
+        let origins = if self.cors_origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(self.cors_origins.clone())
+        };
+        app.layer(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::POST, Method::PUT])
+                .allow_headers([AUTHORIZATION, CONTENT_TYPE]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS,
+        ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    };
+    use axum::routing::get;
+
+    use super::*;
+
+    fn peer(addr: &str) -> SocketAddr {
+        SocketAddr::new(addr.parse().unwrap(), 4321)
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_the_settings() -> anyhow::Result<()> {
+        assert_eq!(ProxyConfig::parse(Some("/"), &[], &[])?.base_path(), "");
+        assert_eq!(
+            ProxyConfig::parse(Some("/musicgpt/"), &[], &[])?.base_path(),
+            "/musicgpt"
+        );
+        assert!(ProxyConfig::parse(Some("musicgpt"), &[], &[]).is_err());
+        assert!(ProxyConfig::parse(Some("/a b"), &[], &[]).is_err());
+        assert!(ProxyConfig::parse(None, &["10.0.0.0/33".to_string()], &[]).is_err());
+        assert!(ProxyConfig::parse(None, &["proxy".to_string()], &[]).is_err());
+        assert!(ProxyConfig::parse(None, &[], &["example.com".to_string()]).is_err());
+        assert!(ProxyConfig::parse(None, &[], &["https://example.com/app".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn follows_forwarded_headers_of_trusted_proxies_only() -> anyhow::Result<()> {
+        let config = ProxyConfig::parse(
+            Some("/app"),
+            &["127.0.0.1".to_string(), "172.16.0.0/12".to_string()],
+            &[],
+        )?;
+        let forwarded = headers(&[
+            (X_FORWARDED_FOR, "6.6.6.6, 203.0.113.7"),
+            (X_FORWARDED_FOR, "172.18.0.2"),
+            (X_FORWARDED_PREFIX, "/tools/"),
+        ]);
+
+        // The client spoofed the first hop, only the ones added by trusted proxies count.
+        let client = config.client_addr(peer("127.0.0.1"), &forwarded);
+        assert_eq!(client, "203.0.113.7".parse::<IpAddr>()?);
+        assert_eq!(
+            config.client_addr(peer("::ffff:127.0.0.1"), &forwarded),
+            client
+        );
+        assert_eq!(
+            config.public_base_path(peer("172.20.1.1"), &forwarded),
+            "/tools/app"
+        );
+
+        let untrusted = peer("198.51.100.1");
+        assert_eq!(config.client_addr(untrusted, &forwarded), untrusted.ip());
+        assert_eq!(config.public_base_path(untrusted, &forwarded), "/app");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_under_the_base_path_with_cors() -> anyhow::Result<()> {
+        let config = ProxyConfig::parse(
+            Some("/musicgpt"),
+            &[],
+            &["https://studio.example.com".to_string()],
+        )?;
+        let app = config
+            .apply(Router::new().route("/jobs", get(|| async { "[]" }).post(|| async { "{}" })));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let host = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{host}/musicgpt/jobs"))
+            .header(ORIGIN, "https://studio.example.com")
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://studio.example.com"
+        );
+
+        let res = client
+            .get(format!("http://{host}/musicgpt/jobs"))
+            .header(ORIGIN, "https://evil.example.com")
+            .send()
+            .await?;
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        // Browsers ask before posting JSON with a token.
+        let res = client
+            .request(Method::OPTIONS, format!("http://{host}/musicgpt/jobs"))
+            .header(ORIGIN, "https://studio.example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "authorization,content-type")
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        let methods = res.headers()[ACCESS_CONTROL_ALLOW_METHODS].to_str()?;
+        assert!(methods.contains("POST"), "{methods}");
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://studio.example.com"
+        );
+
+        let res = client.get(format!("http://{host}/jobs")).send().await?;
+        assert_eq!(res.status(), 404);
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! Estimates of when queued jobs start, from how fast the active model generated the last
+//! ones, so that clients can tell users how long they will wait.
+
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::backend::music_gpt_ws_handler::IdPair;
+
+/// Weight of the last job in the measured real-time factor.
+const RTF_SMOOTHING: f64 = 0.3;
+/// Progress the running job needs before its own pace is trusted for estimating its end.
+const MIN_TRUSTED_PROGRESS: f32 = 0.05;
+
+/// Real-time factor (seconds spent generating per second of audio) of the model, measured
+/// on the jobs it generated from scratch and smoothed over the last ones.
+#[derive(Clone, Debug, Default)]
+pub struct RtfMeter {
+    model: Option<String>,
+    rtf: Option<f64>,
+}
+
+impl RtfMeter {
+    /// Accounts a job of `secs` seconds that took `elapsed` to generate with `model`.
+    /// What was measured with other models is discarded.
+    pub fn record(&mut self, model: Option<&str>, secs: usize, elapsed: Duration) {
+        if secs == 0 {
+            return;
+        }
+        let rtf = elapsed.as_secs_f64() / secs as f64;
+        self.rtf = match self.rtf {
+            Some(last) if self.model.as_deref() == model => {
+                Some(last * (1.0 - RTF_SMOOTHING) + rtf * RTF_SMOOTHING)
+            }
+            _ => Some(rtf),
+        };
+        self.model = model.map(str::to_string);
+    }
+
+    /// The real-time factor of `model`, if it generated any job yet.
+    pub fn rtf(&self, model: Option<&str>) -> Option<f64> {
+        self.rtf.filter(|_| self.model.as_deref() == model)
+    }
+}
+
+/// A job waiting in the queue of the backend, or being processed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedJob {
+    pub id: String,
+    pub prompt: String,
+    pub secs: usize,
+    /// Who requested it, if known.
+    pub user: Option<String>,
+}
+
+/// How far a job being processed got.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunningJob {
+    pub id: String,
+    pub progress: f32,
+    pub elapsed: Duration,
+}
+
+/// The queue of the backend at some point.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueSnapshot {
+    /// In the order they are processed in, starting by the running ones.
+    pub jobs: Vec<QueuedJob>,
+    pub running: Vec<RunningJob>,
+    /// Real-time factor of the active model, if known.
+    pub rtf: Option<f64>,
+    /// Jobs that can run at the same time, at least 1.
+    pub slots: usize,
+}
+
+/// Where a job is in the queue, and when it is expected to start.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct JobQueuePosition {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    /// 0 for running jobs, 1 for the next one to start, and so on.
+    pub position: usize,
+    /// Jobs processed before this one, including the running ones.
+    pub jobs_ahead: usize,
+    /// Seconds until the job starts, 0 once it is running. Unknown until the active model
+    /// generated a job.
+    pub starts_in_secs: Option<f64>,
+    /// Unix time in milliseconds the job is expected to start at.
+    pub estimated_start: Option<u128>,
+}
+
+impl QueueSnapshot {
+    /// The position of each job, estimating their start from `now`, in Unix milliseconds.
+    /// Waiting jobs start as soon as one of the jobs ahead of them finishes.
+    pub fn positions(&self, now: u128) -> Vec<JobQueuePosition> {
+        // Seconds until each slot is free again, None if unknown.
+        let n = self.slots.max(1);
+        let mut slots = vec![Some(0.0); n];
+        let mut started = 0;
+        let mut positions = vec![];
+        for (i, job) in self.jobs.iter().enumerate() {
+            let running = self
+                .running
+                .iter()
+                .find(|running| running.id == job.id)
+                .filter(|_| started == i);
+            let (position, starts_in) = match running {
+                Some(running) => {
+                    started += 1;
+                    slots[i % n] = self.remaining_secs(job, running);
+                    (0, Some(0.0))
+                }
+                None => {
+                    // An unknown slot sorts first, as nothing is known after it.
+                    let slot = (0..n)
+                        .min_by(|&a, &b| slots[a].partial_cmp(&slots[b]).unwrap_or(Ordering::Equal))
+                        .unwrap_or_default();
+                    let starts_in = slots[slot];
+                    slots[slot] = starts_in
+                        .zip(self.rtf)
+                        .map(|(start, rtf)| start + rtf * job.secs as f64);
+                    (i + 1 - started, starts_in)
+                }
+            };
+            if let Ok(IdPair(chat_id, id)) = serde_json::from_str(&job.id) {
+                positions.push(JobQueuePosition {
+                    id,
+                    chat_id,
+                    prompt: job.prompt.clone(),
+                    secs: job.secs,
+                    position,
+                    jobs_ahead: i,
+                    starts_in_secs: starts_in,
+                    estimated_start: starts_in.map(|secs| now + (secs * 1000.0) as u128),
+                });
+            }
+        }
+        positions
+    }
+
+    /// Seconds the running job still needs, from its own pace once it made some progress.
+    fn remaining_secs(&self, job: &QueuedJob, running: &RunningJob) -> Option<f64> {
+        let progress = running.progress.clamp(0.0, 1.0) as f64;
+        if running.progress >= MIN_TRUSTED_PROGRESS {
+            Some(running.elapsed.as_secs_f64() * (1.0 - progress) / progress)
+        } else {
+            self.rtf.map(|rtf| rtf * job.secs as f64 * (1.0 - progress))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: (Uuid, Uuid), secs: usize) -> QueuedJob {
+        QueuedJob {
+            id: IdPair(id.0, id.1).to_string(),
+            prompt: "Create a cool song".to_string(),
+            secs,
+            user: None,
+        }
+    }
+
+    #[test]
+    fn measures_the_rtf_of_the_active_model() {
+        let mut meter = RtfMeter::default();
+        assert_eq!(meter.rtf(Some("small")), None);
+        meter.record(Some("small"), 10, Duration::from_secs(20));
+        assert_eq!(meter.rtf(Some("small")), Some(2.0));
+        meter.record(Some("small"), 10, Duration::from_secs(30));
+        let smoothed = meter.rtf(Some("small")).unwrap();
+        assert!((smoothed - 2.3).abs() < 1e-9, "{smoothed}");
+        assert_eq!(meter.rtf(Some("medium")), None);
+        meter.record(Some("medium"), 10, Duration::from_secs(50));
+        assert_eq!(meter.rtf(Some("medium")), Some(5.0));
+    }
+
+    #[test]
+    fn estimates_when_queued_jobs_start() {
+        let ids = [0, 1, 2].map(|_| (Uuid::new_v4(), Uuid::new_v4()));
+        let mut snapshot = QueueSnapshot {
+            jobs: vec![job(ids[0], 30), job(ids[1], 60), job(ids[2], 10)],
+            running: vec![RunningJob {
+                id: job(ids[0], 30).id,
+                progress: 0.25,
+                elapsed: Duration::from_secs(20),
+            }],
+            rtf: Some(2.0),
+            slots: 1,
+        };
+        let positions = snapshot.positions(1_000_000);
+        let summary = positions
+            .iter()
+            .map(|p| (p.chat_id, p.position, p.jobs_ahead, p.starts_in_secs))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (ids[0].0, 0, 0, Some(0.0)),
+                // The running job needs 3 times what it took to reach a quarter.
+                (ids[1].0, 1, 1, Some(60.0)),
+                (ids[2].0, 2, 2, Some(180.0)),
+            ]
+        );
+        assert_eq!(positions[2].estimated_start, Some(1_180_000));
+
+        // Until the model generated something, only the order is known.
+        snapshot.rtf = None;
+        snapshot.running = vec![];
+        let positions = snapshot.positions(0);
+        assert_eq!(positions[0].position, 1);
+        assert_eq!(positions[0].starts_in_secs, Some(0.0));
+        assert_eq!(positions[1].starts_in_secs, None);
+    }
+
+    #[test]
+    fn estimates_when_jobs_start_with_several_slots() {
+        let ids = [0, 1, 2, 3].map(|_| (Uuid::new_v4(), Uuid::new_v4()));
+        let running = |id, progress| RunningJob {
+            id,
+            progress,
+            elapsed: Duration::from_secs(30),
+        };
+        let snapshot = QueueSnapshot {
+            jobs: vec![
+                job(ids[0], 30),
+                job(ids[1], 30),
+                job(ids[2], 10),
+                job(ids[3], 10),
+            ],
+            running: vec![
+                running(job(ids[0], 30).id, 0.5),
+                running(job(ids[1], 30).id, 0.75),
+            ],
+            rtf: Some(2.0),
+            slots: 2,
+        };
+        let summary = snapshot
+            .positions(0)
+            .iter()
+            .map(|p| (p.position, p.starts_in_secs))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (0, Some(0.0)),
+                (0, Some(0.0)),
+                // Each waiting job takes the slot that is free first.
+                (1, Some(10.0)),
+                (2, Some(30.0)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Settings of a running server that can change without restarting it: the limits of
+//! new jobs, the processing of extended renders and the notifiers. Jobs already running
+//! keep the settings they started with.
+
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn};
+
+use crate::backend::audio_generation_backend::JobProcessor;
+use crate::backend::job_limits::JobLimits;
+use crate::backend::model_registry::{ModelEntry, ModelRegistry, SwappableJobProcessor};
+use crate::backend::notifier::{Notifications, NotifierConfig};
+use crate::backend::render_manifest::RenderSettings;
+use crate::storage::Storage;
+
+/// What a reload replaces.
+#[derive(Clone, Debug)]
+pub struct LiveSettings {
+    pub limits: JobLimits,
+    pub render_settings: RenderSettings,
+    pub notifiers: Vec<NotifierConfig>,
+}
+
+pub struct Reloader<S> {
+    pub limits: Arc<RwLock<JobLimits>>,
+    pub settings: watch::Sender<RenderSettings>,
+    pub notifications: Notifications<S>,
+    pub processor: SwappableJobProcessor,
+}
+
+impl<S: Storage> Reloader<S> {
+    /// Replaces the settings of the server with `live`, or none of them if any is invalid.
+    pub fn apply(&self, live: LiveSettings) -> anyhow::Result<()> {
+        let post = &live.render_settings.post;
+        let changed = self.settings.borrow().post != *post;
+        let processor = match changed {
+            true => Some(self.processor.with_post_chain(post)?),
+            false => None,
+        };
+        self.notifications.replace(&live.notifiers)?;
+        if let Some(processor) = processor {
+            self.processor.swap(processor);
+        }
+        *self.limits.write().unwrap() = live.limits;
+        self.settings.send_replace(live.render_settings);
+        Ok(())
+    }
+
+    /// Applies each of the settings received, until the sender is dropped.
+    pub fn spawn(self, mut rx: mpsc::UnboundedReceiver<LiveSettings>) -> tokio::task::JoinHandle<()>
+    where
+        S: 'static,
+    {
+        tokio::spawn(async move {
+            while let Some(live) = rx.recv().await {
+                match self.apply(live) {
+                    Ok(()) => info!("Reloaded the settings"),
+                    Err(err) => warn!("The settings were not reloaded: {err}"),
+                }
+            }
+        })
+    }
+}
+
+/// Models loaded at runtime, like with `UseModel`, process extended renders with the
+/// latest settings instead of the ones the server started with.
+pub struct LiveRegistry<R> {
+    pub inner: R,
+    pub settings: watch::Receiver<RenderSettings>,
+}
+
+#[async_trait]
+impl<R: ModelRegistry> ModelRegistry for LiveRegistry<R> {
+    async fn list(&self) -> anyhow::Result<Vec<ModelEntry>> {
+        self.inner.list().await
+    }
+
+    async fn load(&self, name: &str) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        let processor = self.inner.load(name).await?;
+        let post = self.settings.borrow().post.clone();
+        // Processors that cannot change their processing are used as they are.
+        Ok(processor.with_post_chain(&post).unwrap_or(processor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audio::extended_generation::ExtendedGenerationConfig;
+    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::extended_audio_backend::ExtendedJobProcessor;
+    use crate::backend::notifier::Channel;
+    use crate::backend::render_manifest::PostChain;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[test]
+    fn applies_all_the_settings_or_none() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let processor = SwappableJobProcessor::new(Arc::new(DummyJobProcessor::default()));
+        let reloader = Reloader {
+            limits: Default::default(),
+            settings: watch::channel(RenderSettings::default()).0,
+            notifications: Notifications::new(storage, &[])?,
+            processor: processor.clone(),
+        };
+        let limits = JobLimits {
+            max_secs: Some(60),
+            ..Default::default()
+        };
+        let notifiers = vec![NotifierConfig {
+            name: "team".to_string(),
+            channel: Channel::Slack {
+                webhook_url: "http://127.0.0.1:1/hook".to_string(),
+            },
+            global: true,
+            only_failures: false,
+        }];
+        let post = PostChain {
+            headroom_db: Some(-1.0),
+            ..Default::default()
+        };
+
+        // The dummy processor has no extended renders to process differently.
+        let err = reloader
+            .apply(LiveSettings {
+                limits: limits.clone(),
+                render_settings: RenderSettings {
+                    post: post.clone(),
+                    ..Default::default()
+                },
+                notifiers: notifiers.clone(),
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The processing of this model cannot be changed"
+        );
+        assert_eq!(*reloader.limits.read().unwrap(), JobLimits::default());
+        assert_eq!(reloader.settings.borrow().post, PostChain::default());
+
+        reloader.apply(LiveSettings {
+            limits: limits.clone(),
+            render_settings: RenderSettings {
+                watermark: true,
+                ..Default::default()
+            },
+            notifiers,
+        })?;
+        assert_eq!(*reloader.limits.read().unwrap(), limits);
+        assert!(reloader.settings.borrow().watermark);
+
+        processor.swap(Arc::new(
+            ExtendedJobProcessor::new(
+                Arc::new(DummyJobProcessor::default()),
+                ExtendedGenerationConfig::default(),
+                32000,
+            )
+            .map_err(anyhow::Error::msg)?,
+        ));
+        reloader.apply(LiveSettings {
+            limits,
+            render_settings: RenderSettings {
+                post: post.clone(),
+                ..Default::default()
+            },
+            notifiers: vec![],
+        })?;
+        assert_eq!(reloader.settings.borrow().post, post);
+        Ok(())
+    }
+}
@@ -0,0 +1,709 @@
+//! Mirrors the renders to a remote, for headless GPU servers whose disks are not where
+//! the user works. The audios directory is uploaded to WebDAV, SFTP or S3 after each job
+//! completes, retrying failed transfers and resuming the ones cut halfway.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::s3::S3Remote;
+use crate::storage::Storage;
+
+/// What is mirrored, with the same layout on the remote.
+const SYNCED_DIR: &str = "audios";
+const LEDGER: &str = "sync/ledger.json";
+
+/// Somewhere files are uploaded to, at paths relative to its root.
+#[async_trait]
+pub trait Remote: Send + Sync {
+    /// How many bytes of the file are on the remote, if it is there.
+    async fn len(&self, path: &str) -> anyhow::Result<Option<u64>>;
+    /// Uploads the local file, keeping the first `offset` bytes already on the remote.
+    /// Remotes that upload in parts keep the id of the unfinished upload in `resume`,
+    /// which is None when the file changed since it started.
+    async fn upload(
+        &self,
+        local: &Path,
+        path: &str,
+        offset: u64,
+        resume: &mut Option<String>,
+    ) -> anyhow::Result<()>;
+}
+
+/// Uploads with HTTP PUT to a WebDAV server, like Nextcloud or a NAS. Parts of files are
+/// sent with a `Content-Range`, for the servers that support it.
+pub struct WebDavRemote {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl WebDavRemote {
+    fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}/{path}", self.url.trim_end_matches('/'));
+        let request = reqwest::Client::new().request(method, url);
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+}
+
+#[async_trait]
+impl Remote for WebDavRemote {
+    async fn len(&self, path: &str) -> anyhow::Result<Option<u64>> {
+        let response = self.request(Method::HEAD, path).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "The WebDAV server answered with {}",
+                response.status()
+            ));
+        }
+        Ok(response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse().ok()))
+    }
+
+    async fn upload(
+        &self,
+        local: &Path,
+        path: &str,
+        offset: u64,
+        _resume: &mut Option<String>,
+    ) -> anyhow::Result<()> {
+        // Collections that already exist answer with 405, which is fine.
+        let mut parent = String::new();
+        for dir in parents(path) {
+            parent = format!("{parent}{dir}/");
+            let method = Method::from_bytes(b"MKCOL")?;
+            self.request(method, &parent).send().await?;
+        }
+        let mut file = tokio::fs::File::open(local).await?;
+        let len = file.metadata().await?.len();
+        if offset > 0 && offset < len {
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            let response = self
+                .request(Method::PUT, path)
+                .header(
+                    reqwest::header::CONTENT_RANGE,
+                    format!("bytes {offset}-{}/{len}", len - 1),
+                )
+                .header(reqwest::header::CONTENT_LENGTH, len - offset)
+                .body(stream(file.try_clone().await?, len - offset))
+                .send()
+                .await?;
+            // Servers that ignore the range answer with success too, but replace the
+            // file with the part, so it is checked.
+            if response.status().is_success() {
+                if self.len(path).await? == Some(len) {
+                    return Ok(());
+                }
+                warn!("The WebDAV server ignored the range of {path}, uploading all of it");
+            } else if !matches!(response.status().as_u16(), 400 | 405 | 416 | 501) {
+                return Err(anyhow!(
+                    "The WebDAV server answered with {}",
+                    response.status()
+                ));
+            }
+            // Servers without partial updates get the whole file.
+            file.rewind().await?;
+        }
+        let response = self
+            .request(Method::PUT, path)
+            .header(reqwest::header::CONTENT_LENGTH, len)
+            .body(stream(file, len))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "The WebDAV server answered with {}",
+                response.status()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The next `len` bytes of `file`, read as they are sent.
+fn stream(file: tokio::fs::File, len: u64) -> reqwest::Body {
+    reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file.take(len)))
+}
+
+/// Uploads with the `sftp` program of OpenSSH, which resumes transfers with `reput`. It
+/// logs in with keys, like from the SSH agent, as there is nobody to type a password.
+pub struct SftpRemote {
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: String,
+    pub identity: Option<PathBuf>,
+    /// Directory on the server the files go in.
+    pub path: String,
+}
+
+impl SftpRemote {
+    fn remote_path(&self, path: &str) -> String {
+        format!("{}/{path}", self.path.trim_end_matches('/'))
+    }
+
+    /// Runs the commands in batch mode, where those starting with `-` may fail.
+    async fn batch(&self, commands: &str) -> anyhow::Result<String> {
+        let mut command = tokio::process::Command::new("sftp");
+        command.args(["-b", "-", "-o", "BatchMode=yes"]);
+        if let Some(port) = self.port {
+            command.args(["-P", &port.to_string()]);
+        }
+        if let Some(identity) = &self.identity {
+            command.arg("-i").arg(identity);
+        }
+        let mut child = command
+            .arg(format!("{}@{}", self.user, self.host))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| anyhow!("Could not run sftp: {err}"))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(commands.as_bytes()).await?;
+        }
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!(
+                "sftp exited with {}: {}",
+                output.status,
+                stderr.trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+#[async_trait]
+impl Remote for SftpRemote {
+    async fn len(&self, path: &str) -> anyhow::Result<Option<u64>> {
+        let listed = self
+            .batch(&format!("-ls -ln {}\n", quote(&self.remote_path(path))))
+            .await?;
+        Ok(listed
+            .lines()
+            .filter(|line| !line.starts_with("sftp>"))
+            .find_map(|line| line.split_whitespace().nth(4)?.parse().ok()))
+    }
+
+    async fn upload(
+        &self,
+        local: &Path,
+        path: &str,
+        offset: u64,
+        _resume: &mut Option<String>,
+    ) -> anyhow::Result<()> {
+        let mut commands = String::new();
+        let mut parent = self.path.trim_end_matches('/').to_string();
+        for dir in parents(path) {
+            parent = format!("{parent}/{dir}");
+            commands += &format!("-mkdir {}\n", quote(&parent));
+        }
+        let put = if offset > 0 { "reput" } else { "put" };
+        commands += &format!(
+            "{put} {} {}\n",
+            quote(&local.to_string_lossy()),
+            quote(&self.remote_path(path))
+        );
+        self.batch(&commands).await?;
+        Ok(())
+    }
+}
+
+/// The directories `path` is in, outermost first.
+fn parents(path: &str) -> Vec<&str> {
+    let mut dirs = path.split('/').collect::<Vec<_>>();
+    dirs.pop();
+    dirs
+}
+
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Where the renders are mirrored to.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RemoteConfig {
+    WebDav {
+        url: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Sftp {
+        host: String,
+        #[serde(default)]
+        port: Option<u16>,
+        user: String,
+        /// Private key to log in with, instead of the default ones.
+        #[serde(default)]
+        identity: Option<PathBuf>,
+        path: String,
+    },
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+impl RemoteConfig {
+    pub fn build(&self) -> Arc<dyn Remote> {
+        match self.clone() {
+            RemoteConfig::WebDav {
+                url,
+                username,
+                password,
+            } => Arc::new(WebDavRemote {
+                url,
+                username,
+                password,
+            }),
+            RemoteConfig::Sftp {
+                host,
+                port,
+                user,
+                identity,
+                path,
+            } => Arc::new(SftpRemote {
+                host,
+                port,
+                user,
+                identity,
+                path,
+            }),
+            RemoteConfig::S3 {
+                endpoint,
+                bucket,
+                region,
+                access_key,
+                secret_key,
+                prefix,
+            } => Arc::new(S3Remote {
+                endpoint,
+                bucket,
+                region,
+                access_key,
+                secret_key,
+                prefix,
+            }),
+        }
+    }
+}
+
+/// How the renders are mirrored.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SyncConfig {
+    pub remote: RemoteConfig,
+    /// Attempts at each file after the first one fails, waiting twice as long each time.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+impl SyncConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Could not read the sync config {path:?}: {err}"))?;
+        serde_json::from_str(&content).map_err(|err| anyhow!("Invalid sync config {path:?}: {err}"))
+    }
+}
+
+/// A file as it was when it was last uploaded.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct Synced {
+    len: u64,
+    /// Milliseconds since the epoch.
+    modified: u128,
+    /// Whether the upload finished, otherwise it is resumed.
+    done: bool,
+    /// The upload to resume on remotes that upload in parts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resume: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct RemoteSync<S: Storage> {
+    storage: S,
+    remote: Arc<dyn Remote>,
+    retries: u32,
+    retry_delay: Duration,
+    /// Syncs run one at a time, so that two never upload the same file.
+    running: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl<S: Storage> RemoteSync<S> {
+    pub fn new(storage: S, config: &SyncConfig) -> Self {
+        Self {
+            storage,
+            remote: config.remote.build(),
+            retries: config.retries,
+            retry_delay: Duration::from_secs(2),
+            running: Arc::default(),
+        }
+    }
+
+    async fn ledger(&self) -> anyhow::Result<HashMap<String, Synced>> {
+        Ok(match self.storage.read(LEDGER).await? {
+            Some(content) => serde_json::from_slice(&content)?,
+            None => HashMap::new(),
+        })
+    }
+
+    async fn files(&self) -> anyhow::Result<Vec<String>> {
+        let mut files = vec![];
+        let mut dirs = vec![SYNCED_DIR.to_string()];
+        while let Some(dir) = dirs.pop() {
+            for path in self.storage.list(&dir).await? {
+                if self.storage.path_buf(&path).is_dir() {
+                    dirs.push(path);
+                } else if !path.ends_with(".partial.wav") {
+                    // Checkpoints of running renders are rewritten until they end.
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Uploads the files that are new or changed since the last sync, and finishes the
+    /// uploads that were cut. Returns how many files were uploaded.
+    pub async fn sync(&self) -> anyhow::Result<usize> {
+        let _running = self.running.lock().await;
+        let mut ledger = self.ledger().await?;
+        let mut uploaded = 0;
+        let mut failed = vec![];
+        for path in self.files().await? {
+            let local = self.storage.path_buf(&path);
+            let metadata = tokio::fs::metadata(&local).await?;
+            let current = Synced {
+                len: metadata.len(),
+                modified: metadata
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis()),
+                done: true,
+                resume: None,
+            };
+            // Uploads of a file that changed since are not resumed.
+            let (resumable, mut resume) = match ledger.get(&path) {
+                Some(synced) if synced == &current => continue,
+                Some(synced)
+                    if synced.len == current.len && synced.modified == current.modified =>
+                {
+                    (true, synced.resume.clone())
+                }
+                _ => (false, None),
+            };
+            // Marked before uploading, so that an upload cut by a restart is resumed.
+            ledger.insert(
+                path.clone(),
+                Synced {
+                    done: false,
+                    resume: resume.clone(),
+                    ..current.clone()
+                },
+            );
+            self.storage
+                .write(LEDGER, serde_json::to_vec(&ledger)?)
+                .await?;
+            let result = self
+                .upload(&local, &path, resumable, &mut resume, current.len)
+                .await;
+            match result {
+                Ok(()) => {
+                    ledger.insert(path, current);
+                    self.storage
+                        .write(LEDGER, serde_json::to_vec(&ledger)?)
+                        .await?;
+                    uploaded += 1;
+                }
+                Err(err) => {
+                    warn!("Could not upload {path} to the remote: {err}");
+                    ledger.insert(
+                        path.clone(),
+                        Synced {
+                            done: false,
+                            resume,
+                            ..current
+                        },
+                    );
+                    self.storage
+                        .write(LEDGER, serde_json::to_vec(&ledger)?)
+                        .await?;
+                    failed.push(path);
+                }
+            }
+        }
+        if !failed.is_empty() {
+            return Err(anyhow!(
+                "{} files were not uploaded, they are retried after the next render",
+                failed.len()
+            ));
+        }
+        Ok(uploaded)
+    }
+
+    /// Uploads a file with retries, each one resuming from what reached the remote.
+    async fn upload(
+        &self,
+        local: &Path,
+        path: &str,
+        mut resumable: bool,
+        resume: &mut Option<String>,
+        len: u64,
+    ) -> anyhow::Result<()> {
+        let mut attempt = 0;
+        loop {
+            // Remote files longer than the local one are stale, and replaced.
+            let offset = match resumable {
+                true => self.remote.len(path).await.ok().flatten().unwrap_or(0),
+                false => 0,
+            };
+            let result = match offset {
+                offset if offset == len => Ok(()),
+                offset if offset > len => self.remote.upload(local, path, 0, resume).await,
+                offset => self.remote.upload(local, path, offset, resume).await,
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt >= self.retries => return Err(err),
+                Err(err) => {
+                    let delay = self.retry_delay * 2u32.pow(attempt);
+                    warn!("Uploading {path} failed, retrying in {delay:?}: {err}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    resumable = true;
+                }
+            }
+        }
+    }
+
+    /// Catches up with the renders made while the server was down, and then syncs after
+    /// each one completes, until the server stops.
+    pub fn spawn(
+        self,
+        mut rx: tokio::sync::broadcast::Receiver<GenerationMessage>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut pending = true;
+            loop {
+                if pending {
+                    match self.sync().await {
+                        Ok(0) => {}
+                        Ok(uploaded) => info!("Uploaded {uploaded} files to the remote"),
+                        Err(err) => warn!("Could not sync the renders: {err}"),
+                    }
+                }
+                pending = match rx.recv().await {
+                    Ok(GenerationMessage::Result(_)) | Err(RecvError::Lagged(_)) => true,
+                    Ok(_) => false,
+                    Err(RecvError::Closed) => return,
+                };
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    /// Keeps the uploads in memory, cutting the first ones halfway.
+    #[derive(Default)]
+    struct MemoryRemote {
+        files: Mutex<HashMap<String, Vec<u8>>>,
+        failures: Mutex<usize>,
+        offsets: Mutex<Vec<u64>>,
+        resumed: Mutex<Vec<Option<String>>>,
+    }
+
+    #[async_trait]
+    impl Remote for Arc<MemoryRemote> {
+        async fn len(&self, path: &str) -> anyhow::Result<Option<u64>> {
+            let files = self.files.lock().unwrap();
+            Ok(files.get(path).map(|data| data.len() as u64))
+        }
+
+        async fn upload(
+            &self,
+            local: &Path,
+            path: &str,
+            offset: u64,
+            resume: &mut Option<String>,
+        ) -> anyhow::Result<()> {
+            let data = std::fs::read(local)?;
+            self.offsets.lock().unwrap().push(offset);
+            self.resumed.lock().unwrap().push(resume.clone());
+            let mut files = self.files.lock().unwrap();
+            let file = files.entry(path.to_string()).or_default();
+            file.truncate(offset as usize);
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                let half = offset as usize + (data.len() - offset as usize) / 2;
+                file.extend(&data[offset as usize..half]);
+                *resume = Some(format!("{path}@{half}"));
+                return Err(anyhow!("Connection reset"));
+            }
+            file.extend(&data[offset as usize..]);
+            *resume = None;
+            Ok(())
+        }
+    }
+
+    /// Stores the files it is given with PUT in memory, ignoring their `Content-Range`.
+    async fn serve_webdav(files: Arc<Mutex<HashMap<String, Vec<u8>>>>) -> String {
+        use axum::body::Bytes;
+        use axum::extract::{Path as UrlPath, State};
+        use axum::routing::get;
+
+        type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+        async fn get_file(
+            State(files): State<Files>,
+            UrlPath(path): UrlPath<String>,
+        ) -> Result<Vec<u8>, StatusCode> {
+            let files = files.lock().unwrap();
+            files.get(&path).cloned().ok_or(StatusCode::NOT_FOUND)
+        }
+        async fn put_file(
+            State(files): State<Files>,
+            UrlPath(path): UrlPath<String>,
+            body: Bytes,
+        ) -> StatusCode {
+            files.lock().unwrap().insert(path, body.to_vec());
+            StatusCode::CREATED
+        }
+
+        let app = axum::Router::new()
+            .route("/*path", get(get_file).put(put_file))
+            .fallback(|| async { StatusCode::METHOD_NOT_ALLOWED })
+            .with_state(files);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn uploads_all_of_the_file_when_the_range_is_ignored() -> anyhow::Result<()> {
+        let files = Arc::new(Mutex::new(HashMap::new()));
+        let remote = WebDavRemote {
+            url: serve_webdav(files.clone()).await,
+            username: None,
+            password: None,
+        };
+        let audio = (0..=255).collect::<Vec<u8>>();
+        let storage = AppFs::new_tmp();
+        storage.write("audios/a.wav", &audio).await?;
+        let local = storage.path_buf("audios/a.wav");
+        files
+            .lock()
+            .unwrap()
+            .insert("audios/a.wav".to_string(), audio[..100].to_vec());
+
+        assert_eq!(remote.len("audios/a.wav").await?, Some(100));
+        remote
+            .upload(&local, "audios/a.wav", 100, &mut None)
+            .await?;
+        assert_eq!(files.lock().unwrap()["audios/a.wav"], audio);
+        assert_eq!(remote.len("audios/a.wav").await?, Some(256));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_cut_uploads() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let memory = Arc::new(MemoryRemote::default());
+        let sync = |retries| RemoteSync {
+            storage: storage.clone(),
+            remote: Arc::new(memory.clone()),
+            retries,
+            retry_delay: Duration::ZERO,
+            running: Arc::default(),
+        };
+        let audio = (0..=255).collect::<Vec<u8>>();
+        storage.write("audios/a.wav", &audio).await?;
+        storage.write("audios/stems/a.wav", b"drums").await?;
+        storage.write("audios/b.partial.wav", b"running").await?;
+
+        *memory.failures.lock().unwrap() = 2;
+        assert_eq!(sync(5).sync().await?, 2);
+        assert_eq!(*memory.offsets.lock().unwrap(), [0, 128, 192, 0]);
+        assert_eq!(memory.files.lock().unwrap()["audios/a.wav"], audio);
+        assert_eq!(memory.files.lock().unwrap()["audios/stems/a.wav"], b"drums");
+        assert!(!memory
+            .files
+            .lock()
+            .unwrap()
+            .contains_key("audios/b.partial.wav"));
+
+        // Files that did not change are not uploaded again.
+        assert_eq!(sync(5).sync().await?, 0);
+        storage.write("audios/stems/a.wav", b"bass").await?;
+        memory.offsets.lock().unwrap().clear();
+        assert_eq!(sync(5).sync().await?, 1);
+        assert_eq!(*memory.offsets.lock().unwrap(), [0]);
+        assert_eq!(memory.files.lock().unwrap()["audios/stems/a.wav"], b"bass");
+
+        // Uploads that ran out of retries are resumed by the next sync.
+        storage.write("audios/c.wav", &audio).await?;
+        memory.offsets.lock().unwrap().clear();
+        memory.resumed.lock().unwrap().clear();
+        *memory.failures.lock().unwrap() = 1;
+        assert!(sync(0).sync().await.is_err());
+        assert_eq!(sync(0).sync().await?, 1);
+        assert_eq!(*memory.offsets.lock().unwrap(), [0, 128]);
+        assert_eq!(
+            *memory.resumed.lock().unwrap(),
+            [None, Some("audios/c.wav@128".to_string())]
+        );
+        assert_eq!(memory.files.lock().unwrap()["audios/c.wav"], audio);
+
+        // Uploads of a file that changed after they were cut start over.
+        let changed = (0..200).rev().collect::<Vec<u8>>();
+        storage.write("audios/d.wav", &audio).await?;
+        memory.offsets.lock().unwrap().clear();
+        memory.resumed.lock().unwrap().clear();
+        *memory.failures.lock().unwrap() = 1;
+        assert!(sync(0).sync().await.is_err());
+        storage.write("audios/d.wav", &changed).await?;
+        assert_eq!(sync(0).sync().await?, 1);
+        assert_eq!(*memory.offsets.lock().unwrap(), [0, 0]);
+        assert_eq!(*memory.resumed.lock().unwrap(), [None, None]);
+        assert_eq!(memory.files.lock().unwrap()["audios/d.wav"], changed);
+        Ok(())
+    }
+}
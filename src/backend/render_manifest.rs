@@ -0,0 +1,1003 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::audio::daw_project::{Clip, Track};
+use crate::audio::denoise::DenoiseConfig;
+use crate::audio::effects::{Effects, NoiseGateConfig};
+use crate::audio::ending::DownbeatEnd;
+use crate::audio::extended_generation::{ExtendedGenerationConfig, SegmentRetry};
+use crate::audio::fingerprint::similarity;
+use crate::audio::gain_staging::{Normalization, SegmentGain};
+use crate::audio::intro_outro::IntroOutro;
+use crate::audio::markers::{Marker, SegmentMarker};
+use crate::audio::wav::decode_wav;
+use crate::backend::audio_generation_backend::JobCheckpoint;
+use crate::backend::model_registry::ModelVersion;
+use crate::backend::usage::LOCAL_USER;
+use crate::cli::SAMPLING_RATE;
+use crate::storage::Storage;
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub enum RenderStatus {
+    Running,
+    Completed,
+    Failed,
+    /// Waiting to be resumed after a shutdown.
+    Pending,
+}
+
+/// Metadata about a render, stored next to its audio file, that allows knowing how
+/// the audio was produced.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct RenderManifest {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    /// The model that produced the audio, if known.
+    pub model: Option<ModelVersion>,
+    pub created_at: u128,
+    pub status: RenderStatus,
+    pub error: Option<String>,
+    /// Segments that failed and were generated again.
+    #[serde(default)]
+    pub retries: Vec<RenderRetry>,
+    /// Gains the segments got when normalized, in decibels.
+    #[serde(default)]
+    pub gains: Vec<RenderGain>,
+    /// Where a render interrupted by a shutdown can continue from.
+    #[serde(default)]
+    pub checkpoint: Option<RenderCheckpoint>,
+    /// How the audio was produced besides the model, for replaying the render.
+    #[serde(default)]
+    pub recipe: Option<RenderRecipe>,
+    /// The audio each segment was generated with, before being processed and stitched,
+    /// for generating the render again with some of its segments edited.
+    #[serde(default)]
+    pub segment_audio: Vec<String>,
+    /// Who requested the render, for accounting its usage.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Acoustic fingerprint of the audio, for finding renders that sound nearly the same.
+    #[serde(default)]
+    pub fingerprint: Vec<u32>,
+    /// Where each segment begins in the audio, exported as cue points and chapters.
+    #[serde(default)]
+    pub markers: Vec<RenderMarker>,
+}
+
+/// A render that sounds like another one, and how much, from 0 to 1.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct SimilarRender {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub prompt: String,
+    pub similarity: f32,
+}
+
+/// Everything besides the model and the prompt that shaped the audio of a render.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct RenderRecipe {
+    /// Seed of the sampling of the model. Renders without one cannot be reproduced
+    /// exactly.
+    pub seed: Option<u64>,
+    /// Tokenizer file that replaced the default one of the model.
+    pub tokenizer: Option<String>,
+    /// The prompt the model was given for each segment.
+    pub segment_prompts: Vec<String>,
+    /// The seed each segment was sampled with instead of [RenderRecipe::seed], for
+    /// renders with edited segments.
+    #[serde(default)]
+    pub segment_seeds: Vec<Option<u64>>,
+    pub post: PostChain,
+}
+
+/// Processing applied to the segments of extended renders, as set in the command line.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct PostChain {
+    pub intro_outro: Option<String>,
+    pub noise_gate_db: Option<f32>,
+    pub noise_gate_release_ms: f32,
+    pub denoise: Option<f32>,
+    pub normalize_segments_db: Option<f32>,
+    pub headroom_db: Option<f32>,
+    /// Seconds the end of extended renders can move for ending on a downbeat, if it does.
+    #[serde(default)]
+    pub end_on_downbeat: Option<f32>,
+    /// Seconds of each segment, of the overlap between segments and of the crossfade over
+    /// it, if not the default ones.
+    #[serde(default)]
+    pub segment_secs: Option<f32>,
+    #[serde(default)]
+    pub overlap_secs: Option<f32>,
+    #[serde(default)]
+    pub crossfade_secs: Option<f32>,
+    /// Whether each segment continues the previous one.
+    #[serde(default)]
+    pub continue_segments: bool,
+}
+
+impl Default for PostChain {
+    fn default() -> Self {
+        Self {
+            intro_outro: None,
+            noise_gate_db: None,
+            noise_gate_release_ms: 200.0,
+            denoise: None,
+            normalize_segments_db: None,
+            headroom_db: None,
+            end_on_downbeat: None,
+            segment_secs: None,
+            overlap_secs: None,
+            crossfade_secs: None,
+            continue_segments: false,
+        }
+    }
+}
+
+impl PostChain {
+    /// `config` with this processing instead of its own.
+    pub fn apply(&self, config: ExtendedGenerationConfig) -> ExtendedGenerationConfig {
+        ExtendedGenerationConfig {
+            intro_outro: self
+                .intro_outro
+                .as_deref()
+                .and_then(IntroOutro::preset)
+                .unwrap_or_default(),
+            effects: Effects {
+                noise_gate: self.noise_gate_db.map(|threshold_db| NoiseGateConfig {
+                    threshold_db,
+                    release_ms: self.noise_gate_release_ms,
+                    ..Default::default()
+                }),
+                headroom_db: self.headroom_db,
+            },
+            denoise: self.denoise.map(|strength| DenoiseConfig { strength }),
+            normalize: self
+                .normalize_segments_db
+                .map(|target_rms_db| Normalization {
+                    target_rms_db,
+                    ..Default::default()
+                }),
+            end_on_downbeat: self.end_on_downbeat.map(|tolerance| DownbeatEnd {
+                tolerance,
+                ..Default::default()
+            }),
+            segment_duration: self.segment_secs.unwrap_or(config.segment_duration),
+            overlap_duration: self.overlap_secs.unwrap_or(config.overlap_duration),
+            crossfade_duration: self.crossfade_secs.unwrap_or(config.crossfade_duration),
+            continue_segments: self.continue_segments,
+            ..config
+        }
+    }
+}
+
+/// The settings every render of a server is produced with.
+#[derive(Clone, Debug, Default)]
+pub struct RenderSettings {
+    pub seed: Option<u64>,
+    /// Tokenizer files that replace the default ones, by model name.
+    pub tokenizers: HashMap<String, PathBuf>,
+    pub post: PostChain,
+    /// Whether renders are saved with a watermark labelling them as AI-generated.
+    pub watermark: bool,
+    /// Embedded into the metadata of each render along with its provenance, if any.
+    pub license: Option<String>,
+}
+
+impl RenderSettings {
+    pub fn recipe(
+        &self,
+        model: Option<&ModelVersion>,
+        segment_prompts: Vec<String>,
+    ) -> RenderRecipe {
+        RenderRecipe {
+            seed: self.seed,
+            tokenizer: model
+                .and_then(|model| self.tokenizers.get(&model.name))
+                .map(|path| path.to_string_lossy().to_string()),
+            segment_prompts,
+            segment_seeds: vec![],
+            post: self.post.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct RenderRetry {
+    pub segment: usize,
+    pub attempt: usize,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct RenderGain {
+    pub segment: usize,
+    pub gain_db: f32,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct RenderMarker {
+    pub segment: usize,
+    pub start_secs: f64,
+    /// How long the segment crossfades with the previous one.
+    #[serde(default)]
+    pub crossfade_secs: f64,
+}
+
+/// The audio of the segments that were completed before a shutdown, stored next to the
+/// manifest.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct RenderCheckpoint {
+    pub segments: usize,
+    pub samples: usize,
+    pub relpath: String,
+}
+
+impl RenderCheckpoint {
+    pub fn new(id: Uuid, segments: usize, samples: usize) -> Self {
+        Self {
+            segments,
+            samples,
+            relpath: format!("audios/{id}.partial.wav"),
+        }
+    }
+}
+
+impl From<SegmentRetry> for RenderRetry {
+    fn from(retry: SegmentRetry) -> Self {
+        Self {
+            segment: retry.segment,
+            attempt: retry.attempt,
+            error: retry.error,
+        }
+    }
+}
+
+impl From<SegmentMarker> for RenderMarker {
+    fn from(marker: SegmentMarker) -> Self {
+        Self {
+            segment: marker.segment,
+            start_secs: marker.start as f64 / SAMPLING_RATE as f64,
+            crossfade_secs: marker.crossfade as f64 / SAMPLING_RATE as f64,
+        }
+    }
+}
+
+impl From<SegmentGain> for RenderGain {
+    fn from(gain: SegmentGain) -> Self {
+        Self {
+            segment: gain.segment,
+            gain_db: gain.gain_db,
+        }
+    }
+}
+
+impl RenderManifest {
+    pub fn new(
+        id: Uuid,
+        chat_id: Uuid,
+        prompt: String,
+        secs: usize,
+        model: Option<ModelVersion>,
+    ) -> Self {
+        Self {
+            id,
+            chat_id,
+            prompt,
+            secs,
+            model,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            status: RenderStatus::Running,
+            error: None,
+            retries: vec![],
+            gains: vec![],
+            checkpoint: None,
+            recipe: None,
+            segment_audio: vec![],
+            user: None,
+            fingerprint: vec![],
+            markers: vec![],
+        }
+    }
+
+    /// Who requested the render. Renders from before users were recorded belong to
+    /// [LOCAL_USER].
+    pub fn owner(&self) -> &str {
+        self.user.as_deref().unwrap_or(LOCAL_USER)
+    }
+
+    /// Reads a manifest from anywhere, like one copied out of the data directory.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path)
+            .map_err(|err| anyhow!("Could not read the manifest {path:?}: {err}"))?;
+        serde_json::from_slice(&content).map_err(|err| anyhow!("Invalid manifest {path:?}: {err}"))
+    }
+
+    fn path(id: Uuid) -> String {
+        format!("audios/{id}.json")
+    }
+
+    pub async fn load<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<Option<Self>> {
+        match storage.read(&Self::path(id)).await? {
+            Some(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every render with a readable manifest, oldest first.
+    pub async fn load_all<S: Storage>(storage: &S) -> anyhow::Result<Vec<Self>> {
+        let mut result = vec![];
+        for path in storage.list("audios").await? {
+            let Some(id) = path
+                .strip_prefix("audios/")
+                .and_then(|name| name.strip_suffix(".json"))
+                .and_then(|id| Uuid::parse_str(id).ok())
+            else {
+                continue;
+            };
+            if let Ok(Some(manifest)) = Self::load(storage, id).await {
+                result.push(manifest);
+            }
+        }
+        result.sort_by_key(|v| v.created_at);
+        Ok(result)
+    }
+
+    /// Renders that were running or pending when the process stopped.
+    pub async fn load_unfinished<S: Storage>(storage: &S) -> anyhow::Result<Vec<Self>> {
+        let mut result = Self::load_all(storage).await?;
+        result.retain(|manifest| {
+            matches!(
+                manifest.status,
+                RenderStatus::Running | RenderStatus::Pending
+            )
+        });
+        Ok(result)
+    }
+
+    pub async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        Ok(storage
+            .write(&Self::path(self.id), serde_json::to_vec_pretty(self)?)
+            .await?)
+    }
+
+    /// Marks a previously saved render as finished. Renders without a manifest are ignored.
+    /// Failed renders keep their checkpoint, so that requesting them again resumes them.
+    pub async fn finish<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        error: Option<String>,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        manifest.status = match error {
+            Some(_) => RenderStatus::Failed,
+            None => RenderStatus::Completed,
+        };
+        if manifest.status == RenderStatus::Completed {
+            if let Some(checkpoint) = manifest.checkpoint.take() {
+                storage.rm(&checkpoint.relpath).await?;
+            }
+        }
+        manifest.error = error;
+        manifest.save(storage).await
+    }
+
+    /// Records how far a running render got. Renders without a manifest are ignored.
+    pub async fn record_checkpoint<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        checkpoint: RenderCheckpoint,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        manifest.checkpoint = Some(checkpoint);
+        manifest.save(storage).await
+    }
+
+    /// Loads the audio of the render's checkpoint, if it has one.
+    pub async fn load_checkpoint<S: Storage>(
+        &self,
+        storage: &S,
+    ) -> anyhow::Result<Option<JobCheckpoint>> {
+        let Some(checkpoint) = &self.checkpoint else {
+            return Ok(None);
+        };
+        let Some(bytes) = storage.read(&checkpoint.relpath).await? else {
+            return Err(anyhow!("Checkpoint {} is missing", checkpoint.relpath));
+        };
+        let mut audio = decode_wav(&bytes).map_err(|err| anyhow!(err))?;
+        if audio.len() < checkpoint.samples {
+            return Err(anyhow!("Checkpoint {} is truncated", checkpoint.relpath));
+        }
+        audio.truncate(checkpoint.samples);
+        Ok(Some(JobCheckpoint {
+            segments: checkpoint.segments,
+            audio,
+        }))
+    }
+
+    /// Marks a previously saved render as pending, so that it continues from `checkpoint`
+    /// after a restart. Renders without a manifest are ignored.
+    pub async fn suspend<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        checkpoint: RenderCheckpoint,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        manifest.status = RenderStatus::Pending;
+        manifest.checkpoint = Some(checkpoint);
+        manifest.save(storage).await
+    }
+
+    /// Adds a retry to a previously saved render. Renders without a manifest are ignored.
+    pub async fn record_retry<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        retry: RenderRetry,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        manifest.retries.push(retry);
+        manifest.save(storage).await
+    }
+
+    /// Adds the gain of a normalized segment to a previously saved render. Renders without
+    /// a manifest are ignored.
+    pub async fn record_gain<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        gain: RenderGain,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        manifest.gains.push(gain);
+        manifest.save(storage).await
+    }
+
+    /// Saves the audio a segment of a previously saved render was generated with. Renders
+    /// without a manifest are ignored.
+    pub async fn record_segment<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        segment: usize,
+        bytes: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        let relpath = format!("audios/{id}.segment-{segment}.wav");
+        storage.write(&relpath, bytes).await?;
+        // Segments are generated in order, so this one replaces any later one.
+        manifest.segment_audio.truncate(segment);
+        manifest.segment_audio.push(relpath);
+        manifest.save(storage).await
+    }
+
+    /// Saves where a segment of a previously saved render begins. Renders without a
+    /// manifest are ignored.
+    pub async fn record_marker<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        marker: RenderMarker,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        // Segments are generated in order, so this one replaces any later one.
+        manifest.markers.truncate(marker.segment);
+        manifest.markers.push(marker);
+        manifest.save(storage).await
+    }
+
+    /// The markers of the segments, labelled with the prompts they were generated with.
+    /// Renders of a single segment have none.
+    pub fn section_markers(&self) -> Vec<Marker> {
+        if self.markers.len() < 2 {
+            return vec![];
+        }
+        let prompts = self.recipe.as_ref().map(|r| &r.segment_prompts);
+        self.markers
+            .iter()
+            .map(|marker| Marker {
+                start: (marker.start_secs * SAMPLING_RATE as f64).round() as usize,
+                label: prompts
+                    .and_then(|prompts| prompts.get(marker.segment))
+                    .cloned()
+                    .unwrap_or_else(|| format!("Segment {}", marker.segment + 1)),
+            })
+            .collect()
+    }
+
+    /// The tracks of a DAW project with each segment as it was generated, placed and faded
+    /// like it was stitched into the `samples` of the render, over a muted track with the
+    /// render itself. Renders of a single segment have none.
+    pub fn daw_tracks(&self, samples: usize) -> Vec<Track> {
+        let segments = self.segment_audio.len().min(self.markers.len());
+        if segments < 2 {
+            return vec![];
+        }
+        let to_samples = |secs: f64| (secs * SAMPLING_RATE as f64).round() as usize;
+        let file_name = |relpath: &str| relpath.rsplit('/').next().unwrap_or(relpath).to_string();
+        let placed = self.markers[..segments]
+            .iter()
+            .map(|marker| {
+                let crossfade = to_samples(marker.crossfade_secs);
+                (
+                    to_samples(marker.start_secs).saturating_sub(crossfade / 2),
+                    crossfade,
+                )
+            })
+            .collect::<Vec<_>>();
+        let labels = self.section_markers();
+        let mut tracks = vec![Track {
+            name: "Render".to_string(),
+            muted: true,
+            clips: vec![Clip {
+                name: self.prompt.clone(),
+                file: format!("{}.wav", self.id),
+                position: 0,
+                length: samples,
+                fade_in: 0,
+                fade_out: 0,
+                gain_db: 0.0,
+            }],
+        }];
+        for (i, &(position, fade_in)) in placed.iter().enumerate() {
+            // Each segment ends where the next one finishes fading in, the last one is cut
+            // where the render ends.
+            let (end, fade_out) = match placed.get(i + 1) {
+                Some(&(next, crossfade)) => (next + crossfade, crossfade),
+                None => (samples, 0),
+            };
+            let gain_db = self.gains.iter().rev().find(|gain| gain.segment == i);
+            tracks.push(Track {
+                name: format!("Segment {}", i + 1),
+                muted: false,
+                clips: vec![Clip {
+                    name: labels[i].label.clone(),
+                    file: file_name(&self.segment_audio[i]),
+                    position,
+                    length: end.saturating_sub(position),
+                    fade_in,
+                    fade_out,
+                    gain_db: gain_db.map_or(0.0, |gain| gain.gain_db),
+                }],
+            });
+        }
+        tracks
+    }
+
+    /// Saves the fingerprint of a previously saved render. Renders without a manifest are
+    /// ignored.
+    pub async fn record_fingerprint<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        fingerprint: Vec<u32>,
+    ) -> anyhow::Result<()> {
+        let Some(mut manifest) = Self::load(storage, id).await? else {
+            return Ok(());
+        };
+        manifest.fingerprint = fingerprint;
+        manifest.save(storage).await
+    }
+
+    /// Other renders that sound at least `min_similarity` like `fingerprint`, the most
+    /// similar first, only among the ones of `owner` if given. Renders saved before
+    /// fingerprints existed are never found.
+    pub async fn find_similar<S: Storage>(
+        storage: &S,
+        id: Uuid,
+        fingerprint: &[u32],
+        min_similarity: f32,
+        owner: Option<&str>,
+    ) -> anyhow::Result<Vec<SimilarRender>> {
+        let mut result = Self::load_all(storage)
+            .await?
+            .into_iter()
+            .filter(|manifest| manifest.id != id && !manifest.fingerprint.is_empty())
+            .filter(|manifest| owner.is_none_or(|owner| manifest.owner() == owner))
+            .map(|manifest| SimilarRender {
+                similarity: similarity(fingerprint, &manifest.fingerprint),
+                id: manifest.id,
+                chat_id: manifest.chat_id,
+                prompt: manifest.prompt,
+            })
+            .filter(|render| render.similarity >= min_similarity)
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        Ok(result)
+    }
+
+    /// Loads the audio each segment of the render was generated with.
+    pub async fn load_segments<S: Storage>(&self, storage: &S) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut result = vec![];
+        for relpath in &self.segment_audio {
+            let Some(bytes) = storage.read(relpath).await? else {
+                return Err(anyhow!("Segment {relpath} is missing"));
+            };
+            result.push(decode_wav(&bytes).map_err(|err| anyhow!(err))?);
+        }
+        Ok(result)
+    }
+
+    /// Stitching audio from different models together produces inconsistent results, so
+    /// a render can only be resumed with the exact model it started with.
+    pub fn check_resume(&self, model: Option<&ModelVersion>) -> anyhow::Result<()> {
+        let Some(started_with) = &self.model else {
+            return Ok(());
+        };
+        match model {
+            Some(model) if model.hash == started_with.hash => Ok(()),
+            Some(model) => Err(anyhow!(
+                "Render {} started with {} ({}), it cannot be resumed with {} ({})",
+                self.id,
+                started_with.name,
+                started_with.short_hash(),
+                model.name,
+                model.short_hash()
+            )),
+            None => Err(anyhow!(
+                "Render {} started with {} ({}), it cannot be resumed with an unknown model",
+                self.id,
+                started_with.name,
+                started_with.short_hash()
+            )),
+        }
+    }
+
+    /// The model and the recipe of the render, failing if they were not recorded or if a
+    /// file they reference is gone, as the render would not be the same without them.
+    pub fn check_replay(&self) -> anyhow::Result<(&ModelVersion, &RenderRecipe)> {
+        let Some(model) = &self.model else {
+            return Err(anyhow!(
+                "Render {} does not record its model, it cannot be replayed",
+                self.id
+            ));
+        };
+        let Some(recipe) = &self.recipe else {
+            return Err(anyhow!(
+                "Render {} does not record how it was produced, it cannot be replayed",
+                self.id
+            ));
+        };
+        if !recipe.segment_seeds.is_empty() {
+            return Err(anyhow!(
+                "Render {} has edited segments, it cannot be replayed",
+                self.id
+            ));
+        }
+        if let Some(tokenizer) = &recipe.tokenizer {
+            if !Path::new(tokenizer).is_file() {
+                return Err(anyhow!(
+                    "Render {} used the tokenizer {tokenizer}, which is missing",
+                    self.id
+                ));
+            }
+        }
+        Ok((model, recipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audio::AudioManager;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn version(hash: &str) -> ModelVersion {
+        ModelVersion {
+            name: "small".to_string(),
+            revision: "v1".to_string(),
+            hash: hash.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_and_finishes_renders() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let id = Uuid::new_v4();
+        let manifest = RenderManifest::new(
+            id,
+            Uuid::new_v4(),
+            "prompt".to_string(),
+            10,
+            Some(version("abc")),
+        );
+        manifest.save(&storage).await?;
+        assert_eq!(RenderManifest::load(&storage, id).await?, Some(manifest));
+
+        let retry = RenderRetry {
+            segment: 2,
+            attempt: 1,
+            error: "out of memory".to_string(),
+        };
+        RenderManifest::record_retry(&storage, id, retry.clone()).await?;
+        RenderManifest::finish(&storage, id, Some("boom".to_string())).await?;
+        let manifest = RenderManifest::load(&storage, id).await?.unwrap();
+        assert_eq!(manifest.status, RenderStatus::Failed);
+        assert_eq!(manifest.error, Some("boom".to_string()));
+        assert_eq!(manifest.retries, vec![retry]);
+
+        assert_eq!(RenderManifest::load(&storage, Uuid::new_v4()).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn suspends_renders_with_a_checkpoint() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let id = Uuid::new_v4();
+        let manifest = RenderManifest::new(id, Uuid::new_v4(), "".to_string(), 60, None);
+        manifest.save(&storage).await?;
+
+        let checkpoint = RenderCheckpoint::new(id, 1, 28);
+        RenderManifest::suspend(&storage, id, checkpoint.clone()).await?;
+        let manifest = RenderManifest::load(&storage, id).await?.unwrap();
+        assert_eq!(manifest.status, RenderStatus::Pending);
+        assert_eq!(manifest.checkpoint, Some(checkpoint));
+        assert_eq!(
+            manifest.checkpoint.unwrap().relpath,
+            format!("audios/{id}.partial.wav")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn loads_unfinished_renders_and_their_checkpoints() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let mut ids = vec![];
+        for status in [
+            RenderStatus::Running,
+            RenderStatus::Completed,
+            RenderStatus::Pending,
+        ] {
+            let id = Uuid::new_v4();
+            let mut manifest = RenderManifest::new(id, Uuid::new_v4(), "".to_string(), 60, None);
+            manifest.status = status;
+            manifest.save(&storage).await?;
+            ids.push(id);
+        }
+        // Audio written after the checkpoint was recorded is not part of it.
+        let checkpoint = RenderCheckpoint::new(ids[0], 1, 3);
+        let wav = crate::audio::wav::encode_wav([0.1, 0.2, 0.3, 0.4], 32000)?;
+        storage.write(&checkpoint.relpath, wav).await?;
+        RenderManifest::record_checkpoint(&storage, ids[0], checkpoint.clone()).await?;
+
+        let mut unfinished = RenderManifest::load_unfinished(&storage).await?;
+        // They might have been created in the same millisecond.
+        unfinished.sort_by_key(|m| ids.iter().position(|id| *id == m.id));
+        let unfinished_ids = unfinished.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(unfinished_ids, vec![ids[0], ids[2]]);
+        let loaded = unfinished[0].load_checkpoint(&storage).await?.unwrap();
+        assert_eq!(loaded.segments, 1);
+        assert_eq!(loaded.audio, vec![0.1, 0.2, 0.3]);
+        assert!(unfinished[1].load_checkpoint(&storage).await?.is_none());
+
+        // Completing the render removes its checkpoint.
+        RenderManifest::finish(&storage, ids[0], None).await?;
+        assert!(!storage.exists(&checkpoint.relpath).await?);
+        let manifest = RenderManifest::load(&storage, ids[0]).await?.unwrap();
+        assert_eq!(manifest.checkpoint, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn records_the_audio_of_segments() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let id = Uuid::new_v4();
+        let manifest = RenderManifest::new(id, Uuid::new_v4(), "".to_string(), 60, None);
+        manifest.save(&storage).await?;
+
+        let wav = |level: f32| AudioManager::default().to_wav(&[level; 100]);
+        RenderManifest::record_segment(&storage, id, 0, wav(0.25)?).await?;
+        RenderManifest::record_segment(&storage, id, 1, wav(0.5)?).await?;
+        // A segment generated again replaces the previous one.
+        RenderManifest::record_segment(&storage, id, 1, wav(-0.5)?).await?;
+        let manifest = RenderManifest::load(&storage, id).await?.unwrap();
+        assert_eq!(
+            manifest.segment_audio,
+            vec![
+                format!("audios/{id}.segment-0.wav"),
+                format!("audios/{id}.segment-1.wav")
+            ]
+        );
+        let segments = manifest.load_segments(&storage).await?;
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].iter().all(|s| (s - 0.25).abs() < 1e-3));
+        assert!(segments[1].iter().all(|s| (s + 0.5).abs() < 1e-3));
+
+        storage.rm(&manifest.segment_audio[1]).await?;
+        let err = manifest.load_segments(&storage).await.unwrap_err();
+        assert!(err.to_string().contains("segment-1.wav is missing"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn labels_the_markers_of_segments() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let id = Uuid::new_v4();
+        let mut manifest = RenderManifest::new(id, Uuid::new_v4(), "lofi".to_string(), 60, None);
+        manifest.recipe = Some(RenderSettings::default().recipe(
+            None,
+            vec!["lofi (introduction)".to_string(), "lofi".to_string()],
+        ));
+        manifest.save(&storage).await?;
+
+        let marker = |segment, start| {
+            SegmentMarker {
+                segment,
+                start,
+                crossfade: 0,
+            }
+            .into()
+        };
+        RenderManifest::record_marker(&storage, id, marker(0, 0)).await?;
+        let manifest = RenderManifest::load(&storage, id).await?.unwrap();
+        assert_eq!(manifest.section_markers(), vec![]);
+
+        RenderManifest::record_marker(&storage, id, marker(1, 50 * SAMPLING_RATE)).await?;
+        RenderManifest::record_marker(&storage, id, marker(2, 100 * SAMPLING_RATE)).await?;
+        // A segment generated again after a restart replaces the later ones.
+        RenderManifest::record_marker(&storage, id, marker(1, 27 * SAMPLING_RATE)).await?;
+        let manifest = RenderManifest::load(&storage, id).await?.unwrap();
+        assert_eq!(
+            manifest.section_markers(),
+            vec![
+                Marker {
+                    start: 0,
+                    label: "lofi (introduction)".to_string()
+                },
+                Marker {
+                    start: 27 * SAMPLING_RATE,
+                    label: "lofi".to_string()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn places_segments_on_daw_tracks() {
+        let id = Uuid::new_v4();
+        let mut manifest = RenderManifest::new(id, Uuid::new_v4(), "lofi".to_string(), 50, None);
+        assert_eq!(manifest.daw_tracks(50 * SAMPLING_RATE), vec![]);
+
+        manifest.segment_audio = (0..2)
+            .map(|i| format!("audios/{id}.segment-{i}.wav"))
+            .collect();
+        let marker = |segment, start_secs, crossfade_secs| RenderMarker {
+            segment,
+            start_secs,
+            crossfade_secs,
+        };
+        manifest.markers = vec![marker(0, 0.0, 0.0), marker(1, 27.0, 2.0)];
+        manifest.gains = vec![RenderGain {
+            segment: 1,
+            gain_db: -3.0,
+        }];
+        let tracks = manifest.daw_tracks(50 * SAMPLING_RATE);
+
+        assert_eq!(tracks.len(), 3);
+        assert!(tracks[0].muted);
+        assert_eq!(tracks[0].clips[0].file, format!("{id}.wav"));
+        let clips = tracks[1..]
+            .iter()
+            .map(|track| {
+                let clip = &track.clips[0];
+                (clip.position, clip.length, clip.fade_in, clip.fade_out)
+            })
+            .collect::<Vec<_>>();
+        let secs = |secs: usize| secs * SAMPLING_RATE;
+        assert_eq!(
+            clips,
+            vec![(0, secs(28), 0, secs(2)), (secs(26), secs(24), secs(2), 0)]
+        );
+        assert_eq!(tracks[2].clips[0].file, format!("{id}.segment-1.wav"));
+        assert_eq!(tracks[2].clips[0].gain_db, -3.0);
+    }
+
+    #[test]
+    fn only_resumes_with_the_same_model() {
+        let manifest = RenderManifest::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "".to_string(),
+            10,
+            Some(version("abc")),
+        );
+        assert!(manifest.check_resume(Some(&version("abc"))).is_ok());
+        assert!(manifest.check_resume(Some(&version("def"))).is_err());
+        assert!(manifest.check_resume(None).is_err());
+
+        let unknown = RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), "".to_string(), 10, None);
+        assert!(unknown.check_resume(Some(&version("def"))).is_ok());
+    }
+
+    #[test]
+    fn only_replays_renders_with_a_recipe_and_their_files() -> anyhow::Result<()> {
+        let mut manifest = RenderManifest::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "lofi".to_string(),
+            60,
+            Some(version("abc")),
+        );
+        assert!(manifest.check_replay().is_err());
+
+        let settings = RenderSettings {
+            seed: Some(42),
+            tokenizers: HashMap::from([("small".to_string(), PathBuf::from("/missing.json"))]),
+            post: PostChain {
+                denoise: Some(0.5),
+                ..Default::default()
+            },
+            watermark: false,
+            license: None,
+        };
+        let recipe = settings.recipe(manifest.model.as_ref(), vec!["lofi".to_string()]);
+        assert_eq!(recipe.tokenizer, Some("/missing.json".to_string()));
+        manifest.recipe = Some(recipe);
+        let err = manifest.check_replay().unwrap_err();
+        assert!(err.to_string().contains("/missing.json, which is missing"));
+
+        manifest.recipe = Some(RenderSettings::default().recipe(None, vec![]));
+        let (model, recipe) = manifest.check_replay()?;
+        assert_eq!(model.hash, "abc");
+        assert_eq!(recipe.tokenizer, None);
+
+        // Edited segments were generated with another prompt or seed than the render.
+        manifest.recipe.as_mut().unwrap().segment_seeds = vec![None, Some(7)];
+        let err = manifest.check_replay().unwrap_err();
+        assert!(err.to_string().contains("has edited segments"));
+
+        // Manifests written before recipes were recorded can still be read.
+        let mut json = serde_json::to_value(&manifest)?;
+        json.as_object_mut().unwrap().remove("recipe");
+        let old: RenderManifest = serde_json::from_value(json)?;
+        assert_eq!(old.recipe, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finds_renders_that_sound_alike() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let mut ids = vec![];
+        for fingerprint in [
+            vec![0u32; 8],
+            vec![0xffff_0000; 8],
+            vec![],
+            vec![0x0000_00ff; 8],
+        ] {
+            let manifest =
+                RenderManifest::new(Uuid::new_v4(), Uuid::new_v4(), "lofi".to_string(), 10, None);
+            manifest.save(&storage).await?;
+            RenderManifest::record_fingerprint(&storage, manifest.id, fingerprint).await?;
+            ids.push(manifest.id);
+        }
+
+        let similar = RenderManifest::find_similar(&storage, ids[0], &[0; 8], 0.5, None).await?;
+        let found = similar.iter().map(|render| render.id).collect::<Vec<_>>();
+        // Neither the render itself nor the one without a fingerprint.
+        assert_eq!(found, vec![ids[3], ids[1]]);
+        assert_eq!(similar[0].similarity, 0.75);
+        assert_eq!(similar[1].similarity, 0.5);
+        Ok(())
+    }
+}
@@ -0,0 +1,541 @@
+//! Generation jobs submitted on a schedule, like a few new ambient tracks every night, for
+//! servers that keep a library of music topped up without anyone requesting it. When each
+//! schedule last ran is saved, so that a run missed while the server was down happens once
+//! it is back up.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use time::{Date, OffsetDateTime};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::music_gpt_chat::Chat;
+use crate::backend::music_gpt_ws_handler::{GenerateAudioRequest, MusicGptWsHandler};
+use crate::backend::usage::LOCAL_USER;
+use crate::storage::Storage;
+
+/// When a schedule runs, as a cron expression of minute, hour, day of month, month and day
+/// of week, in UTC. Each field is `*`, a number, a range like `1-5`, any of those with a
+/// step like `*/15`, or a list of them like `0,30`. `@hourly`, `@daily`, `@weekly` and
+/// `@monthly` are also accepted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is 0.
+    weekdays: u64,
+    /// Whether the days of the month or of the week were `*`. If only one of them was,
+    /// the other one decides, and if none was, either one matching is enough.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    fn matches_date(&self, date: Date) -> bool {
+        let day = self.days >> date.day() & 1 == 1;
+        let weekday = self.weekdays >> date.weekday().number_days_from_sunday() & 1 == 1;
+        let matches_day = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+        matches_day && self.months >> date.month() as u8 & 1 == 1
+    }
+
+    /// The first minute the schedule runs at after `after`, if any in the next years.
+    pub fn next_after(&self, after: OffsetDateTime) -> Option<OffsetDateTime> {
+        let start = after
+            .to_offset(time::UtcOffset::UTC)
+            .replace_nanosecond(0)
+            .ok()?;
+        let start = start.replace_second(0).ok()? + time::Duration::minutes(1);
+        let mut date = start.date();
+        // Long enough for schedules that only run on February 29th.
+        for _ in 0..366 * 8 {
+            if self.matches_date(date) {
+                let first_hour = if date == start.date() {
+                    start.hour()
+                } else {
+                    0
+                };
+                for hour in (first_hour..24).filter(|hour| self.hours >> hour & 1 == 1) {
+                    let first_minute = match date == start.date() && hour == start.hour() {
+                        true => start.minute(),
+                        false => 0,
+                    };
+                    if let Some(minute) =
+                        (first_minute..60).find(|minute| self.minutes >> minute & 1 == 1)
+                    {
+                        return Some(date.with_hms(hour, minute, 0).ok()?.assume_utc());
+                    }
+                }
+            }
+            date = date.next_day()?;
+        }
+        None
+    }
+}
+
+/// The values of a field within `min..=max`, as a bitmask.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let number = |s: &str| {
+            s.parse::<u64>()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(|| format!("{s:?} is not a number from {min} to {max}"))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // A single value with a step runs from it until the end, like in most crons.
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        let step = match step {
+            Some(step) => step
+                .parse::<u64>()
+                .ok()
+                .filter(|step| *step > 0)
+                .ok_or_else(|| format!("Invalid step {step:?}"))?,
+            None => 1,
+        };
+        if start > end {
+            return Err(format!("Invalid range {range:?}"));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expanded = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            s => s,
+        };
+        let fields = expanded.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "Invalid cron expression {s:?}, expected minute, hour, day of month, month and day of week, like 0 2 * * *"
+            ));
+        };
+        let invalid = |err: String| format!("Invalid cron expression {s:?}: {err}");
+        let weekday_mask = parse_field(weekdays, 0, 7).map_err(invalid)?;
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59).map_err(invalid)?,
+            hours: parse_field(hours, 0, 23).map_err(invalid)?,
+            days: parse_field(days, 1, 31).map_err(invalid)?,
+            months: parse_field(months, 1, 12).map_err(invalid)?,
+            // 7 is also Sunday.
+            weekdays: (weekday_mask | weekday_mask >> 7) & 0x7f,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+fn one() -> usize {
+    1
+}
+
+/// A generation job submitted on a schedule, as given in the schedules file.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct Schedule {
+    /// Identifies the schedule across restarts, so it must be unique.
+    pub name: String,
+    /// When the schedule runs, see [Cron].
+    pub cron: String,
+    pub prompt: String,
+    pub secs: usize,
+    /// Renders generated in each run, each one with its own seed unless one is set.
+    #[serde(default = "one")]
+    pub count: usize,
+    /// Who the renders are accounted to, the local user if none.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Notifiers told when each render ends, on top of the global ones.
+    #[serde(default)]
+    pub notify: Vec<String>,
+}
+
+impl Schedule {
+    /// Reads a JSON list of schedules, checking that they can run.
+    pub fn load_all(path: &Path) -> anyhow::Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Could not read the schedules {path:?}: {err}"))?;
+        let schedules: Vec<Self> = serde_json::from_str(&content)
+            .map_err(|err| anyhow!("Invalid schedules {path:?}: {err}"))?;
+        for (i, schedule) in schedules.iter().enumerate() {
+            schedule.validate()?;
+            if schedules[..i].iter().any(|s| s.name == schedule.name) {
+                return Err(anyhow!(
+                    "There is more than one schedule named {:?}",
+                    schedule.name
+                ));
+            }
+        }
+        Ok(schedules)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("Schedules must have a name"));
+        }
+        self.cron.parse::<Cron>().map_err(|err| anyhow!(err))?;
+        if self.secs < 1 {
+            return Err(anyhow!("The secs of schedule {:?} must > 0", self.name));
+        }
+        if self.count < 1 {
+            return Err(anyhow!("The count of schedule {:?} must > 0", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// What is saved about each schedule, so that it carries on after a restart.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+struct ScheduleRecord {
+    /// Runs until this time, in Unix milliseconds, were already submitted or skipped.
+    checked_at: u128,
+    last_run: Option<u128>,
+    /// The renders submitted in the last run.
+    last_jobs: Vec<Uuid>,
+}
+
+const RECORDS_PATH: &str = "schedules.json";
+
+async fn load_records<S: Storage>(storage: &S) -> anyhow::Result<BTreeMap<String, ScheduleRecord>> {
+    match storage.read(RECORDS_PATH).await? {
+        Some(content) => Ok(serde_json::from_slice(&content)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+fn to_datetime(ms: u128) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(ms as i128 * 1_000_000)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+fn to_ms(datetime: OffsetDateTime) -> u128 {
+    (datetime.unix_timestamp_nanos() / 1_000_000).max(0) as u128
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+/// A schedule and when it runs, for the job API.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ScheduleStatus {
+    pub schedule: Schedule,
+    pub last_run: Option<u128>,
+    /// The renders submitted in the last run, whose manifests are in `GET /jobs/:id`.
+    pub last_jobs: Vec<Uuid>,
+    pub next_run: Option<u128>,
+}
+
+/// Submits the jobs of the schedules when they are due.
+#[derive(Clone)]
+pub struct Scheduler<S: Storage> {
+    storage: S,
+    schedules: Arc<Vec<(Schedule, Cron)>>,
+}
+
+impl<S: Storage> Scheduler<S> {
+    pub fn new(storage: S, schedules: Vec<Schedule>) -> anyhow::Result<Self> {
+        let schedules = schedules
+            .into_iter()
+            .map(|schedule| {
+                let cron = schedule.cron.parse().map_err(|err: String| anyhow!(err))?;
+                Ok((schedule, cron))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            storage,
+            schedules: Arc::new(schedules),
+        })
+    }
+
+    /// Every schedule with its last and next run.
+    pub async fn status(&self) -> anyhow::Result<Vec<ScheduleStatus>> {
+        let records = load_records(&self.storage).await?;
+        let now = now_ms();
+        Ok(self
+            .schedules
+            .iter()
+            .map(|(schedule, cron)| {
+                let record = records.get(&schedule.name).cloned().unwrap_or_default();
+                let checked_at = record.checked_at.max(now);
+                ScheduleStatus {
+                    schedule: schedule.clone(),
+                    last_run: record.last_run,
+                    last_jobs: record.last_jobs,
+                    next_run: cron.next_after(to_datetime(checked_at)).map(to_ms),
+                }
+            })
+            .collect())
+    }
+
+    /// Submits the schedules that are due at `now` through `handler`, and returns when
+    /// the next one is. Schedules seen for the first time only run from `now` on, and the
+    /// ones that missed several runs only run once.
+    async fn run_due(
+        &self,
+        handler: &MusicGptWsHandler<S>,
+        now: u128,
+    ) -> anyhow::Result<Option<u128>> {
+        let mut records = load_records(&self.storage).await?;
+        let mut next = None::<u128>;
+        for (schedule, cron) in self.schedules.iter() {
+            let record = records
+                .entry(schedule.name.clone())
+                .or_insert_with(|| ScheduleRecord {
+                    checked_at: now,
+                    ..Default::default()
+                });
+            let due = cron
+                .next_after(to_datetime(record.checked_at))
+                .is_some_and(|run| to_ms(run) <= now);
+            if due {
+                info!(schedule = schedule.name, "Submitting scheduled jobs");
+                record.last_jobs = submit(handler, schedule, now).await;
+                record.last_run = Some(now);
+                record.checked_at = now;
+            }
+            if let Some(run) = cron.next_after(to_datetime(record.checked_at)) {
+                next = Some(next.map_or(to_ms(run), |next| next.min(to_ms(run))));
+            }
+        }
+        self.storage
+            .write(RECORDS_PATH, serde_json::to_vec_pretty(&records)?)
+            .await?;
+        Ok(next)
+    }
+
+    /// Runs the schedules until the process exits.
+    pub fn spawn(self, handler: MusicGptWsHandler<S>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let next = match self.run_due(&handler, now_ms()).await {
+                    Ok(next) => next,
+                    Err(err) => {
+                        warn!("Could not run the schedules: {err}");
+                        Some(now_ms() + 60_000)
+                    }
+                };
+                let Some(next) = next else {
+                    return;
+                };
+                let wait = next.saturating_sub(now_ms()) as u64;
+                tokio::time::sleep(Duration::from_millis(wait)).await;
+            }
+        })
+    }
+}
+
+/// Requests the renders of a run of `schedule` in a new chat named after it, returning
+/// the ones that were accepted.
+async fn submit<S: Storage>(
+    handler: &MusicGptWsHandler<S>,
+    schedule: &Schedule,
+    now: u128,
+) -> Vec<Uuid> {
+    let chat = Chat {
+        chat_id: Uuid::new_v4(),
+        name: format!("{} {}", schedule.name, to_datetime(now).date()),
+        created_at: now,
+    };
+    if let Err(err) = chat.save(&handler.storage).await {
+        warn!(schedule = schedule.name, "Could not create the chat: {err}");
+        return vec![];
+    }
+    let handler = MusicGptWsHandler {
+        user: schedule.user.clone().unwrap_or(LOCAL_USER.to_string()),
+        ..handler.clone()
+    };
+    let mut jobs = vec![];
+    for _ in 0..schedule.count {
+        let req = GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: chat.chat_id,
+            prompt: schedule.prompt.clone(),
+            secs: schedule.secs,
+            notify: schedule.notify.clone(),
+            audition: false,
+        };
+        let id = req.id;
+        match handler.request_generation(req).await {
+            Ok(()) => jobs.push(id),
+            Err(err) => warn!(schedule = schedule.name, "Could not submit a job: {err}"),
+        }
+    }
+    jobs
+}
+
+/// HTTP route listing the schedules with their last and next runs.
+pub fn schedule_routes<S: Storage>(scheduler: Scheduler<S>) -> Router {
+    Router::new()
+        .route("/schedules", get(list_schedules::<S>))
+        .with_state(scheduler)
+}
+
+async fn list_schedules<S: Storage>(State(scheduler): State<Scheduler<S>>) -> Response {
+    match scheduler.status().await {
+        Ok(status) => Json(status).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::RwLock;
+
+    use crate::backend::_test_utils::{DummyJobProcessor, DummyModelRegistry};
+    use crate::backend::audio_generation_backend::BackendInboundMsg;
+    use crate::backend::model_registry::SwappableJobProcessor;
+    use crate::backend::music_gpt_ws_handler::Info;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn at(year: i32, month: u8, day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        let month = time::Month::try_from(month).unwrap();
+        let date = Date::from_calendar_date(year, month, day).unwrap();
+        date.with_hms(hour, minute, 0).unwrap().assume_utc()
+    }
+
+    fn next(cron: &str, after: OffsetDateTime) -> OffsetDateTime {
+        cron.parse::<Cron>().unwrap().next_after(after).unwrap()
+    }
+
+    #[test]
+    fn finds_the_next_run_of_cron_expressions() {
+        let after = at(2024, 2, 27, 13, 45) + time::Duration::seconds(30);
+        assert_eq!(next("0 2 * * *", after), at(2024, 2, 28, 2, 0));
+        assert_eq!(next("*/20 * * * *", after), at(2024, 2, 27, 14, 0));
+        assert_eq!(next("46 13 * * *", after), at(2024, 2, 27, 13, 46));
+        assert_eq!(next("45 13 * * *", after), at(2024, 2, 28, 13, 45));
+        // Weekdays only, and Sundays given as 7.
+        assert_eq!(
+            next("0 9 * * 1-5", at(2024, 3, 1, 10, 0)),
+            at(2024, 3, 4, 9, 0)
+        );
+        assert_eq!(next("0 9 * * 7", after), at(2024, 3, 3, 9, 0));
+        // Either the day of the month or of the week, when both are set.
+        assert_eq!(next("0 0 1 * 4", after), at(2024, 2, 29, 0, 0));
+        assert_eq!(
+            next("0 0 29 2 *", at(2024, 3, 1, 0, 0)),
+            at(2028, 2, 29, 0, 0)
+        );
+        assert_eq!(next("@monthly", after), at(2024, 3, 1, 0, 0));
+
+        assert!("0 2 * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("5-1 * * * *".parse::<Cron>().is_err());
+        assert_eq!(
+            "0 0 31 2 *".parse::<Cron>().unwrap().next_after(after),
+            None
+        );
+    }
+
+    fn handler(storage: &AppFs) -> (MusicGptWsHandler<AppFs>, Receiver<BackendInboundMsg>) {
+        let (ai_tx, ai_rx) = channel();
+        let processor = Arc::new(DummyJobProcessor::default());
+        let handler = MusicGptWsHandler {
+            storage: storage.clone(),
+            ai_broadcast_tx: tokio::sync::broadcast::channel(10).0,
+            ai_tx,
+            info: Arc::new(RwLock::new(Info {
+                model: "dummy".to_string(),
+                selection_reason: "".to_string(),
+                device: "Cpu".to_string(),
+            })),
+            info_broadcast_tx: tokio::sync::broadcast::channel(10).0,
+            processor: SwappableJobProcessor::new(processor),
+            registry: Arc::new(DummyModelRegistry),
+            limits: Default::default(),
+            quotas: Default::default(),
+            maintenance: Default::default(),
+            user: "someone".to_string(),
+            admin: false,
+        };
+        (handler, ai_rx)
+    }
+
+    #[tokio::test]
+    async fn submits_due_schedules_once_across_restarts() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (handler, ai_rx) = handler(&storage);
+        let schedule = Schedule {
+            name: "nightly-ambient".to_string(),
+            cron: "0 2 * * *".to_string(),
+            prompt: "ambient".to_string(),
+            secs: 10,
+            count: 2,
+            user: None,
+            notify: vec![],
+        };
+        let ms = |datetime| to_ms(datetime);
+        let scheduler = Scheduler::new(storage.clone(), vec![schedule.clone()])?;
+
+        // Added at noon, so it does not run until the night.
+        let next = scheduler
+            .run_due(&handler, ms(at(2024, 5, 1, 12, 0)))
+            .await?;
+        assert_eq!(next, Some(ms(at(2024, 5, 2, 2, 0))));
+        assert!(ai_rx.try_recv().is_err());
+
+        // Down for two nights, it catches up once.
+        let scheduler = Scheduler::new(storage.clone(), vec![schedule])?;
+        let now = ms(at(2024, 5, 3, 8, 0));
+        let next = scheduler.run_due(&handler, now).await?;
+        assert_eq!(next, Some(ms(at(2024, 5, 4, 2, 0))));
+        let jobs = ai_rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(jobs.len(), 2);
+        let BackendInboundMsg::Request(job) = &jobs[0] else {
+            panic!("Expected a request, got {:?}", jobs[0]);
+        };
+        assert_eq!(job.prompt, "ambient");
+        assert_eq!(job.user.as_deref(), Some(LOCAL_USER));
+
+        let status = scheduler.status().await?;
+        assert_eq!(status[0].last_run, Some(now));
+        assert_eq!(status[0].last_jobs.len(), 2);
+        assert!(scheduler.run_due(&handler, now + 1000).await?.is_some());
+        assert!(ai_rx.try_recv().is_err());
+        Ok(())
+    }
+}
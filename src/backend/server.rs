@@ -1,59 +1,211 @@
-use axum::extract::WebSocketUpgrade;
-use axum::response::Html;
+use axum::extract::{ConnectInfo, Query, State, WebSocketUpgrade};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
 use axum::routing::get;
 use axum::Router;
-use std::path::Path;
-use tower_http::services::ServeDir;
-use tracing::info;
-
-use crate::backend::audio_generation_backend::{AudioGenerationBackend, JobProcessor};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+use crate::backend::admin::{admin_routes, Maintenance};
+use crate::backend::archive::{archive_routes, Archive, ArchiveConfig};
+use crate::backend::audio_generation_backend::{
+    AudioGenerationBackend, BackendInboundMsg, JobProcessor,
+};
 use crate::backend::audio_generation_fanout::audio_generation_fanout;
+use crate::backend::job_graph::{job_graph_routes, GraphRun};
+use crate::backend::job_limits::JobLimits;
+use crate::backend::job_routes::{job_routes, serve_path};
+use crate::backend::live_renders::LiveRenders;
+use crate::backend::model_registry::{ModelRegistry, SwappableJobProcessor};
 use crate::backend::music_gpt_ws_handler::{Info, MusicGptWsHandler};
+use crate::backend::notifier::{Notifications, NotifierConfig};
+use crate::backend::openapi::openapi_routes;
+use crate::backend::podcast::podcast_routes;
+use crate::backend::proxy::ProxyConfig;
+use crate::backend::reload::{LiveRegistry, LiveSettings, Reloader};
+use crate::backend::remote_sync::{RemoteSync, SyncConfig};
+use crate::backend::render_manifest::RenderSettings;
+use crate::backend::scheduler::{schedule_routes, Schedule, Scheduler};
+use crate::backend::tls::{serve_tls, TlsConfig};
+use crate::backend::usage::{
+    require_tokens, usage_routes, ApiTokens, Quotas, TokenQuery, LOCAL_USER,
+};
 use crate::backend::ws_handler::WsHandler;
 use crate::storage::Storage;
 
 pub struct RunWebServerOptions {
     pub name: String,
+    pub selection_reason: String,
     pub device: String,
     pub port: usize,
     pub auto_open: bool,
     pub expose: bool,
+    pub limits: JobLimits,
+    /// Recorded in the manifest of each render.
+    pub render_settings: RenderSettings,
+    /// Required for connecting, if any.
+    pub tokens: ApiTokens,
+    pub quotas: Quotas,
+    /// Base path, trusted proxies and CORS origins, for serving behind a reverse proxy.
+    pub proxy: ProxyConfig,
+    /// Certificate for serving over HTTPS, plain HTTP otherwise.
+    pub tls: Option<TlsConfig>,
+    /// Jobs submitted automatically, listed in `GET /schedules`.
+    pub schedules: Vec<Schedule>,
+    /// Where renders are reported once they end.
+    pub notifiers: Vec<NotifierConfig>,
+    /// Where clients reach the server, like https://music.example.com. If set, the
+    /// finished renders are published as a podcast feed with links under it.
+    pub podcast_url: Option<String>,
+    /// Remote the renders are mirrored to after each job completes, if any.
+    pub sync: Option<SyncConfig>,
+    /// If set, completed renders are archived by hash and served under `/archive`.
+    pub archive: Option<ArchiveConfig>,
+    /// Whether a Swagger UI, loaded from a CDN, is served at `/docs`.
+    pub api_docs: bool,
+    /// Settings the server switches to while running, if they can change.
+    pub reloads: Option<tokio::sync::mpsc::UnboundedReceiver<LiveSettings>>,
 }
 
-pub async fn run_web_server<T, S, P>(
+pub async fn run_web_server<T, S, P, R>(
     root: P,
     storage: S,
     processor: T,
+    registry: R,
     opts: RunWebServerOptions,
 ) -> anyhow::Result<()>
 where
     T: JobProcessor + 'static,
     S: Storage + 'static,
     P: AsRef<Path>,
+    R: ModelRegistry,
 {
-    let (ai_tx, ai_rx) = AudioGenerationBackend::new(processor).run();
-    let ai_broadcast_tx = audio_generation_fanout(ai_rx, storage.clone());
+    // The audio of long renders is moved to disk while they run. Whatever is there was
+    // left by a previous run that did not exit cleanly.
+    let spill_dir = storage.path_buf("spill");
+    let _ = std::fs::remove_dir_all(&spill_dir);
+    let processor = SwappableJobProcessor::new(Arc::new(processor));
+    let backend = AudioGenerationBackend::new(processor.clone()).with_spill_dir(&spill_dir);
+    let queue = backend.queue();
+    let (ai_tx, ai_rx) = backend.run();
+    let live_renders = LiveRenders::default().with_spill_dir(spill_dir);
+    let (settings_tx, settings_rx) = tokio::sync::watch::channel(opts.render_settings);
+    let (ai_broadcast_tx, fanout) = audio_generation_fanout(
+        ai_rx,
+        storage.clone(),
+        live_renders.clone(),
+        settings_rx.clone(),
+    );
+    let shutdown_tx = ai_tx.clone();
+    let (info_broadcast_tx, _) = tokio::sync::broadcast::channel(10);
+    let maintenance = Maintenance::default();
 
     let ws_handler = MusicGptWsHandler {
         ai_tx,
-        storage,
-        info: Info {
+        storage: storage.clone(),
+        info: Arc::new(RwLock::new(Info {
             model: opts.name,
+            selection_reason: opts.selection_reason,
             device: opts.device,
-        },
+        })),
+        info_broadcast_tx,
         ai_broadcast_tx,
+        processor,
+        registry: Arc::new(LiveRegistry {
+            inner: registry,
+            settings: settings_rx,
+        }),
+        limits: Arc::new(RwLock::new(opts.limits)),
+        quotas: opts.quotas.clone(),
+        maintenance: maintenance.clone(),
+        user: LOCAL_USER.to_string(),
+        admin: false,
     };
 
+    let resume_handler = ws_handler.clone();
+    let scheduler = Scheduler::new(storage.clone(), opts.schedules)?;
+    let notifications = Notifications::new(storage.clone(), &opts.notifiers)?;
+    let notifications_task = notifications
+        .clone()
+        .spawn(ws_handler.ai_broadcast_tx.subscribe());
+    let reload_task = opts.reloads.map(|rx| {
+        let reloader = Reloader {
+            limits: ws_handler.limits.clone(),
+            settings: settings_tx,
+            notifications,
+            processor: ws_handler.processor.clone(),
+        };
+        reloader.spawn(rx)
+    });
+    let archive_task = opts.archive.clone().map(|config| {
+        Archive::new(storage.clone(), config).spawn(ws_handler.ai_broadcast_tx.subscribe())
+    });
+    let sync_task = opts.sync.as_ref().map(|config| {
+        RemoteSync::new(storage.clone(), config).spawn(ws_handler.ai_broadcast_tx.subscribe())
+    });
+    let proxy = Arc::new(opts.proxy);
+    let app_proxy = proxy.clone();
+    let ws_proxy = proxy.clone();
     let app = Router::new()
-        .fallback(get(web_app))
-        .nest_service("/files", ServeDir::new(root))
+        .fallback(get(
+            |ConnectInfo(peer): ConnectInfo<SocketAddr>, headers: HeaderMap| async move {
+                web_app(&app_proxy.public_base_path(peer, &headers))
+            },
+        ))
+        .merge(files_routes(root.as_ref(), opts.tokens.clone()))
+        .merge(job_routes(
+            live_renders,
+            storage.clone(),
+            ws_handler.ai_broadcast_tx.clone(),
+            queue.clone(),
+            opts.tokens.clone(),
+        ))
+        .merge(admin_routes(
+            queue,
+            ws_handler.ai_tx.clone(),
+            opts.tokens.clone(),
+            maintenance,
+        ))
+        .merge(match &opts.podcast_url {
+            Some(url) => require_tokens(podcast_routes(storage.clone(), url), opts.tokens.clone()),
+            None => Router::new(),
+        })
+        .merge(match &opts.archive {
+            Some(_) => require_tokens(archive_routes(storage.clone()), opts.tokens.clone()),
+            None => Router::new(),
+        })
+        .merge(usage_routes(storage, opts.tokens.clone(), opts.quotas))
+        .merge(require_tokens(
+            schedule_routes(scheduler.clone()),
+            opts.tokens.clone(),
+        ))
+        .merge(job_graph_routes(ws_handler.clone(), opts.tokens.clone()))
+        .merge(openapi_routes(opts.api_docs))
         .route(
             "/ws",
-            get(|ws: WebSocketUpgrade| async move {
-                let ws_handler = ws_handler.clone();
-                ws.on_upgrade(move |ws| ws_handler.handle(ws))
-            }),
+            get(
+                |ws: WebSocketUpgrade,
+                 ConnectInfo(peer): ConnectInfo<SocketAddr>,
+                 headers: HeaderMap,
+                 Query(query): Query<TokenQuery>| async move {
+                    let token = query.token(&headers);
+                    let Some(user) = opts.tokens.authenticate(token) else {
+                        let client = ws_proxy.client_addr(peer, &headers);
+                        warn!("Rejected a connection from {client} with an invalid API token");
+                        return (StatusCode::UNAUTHORIZED, "Invalid API token").into_response();
+                    };
+                    let ws_handler = MusicGptWsHandler {
+                        user,
+                        admin: opts.tokens.authenticate_admin(token).is_some(),
+                        ..ws_handler.clone()
+                    };
+                    ws.on_upgrade(move |ws| ws_handler.handle(ws))
+                },
+            ),
         );
+    let app = proxy.apply(app);
 
     let port = opts.port;
     let host = if opts.expose { "0.0.0.0" } else { "127.0.0.1" };
@@ -67,20 +219,117 @@ where
         "localhost".to_string()
     };
     let listener = tokio::net::TcpListener::bind(format!("{host}:{port}")).await?;
-    let addr = format!("http://{advertised}:{port}");
+    if let Err(err) = resume_handler.resume_unfinished().await {
+        warn!("Could not resume unfinished renders: {err}");
+    }
+    if let Err(err) = GraphRun::fail_unfinished(&resume_handler.storage).await {
+        warn!("Could not mark the unfinished pipelines as failed: {err}");
+    }
+    // Submitting after resuming, so that jobs scheduled while down run after the ones
+    // that were interrupted.
+    let scheduler_task = scheduler.spawn(resume_handler);
+    let scheme = if opts.tls.is_some() { "https" } else { "http" };
+    let addr = format!("{scheme}://{advertised}:{port}{}", proxy.base_path());
     info!("MusicGPT running at {addr}");
     if opts.auto_open {
         let _ = open::that(addr);
     }
 
-    Ok(axum::serve(listener, app).await?)
+    // The backend is told to stop right away, so that streams of the running render end
+    // once it is checkpointed instead of keeping the server up.
+    let shutdown = async move {
+        shutdown_signal().await;
+        info!("Shutting down after checkpointing the running render, Ctrl+C again to quit now");
+        scheduler_task.abort();
+        notifications_task.abort();
+        if let Some(reload_task) = &reload_task {
+            reload_task.abort();
+        }
+        if let Some(sync_task) = &sync_task {
+            sync_task.abort();
+        }
+        if let Some(archive_task) = &archive_task {
+            archive_task.abort();
+        }
+        let _ = shutdown_tx.send(BackendInboundMsg::Shutdown);
+        tokio::spawn(async {
+            let _ = tokio::signal::ctrl_c().await;
+            std::process::exit(130);
+        });
+    };
+    match &opts.tls {
+        Some(tls) => serve_tls(listener, app, tls, shutdown).await?,
+        None => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown)
+            .await?
+        }
+    }
+    fanout.await?;
+    Ok(())
 }
 
-async fn web_app() -> Html<&'static str> {
-    Html(include_str!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/web/dist/index.html"
-    )))
+/// Resolves on Ctrl+C or, in Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Stored files, for API token holders. The usage of the users is not among them.
+fn files_routes(root: &Path, tokens: ApiTokens) -> Router {
+    let routes = Router::new()
+        .route("/files/*path", get(get_file))
+        .with_state(root.to_path_buf());
+    require_tokens(routes, tokens)
+}
+
+async fn get_file(
+    State(root): State<PathBuf>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let path = Path::new(&path);
+    let normal = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !normal || path.starts_with("usage") {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    serve_path(root.join(path), method, headers).await
+}
+
+/// The web app, told the path it is served at so that it builds its links and API URLs
+/// under it.
+fn web_app(base_path: &str) -> Html<String> {
+    let html = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/web/dist/index.html"));
+    // Escaped so that the path cannot close the script.
+    let base_path = serde_json::to_string(base_path)
+        .expect("Could not serialize base path")
+        .replace('<', "\\u003c");
+    let script = format!("<script>window.MUSICGPT_BASE_PATH = {base_path};</script>");
+    Html(html.replacen("<head>", &format!("<head>{script}"), 1))
 }
 
 #[cfg(test)]
@@ -100,10 +349,10 @@ mod tests {
     use std::time::Duration;
 
     use super::*;
-    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::_test_utils::{DummyJobProcessor, DummyModelRegistry};
     use crate::backend::music_gpt_chat::{AiChatEntry, ChatEntry, UserChatEntry};
     use crate::backend::music_gpt_ws_handler::{
-        ChatRequest, GenerateAudioRequest, InboundMsg, OutboundMsg,
+        ChatRequest, GenerateAudioRequest, InboundMsg, OutboundMsg, UseModelRequest,
     };
     use crate::storage::AppFs;
 
@@ -118,6 +367,8 @@ mod tests {
             chat_id,
             prompt: "Create a cool song".to_string(),
             secs: 4,
+            notify: vec![],
+            audition: false,
         })
         .to_ws(&mut ws)
         .await?;
@@ -147,6 +398,8 @@ mod tests {
 
         let res = reqwest::get(format!("http://{host}/files/audios/{id}.wav")).await?;
         assert_eq!(res.status(), 200);
+        let res = reqwest::get(format!("http://{host}/files/usage/local.json")).await?;
+        assert_eq!(res.status(), 404);
 
         Ok(())
     }
@@ -164,6 +417,8 @@ mod tests {
             chat_id,
             prompt: "Create a cool song".to_string(),
             secs: 4,
+            notify: vec![],
+            audition: false,
         })
         .to_ws(&mut ws)
         .await?;
@@ -202,6 +457,8 @@ mod tests {
             chat_id,
             prompt: "fail at 2".to_string(),
             secs: 4,
+            notify: vec![],
+            audition: false,
         })
         .to_ws(&mut ws)
         .await?;
@@ -240,6 +497,8 @@ mod tests {
             chat_id,
             prompt: "foo".to_string(),
             secs: 1,
+            notify: vec![],
+            audition: false,
         })
         .to_ws(&mut ws)
         .await?;
@@ -280,6 +539,48 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn lists_and_switches_models() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+
+        let info = OutboundMsg::from_ws(&mut ws).await?.info();
+        assert_eq!(info.model, "Dummy");
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        InboundMsg::ListModels.to_ws(&mut ws).await?;
+        let models = OutboundMsg::from_ws(&mut ws).await?.models();
+        assert_eq!(models.len(), 3);
+        assert!(models[1].installed);
+        assert!(!models[2].installed);
+
+        InboundMsg::UseModel(UseModelRequest {
+            name: "dummy-2".to_string(),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let info = OutboundMsg::from_ws(&mut ws).await?.info();
+        assert_eq!(info.model, "Dummy 2");
+        assert_eq!(info.selection_reason, "Dummy 2 selected at runtime");
+
+        InboundMsg::UseModel(UseModelRequest {
+            name: "unknown".to_string(),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let err = OutboundMsg::from_ws(&mut ws).await?.unwrap_error();
+        assert_eq!(err, "Unknown model unknown");
+
+        InboundMsg::UseModel(UseModelRequest {
+            name: "dummy-3".to_string(),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let err = OutboundMsg::from_ws(&mut ws).await?.unwrap_error();
+        assert!(err.starts_with("Dummy 3 is not installed"));
+
+        Ok(())
+    }
+
     #[async_trait]
     trait TungsteniteMsg: Sized {
         async fn to_ws(
@@ -321,15 +622,30 @@ mod tests {
         let port = PORT.fetch_add(1, Ordering::SeqCst) as usize;
         let run_options = RunWebServerOptions {
             name: "Dummy".to_string(),
+            selection_reason: "Dummy selected for testing".to_string(),
             device: "Cpu".to_string(),
             port,
             auto_open: false,
             expose: false,
+            limits: JobLimits::default(),
+            render_settings: Default::default(),
+            tokens: Default::default(),
+            quotas: Default::default(),
+            proxy: Default::default(),
+            tls: None,
+            schedules: vec![],
+            notifiers: vec![],
+            podcast_url: None,
+            sync: None,
+            archive: None,
+            api_docs: false,
+            reloads: None,
         };
         tokio::spawn(run_web_server(
             app_fs.root.clone(),
             app_fs,
             processor,
+            DummyModelRegistry,
             run_options,
         ));
         let (ws_stream, _) = connect_async(&format!("ws://localhost:{port}/ws")).await?;
@@ -0,0 +1,346 @@
+//! Several instances of the same model, each one with its own inference sessions, so that
+//! queued jobs can run at the same time. Running them on a single instance serializes
+//! them, and loading one per job is slow and makes memory usage spike.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::ThreadId;
+
+use crate::audio::audio_sink::AudioSink;
+use crate::backend::audio_generation_backend::{JobCheckpoint, SegmentEdit};
+use crate::backend::job_limits::JobEstimate;
+use crate::backend::model_registry::ModelVersion;
+use crate::backend::JobProcessor;
+use crate::musicgen::Continuation;
+
+type CreateProcessor = dyn Fn() -> ort::Result<Arc<dyn JobProcessor>> + Send + Sync;
+
+/// Processes each call with an instance that is not busy, loading a new one if all of
+/// them are and there are less than the maximum. Otherwise, waits for one to be free.
+pub struct SessionPool {
+    first: Arc<dyn JobProcessor>,
+    create: Box<CreateProcessor>,
+    max: usize,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Arc<dyn JobProcessor>>,
+    /// Instances leased by each thread, to know which one to terminate.
+    busy: Vec<(ThreadId, Arc<dyn JobProcessor>)>,
+    /// Instances loaded or being loaded, whether they are busy or not.
+    created: usize,
+}
+
+/// An instance taken from the pool, which goes back to it when dropped.
+struct Lease<'a> {
+    pool: &'a SessionPool,
+    processor: Option<Arc<dyn JobProcessor>>,
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        if let Some(processor) = self.processor.take() {
+            let mut state = self.pool.state.lock().unwrap();
+            state
+                .busy
+                .retain(|(_, busy)| !Arc::ptr_eq(busy, &processor));
+            state.idle.push(processor);
+            self.pool.released.notify_one();
+        }
+    }
+}
+
+impl SessionPool {
+    /// Starts with `first`, loading up to `max` instances in total with `create` as
+    /// they are needed.
+    pub fn new(
+        first: Arc<dyn JobProcessor>,
+        max: usize,
+        create: impl Fn() -> ort::Result<Arc<dyn JobProcessor>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            first: first.clone(),
+            create: Box::new(create),
+            max: max.max(1),
+            state: Mutex::new(PoolState {
+                idle: vec![first],
+                busy: vec![],
+                created: 1,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    fn lease(&self) -> ort::Result<Lease<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(processor) = state.idle.pop() {
+                let worker = std::thread::current().id();
+                state.busy.push((worker, processor.clone()));
+                return Ok(Lease {
+                    pool: self,
+                    processor: Some(processor),
+                });
+            }
+            if state.created < self.max {
+                state.created += 1;
+                // Loading takes a while, other calls can take the instances that are
+                // released meanwhile.
+                drop(state);
+                return match (self.create)() {
+                    Ok(processor) => {
+                        let worker = std::thread::current().id();
+                        let mut state = self.state.lock().unwrap();
+                        state.busy.push((worker, processor.clone()));
+                        Ok(Lease {
+                            pool: self,
+                            processor: Some(processor),
+                        })
+                    }
+                    Err(err) => {
+                        self.state.lock().unwrap().created -= 1;
+                        self.released.notify_one();
+                        Err(err)
+                    }
+                };
+            }
+            state = self.released.wait(state).unwrap();
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&dyn JobProcessor) -> ort::Result<R>) -> ort::Result<R> {
+        let lease = self.lease()?;
+        f(lease.processor.as_deref().unwrap())
+    }
+}
+
+impl JobProcessor for SessionPool {
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<f32>> {
+        self.with(|processor| processor.process(prompt, secs, on_progress))
+    }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.with(|processor| processor.process_streaming(prompt, secs, on_progress, sink))
+    }
+
+    fn process_exact(
+        &self,
+        prompt: &str,
+        secs: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.with(|processor| processor.process_exact(prompt, secs, on_progress, sink))
+    }
+
+    fn resume_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        checkpoint: &JobCheckpoint,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.with(|processor| {
+            processor.resume_streaming(prompt, secs, checkpoint, on_progress, sink)
+        })
+    }
+
+    fn process_seeded(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seed: u64,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.with(|processor| processor.process_seeded(prompt, secs, seed, on_progress, sink))
+    }
+
+    /// The decoder state of `from` is plain tensors, so any instance can continue it.
+    fn process_continuing(
+        &self,
+        prompt: &str,
+        secs: usize,
+        from: Option<Continuation>,
+        context: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<Option<Continuation>> {
+        self.with(|processor| {
+            processor.process_continuing(prompt, secs, from, context, on_progress, sink)
+        })
+    }
+
+    fn process_variations(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seeds: &[u64],
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<Vec<f32>>> {
+        self.with(|processor| processor.process_variations(prompt, secs, seeds, on_progress))
+    }
+
+    fn edit_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        edit: &SegmentEdit,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.with(|processor| processor.edit_streaming(prompt, secs, edit, on_progress, sink))
+    }
+
+    fn model_version(&self) -> Option<ModelVersion> {
+        self.first.model_version()
+    }
+
+    fn segment_prompts(&self, prompt: &str, secs: usize) -> Vec<String> {
+        self.first.segment_prompts(prompt, secs)
+    }
+
+    fn estimate(&self, secs: usize) -> Option<JobEstimate> {
+        self.first.estimate(secs)
+    }
+
+    /// Recovers the instances that are not busy, which includes the one that failed, as
+    /// it is released before its caller finds out.
+    fn recover(&self) -> ort::Result<()> {
+        let idle = std::mem::take(&mut self.state.lock().unwrap().idle);
+        let leases = idle
+            .into_iter()
+            .map(|processor| Lease {
+                pool: self,
+                processor: Some(processor),
+            })
+            .collect::<Vec<_>>();
+        leases
+            .iter()
+            .try_for_each(|lease| lease.processor.as_ref().unwrap().recover())
+    }
+
+    /// Terminates the instance leased by `worker`, leaving the others running.
+    fn terminate(&self, worker: ThreadId) {
+        let state = self.state.lock().unwrap();
+        let leased = (state.busy.iter()).find(|(thread, _)| *thread == worker);
+        let Some((_, processor)) = leased.cloned() else {
+            return;
+        };
+        drop(state);
+        processor.terminate(worker)
+    }
+
+    fn max_concurrent_jobs(&self) -> usize {
+        self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use crate::backend::_test_utils::DummyJobProcessor;
+
+    use super::*;
+
+    fn pool(max: usize, loaded: Arc<AtomicUsize>) -> SessionPool {
+        let processor = || Arc::new(DummyJobProcessor::new(Duration::from_millis(50)));
+        SessionPool::new(processor(), max, move || {
+            loaded.fetch_add(1, Ordering::SeqCst);
+            Ok(processor())
+        })
+    }
+
+    #[test]
+    fn runs_calls_on_separate_instances_up_to_the_max() {
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let pool = pool(2, loaded.clone());
+        let started = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| pool.process("", 2, Box::new(|_, _| false)).unwrap());
+            }
+        });
+        // Two rounds of two jobs of 100ms each.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(400), "{elapsed:?}");
+        assert_eq!(loaded.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.state.lock().unwrap().created, 2);
+        assert_eq!(pool.max_concurrent_jobs(), 2);
+    }
+
+    /// Runs until it gets terminated.
+    #[derive(Default)]
+    struct BlockingJobProcessor {
+        terminated: std::sync::atomic::AtomicBool,
+    }
+
+    impl JobProcessor for BlockingJobProcessor {
+        fn process(
+            &self,
+            _prompt: &str,
+            _secs: usize,
+            _on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        ) -> ort::Result<Vec<f32>> {
+            let started = Instant::now();
+            while !self.terminated.load(Ordering::SeqCst) {
+                if started.elapsed() > Duration::from_secs(5) {
+                    return Ok(vec![]);
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            Err(ort::Error::new("Terminated"))
+        }
+
+        fn terminate(&self, _worker: ThreadId) {
+            self.terminated.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn terminates_the_instance_of_the_worker() {
+        let processor = || Arc::new(BlockingJobProcessor::default());
+        let pool = SessionPool::new(processor(), 2, move || Ok(processor()));
+        std::thread::scope(|scope| {
+            let stuck = scope.spawn(|| pool.process("", 1, Box::new(|_, _| false)));
+            let other = scope.spawn(|| pool.process("", 1, Box::new(|_, _| false)));
+            while pool.state.lock().unwrap().busy.len() < 2 {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+
+            pool.terminate(stuck.thread().id());
+            assert!(stuck.join().unwrap().is_err());
+            assert!(!other.is_finished());
+            pool.terminate(other.thread().id());
+            assert!(other.join().unwrap().is_err());
+        });
+        assert_eq!(pool.state.lock().unwrap().busy.len(), 0);
+    }
+
+    #[test]
+    fn does_not_load_instances_until_needed() {
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let pool = pool(3, loaded.clone());
+        for _ in 0..3 {
+            pool.process("", 1, Box::new(|_, _| false)).unwrap();
+        }
+        assert_eq!(loaded.load(Ordering::SeqCst), 0);
+        assert_eq!(pool.state.lock().unwrap().created, 1);
+    }
+}
@@ -0,0 +1,282 @@
+//! Serving the web app over HTTPS without a reverse proxy in front, for servers reached
+//! over the LAN. Certificates renewed on disk, like the ones an ACME client such as certbot
+//! keeps up to date, are picked up without restarting the server.
+
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::anyhow;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use futures_util::FutureExt;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tower_http::add_extension::AddExtension;
+use tracing::{debug, info, warn};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Connections that do not complete the handshake in this time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Paths of a PEM certificate chain and of its private key.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    /// The rustls config serving the certificate, failing if it cannot be loaded now.
+    fn server_config(&self) -> anyhow::Result<Arc<ServerConfig>> {
+        let resolver = ReloadingCert::new(self.clone(), RELOAD_CHECK_INTERVAL)?;
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    fn load(&self) -> anyhow::Result<CertifiedKey> {
+        let cert_error = |err| anyhow!("Could not read {}: {err}", self.cert_path.display());
+        let key_error = |err| anyhow!("Could not read {}: {err}", self.key_path.display());
+
+        let mut reader = BufReader::new(std::fs::File::open(&self.cert_path).map_err(cert_error)?);
+        let certs = rustls_pemfile::certs(&mut reader)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(cert_error)?;
+        if certs.is_empty() {
+            return Err(anyhow!("{} has no certificates", self.cert_path.display()));
+        }
+        let mut reader = BufReader::new(std::fs::File::open(&self.key_path).map_err(key_error)?);
+        let Some(key) = rustls_pemfile::private_key(&mut reader).map_err(key_error)? else {
+            return Err(anyhow!("{} has no private key", self.key_path.display()));
+        };
+        let key = any_supported_type(&key)
+            .map_err(|err| anyhow!("Invalid key {}: {err}", self.key_path.display()))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+
+    /// When the certificate or its key last changed.
+    fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&self.cert_path).max(modified(&self.key_path))
+    }
+}
+
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+/// Serves the certificate last loaded, loading it again once its files change. Broken
+/// files, like the ones seen while a renewal is being written, keep the previous one.
+struct ReloadingCert {
+    tls: TlsConfig,
+    check_interval: Duration,
+    loaded: Mutex<LoadedCert>,
+}
+
+impl Debug for ReloadingCert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReloadingCert")
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl ReloadingCert {
+    fn new(tls: TlsConfig, check_interval: Duration) -> anyhow::Result<Self> {
+        let loaded = LoadedCert {
+            modified: tls.modified(),
+            key: Arc::new(tls.load()?),
+            checked_at: Instant::now(),
+        };
+        Ok(Self {
+            tls,
+            check_interval,
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        let mut loaded = self.loaded.lock().expect("Certificate lock poisoned");
+        if loaded.checked_at.elapsed() < self.check_interval {
+            return loaded.key.clone();
+        }
+        loaded.checked_at = Instant::now();
+        let modified = self.tls.modified();
+        if modified != loaded.modified {
+            match self.tls.load() {
+                Ok(key) => {
+                    info!("Loaded the renewed TLS certificate");
+                    loaded.key = Arc::new(key);
+                    loaded.modified = modified;
+                }
+                Err(err) => warn!("Keeping the previous TLS certificate: {err}"),
+            }
+        }
+        loaded.key.clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// Serves `app` over TLS until `signal` resolves, then waits for the open connections to
+/// finish, like [axum::serve] does with its graceful shutdown.
+pub async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    tls: &TlsConfig,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let acceptor = TlsAcceptor::from(tls.server_config()?);
+
+    let (signal_tx, signal_rx) = watch::channel(());
+    let signal_tx = Arc::new(signal_tx);
+    tokio::spawn(async move {
+        signal.await;
+        drop(signal_rx);
+    });
+    let (close_tx, close_rx) = watch::channel(());
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(err) => {
+                    debug!("Could not accept a connection: {err}");
+                    continue;
+                }
+            },
+            _ = signal_tx.closed() => break,
+        };
+        let service = AddExtension::new(app.clone(), ConnectInfo(peer));
+        let acceptor = acceptor.clone();
+        let signal_tx = signal_tx.clone();
+        let close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => return debug!("TLS handshake with {peer} failed: {err}"),
+                    Err(_) => return debug!("TLS handshake with {peer} timed out"),
+                };
+            let builder = Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                TowerToHyperService::new(service),
+            );
+            tokio::pin!(conn);
+            let closed = signal_tx.closed().fuse();
+            tokio::pin!(closed);
+            loop {
+                tokio::select! {
+                    result = conn.as_mut() => {
+                        if let Err(err) = result {
+                            debug!("Could not serve {peer}: {err}");
+                        }
+                        break;
+                    }
+                    _ = &mut closed => conn.as_mut().graceful_shutdown(),
+                }
+            }
+            drop(close_rx);
+        });
+    }
+
+    drop(close_rx);
+    close_tx.closed().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+
+    use super::*;
+
+    fn localhost() -> TlsConfig {
+        let assets = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/tls");
+        TlsConfig {
+            cert_path: assets.join("localhost.crt"),
+            key_path: assets.join("localhost.key"),
+        }
+    }
+
+    #[test]
+    fn keeps_the_previous_certificate_while_the_new_one_is_broken() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("musicgpt-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let tls = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        std::fs::copy(localhost().cert_path, &tls.cert_path)?;
+        std::fs::copy(localhost().key_path, &tls.key_path)?;
+        let cert = ReloadingCert::new(tls.clone(), Duration::ZERO)?;
+        let first = cert.current();
+
+        std::fs::write(&tls.key_path, "")?;
+        let modified = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&tls.key_path)?
+            .set_modified(modified)?;
+        assert!(Arc::ptr_eq(&cert.current(), &first));
+
+        std::fs::copy(localhost().key_path, &tls.key_path)?;
+        assert!(!Arc::ptr_eq(&cert.current(), &first));
+
+        std::fs::write(&tls.cert_path, "not a certificate")?;
+        assert!(TlsConfig::load(&tls).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_over_https() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let app = Router::new().route("/jobs", get(|| async { "[]" }));
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve_tls(listener, app, &localhost(), async {
+                let _ = stop_rx.await;
+            })
+            .await
+        });
+
+        let cert = std::fs::read(localhost().cert_path)?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&cert)?)
+            .build()?;
+        let res = client
+            .get(format!("https://localhost:{port}/jobs"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.text().await?, "[]");
+
+        let _ = stop_tx.send(());
+        server.await??;
+        Ok(())
+    }
+}
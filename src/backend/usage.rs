@@ -0,0 +1,461 @@
+//! Accounting of what each user of a shared server generates, and the quotas that keep any
+//! of them from using more than their share.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::anyhow;
+use axum::extract::{Query, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{from_fn_with_state, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use time::Date;
+
+use crate::storage::Storage;
+
+/// The user everything is accounted to on servers without API tokens.
+pub const LOCAL_USER: &str = "local";
+
+/// API tokens and the users they belong to. Without any, the server is open to everyone
+/// and all the usage is accounted to [LOCAL_USER].
+#[derive(Clone, Debug, Default)]
+pub struct ApiTokens {
+    users: HashMap<String, String>,
+    /// Users allowed to manage the jobs of everyone.
+    admins: HashSet<String>,
+}
+
+impl ApiTokens {
+    /// Parses `user:token` pairs, as given in the command line.
+    pub fn parse(specs: &[String]) -> anyhow::Result<Self> {
+        let mut users = HashMap::new();
+        for spec in specs {
+            let Some((user, token)) = spec.split_once(':') else {
+                return Err(anyhow!("API token {spec:?} must be in the form user:token"));
+            };
+            let valid_user = user
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if user.is_empty() || !valid_user {
+                return Err(anyhow!(
+                    "User {user:?} must only contain letters, digits, - and _"
+                ));
+            }
+            if token.is_empty() {
+                return Err(anyhow!("The API token of {user} is empty"));
+            }
+            if users.insert(token.to_string(), user.to_string()).is_some() {
+                return Err(anyhow!("The API token of {user} is used more than once"));
+            }
+        }
+        Ok(Self {
+            users,
+            admins: HashSet::new(),
+        })
+    }
+
+    /// Makes `admins`, which must have API tokens, administrators of the server.
+    pub fn with_admins(mut self, admins: &[String]) -> anyhow::Result<Self> {
+        for admin in admins {
+            if !self.users.values().any(|user| user == admin) {
+                return Err(anyhow!("Admin {admin} has no API token"));
+            }
+            self.admins.insert(admin.clone());
+        }
+        Ok(self)
+    }
+
+    /// The user a request with `token` is made by, or None if it is not allowed.
+    pub fn authenticate(&self, token: Option<&str>) -> Option<String> {
+        if self.users.is_empty() {
+            return Some(LOCAL_USER.to_string());
+        }
+        self.users.get(token?).cloned()
+    }
+
+    /// Same as [ApiTokens::authenticate], but only allows administrators. Servers without
+    /// API tokens are administered by everyone who can reach them.
+    pub fn authenticate_admin(&self, token: Option<&str>) -> Option<String> {
+        self.authenticate(token)
+            .filter(|user| self.users.is_empty() || self.admins.contains(user))
+    }
+}
+
+/// Where a request carries its API token: an `Authorization: Bearer` header, or a `token`
+/// query parameter for browsers, which cannot set headers on WebSockets.
+#[derive(Debug, Default, Deserialize)]
+pub struct TokenQuery {
+    token: Option<String>,
+}
+
+impl TokenQuery {
+    pub fn token<'a>(&'a self, headers: &'a HeaderMap) -> Option<&'a str> {
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or(self.token.as_deref())
+    }
+}
+
+/// Who made a request to routes guarded by [require_tokens].
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub user: String,
+    pub admin: bool,
+}
+
+impl Caller {
+    /// Renders and jobs are only visible to who requested them, and to admins.
+    pub fn can_see(&self, owner: &str) -> bool {
+        self.admin || self.user == owner
+    }
+}
+
+/// Rejects the requests to the routes of `router` without a valid API token, and gives
+/// their handlers the [Caller] as an extension.
+pub fn require_tokens(router: Router, tokens: ApiTokens) -> Router {
+    router.route_layer(from_fn_with_state(tokens, check_token))
+}
+
+async fn check_token(
+    State(tokens): State<ApiTokens>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let token = query.token(&headers);
+    let Some(user) = tokens.authenticate(token) else {
+        return (StatusCode::UNAUTHORIZED, "Invalid API token").into_response();
+    };
+    let admin = tokens.authenticate_admin(token).is_some();
+    req.extensions_mut().insert(Caller { user, admin });
+    next.run(req).await
+}
+
+/// Caps on what each user can generate, reset at the start of each UTC day and month.
+/// Quotas that are not set are not enforced.
+#[derive(Clone, Debug, Default, Type, Serialize, Deserialize, PartialEq)]
+pub struct Quotas {
+    pub daily_secs: Option<usize>,
+    pub monthly_secs: Option<usize>,
+    pub daily_gpu_secs: Option<f64>,
+    pub monthly_gpu_secs: Option<f64>,
+}
+
+impl Quotas {
+    /// Decides whether a user with `usage` can request `secs` more seconds of audio on
+    /// `today`. How long a job keeps the model busy is only known once it finishes, so
+    /// users are stopped once they used up their processing time instead.
+    pub fn admit(&self, usage: &UserUsage, today: Date, secs: usize) -> anyhow::Result<()> {
+        let periods = [
+            (
+                "daily",
+                usage.day(today),
+                self.daily_secs,
+                self.daily_gpu_secs,
+            ),
+            (
+                "monthly",
+                usage.month(today),
+                self.monthly_secs,
+                self.monthly_gpu_secs,
+            ),
+        ];
+        for (period, used, max_secs, max_gpu_secs) in periods {
+            if let Some(max) = max_secs {
+                if used.secs + secs > max {
+                    return Err(anyhow!(
+                        "Requested {secs}s of audio, but only {}s are left of the {period} quota of {max}s",
+                        max.saturating_sub(used.secs)
+                    ));
+                }
+            }
+            if let Some(max) = max_gpu_secs {
+                if used.gpu_secs >= max {
+                    return Err(anyhow!(
+                        "Used {:.0}s of processing time, the {period} quota of {max:.0}s is exhausted",
+                        used.gpu_secs
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a user generated over some period.
+#[derive(Clone, Debug, Default, Type, Serialize, Deserialize, PartialEq)]
+pub struct UsageTotals {
+    pub renders: usize,
+    /// Seconds of audio generated.
+    pub secs: usize,
+    /// Seconds the model spent generating, including failed renders.
+    pub gpu_secs: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, other: &UsageTotals) {
+        self.renders += other.renders;
+        self.secs += other.secs;
+        self.gpu_secs += other.gpu_secs;
+    }
+}
+
+/// Everything a user generated, by UTC day.
+#[derive(Clone, Debug, Default, Type, Serialize, Deserialize, PartialEq)]
+pub struct UserUsage {
+    pub user: String,
+    /// Keyed by date, like 2024-05-31.
+    pub days: BTreeMap<String, UsageTotals>,
+}
+
+impl UserUsage {
+    fn path(user: &str) -> String {
+        format!("usage/{user}.json")
+    }
+
+    pub async fn load<S: Storage>(storage: &S, user: &str) -> anyhow::Result<Self> {
+        match storage.read(&Self::path(user)).await? {
+            Some(content) => Ok(serde_json::from_slice(&content)?),
+            None => Ok(Self {
+                user: user.to_string(),
+                days: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Adds a render to what `user` generated on `day`.
+    pub async fn record<S: Storage>(
+        storage: &S,
+        user: &str,
+        day: Date,
+        render: UsageTotals,
+    ) -> anyhow::Result<()> {
+        let mut usage = Self::load(storage, user).await?;
+        usage.days.entry(day.to_string()).or_default().add(&render);
+        Ok(storage
+            .write(&Self::path(user), serde_json::to_vec_pretty(&usage)?)
+            .await?)
+    }
+
+    pub fn day(&self, day: Date) -> UsageTotals {
+        self.days.get(&day.to_string()).cloned().unwrap_or_default()
+    }
+
+    /// What was generated during the month of `day`.
+    pub fn month(&self, day: Date) -> UsageTotals {
+        let month = format!("{:04}-{:02}-", day.year(), day.month() as u8);
+        let mut totals = UsageTotals::default();
+        for (_, usage) in self.days.iter().filter(|(d, _)| d.starts_with(&month)) {
+            totals.add(usage);
+        }
+        totals
+    }
+}
+
+/// The day usage is accounted to.
+pub fn today() -> Date {
+    time::OffsetDateTime::now_utc().date()
+}
+
+/// What the caller of `GET /usage` generated, and how much of it the quotas allow.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct UsageReport {
+    pub user: String,
+    pub today: UsageTotals,
+    pub month: UsageTotals,
+    pub quotas: Quotas,
+}
+
+#[derive(Clone)]
+struct UsageRoutesState<S: Storage> {
+    storage: S,
+    tokens: ApiTokens,
+    quotas: Quotas,
+}
+
+/// HTTP route for users to check their own usage.
+pub fn usage_routes<S: Storage>(storage: S, tokens: ApiTokens, quotas: Quotas) -> Router {
+    Router::new()
+        .route("/usage", get(get_usage::<S>))
+        .with_state(UsageRoutesState {
+            storage,
+            tokens,
+            quotas,
+        })
+}
+
+async fn get_usage<S: Storage>(
+    State(state): State<UsageRoutesState<S>>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+) -> Response {
+    let Some(user) = state.tokens.authenticate(query.token(&headers)) else {
+        return (StatusCode::UNAUTHORIZED, "Invalid API token").into_response();
+    };
+    match UserUsage::load(&state.storage, &user).await {
+        Ok(usage) => Json(UsageReport {
+            today: usage.day(today()),
+            month: usage.month(today()),
+            user,
+            quotas: state.quotas,
+        })
+        .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Month;
+
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn date(month: Month, day: u8) -> Date {
+        Date::from_calendar_date(2024, month, day).unwrap()
+    }
+
+    fn render(secs: usize, gpu_secs: f64) -> UsageTotals {
+        UsageTotals {
+            renders: 1,
+            secs,
+            gpu_secs,
+        }
+    }
+
+    #[test]
+    fn authenticates_tokens() -> anyhow::Result<()> {
+        let open = ApiTokens::default();
+        assert_eq!(open.authenticate(None), Some(LOCAL_USER.to_string()));
+
+        let tokens = ApiTokens::parse(&["alice:s3cret".to_string(), "bob:hunter2".to_string()])?;
+        assert_eq!(
+            tokens.authenticate(Some("hunter2")),
+            Some("bob".to_string())
+        );
+        assert_eq!(tokens.authenticate(Some("nope")), None);
+        assert_eq!(tokens.authenticate(None), None);
+
+        assert!(ApiTokens::parse(&["alice".to_string()]).is_err());
+        assert!(ApiTokens::parse(&["../alice:token".to_string()]).is_err());
+        assert!(ApiTokens::parse(&["alice:a".to_string(), "bob:a".to_string()]).is_err());
+
+        let tokens = tokens.with_admins(&["alice".to_string()])?;
+        assert_eq!(
+            tokens.authenticate_admin(Some("s3cret")),
+            Some("alice".to_string())
+        );
+        assert_eq!(tokens.authenticate_admin(Some("hunter2")), None);
+        assert!(tokens.with_admins(&["carol".to_string()]).is_err());
+        assert!(open.authenticate_admin(None).is_some());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer s3cret".parse()?);
+        assert_eq!(TokenQuery::default().token(&headers), Some("s3cret"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requires_tokens_on_guarded_routes() -> anyhow::Result<()> {
+        let tokens = ApiTokens::parse(&["alice:s3cret".to_string(), "bob:hunter2".to_string()])?
+            .with_admins(&["bob".to_string()])?;
+        let routes = Router::new().route(
+            "/whoami",
+            get(
+                |axum::Extension(caller): axum::Extension<Caller>| async move {
+                    format!("{} {}", caller.user, caller.admin)
+                },
+            ),
+        );
+        let app = require_tokens(routes, tokens);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let host = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let res = reqwest::get(format!("http://{host}/whoami")).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = reqwest::get(format!("http://{host}/whoami?token=nope")).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = reqwest::get(format!("http://{host}/whoami?token=s3cret")).await?;
+        assert_eq!(res.text().await?, "alice false");
+        let res = reqwest::Client::new()
+            .get(format!("http://{host}/whoami"))
+            .bearer_auth("hunter2")
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "bob true");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accounts_usage_by_day_and_month() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        UserUsage::record(&storage, "alice", date(Month::May, 30), render(60, 40.0)).await?;
+        UserUsage::record(&storage, "alice", date(Month::May, 31), render(30, 20.0)).await?;
+        UserUsage::record(&storage, "alice", date(Month::May, 31), render(10, 5.0)).await?;
+        UserUsage::record(&storage, "alice", date(Month::June, 1), render(20, 10.0)).await?;
+        UserUsage::record(&storage, "bob", date(Month::May, 31), render(5, 1.0)).await?;
+
+        let usage = UserUsage::load(&storage, "alice").await?;
+        let may_31 = date(Month::May, 31);
+        assert_eq!(
+            usage.day(may_31),
+            UsageTotals {
+                renders: 2,
+                secs: 40,
+                gpu_secs: 25.0
+            }
+        );
+        assert_eq!(usage.month(may_31).secs, 100);
+        assert_eq!(usage.month(date(Month::June, 1)).renders, 1);
+        assert_eq!(usage.day(date(Month::June, 2)), UsageTotals::default());
+        assert_eq!(UserUsage::load(&storage, "carol").await?.days.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn enforces_quotas() {
+        let mut usage = UserUsage::default();
+        usage
+            .days
+            .insert("2024-05-30".to_string(), render(60, 40.0));
+        usage
+            .days
+            .insert("2024-05-31".to_string(), render(30, 20.0));
+        let today = date(Month::May, 31);
+
+        assert!(Quotas::default().admit(&usage, today, 1000).is_ok());
+
+        let daily = Quotas {
+            daily_secs: Some(60),
+            ..Default::default()
+        };
+        assert!(daily.admit(&usage, today, 30).is_ok());
+        let err = daily.admit(&usage, today, 31).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only 30s are left of the daily quota"));
+
+        let monthly = Quotas {
+            monthly_secs: Some(100),
+            ..Default::default()
+        };
+        assert!(monthly.admit(&usage, today, 20).is_err());
+        assert!(monthly.admit(&usage, date(Month::June, 1), 20).is_ok());
+
+        let gpu = Quotas {
+            daily_gpu_secs: Some(20.0),
+            ..Default::default()
+        };
+        let err = gpu.admit(&usage, today, 1).unwrap_err();
+        assert!(err.to_string().contains("daily quota of 20s is exhausted"));
+    }
+}
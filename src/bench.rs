@@ -0,0 +1,226 @@
+//! Standardized generations for measuring how fast a model runs with the available
+//! execution providers and thread counts. Results are kept in the data directory, so that
+//! each run is compared with the previous one in the same setup.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+
+use crate::backend::JobProcessor;
+use crate::storage::Storage;
+
+const RESULTS_FILE: &str = "bench-results.json";
+const BENCH_PROMPT: &str = "Create a relaxing LoFi song";
+/// How often the memory of the process is sampled while generating.
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Measurements of one standardized generation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    /// Milliseconds since epoch of when the generation ran.
+    pub timestamp: u64,
+    /// MusicGPT version that ran the generation.
+    pub version: String,
+    pub model: String,
+    pub provider: String,
+    /// Threads per operation, None when ONNX Runtime decided.
+    pub threads: Option<usize>,
+    /// Seconds of audio generated.
+    pub secs: usize,
+    pub generation_secs: f64,
+    /// Peak resident memory of the whole process while generating, including the model.
+    pub peak_memory_bytes: u64,
+}
+
+impl BenchResult {
+    /// Seconds spent generating per second of audio.
+    pub fn rtf(&self) -> f64 {
+        self.generation_secs / self.secs.max(1) as f64
+    }
+
+    fn same_setup(&self, other: &BenchResult) -> bool {
+        self.model == other.model
+            && self.provider == other.provider
+            && self.threads == other.threads
+            && self.secs == other.secs
+    }
+}
+
+/// Generates `secs` seconds of audio, returning how long it took and the peak memory of
+/// the process meanwhile.
+pub fn run(processor: &dyn JobProcessor, secs: usize) -> anyhow::Result<(Duration, u64)> {
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let sampler = scope.spawn(|| {
+            let Ok(pid) = sysinfo::get_current_pid() else {
+                return 0;
+            };
+            let mut system = System::new();
+            let mut peak = 0;
+            loop {
+                system.refresh_processes_specifics(
+                    ProcessesToUpdate::Some(&[pid]),
+                    false,
+                    ProcessRefreshKind::nothing().with_memory(),
+                );
+                if let Some(process) = system.process(pid) {
+                    peak = peak.max(process.memory());
+                }
+                if done.load(Ordering::SeqCst) {
+                    return peak;
+                }
+                std::thread::sleep(MEMORY_SAMPLE_INTERVAL);
+            }
+        });
+        let start = Instant::now();
+        let result = processor.process(BENCH_PROMPT, secs, Box::new(|_, _| false));
+        let elapsed = start.elapsed();
+        done.store(true, Ordering::SeqCst);
+        let peak = sampler.join().unwrap_or_default();
+        let audio = result?;
+        if audio.is_empty() {
+            return Err(anyhow::anyhow!("No audio was generated"));
+        }
+        Ok((elapsed, peak))
+    })
+}
+
+/// Builds a [BenchResult] for a generation that just finished.
+pub fn result(
+    model: &str,
+    provider: &str,
+    threads: Option<usize>,
+    secs: usize,
+    (elapsed, peak_memory_bytes): (Duration, u64),
+) -> BenchResult {
+    BenchResult {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        model: model.to_string(),
+        provider: provider.to_string(),
+        threads,
+        secs,
+        generation_secs: elapsed.as_secs_f64(),
+        peak_memory_bytes,
+    }
+}
+
+/// All the results stored so far, oldest first.
+pub async fn load_results<S: Storage>(storage: &S) -> anyhow::Result<Vec<BenchResult>> {
+    match storage.read(RESULTS_FILE).await? {
+        Some(content) => Ok(serde_json::from_slice(&content).unwrap_or_default()),
+        None => Ok(vec![]),
+    }
+}
+
+/// Stores `results` after the ones stored so far.
+pub async fn save_results<S: Storage>(storage: &S, results: &[BenchResult]) -> anyhow::Result<()> {
+    let mut all = load_results(storage).await?;
+    all.extend_from_slice(results);
+    Ok(storage
+        .write(RESULTS_FILE, serde_json::to_vec_pretty(&all)?)
+        .await?)
+}
+
+/// Builds a table comparing `results`, along with how their real-time factor changed
+/// since the last result in `history` with the same setup.
+pub fn report(results: &[BenchResult], history: &[BenchResult]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<10} {:>7} {:>6} {:>9} {:>6} {:>9} {:>9}",
+        "Provider", "Threads", "Audio", "Time", "RTF", "Memory", "vs last"
+    );
+    for result in results {
+        let threads = match result.threads {
+            Some(threads) => threads.to_string(),
+            None => "auto".to_string(),
+        };
+        let trend = match history.iter().rev().find(|r| r.same_setup(result)) {
+            Some(last) => format!("{:+.0}%", (result.rtf() / last.rtf() - 1.0) * 100.0),
+            None => "-".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "{:<10} {:>7} {:>5}s {:>8.1}s {:>6.2} {:>6} MB {:>9}",
+            result.provider,
+            threads,
+            result.secs,
+            result.generation_secs,
+            result.rtf(),
+            result.peak_memory_bytes / 1024 / 1024,
+            trend
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn bench_result(provider: &str, threads: Option<usize>, generation_secs: f64) -> BenchResult {
+        BenchResult {
+            timestamp: 0,
+            version: "0.0.0".to_string(),
+            model: "small".to_string(),
+            provider: provider.to_string(),
+            threads,
+            secs: 10,
+            generation_secs,
+            peak_memory_bytes: 2048 * 1024 * 1024,
+        }
+    }
+
+    #[test]
+    fn compares_with_the_last_run_of_the_same_setup() {
+        let history = [
+            bench_result("Cpu", Some(4), 20.0),
+            bench_result("Cpu", Some(4), 10.0),
+            bench_result("Cuda", None, 1.0),
+        ];
+        let results = [
+            bench_result("Cpu", Some(4), 5.0),
+            bench_result("Cpu", None, 8.0),
+        ];
+        let report = report(&results, &history);
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[1],
+            "Cpu              4    10s      5.0s   0.50   2048 MB      -50%"
+        );
+        assert!(lines[2].contains("auto"));
+        assert!(lines[2].ends_with(" -"));
+    }
+
+    #[tokio::test]
+    async fn stores_results_after_the_previous_ones() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        assert!(load_results(&storage).await?.is_empty());
+        save_results(&storage, &[bench_result("Cpu", None, 1.0)]).await?;
+        save_results(&storage, &[bench_result("Cpu", None, 2.0)]).await?;
+        let results = load_results(&storage).await?;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].generation_secs, 2.0);
+        Ok(())
+    }
+
+    #[test]
+    fn measures_generations() -> anyhow::Result<()> {
+        let processor = DummyJobProcessor::new(Duration::from_millis(10));
+        let (elapsed, peak) = run(&processor, 5)?;
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(peak > 0);
+        Ok(())
+    }
+}
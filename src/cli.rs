@@ -1,17 +1,54 @@
 use anyhow::anyhow;
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fmt::{Display, Formatter};
-use std::path::PathBuf;
-use tracing::warn;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
 
+use crate::affinity::{self, CpuSet, SessionThreads};
+use crate::audio::aiff;
+use crate::audio::audio_sink::{wav_file_sink, MemorySink};
+use crate::audio::diff::{self, DiffTolerance};
+use crate::audio::dj_mix::{dj_mix, MixConfig, DEFAULT_CROSSFADE_BEATS};
+use crate::audio::extended_generation::{
+    ExtendedAudioGenerator, ExtendedGenerationConfig, JoinStyle,
+};
+use crate::audio::game_layers::{align_layers, layer_prompts, INTENSITIES, SLACK_SECS};
+use crate::audio::id3;
+use crate::audio::inpaint::{
+    append_outro, inpaint, intro_len, prepend_intro, InpaintRegion, DEFAULT_CROSSFADE_SECS,
+};
+use crate::audio::intro_outro::{IntroOutro, PRESETS};
+use crate::audio::loop_points::{self, LoopConfig};
+use crate::audio::loudness::LoudnessReport;
+use crate::audio::midi;
+use crate::audio::remix::{remix, section_len, section_prompts, sections};
+use crate::audio::resample::resample;
+use crate::audio::slicer::{self, SliceConfig, SliceKind};
+use crate::audio::spectrogram::{encode_bmp, spectrogram, SpectrogramConfig};
+use crate::audio::transitions::TransitionStyle;
+use crate::audio::upmix::{upmix, UpmixConfig};
+use crate::audio::watermark::{self, WatermarkPayload, WatermarkSink, Watermarker};
+use crate::audio::wav;
 use crate::backend::*;
+use crate::custom_models::CustomModel;
+use crate::gpu::SessionDevice;
+use crate::logging::LogFormat;
 use crate::onnxruntime_lib;
 use crate::storage::*;
 use crate::terminal::*;
-use crate::{gpu, musicgen_models};
+use crate::transcription::Transcriber;
+use crate::{
+    bench, config_file, gpu, hardware, hub, logging, model_cache, musicgen_models, profile,
+};
 
 pub const INPUT_IDS_BATCH_PER_SECOND: usize = 50;
+pub const SAMPLING_RATE: usize = 32000;
 
 #[derive(Clone, Copy, ValueEnum)]
 pub enum Model {
@@ -38,10 +75,501 @@ impl Display for Model {
     }
 }
 
+impl Model {
+    /// Rough estimation of the peak memory needed for running inference with this model.
+    pub fn estimated_memory_bytes(&self) -> u64 {
+        const GB: u64 = 1024 * 1024 * 1024;
+        match self {
+            Model::Small => 3 * GB,
+            Model::SmallFp16 => 2 * GB,
+            Model::SmallQuant => 2 * GB,
+            Model::Medium => 8 * GB,
+            Model::MediumFp16 => 5 * GB,
+            Model::MediumQuant => 4 * GB,
+            Model::Large => 16 * GB,
+        }
+    }
+
+    /// Rough real-time factor (seconds spent generating per second of audio) measured
+    /// running this model on an 8 core CPU.
+    pub fn reference_rtf(&self) -> f64 {
+        match self {
+            Model::Small => 0.6,
+            Model::SmallFp16 => 3.0,
+            Model::SmallQuant => 0.5,
+            Model::Medium => 2.0,
+            Model::MediumFp16 => 8.0,
+            Model::MediumQuant => 1.6,
+            Model::Large => 4.5,
+        }
+    }
+
+    /// The name used for referencing the model in the CLI and in the API.
+    pub fn name(&self) -> String {
+        self.to_possible_value()
+            .expect("Model variants are never skipped")
+            .get_name()
+            .to_string()
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the AI models used for generating audio.
+    #[command(subcommand)]
+    Models(ModelsCommand),
+    /// Report the detected hardware, the available execution providers and the
+    /// expected performance of each model.
+    Hardware,
+    /// Find the points between which a render loops the most seamlessly, and export the
+    /// audio between them as a loop.
+    Loop {
+        /// The render, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Where the loop is written. Defaults to the input path with a `-loop` suffix.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Shortest loop, in seconds.
+        #[arg(long, default_value = "4")]
+        min_secs: f32,
+        /// Longest loop, in seconds.
+        #[arg(long, default_value = "30")]
+        max_secs: f32,
+        /// Milliseconds over which the end of the loop is crossfaded into its start.
+        #[arg(long, default_value = "50")]
+        crossfade_ms: u32,
+    },
+    /// Slice a render into a sample pack: a folder with its one-shot hits, cut at its
+    /// transients, its one-bar loops, and a `pack.json` listing them.
+    Slice {
+        /// The render, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Name of the pack, which its samples are named after. Defaults to the name of the
+        /// input.
+        #[arg(long)]
+        name: Option<String>,
+        /// Where the pack is written. Defaults to the input path with a `-samples` suffix.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Most hits in the pack, the strongest ones.
+        #[arg(long, default_value = "32")]
+        max_hits: usize,
+        /// Beats per bar of the loops.
+        #[arg(long, default_value = "4")]
+        beats_per_bar: usize,
+    },
+    /// Render only a few seconds around each join between already generated segments, for
+    /// auditioning the crossfade settings without stitching the whole piece.
+    PreviewJoins {
+        /// The segments in order, WAV files like the ones MusicGPT generates.
+        #[arg(required = true, num_args = 2..)]
+        segments: Vec<PathBuf>,
+        /// Where the previews are written, one after the other with a second of silence
+        /// between them.
+        #[arg(long, default_value = "joins-preview.wav")]
+        output: PathBuf,
+        /// Seconds rendered on each side of each crossfade.
+        #[arg(long, default_value = "3")]
+        context_secs: f32,
+        /// Seconds over which consecutive segments are crossfaded.
+        #[arg(long, default_value = "2")]
+        crossfade_secs: f32,
+        /// Seconds of overlap between consecutive segments.
+        #[arg(long, default_value = "4")]
+        overlap_secs: f32,
+        /// How consecutive segments are blended.
+        #[arg(long, value_enum, default_value_t = Join::Crossfade)]
+        join: Join,
+        /// [ride-out join] Seconds it takes the next segment to fade in.
+        #[arg(long, default_value = "0.5")]
+        attack_secs: f32,
+    },
+    /// Compare two renders one second at a time, reporting the seconds in which their
+    /// samples, spectra or loudness diverge. Fails when they do.
+    Diff {
+        /// The reference render, a WAV file like the ones MusicGPT generates.
+        a: PathBuf,
+        /// The render compared with it, at the same sample rate.
+        b: PathBuf,
+        /// Largest difference between two samples that still counts as the same audio.
+        #[arg(long, default_value = "0.001")]
+        max_delta: f32,
+        /// Largest average distance between the log spectra of a second of each render.
+        #[arg(long, default_value = "0.01")]
+        max_spectral_distance: f32,
+        /// Largest difference of loudness in a second, in dB.
+        #[arg(long, default_value = "0.1")]
+        max_loudness_db: f32,
+    },
+    /// Look for the watermark that --watermark embeds in a render, and print what it says.
+    /// Fails when there is none.
+    DetectWatermark {
+        /// The render, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+    },
+    /// Generate a stretch of a render again, like a passage that went wrong, and crossfade
+    /// it back in. The replacement is aligned with the audio at both joins and brought to
+    /// the level of the audio around it.
+    Inpaint {
+        /// The render, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Where the stretch starts, in seconds or as minutes and seconds like 1:10.
+        #[arg(long, value_parser = parse_timestamp)]
+        from: f32,
+        /// Where the stretch ends, in seconds or as minutes and seconds like 1:40.
+        #[arg(long, value_parser = parse_timestamp)]
+        to: f32,
+        /// The prompt the replacement is generated from.
+        #[arg(long)]
+        prompt: String,
+        /// Seconds the replacement is crossfaded over at each join.
+        #[arg(long, default_value_t = DEFAULT_CROSSFADE_SECS)]
+        crossfade: f32,
+        /// Where the audio is written. Defaults to the input path with an `-inpainted`
+        /// suffix.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Generate an intro that leads into the beginning of a render, or of any track you
+    /// already like. The track is left as is, with the intro riding out under its first
+    /// moments.
+    Intro {
+        /// The track, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Seconds of intro before the track starts.
+        #[arg(long)]
+        secs: f32,
+        /// The prompt the intro is generated from.
+        #[arg(long)]
+        prompt: String,
+        /// Seconds the intro keeps playing under the beginning of the track.
+        #[arg(long, default_value_t = DEFAULT_CROSSFADE_SECS)]
+        crossfade: f32,
+        /// Where the audio is written. Defaults to the input path with an `-intro` suffix.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Generate a track around a clip of your own, like a recorded riff or a vocal drop,
+    /// placed at a position of it. The audio before and after the clip is generated from
+    /// the prompt, aligned with the clip and brought to its level, and rides under its
+    /// edges, so the clip itself is left as is.
+    Splice {
+        /// The clip, a WAV file.
+        clip: PathBuf,
+        /// Where the clip starts in the track, in seconds or as minutes and seconds like
+        /// 0:40.
+        #[arg(long, value_parser = parse_timestamp)]
+        at: f32,
+        /// Seconds of the whole track, including the clip.
+        #[arg(long)]
+        secs: f32,
+        /// The prompt the audio around the clip is generated from.
+        #[arg(long)]
+        prompt: String,
+        /// Seconds the generated audio keeps playing under each edge of the clip.
+        #[arg(long, default_value_t = DEFAULT_CROSSFADE_SECS)]
+        crossfade: f32,
+        /// Where the audio is written.
+        #[arg(long, default_value = "musicgpt-spliced.wav")]
+        output: PathBuf,
+    },
+    /// Generate a track again from a new prompt, keeping its structure. The track is split
+    /// into sections where its sound changes, each one is generated from the prompt with a
+    /// description of its energy, and the remix follows the loudness of the track.
+    Remix {
+        /// The track, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// The prompt the remix is generated from.
+        #[arg(long)]
+        prompt: String,
+        /// Seconds each section is crossfaded over the next one.
+        #[arg(long, default_value_t = DEFAULT_CROSSFADE_SECS)]
+        crossfade: f32,
+        /// Where the audio is written. Defaults to the input path with a `-remix` suffix.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Mix finished tracks into a continuous DJ mix. Every track is stretched to the tempo
+    /// of the mix, and fades into the next one over a long crossfade with their beats
+    /// lined up.
+    Mix {
+        /// The tracks in the order they are played, WAV files like the ones MusicGPT
+        /// generates.
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+        /// Tempo of the mix. Defaults to the one detected in the first track.
+        #[arg(long)]
+        bpm: Option<f32>,
+        /// Beats each track fades into the next one over.
+        #[arg(long, default_value_t = DEFAULT_CROSSFADE_BEATS)]
+        crossfade_beats: usize,
+        /// A synthesized element building up to the middle of each crossfade.
+        #[arg(long, value_enum)]
+        transition: Option<TransitionEffect>,
+        /// Where the mix is written.
+        #[arg(long, default_value = "musicgpt-mix.wav")]
+        output: PathBuf,
+    },
+    /// Transcribe a render, or a stem of one, into a MIDI file for editing its notes in a
+    /// DAW. Notes are estimated from the pitches in the audio unless --model is given.
+    Transcribe {
+        /// The audio, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Where the MIDI file is written. Defaults to the input path with a `.mid`
+        /// extension.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// The ONNX export of a basic-pitch model, which transcribes chords and busy mixes
+        /// much better than the estimation.
+        #[arg(long)]
+        model: Option<PathBuf>,
+    },
+    /// Convert a render into a big-endian AIFF file, for toolchains that prefer it to WAV.
+    /// Its metadata, like the provenance and the license, is carried over.
+    Aiff {
+        /// The render, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Bits per sample, 16 or 24.
+        #[arg(long, default_value = "24")]
+        bits: u16,
+        /// Where the AIFF file is written. Defaults to the input path with a `.aiff`
+        /// extension.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Upmix a render into a 5.1 surround WAV file, for video projects that need surround
+    /// deliverables. The lows go to the LFE channel and decorrelated copies of the rest
+    /// to the surrounds. Its metadata is carried over.
+    Upmix {
+        /// The render, a mono or stereo WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Frequencies under this one go to the LFE channel.
+        #[arg(long, default_value = "120")]
+        lfe_hz: f32,
+        /// Where the 5.1 file is written. Defaults to the input path ending in `-5.1.wav`.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Package a render for delivery as a single ZIP file with its audio in the chosen
+    /// formats, its generation manifest, a loudness report, a spectrogram and optionally a
+    /// cover image.
+    Package {
+        /// The render, a WAV file like the ones MusicGPT generates.
+        input: PathBuf,
+        /// Formats of the audio in the package, like wav,aiff.
+        #[arg(long, value_delimiter = ',', default_values_t = [DeliveryFormat::Wav])]
+        formats: Vec<DeliveryFormat>,
+        /// The manifest of the render. Defaults to the JSON file next to the input, if
+        /// there is one, or else to the provenance embedded in it.
+        #[arg(long)]
+        manifest: Option<PathBuf>,
+        /// A PNG, JPEG, GIF or WebP cover image. Defaults to the cover art embedded in the
+        /// input, or else to art generated from the prompt of the manifest with --artwork.
+        #[arg(long)]
+        cover: Option<PathBuf>,
+        /// Where the ZIP file is written. Defaults to the input path with a `.zip`
+        /// extension.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Render again the job described by the manifest of a render, with the same model
+    /// version, seed, segment prompts and post-processing. Fails if the model or the
+    /// tokenizer it used is missing or changed.
+    Replay {
+        /// The manifest of the render, like the JSON files next to the audio MusicGPT
+        /// generates.
+        manifest: PathBuf,
+        /// Where the audio is written. Defaults to the manifest path with a `-replay`
+        /// suffix.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Generate several variations of a prompt, each one sampled with its own seed, and
+    /// write them next to each other. Variations are decoded --batch-size at a time.
+    Variations {
+        /// The prompt of every variation.
+        prompt: String,
+        /// How many variations are generated.
+        #[arg(long, default_value = "4")]
+        count: usize,
+        /// Seconds of audio of each variation.
+        #[arg(long, default_value = "10")]
+        secs: usize,
+        /// Directory where the variations are written, named after their seeds.
+        #[arg(long, default_value = "variations")]
+        output: PathBuf,
+    },
+    /// Generate calm, medium and combat layers of the same piece for vertical remixing in
+    /// game audio middleware. The layers are sampled with the same seed, lined up to the
+    /// sample and share the same loop points, which are written into each file and into a
+    /// `layers.json` next to them.
+    Layers {
+        /// The prompt of the piece, which each layer adds its intensity to.
+        prompt: String,
+        /// Seconds of audio of each layer.
+        #[arg(long, default_value = "20")]
+        secs: usize,
+        /// Shortest loop, in seconds.
+        #[arg(long, default_value = "4")]
+        min_loop_secs: f32,
+        /// Directory where the layers are written, named after their intensity.
+        #[arg(long, default_value = "layers")]
+        output: PathBuf,
+    },
+    /// Run a pipeline of generate, mix, master, effects, export and upload steps described
+    /// in a YAML file, the same as the ones `POST /pipelines` takes.
+    Run {
+        /// The YAML file describing the pipeline.
+        pipeline: PathBuf,
+    },
+    /// Measure the real-time factor and memory of standardized generations with the
+    /// selected model, on the CPU and on the GPU if there is one, and compare them with
+    /// the previous run.
+    Bench {
+        /// Threads per operation to compare on the CPU, like 2,4,8. By default, ONNX
+        /// Runtime decides.
+        #[arg(long, value_delimiter = ',')]
+        threads: Vec<usize>,
+        /// Seconds of audio of each generation. The ones longer than a segment go through
+        /// extended generation.
+        #[arg(long, value_delimiter = ',', default_values_t = [10, 30, 120])]
+        secs: Vec<usize>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Join {
+    Crossfade,
+    RideOut,
+    Multiband,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TransitionEffect {
+    Riser,
+    NoiseCrescendo,
+    CymbalSwell,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DeliveryFormat {
+    Wav,
+    /// 24 bit AIFF.
+    Aiff,
+    /// A 5.1 surround upmix in WAV.
+    #[value(name = "5.1")]
+    Surround,
+}
+
+impl Display for DeliveryFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("no skipped values");
+        write!(f, "{}", value.get_name())
+    }
+}
+
+#[derive(Subcommand)]
+enum ModelsCommand {
+    /// List the available models, whether they are installed, their size and capabilities.
+    List,
+    /// Set the model used when no --model argument is provided.
+    Use {
+        /// The name of the model to use from now on, as shown in `models list`.
+        model: String,
+    },
+    /// Search the Hugging Face Hub for models compatible with MusicGPT, which are the repos
+    /// tagged with `musicgpt` that have a `musicgpt.json` manifest.
+    Search {
+        /// Text to look for in the repo names.
+        #[arg(default_value = "")]
+        query: String,
+    },
+    /// Download a compatible model from the Hugging Face Hub, making it available
+    /// to `models use` and the web app.
+    Pull {
+        /// The repo id, like `user/repo`.
+        repo: String,
+        /// Branch, tag or commit to download.
+        #[arg(long, default_value = "main")]
+        revision: String,
+    },
+    /// Load the installed models and generate a short sample with each of them, reporting
+    /// which ones work.
+    Verify {
+        /// Only verify this model, as shown in `models list`.
+        model: Option<String>,
+    },
+    /// Report how much space the downloaded models take.
+    Cache,
+    /// Free space by removing files that no model uses, like interrupted downloads or
+    /// previous versions of the models.
+    Gc {
+        /// Also remove all the models but the N most recently used ones. The model set
+        /// with `models use` and pinned models are always kept.
+        #[arg(long)]
+        keep_last: Option<usize>,
+        /// Only print what would be removed.
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+    /// Pin a model to an exact version, refusing to load it if its files change.
+    Pin {
+        /// The name of the model, as shown in `models list`.
+        model: String,
+        /// The model hash, or a prefix of it. If omitted, the installed version is pinned.
+        hash: Option<String>,
+    },
+    /// Remove the version pin of a model.
+    Unpin {
+        /// The name of the model, as shown in `models list`.
+        model: String,
+    },
+    /// Use an alternative tokenizer JSON file for a model, for example the one shipped
+    /// with a fine-tune. If no file is provided, the model's default tokenizer is restored.
+    Tokenizer {
+        /// The name of the model, as shown in `models list`.
+        model: String,
+        /// Path to the tokenizer JSON file.
+        file: Option<PathBuf>,
+    },
+}
+
+/// Settings persisted in the data directory across runs.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Settings {
+    model: Option<String>,
+    /// Alternative tokenizer files by model name.
+    #[serde(default)]
+    pub(crate) tokenizers: HashMap<String, PathBuf>,
+    /// Hashes that models must match for being loaded, by model name.
+    #[serde(default)]
+    pub(crate) pinned_versions: HashMap<String, String>,
+}
+
+const SETTINGS_FILE: &str = "settings.json";
+
+impl Settings {
+    pub(crate) async fn load<S: Storage>(storage: &S) -> anyhow::Result<Self> {
+        match storage.read(SETTINGS_FILE).await? {
+            Some(content) => Ok(serde_json::from_slice(&content)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        Ok(storage
+            .write(SETTINGS_FILE, serde_json::to_vec_pretty(self)?)
+            .await?)
+    }
+}
+
 #[derive(Parser)]
 #[command(name = "MusicGPT")]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The prompt for the LLM.
     /// If this argument is provided, MusicGPT will enter
     /// [CLI mode], where audio playback and prompting is managed through the terminal.
@@ -53,13 +581,21 @@ struct Args {
     /// The model to use. Some models are experimental, for example quantized models
     /// have a degraded quality and fp16 models are very slow.
     /// Beware of large models, you will need really powerful hardware for those.
-    #[arg(long, default_value = "small")]
-    model: Model,
+    /// If omitted, the model set with `models use` is used, and if none was set, the
+    /// largest model that fits in the available memory is picked automatically.
+    #[arg(long, default_value = None)]
+    model: Option<Model>,
+
+    /// Path to the JSON manifest of a user-supplied model, like a fine-tuned MusicGen
+    /// variant. It can be provided multiple times, and the first one is used unless
+    /// --model is also provided.
+    #[arg(long, global = true)]
+    custom_model: Vec<PathBuf>,
 
     /// The LLM models are exported using https://github.com/huggingface/optimum,
     /// and they export transformer-based decoders either in two files, or a single
     /// merged one.
-    #[arg(long, default_value = "false")]
+    #[arg(long, global = true, default_value = "false")]
     use_split_decoder: bool,
 
     /// Force the download of LLM models.
@@ -67,16 +603,40 @@ struct Args {
     force_download: bool,
 
     /// Overrides the default data storage path.
-    #[arg(long, default_value = None)]
+    #[arg(long, global = true, default_value = None)]
     data_path: Option<PathBuf>,
 
+    /// JSON file with named profiles of flags. Defaults to config.json in the config
+    /// directory of MusicGPT, like ~/.config/musicgpt on Linux.
+    #[arg(long, global = true, default_value = None)]
+    config: Option<PathBuf>,
+
+    /// Profile of the config file whose flags are applied, like draft or final. Flags given
+    /// in the command line win over the ones of the profile. Defaults to the
+    /// default_profile of the config file, if any.
+    #[arg(long, global = true, default_value = None)]
+    profile: Option<String>,
+
+    /// Records the time spent in each stage of the generation, like decoding or
+    /// crossfading, and writes it to this JSON file when exiting, as a Chrome trace that
+    /// Perfetto or speedscope show as a flame graph.
+    #[arg(long, global = true, default_value = None)]
+    trace: Option<PathBuf>,
+
+    /// Format of the logs, either human-readable text or one JSON object per line, with
+    /// the id of the job and the index of the segment each event belongs to.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
     /// Use the device's GPU for inference if available. GPU support is experimental.
     #[arg(long, default_value = "false")]
     gpu: bool,
 
-    /// [CLI mode] The seconds of audio to generate.
-    #[arg(long, default_value = "10")]
-    secs: usize,
+    /// [CLI mode] The seconds of audio to generate, like 87.43. Renders longer than 30
+    /// seconds are generated in segments stitched together. Defaults to the duration the
+    /// prompt asks for, like "a three and a half minute track", or to 10 seconds.
+    #[arg(long)]
+    secs: Option<f32>,
 
     /// [CLI mode] Output path for the resulting .wav file.
     #[arg(long, default_value = "musicgpt-generated.wav")]
@@ -90,6 +650,12 @@ struct Args {
     #[arg(long, default_value = "false")]
     no_interactive: bool,
 
+    /// [CLI mode] Writes each segment of extended renders to a temporary file as soon as
+    /// it is generated, and asks whether to keep it or to generate it again before
+    /// stitching it with the rest.
+    #[arg(long, default_value = "false")]
+    audition: bool,
+
     /// [UI mode] Omits automatically opening the web app in a browser.
     #[arg(long, default_value = "false")]
     ui_no_open: bool,
@@ -101,15 +667,288 @@ struct Args {
     /// [UI mode] Exposes the MusicGPT web app in 0.0.0.0 instead of 127.0.0.1.
     #[arg(long, default_value = "false")]
     ui_expose: bool,
+
+    /// Intro and outro preset applied to extended renders, one of gentle, cinematic or
+    /// club.
+    #[arg(long, default_value = None)]
+    intro_outro: Option<String>,
+
+    /// Turns down extended renders while their level stays under this many dBFS, like -50,
+    /// suppressing codec hiss in quiet passages.
+    #[arg(long, default_value = None, allow_hyphen_values = true)]
+    noise_gate: Option<f32>,
+
+    /// Milliseconds it takes the noise gate to close once the level falls.
+    #[arg(long, default_value = "200")]
+    noise_gate_release_ms: f32,
+
+    /// Removes the codec buzz from the segments of extended renders, with a strength from
+    /// 0 to 1.
+    #[arg(long, default_value = None)]
+    denoise: Option<f32>,
+
+    /// Brings each segment of extended renders to this RMS level in dBFS, like -20, before
+    /// crossfading it, so that joins between segments at different levels stay even.
+    #[arg(long, default_value = None, allow_hyphen_values = true)]
+    normalize_segments: Option<f32>,
+
+    /// Limits the peaks of extended renders to this many dB under full scale.
+    #[arg(long, default_value = None)]
+    headroom: Option<f32>,
+
+    /// Ends extended renders on the downbeat closest to their duration, up to this many
+    /// seconds earlier or later, instead of cutting them in the middle of a bar.
+    #[arg(long, default_value = None)]
+    end_on_downbeat: Option<f32>,
+
+    /// Seconds of each segment of extended renders, up to 30. Defaults to 28, or less for
+    /// custom models limited to shorter generations.
+    #[arg(long, default_value = None)]
+    segment_secs: Option<f32>,
+
+    /// Seconds consecutive segments of extended renders overlap. Defaults to 4.
+    #[arg(long, default_value = None)]
+    segment_overlap_secs: Option<f32>,
+
+    /// Seconds of the overlap over which consecutive segments are crossfaded. Defaults to 2.
+    #[arg(long, default_value = None)]
+    segment_crossfade_secs: Option<f32>,
+
+    /// Generates each segment of extended renders as a continuation of the previous one,
+    /// reusing the decoder's cache when it has room for it, instead of from scratch.
+    #[arg(long, default_value_t = false)]
+    continue_segments: bool,
+
+    /// Seed of the sampling of the model, so that the same prompt always renders the same
+    /// audio. It is recorded in the manifest of each render for replaying it.
+    #[arg(long, default_value = None)]
+    seed: Option<u64>,
+
+    /// Embeds an inaudible watermark labelling the audio as AI-generated into every render,
+    /// along with the id of the job in UI mode. Renders need at least 5 seconds for it.
+    #[arg(long, default_value_t = false)]
+    watermark: bool,
+
+    /// License recorded in the metadata of every render, like CC-BY-4.0, next to where it
+    /// comes from: the model and its version, a hash of the prompt and when it was made.
+    #[arg(long, default_value = None)]
+    license: Option<String>,
+
+    /// JSON file with the program or the HTTP endpoint generating cover art from the prompt,
+    /// embedded in the ID3 tags of renders and in the ZIP files of `package`.
+    #[arg(long, default_value = None)]
+    artwork: Option<PathBuf>,
+
+    /// Variations of a prompt decoded at the same time in one run of the model, stacked
+    /// along its batch dimension. Larger batches are faster but need more memory.
+    #[arg(long, default_value = "4")]
+    batch_size: usize,
+
+    /// Cores the threads of each inference operation run on, like 0-15 or 0,2,4,6, one
+    /// thread pinned to each core. Only supported on Linux and Windows.
+    #[arg(long, default_value = None)]
+    ort_cpus: Option<CpuSet>,
+
+    /// Cores the audio post-processing workers run on, one worker pinned to each core. Only
+    /// supported on Linux.
+    #[arg(long, default_value = None)]
+    dsp_cpus: Option<CpuSet>,
+
+    /// Runs inference and post-processing on the cores of this NUMA node, unless
+    /// --ort-cpus or --dsp-cpus place them elsewhere. Only supported on Linux.
+    #[arg(long, default_value = None)]
+    numa_node: Option<usize>,
+
+    /// Smaller model that drafts the tokens of the selected one, which then only checks
+    /// them. Speeds up large models on GPUs with memory for both.
+    #[arg(long, default_value = None)]
+    draft_model: Option<Model>,
+
+    /// Steps the --draft-model proposes before the selected model checks them.
+    #[arg(long, default_value = "4")]
+    draft_steps: usize,
+
+    /// [UI mode] Jobs that run at the same time, each one on its own copy of the model
+    /// loaded when first needed. Every copy takes as much memory as the first one.
+    #[arg(long, default_value = "1")]
+    max_concurrent_jobs: usize,
+
+    /// [UI mode] Rejects requests for more than these seconds of audio.
+    #[arg(long, default_value = None)]
+    max_job_secs: Option<usize>,
+
+    /// [UI mode] Rejects jobs that would generate more than these segments at the same time.
+    #[arg(long, default_value = None)]
+    max_job_concurrent_segments: Option<usize>,
+
+    /// [UI mode] Rejects jobs estimated to need more than these megabytes of memory, on top
+    /// of the memory used by the model.
+    #[arg(long, default_value = None)]
+    max_job_memory_mb: Option<u64>,
+
+    /// [UI mode] Requires connecting with an API token, given as user:token. Can be
+    /// repeated, and the usage of each user is accounted separately.
+    #[arg(long)]
+    api_token: Vec<String>,
+
+    /// [UI mode] User of an --api-token allowed to manage the jobs of every user through
+    /// the /admin routes. Can be repeated.
+    #[arg(long)]
+    admin: Vec<String>,
+
+    /// [UI mode] Seconds of audio each user can generate per UTC day.
+    #[arg(long, default_value = None)]
+    daily_quota_secs: Option<usize>,
+
+    /// [UI mode] Seconds of audio each user can generate per UTC month.
+    #[arg(long, default_value = None)]
+    monthly_quota_secs: Option<usize>,
+
+    /// [UI mode] Seconds each user can keep the model busy per UTC day.
+    #[arg(long, default_value = None)]
+    daily_gpu_quota_secs: Option<f64>,
+
+    /// [UI mode] Seconds each user can keep the model busy per UTC month.
+    #[arg(long, default_value = None)]
+    monthly_gpu_quota_secs: Option<f64>,
+
+    /// [UI mode] Serves the web app and its API under this path, like /musicgpt, for
+    /// reverse proxies that forward a subpath without stripping it.
+    #[arg(long, default_value = None)]
+    ui_base_path: Option<String>,
+
+    /// [UI mode] Address or CIDR range of a reverse proxy whose X-Forwarded-For and
+    /// X-Forwarded-Prefix headers are trusted. Can be repeated.
+    #[arg(long)]
+    ui_trusted_proxy: Vec<String>,
+
+    /// [UI mode] Origin allowed to call the HTTP API from browsers, like
+    /// https://example.com, or * for any. Can be repeated.
+    #[arg(long)]
+    ui_cors_origin: Vec<String>,
+
+    /// [UI mode] Serves the web app over HTTPS with this PEM certificate chain. Renewed
+    /// certificates are picked up without restarting.
+    #[arg(long, default_value = None, requires = "ui_tls_key")]
+    ui_tls_cert: Option<PathBuf>,
+
+    /// [UI mode] PEM private key of the certificate given in --ui-tls-cert.
+    #[arg(long, default_value = None, requires = "ui_tls_cert")]
+    ui_tls_key: Option<PathBuf>,
+
+    /// [UI mode] JSON file with generation jobs to submit on a cron schedule, like a few
+    /// new tracks every night.
+    #[arg(long, default_value = None)]
+    schedules: Option<PathBuf>,
+
+    /// [UI mode] JSON file with the email, Slack and Discord notifiers told when renders
+    /// complete or fail.
+    #[arg(long, default_value = None)]
+    notifiers: Option<PathBuf>,
+
+    /// [UI mode] Publishes the finished renders as a podcast feed at /podcast.xml, with
+    /// links under this URL the server is reached at, like https://music.example.com.
+    #[arg(long, default_value = None)]
+    ui_podcast_url: Option<String>,
+
+    /// [UI mode] JSON file with a WebDAV, SFTP or S3 remote the renders are mirrored to
+    /// after each job completes.
+    #[arg(long, default_value = None)]
+    sync: Option<PathBuf>,
+
+    /// [UI mode] Archives the completed renders and their manifests named after their
+    /// SHA-256, served at /archive/{sha256} and never changing.
+    #[arg(long, default_value = "false")]
+    archive: bool,
+
+    /// [UI mode] IPFS RPC API the archived renders are pinned on, like
+    /// http://127.0.0.1:5001.
+    #[arg(long, default_value = None, requires = "archive")]
+    archive_ipfs_api: Option<String>,
+
+    /// [UI mode] Serves a Swagger UI for browsing /openapi.json at /docs. The page loads
+    /// its scripts from unpkg.com.
+    #[arg(long, default_value = "false")]
+    ui_api_docs: bool,
 }
 
 impl Args {
     fn validate(&self) -> anyhow::Result<()> {
-        if self.secs < 1 {
+        if self.secs.is_some_and(|secs| secs <= 0.0) {
             return Err(anyhow!("--secs must > 0"));
         }
-        if self.secs > 30 {
-            return Err(anyhow!("--secs must <= 30"));
+        if self.batch_size < 1 {
+            return Err(anyhow!("--batch-size must > 0"));
+        }
+        if self.draft_steps < 1 {
+            return Err(anyhow!("--draft-steps must > 0"));
+        }
+        if self.max_concurrent_jobs < 1 {
+            return Err(anyhow!("--max-concurrent-jobs must > 0"));
+        }
+        self.post_chain()
+            .apply(Default::default())
+            .validate()
+            .map_err(|err| anyhow!("Invalid segments of extended renders: {err}"))?;
+        if self.draft_model.is_some() && self.use_split_decoder {
+            return Err(anyhow!(
+                "--draft-model cannot be used with --use-split-decoder"
+            ));
+        }
+        if self.max_job_secs == Some(0) {
+            return Err(anyhow!("--max-job-secs must > 0"));
+        }
+        if self.max_job_concurrent_segments == Some(0) {
+            return Err(anyhow!("--max-job-concurrent-segments must > 0"));
+        }
+        if self.daily_quota_secs == Some(0) {
+            return Err(anyhow!("--daily-quota-secs must > 0"));
+        }
+        if self.monthly_quota_secs == Some(0) {
+            return Err(anyhow!("--monthly-quota-secs must > 0"));
+        }
+        if self.daily_gpu_quota_secs.is_some_and(|secs| secs <= 0.0) {
+            return Err(anyhow!("--daily-gpu-quota-secs must > 0"));
+        }
+        if self.monthly_gpu_quota_secs.is_some_and(|secs| secs <= 0.0) {
+            return Err(anyhow!("--monthly-gpu-quota-secs must > 0"));
+        }
+        ApiTokens::parse(&self.api_token)?.with_admins(&self.admin)?;
+        self.proxy_config()?;
+        self.cpus()?;
+        if let Some(name) = &self.intro_outro {
+            if IntroOutro::preset(name).is_none() {
+                return Err(anyhow!(
+                    "--intro-outro must be one of {}",
+                    PRESETS.join(", ")
+                ));
+            }
+        }
+        if self.noise_gate.is_some_and(|db| db >= 0.0) {
+            return Err(anyhow!("--noise-gate must < 0"));
+        }
+        if self.noise_gate_release_ms <= 0.0 {
+            return Err(anyhow!("--noise-gate-release-ms must > 0"));
+        }
+        if self
+            .denoise
+            .is_some_and(|strength| !(0.0..=1.0).contains(&strength))
+        {
+            return Err(anyhow!("--denoise must be between 0 and 1"));
+        }
+        if self.normalize_segments.is_some_and(|db| db >= 0.0) {
+            return Err(anyhow!("--normalize-segments must < 0"));
+        }
+        if self.headroom.is_some_and(|db| db < 0.0) {
+            return Err(anyhow!("--headroom must >= 0"));
+        }
+        if self.end_on_downbeat.is_some_and(|secs| secs <= 0.0) {
+            return Err(anyhow!("--end-on-downbeat must > 0"));
+        }
+        if self.audition && self.seed.is_some() {
+            return Err(anyhow!(
+                "--audition cannot be used with --seed, rejected segments would sound the same again"
+            ));
         }
         if self.no_interactive && self.prompt.is_empty() {
             return Err(anyhow!(
@@ -118,21 +957,1745 @@ impl Args {
         }
         Ok(())
     }
+
+    /// The cores of the inference threads and of the post-processing workers, if placed.
+    fn cpus(&self) -> anyhow::Result<(Option<CpuSet>, Option<CpuSet>)> {
+        let node = match self.numa_node {
+            Some(node) => Some(CpuSet::numa_node(node).map_err(|err| anyhow!(err))?),
+            None => None,
+        };
+        Ok((
+            self.ort_cpus.clone().or(node.clone()),
+            self.dsp_cpus.clone().or(node),
+        ))
+    }
+
+    fn proxy_config(&self) -> anyhow::Result<ProxyConfig> {
+        ProxyConfig::parse(
+            self.ui_base_path.as_deref(),
+            &self.ui_trusted_proxy,
+            &self.ui_cors_origin,
+        )
+    }
+
+    fn post_chain(&self) -> PostChain {
+        PostChain {
+            intro_outro: self.intro_outro.clone(),
+            noise_gate_db: self.noise_gate,
+            noise_gate_release_ms: self.noise_gate_release_ms,
+            denoise: self.denoise,
+            normalize_segments_db: self.normalize_segments,
+            headroom_db: self.headroom,
+            end_on_downbeat: self.end_on_downbeat,
+            segment_secs: self.segment_secs,
+            overlap_secs: self.segment_overlap_secs,
+            crossfade_secs: self.segment_crossfade_secs,
+            continue_segments: self.continue_segments,
+        }
+    }
+
+    fn job_limits(&self) -> JobLimits {
+        JobLimits {
+            max_secs: self.max_job_secs,
+            max_concurrent_segments: self.max_job_concurrent_segments,
+            max_memory_bytes: self.max_job_memory_mb.map(|mb| mb * 1024 * 1024),
+        }
+    }
 }
 
-pub async fn cli() -> anyhow::Result<()> {
-    let args = Args::parse();
+/// Flags of UI mode that can change in the config file while the server runs. The
+/// others need a restart, like the ones picking the model.
+const RELOADABLE_FLAGS: &[&str] = &[
+    "--max-job-secs",
+    "--max-job-concurrent-segments",
+    "--max-job-memory-mb",
+    "--notifiers",
+    "--intro-outro",
+    "--noise-gate",
+    "--noise-gate-release-ms",
+    "--denoise",
+    "--normalize-segments",
+    "--headroom",
+    "--end-on-downbeat",
+    "--segment-secs",
+    "--segment-overlap-secs",
+    "--segment-crossfade-secs",
+    "--continue-segments",
+    "--watermark",
+    "--license",
+];
+
+/// The settings of the server after the config file changed from setting the flags
+/// `applied` to the current ones, which are returned along with them.
+fn reload_settings(
+    argv: &[OsString],
+    applied: &[(String, Option<String>)],
+    tokenizers: &HashMap<String, PathBuf>,
+) -> anyhow::Result<(config_file::Flags, Option<PathBuf>, LiveSettings)> {
+    let flags = config_file::applied_flags(argv)?;
+    let restart = config_file::changed_flags(applied, &flags)
+        .into_iter()
+        .filter(|flag| !RELOADABLE_FLAGS.contains(&flag.as_str()))
+        .collect::<Vec<_>>();
+    if !restart.is_empty() {
+        return Err(anyhow!(
+            "Changing {} needs a restart, nothing was reloaded",
+            restart.join(", ")
+        ));
+    }
+    let args = Args::try_parse_from(config_file::with_profile(argv.to_vec())?)?;
     args.validate()?;
+    let notifiers = match &args.notifiers {
+        Some(path) => NotifierConfig::load_all(path)?,
+        None => vec![],
+    };
+    let live = LiveSettings {
+        limits: args.job_limits(),
+        render_settings: RenderSettings {
+            seed: args.seed,
+            tokenizers: tokenizers.clone(),
+            post: args.post_chain(),
+            watermark: args.watermark,
+            license: args.license.clone(),
+        },
+        notifiers,
+    };
+    Ok((flags, args.notifiers, live))
+}
+
+/// Sends the settings of the server each time the config file or the notifiers change,
+/// polling them every few seconds, until the server stops.
+fn watch_settings(
+    argv: Vec<OsString>,
+    notifiers: Option<PathBuf>,
+    tokenizers: HashMap<String, PathBuf>,
+) -> tokio::sync::mpsc::UnboundedReceiver<LiveSettings> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let modified = |path: Option<&Path>| {
+            path.and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        };
+        let config = config_file::config_path(&argv);
+        let mut applied = config_file::applied_flags(&argv).unwrap_or_default();
+        let mut notifiers = notifiers;
+        let mut seen = (modified(Some(&config)), modified(notifiers.as_deref()));
+        loop {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            if tx.is_closed() {
+                return;
+            }
+            let current = (modified(Some(&config)), modified(notifiers.as_deref()));
+            if current == seen {
+                continue;
+            }
+            seen = current;
+            match reload_settings(&argv, &applied, &tokenizers) {
+                Ok((flags, path, live)) => {
+                    applied = flags;
+                    notifiers = path;
+                    seen.1 = modified(notifiers.as_deref());
+                    let _ = tx.send(live);
+                }
+                Err(err) => warn!("The config file {config:?} was not reloaded: {err}"),
+            }
+        }
+    });
+    rx
+}
 
-    let storage = AppFs::new(
-        args.data_path.unwrap_or(
-            ProjectDirs::from("com", "gabotechs", "musicgpt")
-                .expect("Could not load project directory")
-                .data_dir()
-                .into(),
+/// Where models, settings and generated audio are stored when no data path is provided.
+pub(crate) fn default_data_path() -> PathBuf {
+    ProjectDirs::from("com", "gabotechs", "musicgpt")
+        .expect("Could not load project directory")
+        .data_dir()
+        .into()
+}
+
+/// Parses a position in a render given in seconds, like `70.5`, or in minutes and seconds,
+/// like `1:10.5`.
+fn parse_timestamp(value: &str) -> Result<f32, String> {
+    let (minutes, secs) = match value.split_once(':') {
+        Some((minutes, secs)) => (minutes.parse::<u32>().map_err(|e| e.to_string())?, secs),
+        None => (0, value),
+    };
+    let secs = secs.parse::<f32>().map_err(|e| e.to_string())?;
+    if !(0.0..).contains(&secs) {
+        return Err(format!("{value} is before the beginning"));
+    }
+    Ok(minutes as f32 * 60.0 + secs)
+}
+
+/// Encodes `audio`, decoded from the WAV file `wav`, as AIFF with the INFO and ID3 tags of
+/// the WAV file.
+fn aiff_from_wav(
+    wav: &[u8],
+    audio: &[f32],
+    sample_rate: u32,
+    bits: u16,
+) -> anyhow::Result<Vec<u8>> {
+    let text = aiff::text_from_info(&wav::info_tags(wav));
+    let mut aiff =
+        aiff::encode_aiff(audio, sample_rate, bits, &text).map_err(|err| anyhow!(err))?;
+    if let Some(tag) = wav::id3_tag(wav) {
+        aiff::append_chunk(&mut aiff, b"ID3 ", tag);
+    }
+    Ok(aiff)
+}
+
+/// Upmixes a mono or stereo WAV file into a 5.1 one, carrying its INFO and ID3 tags over.
+fn upmix_wav(bytes: &[u8], config: &UpmixConfig) -> anyhow::Result<Vec<u8>> {
+    let (channels, sample_rate) = wav::decode_wav_channels(bytes).map_err(|err| anyhow!(err))?;
+    let (left, right) = match channels.as_slice() {
+        [mono] => (mono, mono),
+        [left, right] => (left, right),
+        _ => return Err(anyhow!("Only mono and stereo renders can be upmixed")),
+    };
+    let surround = upmix(left, right, sample_rate as usize, config);
+    let mut upmixed = wav::encode_wav_channels(&surround, sample_rate)?;
+    let tags = wav::info_tags(bytes);
+    if !tags.is_empty() {
+        let tags = tags
+            .iter()
+            .map(|(id, value)| (*id, value.as_str()))
+            .collect::<Vec<_>>();
+        wav::append_chunk(&mut upmixed, &wav::info_chunk(&tags));
+    }
+    if let Some(tag) = wav::id3_tag(bytes) {
+        wav::append_chunk(&mut upmixed, &wav::id3_chunk(tag));
+    }
+    Ok(upmixed)
+}
+
+/// Picks the model to run, returning its name, its display name and why it was picked.
+/// Models passed with --custom-model take precedence over the one set with `models use`.
+async fn select_model<S: Storage>(
+    model: Option<Model>,
+    registry: &musicgen_models::MusicGenModelRegistry<S>,
+    custom: bool,
+    used: Option<String>,
+    gpu: bool,
+) -> anyhow::Result<(String, String, String)> {
+    Ok(match (model, custom, used) {
+        (Some(model), _, _) => (
+            model.name(),
+            model.to_string(),
+            format!("{model} selected with --model"),
         ),
-    );
+        (None, true, _) => {
+            // Models passed with --custom-model are always first in the registry.
+            let display_name = registry.custom_models[0].entry().display_name;
+            let reason = format!("{display_name} selected with --custom-model");
+            (registry.custom_models[0].name.clone(), display_name, reason)
+        }
+        (None, false, Some(name)) => {
+            let Some(entry) = registry.list().await?.into_iter().find(|m| m.name == name) else {
+                return Err(anyhow!("Unknown model {name} set with `models use`"));
+            };
+            let reason = format!("{} selected with `models use`", entry.display_name);
+            (entry.name, entry.display_name, reason)
+        }
+        (None, false, None) => {
+            let (model, reason) = auto_select_model(&registry.storage, gpu).await?;
+            (model.name(), model.to_string(), reason)
+        }
+    })
+}
+
+/// Picks the largest model the hardware can run, see [hardware::select_model].
+async fn auto_select_model<S: Storage>(storage: &S, gpu: bool) -> anyhow::Result<(Model, String)> {
+    let providers = match gpu {
+        // Probing needs ONNX Runtime, which is set up again with the provider once the
+        // model is picked.
+        true => {
+            onnxruntime_lib::init::init(storage.clone())
+                .await?
+                .commit()?;
+            gpu::probe_execution_providers()
+        }
+        false => vec![],
+    };
+    Ok(hardware::select_model(
+        &hardware::HardwareInfo::probe(),
+        &providers,
+        gpu,
+    ))
+}
+
+/// The command line with the flags set by `MUSICGPT_*` environment variables, and the
+/// variables that do not set any.
+fn command_line() -> anyhow::Result<(Vec<OsString>, Vec<String>)> {
+    config_file::with_env(
+        std::env::args_os().collect(),
+        &Args::command(),
+        std::env::vars_os(),
+    )
+}
+
+pub async fn cli() -> anyhow::Result<()> {
+    let (line, unknown) = command_line()?;
+    let args = Args::parse_from(config_file::with_profile(line)?);
+    logging::init(args.log_format);
+    for var in unknown {
+        warn!("{var} does not set any flag, it is ignored");
+    }
+    args.validate()?;
+
+    let Some(path) = args.trace.clone() else {
+        return run(args).await;
+    };
+    profile::start();
+    let result = run(args).await;
+    profile::finish(&path)?;
+    result
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let proxy = args.proxy_config()?;
+    // Invalid schedules are reported before spending time loading the model.
+    let schedules = match &args.schedules {
+        Some(path) => Schedule::load_all(path)?,
+        None => vec![],
+    };
+    let notifiers = match &args.notifiers {
+        Some(path) => NotifierConfig::load_all(path)?,
+        None => vec![],
+    };
+    let sync = match &args.sync {
+        Some(path) => Some(SyncConfig::load(path)?),
+        None => None,
+    };
+    let artwork = match &args.artwork {
+        Some(path) => Some(ArtworkConfig::load(path)?.build()),
+        None => None,
+    };
+    let (ort_cpus, dsp_cpus) = args.cpus()?;
+    if let Some(cpus) = &dsp_cpus {
+        affinity::pin_dsp_threads(cpus).map_err(|err| anyhow!(err))?;
+        info!("Post-processing runs on cores {cpus}");
+    }
+    let storage = AppFs::new(args.data_path.clone().unwrap_or_else(default_data_path));
     let root = storage.root.clone();
+    // Jobs of the CLI mode have no id, the ones in UI mode add theirs.
+    let watermark = args.watermark.then_some(WatermarkPayload {
+        ai_generated: true,
+        job_id: None,
+    });
+
+    let mut settings = Settings::load(&storage).await?;
+    let mut custom_models = args
+        .custom_model
+        .iter()
+        .map(|path| CustomModel::load(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Models pulled from the hub cannot take the name of any of these.
+    let reserved_names = Model::value_variants()
+        .iter()
+        .map(Model::name)
+        .chain(custom_models.iter().map(|m| m.name.clone()))
+        .collect::<Vec<_>>();
+    custom_models.extend(hub::installed(&storage).await?);
+    let post = args.post_chain();
+    let registry = musicgen_models::MusicGenModelRegistry {
+        storage: storage.clone(),
+        use_split_decoder: args.use_split_decoder,
+        force_download: args.force_download,
+        custom_models,
+        tokenizers: settings.tokenizers.clone(),
+        pins: settings.pinned_versions.clone(),
+        gpu: args.gpu,
+        post: Default::default(),
+        device: SessionDevice::Default,
+        threads: SessionThreads {
+            intra: None,
+            cpus: ort_cpus,
+        },
+        seed: args.seed,
+        batch_size: args.batch_size,
+        draft_model: args.draft_model,
+        draft_steps: args.draft_steps,
+        sessions: args.max_concurrent_jobs,
+    }
+    .with_post_chain(&post);
+
+    match args.command {
+        Some(Command::Models(ModelsCommand::List)) => {
+            let active = match (args.custom_model.is_empty(), settings.model) {
+                (false, _) => registry.custom_models[0].name.clone(),
+                (true, Some(name)) => name,
+                (true, None) => auto_select_model(&storage, args.gpu).await?.0.name(),
+            };
+            for model in registry.list().await? {
+                println!(
+                    "{} {:<12} {:<28} {:>10} max {}s, {}{}{}",
+                    if model.name == active { "*" } else { " " },
+                    model.name,
+                    model.display_name,
+                    if model.installed {
+                        format!("{} MB", model.size_bytes / 1024 / 1024)
+                    } else {
+                        "-".to_string()
+                    },
+                    model.capabilities.max_secs,
+                    if model.capabilities.stereo {
+                        "stereo"
+                    } else {
+                        "mono"
+                    },
+                    if model.capabilities.melody {
+                        ", melody"
+                    } else {
+                        ""
+                    },
+                    if model.installed {
+                        ""
+                    } else {
+                        " (not installed)"
+                    },
+                );
+            }
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Use { model })) => {
+            let Some(entry) = registry.list().await?.into_iter().find(|m| m.name == model) else {
+                return Err(anyhow!("Unknown model {model}"));
+            };
+            settings.model = Some(entry.name);
+            settings.save(&storage).await?;
+            info!("{} will be used from now on", entry.display_name);
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Search { query })) => {
+            for model in hub::search(&query).await? {
+                println!(
+                    "{:<48} {:>8} downloads {:>6} likes   updated {}",
+                    model.id,
+                    model.downloads,
+                    model.likes,
+                    model.last_modified.as_deref().unwrap_or("-"),
+                );
+            }
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Pull { repo, revision })) => {
+            let model = hub::pull(
+                &storage,
+                &repo,
+                &revision,
+                args.force_download,
+                &reserved_names,
+            )
+            .await?;
+            info!(
+                "{repo} is now available as {}, use it with `models use {0}`",
+                model.name
+            );
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Verify { model })) => {
+            let entries = registry.list().await?;
+            let to_verify = match model {
+                Some(name) => match entries.into_iter().find(|e| e.name == name) {
+                    Some(entry) if entry.installed => vec![entry],
+                    Some(_) => return Err(anyhow!("{name} is not installed")),
+                    None => return Err(anyhow!("Unknown model {name}")),
+                },
+                None => entries.into_iter().filter(|e| e.installed).collect(),
+            };
+            onnxruntime_lib::init::init(storage.clone())
+                .await?
+                .commit()?;
+            let mut failed = 0;
+            for entry in to_verify {
+                let result = verify_model(&registry, &entry.name).await;
+                let timing = format!(
+                    "load {:.1}s, 1s of audio in {:.1}s",
+                    result.load_time.as_secs_f32(),
+                    result.generation_time.as_secs_f32()
+                );
+                match result.error {
+                    None => println!("PASS {:<24} {timing}", result.name),
+                    Some(err) => {
+                        failed += 1;
+                        println!("FAIL {:<24} {err}", result.name)
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(anyhow!("{failed} models failed verification"));
+            }
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Cache)) => {
+            let report = model_cache::report(&storage).await?;
+            let mb = |bytes: u64| bytes / 1024 / 1024;
+            for model in report.models.iter() {
+                let last_used = match model.last_used {
+                    Some(ms) => {
+                        time::OffsetDateTime::from_unix_timestamp_nanos(ms as i128 * 1_000_000)?
+                            .date()
+                            .to_string()
+                    }
+                    None => "never".to_string(),
+                };
+                println!(
+                    "  {:<24} {:>8} MB   last used {last_used}",
+                    model.name,
+                    mb(model.size_bytes)
+                );
+            }
+            let unused_bytes = report.unused.iter().map(|(_, size)| size).sum::<u64>();
+            println!(
+                "  {:<24} {:>8} MB   {} files, remove them with `models gc`",
+                "(unused)",
+                mb(unused_bytes),
+                report.unused.len()
+            );
+            println!("Total: {} MB in {:?}", mb(report.total_bytes), root);
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Gc { keep_last, dry_run })) => {
+            let report = model_cache::report(&storage).await?;
+            let keep = settings
+                .model
+                .iter()
+                .chain(settings.pinned_versions.keys())
+                .cloned()
+                .collect::<Vec<_>>();
+            let to_remove = model_cache::plan_gc(&report, keep_last, &keep);
+            if dry_run {
+                for file in to_remove.iter() {
+                    println!("{file}");
+                }
+                return Ok(());
+            }
+            let freed = model_cache::remove(&storage, &to_remove).await?;
+            info!(
+                "Removed {} files, {} MB freed",
+                to_remove.len(),
+                freed / 1024 / 1024
+            );
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Pin { model, hash })) => {
+            let version = registry.version(&model).await?;
+            let hash = hash.unwrap_or(version.hash.clone());
+            if !version.hash.starts_with(&hash) {
+                warn!(
+                    "The installed version of {model} is {}, it will fail to load until it matches {hash}",
+                    version.short_hash()
+                );
+            }
+            info!("{model} pinned to version {hash}");
+            settings.pinned_versions.insert(model, hash);
+            settings.save(&storage).await?;
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Unpin { model })) => {
+            if settings.pinned_versions.remove(&model).is_some() {
+                info!("{model} is no longer pinned");
+                settings.save(&storage).await?;
+            }
+            return Ok(());
+        }
+        Some(Command::Models(ModelsCommand::Tokenizer { model, file })) => {
+            if !registry.list().await?.iter().any(|m| m.name == model) {
+                return Err(anyhow!("Unknown model {model}"));
+            }
+            match file {
+                Some(file) => {
+                    let file = std::fs::canonicalize(&file)
+                        .map_err(|err| anyhow!("Invalid tokenizer file {file:?}: {err}"))?;
+                    // The vocabulary is checked against the text encoder when the model loads.
+                    musicgen_models::load_tokenizer(&file)?;
+                    info!("{model} will use the tokenizer at {file:?} from now on");
+                    settings.tokenizers.insert(model, file);
+                }
+                None => {
+                    info!("{model} will use its default tokenizer from now on");
+                    settings.tokenizers.remove(&model);
+                }
+            }
+            settings.save(&storage).await?;
+            return Ok(());
+        }
+        Some(Command::Hardware) => {
+            onnxruntime_lib::init::init(storage.clone())
+                .await?
+                .commit()?;
+            let providers = gpu::probe_execution_providers();
+            print!(
+                "{}",
+                hardware::report(&hardware::HardwareInfo::probe(), &providers)
+            );
+            return Ok(());
+        }
+        Some(Command::Loop {
+            input,
+            output,
+            min_secs,
+            max_secs,
+            crossfade_ms,
+        }) => {
+            if min_secs > max_secs {
+                return Err(anyhow!("--min-secs must <= --max-secs"));
+            }
+            let (audio, sample_rate) = wav::decode_wav_with_sample_rate(&std::fs::read(&input)?)
+                .map_err(|err| anyhow!(err))?;
+            let config = LoopConfig {
+                min_secs,
+                max_secs,
+                crossfade_secs: crossfade_ms as f32 / 1000.0,
+            };
+            let Some(points) = loop_points::find_loop_points(&audio, sample_rate as usize, &config)
+            else {
+                return Err(anyhow!(
+                    "{input:?} is too short for a loop of at least {min_secs}s"
+                ));
+            };
+            let crossfade = (config.crossfade_secs * sample_rate as f32) as usize;
+            let looped = loop_points::export_loop(&audio, &points, crossfade);
+            let output = output.unwrap_or_else(|| {
+                let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+                input.with_file_name(format!("{stem}-loop.wav"))
+            });
+            std::fs::write(&output, wav::encode_wav(looped, sample_rate)?)?;
+            let secs = |samples: usize| samples as f32 / sample_rate as f32;
+            println!(
+                "Loop from {:.2}s to {:.2}s (similarity {:.2}) written to {output:?}",
+                secs(points.start),
+                secs(points.end),
+                points.score
+            );
+            return Ok(());
+        }
+        Some(Command::Slice {
+            input,
+            name,
+            output,
+            max_hits,
+            beats_per_bar,
+        }) => {
+            if beats_per_bar == 0 {
+                return Err(anyhow!("--beats-per-bar must > 0"));
+            }
+            let (audio, sample_rate) = wav::decode_wav_with_sample_rate(&std::fs::read(&input)?)
+                .map_err(|err| anyhow!("Invalid render {input:?}: {err}"))?;
+            let sample_rate = sample_rate as usize;
+            let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+            let name = name.unwrap_or_else(|| stem.to_string());
+            let output = output.unwrap_or_else(|| input.with_file_name(format!("{stem}-samples")));
+            let config = SliceConfig {
+                max_hits,
+                beats_per_bar,
+                ..Default::default()
+            };
+            let (loops, bpm) = slicer::bar_loops(&audio, sample_rate, &config);
+            let hits = slicer::hits(&audio, sample_rate, &config);
+            let mut samples = vec![];
+            for dir in ["hits", "loops"] {
+                std::fs::create_dir_all(output.join(dir))?;
+            }
+            let numbered = hits.iter().enumerate().chain(loops.iter().enumerate());
+            for (i, slice) in numbered {
+                let (kind, file) = match slice.kind {
+                    SliceKind::Hit => ("hit", format!("hits/{name}-hit-{:02}.wav", i + 1)),
+                    SliceKind::Loop => (
+                        "loop",
+                        format!("loops/{name}-loop-{:02}-{}bpm.wav", i + 1, bpm.round()),
+                    ),
+                };
+                let audio = slicer::extract(&audio, slice, sample_rate);
+                std::fs::write(
+                    output.join(&file),
+                    wav::encode_wav(audio, sample_rate as u32)?,
+                )?;
+                samples.push(serde_json::json!({
+                    "file": file,
+                    "kind": kind,
+                    "start_secs": slice.start as f32 / sample_rate as f32,
+                    "secs": slice.len as f32 / sample_rate as f32,
+                }));
+            }
+            let pack = serde_json::json!({
+                "name": name,
+                "bpm": (!loops.is_empty()).then_some(bpm.round()),
+                "sample_rate": sample_rate,
+                "samples": samples,
+            });
+            std::fs::write(output.join("pack.json"), serde_json::to_vec_pretty(&pack)?)?;
+            println!(
+                "{} hits and {} loops written to {}",
+                hits.len(),
+                loops.len(),
+                output.display()
+            );
+            return Ok(());
+        }
+        Some(Command::PreviewJoins {
+            segments,
+            output,
+            context_secs,
+            crossfade_secs,
+            overlap_secs,
+            join,
+            attack_secs,
+        }) => {
+            let mut sample_rate = None;
+            let mut audios = vec![];
+            for path in &segments {
+                let (audio, rate) = wav::decode_wav_with_sample_rate(&std::fs::read(path)?)
+                    .map_err(|err| anyhow!("Invalid segment {path:?}: {err}"))?;
+                if *sample_rate.get_or_insert(rate) != rate {
+                    return Err(anyhow!("{path:?} has a different sample rate"));
+                }
+                audios.push(audio);
+            }
+            let sample_rate = sample_rate.unwrap_or(SAMPLING_RATE as u32);
+            let config = ExtendedGenerationConfig {
+                crossfade_duration: crossfade_secs,
+                overlap_duration: overlap_secs,
+                join: match join {
+                    Join::Crossfade => JoinStyle::Crossfade,
+                    Join::RideOut => JoinStyle::TailRideOut {
+                        attack: attack_secs,
+                    },
+                    Join::Multiband => JoinStyle::Multiband(Default::default()),
+                },
+                ..Default::default()
+            };
+            let generator = ExtendedAudioGenerator::new(config, sample_rate as usize)
+                .map_err(|err| anyhow!(err))?;
+            let mut preview = vec![];
+            for join in generator.preview_joins(&audios, context_secs) {
+                println!(
+                    "Join {} at {:.2}s of the piece starts at {:.2}s of the preview",
+                    join.boundary + 1,
+                    join.start as f32 / sample_rate as f32,
+                    preview.len() as f32 / sample_rate as f32
+                );
+                preview.extend(join.audio);
+                preview.extend(vec![0.0; sample_rate as usize]);
+            }
+            std::fs::write(&output, wav::encode_wav(preview, sample_rate)?)?;
+            println!("Previews written to {output:?}");
+            return Ok(());
+        }
+        Some(Command::Diff {
+            a,
+            b,
+            max_delta,
+            max_spectral_distance,
+            max_loudness_db,
+        }) => {
+            let read = |path: &PathBuf| {
+                wav::decode_wav_with_sample_rate(&std::fs::read(path)?)
+                    .map_err(|err| anyhow!("Invalid render {path:?}: {err}"))
+            };
+            let ((audio_a, rate_a), (audio_b, rate_b)) = (read(&a)?, read(&b)?);
+            if rate_a != rate_b {
+                return Err(anyhow!("{a:?} is at {rate_a}Hz but {b:?} is at {rate_b}Hz"));
+            }
+            let tolerance = DiffTolerance {
+                max_delta,
+                spectral_distance: max_spectral_distance,
+                loudness_db: max_loudness_db,
+            };
+            let diff = diff::diff(&audio_a, &audio_b, rate_a as usize);
+            print!("{}", diff.report(&tolerance));
+            if let Some(first) = diff.diverging(&tolerance).next() {
+                return Err(anyhow!("The renders diverge from {}s", first.second));
+            }
+            if !diff.matches(&tolerance) {
+                return Err(anyhow!("The renders have different lengths"));
+            }
+            println!("The renders match");
+            return Ok(());
+        }
+        Some(Command::Mix {
+            inputs,
+            bpm,
+            crossfade_beats,
+            transition,
+            output,
+        }) => {
+            if bpm.is_some_and(|bpm| bpm <= 0.0) {
+                return Err(anyhow!("--bpm must > 0"));
+            }
+            let mut tracks = vec![];
+            let mut sample_rate = None;
+            for input in &inputs {
+                let (audio, rate) = wav::decode_wav_with_sample_rate(&std::fs::read(input)?)
+                    .map_err(|err| anyhow!("Invalid track {input:?}: {err}"))?;
+                // Tracks are brought to the sample rate of the first one.
+                let sample_rate = *sample_rate.get_or_insert(rate);
+                tracks.push(resample(&audio, rate, sample_rate));
+            }
+            let sample_rate = sample_rate.expect("Programming error");
+            let config = MixConfig {
+                bpm,
+                crossfade_beats,
+                transition: transition.map(|transition| match transition {
+                    TransitionEffect::Riser => TransitionStyle::Riser,
+                    TransitionEffect::NoiseCrescendo => TransitionStyle::NoiseCrescendo,
+                    TransitionEffect::CymbalSwell => TransitionStyle::CymbalSwell,
+                }),
+            };
+            let (mixed, placed) =
+                dj_mix(&tracks, &config, sample_rate as usize).map_err(|err| anyhow!(err))?;
+            for (input, track) in inputs.iter().zip(placed) {
+                let bpm = track
+                    .bpm
+                    .map_or("unknown".to_string(), |bpm| format!("{bpm:.1}"));
+                info!(
+                    "{input:?} at {:.1}s, {bpm} BPM stretched by {:.3}",
+                    track.start as f32 / sample_rate as f32,
+                    track.ratio
+                );
+            }
+            std::fs::write(&output, wav::encode_wav(mixed, sample_rate)?)?;
+            println!("Mix written to {output:?}");
+            return Ok(());
+        }
+        Some(Command::DetectWatermark { input }) => {
+            let audio = wav::decode_wav(&std::fs::read(&input)?)
+                .map_err(|err| anyhow!("Invalid render {input:?}: {err}"))?;
+            let Some(payload) = watermark::detect(&audio) else {
+                return Err(anyhow!("No watermark found in {input:?}"));
+            };
+            let label = match payload.ai_generated {
+                true => "AI-generated",
+                false => "Not labelled as AI-generated",
+            };
+            match payload.job_id {
+                Some(id) => println!("{label}, by job {}", Uuid::from_u128(id)),
+                None => println!("{label}"),
+            }
+            return Ok(());
+        }
+        Some(Command::Transcribe {
+            input,
+            output,
+            model,
+        }) => {
+            let (audio, sample_rate) = wav::decode_wav_with_sample_rate(&std::fs::read(&input)?)
+                .map_err(|err| anyhow!("Invalid audio {input:?}: {err}"))?;
+            let transcriber = Transcriber::new(model.as_deref())?;
+            let notes = transcriber.transcribe(&audio, sample_rate)?;
+            let output = output.unwrap_or_else(|| input.with_extension("mid"));
+            std::fs::write(&output, midi::write_midi(&notes))?;
+            println!("{} notes written to {}", notes.len(), output.display());
+            return Ok(());
+        }
+        Some(Command::Aiff {
+            input,
+            bits,
+            output,
+        }) => {
+            if bits != 16 && bits != 24 {
+                return Err(anyhow!("--bits must be 16 or 24"));
+            }
+            let bytes = std::fs::read(&input)?;
+            let (audio, sample_rate) = wav::decode_wav_with_sample_rate(&bytes)
+                .map_err(|err| anyhow!("Invalid render {input:?}: {err}"))?;
+            let output = output.unwrap_or_else(|| input.with_extension("aiff"));
+            std::fs::write(&output, aiff_from_wav(&bytes, &audio, sample_rate, bits)?)?;
+            println!("{bits} bit AIFF written to {}", output.display());
+            return Ok(());
+        }
+        Some(Command::Upmix {
+            input,
+            lfe_hz,
+            output,
+        }) => {
+            if lfe_hz <= 0.0 {
+                return Err(anyhow!("--lfe-hz must > 0"));
+            }
+            let config = UpmixConfig {
+                lfe_hz,
+                ..Default::default()
+            };
+            let upmixed = upmix_wav(&std::fs::read(&input)?, &config)
+                .map_err(|err| anyhow!("Cannot upmix {input:?}: {err}"))?;
+            let output = output.unwrap_or_else(|| {
+                let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+                input.with_file_name(format!("{stem}-5.1.wav"))
+            });
+            std::fs::write(&output, upmixed)?;
+            println!("5.1 upmix written to {}", output.display());
+            return Ok(());
+        }
+        Some(Command::Package {
+            input,
+            formats,
+            manifest,
+            cover,
+            output,
+        }) => {
+            let mut bytes = std::fs::read(&input)?;
+            let (channels, sample_rate) = wav::decode_wav_channels(&bytes)
+                .map_err(|err| anyhow!("Invalid render {input:?}: {err}"))?;
+            // Measured on the mono fold down, like the renders are generated.
+            let mono = (0..channels[0].len())
+                .map(|i| channels.iter().map(|c| c[i]).sum::<f32>() / channels.len() as f32)
+                .collect::<Vec<_>>();
+            let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+            let mut files: Vec<(String, Vec<u8>)> = vec![];
+            let sidecar = input.with_extension("json");
+            let manifest = match manifest.or_else(|| sidecar.exists().then_some(sidecar)) {
+                Some(path) => Some(std::fs::read(path)?),
+                None => None,
+            };
+            let embedded = wav::id3_tag(&bytes).and_then(id3::picture);
+            let cover = match (cover, embedded) {
+                (Some(path), _) => Some(
+                    id3::Picture::sniff(std::fs::read(&path)?)
+                        .ok_or_else(|| anyhow!("--cover must be a PNG, JPEG, GIF or WebP image"))?,
+                ),
+                (None, Some(embedded)) => Some(embedded),
+                (None, None) => {
+                    let manifest = manifest
+                        .as_ref()
+                        .and_then(|m| serde_json::from_slice::<RenderManifest>(m).ok());
+                    match (&artwork, manifest) {
+                        (Some(artwork), Some(manifest)) => {
+                            let request = ArtworkRequest {
+                                prompt: manifest.prompt,
+                                secs: mono.len() as f32 / sample_rate as f32,
+                                model: manifest.model.map(|m| format!("{} {}", m.name, m.revision)),
+                                license: wav::info_tags(&bytes)
+                                    .into_iter()
+                                    .find(|(id, _)| id == b"ICOP")
+                                    .map(|(_, license)| license),
+                            };
+                            Some(artwork.artwork(&request).await?)
+                        }
+                        _ => None,
+                    }
+                }
+            };
+            // The audio files carry the cover art too, unless they already have tags.
+            if let Some(cover) = cover.as_ref().filter(|_| wav::id3_tag(&bytes).is_none()) {
+                let text = id3::frames_from_info(&wav::info_tags(&bytes));
+                wav::append_chunk(
+                    &mut bytes,
+                    &wav::id3_chunk(&id3::id3_tag(&text, Some(cover))),
+                );
+            }
+            for format in formats {
+                match format {
+                    DeliveryFormat::Wav => files.push((format!("audio/{stem}.wav"), bytes.clone())),
+                    DeliveryFormat::Aiff => {
+                        let aiff = aiff_from_wav(&bytes, &mono, sample_rate, 24)?;
+                        files.push((format!("audio/{stem}.aiff"), aiff));
+                    }
+                    DeliveryFormat::Surround => {
+                        let upmixed = upmix_wav(&bytes, &UpmixConfig::default())?;
+                        files.push((format!("audio/{stem}-5.1.wav"), upmixed));
+                    }
+                }
+            }
+            match manifest {
+                Some(manifest) => files.push(("manifest.json".into(), manifest)),
+                None => {
+                    let provenance = wav::info_tags(&bytes)
+                        .into_iter()
+                        .find(|(id, _)| id == b"ICMT")
+                        .filter(|(_, value)| {
+                            serde_json::from_str::<serde_json::Value>(value).is_ok()
+                        });
+                    if let Some((_, provenance)) = provenance {
+                        files.push(("provenance.json".into(), provenance.into_bytes()));
+                    }
+                }
+            }
+            let loudness = LoudnessReport::measure(&mono, sample_rate as usize);
+            let report = serde_json::json!({
+                "integrated_lufs": loudness.integrated_lufs,
+                "max_short_term_lufs": loudness.max_short_term_lufs,
+                "sample_peak_dbfs": loudness.sample_peak_dbfs,
+                "rms_dbfs": loudness.rms_dbfs,
+                "secs": loudness.secs,
+                "sample_rate": sample_rate,
+                "channels": channels.len(),
+            });
+            files.push(("loudness.json".into(), serde_json::to_vec_pretty(&report)?));
+            let columns = spectrogram(&mono, sample_rate as usize, &SpectrogramConfig::default());
+            files.push(("spectrogram.bmp".into(), encode_bmp(&columns)));
+            if let Some(cover) = cover {
+                files.push((format!("cover.{}", cover.extension()), cover.data));
+            }
+
+            let output = output.unwrap_or_else(|| input.with_extension("zip"));
+            let mut zip = zip::ZipWriter::new(std::fs::File::create(&output)?);
+            for (name, data) in &files {
+                zip.start_file(name.as_str(), zip::write::SimpleFileOptions::default())?;
+                std::io::Write::write_all(&mut zip, data)?;
+            }
+            zip.finish()?;
+            println!("{} files packaged in {}", files.len(), output.display());
+            return Ok(());
+        }
+        Some(Command::Replay {
+            manifest: path,
+            output,
+        }) => {
+            let manifest = RenderManifest::read(&path)?;
+            let (model, recipe) = manifest.check_replay()?;
+            if recipe.seed.is_none() {
+                warn!(
+                    "Render {} was not seeded, its replay will not sound the same",
+                    manifest.id
+                );
+            }
+            // Only the model and the tokenizer the render was made with are loaded.
+            let registry = musicgen_models::MusicGenModelRegistry {
+                tokenizers: recipe
+                    .tokenizer
+                    .iter()
+                    .map(|file| (model.name.clone(), PathBuf::from(file)))
+                    .collect(),
+                pins: HashMap::from([(model.name.clone(), model.hash.clone())]),
+                seed: recipe.seed,
+                ..registry.clone()
+            }
+            .with_post_chain(&recipe.post);
+            let installed = registry
+                .list()
+                .await?
+                .into_iter()
+                .any(|entry| entry.name == model.name && entry.installed);
+            if !installed {
+                return Err(anyhow!(
+                    "Render {} was made with {}, which is not installed",
+                    manifest.id,
+                    model.name
+                ));
+            }
+            let version = registry.version(&model.name).await?;
+            if version.hash != model.hash {
+                return Err(anyhow!(
+                    "Render {} was made with {} ({}), but the installed one is {}",
+                    manifest.id,
+                    model.name,
+                    model.short_hash(),
+                    version.short_hash()
+                ));
+            }
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&model.name).await?;
+            if processor.segment_prompts(&manifest.prompt, manifest.secs) != recipe.segment_prompts
+            {
+                return Err(anyhow!(
+                    "The segments of render {} were prompted differently than this version of MusicGPT prompts them",
+                    manifest.id
+                ));
+            }
+
+            info!(
+                "Replaying {}s of \"{}\" with {} ({})",
+                manifest.secs,
+                manifest.prompt,
+                model.name,
+                model.short_hash()
+            );
+            let sample_rate = registry
+                .custom_models
+                .iter()
+                .find(|custom| custom.name == model.name)
+                .map_or(SAMPLING_RATE, |custom| custom.capabilities.sample_rate);
+            let output = output.unwrap_or_else(|| {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                path.with_file_name(format!("{stem}-replay.wav"))
+            });
+            // Written as it is stitched, without holding the whole render in memory.
+            let mut sink = wav_file_sink(&output, sample_rate as u32, manifest.secs * sample_rate)
+                .map_err(|err| anyhow!(err))?;
+            if let Some(payload) = watermark {
+                sink = Box::new(WatermarkSink::new(sink, payload));
+            }
+            let (prompt, secs) = (manifest.prompt.clone(), manifest.secs);
+            let version = processor.model_version();
+            tokio::task::spawn_blocking(move || {
+                processor.process_streaming(&prompt, secs, Box::new(|_, _| false), &mut sink)
+            })
+            .await??;
+            let provenance =
+                Provenance::generated_now(version.as_ref(), &manifest.prompt, args.license);
+            wav::append_chunk_to_file(&output, &provenance.wav_chunks())
+                .map_err(|err| anyhow!(err))?;
+            println!("Render {} replayed to {output:?}", manifest.id);
+            return Ok(());
+        }
+        Some(Command::Inpaint {
+            input,
+            from,
+            to,
+            prompt,
+            crossfade,
+            output,
+        }) => {
+            if crossfade < 0.0 {
+                return Err(anyhow!("--crossfade must >= 0"));
+            }
+            let (audio, sample_rate) = wav::decode_wav_with_sample_rate(&std::fs::read(&input)?)
+                .map_err(|err| anyhow!("Invalid render {input:?}: {err}"))?;
+            let at = |secs: f32| (secs * sample_rate as f32) as usize;
+            let region = InpaintRegion {
+                start: at(from),
+                end: at(to),
+                crossfade: at(crossfade),
+            };
+            region.validate(audio.len()).map_err(|err| anyhow!(err))?;
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&name).await?;
+            let model_rate = registry
+                .custom_models
+                .iter()
+                .find(|custom| custom.name == name)
+                .map_or(SAMPLING_RATE, |custom| custom.capabilities.sample_rate);
+
+            let secs = region
+                .replacement_len(audio.len(), sample_rate as usize)
+                .div_ceil(sample_rate as usize);
+            info!("Generating {secs}s of \"{prompt}\" for replacing {from}s to {to}s");
+            let version = processor.model_version();
+            let replacement = {
+                let prompt = prompt.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut sink = MemorySink::new();
+                    processor
+                        .process_streaming(&prompt, secs, Box::new(|_, _| false), &mut sink)
+                        .map(|_| sink.into_inner())
+                })
+                .await??
+            };
+            let replacement = resample(&replacement, model_rate as u32, sample_rate);
+            let mut inpainted = inpaint(&audio, &region, &replacement, sample_rate as usize)
+                .map_err(|err| anyhow!(err))?;
+            info!(
+                "Replacement shifted by {:.2}s and turned by {:+.1}dB",
+                inpainted.offset as f32 / sample_rate as f32,
+                inpainted.gain_db
+            );
+            if let Some(payload) = watermark {
+                Watermarker::new(payload).apply(&mut inpainted.audio);
+            }
+            let output = output.unwrap_or_else(|| {
+                let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+                input.with_file_name(format!("{stem}-inpainted.wav"))
+            });
+            let provenance = Provenance::generated_now(version.as_ref(), &prompt, args.license);
+            let mut bytes = wav::encode_wav(inpainted.audio, sample_rate)?;
+            wav::append_chunk(&mut bytes, &provenance.wav_chunks());
+            std::fs::write(&output, bytes)?;
+            println!("Inpainted render written to {output:?}");
+            return Ok(());
+        }
+        Some(Command::Intro {
+            input,
+            secs,
+            prompt,
+            crossfade,
+            output,
+        }) => {
+            if secs <= 0.0 {
+                return Err(anyhow!("--secs must > 0"));
+            }
+            if crossfade < 0.0 {
+                return Err(anyhow!("--crossfade must >= 0"));
+            }
+            let (audio, sample_rate) = wav::decode_wav_with_sample_rate(&std::fs::read(&input)?)
+                .map_err(|err| anyhow!("Invalid track {input:?}: {err}"))?;
+            let at = |secs: f32| (secs * sample_rate as f32) as usize;
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&name).await?;
+            let model_rate = registry
+                .custom_models
+                .iter()
+                .find(|custom| custom.name == name)
+                .map_or(SAMPLING_RATE, |custom| custom.capabilities.sample_rate);
+
+            let generated_secs = intro_len(at(secs), at(crossfade), sample_rate as usize)
+                .div_ceil(sample_rate as usize);
+            info!("Generating {generated_secs}s of \"{prompt}\" for a {secs}s intro");
+            let version = processor.model_version();
+            let intro = {
+                let prompt = prompt.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut sink = MemorySink::new();
+                    processor
+                        .process_streaming(
+                            &prompt,
+                            generated_secs,
+                            Box::new(|_, _| false),
+                            &mut sink,
+                        )
+                        .map(|_| sink.into_inner())
+                })
+                .await??
+            };
+            let intro = resample(&intro, model_rate as u32, sample_rate);
+            let mut prepended = prepend_intro(
+                &audio,
+                &intro,
+                at(secs),
+                at(crossfade),
+                sample_rate as usize,
+            )
+            .map_err(|err| anyhow!(err))?;
+            info!(
+                "Intro taken from {:.2}s of the generated audio and turned by {:+.1}dB",
+                prepended.offset as f32 / sample_rate as f32,
+                prepended.gain_db
+            );
+            if let Some(payload) = watermark {
+                Watermarker::new(payload).apply(&mut prepended.audio);
+            }
+            let output = output.unwrap_or_else(|| {
+                let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+                input.with_file_name(format!("{stem}-intro.wav"))
+            });
+            let provenance = Provenance::generated_now(version.as_ref(), &prompt, args.license);
+            let mut bytes = wav::encode_wav(prepended.audio, sample_rate)?;
+            wav::append_chunk(&mut bytes, &provenance.wav_chunks());
+            std::fs::write(&output, bytes)?;
+            println!("Track with its intro written to {output:?}");
+            return Ok(());
+        }
+        Some(Command::Splice {
+            clip,
+            at,
+            secs,
+            prompt,
+            crossfade,
+            output,
+        }) => {
+            if crossfade < 0.0 {
+                return Err(anyhow!("--crossfade must >= 0"));
+            }
+            let (clip, sample_rate) = wav::decode_wav_with_sample_rate(&std::fs::read(&clip)?)
+                .map_err(|err| anyhow!("Invalid clip {clip:?}: {err}"))?;
+            let samples = |secs: f32| (secs * sample_rate as f32) as usize;
+            let clip_secs = clip.len() as f32 / sample_rate as f32;
+            if secs < at + clip_secs {
+                return Err(anyhow!(
+                    "--secs must >= --at plus the {clip_secs:.2}s of the clip"
+                ));
+            }
+            let before = samples(at);
+            let after = samples(secs).saturating_sub(before + clip.len());
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&name).await?;
+            let model_rate = registry
+                .custom_models
+                .iter()
+                .find(|custom| custom.name == name)
+                .map_or(SAMPLING_RATE, |custom| custom.capabilities.sample_rate);
+            let version = processor.model_version();
+
+            // Each side of the clip is generated on its own, so that it can be aligned with it.
+            let generate = |len: usize| {
+                let processor = processor.clone();
+                let prompt = prompt.clone();
+                let generated_secs = intro_len(len, samples(crossfade), sample_rate as usize)
+                    .div_ceil(sample_rate as usize);
+                info!("Generating {generated_secs}s of \"{prompt}\" around the clip");
+                async move {
+                    let generated = tokio::task::spawn_blocking(move || {
+                        let mut sink = MemorySink::new();
+                        processor
+                            .process_streaming(
+                                &prompt,
+                                generated_secs,
+                                Box::new(|_, _| false),
+                                &mut sink,
+                            )
+                            .map(|_| sink.into_inner())
+                    })
+                    .await??;
+                    anyhow::Ok(resample(&generated, model_rate as u32, sample_rate))
+                }
+            };
+            let mut audio = clip;
+            if before > 0 {
+                let generated = generate(before).await?;
+                let prepended = prepend_intro(
+                    &audio,
+                    &generated,
+                    before,
+                    samples(crossfade),
+                    sample_rate as usize,
+                )
+                .map_err(|err| anyhow!(err))?;
+                info!(
+                    "Audio before the clip taken from {:.2}s of the generated audio and turned by {:+.1}dB",
+                    prepended.offset as f32 / sample_rate as f32,
+                    prepended.gain_db
+                );
+                audio = prepended.audio;
+            }
+            if after > 0 {
+                let generated = generate(after).await?;
+                let appended = append_outro(
+                    &audio,
+                    &generated,
+                    after,
+                    samples(crossfade),
+                    sample_rate as usize,
+                )
+                .map_err(|err| anyhow!(err))?;
+                info!(
+                    "Audio after the clip taken from {:.2}s of the generated audio and turned by {:+.1}dB",
+                    appended.offset as f32 / sample_rate as f32,
+                    appended.gain_db
+                );
+                audio = appended.audio;
+            }
+            if let Some(payload) = watermark {
+                Watermarker::new(payload).apply(&mut audio);
+            }
+            let provenance = Provenance::generated_now(version.as_ref(), &prompt, args.license);
+            let mut bytes = wav::encode_wav(audio, sample_rate)?;
+            wav::append_chunk(&mut bytes, &provenance.wav_chunks());
+            std::fs::write(&output, bytes)?;
+            println!("Track with the clip spliced in written to {output:?}");
+            return Ok(());
+        }
+        Some(Command::Remix {
+            input,
+            prompt,
+            crossfade,
+            output,
+        }) => {
+            if crossfade < 0.0 {
+                return Err(anyhow!("--crossfade must >= 0"));
+            }
+            let (audio, sample_rate) = wav::decode_wav_with_sample_rate(&std::fs::read(&input)?)
+                .map_err(|err| anyhow!("Invalid track {input:?}: {err}"))?;
+            let crossfade = (crossfade * sample_rate as f32) as usize;
+            let sections = sections(&audio, sample_rate as usize);
+            let prompts = section_prompts(&prompt, &sections);
+            for (section, prompt) in sections.iter().zip(&prompts) {
+                info!(
+                    "Section from {:.1}s to {:.1}s: \"{prompt}\"",
+                    section.start as f32 / sample_rate as f32,
+                    section.end as f32 / sample_rate as f32
+                );
+            }
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&name).await?;
+            let model_rate = registry
+                .custom_models
+                .iter()
+                .find(|custom| custom.name == name)
+                .map_or(SAMPLING_RATE, |custom| custom.capabilities.sample_rate);
+
+            let version = processor.model_version();
+            let mut generated = vec![];
+            for (i, (section, prompt)) in sections.iter().zip(prompts).enumerate() {
+                let secs = section_len(section, crossfade, sample_rate as usize)
+                    .div_ceil(sample_rate as usize);
+                info!("Generating section {} of {}", i + 1, sections.len());
+                let processor = processor.clone();
+                let audio = tokio::task::spawn_blocking(move || {
+                    let mut sink = MemorySink::new();
+                    processor
+                        .process_streaming(&prompt, secs, Box::new(|_, _| false), &mut sink)
+                        .map(|_| sink.into_inner())
+                })
+                .await??;
+                generated.push(resample(&audio, model_rate as u32, sample_rate));
+            }
+            let mut remixed = remix(
+                &audio,
+                &sections,
+                &generated,
+                crossfade,
+                sample_rate as usize,
+            )
+            .map_err(|err| anyhow!(err))?;
+            if let Some(payload) = watermark {
+                Watermarker::new(payload).apply(&mut remixed);
+            }
+            let output = output.unwrap_or_else(|| {
+                let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+                input.with_file_name(format!("{stem}-remix.wav"))
+            });
+            let provenance = Provenance::generated_now(version.as_ref(), &prompt, args.license);
+            let mut bytes = wav::encode_wav(remixed, sample_rate)?;
+            wav::append_chunk(&mut bytes, &provenance.wav_chunks());
+            std::fs::write(&output, bytes)?;
+            println!("Remix written to {output:?}");
+            return Ok(());
+        }
+        Some(Command::Variations {
+            prompt,
+            count,
+            secs,
+            output,
+        }) => {
+            if count < 1 {
+                return Err(anyhow!("--count must > 0"));
+            }
+            if !(1..=30).contains(&secs) {
+                return Err(anyhow!("--secs must be between 1 and 30"));
+            }
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&name).await?;
+
+            // Any variation can be generated alone again with --seed.
+            let first = args.seed.unwrap_or_else(rand::random);
+            let seeds = (0..count as u64)
+                .map(|i| first.wrapping_add(i))
+                .collect::<Vec<_>>();
+            info!(
+                "Generating {count} variations of {secs}s of \"{prompt}\", {} at a time",
+                args.batch_size
+            );
+            let provenance = Provenance::generated_now(
+                processor.model_version().as_ref(),
+                &prompt,
+                args.license,
+            );
+            let variations = {
+                let seeds = seeds.clone();
+                tokio::task::spawn_blocking(move || {
+                    processor.process_variations(&prompt, secs, &seeds, Box::new(|_, _| false))
+                })
+                .await??
+            };
+            let sample_rate = registry
+                .custom_models
+                .iter()
+                .find(|custom| custom.name == name)
+                .map_or(SAMPLING_RATE, |custom| custom.capabilities.sample_rate);
+            std::fs::create_dir_all(&output)?;
+            for (seed, mut audio) in seeds.iter().zip(variations) {
+                if let Some(payload) = watermark {
+                    Watermarker::new(payload).apply(&mut audio);
+                }
+                let path = output.join(format!("{seed}.wav"));
+                let mut bytes = wav::encode_wav(audio, sample_rate as u32)?;
+                wav::append_chunk(&mut bytes, &provenance.wav_chunks());
+                std::fs::write(&path, bytes)?;
+                println!("Variation with seed {seed} written to {path:?}");
+            }
+            return Ok(());
+        }
+        Some(Command::Layers {
+            prompt,
+            secs,
+            min_loop_secs,
+            output,
+        }) => {
+            if !(1..=30 - SLACK_SECS).contains(&secs) {
+                return Err(anyhow!("--secs must be between 1 and {}", 30 - SLACK_SECS));
+            }
+            if min_loop_secs <= 0.0 {
+                return Err(anyhow!("--min-loop-secs must > 0"));
+            }
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&name).await?;
+            let sample_rate = registry
+                .custom_models
+                .iter()
+                .find(|custom| custom.name == name)
+                .map_or(SAMPLING_RATE, |custom| custom.capabilities.sample_rate);
+
+            // The same seed for every layer keeps them as close to each other as the prompts
+            // allow.
+            let seed = args.seed.unwrap_or_else(rand::random);
+            let prompts = layer_prompts(&prompt);
+            let provenance = Provenance::generated_now(
+                processor.model_version().as_ref(),
+                &prompt,
+                args.license,
+            );
+            let mut layers = vec![];
+            for layer_prompt in &prompts {
+                info!("Generating {secs}s of \"{layer_prompt}\" with seed {seed}");
+                let processor = processor.clone();
+                let layer_prompt = layer_prompt.clone();
+                let layer = tokio::task::spawn_blocking(move || {
+                    let mut sink = MemorySink::new();
+                    processor
+                        .process_seeded(
+                            &layer_prompt,
+                            secs + SLACK_SECS,
+                            seed,
+                            Box::new(|_, _| false),
+                            &mut sink,
+                        )
+                        .map(|_| sink.into_inner())
+                })
+                .await??;
+                layers.push(layer);
+            }
+            let config = LoopConfig {
+                min_secs: min_loop_secs,
+                max_secs: secs as f32,
+                ..Default::default()
+            };
+            let aligned = align_layers(&layers, secs * sample_rate, &config, sample_rate)
+                .map_err(|err| anyhow!(err))?;
+            if aligned.loop_points.is_none() {
+                warn!("The layers are too short for a loop of at least {min_loop_secs}s");
+            }
+
+            std::fs::create_dir_all(&output)?;
+            let mut files = vec![];
+            for (((intensity, _), layer_prompt), mut audio) in
+                INTENSITIES.iter().zip(&prompts).zip(aligned.layers)
+            {
+                if let Some(payload) = watermark {
+                    Watermarker::new(payload).apply(&mut audio);
+                }
+                let file = format!("{intensity}.wav");
+                let mut bytes = wav::encode_wav(audio, sample_rate as u32)?;
+                if let Some(points) = &aligned.loop_points {
+                    wav::append_chunk(&mut bytes, &loop_points::smpl_chunk(points, sample_rate));
+                }
+                wav::append_chunk(&mut bytes, &provenance.wav_chunks());
+                std::fs::write(output.join(&file), bytes)?;
+                files.push(serde_json::json!({ "file": file, "prompt": layer_prompt }));
+            }
+            let secs_at = |samples: usize| samples as f32 / sample_rate as f32;
+            let description = serde_json::json!({
+                "prompt": prompt,
+                "seed": seed,
+                "sample_rate": sample_rate,
+                "bpm": aligned.bpm,
+                "loop": aligned.loop_points.as_ref().map(|points| serde_json::json!({
+                    "start_sample": points.start,
+                    "end_sample": points.end,
+                    "start_secs": secs_at(points.start),
+                    "end_secs": secs_at(points.end),
+                })),
+                "layers": files,
+            });
+            let path = output.join("layers.json");
+            std::fs::write(&path, serde_json::to_vec_pretty(&description)?)?;
+            match &aligned.loop_points {
+                Some(points) => println!(
+                    "Layers looping from {:.2}s to {:.2}s written to {output:?}",
+                    secs_at(points.start),
+                    secs_at(points.end)
+                ),
+                None => println!("Layers written to {output:?}"),
+            }
+            return Ok(());
+        }
+        Some(Command::Run { pipeline }) => {
+            let graph = JobGraph::read(&pipeline)?;
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            if args.gpu {
+                let (_, provider) = gpu::init_gpu()?;
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+            }
+            ort_builder.commit()?;
+            let processor = registry.load(&name).await?;
+
+            let generator = ProcessorGenerator::new(storage.clone(), processor, watermark);
+            let run = run_graph(&storage, &generator, GraphRun::new(graph)).await?;
+            let mut failed = 0;
+            for step in &run.steps {
+                match (&step.state, &step.artifact, &step.error) {
+                    (StepState::Completed, Some(artifact), _) => {
+                        println!("{}: {:?}", step.name, root.join(artifact))
+                    }
+                    (StepState::Failed, _, Some(error)) => {
+                        failed += 1;
+                        println!("{}: failed, {error}", step.name)
+                    }
+                    (state, _, _) => println!("{}: {state:?}", step.name),
+                }
+            }
+            if failed > 0 {
+                return Err(anyhow!("{failed} steps of the pipeline failed"));
+            }
+            return Ok(());
+        }
+        Some(Command::Bench { threads, secs }) => {
+            if threads.contains(&0) {
+                return Err(anyhow!("--threads must > 0"));
+            }
+            if secs.contains(&0) {
+                return Err(anyhow!("--secs must > 0"));
+            }
+            let (name, _, selection_reason) = select_model(
+                args.model,
+                &registry,
+                !args.custom_model.is_empty(),
+                settings.model,
+                args.gpu,
+            )
+            .await?;
+            info!("{selection_reason}");
+
+            let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
+            let mut setups = match threads.is_empty() {
+                true => vec![("Cpu", SessionDevice::Cpu, None)],
+                false => threads
+                    .into_iter()
+                    .map(|threads| ("Cpu", SessionDevice::Cpu, Some(threads)))
+                    .collect(),
+            };
+            if let Ok((gpu_device, provider)) = gpu::init_gpu() {
+                ort_builder = ort_builder.with_execution_providers(&[provider]);
+                setups.push((gpu_device, SessionDevice::Default, None));
+            }
+            ort_builder.commit()?;
+
+            let mut results = vec![];
+            for (provider, device, intra_threads) in setups {
+                let registry = musicgen_models::MusicGenModelRegistry {
+                    gpu: device == SessionDevice::Default,
+                    device,
+                    threads: SessionThreads {
+                        intra: intra_threads,
+                        ..registry.threads.clone()
+                    },
+                    ..registry.clone()
+                };
+                let intra_threads = registry.threads.intra_threads();
+                let processor = registry.load(&name).await?;
+                for &secs in &secs {
+                    info!("Generating {secs}s on {provider}...");
+                    let processor = processor.clone();
+                    let measured =
+                        tokio::task::spawn_blocking(move || bench::run(&processor, secs)).await??;
+                    results.push(bench::result(
+                        &name,
+                        provider,
+                        intra_threads,
+                        secs,
+                        measured,
+                    ));
+                }
+            }
+            let history = bench::load_results(&storage).await?;
+            print!("{}", bench::report(&results, &history));
+            bench::save_results(&storage, &results).await?;
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let (name, display_name, selection_reason) = select_model(
+        args.model,
+        &registry,
+        !args.custom_model.is_empty(),
+        settings.model,
+        args.gpu,
+    )
+    .await?;
+    info!("{selection_reason}");
 
     let mut ort_builder = onnxruntime_lib::init::init(storage.clone()).await?;
     let device = if args.gpu {
@@ -145,38 +2708,74 @@ pub async fn cli() -> anyhow::Result<()> {
     };
     ort_builder.commit()?;
 
-    let musicgen_models = musicgen_models::MusicGenModels::new(
-        storage.clone(),
-        args.model,
-        args.use_split_decoder,
-        args.force_download,
-    )
-    .await?;
+    let processor = registry.load(&name).await?;
 
     if args.prompt.is_empty() {
         run_web_server(
             root,
             storage,
-            musicgen_models,
+            processor,
+            registry,
             RunWebServerOptions {
-                name: args.model.to_string(),
+                name: display_name,
+                selection_reason,
                 device: device.to_string(),
                 port: args.ui_port,
                 auto_open: true,
                 expose: args.ui_expose,
+                limits: args.job_limits(),
+                render_settings: RenderSettings {
+                    seed: args.seed,
+                    tokenizers: settings.tokenizers.clone(),
+                    post,
+                    watermark: args.watermark,
+                    license: args.license.clone(),
+                },
+                tokens: ApiTokens::parse(&args.api_token)?.with_admins(&args.admin)?,
+                quotas: Quotas {
+                    daily_secs: args.daily_quota_secs,
+                    monthly_secs: args.monthly_quota_secs,
+                    daily_gpu_secs: args.daily_gpu_quota_secs,
+                    monthly_gpu_secs: args.monthly_gpu_quota_secs,
+                },
+                proxy,
+                tls: args
+                    .ui_tls_cert
+                    .zip(args.ui_tls_key)
+                    .map(|(cert_path, key_path)| TlsConfig {
+                        cert_path,
+                        key_path,
+                    }),
+                schedules,
+                notifiers,
+                podcast_url: args.ui_podcast_url,
+                sync,
+                archive: args.archive.then_some(ArchiveConfig {
+                    ipfs_api: args.archive_ipfs_api,
+                }),
+                api_docs: args.ui_api_docs,
+                reloads: Some(watch_settings(
+                    command_line()?.0,
+                    args.notifiers.clone(),
+                    settings.tokenizers.clone(),
+                )),
             },
         )
         .await
     } else {
         run_terminal_loop(
             root,
-            musicgen_models,
+            processor,
             RunTerminalOptions {
                 init_prompt: args.prompt,
                 init_secs: args.secs,
                 init_output: args.output,
                 no_playback: args.no_playback,
                 no_interactive: args.no_interactive,
+                audition: args.audition,
+                watermark,
+                license: args.license,
+                artwork,
             },
         )
         .await
@@ -0,0 +1,447 @@
+//! Named profiles of flags, like `draft`, `final` or `server`, kept in a config file so
+//! that long lists of flags do not need to be typed again. A profile maps the names of
+//! flags to their values, and it is applied as if its flags were given before the ones
+//! in the command line, which win over it. Flags can also be set with `MUSICGPT_*`
+//! environment variables, which win over the profile but not over the command line.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use clap::{ArgAction, Command};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Profile applied when none is given with --profile.
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// Flags with their values, like `("--model", Some("large"))`.
+pub type Flags = Vec<(String, Option<String>)>;
+
+/// Prefix of the environment variables that set flags, like `MUSICGPT_DATA_PATH` for
+/// --data-path.
+const ENV_PREFIX: &str = "MUSICGPT_";
+
+/// Where the config file is read from when no --config is given.
+pub fn default_config_path() -> PathBuf {
+    ProjectDirs::from("com", "gabotechs", "musicgpt")
+        .expect("Could not load project directory")
+        .config_dir()
+        .join("config.json")
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Could not read the config file {path:?}: {err}"))?;
+        serde_json::from_str(&content).map_err(|err| anyhow!("Invalid config file {path:?}: {err}"))
+    }
+
+    /// The flags of a profile. Flags set to `true` are given without a value and those set
+    /// to `false` or `null` are left out, while lists give the flag once per item.
+    pub fn profile_flags(&self, name: &str) -> anyhow::Result<Flags> {
+        let profile = self.profiles.get(name).ok_or_else(|| {
+            let names = self.profiles.keys().cloned().collect::<Vec<_>>();
+            anyhow!("There is no profile {name:?}, the config file has {names:?}")
+        })?;
+        let mut flags = vec![];
+        for (key, value) in profile {
+            let flag = format!("--{}", key.trim_start_matches('-').replace('_', "-"));
+            let values = match value {
+                Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    Value::Bool(true) => flags.push((flag.clone(), None)),
+                    Value::Bool(false) | Value::Null => {}
+                    Value::String(value) => flags.push((flag.clone(), Some(value.clone()))),
+                    Value::Number(value) => flags.push((flag.clone(), Some(value.to_string()))),
+                    _ => return Err(anyhow!("Invalid value of {key:?} in profile {name:?}")),
+                }
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// The value of a flag in the command line, given as `--flag value` or `--flag=value`.
+fn flag_value(args: &[OsString], flag: &str) -> Option<OsString> {
+    let prefix = format!("{flag}=");
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix(&prefix)) {
+            return Some(value.into());
+        }
+    }
+    None
+}
+
+/// The config file a command line reads its profiles from.
+pub fn config_path(args: &[OsString]) -> PathBuf {
+    match flag_value(args, "--config") {
+        Some(path) => path.into(),
+        None => default_config_path(),
+    }
+}
+
+/// The flags of the profile picked with --profile, or of the default one, that are not
+/// in the command line, which wins over them.
+pub fn applied_flags(args: &[OsString]) -> anyhow::Result<Flags> {
+    let profile = flag_value(args, "--profile");
+    let path = config_path(args);
+    let explicit = flag_value(args, "--config").is_some();
+    let config = match (explicit || path.exists(), &profile) {
+        (true, _) => ConfigFile::load(&path)?,
+        (false, None) => return Ok(vec![]),
+        (false, Some(_)) => return Err(anyhow!("--profile needs a config file, like {path:?}")),
+    };
+    let name = match profile {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => match config.default_profile.clone() {
+            Some(name) => name,
+            None => return Ok(vec![]),
+        },
+    };
+    let mut flags = config.profile_flags(&name)?;
+    flags.retain(|(flag, _)| !is_given(args, flag));
+    Ok(flags)
+}
+
+/// Whether the command line has a flag, with or without a value.
+fn is_given(args: &[OsString], flag: &str) -> bool {
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| arg == flag || arg.starts_with(&format!("{flag}=")))
+}
+
+/// The command line with each list of flags inserted at its position, like after the
+/// program name for the flags of the top command.
+fn insert_flags(args: Vec<OsString>, mut flags: Vec<(usize, Flags)>) -> Vec<OsString> {
+    flags.sort_by_key(|(position, _)| *position);
+    let mut flags = flags.into_iter().peekable();
+    let mut merged = vec![];
+    let insert = |merged: &mut Vec<OsString>, flags: Flags| {
+        for (flag, value) in flags {
+            // Values are attached, so that negative numbers are not taken for flags.
+            match value {
+                Some(value) => merged.push(format!("{flag}={value}").into()),
+                None => merged.push(flag.into()),
+            }
+        }
+    };
+    for (i, arg) in args.into_iter().enumerate() {
+        merged.push(arg);
+        while let Some((_, list)) = flags.next_if(|(position, _)| *position == i + 1) {
+            insert(&mut merged, list);
+        }
+    }
+    for (_, list) in flags {
+        insert(&mut merged, list);
+    }
+    merged
+}
+
+/// The flags set to different values in `before` and `after`.
+pub fn changed_flags(
+    before: &[(String, Option<String>)],
+    after: &[(String, Option<String>)],
+) -> BTreeSet<String> {
+    let values = |flags: &[(String, Option<String>)], flag: &str| {
+        flags
+            .iter()
+            .filter(|(name, _)| name == flag)
+            .map(|(_, value)| value.clone())
+            .collect::<Vec<_>>()
+    };
+    before
+        .iter()
+        .chain(after)
+        .filter(|(flag, _)| values(before, flag) != values(after, flag))
+        .map(|(flag, _)| flag.clone())
+        .collect()
+}
+
+/// The command line with the flags of the profile picked with --profile, or of the default
+/// one, inserted after the program name. Flags of the profile that are also in the
+/// command line are left out, so that the command line wins.
+pub fn with_profile(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let flags = applied_flags(&args)?;
+    Ok(insert_flags(args, vec![(1, flags)]))
+}
+
+/// Variables named like the ones that set flags, but that MusicGPT sets or reads for other
+/// purposes: the prompt given to artwork commands, and the switch for updating the golden
+/// files of the tests.
+const RESERVED_VARS: &[&str] = &["MUSICGPT_PROMPT", "MUSICGPT_UPDATE_GOLDEN"];
+
+/// The commands run by a command line, the top one first, with the position where their
+/// flags are inserted.
+fn invoked<'a>(args: &[OsString], command: &'a Command) -> Vec<(usize, &'a Command)> {
+    let mut invoked = vec![(1, command)];
+    let mut tokens = args.iter().enumerate().skip(1);
+    while let Some((i, token)) = tokens.next() {
+        let Some(token) = token.to_str() else {
+            continue;
+        };
+        if token == "--" {
+            break;
+        }
+        if let Some(long) = token.strip_prefix("--") {
+            // The value of a flag given apart is not a subcommand.
+            let takes_value = invoked.iter().any(|(_, command)| {
+                command
+                    .get_arguments()
+                    .any(|arg| arg.get_long() == Some(long) && arg.get_action().takes_values())
+            });
+            if takes_value {
+                tokens.next();
+            }
+        } else if !token.starts_with('-') {
+            let current = invoked[invoked.len() - 1].1;
+            if let Some(subcommand) = current.find_subcommand(token) {
+                invoked.push((i + 1, subcommand));
+            }
+        }
+    }
+    invoked
+}
+
+/// Whether `command` or any of its subcommands has the flag `--{long}`.
+fn has_flag(command: &Command, long: &str) -> bool {
+    command
+        .get_arguments()
+        .any(|arg| arg.get_long() == Some(long))
+        || command
+            .get_subcommands()
+            .any(|subcommand| has_flag(subcommand, long))
+}
+
+/// The command line with the flags set by the `MUSICGPT_*` variables in `vars`, unless
+/// they are also in the command line. Flags of subcommands are set when the subcommand
+/// runs. Flags without a value are set with `true` or `false`, and the ones that can be
+/// repeated with comma-separated lists. Also returns the variables that do not match any
+/// flag of `command`, which are ignored.
+pub fn with_env(
+    args: Vec<OsString>,
+    command: &Command,
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+) -> anyhow::Result<(Vec<OsString>, Vec<String>)> {
+    let mut vars = vars
+        .into_iter()
+        .filter(|(key, _)| key.to_string_lossy().starts_with(ENV_PREFIX))
+        .filter(|(key, _)| !RESERVED_VARS.iter().any(|reserved| key == reserved))
+        .map(
+            |(key, value)| match (key.into_string(), value.into_string()) {
+                (Ok(key), Ok(value)) => Ok((key, value)),
+                (key, _) => Err(anyhow!("The variable {key:?} is not valid UTF-8")),
+            },
+        )
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    vars.sort();
+    let invoked = invoked(&args, command);
+    let mut flags = invoked
+        .iter()
+        .map(|(position, _)| (*position, Flags::new()))
+        .collect::<Vec<_>>();
+    let mut unknown = vec![];
+    for (key, value) in vars {
+        let long = key[ENV_PREFIX.len()..]
+            .to_ascii_lowercase()
+            .replace('_', "-");
+        // The innermost command wins, like it does for flags given in the command line.
+        let found = invoked
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, (_, command))| {
+                let arg = command
+                    .get_arguments()
+                    .find(|arg| arg.get_long() == Some(long.as_str()))?;
+                Some((i, arg))
+            });
+        let Some((i, arg)) = found else {
+            // Flags of other subcommands are left for when they run.
+            if !has_flag(command, &long) {
+                unknown.push(key);
+            }
+            continue;
+        };
+        let flag = format!("--{long}");
+        if is_given(&args, &flag) {
+            continue;
+        }
+        let flags = &mut flags[i].1;
+        match arg.get_action() {
+            ArgAction::SetTrue => match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" => flags.push((flag, None)),
+                "0" | "false" | "no" | "" => {}
+                _ => return Err(anyhow!("{key} must be true or false, not {value:?}")),
+            },
+            ArgAction::Append => flags.extend(
+                value
+                    .split(',')
+                    .filter(|item| !item.is_empty())
+                    .map(|item| (flag.clone(), Some(item.to_string()))),
+            ),
+            _ => flags.push((flag, Some(value))),
+        }
+    }
+    Ok((insert_flags(args, flags), unknown))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_the_flags_of_a_profile() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("musicgpt-config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{
+              "default_profile": "draft",
+              "profiles": {
+                "draft": { "model": "small" },
+                "final": {
+                  "model": "large", "gpu": true, "watermark": false,
+                  "noise_gate": -50, "api-token": ["a:1", "b:2"]
+                }
+              }
+            }"#,
+        )?;
+        let config = path.to_string_lossy().to_string();
+        let line = |args: &[&str]| {
+            let mut line = vec!["musicgpt", "--config", &config];
+            line.extend(args);
+            line.into_iter().map(OsString::from).collect::<Vec<_>>()
+        };
+
+        let expanded = with_profile(line(&["--profile", "final", "--model=medium", "rain"]))?;
+        let mut expected = vec!["musicgpt", "--api-token=a:1", "--api-token=b:2", "--gpu"];
+        expected.push("--noise-gate=-50");
+        expected.extend([
+            "--config",
+            &config,
+            "--profile",
+            "final",
+            "--model=medium",
+            "rain",
+        ]);
+        assert_eq!(expanded, expected);
+
+        let expanded = with_profile(line(&["rain"]))?;
+        assert_eq!(expanded[1], "--model=small");
+
+        let draft = applied_flags(&line(&["rain"]))?;
+        let last = applied_flags(&line(&["--profile", "final", "--model=medium"]))?;
+        let changed = changed_flags(&draft, &last).into_iter().collect::<Vec<_>>();
+        assert_eq!(changed, ["--api-token", "--gpu", "--model", "--noise-gate"]);
+
+        let err = with_profile(line(&["--profile", "server"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "There is no profile \"server\", the config file has [\"draft\", \"final\"]"
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn sets_flags_from_the_environment() -> anyhow::Result<()> {
+        let command = Command::new("musicgpt")
+            .arg(clap::Arg::new("prompt"))
+            .arg(clap::Arg::new("data_path").long("data-path"))
+            .arg(clap::Arg::new("ui_port").long("ui-port"))
+            .arg(clap::Arg::new("gpu").long("gpu").action(ArgAction::SetTrue))
+            .arg(
+                clap::Arg::new("ui_expose")
+                    .long("ui-expose")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                clap::Arg::new("api_token")
+                    .long("api-token")
+                    .action(ArgAction::Append),
+            )
+            .subcommand(
+                Command::new("inpaint")
+                    .arg(clap::Arg::new("input"))
+                    .arg(clap::Arg::new("from").long("from")),
+            );
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(key, value)| (OsString::from(key), OsString::from(value)))
+                .collect::<Vec<_>>()
+        };
+        let line = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+
+        let (expanded, unknown) = with_env(
+            line(&["musicgpt", "--ui-port=9000"]),
+            &command,
+            vars(&[
+                ("HOME", "/root"),
+                ("MUSICGPT_UI_PORT", "80"),
+                ("MUSICGPT_GPU", "true"),
+                ("MUSICGPT_UI_EXPOSE", "0"),
+                ("MUSICGPT_DATA_PATH", "/data"),
+                ("MUSICGPT_API_TOKEN", "a:1,b:2"),
+                ("MUSICGPT_FROM", "1:10"),
+                ("MUSICGPT_PROMPT", "rain"),
+                ("MUSICGPT_VERSION", "2"),
+            ]),
+        )?;
+        let expected = [
+            "musicgpt",
+            "--api-token=a:1",
+            "--api-token=b:2",
+            "--data-path=/data",
+            "--gpu",
+            "--ui-port=9000",
+        ];
+        assert_eq!(expanded, expected);
+        assert_eq!(unknown, ["MUSICGPT_VERSION"]);
+
+        // Flags of subcommands go after them.
+        let (expanded, _) = with_env(
+            line(&["musicgpt", "--data-path", "inpaint", "inpaint", "a.wav"]),
+            &command,
+            vars(&[("MUSICGPT_FROM", "1:10"), ("MUSICGPT_GPU", "1")]),
+        )?;
+        let expected = [
+            "musicgpt",
+            "--gpu",
+            "--data-path",
+            "inpaint",
+            "inpaint",
+            "--from=1:10",
+            "a.wav",
+        ];
+        assert_eq!(expanded, expected);
+
+        let err = with_env(
+            line(&["musicgpt"]),
+            &command,
+            vars(&[("MUSICGPT_GPU", "cuda")]),
+        );
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "MUSICGPT_GPU must be true or false, not \"cuda\""
+        );
+        Ok(())
+    }
+}
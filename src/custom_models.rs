@@ -0,0 +1,244 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{ModelCapabilities, ModelEntry};
+use crate::cli::{Model, SAMPLING_RATE};
+use crate::musicgen::MusicGenConfig;
+use crate::musicgen_models::{DecoderFiles, MusicGenFiles};
+
+/// What a user-supplied model is able to do, declared in its manifest.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CustomModelCapabilities {
+    /// The sample rate of the audio produced by the model's audio codec.
+    pub sample_rate: usize,
+    /// Max seconds of audio that the model can produce in a single inference pass.
+    pub max_secs: usize,
+    #[serde(default)]
+    pub stereo: bool,
+    #[serde(default)]
+    pub melody: bool,
+}
+
+/// Manifest describing a user-supplied MusicGen variant, for example a fine-tuned one
+/// exported with https://github.com/huggingface/optimum. Relative file paths are resolved
+/// against the directory where the manifest lives.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CustomModel {
+    pub name: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Free-form version of the model, like the revision it was downloaded from.
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub fp16: bool,
+    #[serde(flatten)]
+    pub files: MusicGenFiles,
+    /// Files that are not loaded directly, but need to live next to the ones that are,
+    /// like the external data of big ONNX models.
+    #[serde(default)]
+    pub extra_files: Vec<PathBuf>,
+    pub capabilities: CustomModelCapabilities,
+}
+
+impl CustomModel {
+    /// Reads a manifest from disk and checks that it describes something MusicGPT can run.
+    pub fn load(manifest: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(manifest)
+            .map_err(|err| anyhow!("Could not read custom model manifest {manifest:?}: {err}"))?;
+        let mut model: CustomModel = serde_json::from_str(&content)
+            .map_err(|err| anyhow!("Invalid custom model manifest {manifest:?}: {err}"))?;
+        let root = manifest.parent().unwrap_or(Path::new("."));
+        model.resolve_paths(root);
+        model.validate()?;
+        Ok(model)
+    }
+
+    fn resolve_paths(&mut self, root: &Path) {
+        let resolve = |path: &mut PathBuf| *path = root.join(&*path);
+        resolve(&mut self.files.config);
+        resolve(&mut self.files.tokenizer);
+        resolve(&mut self.files.text_encoder);
+        match &mut self.files.decoder {
+            DecoderFiles::Merged {
+                decoder_model_merged,
+            } => resolve(decoder_model_merged),
+            DecoderFiles::Split {
+                decoder_model,
+                decoder_with_past_model,
+            } => {
+                resolve(decoder_model);
+                resolve(decoder_with_past_model);
+            }
+        }
+        resolve(&mut self.files.audio_encodec);
+        self.extra_files.iter_mut().for_each(resolve);
+    }
+
+    /// All the files that compose the model, including the extra ones.
+    pub fn all_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.iter().chain(self.extra_files.iter())
+    }
+
+    /// Validates the manifest against the files it points to. The ONNX files themselves
+    /// are validated when building the sessions.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let name = &self.name;
+        if name.is_empty() {
+            return Err(anyhow!("Custom model name cannot be empty"));
+        }
+        if Model::value_variants().iter().any(|m| m.name() == *name) {
+            return Err(anyhow!(
+                "Custom model name {name} conflicts with a built-in model"
+            ));
+        }
+        let capabilities = &self.capabilities;
+        if capabilities.max_secs == 0 || capabilities.max_secs > 30 {
+            return Err(anyhow!(
+                "Custom model {name} declares max_secs {}, it must be between 1 and 30",
+                capabilities.max_secs
+            ));
+        }
+        if capabilities.stereo || capabilities.melody {
+            return Err(anyhow!(
+                "Custom model {name} declares stereo or melody capabilities, which are not supported yet"
+            ));
+        }
+        if capabilities.sample_rate != SAMPLING_RATE {
+            return Err(anyhow!(
+                "Custom model {name} declares a sample rate of {}, but only {SAMPLING_RATE} is supported",
+                capabilities.sample_rate
+            ));
+        }
+        for file in self.all_files() {
+            if !file.is_file() {
+                return Err(anyhow!("Custom model {name} is missing file {file:?}"));
+            }
+        }
+
+        let config = std::fs::read_to_string(&self.files.config)?;
+        let config: MusicGenConfig = serde_json::from_str(&config).map_err(|err| {
+            anyhow!(
+                "Custom model {name} has an invalid config file {:?}: {err}",
+                self.files.config
+            )
+        })?;
+        if config.audio_encoder.sampling_rate != capabilities.sample_rate {
+            return Err(anyhow!(
+                "Custom model {name} declares a sample rate of {}, but its config says {}",
+                capabilities.sample_rate,
+                config.audio_encoder.sampling_rate
+            ));
+        }
+        if config.decoder.num_hidden_layers == 0 {
+            return Err(anyhow!(
+                "Custom model {name} has a decoder without hidden layers"
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn entry(&self) -> ModelEntry {
+        ModelEntry {
+            name: self.name.clone(),
+            display_name: self.display_name.clone().unwrap_or(self.name.clone()),
+            installed: true,
+            size_bytes: self
+                .all_files()
+                .filter_map(|file| std::fs::metadata(file).ok())
+                .map(|metadata| metadata.len())
+                .sum(),
+            capabilities: ModelCapabilities {
+                max_secs: self.capabilities.max_secs,
+                stereo: self.capabilities.stereo,
+                melody: self.capabilities.melody,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "audio_encoder": { "sampling_rate": 32000 },
+        "decoder": { "num_attention_heads": 16, "num_hidden_layers": 24, "top_k": 250, "pad_token_id": 2048 },
+        "text_encoder": { "d_kv": 64 }
+    }"#;
+
+    fn write_model(manifest: &str) -> anyhow::Result<PathBuf> {
+        let dir = PathBuf::from(format!("/tmp/musicgpt-tests/{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("config.json"), CONFIG)?;
+        for file in [
+            "tokenizer.json",
+            "text_encoder.onnx",
+            "decoder_model_merged.onnx",
+            "encodec_decode.onnx",
+        ] {
+            std::fs::write(dir.join(file), "")?;
+        }
+        std::fs::write(dir.join("manifest.json"), manifest)?;
+        Ok(dir.join("manifest.json"))
+    }
+
+    fn manifest(name: &str, sample_rate: usize, max_secs: usize) -> String {
+        format!(
+            r#"{{
+                "name": "{name}",
+                "config": "config.json",
+                "tokenizer": "tokenizer.json",
+                "text_encoder": "text_encoder.onnx",
+                "decoder_model_merged": "decoder_model_merged.onnx",
+                "audio_encodec": "encodec_decode.onnx",
+                "capabilities": {{ "sample_rate": {sample_rate}, "max_secs": {max_secs} }}
+            }}"#
+        )
+    }
+
+    #[test]
+    fn loads_a_valid_manifest() -> anyhow::Result<()> {
+        let path = write_model(&manifest("lofi", 32000, 20))?;
+        let model = CustomModel::load(&path)?;
+        assert_eq!(
+            model.files.config,
+            path.parent().unwrap().join("config.json")
+        );
+        let entry = model.entry();
+        assert_eq!(entry.display_name, "lofi");
+        assert_eq!(entry.capabilities.max_secs, 20);
+        assert_eq!(entry.size_bytes, CONFIG.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_mismatching_sample_rate() -> anyhow::Result<()> {
+        let path = write_model(&manifest("lofi", 48000, 20))?;
+        let err = CustomModel::load(&path).unwrap_err();
+        assert!(err.to_string().contains("only 32000 is supported"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_durations_and_names() -> anyhow::Result<()> {
+        let path = write_model(&manifest("lofi", 32000, 60))?;
+        assert!(CustomModel::load(&path).is_err());
+        let path = write_model(&manifest("small", 32000, 10))?;
+        let err = CustomModel::load(&path).unwrap_err();
+        assert!(err.to_string().contains("conflicts with a built-in model"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_missing_files() -> anyhow::Result<()> {
+        let path = write_model(&manifest("lofi", 32000, 20))?;
+        std::fs::remove_file(path.parent().unwrap().join("encodec_decode.onnx"))?;
+        let err = CustomModel::load(&path).unwrap_err();
+        assert!(err.to_string().contains("is missing file"));
+        Ok(())
+    }
+}
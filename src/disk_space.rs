@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use sysinfo::Disks;
+use tracing::info;
+
+use crate::model_cache;
+use crate::storage::Storage;
+
+const MB: u64 = 1024 * 1024;
+
+/// Free space in the volume that holds `path`, if it can be determined.
+pub fn available_space(path: &Path) -> Option<u64> {
+    // The path might not exist yet, its closest existing ancestor is in the same volume.
+    let path = path.ancestors().find_map(|p| p.canonicalize().ok())?;
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// Fails if the storage's volume does not have `needed` bytes available. Before failing,
+/// model files that no model uses anymore are removed, like `models gc` does.
+pub async fn ensure_space<S: Storage>(storage: &S, needed: u64) -> anyhow::Result<()> {
+    let root = storage.path_buf("");
+    let Some(available) = available_space(&root) else {
+        return Ok(());
+    };
+    if available >= needed {
+        return Ok(());
+    }
+    let report = model_cache::report(storage).await?;
+    let to_remove = model_cache::plan_gc(&report, None, &[]);
+    if !to_remove.is_empty() {
+        let freed = model_cache::remove(storage, &to_remove).await?;
+        info!("Freed {} MB of unused model files", freed / MB);
+        if available + freed >= needed {
+            return Ok(());
+        }
+    }
+    let available = available_space(&root).unwrap_or(available);
+    if available >= needed {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Not enough disk space in {root:?}: {} MB are needed, but only {} MB are available",
+        needed.div_ceil(MB),
+        available / MB
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[test]
+    fn finds_the_volume_of_paths_that_do_not_exist_yet() {
+        let tmp = std::env::temp_dir();
+        assert!(available_space(&tmp).is_some());
+        assert_eq!(
+            available_space(&tmp.join("does/not/exist")).is_some(),
+            available_space(&tmp).is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_when_there_is_not_enough_space() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        ensure_space(&storage, 0).await?;
+        let err = ensure_space(&storage, u64::MAX).await.unwrap_err();
+        assert!(err.to_string().starts_with("Not enough disk space"));
+        Ok(())
+    }
+}
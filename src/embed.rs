@@ -0,0 +1,126 @@
+//! Rust API for hosts that embed the generator, like game engines, and play its audio
+//! while it's being generated:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use musicgpt::audio::ring_playback::{ring_playback, UnderrunStrategy};
+//! use musicgpt::embed::{Generation, Model};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let model = Model::load("small", None, false).await?;
+//! let (writer, mut player) = ring_playback(model.sample_rate() * 2, UnderrunStrategy::Silence);
+//! let generation = Arc::new(Generation::default());
+//! let progress = generation.clone();
+//! std::thread::spawn(move || model.generate("Create a relaxing LoFi song", 10, &progress, writer));
+//! // From the audio thread:
+//! let mut buffer = [0.0; 512];
+//! player.fill(&mut buffer);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::audio::ring_playback::RingWriter;
+use crate::backend::{JobProcessor, ModelRegistry};
+use crate::cli::{default_data_path, Settings, SAMPLING_RATE};
+use crate::gpu::SessionDevice;
+use crate::musicgen_models::MusicGenModelRegistry;
+use crate::storage::AppFs;
+use crate::{gpu, hub, onnxruntime_lib};
+
+/// A loaded model, which can generate audio for several prompts one after the other.
+#[derive(Clone)]
+pub struct Model {
+    processor: Arc<dyn JobProcessor>,
+}
+
+impl Model {
+    pub(crate) fn new(processor: Arc<dyn JobProcessor>) -> Self {
+        Self { processor }
+    }
+
+    /// Loads a model, downloading it first if needed. Without `data_path`, the same data
+    /// directory as the CLI is used.
+    pub async fn load(name: &str, data_path: Option<PathBuf>, gpu: bool) -> anyhow::Result<Self> {
+        let storage = AppFs::new(data_path.unwrap_or_else(default_data_path));
+        let settings = Settings::load(&storage).await?;
+        let registry = MusicGenModelRegistry {
+            storage: storage.clone(),
+            use_split_decoder: false,
+            force_download: false,
+            custom_models: hub::installed(&storage).await?,
+            tokenizers: settings.tokenizers,
+            pins: settings.pinned_versions,
+            gpu,
+            post: Default::default(),
+            device: SessionDevice::Default,
+            threads: Default::default(),
+            seed: None,
+            batch_size: 1,
+            draft_model: None,
+            draft_steps: 4,
+            sessions: 1,
+        };
+        let mut ort_builder = onnxruntime_lib::init::init(storage).await?;
+        if gpu {
+            let (_, provider) = gpu::init_gpu()?;
+            ort_builder = ort_builder.with_execution_providers(&[provider]);
+        }
+        ort_builder.commit()?;
+        Ok(Self::new(registry.load(name).await?))
+    }
+
+    /// Sample rate of the generated audio.
+    pub fn sample_rate(&self) -> usize {
+        SAMPLING_RATE
+    }
+
+    /// Generates `secs` seconds of mono audio for `prompt` into `writer`, blocking until
+    /// it's done. The writer waits for the player to make room, so generation does not
+    /// get ahead of playback by more than the capacity of the ring buffer.
+    pub fn generate(
+        &self,
+        prompt: &str,
+        secs: usize,
+        generation: &Arc<Generation>,
+        mut writer: RingWriter,
+    ) -> Result<(), String> {
+        let progress = generation.clone();
+        let on_progress = Box::new(move |elapsed: f32, total: f32| {
+            let value = (elapsed / total).clamp(0.0, 1.0);
+            progress.progress.store(value.to_bits(), Ordering::SeqCst);
+            progress.cancelled.load(Ordering::SeqCst)
+        });
+        self.processor
+            .process_streaming(prompt, secs, on_progress, &mut writer)
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Progress of a generation, shared between the thread running it and the host.
+#[derive(Default)]
+pub struct Generation {
+    /// Bits of the f32 progress, from 0 to 1.
+    progress: AtomicU32,
+    cancelled: AtomicBool,
+}
+
+impl Generation {
+    /// From 0 to 1.
+    pub fn progress(&self) -> f32 {
+        f32::from_bits(self.progress.load(Ordering::SeqCst))
+    }
+
+    /// Asks the generation to stop, which makes [Model::generate] fail.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
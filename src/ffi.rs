@@ -0,0 +1,446 @@
+//! Stable C ABI for embedding the generator in non-Rust hosts, like game engines, as a
+//! shared library. Build it with:
+//!
+//! ```sh
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+//!
+//! The functions are declared in `include/musicgpt.h`. Jobs run in background threads,
+//! one after the other for each processor, and hosts poll them for progress and audio.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr::null;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+use anyhow::anyhow;
+
+use crate::audio::ring_playback::{ring_playback, RingPlayer, UnderrunStrategy};
+use crate::cli::SAMPLING_RATE;
+use crate::embed::{Generation, Model};
+
+const JOB_UNKNOWN: i32 = -1;
+const JOB_QUEUED: i32 = 0;
+const JOB_RUNNING: i32 = 1;
+const JOB_DONE: i32 = 2;
+const JOB_FAILED: i32 = 3;
+const JOB_CANCELLED: i32 = 4;
+
+/// Seconds of audio a job generates ahead of the host fetching it.
+const RING_SECS: usize = 30;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: impl ToString) {
+    let err = CString::new(err.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(err));
+}
+
+/// Locks `mutex` even if a thread panicked while holding it, as all the state behind the
+/// locks stays consistent and panicking across the C ABI is not an option.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown", |m| m.as_str()),
+    }
+}
+
+/// A loaded model that generates audio for the jobs submitted to it.
+pub struct MusicGptProcessor {
+    model: Model,
+    jobs: Mutex<HashMap<u64, Arc<FfiJob>>>,
+    next_id: AtomicU64,
+    /// Held while a job runs, so jobs from the same processor run one after the other.
+    running: Arc<Mutex<()>>,
+}
+
+struct FfiJob {
+    status: AtomicI32,
+    generation: Arc<Generation>,
+    /// Generated audio not fetched by the host yet. Dropping it stops the generation.
+    player: Mutex<Option<RingPlayer>>,
+    error: Mutex<Option<CString>>,
+}
+
+impl FfiJob {
+    /// Stops the job, discarding the audio that was not fetched.
+    fn cancel(&self) {
+        self.generation.cancel();
+        // The generation might be waiting for the host to fetch audio.
+        lock(&self.player).take();
+    }
+}
+
+impl MusicGptProcessor {
+    fn new(model: Model) -> Self {
+        Self {
+            model,
+            jobs: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            running: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn submit(&self, prompt: String, secs: usize) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (writer, player) = ring_playback(
+            RING_SECS * self.model.sample_rate(),
+            UnderrunStrategy::Silence,
+        );
+        let job = Arc::new(FfiJob {
+            status: AtomicI32::new(JOB_QUEUED),
+            generation: Arc::new(Generation::default()),
+            player: Mutex::new(Some(player)),
+            error: Mutex::new(None),
+        });
+        lock(&self.jobs).insert(id, job.clone());
+
+        let model = self.model.clone();
+        let running = self.running.clone();
+        std::thread::spawn(move || {
+            let _running = lock(&running);
+            if job.generation.is_cancelled() {
+                job.status.store(JOB_CANCELLED, Ordering::SeqCst);
+                return;
+            }
+            job.status.store(JOB_RUNNING, Ordering::SeqCst);
+            // A panic must not unwind into the host, nor leave the job running forever.
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                model.generate(&prompt, secs, &job.generation, writer)
+            }))
+            .unwrap_or_else(|panic| {
+                Err(format!("Generation panicked: {}", panic_message(&*panic)))
+            });
+            let status = match result {
+                Ok(()) => JOB_DONE,
+                Err(_) if job.generation.is_cancelled() => JOB_CANCELLED,
+                Err(err) => {
+                    let err = CString::new(err.replace('\0', "")).unwrap_or_default();
+                    *lock(&job.error) = Some(err);
+                    JOB_FAILED
+                }
+            };
+            job.status.store(status, Ordering::SeqCst);
+        });
+        id
+    }
+
+    fn job(&self, id: u64) -> Option<Arc<FfiJob>> {
+        lock(&self.jobs).get(&id).cloned()
+    }
+}
+
+/// # Safety
+/// `ptr` must be null or point to a nul terminated string.
+unsafe fn opt_str<'a>(ptr: *const c_char) -> anyhow::Result<Option<&'a str>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(CStr::from_ptr(ptr).to_str()?))
+}
+
+/// Loads a model, downloading it first if needed. `data_path` can be null for using the
+/// same data directory as the CLI. Returns null on failure, see [musicgpt_last_error].
+///
+/// # Safety
+/// `model` must point to a nul terminated string, and `data_path` must be either null or
+/// point to one.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_processor_create(
+    model: *const c_char,
+    data_path: *const c_char,
+    gpu: bool,
+) -> *mut MusicGptProcessor {
+    let result = (|| {
+        let model = opt_str(model)?.ok_or_else(|| anyhow!("model must not be null"))?;
+        let data_path = opt_str(data_path)?.map(PathBuf::from);
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(Model::load(model, data_path, gpu))
+    })();
+    match result {
+        Ok(processor) => Box::into_raw(Box::new(MusicGptProcessor::new(processor))),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Cancels all the jobs and releases the processor. Audio being generated when this is
+/// called finishes in the background.
+///
+/// # Safety
+/// `processor` must be null or have been returned by [musicgpt_processor_create], and
+/// must not be used afterward.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_processor_free(processor: *mut MusicGptProcessor) {
+    if processor.is_null() {
+        return;
+    }
+    let processor = Box::from_raw(processor);
+    for job in lock(&processor.jobs).values() {
+        job.cancel();
+    }
+}
+
+/// Queues the generation of `secs` seconds of audio for `prompt`. Returns the id of the
+/// job, or 0 on failure.
+///
+/// # Safety
+/// `processor` must be a live processor and `prompt` must point to a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_job_submit(
+    processor: *const MusicGptProcessor,
+    prompt: *const c_char,
+    secs: u32,
+) -> u64 {
+    let Some(processor) = processor.as_ref() else {
+        set_last_error("processor must not be null");
+        return 0;
+    };
+    match opt_str(prompt) {
+        Ok(Some(prompt)) => processor.submit(prompt.to_string(), secs as usize),
+        Ok(None) => {
+            set_last_error("prompt must not be null");
+            0
+        }
+        Err(err) => {
+            set_last_error(err);
+            0
+        }
+    }
+}
+
+/// One of the MUSICGPT_JOB_* statuses.
+///
+/// # Safety
+/// `processor` must be a live processor.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_job_status(processor: *const MusicGptProcessor, job: u64) -> i32 {
+    match processor.as_ref().and_then(|p| p.job(job)) {
+        Some(job) => job.status.load(Ordering::SeqCst),
+        None => JOB_UNKNOWN,
+    }
+}
+
+/// The progress of the job, from 0 to 1, or a negative number for unknown jobs.
+///
+/// # Safety
+/// `processor` must be a live processor.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_job_progress(
+    processor: *const MusicGptProcessor,
+    job: u64,
+) -> f32 {
+    match processor.as_ref().and_then(|p| p.job(job)) {
+        Some(job) => job.generation.progress(),
+        None => -1.0,
+    }
+}
+
+/// Moves up to `len` of the mono samples generated so far into `out`, returning how many
+/// were written. Never blocks, so it returns 0 while another thread fetches samples of the
+/// same job. Samples are at [musicgpt_sample_rate]. The job waits for the host to fetch
+/// its audio once [RING_SECS] seconds of it are pending.
+///
+/// # Safety
+/// `processor` must be a live processor and `out` must point to at least `len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_job_fetch_samples(
+    processor: *const MusicGptProcessor,
+    job: u64,
+    out: *mut f32,
+    len: usize,
+) -> usize {
+    let Some(job) = processor.as_ref().and_then(|p| p.job(job)) else {
+        return 0;
+    };
+    if out.is_null() || len == 0 {
+        return 0;
+    }
+    let mut player = match job.player.try_lock() {
+        Ok(player) => player,
+        Err(TryLockError::Poisoned(err)) => err.into_inner(),
+        Err(TryLockError::WouldBlock) => return 0,
+    };
+    match player.as_mut() {
+        Some(player) => player.read(std::slice::from_raw_parts_mut(out, len)),
+        None => 0,
+    }
+}
+
+/// Asks the job to stop, discarding the audio that was not fetched. Its status becomes
+/// MUSICGPT_JOB_CANCELLED once it stopped.
+///
+/// # Safety
+/// `processor` must be a live processor.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_job_cancel(processor: *const MusicGptProcessor, job: u64) {
+    if let Some(job) = processor.as_ref().and_then(|p| p.job(job)) {
+        job.cancel();
+    }
+}
+
+/// Why the job failed, or null if it did not. Valid until the job is freed.
+///
+/// # Safety
+/// `processor` must be a live processor.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_job_error(
+    processor: *const MusicGptProcessor,
+    job: u64,
+) -> *const c_char {
+    match processor.as_ref().and_then(|p| p.job(job)) {
+        // The string lives in the job, which the processor keeps until it is freed.
+        Some(job) => match lock(&job.error).as_ref() {
+            Some(err) => err.as_ptr(),
+            None => null(),
+        },
+        None => null(),
+    }
+}
+
+/// Forgets about the job, cancelling it if it is still running.
+///
+/// # Safety
+/// `processor` must be a live processor.
+#[no_mangle]
+pub unsafe extern "C" fn musicgpt_job_free(processor: *const MusicGptProcessor, job: u64) {
+    if let Some(processor) = processor.as_ref() {
+        if let Some(job) = lock(&processor.jobs).remove(&job) {
+            job.cancel();
+        }
+    }
+}
+
+/// Sample rate of the generated audio.
+#[no_mangle]
+pub extern "C" fn musicgpt_sample_rate() -> u32 {
+    SAMPLING_RATE as u32
+}
+
+/// The error of the last call that failed in this thread, or null. Valid until the next
+/// failing call in the same thread.
+#[no_mangle]
+pub extern "C" fn musicgpt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match last.borrow().as_ref() {
+        Some(err) => err.as_ptr(),
+        None => null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::JobProcessor;
+
+    use super::*;
+
+    fn wait_for(processor: &MusicGptProcessor, job: u64) -> i32 {
+        loop {
+            let status = unsafe { musicgpt_job_status(processor, job) };
+            if status != JOB_QUEUED && status != JOB_RUNNING {
+                return status;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn generates_and_fetches_audio() {
+        let processor = MusicGptProcessor::new(Model::new(Arc::new(DummyJobProcessor::default())));
+        let prompt = CString::new("a prompt").unwrap();
+        let job = unsafe { musicgpt_job_submit(&processor, prompt.as_ptr(), 4) };
+        assert_ne!(job, 0);
+        assert_eq!(wait_for(&processor, job), JOB_DONE);
+        assert_eq!(unsafe { musicgpt_job_progress(&processor, job) }, 1.0);
+
+        let mut out = [0.0; 3];
+        let n = unsafe { musicgpt_job_fetch_samples(&processor, job, out.as_mut_ptr(), 3) };
+        assert_eq!((n, out), (3, [0.0, 1.0, 2.0]));
+        let n = unsafe { musicgpt_job_fetch_samples(&processor, job, out.as_mut_ptr(), 3) };
+        assert_eq!((n, out[0]), (1, 3.0));
+
+        unsafe { musicgpt_job_free(&processor, job) };
+        assert_eq!(unsafe { musicgpt_job_status(&processor, job) }, JOB_UNKNOWN);
+    }
+
+    /// Generates more audio than a job can have pending.
+    struct Loud;
+
+    impl JobProcessor for Loud {
+        fn process(
+            &self,
+            _prompt: &str,
+            _secs: usize,
+            _on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        ) -> ort::Result<Vec<f32>> {
+            Ok(vec![0.0; 2 * RING_SECS * SAMPLING_RATE])
+        }
+    }
+
+    struct Panicking;
+
+    impl JobProcessor for Panicking {
+        fn process(
+            &self,
+            _prompt: &str,
+            _secs: usize,
+            _on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        ) -> ort::Result<Vec<f32>> {
+            panic!("model exploded")
+        }
+    }
+
+    #[test]
+    fn reports_failures_and_cancellations() {
+        let processor = MusicGptProcessor::new(Model::new(Arc::new(DummyJobProcessor::new(
+            Duration::from_millis(20),
+        ))));
+        let failing = CString::new("fail at 1").unwrap();
+        let job = unsafe { musicgpt_job_submit(&processor, failing.as_ptr(), 4) };
+        assert_eq!(wait_for(&processor, job), JOB_FAILED);
+        let err = unsafe { CStr::from_ptr(musicgpt_job_error(&processor, job)) };
+        assert_eq!(err.to_str().unwrap(), "Failed at 1");
+
+        let prompt = CString::new("a prompt").unwrap();
+        let job = unsafe { musicgpt_job_submit(&processor, prompt.as_ptr(), 100) };
+        unsafe { musicgpt_job_cancel(&processor, job) };
+        assert_eq!(wait_for(&processor, job), JOB_CANCELLED);
+
+        // Panics fail the job instead of unwinding into the host.
+        let processor = MusicGptProcessor::new(Model::new(Arc::new(Panicking)));
+        let job = unsafe { musicgpt_job_submit(&processor, prompt.as_ptr(), 1) };
+        assert_eq!(wait_for(&processor, job), JOB_FAILED);
+        let err = unsafe { CStr::from_ptr(musicgpt_job_error(&processor, job)) };
+        assert_eq!(err.to_str().unwrap(), "Generation panicked: model exploded");
+
+        // A job waiting for the host to fetch its audio stops too.
+        let processor = MusicGptProcessor::new(Model::new(Arc::new(Loud)));
+        let job = unsafe { musicgpt_job_submit(&processor, prompt.as_ptr(), 1) };
+        while unsafe { musicgpt_job_status(&processor, job) } != JOB_RUNNING {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        unsafe { musicgpt_job_cancel(&processor, job) };
+        assert_eq!(wait_for(&processor, job), JOB_CANCELLED);
+
+        assert_eq!(unsafe { musicgpt_job_submit(&processor, null(), 4) }, 0);
+        let err = unsafe { CStr::from_ptr(musicgpt_last_error()) };
+        assert_eq!(err.to_str().unwrap(), "prompt must not be null");
+    }
+}
@@ -1,11 +1,72 @@
 use anyhow::anyhow;
 use log::{error, info};
 use ort::execution_providers::{
-    CUDAExecutionProvider, CoreMLExecutionProvider, ExecutionProvider, ExecutionProviderDispatch,
-    TensorRTExecutionProvider,
+    CPUExecutionProvider, CUDAExecutionProvider, CoreMLExecutionProvider, ExecutionProvider,
+    ExecutionProviderDispatch, TensorRTExecutionProvider,
 };
 use ort::session::Session;
 
+pub struct ExecutionProviderStatus {
+    pub name: &'static str,
+    /// Whether MusicGPT was compiled with the feature flag that enables this provider.
+    pub enabled_in_build: bool,
+    /// Whether the ONNX Runtime library in use was compiled with support for this provider.
+    pub supported_by_onnxruntime: bool,
+    /// The error, if any, raised when trying to register the provider.
+    pub error: Option<String>,
+}
+
+impl ExecutionProviderStatus {
+    /// Whether sessions can run on the provider.
+    pub fn is_usable(&self) -> bool {
+        self.enabled_in_build && self.supported_by_onnxruntime && self.error.is_none()
+    }
+}
+
+/// Reports the status of every hardware accelerator MusicGPT knows about. ONNX Runtime
+/// needs to be initialized before calling this.
+pub fn probe_execution_providers() -> Vec<ExecutionProviderStatus> {
+    fn probe(
+        name: &'static str,
+        enabled_in_build: bool,
+        provider: impl ExecutionProvider,
+    ) -> ExecutionProviderStatus {
+        let supported_by_onnxruntime = provider.is_available().unwrap_or(false);
+        let error = if !enabled_in_build {
+            None
+        } else {
+            match Session::builder() {
+                Ok(mut builder) => provider.register(&mut builder).err().map(|e| e.to_string()),
+                Err(err) => Some(err.to_string()),
+            }
+        };
+        ExecutionProviderStatus {
+            name,
+            enabled_in_build,
+            supported_by_onnxruntime,
+            error,
+        }
+    }
+
+    vec![
+        probe(
+            "TensorRT",
+            cfg!(feature = "tensorrt"),
+            TensorRTExecutionProvider::default(),
+        ),
+        probe(
+            "Cuda",
+            cfg!(feature = "cuda"),
+            CUDAExecutionProvider::default(),
+        ),
+        probe(
+            "CoreML",
+            cfg!(feature = "coreml"),
+            CoreMLExecutionProvider::default().with_ane_only(),
+        ),
+    ]
+}
+
 pub fn init_gpu() -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
     let mut dummy_builder = Session::builder()?;
 
@@ -44,3 +105,57 @@ pub fn init_gpu() -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
         "No hardware accelerator was detected, try running the program without the --gpu flag",
     ))
 }
+
+/// Where inference sessions run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionDevice {
+    /// The execution providers ONNX Runtime was initialized with.
+    Default,
+    /// Only the CPU, even if ONNX Runtime was initialized with a GPU provider.
+    Cpu,
+}
+
+impl SessionDevice {
+    /// Providers registered in a session before the ones ONNX Runtime was initialized with,
+    /// which take precedence over them.
+    pub fn execution_providers(&self) -> Vec<ExecutionProviderDispatch> {
+        match self {
+            SessionDevice::Default => vec![],
+            SessionDevice::Cpu => vec![CPUExecutionProvider::default().build()],
+        }
+    }
+}
+
+/// Whether an inference error was caused by the device running out of memory, judging
+/// by the messages CUDA, DirectML and CoreML produce.
+pub fn is_out_of_memory(err: &str) -> bool {
+    let err = err.to_lowercase();
+    [
+        "out of memory",
+        "failed to allocate memory",
+        "cudaerrormemoryallocation",
+        "e_outofmemory",
+        "0x8007000e",
+    ]
+    .iter()
+    .any(|pattern| err.contains(pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_out_of_memory_errors() {
+        assert!(is_out_of_memory(
+            "Non-zero status code returned while running MatMul node. CUDA failure 2: out of memory"
+        ));
+        assert!(is_out_of_memory(
+            "Failed to allocate memory for requested buffer of size 536870912"
+        ));
+        assert!(is_out_of_memory(
+            "DmlExecutionProvider: 887A0005 E_OUTOFMEMORY"
+        ));
+        assert!(!is_out_of_memory("Invalid input name: input_ids"));
+    }
+}
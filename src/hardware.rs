@@ -0,0 +1,326 @@
+use std::fmt::Write;
+use std::process::Command;
+
+use clap::ValueEnum;
+use sysinfo::{CpuRefreshKind, RefreshKind, System};
+
+use crate::cli::Model;
+use crate::gpu::ExecutionProviderStatus;
+
+const GB: u64 = 1024 * 1024 * 1024;
+
+/// The number of physical CPU cores of the machine used as reference for
+/// [Model::reference_rtf].
+const REFERENCE_CORES: usize = 8;
+
+pub struct GpuInfo {
+    pub name: String,
+    pub vram_bytes: u64,
+    pub driver_version: String,
+}
+
+pub struct HardwareInfo {
+    pub cpu_brand: String,
+    pub physical_cores: usize,
+    pub logical_cores: usize,
+    pub total_memory_bytes: u64,
+    pub available_memory_bytes: u64,
+    pub gpus: Vec<GpuInfo>,
+}
+
+impl HardwareInfo {
+    pub fn probe() -> Self {
+        let mut system =
+            System::new_with_specifics(RefreshKind::nothing().with_cpu(CpuRefreshKind::nothing()));
+        system.refresh_memory();
+        let logical_cores = system.cpus().len();
+        Self {
+            cpu_brand: system
+                .cpus()
+                .first()
+                .map(|cpu| cpu.brand().trim().to_string())
+                .unwrap_or_default(),
+            physical_cores: system.physical_core_count().unwrap_or(logical_cores),
+            logical_cores,
+            total_memory_bytes: system.total_memory(),
+            available_memory_bytes: system.available_memory(),
+            gpus: probe_nvidia_gpus(),
+        }
+    }
+
+    /// Expected real-time factor (seconds spent generating per second of audio) for
+    /// running the given model on CPU, extrapolated from the reference measurements
+    /// based on the number of physical cores.
+    pub fn expected_cpu_rtf(&self, model: Model) -> f64 {
+        let cores = self.physical_cores.max(1) as f64;
+        model.reference_rtf() * REFERENCE_CORES as f64 / cores
+    }
+
+    /// Memory that can be used for running inference. If running on a GPU with dedicated
+    /// memory, that's the GPU's VRAM, otherwise it's the system's available RAM.
+    pub fn inference_memory_bytes(&self, gpu: bool) -> (u64, String) {
+        match self.gpus.iter().max_by_key(|g| g.vram_bytes) {
+            Some(g) if gpu => (g.vram_bytes, format!("VRAM on {}", g.name)),
+            _ => (self.available_memory_bytes, "RAM".to_string()),
+        }
+    }
+}
+
+/// Picks the largest non-experimental model that fits in the memory available for
+/// inference, returning it along with a human-readable explanation of the decision. The
+/// GPU is only counted on if one of `providers` can run on it, and without it the large
+/// model is left out, as it generates several times slower than real time on a CPU.
+pub fn select_model(
+    hardware: &HardwareInfo,
+    providers: &[ExecutionProviderStatus],
+    gpu: bool,
+) -> (Model, String) {
+    let accelerated = gpu && providers.iter().any(|provider| provider.is_usable());
+    let (memory, kind) = hardware.inference_memory_bytes(accelerated);
+    let memory_gb = memory as f64 / GB as f64;
+    let (candidates, note) = match (gpu, accelerated) {
+        (_, true) => (&[Model::Large, Model::Medium, Model::Small][..], ""),
+        (true, false) => (
+            &[Model::Medium, Model::Small][..],
+            ", no GPU execution provider is usable so it runs on the CPU",
+        ),
+        (false, false) => (&[Model::Medium, Model::Small][..], ""),
+    };
+    for model in candidates {
+        let needed_gb = model.estimated_memory_bytes() as f64 / GB as f64;
+        if memory_gb >= needed_gb {
+            return (
+                *model,
+                format!("{model} selected automatically: {memory_gb:.1} GB of {kind} available, it needs ~{needed_gb:.0} GB{note}"),
+            );
+        }
+    }
+    (
+        Model::Small,
+        format!("{} selected automatically: only {memory_gb:.1} GB of {kind} available, which might not be enough for any model{note}", Model::Small),
+    )
+}
+
+/// Builds a human-readable report of the hardware relevant for running inference.
+pub fn report(hardware: &HardwareInfo, providers: &[ExecutionProviderStatus]) -> String {
+    let mut out = String::new();
+    let gb = |bytes: u64| bytes as f64 / GB as f64;
+
+    let _ = writeln!(out, "CPU");
+    let _ = writeln!(out, "  {}", hardware.cpu_brand);
+    let _ = writeln!(
+        out,
+        "  {} physical cores, {} logical cores",
+        hardware.physical_cores, hardware.logical_cores
+    );
+    let _ = writeln!(out, "Memory");
+    let _ = writeln!(
+        out,
+        "  {:.1} GB available of {:.1} GB",
+        gb(hardware.available_memory_bytes),
+        gb(hardware.total_memory_bytes)
+    );
+
+    let _ = writeln!(out, "GPUs");
+    if hardware.gpus.is_empty() {
+        let _ = writeln!(out, "  No NVIDIA GPU detected");
+    }
+    for gpu in hardware.gpus.iter() {
+        let _ = writeln!(
+            out,
+            "  {} ({:.1} GB VRAM, driver {})",
+            gpu.name,
+            gb(gpu.vram_bytes),
+            gpu.driver_version
+        );
+    }
+
+    let _ = writeln!(out, "Execution providers");
+    let _ = writeln!(out, "  Cpu: available");
+    for provider in providers {
+        let status = match (
+            provider.enabled_in_build,
+            provider.supported_by_onnxruntime,
+            &provider.error,
+        ) {
+            (false, _, _) => "not enabled in this build".to_string(),
+            (true, _, Some(err)) => format!("failed to load: {err}"),
+            (true, false, None) => "not supported by the ONNX Runtime library".to_string(),
+            (true, true, None) => "available, use it with --gpu".to_string(),
+        };
+        let _ = writeln!(out, "  {}: {status}", provider.name);
+    }
+
+    let _ = writeln!(
+        out,
+        "Expected real-time factor on CPU (seconds of compute per second of audio, lower is better)"
+    );
+    for model in Model::value_variants() {
+        let fits = if hardware.available_memory_bytes >= model.estimated_memory_bytes() {
+            ""
+        } else {
+            " (might not fit in memory)"
+        };
+        let _ = writeln!(
+            out,
+            "  {:<28} ~{:.1}{fits}",
+            model.to_string(),
+            hardware.expected_cpu_rtf(*model)
+        );
+    }
+    out
+}
+
+fn probe_nvidia_gpus() -> Vec<GpuInfo> {
+    let Ok(output) = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=name,memory.total,driver_version",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+    else {
+        return vec![];
+    };
+    if !output.status.success() {
+        return vec![];
+    }
+    parse_nvidia_smi(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_nvidia_smi(output: &str) -> Vec<GpuInfo> {
+    let mut result = vec![];
+    for line in output.lines() {
+        let fields = line.split(',').map(|f| f.trim()).collect::<Vec<_>>();
+        let [name, vram_mib, driver_version] = fields[..] else {
+            continue;
+        };
+        let Ok(vram_mib) = vram_mib.parse::<u64>() else {
+            continue;
+        };
+        result.push(GpuInfo {
+            name: name.to_string(),
+            vram_bytes: vram_mib * 1024 * 1024,
+            driver_version: driver_version.to_string(),
+        })
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hardware(ram_gb: u64, vram_gb: Option<u64>) -> HardwareInfo {
+        HardwareInfo {
+            cpu_brand: "Dummy CPU".to_string(),
+            physical_cores: 4,
+            logical_cores: 8,
+            total_memory_bytes: ram_gb * GB,
+            available_memory_bytes: ram_gb * GB,
+            gpus: vram_gb
+                .map(|vram_gb| GpuInfo {
+                    name: "Dummy".to_string(),
+                    vram_bytes: vram_gb * GB,
+                    driver_version: "1.0".to_string(),
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn cuda(error: Option<&str>) -> Vec<ExecutionProviderStatus> {
+        vec![ExecutionProviderStatus {
+            name: "Cuda",
+            enabled_in_build: true,
+            supported_by_onnxruntime: true,
+            error: error.map(str::to_string),
+        }]
+    }
+
+    #[test]
+    fn selects_the_largest_model_that_fits() {
+        let (model, _) = select_model(&hardware(64, Some(24)), &cuda(None), true);
+        assert_eq!(model.name(), "large");
+        let (model, _) = select_model(&hardware(12, None), &[], false);
+        assert_eq!(model.name(), "medium");
+        let (model, reason) = select_model(&hardware(4, None), &[], false);
+        assert_eq!(model.name(), "small");
+        assert!(reason.contains("4.0 GB of RAM"));
+    }
+
+    #[test]
+    fn leaves_out_the_large_model_on_cpu() {
+        let (model, _) = select_model(&hardware(64, None), &[], false);
+        assert_eq!(model.name(), "medium");
+    }
+
+    #[test]
+    fn uses_vram_only_when_running_on_gpu() {
+        let (model, reason) = select_model(&hardware(64, Some(6)), &cuda(None), true);
+        assert_eq!(model.name(), "small");
+        assert!(reason.contains("VRAM on Dummy"));
+        let (model, _) = select_model(&hardware(64, Some(6)), &cuda(None), false);
+        assert_eq!(model.name(), "medium");
+
+        // A GPU that no execution provider can run on does not count.
+        let (model, reason) = select_model(&hardware(64, Some(6)), &cuda(Some("no CUDA")), true);
+        assert_eq!(model.name(), "medium");
+        assert!(reason.contains("of RAM"));
+        assert!(reason.contains("runs on the CPU"));
+    }
+
+    #[test]
+    fn falls_back_to_small_if_nothing_fits() {
+        let (model, reason) = select_model(&hardware(1, None), &[], false);
+        assert_eq!(model.name(), "small");
+        assert!(reason.contains("might not be enough"));
+    }
+
+    #[test]
+    fn parses_nvidia_smi_output() {
+        let gpus = parse_nvidia_smi("NVIDIA GeForce RTX 3090, 24576, 535.104.05\ngarbage\n");
+        assert_eq!(gpus.len(), 1);
+        assert_eq!(gpus[0].name, "NVIDIA GeForce RTX 3090");
+        assert_eq!(gpus[0].vram_bytes, 24576 * 1024 * 1024);
+        assert_eq!(gpus[0].driver_version, "535.104.05");
+    }
+
+    #[test]
+    fn expected_rtf_scales_with_cores() {
+        let mut hw = hardware(16, None);
+        hw.physical_cores = REFERENCE_CORES;
+        assert_eq!(
+            hw.expected_cpu_rtf(Model::Small),
+            Model::Small.reference_rtf()
+        );
+        hw.physical_cores = REFERENCE_CORES * 2;
+        assert_eq!(
+            hw.expected_cpu_rtf(Model::Small),
+            Model::Small.reference_rtf() / 2.0
+        );
+    }
+
+    #[test]
+    fn reports_hardware() {
+        let providers = vec![
+            ExecutionProviderStatus {
+                name: "Cuda",
+                enabled_in_build: true,
+                supported_by_onnxruntime: true,
+                error: Some("libcudart.so not found".to_string()),
+            },
+            ExecutionProviderStatus {
+                name: "CoreML",
+                enabled_in_build: false,
+                supported_by_onnxruntime: false,
+                error: None,
+            },
+        ];
+        let report = report(&hardware(4, Some(24)), &providers);
+        assert!(report.contains("Dummy (24.0 GB VRAM, driver 1.0)"));
+        assert!(report.contains("Cuda: failed to load: libcudart.so not found"));
+        assert!(report.contains("CoreML: not enabled in this build"));
+        assert!(report.contains("MusicGen Large"));
+        assert!(report.contains("(might not fit in memory)"));
+    }
+}
@@ -0,0 +1,302 @@
+use std::path::{Component, Path};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::custom_models::CustomModel;
+use crate::storage::Storage;
+use crate::storage_ext::StorageExt;
+
+const HUB_URL: &str = "https://huggingface.co";
+/// Tag that compatible repos in the Hugging Face Hub must have for [search] to find them.
+const HUB_TAG: &str = "musicgpt";
+/// Custom model manifest that compatible repos have at their root.
+pub const MANIFEST_FILE: &str = "musicgpt.json";
+/// Directory inside the data directory where models pulled from the hub are stored.
+const HUB_DIR: &str = "hub";
+
+#[derive(Debug, Deserialize)]
+pub struct HubSibling {
+    pub rfilename: String,
+}
+
+/// A model repo as returned by the Hugging Face Hub API.
+#[derive(Debug, Deserialize)]
+pub struct HubModel {
+    pub id: String,
+    #[serde(default)]
+    pub downloads: u64,
+    #[serde(default)]
+    pub likes: u64,
+    #[serde(rename = "lastModified", default)]
+    pub last_modified: Option<String>,
+    #[serde(default)]
+    pub siblings: Vec<HubSibling>,
+}
+
+impl HubModel {
+    /// Only repos that ship a MusicGPT manifest can be pulled.
+    pub fn is_compatible(&self) -> bool {
+        self.siblings.iter().any(|s| s.rfilename == MANIFEST_FILE)
+    }
+}
+
+/// Searches the Hugging Face Hub for repos that can be pulled with [pull]. Only repos
+/// published with the [HUB_TAG] tag and a [MANIFEST_FILE] at their root are found, an
+/// ONNX export alone is not enough.
+pub async fn search(query: &str) -> anyhow::Result<Vec<HubModel>> {
+    let resp = reqwest::Client::new()
+        .get(format!("{HUB_URL}/api/models"))
+        .query(&[
+            ("search", query),
+            ("filter", HUB_TAG),
+            ("full", "true"),
+            ("limit", "50"),
+        ])
+        .send()
+        .await?
+        .error_for_status()?;
+    parse_search(&resp.text().await?)
+}
+
+fn parse_search(body: &str) -> anyhow::Result<Vec<HubModel>> {
+    let models: Vec<HubModel> = serde_json::from_str(body)?;
+    Ok(models.into_iter().filter(|m| m.is_compatible()).collect())
+}
+
+fn local_dir(repo: &str) -> String {
+    format!("{HUB_DIR}/{}", repo.replace('/', "--"))
+}
+
+/// Makes sure a path in a remote manifest cannot escape the repo's local directory.
+fn check_relative(path: &Path) -> anyhow::Result<()> {
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Manifest references {path:?}, which is not a relative path inside the repo"
+        ))
+    }
+}
+
+/// Makes sure a revision is a single segment of the download URLs.
+fn check_revision(revision: &str) -> anyhow::Result<()> {
+    if revision.is_empty() || revision.contains(['/', '?', '#']) || revision.contains("..") {
+        Err(anyhow!(
+            "Invalid revision {revision:?}, it must be a branch, tag or commit name"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes sure a model pulled into `dir` does not shadow another one with the same name.
+/// Pulling the same repo again is fine, as it replaces the model in `dir`.
+async fn check_name<S: Storage>(
+    storage: &S,
+    dir: &str,
+    name: &str,
+    reserved: &[String],
+) -> anyhow::Result<()> {
+    let mut taken = reserved.iter().any(|other| other == name);
+    for (other_dir, other) in installed_in(storage).await? {
+        taken |= other_dir != dir && other.name == name;
+    }
+    match taken {
+        true => Err(anyhow!(
+            "There is already a model named {name}, it cannot be installed again"
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Downloads a model repo from the Hugging Face Hub into the local data directory,
+/// where it will be picked up by [installed] from then on. The name of the model cannot
+/// be one of `reserved`, nor the name of a model pulled from another repo.
+pub async fn pull<S: Storage>(
+    storage: &S,
+    repo: &str,
+    revision: &str,
+    force_download: bool,
+    reserved: &[String],
+) -> anyhow::Result<CustomModel> {
+    check_relative(Path::new(repo))?;
+    check_revision(revision)?;
+    let base_url = format!("{HUB_URL}/{repo}/resolve/{revision}");
+    let manifest = reqwest::get(format!("{base_url}/{MANIFEST_FILE}"))
+        .await?
+        .error_for_status()
+        .map_err(|err| anyhow!("{repo} does not look like a MusicGPT compatible repo: {err}"))?
+        .text()
+        .await?;
+    let mut model: CustomModel = serde_json::from_str(&manifest)
+        .map_err(|err| anyhow!("{repo} has an invalid {MANIFEST_FILE}: {err}"))?;
+    if model.version.is_none() {
+        model.version = Some(format!("{repo}@{revision}"));
+    }
+
+    let dir = local_dir(repo);
+    check_name(storage, &dir, &model.name, reserved).await?;
+
+    let mut remote_file_spec = vec![];
+    for file in model.all_files() {
+        check_relative(file)?;
+        let file = file.to_string_lossy().replace('\\', "/");
+        remote_file_spec.push((format!("{base_url}/{file}"), format!("{dir}/{file}")));
+    }
+    storage
+        .download_many(
+            remote_file_spec,
+            force_download,
+            format!("Downloading {repo} from the Hugging Face Hub"),
+            format!("{repo} downloaded correctly"),
+        )
+        .await?;
+
+    let manifest_file = format!("{dir}/{MANIFEST_FILE}");
+    storage
+        .write(&manifest_file, serde_json::to_vec_pretty(&model)?)
+        .await?;
+    CustomModel::load(&storage.path_buf(&manifest_file))
+}
+
+/// Lists the models previously pulled from the hub. Invalid ones are skipped with a warning.
+pub async fn installed<S: Storage>(storage: &S) -> anyhow::Result<Vec<CustomModel>> {
+    Ok(installed_in(storage)
+        .await?
+        .into_iter()
+        .map(|(_, model)| model)
+        .collect())
+}
+
+/// Like [installed], along with the directory of each model.
+async fn installed_in<S: Storage>(storage: &S) -> anyhow::Result<Vec<(String, CustomModel)>> {
+    let mut models = vec![];
+    for dir in storage.list(HUB_DIR).await? {
+        let manifest_file = format!("{dir}/{MANIFEST_FILE}");
+        if !storage.exists(&manifest_file).await? {
+            continue;
+        }
+        match CustomModel::load(&storage.path_buf(&manifest_file)) {
+            Ok(model) => models.push((dir, model)),
+            Err(err) => warn!("Ignoring model in {dir}: {err}"),
+        }
+    }
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[test]
+    fn parses_search_results() -> anyhow::Result<()> {
+        let models = parse_search(
+            r#"[
+                {
+                    "id": "foo/lofi-musicgen",
+                    "downloads": 12,
+                    "likes": 3,
+                    "lastModified": "2024-05-01T00:00:00.000Z",
+                    "siblings": [{ "rfilename": "musicgpt.json" }, { "rfilename": "config.json" }]
+                },
+                { "id": "bar/not-compatible", "siblings": [{ "rfilename": "config.json" }] }
+            ]"#,
+        )?;
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id, "foo/lofi-musicgen");
+        assert_eq!(models[0].downloads, 12);
+        assert_eq!(models[0].likes, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_repo() {
+        assert!(check_relative(Path::new("fp32/decoder.onnx")).is_ok());
+        assert!(check_relative(Path::new("../decoder.onnx")).is_err());
+        assert!(check_relative(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn rejects_revisions_outside_the_repo() {
+        assert!(check_revision("main").is_ok());
+        assert!(check_revision("v1.0").is_ok());
+        assert!(check_revision("").is_err());
+        assert!(check_revision("../../other/repo").is_err());
+        assert!(check_revision("main/extra").is_err());
+        assert!(check_revision("main?download=1").is_err());
+    }
+
+    #[tokio::test]
+    async fn lists_installed_models() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        assert!(installed(&storage).await?.is_empty());
+
+        let dir = local_dir("foo/lofi");
+        storage
+            .write(
+                &format!("{dir}/config.json"),
+                r#"{
+                    "audio_encoder": { "sampling_rate": 32000 },
+                    "decoder": { "num_attention_heads": 16, "num_hidden_layers": 24, "top_k": 250, "pad_token_id": 2048 },
+                    "text_encoder": { "d_kv": 64 }
+                }"#,
+            )
+            .await?;
+        for file in [
+            "tokenizer.json",
+            "text_encoder.onnx",
+            "decoder_model_merged.onnx",
+            "encodec_decode.onnx",
+        ] {
+            storage.write(&format!("{dir}/{file}"), "").await?;
+        }
+        storage
+            .write(
+                &format!("{dir}/{MANIFEST_FILE}"),
+                r#"{
+                    "name": "lofi",
+                    "config": "config.json",
+                    "tokenizer": "tokenizer.json",
+                    "text_encoder": "text_encoder.onnx",
+                    "decoder_model_merged": "decoder_model_merged.onnx",
+                    "audio_encodec": "encodec_decode.onnx",
+                    "capabilities": { "sample_rate": 32000, "max_secs": 30 }
+                }"#,
+            )
+            .await?;
+        // A broken one is ignored.
+        storage
+            .write(
+                &format!("{}/{MANIFEST_FILE}", local_dir("bar/broken")),
+                "{}",
+            )
+            .await?;
+
+        let models = installed(&storage).await?;
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "lofi");
+
+        // Names are unique, unless the same repo is pulled again.
+        let reserved = ["small".to_string()];
+        check_name(&storage, &dir, "lofi", &reserved).await?;
+        check_name(&storage, &local_dir("bar/other"), "jazz", &reserved).await?;
+        assert!(
+            check_name(&storage, &local_dir("bar/other"), "lofi", &reserved)
+                .await
+                .is_err()
+        );
+        assert!(
+            check_name(&storage, &local_dir("bar/other"), "small", &reserved)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+#[cfg(feature = "onnx")]
+mod affinity;
+pub mod audio;
+#[cfg(feature = "onnx")]
+mod backend;
+#[cfg(feature = "onnx")]
+mod bench;
+#[cfg(feature = "onnx")]
+pub mod cli;
+#[cfg(feature = "onnx")]
+mod config_file;
+#[cfg(feature = "onnx")]
+mod custom_models;
+#[cfg(feature = "onnx")]
+mod disk_space;
+#[cfg(feature = "onnx")]
+pub mod embed;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "onnx")]
+mod gpu;
+#[cfg(feature = "onnx")]
+mod hardware;
+#[cfg(feature = "onnx")]
+mod hub;
+#[cfg(feature = "onnx")]
+mod logging;
+#[cfg(feature = "onnx")]
+mod model_cache;
+#[cfg(feature = "onnx")]
+mod model_hashes;
+#[cfg(feature = "onnx")]
+mod musicgen;
+#[cfg(feature = "onnx")]
+mod musicgen_models;
+#[cfg(feature = "onnx")]
+mod onnxruntime_lib;
+#[cfg(feature = "onnx")]
+pub mod profile;
+#[cfg(feature = "onnx")]
+mod storage;
+#[cfg(feature = "onnx")]
+mod storage_ext;
+#[cfg(feature = "onnx")]
+mod terminal;
+#[cfg(feature = "onnx")]
+mod transcription;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+
+#[cfg(feature = "mock-backend")]
+pub use backend::{
+    run_web_server, ExtendedJobProcessor, Fault, FaultInjectingJobProcessor, JobLimits,
+    JobProcessor, MockJobProcessor, MockModelRegistry, ModelRegistry, RunWebServerOptions,
+};
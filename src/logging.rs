@@ -0,0 +1,114 @@
+//! Output of the logs, either human-readable or as newline-delimited JSON for log
+//! pipelines. JSON events carry the fields of the spans they happened in, like the id of
+//! the job and the index of the segment, so that they can be filtered and grouped.
+
+use clap::ValueEnum;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::time::UtcTime;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+use crate::profile;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Sets up the global subscriber, which also records the spans while profiling.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::new("info,ort=off");
+    let output = match format {
+        LogFormat::Text => text_layer().with_filter(filter).boxed(),
+        LogFormat::Json => json_layer(std::io::stdout).with_filter(filter).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(output)
+        .with(profile::layer())
+        .init();
+}
+
+fn text_layer<S: Subscriber + for<'a> LookupSpan<'a>>() -> impl Layer<S> {
+    let time_format = time::format_description::parse(
+        "[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]",
+    )
+    .expect("Failed to create timestamp format");
+    let format = fmt::format()
+        .with_target(false)
+        .with_timer(UtcTime::new(time_format));
+    fmt::layer().event_format(format)
+}
+
+/// One JSON object per line, with the fields of the event at the top level and the
+/// spans it happened in, outermost first, under `spans`.
+fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(true)
+        .with_target(false)
+        .with_timer(UtcTime::rfc_3339())
+        .with_writer(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::{info, info_span, warn};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_events_with_their_spans_as_json_lines() -> anyhow::Result<()> {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(json_layer(move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _job = info_span!("job", job_id = "abc", model = "small").entered();
+            info!("Generating 2 segments");
+            let _segment = info_span!("segment", segment = 1usize).entered();
+            warn!(attempt = 1, "Segment failed");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        let events = output
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["level"], "INFO");
+        assert_eq!(events[0]["message"], "Generating 2 segments");
+        assert_eq!(
+            events[0]["spans"],
+            serde_json::json!([{ "name": "job", "job_id": "abc", "model": "small" }])
+        );
+        assert_eq!(events[1]["attempt"], 1);
+        assert_eq!(events[1]["spans"][1]["segment"], 1);
+        assert!(events[1]["timestamp"].is_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use regex::Regex;
+
+use crate::cli::Model;
+use crate::musicgen_models::remote_file_spec;
+use crate::storage::Storage;
+
+const USAGE_FILE: &str = "model-usage.json";
+const HUB_DIR: &str = "hub";
+
+/// A model that takes space in the data directory.
+#[derive(Debug, PartialEq)]
+pub struct CachedModel {
+    pub name: String,
+    /// Files in the data directory that belong to this model. Some of them might
+    /// be shared with other models.
+    pub files: Vec<String>,
+    pub size_bytes: u64,
+    /// Milliseconds since epoch of the last time the model was loaded.
+    pub last_used: Option<u128>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CacheReport {
+    pub models: Vec<CachedModel>,
+    /// Files in the model directories that no known model uses, like previous versions
+    /// of the models or interrupted downloads.
+    pub unused: Vec<(String, u64)>,
+    /// Size of all the files in the model directories. Files shared by several models
+    /// are only counted once.
+    pub total_bytes: u64,
+}
+
+/// Records that a model was just loaded, used for deciding which models to keep.
+pub async fn record_usage<S: Storage>(storage: &S, name: &str) -> anyhow::Result<()> {
+    let mut usage = load_usage(storage).await?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    usage.insert(name.to_string(), now);
+    Ok(storage
+        .write(USAGE_FILE, serde_json::to_vec_pretty(&usage)?)
+        .await?)
+}
+
+async fn load_usage<S: Storage>(storage: &S) -> anyhow::Result<HashMap<String, u128>> {
+    match storage.read(USAGE_FILE).await? {
+        Some(content) => Ok(serde_json::from_slice(&content).unwrap_or_default()),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Lists recursively all the files under a directory of the storage, relative to its root.
+fn walk(root: &Path, dir: &str, out: &mut Vec<(String, u64)>) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(root.join(dir)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let rel = format!("{dir}/{}", entry.file_name().to_string_lossy());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(root, &rel, out)?;
+        } else {
+            out.push((rel, metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+/// Inspects the directories where models are downloaded.
+pub async fn report<S: Storage>(storage: &S) -> anyhow::Result<CacheReport> {
+    let root = storage.path_buf("");
+    let version_dir = Regex::new(r"^v\d+$")?;
+    let mut files = vec![];
+    if root.exists() {
+        for dir in std::fs::read_dir(&root)? {
+            let name = dir?.file_name().to_string_lossy().to_string();
+            if version_dir.is_match(&name) || name == HUB_DIR {
+                walk(&root, &name, &mut files)?;
+            }
+        }
+    }
+    let sizes: HashMap<String, u64> = files.iter().cloned().collect();
+    let total_bytes = sizes.values().sum();
+    let usage = load_usage(storage).await?;
+
+    let mut models = vec![];
+    let mut used = HashSet::new();
+    for model in Model::value_variants() {
+        let name = model.name();
+        let mut model_files = vec![];
+        for split in [false, true] {
+            for (_, local_file) in remote_file_spec(*model, split) {
+                used.insert(local_file.to_string());
+                if sizes.contains_key(local_file) && !model_files.contains(&local_file.to_string())
+                {
+                    model_files.push(local_file.to_string());
+                }
+            }
+        }
+        // Only models whose files are all present are considered installed.
+        let installed = [false, true].iter().any(|split| {
+            remote_file_spec(*model, *split)
+                .iter()
+                .all(|(_, f)| sizes.contains_key(*f))
+        });
+        if installed {
+            models.push(CachedModel {
+                size_bytes: model_files.iter().map(|f| sizes[f]).sum(),
+                files: model_files,
+                last_used: usage.get(&name).copied(),
+                name,
+            });
+        }
+    }
+    for dir in storage.list(HUB_DIR).await? {
+        let prefix = format!("{dir}/");
+        let model_files: Vec<String> = files
+            .iter()
+            .filter(|(f, _)| f.starts_with(&prefix) && !f.ends_with(".temp"))
+            .map(|(f, _)| f.clone())
+            .collect();
+        let Some(manifest) = storage.read(&format!("{dir}/musicgpt.json")).await? else {
+            continue;
+        };
+        let Ok(manifest) = serde_json::from_slice::<serde_json::Value>(&manifest) else {
+            continue;
+        };
+        let name = manifest["name"].as_str().unwrap_or(&dir).to_string();
+        used.extend(model_files.iter().cloned());
+        models.push(CachedModel {
+            size_bytes: model_files.iter().map(|f| sizes[f]).sum(),
+            files: model_files,
+            last_used: usage.get(&name).copied(),
+            name,
+        });
+    }
+
+    let unused = files
+        .into_iter()
+        .filter(|(f, _)| !used.contains(f))
+        .collect();
+    Ok(CacheReport {
+        models,
+        unused,
+        total_bytes,
+    })
+}
+
+/// Decides which files to remove: the unused ones, and if `keep_last` is provided, the
+/// ones of all the models but the `keep_last` most recently used and the ones in `keep`.
+pub fn plan_gc(report: &CacheReport, keep_last: Option<usize>, keep: &[String]) -> Vec<String> {
+    let mut to_remove: Vec<String> = report.unused.iter().map(|(f, _)| f.clone()).collect();
+    let Some(keep_last) = keep_last else {
+        return to_remove;
+    };
+    let mut by_usage = report.models.iter().collect::<Vec<_>>();
+    by_usage.sort_by_key(|m| Reverse(m.last_used));
+    let (kept, evicted): (Vec<_>, Vec<_>) = by_usage
+        .into_iter()
+        .enumerate()
+        .partition(|(i, m)| *i < keep_last || keep.contains(&m.name));
+    // Files shared with a kept model must stay.
+    let kept_files: HashSet<&String> = kept.iter().flat_map(|(_, m)| m.files.iter()).collect();
+    for (_, model) in evicted {
+        for file in model.files.iter() {
+            if !kept_files.contains(file) && !to_remove.contains(file) {
+                to_remove.push(file.clone())
+            }
+        }
+    }
+    to_remove
+}
+
+/// Removes the given files, returning the amount of bytes freed.
+pub async fn remove<S: Storage>(storage: &S, files: &[String]) -> anyhow::Result<u64> {
+    let mut freed = 0;
+    for file in files {
+        if let Ok(metadata) = std::fs::metadata(storage.path_buf(file)) {
+            freed += metadata.len();
+        }
+        storage.rm(file).await?;
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    async fn install<S: Storage>(storage: &S, model: Model) -> anyhow::Result<()> {
+        for (_, file) in remote_file_spec(model, false) {
+            storage.write(file, "12345").await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_models_and_unused_files() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        install(&storage, Model::Small).await?;
+        storage.write("v0/small/decoder.onnx", "old").await?;
+        storage
+            .write("v1/small_fp32/encodec_decode.onnx.temp", "partial")
+            .await?;
+        storage.write("chats/foo.json", "not a model").await?;
+        record_usage(&storage, "small").await?;
+
+        let report = report(&storage).await?;
+        assert_eq!(report.models.len(), 1);
+        assert_eq!(report.models[0].name, "small");
+        assert_eq!(report.models[0].size_bytes, 5 * 5);
+        assert_eq!(report.total_bytes, 5 * 5 + 3 + 7);
+        assert!(report.models[0].last_used.is_some());
+        let mut unused = report.unused.clone();
+        unused.sort();
+        assert_eq!(
+            unused,
+            vec![
+                ("v0/small/decoder.onnx".to_string(), 3),
+                ("v1/small_fp32/encodec_decode.onnx.temp".to_string(), 7),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_the_most_recently_used_models() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        install(&storage, Model::Small).await?;
+        install(&storage, Model::SmallQuant).await?;
+        install(&storage, Model::Medium).await?;
+        record_usage(&storage, "medium").await?;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        record_usage(&storage, "small-quant").await?;
+
+        let report = report(&storage).await?;
+        assert!(plan_gc(&report, None, &[]).is_empty());
+
+        let to_remove = plan_gc(&report, Some(1), &[]);
+        // Small shares the text encoder, config and tokenizer with the kept small-quant.
+        assert!(to_remove.contains(&"v1/small_fp32/decoder_model_merged.onnx".to_string()));
+        assert!(!to_remove.contains(&"v1/small_fp32/text_encoder.onnx".to_string()));
+        assert!(to_remove.contains(&"v1/medium_fp32/decoder_model_merged.onnx".to_string()));
+        assert!(!to_remove.contains(&"v1/small_i8/decoder_model_merged.onnx".to_string()));
+
+        let to_remove = plan_gc(&report, Some(1), &["medium".to_string()]);
+        assert!(!to_remove.contains(&"v1/medium_fp32/decoder_model_merged.onnx".to_string()));
+
+        let freed = remove(&storage, &to_remove).await?;
+        assert_eq!(freed, 5 * to_remove.len() as u64);
+        let report = super::report(&storage).await?;
+        let mut names = report
+            .models
+            .iter()
+            .map(|m| m.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["medium", "small-quant"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::storage::Storage;
+
+const CACHE_FILE: &str = "model-hashes.json";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct CachedHash {
+    size: u64,
+    modified: u128,
+    sha256: String,
+}
+
+/// Computes the SHA-256 of model files. Hashing multi-GB files is slow, so results are
+/// cached in the data directory and only recomputed when a file's size or modification
+/// time changes.
+pub struct FileHasher<S: Storage> {
+    storage: S,
+    cache: HashMap<PathBuf, CachedHash>,
+}
+
+impl<S: Storage> FileHasher<S> {
+    pub async fn new(storage: S) -> anyhow::Result<Self> {
+        let cache = match storage.read(CACHE_FILE).await? {
+            Some(content) => serde_json::from_slice(&content).unwrap_or_default(),
+            None => HashMap::new(),
+        };
+        Ok(Self { storage, cache })
+    }
+
+    /// SHA-256 of a single file, as a hex string.
+    pub async fn hash_file(&mut self, file: &Path) -> anyhow::Result<String> {
+        let metadata = tokio::fs::metadata(file).await?;
+        let size = metadata.len();
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        if let Some(cached) = self.cache.get(file) {
+            if cached.size == size && cached.modified == modified {
+                return Ok(cached.sha256.clone());
+            }
+        }
+
+        info!("Computing checksum of {file:?}, this only needs to be done once");
+        let path = file.to_path_buf();
+        let sha256 = tokio::task::spawn_blocking(move || sha256_file(&path)).await??;
+        self.cache.insert(
+            file.to_path_buf(),
+            CachedHash {
+                size,
+                modified,
+                sha256: sha256.clone(),
+            },
+        );
+        self.storage
+            .write(CACHE_FILE, serde_json::to_vec_pretty(&self.cache)?)
+            .await?;
+        Ok(sha256)
+    }
+
+    /// Single hash identifying a set of files, derived from their names and contents.
+    pub async fn hash_files(&mut self, files: &[PathBuf]) -> anyhow::Result<String> {
+        let mut hasher = Sha256::new();
+        for file in files {
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            hasher.update(file_name.as_bytes());
+            hasher.update(self.hash_file(file).await?.as_bytes());
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn hashes_and_caches_files() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        storage.write("models/a.onnx", "foo").await?;
+        storage.write("models/b.onnx", "bar").await?;
+        let a = storage.path_buf("models/a.onnx");
+        let b = storage.path_buf("models/b.onnx");
+
+        let mut hasher = FileHasher::new(storage.clone()).await?;
+        assert_eq!(
+            hasher.hash_file(&a).await?,
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        );
+        let combined = hasher.hash_files(&[a.clone(), b.clone()]).await?;
+        assert!(storage.exists(CACHE_FILE).await?);
+
+        // A new hasher reuses the cache.
+        let mut hasher = FileHasher::new(storage.clone()).await?;
+        assert_eq!(hasher.cache.len(), 2);
+        assert_eq!(hasher.hash_files(&[a.clone(), b.clone()]).await?, combined);
+
+        // Changing the content changes the hash.
+        storage.write("models/b.onnx", "other content").await?;
+        assert_ne!(hasher.hash_files(&[a.clone(), b.clone()]).await?, combined);
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! Generation that continues where a previous one stopped. While the decoder still has
+//! room for more steps and the prompt is the same, its key/value cache is kept and the
+//! decoding just goes on. Otherwise, the last frames of the previous generation are given
+//! to a fresh decoder in a single run, and the new frames are sampled after them.
+
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use rand::rngs::StdRng;
+
+use crate::musicgen::delay_pattern_mask_ids::DelayedPatternMaskIds;
+use crate::musicgen::logits::Logits;
+use crate::musicgen::music_gen_decoder::sampling_rng;
+use crate::musicgen::speculative_decoder::{sample_step, DecoderState, DecoderSteps};
+
+/// The decoder was trained on up to 30 seconds of frames, so longer caches are not kept.
+const MAX_CACHED_STEPS: usize = 1500;
+
+/// Where a generation stopped: its decoder state, and the frames it generated.
+pub struct Continuation {
+    steps: Arc<dyn DecoderSteps>,
+    prompt: String,
+    ids: DelayedPatternMaskIds<4>,
+    state: DecoderState,
+    rng: StdRng,
+    /// The frames given to the decoder before the generated ones, followed by them.
+    frames: Vec<[i64; 4]>,
+}
+
+impl Continuation {
+    pub fn frames(&self) -> &[[i64; 4]] {
+        &self.frames
+    }
+
+    /// Whether generating `len` more frames of `prompt` can keep the decoder state.
+    pub fn reusable(&self, prompt: &str, len: usize) -> bool {
+        // The delay pattern needs 3 more steps than frames.
+        self.prompt == prompt && self.state.len() + len + 3 <= MAX_CACHED_STEPS
+    }
+
+    /// Forgets the frames after the first `len` ones, along with the steps that sampled
+    /// them, like when the audio of the generation was cut.
+    pub fn truncate(&mut self, len: usize) -> ort::Result<()> {
+        if len >= self.frames.len() {
+            return Ok(());
+        }
+        let cut = self.frames.len() - len;
+        self.frames.truncate(len);
+        // The cache has all the steps but the last one pushed.
+        let steps = self.ids.len().saturating_sub(cut);
+        self.ids.truncate(steps);
+        self.steps.truncate(&mut self.state, steps)
+    }
+}
+
+/// Decodes `len` frames after the ones of `from`, sending them as they get sampled. The
+/// decoder state of `from` is kept if `started` is None, otherwise the decoding goes on
+/// from the state [DecoderSteps::start] returned, given the last `context` frames of
+/// `from` first. The receiver of
+/// the frames must be drained before joining the thread, which returns where the
+/// generation stopped unless it failed or there was nothing to generate.
+pub fn continue_tokens(
+    steps: Arc<dyn DecoderSteps>,
+    prompt: &str,
+    started: Option<(DecoderState, Logits)>,
+    from: Option<Continuation>,
+    context: usize,
+    len: usize,
+    seed: Option<u64>,
+) -> (
+    Receiver<ort::Result<[i64; 4]>>,
+    JoinHandle<Option<Continuation>>,
+) {
+    let prompt = prompt.to_string();
+    let (tx, rx) = std::sync::mpsc::channel::<ort::Result<[i64; 4]>>();
+    let handle = std::thread::spawn(move || {
+        let result = (|| {
+            if len == 0 {
+                return Ok(None);
+            }
+            let top_k = steps.top_k();
+            let pad_token_id = steps.pad_token_id();
+            let (mut ids, mut state, mut logits, mut rng, mut frames, forced) = match started {
+                None => {
+                    let from = from.ok_or_else(|| ort::Error::new("Nothing to continue"))?;
+                    // The last step was sampled, but not given to the decoder yet.
+                    (from.ids, from.state, None, from.rng, from.frames, vec![])
+                }
+                Some((mut state, logits)) => {
+                    let (rng, forced) = match from {
+                        Some(from) => {
+                            let start = from.frames.len().saturating_sub(context);
+                            (from.rng, from.frames[start..].to_vec())
+                        }
+                        None => (sampling_rng(seed), vec![]),
+                    };
+                    // The steps with all their frames in the context are given at once.
+                    let mut ids = DelayedPatternMaskIds::<4>::new();
+                    for step in 0..forced.len() {
+                        ids.push(delayed(&forced, step, pad_token_id));
+                    }
+                    let feed = (0..ids.len())
+                        .map(|step| ids.delayed_masked(step, pad_token_id))
+                        .collect::<Vec<_>>();
+                    let logits = match feed.is_empty() {
+                        true => logits,
+                        false => last(steps.run(&mut state, &feed)?)?,
+                    };
+                    (ids, state, Some(logits), rng, forced.clone(), forced)
+                }
+            };
+            // Frames that end before this one were already sent.
+            let first = frames.len();
+            let mut sent = 0;
+            while sent < len {
+                let logits = match logits.take() {
+                    Some(logits) => logits,
+                    None => {
+                        let feed = [ids.last_delayed_masked(pad_token_id)];
+                        last(steps.run(&mut state, &feed)?)?
+                    }
+                };
+                let step = ids.len();
+                let mut sampled = sample_step(&logits.top_k_probs(top_k), &mut rng);
+                // The codebooks of the context frames that are still to be given.
+                for (codebook, token) in sampled.iter_mut().enumerate() {
+                    if let Some(frame) = step.checked_sub(codebook).and_then(|f| forced.get(f)) {
+                        *token = frame[codebook];
+                    }
+                }
+                ids.push(sampled);
+                if let Some(frame) = ids.last_de_delayed() {
+                    if step - 3 >= first {
+                        frames.push(frame);
+                        sent += 1;
+                        if tx.send(Ok(frame)).is_err() {
+                            return Err(ort::Error::new("Aborted"));
+                        }
+                    }
+                }
+            }
+            Ok(Some(Continuation {
+                steps: steps.clone(),
+                prompt,
+                ids,
+                state,
+                rng,
+                frames,
+            }))
+        })();
+        match result {
+            Ok(continuation) => continuation,
+            Err(err) => {
+                let _ = tx.send(Err(err));
+                None
+            }
+        }
+    });
+    (rx, handle)
+}
+
+fn last(mut logits: Vec<Logits>) -> ort::Result<Logits> {
+    logits
+        .pop()
+        .ok_or_else(|| ort::Error::new("The decoder returned no logits"))
+}
+
+/// The ids of `step` in the delay pattern of `frames`, with the padding for the codebooks
+/// whose frame is not there.
+fn delayed(frames: &[[i64; 4]], step: usize, pad_token_id: i64) -> [i64; 4] {
+    let mut ids = [pad_token_id; 4];
+    for (codebook, id) in ids.iter_mut().enumerate() {
+        if let Some(frame) = step.checked_sub(codebook).and_then(|f| frames.get(f)) {
+            *id = frame[codebook];
+        }
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use ndarray::Array;
+    use ort::value::DynValue;
+
+    use super::*;
+
+    /// Samples the number of steps in its cache as every token, and records what it is fed.
+    #[derive(Default)]
+    struct CountingSteps {
+        starts: Mutex<usize>,
+        fed: Mutex<Vec<Vec<[i64; 4]>>>,
+    }
+
+    fn logits(token: usize) -> Logits {
+        let mut logits = Array::zeros((4, 16));
+        logits.column_mut(token % 16).fill(10.0);
+        Logits::from(logits.into_dyn())
+    }
+
+    impl DecoderSteps for CountingSteps {
+        fn start(&self, _: &DynValue, _: &DynValue) -> ort::Result<(DecoderState, Logits)> {
+            unreachable!("The tests start the decoder themselves")
+        }
+
+        fn run(&self, state: &mut DecoderState, steps: &[[i64; 4]]) -> ort::Result<Vec<Logits>> {
+            self.fed.lock().unwrap().push(steps.to_vec());
+            let len = state.len();
+            *state = DecoderState::empty(len + steps.len());
+            Ok((1..=steps.len()).map(|i| logits(len + i)).collect())
+        }
+
+        fn truncate(&self, state: &mut DecoderState, len: usize) -> ort::Result<()> {
+            *state = DecoderState::empty(state.len().min(len));
+            Ok(())
+        }
+
+        fn top_k(&self) -> usize {
+            1
+        }
+
+        fn pad_token_id(&self) -> i64 {
+            99
+        }
+    }
+
+    fn generate(
+        steps: &Arc<CountingSteps>,
+        prompt: &str,
+        start: bool,
+        from: Option<Continuation>,
+        len: usize,
+    ) -> (Vec<[i64; 4]>, Continuation) {
+        let started = start.then(|| {
+            *steps.starts.lock().unwrap() += 1;
+            (DecoderState::empty(1), logits(1))
+        });
+        let (rx, handle) = continue_tokens(steps.clone(), prompt, started, from, 2, len, Some(0));
+        let frames = rx.into_iter().collect::<ort::Result<Vec<_>>>().unwrap();
+        (frames, handle.join().unwrap().unwrap())
+    }
+
+    #[test]
+    fn continues_from_the_cache_or_from_the_last_frames() {
+        let steps = Arc::new(CountingSteps::default());
+        let (frames, first) = generate(&steps, "a", true, None, 5);
+        assert_eq!(frames[0], [1, 2, 3, 4]);
+        assert_eq!(frames[4], [5, 6, 7, 8]);
+
+        // The same prompt keeps decoding where it stopped, without starting again.
+        assert!(first.reusable("a", 2));
+        steps.fed.lock().unwrap().clear();
+        let (frames, reused) = generate(&steps, "a", false, Some(first), 2);
+        assert_eq!(frames, [[6, 7, 8, 9], [7, 8, 9, 10]]);
+        assert_eq!(*steps.starts.lock().unwrap(), 1);
+        assert_eq!(*steps.fed.lock().unwrap(), [[[8; 4]], [[9; 4]]]);
+        assert_eq!(reused.frames().len(), 7);
+
+        // Another prompt starts again, given the last frames at once.
+        assert!(!reused.reusable("b", 2));
+        steps.fed.lock().unwrap().clear();
+        let (frames, mut restarted) = generate(&steps, "b", true, Some(reused), 2);
+        assert_eq!(frames, [[3, 4, 5, 6], [4, 5, 6, 7]]);
+        assert_eq!(*steps.starts.lock().unwrap(), 2);
+        assert_eq!(
+            steps.fed.lock().unwrap()[0],
+            [[6, 99, 99, 99], [7, 7, 99, 99]]
+        );
+        assert_eq!(
+            restarted.frames(),
+            [[6, 7, 8, 9], [7, 8, 9, 10], [3, 4, 5, 6], [4, 5, 6, 7]]
+        );
+
+        restarted.truncate(3).unwrap();
+        assert_eq!(restarted.frames().len(), 3);
+        assert!(restarted.reusable("b", MAX_CACHED_STEPS - 9));
+        assert!(!restarted.reusable("b", MAX_CACHED_STEPS - 8));
+    }
+
+    #[test]
+    fn delays_the_context_frames() {
+        let frames = [[1, 2, 3, 4], [5, 6, 7, 8]];
+        let mut ids = DelayedPatternMaskIds::<4>::new();
+        for step in 0..5 {
+            ids.push(delayed(&frames, step, 0));
+        }
+        assert_eq!(ids.step(0), [1, 0, 0, 0]);
+        assert_eq!(ids.step(1), [5, 2, 0, 0]);
+        assert_eq!(ids.step(3), [0, 0, 7, 4]);
+        assert_eq!(ids.last_de_delayed(), Some([5, 6, 7, 8]));
+    }
+}
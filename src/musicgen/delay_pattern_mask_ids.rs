@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DelayedPatternMaskIds<const N: usize> {
     batches: [Vec<i64>; N],
 }
@@ -40,6 +40,39 @@ impl<const N: usize> DelayedPatternMaskIds<N> {
         result
     }
 
+    /// How many steps were pushed.
+    pub fn len(&self) -> usize {
+        self.batches[0].len()
+    }
+
+    /// Forgets the steps after the first `len` ones.
+    pub fn truncate(&mut self, len: usize) {
+        for batch in &mut self.batches {
+            batch.truncate(len);
+        }
+    }
+
+    /// The token ids pushed in `step`.
+    pub fn step(&self, step: usize) -> [i64; N] {
+        let mut result = [0; N];
+        for (i, item) in result.iter_mut().enumerate() {
+            *item = self.batches[i][step];
+        }
+        result
+    }
+
+    /// Same as [DelayedPatternMaskIds::last_delayed_masked], but for the ids pushed in
+    /// `step` instead of the last ones.
+    pub fn delayed_masked(&self, step: usize, pad_token_id: i64) -> [i64; N] {
+        let mut result = self.step(step);
+        for (i, item) in result.iter_mut().enumerate() {
+            if step < i {
+                *item = pad_token_id
+            }
+        }
+        result
+    }
+
     pub fn last_de_delayed(&self) -> Option<[i64; N]> {
         // We want to gather the last diagonal set of numbers avoiding Ps
         // (e.g. [(0,0), (1,1), (2,2), (3,3)])
@@ -79,6 +112,28 @@ mod tests {
         assert_eq!(input_ids.last_delayed_masked(0), [17, 18, 19, 20]);
     }
 
+    #[test]
+    fn delayed_masked() {
+        let mut input_ids = DelayedPatternMaskIds::<4>::new();
+        let mut masked = vec![];
+        for step in [
+            [1, 2, 3, 4],
+            [5, 6, 7, 8],
+            [9, 10, 11, 12],
+            [13, 14, 15, 16],
+        ] {
+            input_ids.push(step);
+            masked.push(input_ids.last_delayed_masked(0));
+        }
+        for (step, masked) in masked.into_iter().enumerate() {
+            assert_eq!(input_ids.delayed_masked(step, 0), masked);
+        }
+        assert_eq!(input_ids.len(), 4);
+        assert_eq!(input_ids.step(1), [5, 6, 7, 8]);
+        input_ids.truncate(2);
+        assert_eq!(input_ids.last_delayed_masked(0), [5, 6, 0, 0]);
+    }
+
     #[test]
     fn last_de_delayed() {
         let mut input_ids = DelayedPatternMaskIds::<4>::new();
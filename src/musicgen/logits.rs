@@ -2,12 +2,12 @@ use half::f16;
 use std::fmt::{Debug, Formatter};
 use std::ops::{Deref, DerefMut};
 
-use ndarray::{s, Array, Array2, Axis, Ix2, Ix3, IxDyn};
+use ndarray::{s, Array, Array2, Array3, ArrayView1, Axis, Ix2, Ix3, IxDyn};
 use num_traits::FloatConst;
 use ort::tensor::ArrayExtensions;
 use ort::value::DynValue;
 use rand::distributions::WeightedIndex;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
 pub struct Logits(Array2<f32>);
 
@@ -58,23 +58,23 @@ impl Debug for Logits {
 
 impl Logits {
     pub fn from_3d_dyn_value(value: &DynValue) -> ort::Result<Self> {
-        let arr = if let Ok(res) = value.try_extract_tensor::<f32>() {
-            res.into_owned()
-        } else {
-            let arr = value.try_extract_tensor::<f16>()?;
-            arr.mapv(f32::from)
-        };
-
-        let arr = arr
-            .into_dimensionality::<Ix3>()
-            .expect("Expected 3 dimensions");
         // logits come in the following shape float32[batch_size,decoder_sequence_length,2048]
         // based on transformers.js we can assume that decoder_sequence_length is going
         // to be 1, so we can just remove it.
-        let arr = arr.remove_axis(Axis(1));
+        let arr = extract_3d(value)?.remove_axis(Axis(1));
         Ok(Self(arr))
     }
 
+    /// Same as [Logits::from_3d_dyn_value], but for a run that was given several steps at
+    /// once, returning the logits after each of them.
+    pub fn steps_from_3d_dyn_value(value: &DynValue) -> ort::Result<Vec<Self>> {
+        let arr = extract_3d(value)?;
+        Ok(arr
+            .axis_iter(Axis(1))
+            .map(|step| Self(step.to_owned()))
+            .collect())
+    }
+
     pub fn apply_free_guidance(self, guidance_scale: usize) -> Self {
         if self.0.dim().0 % 2 != 0 {
             panic!("In order to apply free guidance to the logits, the first size of the first dimension must be even")
@@ -96,40 +96,103 @@ impl Logits {
     /// # Arguments
     ///
     /// * `k`: Take into account only top k logits in each batch
+    /// * `rng`: Source of randomness, seeded for reproducible samples
     ///
     /// returns: Vec<(i64, f32), Global> the per-batch sample
-    pub fn sample(&self, k: usize) -> Vec<(i64, f32)> {
-        let mut result = vec![];
+    pub fn sample(&self, k: usize, rng: &mut impl Rng) -> Vec<(i64, f32)> {
         let softmax_logits = self.0.softmax(Axis(1));
-        for batch in softmax_logits.axis_iter(Axis(0)) {
-            let k = k.min(batch.len());
-
-            // Vec<(token_id, softmax_prob)>
-            let mut softmax_logits_batch = batch
-                .iter()
-                .enumerate()
-                .map(|(i, e)| (i as i64, *e))
-                .collect::<Vec<_>>();
+        softmax_logits
+            .axis_iter(Axis(0))
+            .map(|batch| sample_softmax(batch, k, rng))
+            .collect()
+    }
 
-            // Sort based on softmax_prob in order to bring the most probable tokens to the front.
-            softmax_logits_batch.sort_by(|a, b| {
-                b.1.partial_cmp(&a.1)
+    /// The probabilities [Logits::sample] samples each batch entry from: the softmax of
+    /// the top k logits, and zero for the rest.
+    pub fn top_k_probs(&self, k: usize) -> Array2<f32> {
+        let mut probs = self.0.softmax(Axis(1));
+        for mut batch in probs.axis_iter_mut(Axis(0)) {
+            let k = k.min(batch.len());
+            let mut sorted = batch.to_vec();
+            sorted.sort_by(|a, b| {
+                b.partial_cmp(a)
                     .expect("Could not compare two numbers in order to sort them")
             });
-            // Trim based on provided k.
-            softmax_logits_batch = softmax_logits_batch[0..k].to_vec();
-            // Create a distribution based on the softmax probabilities.
-            let distribution = WeightedIndex::new(softmax_logits_batch.iter().map(|e| e.1))
-                .expect("Could not create WeightedIndex distribution");
-            // Sample a random index based on the softmax probabilities.
-            let (idx, softmax_prob) = softmax_logits_batch[thread_rng().sample(distribution)];
-            // based on JS implementation:
-            //  Math.log(probabilities[sampledIndex])
-            // In JS, Math.log uses euler's number base.
-            result.push((idx, softmax_prob.log(f32::E())))
+            let threshold = sorted[k.max(1) - 1];
+            // Ties with the k-th probability are kept, they are as likely as it.
+            batch.mapv_inplace(|p| if p >= threshold { p } else { 0.0 });
+            let total = batch.sum();
+            batch /= total;
         }
-        result
+        probs
     }
+
+    /// Same as [Logits::sample], but splits the batch in as many consecutive groups of
+    /// the same size as `rngs`, sampling each group with its own source of randomness. A
+    /// group samples the same tokens as if it was sampled alone with the same rng.
+    pub fn sample_groups<R: Rng>(&self, k: usize, rngs: &mut [R]) -> Vec<Vec<(i64, f32)>> {
+        let softmax_logits = self.0.softmax(Axis(1));
+        let group_len = softmax_logits.dim().0 / rngs.len().max(1);
+        rngs.iter_mut()
+            .enumerate()
+            .map(|(i, rng)| {
+                softmax_logits
+                    .slice(s![i * group_len..(i + 1) * group_len, ..])
+                    .axis_iter(Axis(0))
+                    .map(|batch| sample_softmax(batch, k, rng))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn extract_3d(value: &DynValue) -> ort::Result<Array3<f32>> {
+    let arr = if let Ok(res) = value.try_extract_tensor::<f32>() {
+        res.into_owned()
+    } else {
+        let arr = value.try_extract_tensor::<f16>()?;
+        arr.mapv(f32::from)
+    };
+    Ok(arr
+        .into_dimensionality::<Ix3>()
+        .expect("Expected 3 dimensions"))
+}
+
+/// Samples an index from `probs`, which must not be all zeros.
+pub fn sample_probs(probs: ArrayView1<f32>, rng: &mut impl Rng) -> i64 {
+    let distribution =
+        WeightedIndex::new(probs.iter()).expect("Could not create WeightedIndex distribution");
+    rng.sample(distribution) as i64
+}
+
+/// Samples one entry of a batch of softmax probabilities, returning the sampled index and
+/// its log probability.
+fn sample_softmax(batch: ArrayView1<f32>, k: usize, rng: &mut impl Rng) -> (i64, f32) {
+    let k = k.min(batch.len());
+
+    // Vec<(token_id, softmax_prob)>
+    let mut softmax_logits_batch = batch
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (i as i64, *e))
+        .collect::<Vec<_>>();
+
+    // Sort based on softmax_prob in order to bring the most probable tokens to the front.
+    softmax_logits_batch.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .expect("Could not compare two numbers in order to sort them")
+    });
+    // Trim based on provided k.
+    softmax_logits_batch = softmax_logits_batch[0..k].to_vec();
+    // Create a distribution based on the softmax probabilities.
+    let distribution = WeightedIndex::new(softmax_logits_batch.iter().map(|e| e.1))
+        .expect("Could not create WeightedIndex distribution");
+    // Sample a random index based on the softmax probabilities.
+    let (idx, softmax_prob) = softmax_logits_batch[rng.sample(distribution)];
+    // based on JS implementation:
+    //  Math.log(probabilities[sampledIndex])
+    // In JS, Math.log uses euler's number base.
+    (idx, softmax_prob.log(f32::E()))
 }
 
 #[cfg(test)]
@@ -142,4 +205,57 @@ mod tests {
         let logits = logits.apply_free_guidance(3);
         assert_eq!(logits.shape(), &[1, 3]);
     }
+
+    #[test]
+    fn same_seed_samples_the_same_tokens() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let logits = Logits::from(Array::from(vec![[0.0f32; 64]; 4]).into_dyn());
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10)
+                .flat_map(|_| logits.sample(64, &mut rng))
+                .map(|(token, _)| token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sample(7), sample(7));
+        assert_ne!(sample(7), sample(8));
+    }
+
+    #[test]
+    fn groups_sample_the_same_tokens_as_alone() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let row = |i: usize| (0..64).map(|j| ((i * j) % 7) as f32).collect::<Vec<_>>();
+        let rows = (0..8).map(row).collect::<Vec<_>>();
+        let logits = |rows: &[Vec<f32>]| {
+            let flat = rows.concat();
+            Logits::from(
+                Array::from_shape_vec((rows.len(), 64), flat)
+                    .unwrap()
+                    .into_dyn(),
+            )
+        };
+
+        let mut rngs = [StdRng::seed_from_u64(1), StdRng::seed_from_u64(2)];
+        let grouped = logits(&rows).sample_groups(8, &mut rngs);
+        assert_eq!(grouped.len(), 2);
+        for (i, seed) in [1, 2].into_iter().enumerate() {
+            let alone =
+                logits(&rows[i * 4..(i + 1) * 4]).sample(8, &mut StdRng::seed_from_u64(seed));
+            assert_eq!(grouped[i], alone);
+        }
+    }
+
+    #[test]
+    fn top_k_probs_are_what_is_sampled_from() {
+        let logits = Logits::from(Array::from(vec![[1.0f32, 3.0, 2.0, 0.0]]).into_dyn());
+        let probs = logits.top_k_probs(2);
+        assert_eq!(probs[[0, 0]], 0.0);
+        assert_eq!(probs[[0, 3]], 0.0);
+        assert!((probs.sum() - 1.0).abs() < 1e-6);
+        assert!(probs[[0, 1]] > probs[[0, 2]]);
+    }
 }
@@ -1,3 +1,4 @@
+mod continuation;
 mod delay_pattern_mask_ids;
 mod logits;
 mod music_gen_audio_encodec;
@@ -6,8 +7,14 @@ mod music_gen_decoder;
 mod music_gen_inputs;
 mod music_gen_outputs;
 mod music_gen_text_encoder;
+mod onnx_embedding;
+mod speculative_decoder;
 mod tensor_ops;
 
+pub use continuation::{continue_tokens, Continuation};
 pub use music_gen_audio_encodec::MusicGenAudioEncodec;
+pub use music_gen_config::MusicGenConfig;
 pub use music_gen_decoder::{MusicGenDecoder, MusicGenMergedDecoder, MusicGenSplitDecoder};
 pub use music_gen_text_encoder::MusicGenTextEncoder;
+pub use onnx_embedding::embedding_rows;
+pub use speculative_decoder::SpeculativeDecoder;
@@ -1,4 +1,4 @@
-use std::collections::VecDeque;
+use std::borrow::Cow;
 
 use half::f16;
 use ndarray::{Array, Axis};
@@ -10,7 +10,17 @@ pub struct MusicGenAudioEncodec {
 }
 
 impl MusicGenAudioEncodec {
-    pub fn encode(&self, tokens: impl IntoIterator<Item = [i64; 4]>) -> ort::Result<VecDeque<f32>> {
+    pub fn encode(&self, tokens: impl IntoIterator<Item = [i64; 4]>) -> ort::Result<Vec<f32>> {
+        self.decode_with(tokens, |audio| audio.into_owned())
+    }
+
+    /// Decodes `tokens` and hands the audio to `f` without copying it out of the output
+    /// tensor, unless the model outputs f16 and it has to be converted.
+    pub fn decode_with<R>(
+        &self,
+        tokens: impl IntoIterator<Item = [i64; 4]>,
+        f: impl FnOnce(Cow<[f32]>) -> R,
+    ) -> ort::Result<R> {
         let mut data = vec![];
         for ids in tokens {
             for id in ids {
@@ -27,10 +37,10 @@ impl MusicGenAudioEncodec {
             .expect("audio_values not found in output");
 
         if let Ok((_, data)) = audio_values.try_extract_raw_tensor::<f32>() {
-            return Ok(data.iter().copied().collect());
+            return Ok(f(Cow::Borrowed(data)));
         }
         if let Ok((_, data)) = audio_values.try_extract_raw_tensor::<f16>() {
-            return Ok(data.iter().map(|e| f32::from(*e)).collect());
+            return Ok(f(Cow::Owned(data.iter().map(|e| f32::from(*e)).collect())));
         }
 
         Err(ort::error::Error::new(
@@ -7,11 +7,16 @@ use crate::musicgen::delay_pattern_mask_ids::DelayedPatternMaskIds;
 use crate::musicgen::music_gen_config::MusicGenConfig;
 use crate::musicgen::music_gen_inputs::MusicGenInputs;
 use crate::musicgen::music_gen_outputs::MusicGenOutputs;
-use crate::musicgen::tensor_ops::{dupe_zeros_along_first_dim, zeros_tensor};
+use crate::musicgen::speculative_decoder::DecoderSteps;
+use crate::musicgen::tensor_ops::{
+    dupe_zeros_along_first_dim, repeat_dupe_zeros_along_first_dim, zeros_tensor,
+};
 use num_traits::Zero;
-use ort::session::Session;
+use ort::session::{RunOptions, Session};
 use ort::tensor::PrimitiveTensorElementType;
 use ort::value::{DynValue, Tensor};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
 pub trait MusicGenType: PrimitiveTensorElementType + Debug + Clone + Zero {}
 
@@ -21,7 +26,15 @@ impl MusicGenType for f32 {}
 impl MusicGenType for half::f16 {}
 
 // TODO: is this configurable?
-const GUIDANCE_SCALE: usize = 3;
+pub(crate) const GUIDANCE_SCALE: usize = 3;
+
+/// The same seed samples the same tokens, no seed samples different ones every time.
+pub(crate) fn sampling_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
 
 pub trait MusicGenDecoder: Send + Sync {
     fn generate_tokens(
@@ -29,11 +42,40 @@ pub trait MusicGenDecoder: Send + Sync {
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
         max_len: usize,
+        seed: Option<u64>,
     ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>>;
+
+    /// Same as [MusicGenDecoder::generate_tokens], but decodes one variation per seed in
+    /// the same session runs, stacked along the batch dimension. Each item has the tokens
+    /// of every variation, in the order of the seeds. By default, batches are not supported.
+    fn generate_token_batch(
+        &self,
+        _last_hidden_state: DynValue,
+        _encoder_attention_mask: DynValue,
+        _max_len: usize,
+        _seeds: &[u64],
+    ) -> ort::Result<Receiver<ort::Result<Vec<[i64; 4]>>>> {
+        Err(ort::Error::new(
+            "This decoder cannot decode variations in a batch",
+        ))
+    }
+
+    /// Whether [MusicGenDecoder::generate_token_batch] is supported.
+    fn decodes_batches(&self) -> bool {
+        false
+    }
+
+    /// The decoder driven one run at a time, for speculative decoding. By default, it
+    /// cannot be.
+    fn steps(&self) -> Option<Arc<dyn DecoderSteps>> {
+        None
+    }
 }
 
 pub struct MusicGenMergedDecoder<T: MusicGenType> {
     pub decoder_model_merged: Arc<Session>,
+    /// Shared by the runs of the decoder, so that they can be terminated.
+    pub run_options: Arc<RunOptions>,
     pub config: MusicGenConfig,
     pub _phantom_data: PhantomData<T>,
 }
@@ -41,22 +83,32 @@ pub struct MusicGenMergedDecoder<T: MusicGenType> {
 unsafe impl<T: MusicGenType> Send for MusicGenMergedDecoder<T> {}
 unsafe impl<T: MusicGenType> Sync for MusicGenMergedDecoder<T> {}
 
-impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
-    fn generate_tokens(
+impl<T: MusicGenType + 'static> MusicGenMergedDecoder<T> {
+    /// Decodes one sequence per rng, stacked along the batch dimension, sending the tokens
+    /// of all of them at each step mapped by `frame`.
+    fn decode<M: Send + 'static>(
         &self,
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
         max_len: usize,
-    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
+        mut rngs: Vec<StdRng>,
+        frame: fn(Vec<[i64; 4]>) -> M,
+    ) -> ort::Result<Receiver<ort::Result<M>>> {
+        let batch = rngs.len();
         // Apparently, there's a setting in huggingface's transformers that says that
         // if `guidance_scale` > 1 then you should concatenate 0 along the first axis.
-        let encoder_hidden_states = dupe_zeros_along_first_dim::<T>(last_hidden_state.downcast()?)?;
+        // With several sequences, the conditioned ones go first and then the zeros.
+        let encoder_hidden_states =
+            repeat_dupe_zeros_along_first_dim::<T>(last_hidden_state.downcast()?, batch)?;
         let encoder_attention_mask =
-            dupe_zeros_along_first_dim::<i64>(encoder_attention_mask.downcast()?)?;
+            repeat_dupe_zeros_along_first_dim::<i64>(encoder_attention_mask.downcast()?, batch)?;
 
-        let mut delay_pattern_mask_ids = DelayedPatternMaskIds::<4>::new();
+        let mut delay_pattern_mask_ids = (0..batch)
+            .map(|_| DelayedPatternMaskIds::<4>::new())
+            .collect::<Vec<_>>();
 
         let decoder_model_merged = self.decoder_model_merged.clone();
+        let run_options = self.run_options.clone();
 
         let mut inputs = MusicGenInputs::new();
         inputs.encoder_attention_mask(encoder_attention_mask)?;
@@ -71,12 +123,15 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
         let encoder_dims = [1, num_attention_heads, 0, d_kv];
 
         // TODO: 100?
-        let (tx, rx) = std::sync::mpsc::channel::<ort::Result<[i64; 4]>>();
+        let (tx, rx) = std::sync::mpsc::channel::<ort::Result<M>>();
         let tx2 = tx.clone();
 
         std::thread::spawn(move || {
             let result = {
-                inputs.input_ids(Tensor::from_array(([8, 1], vec![pad_token_id; 8]))?)?;
+                inputs.input_ids(Tensor::from_array((
+                    [8 * batch, 1],
+                    vec![pad_token_id; 8 * batch],
+                ))?)?;
 
                 for i in 0..num_hidden_layers {
                     inputs.past_key_value_decoder_key(i, zeros_tensor::<T>(&decoder_dims))?;
@@ -86,26 +141,34 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
                 }
                 inputs.use_cache_branch(false);
                 for _ in 0..max_len {
-                    let outputs = decoder_model_merged.run(inputs.ort())?;
+                    let outputs =
+                        decoder_model_merged.run_with_options(inputs.ort(), &run_options)?;
                     let mut outputs = MusicGenOutputs::new(outputs);
 
-                    delay_pattern_mask_ids.push(
-                        outputs
-                            .take_logits()?
-                            .apply_free_guidance(GUIDANCE_SCALE)
-                            .sample(top_k)
-                            .iter()
-                            .map(|e| e.0),
-                    );
+                    let sampled = outputs
+                        .take_logits()?
+                        .apply_free_guidance(GUIDANCE_SCALE)
+                        .sample_groups(top_k, &mut rngs);
+                    for (ids, tokens) in delay_pattern_mask_ids.iter_mut().zip(sampled) {
+                        ids.push(tokens.iter().map(|e| e.0));
+                    }
 
-                    let [a, b, c, d] = delay_pattern_mask_ids.last_delayed_masked(pad_token_id);
+                    // The same ids are given to the conditioned and unconditioned halves.
+                    let masked = delay_pattern_mask_ids
+                        .iter()
+                        .flat_map(|ids| ids.last_delayed_masked(pad_token_id))
+                        .collect::<Vec<_>>();
                     inputs.input_ids(ort::value::Value::from_array((
-                        [8, 1],
-                        vec![a, b, c, d, a, b, c, d],
+                        [8 * batch, 1],
+                        [masked.clone(), masked].concat(),
                     ))?)?;
 
-                    if let Some(last_de_delayed) = delay_pattern_mask_ids.last_de_delayed() {
-                        let sent = tx.send(Ok(last_de_delayed));
+                    let last_de_delayed = delay_pattern_mask_ids
+                        .iter()
+                        .map(|ids| ids.last_de_delayed())
+                        .collect::<Option<Vec<_>>>();
+                    if let Some(last_de_delayed) = last_de_delayed {
+                        let sent = tx.send(Ok(frame(last_de_delayed)));
                         if sent.is_err() {
                             break;
                         }
@@ -141,9 +204,59 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
     }
 }
 
+impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
+    fn generate_tokens(
+        &self,
+        last_hidden_state: DynValue,
+        encoder_attention_mask: DynValue,
+        max_len: usize,
+        seed: Option<u64>,
+    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
+        self.decode(
+            last_hidden_state,
+            encoder_attention_mask,
+            max_len,
+            vec![sampling_rng(seed)],
+            |mut frames| frames.remove(0),
+        )
+    }
+
+    fn generate_token_batch(
+        &self,
+        last_hidden_state: DynValue,
+        encoder_attention_mask: DynValue,
+        max_len: usize,
+        seeds: &[u64],
+    ) -> ort::Result<Receiver<ort::Result<Vec<[i64; 4]>>>> {
+        let rngs = seeds.iter().map(|&seed| sampling_rng(Some(seed))).collect();
+        self.decode(
+            last_hidden_state,
+            encoder_attention_mask,
+            max_len,
+            rngs,
+            |frames| frames,
+        )
+    }
+
+    fn decodes_batches(&self) -> bool {
+        true
+    }
+
+    fn steps(&self) -> Option<Arc<dyn DecoderSteps>> {
+        Some(Arc::new(Self {
+            decoder_model_merged: self.decoder_model_merged.clone(),
+            run_options: self.run_options.clone(),
+            config: self.config.clone(),
+            _phantom_data: PhantomData,
+        }))
+    }
+}
+
 pub struct MusicGenSplitDecoder<T: MusicGenType> {
     pub decoder_model: Session,
     pub decoder_with_past_model: Arc<Session>,
+    /// Shared by the runs of both models, so that they can be terminated.
+    pub run_options: Arc<RunOptions>,
     pub config: MusicGenConfig,
     pub _phantom_data: PhantomData<T>,
 }
@@ -157,6 +270,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
         max_len: usize,
+        seed: Option<u64>,
     ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
         // Apparently, there's a setting in huggingface's transformers that says that
         // if `guidance_scale` > 1 then you should concatenate 0 along the first axis.
@@ -169,20 +283,23 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
         let num_hidden_layers = self.config.decoder.num_hidden_layers;
         let pad_token_id = self.config.decoder.pad_token_id;
         let top_k = self.config.decoder.top_k;
+        let mut rng = sampling_rng(seed);
 
         let mut inputs = MusicGenInputs::new();
         inputs.encoder_attention_mask(encoder_attention_mask)?;
         inputs.input_ids(Tensor::from_array(([8, 1], vec![pad_token_id; 8]))?)?;
         inputs.encoder_hidden_states(encoder_hidden_states)?;
 
-        let outputs = self.decoder_model.run(inputs.ort())?;
+        let outputs = self
+            .decoder_model
+            .run_with_options(inputs.ort(), &self.run_options)?;
         let mut outputs = MusicGenOutputs::new(outputs);
 
         delay_pattern_mask_ids.push(
             outputs
                 .take_logits()?
                 .apply_free_guidance(GUIDANCE_SCALE)
-                .sample(top_k)
+                .sample(top_k, &mut rng)
                 .iter()
                 .map(|e| e.0),
         );
@@ -201,6 +318,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
         inputs.remove_encoder_hidden_states();
 
         let decoder_with_past = self.decoder_with_past_model.clone();
+        let run_options = self.run_options.clone();
 
         // TODO: 100?
         let (tx, rx) = std::sync::mpsc::channel::<ort::Result<[i64; 4]>>();
@@ -211,14 +329,14 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
                     let [a, b, c, d] = delay_pattern_mask_ids.last_delayed_masked(pad_token_id);
                     inputs
                         .input_ids(Tensor::from_array(([8, 1], vec![a, b, c, d, a, b, c, d]))?)?;
-                    let outputs = decoder_with_past.run(inputs.ort())?;
+                    let outputs = decoder_with_past.run_with_options(inputs.ort(), &run_options)?;
                     let mut outputs = MusicGenOutputs::new(outputs);
 
                     delay_pattern_mask_ids.push(
                         outputs
                             .take_logits()?
                             .apply_free_guidance(GUIDANCE_SCALE)
-                            .sample(top_k)
+                            .sample(top_k, &mut rng)
                             .iter()
                             .map(|e| e.0),
                     );
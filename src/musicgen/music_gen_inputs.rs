@@ -1,6 +1,9 @@
+use crate::musicgen::tensor_ops::truncate_third_dim;
 use ort::session::SessionInputs;
+use ort::tensor::PrimitiveTensorElementType;
 use ort::value::{DynValue, Tensor};
 use std::collections::HashMap;
+use std::fmt::Debug;
 
 pub struct MusicGenInputs {
     inputs: HashMap<String, DynValue>,
@@ -80,6 +83,25 @@ impl MusicGenInputs {
         Ok(())
     }
 
+    /// Keeps only the first `len` steps of the cached decoder keys and values.
+    pub fn truncate_past_decoder<T: PrimitiveTensorElementType + Debug + Clone + 'static>(
+        &mut self,
+        num_hidden_layers: usize,
+        len: usize,
+    ) -> ort::Result<()> {
+        for i in 0..num_hidden_layers {
+            for kind in ["key", "value"] {
+                let name = format!("past_key_values.{i}.decoder.{kind}");
+                let Some(past) = self.inputs.get(&name) else {
+                    continue;
+                };
+                let truncated = truncate_third_dim::<T>(past, len)?;
+                self.inputs.insert(name, truncated.into_dyn());
+            }
+        }
+        Ok(())
+    }
+
     pub fn use_cache_branch(&mut self, value: bool) {
         self.use_cache_branch = value;
         self.inputs.insert(
@@ -88,7 +110,7 @@ impl MusicGenInputs {
         );
     }
 
-    pub fn ort(&self) -> SessionInputs {
+    pub fn ort(&self) -> SessionInputs<'_, '_> {
         SessionInputs::ValueMap(
             self.inputs
                 .iter()
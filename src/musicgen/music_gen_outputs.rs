@@ -15,6 +15,11 @@ impl<'r, 's> MusicGenOutputs<'r, 's> {
         Logits::from_3d_dyn_value(&self.outputs.remove("logits").unwrap())
     }
 
+    /// The logits after each of the steps the decoder was given.
+    pub fn take_step_logits(&mut self) -> ort::Result<Vec<Logits>> {
+        Logits::steps_from_3d_dyn_value(&self.outputs.remove("logits").unwrap())
+    }
+
     pub fn take_present_decoder_key(&mut self, i: usize) -> DynValue {
         let key = format!("present.{i}.decoder.key");
         self.outputs
@@ -0,0 +1,298 @@
+//! Reads the size of an embedding table straight from an ONNX file, as ONNX Runtime does
+//! not expose the shapes of the weights. Only the few fields of onnx.proto needed for it
+//! are parsed, the weights themselves are skipped over.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Field numbers in onnx.proto.
+const MODEL_GRAPH: u64 = 7;
+const GRAPH_NODE: u64 = 1;
+const GRAPH_INITIALIZER: u64 = 5;
+const NODE_INPUT: u64 = 1;
+const NODE_OP_TYPE: u64 = 4;
+const TENSOR_DIMS: u64 = 1;
+const TENSOR_NAME: u64 = 8;
+
+// Wire types.
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LEN: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// Names and inputs are short, anything longer means the file is not what it seems.
+const MAX_STRING_LEN: u64 = 1 << 16;
+
+/// Number of rows of the table that the model in `file` looks `input` up in, which is the
+/// number of tokens it knows. None if it does not embed `input` with a Gather on a weight.
+pub fn embedding_rows(file: &Path, input: &str) -> std::io::Result<Option<usize>> {
+    let mut reader = ProtoReader::new(BufReader::new(File::open(file)?));
+    read_embedding_rows(&mut reader, input)
+}
+
+fn read_embedding_rows<R: Read + Seek>(
+    reader: &mut ProtoReader<R>,
+    input: &str,
+) -> std::io::Result<Option<usize>> {
+    let mut table = None;
+    let mut weights = HashMap::new();
+    while let Some((field, wire)) = reader.key(None)? {
+        if (field, wire) != (MODEL_GRAPH, LEN) {
+            reader.skip(wire)?;
+            continue;
+        }
+        let graph_end = reader.len_end()?;
+        while let Some((field, wire)) = reader.key(Some(graph_end))? {
+            match (field, wire) {
+                (GRAPH_NODE, LEN) => {
+                    let end = reader.len_end()?;
+                    let (op_type, inputs) = read_node(reader, end)?;
+                    if op_type == "Gather" && inputs.get(1).is_some_and(|i| i == input) {
+                        table = inputs.into_iter().next();
+                    }
+                }
+                (GRAPH_INITIALIZER, LEN) => {
+                    let end = reader.len_end()?;
+                    let (name, dims) = read_tensor(reader, end)?;
+                    weights.insert(name, dims);
+                }
+                _ => reader.skip(wire)?,
+            }
+        }
+    }
+    Ok(table
+        .and_then(|table| weights.remove(&table))
+        .and_then(|dims| dims.first().map(|rows| *rows as usize)))
+}
+
+fn read_node<R: Read + Seek>(
+    reader: &mut ProtoReader<R>,
+    end: u64,
+) -> std::io::Result<(String, Vec<String>)> {
+    let mut op_type = String::new();
+    let mut inputs = vec![];
+    while let Some((field, wire)) = reader.key(Some(end))? {
+        match (field, wire) {
+            (NODE_INPUT, LEN) => inputs.push(reader.string()?),
+            (NODE_OP_TYPE, LEN) => op_type = reader.string()?,
+            _ => reader.skip(wire)?,
+        }
+    }
+    Ok((op_type, inputs))
+}
+
+fn read_tensor<R: Read + Seek>(
+    reader: &mut ProtoReader<R>,
+    end: u64,
+) -> std::io::Result<(String, Vec<u64>)> {
+    let mut name = String::new();
+    let mut dims = vec![];
+    while let Some((field, wire)) = reader.key(Some(end))? {
+        match (field, wire) {
+            (TENSOR_DIMS, VARINT) => dims.push(reader.required_varint()?),
+            (TENSOR_DIMS, LEN) => {
+                let end = reader.len_end()?;
+                while reader.pos < end {
+                    dims.push(reader.required_varint()?);
+                }
+            }
+            (TENSOR_NAME, LEN) => name = reader.string()?,
+            _ => reader.skip(wire)?,
+        }
+    }
+    Ok((name, dims))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid ONNX file: {msg}"))
+}
+
+/// Protobuf reader that keeps track of its position, for knowing where nested messages end.
+struct ProtoReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read + Seek> ProtoReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    /// None at the end of the file.
+    fn varint(&mut self) -> std::io::Result<Option<u64>> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0];
+            if self.inner.read(&mut byte)? == 0 {
+                return match shift {
+                    0 => Ok(None),
+                    _ => Err(invalid("truncated varint")),
+                };
+            }
+            self.pos += 1;
+            value |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn required_varint(&mut self) -> std::io::Result<u64> {
+        self.varint()?.ok_or_else(|| invalid("unexpected end"))
+    }
+
+    /// Field number and wire type of the next field of a message ending at `end`, or of
+    /// the top level message if None.
+    fn key(&mut self, end: Option<u64>) -> std::io::Result<Option<(u64, u64)>> {
+        if end.is_some_and(|end| self.pos >= end) {
+            return Ok(None);
+        }
+        match (self.varint()?, end) {
+            (Some(key), _) => Ok(Some((key >> 3, key & 7))),
+            (None, None) => Ok(None),
+            (None, Some(_)) => Err(invalid("unexpected end")),
+        }
+    }
+
+    /// Reads the length of a nested message, returning where it ends.
+    fn len_end(&mut self) -> std::io::Result<u64> {
+        let len = self.required_varint()?;
+        Ok(self.pos + len)
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        let len = self.required_varint()?;
+        if len > MAX_STRING_LEN {
+            return Err(invalid("string too long"));
+        }
+        let mut buf = vec![0; len as usize];
+        self.inner.read_exact(&mut buf)?;
+        self.pos += len;
+        String::from_utf8(buf).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn seek(&mut self, len: u64) -> std::io::Result<()> {
+        let offset = i64::try_from(len).map_err(|_| invalid("field too long"))?;
+        self.inner.seek(SeekFrom::Current(offset))?;
+        self.pos += len;
+        Ok(())
+    }
+
+    fn skip(&mut self, wire: u64) -> std::io::Result<()> {
+        match wire {
+            VARINT => self.required_varint().map(drop),
+            FIXED64 => self.seek(8),
+            LEN => {
+                let len = self.required_varint()?;
+                self.seek(len)
+            }
+            FIXED32 => self.seek(4),
+            _ => Err(invalid("unsupported wire type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        while value >= 0x80 {
+            bytes.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+        bytes
+    }
+
+    fn field(number: u64, wire: u64, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = varint(number << 3 | wire);
+        if wire == LEN {
+            bytes.extend(varint(payload.len() as u64));
+        }
+        bytes.extend(payload);
+        bytes
+    }
+
+    fn node(op_type: &str, inputs: &[&str]) -> Vec<u8> {
+        let mut node = vec![];
+        for input in inputs {
+            node.extend(field(NODE_INPUT, LEN, input.as_bytes()));
+        }
+        node.extend(field(NODE_OP_TYPE, LEN, op_type.as_bytes()));
+        field(GRAPH_NODE, LEN, &node)
+    }
+
+    fn initializer(name: &str, dims: &[u64], packed: bool) -> Vec<u8> {
+        let mut tensor = vec![];
+        match packed {
+            true => {
+                let dims = dims.iter().flat_map(|d| varint(*d)).collect::<Vec<_>>();
+                tensor.extend(field(TENSOR_DIMS, LEN, &dims));
+            }
+            false => {
+                for dim in dims {
+                    tensor.extend(field(TENSOR_DIMS, VARINT, &varint(*dim)));
+                }
+            }
+        }
+        tensor.extend(field(TENSOR_NAME, LEN, name.as_bytes()));
+        // raw_data
+        tensor.extend(field(9, LEN, &[0; 64]));
+        field(GRAPH_INITIALIZER, LEN, &tensor)
+    }
+
+    fn model(graph: &[Vec<u8>]) -> Vec<u8> {
+        // ir_version, then the graph.
+        let mut model = field(1, VARINT, &varint(8));
+        model.extend(field(MODEL_GRAPH, LEN, &graph.concat()));
+        model
+    }
+
+    fn rows(model: Vec<u8>) -> std::io::Result<Option<usize>> {
+        read_embedding_rows(&mut ProtoReader::new(Cursor::new(model)), "input_ids")
+    }
+
+    #[test]
+    fn reads_the_rows_of_the_embedding_table() -> std::io::Result<()> {
+        let graph = [
+            node("Gather", &["shared.weight", "input_ids"]),
+            node("Gather", &["other.weight", "attention_mask"]),
+            initializer("other.weight", &[7, 2], false),
+            initializer("shared.weight", &[32128, 768], true),
+        ];
+        assert_eq!(rows(model(&graph))?, Some(32128));
+
+        let graph = [
+            initializer("shared.weight", &[32128, 768], false),
+            node("Gather", &["shared.weight", "input_ids"]),
+        ];
+        assert_eq!(rows(model(&graph))?, Some(32128));
+        Ok(())
+    }
+
+    #[test]
+    fn finds_nothing_without_a_gather_on_a_weight() -> std::io::Result<()> {
+        let graph = [
+            node("DequantizeLinear", &["shared.weight_quantized"]),
+            node("Gather", &["dequantized", "input_ids"]),
+            initializer("shared.weight_quantized", &[32128, 768], true),
+        ];
+        assert_eq!(rows(model(&graph))?, None);
+        assert_eq!(rows(model(&[]))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_truncated_files() {
+        let mut model = model(&[node("Gather", &["shared.weight", "input_ids"])]);
+        model.truncate(model.len() - 3);
+        assert!(rows(model).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Speculative decoding: a small draft decoder proposes the next few steps, and the large
+//! decoder checks all of them in a single run, keeping the ones it agrees with. The tokens
+//! follow the distribution of the large decoder, it just needs fewer runs when the draft
+//! guesses well.
+
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use ndarray::{Array2, ArrayView1, Axis};
+use ort::value::{DynValue, Tensor};
+use rand::Rng;
+
+use crate::musicgen::delay_pattern_mask_ids::DelayedPatternMaskIds;
+use crate::musicgen::logits::{sample_probs, Logits};
+use crate::musicgen::music_gen_decoder::{
+    sampling_rng, MusicGenDecoder, MusicGenMergedDecoder, MusicGenType, GUIDANCE_SCALE,
+};
+use crate::musicgen::music_gen_inputs::MusicGenInputs;
+use crate::musicgen::music_gen_outputs::MusicGenOutputs;
+use crate::musicgen::tensor_ops::{dupe_zeros_along_first_dim, zeros_tensor};
+
+/// The inputs of a decoder between runs, with the keys and values of the steps it was
+/// given so far.
+pub struct DecoderState {
+    inputs: MusicGenInputs,
+    /// Steps in the cache, counting the padding the decoding starts from.
+    len: usize,
+}
+
+impl DecoderState {
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(test)]
+    pub(super) fn empty(len: usize) -> Self {
+        Self {
+            inputs: MusicGenInputs::new(),
+            len,
+        }
+    }
+}
+
+/// A decoder driven one run at a time, so that another decoder can run between them.
+pub trait DecoderSteps: Send + Sync {
+    /// Runs the decoder on the padding the decoding starts from, returning the guided
+    /// logits of the first step.
+    fn start(
+        &self,
+        last_hidden_state: &DynValue,
+        encoder_attention_mask: &DynValue,
+    ) -> ort::Result<(DecoderState, Logits)>;
+
+    /// Gives the decoder the delayed and masked ids of `steps` after the ones in the
+    /// cache, returning the guided logits after each of them.
+    fn run(&self, state: &mut DecoderState, steps: &[[i64; 4]]) -> ort::Result<Vec<Logits>>;
+
+    /// Forgets the cached steps after the first `len` ones.
+    fn truncate(&self, state: &mut DecoderState, len: usize) -> ort::Result<()>;
+
+    fn top_k(&self) -> usize;
+
+    fn pad_token_id(&self) -> i64;
+}
+
+impl<T: MusicGenType + 'static> DecoderSteps for MusicGenMergedDecoder<T> {
+    fn start(
+        &self,
+        last_hidden_state: &DynValue,
+        encoder_attention_mask: &DynValue,
+    ) -> ort::Result<(DecoderState, Logits)> {
+        // Both decoders are given the same encoded text, so it is copied.
+        let (shape, data) = last_hidden_state.try_extract_raw_tensor::<T>()?;
+        let last_hidden_state = Tensor::from_array((shape.to_vec(), data.to_vec()))?;
+        let (shape, data) = encoder_attention_mask.try_extract_raw_tensor::<i64>()?;
+        let encoder_attention_mask = Tensor::from_array((shape.to_vec(), data.to_vec()))?;
+
+        let num_hidden_layers = self.config.decoder.num_hidden_layers;
+        let dims = [
+            1,
+            self.config.decoder.num_attention_heads,
+            0,
+            self.config.text_encoder.d_kv,
+        ];
+        let mut inputs = MusicGenInputs::new();
+        inputs.encoder_attention_mask(dupe_zeros_along_first_dim(encoder_attention_mask)?)?;
+        inputs.encoder_hidden_states(dupe_zeros_along_first_dim(last_hidden_state)?)?;
+        inputs.input_ids(Tensor::from_array((
+            [8, 1],
+            vec![self.config.decoder.pad_token_id; 8],
+        ))?)?;
+        for i in 0..num_hidden_layers {
+            inputs.past_key_value_decoder_key(i, zeros_tensor::<T>(&dims))?;
+            inputs.past_key_value_decoder_value(i, zeros_tensor::<T>(&dims))?;
+            inputs.past_key_value_encoder_key(i, zeros_tensor::<T>(&dims))?;
+            inputs.past_key_value_encoder_value(i, zeros_tensor::<T>(&dims))?;
+        }
+        inputs.use_cache_branch(false);
+
+        let outputs = self
+            .decoder_model_merged
+            .run_with_options(inputs.ort(), &self.run_options)?;
+        let mut outputs = MusicGenOutputs::new(outputs);
+        let logits = outputs.take_logits()?.apply_free_guidance(GUIDANCE_SCALE);
+        for j in 0..num_hidden_layers {
+            inputs.past_key_value_decoder_key(j, outputs.take_present_decoder_key(j))?;
+            inputs.past_key_value_decoder_value(j, outputs.take_present_decoder_value(j))?;
+            inputs.past_key_value_encoder_key(j, outputs.take_present_encoder_key(j))?;
+            inputs.past_key_value_encoder_value(j, outputs.take_present_encoder_value(j))?;
+        }
+        inputs.use_cache_branch(true);
+        Ok((DecoderState { inputs, len: 1 }, logits))
+    }
+
+    fn run(&self, state: &mut DecoderState, steps: &[[i64; 4]]) -> ort::Result<Vec<Logits>> {
+        // One row per codebook, the same for the conditioned and unconditioned halves.
+        let input_ids = (0..8)
+            .flat_map(|row| steps.iter().map(move |step| step[row % 4]))
+            .collect::<Vec<_>>();
+        state
+            .inputs
+            .input_ids(Tensor::from_array(([8, steps.len()], input_ids))?)?;
+
+        let outputs = self
+            .decoder_model_merged
+            .run_with_options(state.inputs.ort(), &self.run_options)?;
+        let mut outputs = MusicGenOutputs::new(outputs);
+        let logits = outputs
+            .take_step_logits()?
+            .into_iter()
+            .map(|logits| logits.apply_free_guidance(GUIDANCE_SCALE))
+            .collect();
+        for j in 0..self.config.decoder.num_hidden_layers {
+            let v = outputs.take_present_decoder_key(j);
+            state.inputs.past_key_value_decoder_key(j, v)?;
+            let v = outputs.take_present_decoder_value(j);
+            state.inputs.past_key_value_decoder_value(j, v)?;
+        }
+        state.len += steps.len();
+        Ok(logits)
+    }
+
+    fn truncate(&self, state: &mut DecoderState, len: usize) -> ort::Result<()> {
+        if len >= state.len {
+            return Ok(());
+        }
+        state
+            .inputs
+            .truncate_past_decoder::<T>(self.config.decoder.num_hidden_layers, len)?;
+        state.len = len;
+        Ok(())
+    }
+
+    fn top_k(&self) -> usize {
+        self.config.decoder.top_k
+    }
+
+    fn pad_token_id(&self) -> i64 {
+        self.config.decoder.pad_token_id
+    }
+}
+
+/// Decodes with `target`, letting `draft` propose `draft_steps` steps before each run of
+/// it. Both need the same codebooks and text encoder, like the MusicGen models of
+/// different sizes. The same seed samples the same tokens, but not the ones the target
+/// samples decoding on its own.
+pub struct SpeculativeDecoder {
+    pub target: Arc<dyn DecoderSteps>,
+    pub draft: Arc<dyn DecoderSteps>,
+    pub draft_steps: usize,
+}
+
+impl MusicGenDecoder for SpeculativeDecoder {
+    fn generate_tokens(
+        &self,
+        last_hidden_state: DynValue,
+        encoder_attention_mask: DynValue,
+        max_len: usize,
+        seed: Option<u64>,
+    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
+        let target = self.target.clone();
+        let draft = self.draft.clone();
+        let draft_steps = self.draft_steps.max(1);
+        let top_k = target.top_k();
+        let pad_token_id = target.pad_token_id();
+        let mut rng = sampling_rng(seed);
+
+        let (tx, rx) = std::sync::mpsc::channel::<ort::Result<[i64; 4]>>();
+        let tx2 = tx.clone();
+        std::thread::spawn(move || {
+            let result = (|| {
+                let mut ids = DelayedPatternMaskIds::<4>::new();
+                let push = |ids: &mut DelayedPatternMaskIds<4>, step: [i64; 4]| {
+                    ids.push(step);
+                    match ids.last_de_delayed() {
+                        Some(last_de_delayed) => tx.send(Ok(last_de_delayed)).is_ok(),
+                        None => true,
+                    }
+                };
+
+                let (mut target_state, logits) =
+                    target.start(&last_hidden_state, &encoder_attention_mask)?;
+                let (mut draft_state, _) =
+                    draft.start(&last_hidden_state, &encoder_attention_mask)?;
+                if max_len == 0
+                    || !push(&mut ids, sample_step(&logits.top_k_probs(top_k), &mut rng))
+                {
+                    return Ok(());
+                }
+                // Both decoders were given all the steps but the last ones, which the
+                // draft can be two behind in after all its steps were accepted.
+                let mut draft_behind = 1;
+
+                while ids.len() < max_len {
+                    let n = ids.len();
+                    let k = draft_steps.min(max_len - n);
+
+                    let mut proposal = ids.clone();
+                    let mut draft_probs = vec![];
+                    let mut feed = (n - draft_behind..n)
+                        .map(|step| proposal.delayed_masked(step, pad_token_id))
+                        .collect::<Vec<_>>();
+                    for _ in 0..k {
+                        let logits = draft.run(&mut draft_state, &feed)?;
+                        let probs = logits[logits.len() - 1].top_k_probs(top_k);
+                        proposal.push(sample_step(&probs, &mut rng));
+                        draft_probs.push(probs);
+                        feed = vec![proposal.last_delayed_masked(pad_token_id)];
+                    }
+
+                    // The target checks all the proposed steps in a single run.
+                    let feed = (n - 1..n + k)
+                        .map(|step| proposal.delayed_masked(step, pad_token_id))
+                        .collect::<Vec<_>>();
+                    let target_probs = target
+                        .run(&mut target_state, &feed)?
+                        .iter()
+                        .map(|logits| logits.top_k_probs(top_k))
+                        .collect::<Vec<_>>();
+
+                    let mut corrected = None;
+                    for j in 0..k {
+                        let step = proposal.step(n + j);
+                        match verify_step(&target_probs[j], &draft_probs[j], step, &mut rng) {
+                            Ok(step) => {
+                                if !push(&mut ids, step) {
+                                    return Ok(());
+                                }
+                            }
+                            Err(step) => {
+                                corrected = Some(step);
+                                break;
+                            }
+                        }
+                    }
+                    // Either the first rejected step is sampled again, or, if all were
+                    // accepted, the target gives the next one for free.
+                    let next = match corrected {
+                        Some(step) => Some(step),
+                        None if ids.len() < max_len => {
+                            Some(sample_step(&target_probs[k], &mut rng))
+                        }
+                        None => None,
+                    };
+                    if let Some(step) = next {
+                        if !push(&mut ids, step) {
+                            return Ok(());
+                        }
+                    }
+
+                    // Drop the rejected steps from the caches.
+                    target.truncate(&mut target_state, ids.len())?;
+                    draft.truncate(&mut draft_state, ids.len())?;
+                    draft_behind = ids.len() + 1 - draft_state.len;
+                }
+                Ok::<(), ort::Error>(())
+            })();
+            if let Err(err) = result {
+                let _ = tx2.send(Err(err));
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+/// Samples a step, one token per codebook.
+pub(super) fn sample_step(probs: &Array2<f32>, rng: &mut impl Rng) -> [i64; 4] {
+    let mut step = [0; 4];
+    for (token, probs) in step.iter_mut().zip(probs.axis_iter(Axis(0))) {
+        *token = sample_probs(probs, rng);
+    }
+    step
+}
+
+/// Checks a step the draft sampled from `draft_probs` against the `target_probs` of the
+/// target, returning it if accepted, or the step with its rejected tokens sampled again.
+/// Codebooks are sampled independently, so each token is checked on its own.
+fn verify_step(
+    target_probs: &Array2<f32>,
+    draft_probs: &Array2<f32>,
+    step: [i64; 4],
+    rng: &mut impl Rng,
+) -> Result<[i64; 4], [i64; 4]> {
+    let mut checked = step;
+    let mut rejected = false;
+    for (c, token) in checked.iter_mut().enumerate() {
+        let p = target_probs.row(c);
+        let q = draft_probs.row(c);
+        let i = *token as usize;
+        if q[i] > 0.0 && rng.gen::<f32>() < (p[i] / q[i]).min(1.0) {
+            continue;
+        }
+        rejected = true;
+        *token = sample_residual(p, q, rng);
+    }
+    match rejected {
+        true => Err(checked),
+        false => Ok(checked),
+    }
+}
+
+/// Samples from the probability the target gives to a token beyond what the draft does,
+/// which makes up for the tokens the draft proposes too often.
+fn sample_residual(p: ArrayView1<f32>, q: ArrayView1<f32>, rng: &mut impl Rng) -> i64 {
+    let residual = (&p - &q).mapv(|d| d.max(0.0));
+    match residual.sum() > 0.0 {
+        true => sample_probs(residual.view(), rng),
+        false => sample_probs(p, rng),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn probs(row: [f32; 3]) -> Array2<f32> {
+        Array2::from_shape_fn((4, 3), |(_, i)| row[i])
+    }
+
+    #[test]
+    fn accepts_what_both_decoders_agree_on() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let p = probs([0.2, 0.5, 0.3]);
+        for _ in 0..100 {
+            let step = sample_step(&p, &mut rng);
+            assert_eq!(verify_step(&p, &p, step, &mut rng), Ok(step));
+        }
+    }
+
+    #[test]
+    fn replaces_tokens_the_target_would_not_sample() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let target = probs([0.0, 0.5, 0.5]);
+        let draft = probs([1.0, 0.0, 0.0]);
+        for _ in 0..100 {
+            let step = verify_step(&target, &draft, [0; 4], &mut rng).unwrap_err();
+            assert!(step.iter().all(|&token| token != 0));
+        }
+    }
+
+    #[test]
+    fn accepted_tokens_follow_the_target() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let target = probs([0.6, 0.3, 0.1]);
+        let draft = probs([0.2, 0.2, 0.6]);
+        let mut counts = [0.0f32; 3];
+        let n = 20000;
+        for _ in 0..n {
+            let step = sample_step(&draft, &mut rng);
+            let step = verify_step(&target, &draft, step, &mut rng).unwrap_or_else(|s| s);
+            counts[step[0] as usize] += 1.0;
+        }
+        let freqs = array![counts[0], counts[1], counts[2]] / n as f32;
+        for (freq, p) in freqs.iter().zip([0.6, 0.3, 0.1]) {
+            assert!((freq - p).abs() < 0.02, "{freqs:?}");
+        }
+    }
+}
@@ -1,7 +1,7 @@
-use ndarray::Array;
+use ndarray::{s, Array};
 use num_traits::{One, Zero};
 use ort::tensor::PrimitiveTensorElementType;
-use ort::value::Tensor;
+use ort::value::{DynValue, Tensor};
 use std::fmt::Debug;
 
 pub fn zeros_tensor<T: PrimitiveTensorElementType + Debug + Clone + Zero + 'static>(
@@ -15,11 +15,24 @@ pub fn dupe_zeros_along_first_dim<
     T: PrimitiveTensorElementType + Debug + Zero + Clone + 'static,
 >(
     tensor: Tensor<T>,
+) -> ort::Result<Tensor<T>> {
+    repeat_dupe_zeros_along_first_dim(tensor, 1)
+}
+
+/// Repeats the tensor `n` times along the first dimension, followed by as many zeros.
+pub fn repeat_dupe_zeros_along_first_dim<
+    T: PrimitiveTensorElementType + Debug + Zero + Clone + 'static,
+>(
+    tensor: Tensor<T>,
+    n: usize,
 ) -> ort::Result<Tensor<T>> {
     let (shape, data) = tensor.try_extract_raw_tensor()?;
     let mut shape = shape.to_vec();
-    shape[0] *= 2;
-    let data = [data.to_vec(), vec![T::zero(); data.len()]].concat();
+    shape[0] *= 2 * n as i64;
+    let repeated = data.iter().cycle().take(data.len() * n).cloned();
+    let data = repeated
+        .chain(std::iter::repeat_n(T::zero(), data.len() * n))
+        .collect::<Vec<_>>();
     Tensor::from_array((shape, data))
 }
 
@@ -28,3 +41,14 @@ pub fn ones_tensor<T: PrimitiveTensorElementType + Debug + Clone + One + 'static
 ) -> Tensor<T> {
     ort::value::Value::from_array(Array::<T, _>::ones(shape)).expect("Could not build zeros tensor")
 }
+
+/// Keeps the first `len` entries of the third dimension of a 4 dimensional tensor, like
+/// the sequence dimension of cached keys and values.
+pub fn truncate_third_dim<T: PrimitiveTensorElementType + Debug + Clone + 'static>(
+    value: &DynValue,
+    len: usize,
+) -> ort::Result<Tensor<T>> {
+    let arr = value.try_extract_tensor::<T>()?;
+    let len = len.min(arr.shape()[2]);
+    Tensor::from_array(arr.slice(s![.., .., ..len, ..]).to_owned())
+}
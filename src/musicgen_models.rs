@@ -1,31 +1,67 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use clap::ValueEnum;
 use half::f16;
 use indicatif::{ProgressBar, ProgressStyle};
-use ort::session::Session;
+use ort::session::{RunOptions, Session};
 use ort::value::DynValue;
-use std::collections::VecDeque;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::thread::ThreadId;
 use std::time::Duration;
 use tokenizers::Tokenizer;
+use tracing::{debug_span, warn};
 
-use crate::backend::JobProcessor;
-use crate::cli::{Model, INPUT_IDS_BATCH_PER_SECOND};
+use crate::affinity::SessionThreads;
+use crate::audio::audio_sink::AudioSink;
+use crate::audio::ending::EndingConfig;
+use crate::audio::extended_generation::ExtendedGenerationConfig;
+use crate::backend::{
+    ExtendedJobProcessor, JobProcessor, ModelCapabilities, ModelEntry, ModelRegistry, ModelVersion,
+    PostChain, SessionPool,
+};
+use crate::cli::{Model, INPUT_IDS_BATCH_PER_SECOND, SAMPLING_RATE};
+use crate::custom_models::CustomModel;
+use crate::gpu::{is_out_of_memory, SessionDevice};
+use crate::model_cache;
+use crate::model_hashes::FileHasher;
 use crate::musicgen::{
-    MusicGenAudioEncodec, MusicGenDecoder, MusicGenMergedDecoder, MusicGenSplitDecoder,
-    MusicGenTextEncoder,
+    continue_tokens, embedding_rows, Continuation, MusicGenAudioEncodec, MusicGenConfig,
+    MusicGenDecoder, MusicGenMergedDecoder, MusicGenSplitDecoder, MusicGenTextEncoder,
+    SpeculativeDecoder,
 };
 use crate::storage::Storage;
 use crate::storage_ext::StorageExt;
 
+/// Frames decoded between each chunk of audio streamed while generating, 2 seconds of audio.
+const STREAM_CHUNK_FRAMES: usize = 2 * INPUT_IDS_BATCH_PER_SECOND;
+/// Frames decoded on each side of a streamed chunk, so that the audio codec has enough
+/// context for producing seamless audio across chunks.
+const STREAM_CONTEXT_FRAMES: usize = 10;
+const SAMPLES_PER_FRAME: usize = SAMPLING_RATE / INPUT_IDS_BATCH_PER_SECOND;
+
 pub struct MusicGenModels {
     text_encoder: MusicGenTextEncoder,
     decoder: Box<dyn MusicGenDecoder>,
     audio_encodec: MusicGenAudioEncodec,
+    version: Option<ModelVersion>,
+    /// Seed of the sampling of each job, which is then reproducible.
+    seed: Option<u64>,
+    /// Variations of a prompt decoded at the same time, if the decoder supports it.
+    batch_size: usize,
+    /// Passed to the runs of the decoders, the draft one included.
+    run_options: Vec<Arc<RunOptions>>,
+    /// Whether the runs were terminated, after which they all fail.
+    terminated: AtomicBool,
 }
 
 impl MusicGenModels {
     pub fn encode_text(&self, text: &str) -> ort::Result<(DynValue, DynValue)> {
+        let _span = debug_span!("text_encoding").entered();
         self.text_encoder.encode(text)
     }
 
@@ -35,153 +71,327 @@ impl MusicGenModels {
         encoder_attention_mask: DynValue,
         max_len: usize,
     ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
-        self.decoder
-            .generate_tokens(last_hidden_state, encoder_attention_mask, max_len)
+        self.decoder.generate_tokens(
+            last_hidden_state,
+            encoder_attention_mask,
+            max_len,
+            self.seed,
+        )
     }
 
     pub fn encode_audio(
         &self,
         tokens: impl IntoIterator<Item = [i64; 4]>,
-    ) -> ort::Result<VecDeque<f32>> {
+    ) -> ort::Result<Vec<f32>> {
+        let _span = debug_span!("codec").entered();
         self.audio_encodec.encode(tokens)
     }
 
-    pub async fn new<S: Storage>(
+    /// Same as [Self::encode_audio], but lends the audio to `f` instead of copying it.
+    pub fn encode_audio_with<R>(
+        &self,
+        tokens: impl IntoIterator<Item = [i64; 4]>,
+        f: impl FnOnce(&[f32]) -> R,
+    ) -> ort::Result<R> {
+        let _span = debug_span!("codec").entered();
+        self.audio_encodec.decode_with(tokens, |audio| f(&audio))
+    }
+
+    /// Generates the audio of `prompt` sampled with `seed`, pushing it into the sink as
+    /// it gets decoded.
+    fn stream(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seed: Option<u64>,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let max_len = secs * INPUT_IDS_BATCH_PER_SECOND;
+
+        let (lhs, am) = self.encode_text(prompt)?;
+        let token_stream = self.decoder.generate_tokens(lhs, am, max_len, seed)?;
+        self.stream_tokens(token_stream, vec![], 0, max_len, on_progress, sink)
+    }
+
+    /// Pushes the audio of the frames received into the sink as they arrive. The codec
+    /// is given the `previous` frames before them, but only the audio of the last
+    /// `repeated` ones is pushed too.
+    fn stream_tokens(
+        &self,
+        token_stream: Receiver<ort::Result<[i64; 4]>>,
+        previous: Vec<[i64; 4]>,
+        repeated: usize,
+        max_len: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        let received_from = previous.len();
+        let mut streamed = previous.len() - repeated;
+        let mut data = previous;
+        // Includes the codec decoding the chunks streamed meanwhile.
+        let decoding = debug_span!("decoding").entered();
+        while let Ok(tokens) = token_stream.recv() {
+            data.push(tokens?);
+            let received = data.len() - received_from;
+            let should_exit = on_progress(received as f32, max_len as f32);
+            if should_exit {
+                return Err(ort::Error::new("Aborted"));
+            }
+            // Wait for the context after the chunk to be generated too.
+            if data.len() - streamed >= STREAM_CHUNK_FRAMES + STREAM_CONTEXT_FRAMES {
+                let until = data.len() - STREAM_CONTEXT_FRAMES;
+                self.stream_frames(&data, streamed, until, sink)?;
+                streamed = until;
+            }
+        }
+        drop(decoding);
+
+        self.stream_frames(&data, streamed, data.len(), sink)?;
+        sink.finalize().map_err(ort::Error::new)
+    }
+
+    /// Decodes the frames in `from..until` into audio, surrounded by some context that is
+    /// discarded afterward, and pushes it into the sink.
+    fn stream_frames(
+        &self,
+        frames: &[[i64; 4]],
+        from: usize,
+        until: usize,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        if from >= until {
+            return Ok(());
+        }
+        let start = from.saturating_sub(STREAM_CONTEXT_FRAMES);
+        let end = (until + STREAM_CONTEXT_FRAMES).min(frames.len());
+        // The chunk goes from the output tensor of the codec into the sink as is.
+        self.encode_audio_with(frames[start..end].iter().copied(), |audio| {
+            let skip = ((from - start) * SAMPLES_PER_FRAME).min(audio.len());
+            // The last chunk takes whatever the codec produced, even if not a whole frame.
+            let end = match until == frames.len() {
+                true => audio.len(),
+                false => (skip + (until - from) * SAMPLES_PER_FRAME).min(audio.len()),
+            };
+            sink.push(&audio[skip..end]).map_err(ort::Error::new)
+        })?
+    }
+
+    /// Loads the models from local files, validating that the ONNX files have the
+    /// shape the rest of the pipeline expects.
+    pub fn from_files(
+        files: &MusicGenFiles,
+        fp16: bool,
+        device: SessionDevice,
+        threads: &SessionThreads,
+    ) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(&files.config)
+            .map_err(|err| anyhow!("Error reading config file {:?}: {err}", files.config))?;
+        let config: MusicGenConfig = serde_json::from_str(&config).map_err(|err| {
+            anyhow!(
+                "Could not deserialize config file {:?}: {err}",
+                files.config
+            )
+        })?;
+        let num_hidden_layers = config.decoder.num_hidden_layers;
+
+        let tokenizer = load_tokenizer(&files.tokenizer)?;
+        let vocab_size = match embedding_rows(&files.text_encoder, "input_ids") {
+            Ok(Some(rows)) => Some(rows),
+            Ok(None) => config.text_encoder.vocab_size,
+            Err(err) => {
+                warn!(
+                    "Could not read the vocabulary size of {:?}: {err}",
+                    files.text_encoder
+                );
+                config.text_encoder.vocab_size
+            }
+        };
+        check_vocab_size(&tokenizer, vocab_size)
+            .map_err(|err| anyhow!("Invalid tokenizer {:?}: {err}", files.tokenizer))?;
+
+        let text_encoder = MusicGenTextEncoder {
+            tokenizer,
+            text_encoder: build_session(
+                &files.text_encoder,
+                &["input_ids", "attention_mask"],
+                &["last_hidden_state"],
+                device,
+                threads,
+            )?,
+        };
+
+        let past_key = format!(
+            "past_key_values.{}.decoder.key",
+            num_hidden_layers.saturating_sub(1)
+        );
+        let run_options = Arc::new(RunOptions::new()?);
+        let decoder: Box<dyn MusicGenDecoder> = match &files.decoder {
+            DecoderFiles::Split {
+                decoder_model,
+                decoder_with_past_model,
+            } => {
+                let decoder_model =
+                    build_session(decoder_model, &["input_ids"], &["logits"], device, threads)?;
+                let decoder_with_past_model = build_session(
+                    decoder_with_past_model,
+                    &["input_ids", &past_key],
+                    &["logits"],
+                    device,
+                    threads,
+                )?;
+                macro_rules! load {
+                    ($ty: ty) => {
+                        Box::new(MusicGenSplitDecoder::<$ty> {
+                            decoder_model,
+                            decoder_with_past_model: Arc::new(decoder_with_past_model),
+                            run_options: run_options.clone(),
+                            config,
+                            _phantom_data: Default::default(),
+                        })
+                    };
+                }
+                if fp16 {
+                    load!(f16)
+                } else {
+                    load!(f32)
+                }
+            }
+            DecoderFiles::Merged {
+                decoder_model_merged,
+            } => {
+                let decoder_model_merged = build_session(
+                    decoder_model_merged,
+                    &["input_ids", "use_cache_branch", &past_key],
+                    &["logits"],
+                    device,
+                    threads,
+                )?;
+                macro_rules! load {
+                    ($ty: ty) => {
+                        Box::new(MusicGenMergedDecoder::<$ty> {
+                            decoder_model_merged: Arc::new(decoder_model_merged),
+                            run_options: run_options.clone(),
+                            config,
+                            _phantom_data: Default::default(),
+                        })
+                    };
+                }
+                if fp16 {
+                    load!(f16)
+                } else {
+                    load!(f32)
+                }
+            }
+        };
+        let audio_encodec = MusicGenAudioEncodec {
+            audio_encodec_decode: build_session(
+                &files.audio_encodec,
+                &[],
+                &["audio_values"],
+                device,
+                threads,
+            )?,
+        };
+
+        Ok(MusicGenModels {
+            text_encoder,
+            decoder,
+            audio_encodec,
+            version: None,
+            seed: None,
+            batch_size: 1,
+            run_options: vec![run_options],
+            terminated: AtomicBool::new(false),
+        })
+    }
+
+    /// Makes the decoder of `draft` propose the steps this one decodes, which then only
+    /// checks them, see [SpeculativeDecoder]. The rest of the draft is dropped.
+    pub fn with_draft(mut self, draft: MusicGenModels, draft_steps: usize) -> anyhow::Result<Self> {
+        let (Some(target), Some(steps)) = (self.decoder.steps(), draft.decoder.steps()) else {
+            return Err(anyhow!(
+                "Speculative decoding needs models with a merged decoder"
+            ));
+        };
+        self.decoder = Box::new(SpeculativeDecoder {
+            target,
+            draft: steps,
+            draft_steps,
+        });
+        self.run_options.extend(draft.run_options);
+        Ok(self)
+    }
+
+    /// Makes the decoder runs in progress fail, and the later ones too, so the models
+    /// have to be loaded again to be used after that.
+    pub fn terminate_runs(&self) {
+        self.terminated.store(true, Ordering::SeqCst);
+        for run_options in &self.run_options {
+            if let Err(err) = run_options.terminate() {
+                warn!("Could not terminate the decoder: {err}");
+            }
+        }
+    }
+}
+
+/// A smaller model that drafts the steps of the loaded one.
+#[derive(Clone)]
+struct Draft {
+    files: MusicGenFiles,
+    fp16: bool,
+    steps: usize,
+}
+
+impl Draft {
+    fn apply(
+        &self,
+        models: MusicGenModels,
+        device: SessionDevice,
+        threads: &SessionThreads,
+    ) -> anyhow::Result<MusicGenModels> {
+        let draft = MusicGenModels::from_files(&self.files, self.fp16, device, threads)?;
+        models.with_draft(draft, self.steps)
+    }
+}
+
+fn is_fp16(model: Model) -> bool {
+    matches!(model, Model::SmallFp16 | Model::MediumFp16)
+}
+
+/// Local paths to the files that compose a MusicGen model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MusicGenFiles {
+    pub config: PathBuf,
+    pub tokenizer: PathBuf,
+    pub text_encoder: PathBuf,
+    #[serde(flatten)]
+    pub decoder: DecoderFiles,
+    pub audio_encodec: PathBuf,
+}
+
+/// The transformer-based decoder can be exported either in two files, or a single merged one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DecoderFiles {
+    Merged {
+        decoder_model_merged: PathBuf,
+    },
+    Split {
+        decoder_model: PathBuf,
+        decoder_with_past_model: PathBuf,
+    },
+}
+
+impl MusicGenFiles {
+    /// Downloads the files of one of the built-in models, if not already present.
+    pub async fn download<S: Storage>(
         storage: S,
         model: Model,
         use_split_decoder: bool,
         force_download: bool,
     ) -> anyhow::Result<Self> {
-        macro_rules! hf_url {
-            ($t: expr) => {
-                (
-                    concat!(
-                        "https://huggingface.co/gabotechs/music_gen/resolve/main/",
-                        $t
-                    ),
-                    concat!("v1/", $t,),
-                )
-            };
-        }
-        let remote_file_spec = match (model, use_split_decoder) {
-            (Model::Small, true) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_fp32/decoder_model.onnx"),
-                hf_url!("small_fp32/decoder_with_past_model.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallQuant, true) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_i8/decoder_model.onnx"),
-                hf_url!("small_i8/decoder_with_past_model.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallFp16, true) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp16/text_encoder.onnx"),
-                hf_url!("small_fp16/decoder_model.onnx"),
-                hf_url!("small_fp16/decoder_with_past_model.onnx"),
-                hf_url!("small_fp16/encodec_decode.onnx"),
-            ],
-            (Model::Medium, true) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_fp32/decoder_model.onnx"),
-                hf_url!("medium_fp32/decoder_with_past_model.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("medium_fp32/decoder_model.onnx_data"),
-                hf_url!("medium_fp32/decoder_with_past_model.onnx_data"),
-            ],
-            (Model::MediumQuant, true) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_i8/decoder_model.onnx"),
-                hf_url!("medium_i8/decoder_with_past_model.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-            ],
-            (Model::MediumFp16, true) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp16/text_encoder.onnx"),
-                hf_url!("medium_fp16/decoder_model.onnx"),
-                hf_url!("medium_fp16/decoder_with_past_model.onnx"),
-                hf_url!("medium_fp16/encodec_decode.onnx"),
-            ],
-            (Model::Large, true) => vec![
-                hf_url!("large/config.json"),
-                hf_url!("large/tokenizer.json"),
-                hf_url!("large_fp32/text_encoder.onnx"),
-                hf_url!("large_fp32/decoder_model.onnx"),
-                hf_url!("large_fp32/decoder_with_past_model.onnx"),
-                hf_url!("large_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("large_fp32/decoder_model.onnx_data"),
-                hf_url!("large_fp32/decoder_with_past_model.onnx_data"),
-            ],
-            (Model::Small, false) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_fp32/decoder_model_merged.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallQuant, false) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_i8/decoder_model_merged.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallFp16, false) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp16/text_encoder.onnx"),
-                hf_url!("small_fp16/decoder_model_merged.onnx"),
-                hf_url!("small_fp16/encodec_decode.onnx"),
-            ],
-            (Model::Medium, false) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_fp32/decoder_model_merged.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("medium_fp32/decoder_model_merged.onnx_data"),
-            ],
-            (Model::MediumQuant, false) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_i8/decoder_model_merged.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-            ],
-            (Model::MediumFp16, false) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp16/text_encoder.onnx"),
-                hf_url!("medium_fp16/decoder_model_merged.onnx"),
-                hf_url!("medium_fp16/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("medium_fp16/decoder_model_merged.onnx_data"),
-            ],
-            (Model::Large, false) => vec![
-                hf_url!("large/config.json"),
-                hf_url!("large/tokenizer.json"),
-                hf_url!("large_fp32/text_encoder.onnx"),
-                hf_url!("large_fp32/decoder_model_merged.onnx"),
-                hf_url!("large_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("large_fp32/decoder_model_merged.onnx_data"),
-            ],
-        };
+        let remote_file_spec = remote_file_spec(model, use_split_decoder);
 
         let mut results = storage
             .download_many(
@@ -192,117 +402,915 @@ impl MusicGenModels {
             )
             .await?;
 
-        // First result is the decoder config.
-        let config = results.pop_front().unwrap();
-        // Second result is the tokenizer.
-        let tokenizer = results.pop_front().unwrap();
-        let mut tokenizer = Tokenizer::from_file(tokenizer).expect("Could not load tokenizer");
-        tokenizer
-            .with_padding(None)
-            .with_truncation(None)
-            .expect("Could not configure tokenizer");
-
-        let mut sessions = build_sessions(results).await?;
-
-        let text_encoder = MusicGenTextEncoder {
-            tokenizer,
+        Ok(MusicGenFiles {
+            // First result is the decoder config.
+            config: results.pop_front().unwrap(),
+            // Second result is the tokenizer.
+            tokenizer: results.pop_front().unwrap(),
             // third result is the text encoder.
-            text_encoder: sessions.pop_front().unwrap(),
+            text_encoder: results.pop_front().unwrap(),
+            decoder: if use_split_decoder {
+                // forth and fifth result are the decoder parts if split.
+                DecoderFiles::Split {
+                    decoder_model: results.pop_front().unwrap(),
+                    decoder_with_past_model: results.pop_front().unwrap(),
+                }
+            } else {
+                // forth result is the decoder.
+                DecoderFiles::Merged {
+                    decoder_model_merged: results.pop_front().unwrap(),
+                }
+            },
+            // next result is the audio encodec, the rest are just downloaded.
+            audio_encodec: results.pop_front().unwrap(),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PathBuf> {
+        let decoder = match &self.decoder {
+            DecoderFiles::Merged {
+                decoder_model_merged,
+            } => vec![decoder_model_merged],
+            DecoderFiles::Split {
+                decoder_model,
+                decoder_with_past_model,
+            } => vec![decoder_model, decoder_with_past_model],
         };
+        [&self.config, &self.tokenizer, &self.text_encoder]
+            .into_iter()
+            .chain(decoder)
+            .chain([&self.audio_encodec])
+    }
+}
 
-        let config = tokio::fs::read_to_string(config)
-            .await
-            .expect("Error reading config file from disk");
-        let config = serde_json::from_str(&config).expect("Could not deserialize config file");
-        #[allow(clippy::collapsible_else_if)]
-        let decoder: Box<dyn MusicGenDecoder> = if use_split_decoder {
-            macro_rules! load {
-                ($ty: ty) => {
-                    Box::new(MusicGenSplitDecoder::<$ty> {
-                        // forth and fifth result are the decoder parts if split.
-                        decoder_model: sessions.pop_front().unwrap(),
-                        decoder_with_past_model: Arc::new(sessions.pop_front().unwrap()),
-                        config,
-                        _phantom_data: Default::default(),
-                    })
-                };
-            }
-            if matches!(model, Model::SmallFp16 | Model::MediumFp16) {
-                load!(f16)
-            } else {
-                load!(f32)
-            }
-        } else {
-            macro_rules! load {
-                ($ty: ty) => {
-                    Box::new(MusicGenMergedDecoder::<$ty> {
-                        // forth result is the decoder.
-                        decoder_model_merged: Arc::new(sessions.pop_front().unwrap()),
-                        config,
-                        _phantom_data: Default::default(),
-                    })
-                };
+/// [ModelRegistry] backed by the MusicGen models in the local data directory.
+#[derive(Clone)]
+pub struct MusicGenModelRegistry<S: Storage> {
+    pub storage: S,
+    pub use_split_decoder: bool,
+    pub force_download: bool,
+    pub custom_models: Vec<CustomModel>,
+    /// Tokenizer files that replace the default ones, by model name.
+    pub tokenizers: HashMap<String, PathBuf>,
+    /// Model hashes, or prefixes of them, that the installed models must match, by model name.
+    pub pins: HashMap<String, String>,
+    /// Whether ONNX Runtime was initialized with a GPU provider, in which case models fall
+    /// back to the CPU when the GPU runs out of memory.
+    pub gpu: bool,
+    /// Segment layout and processing of extended renders.
+    pub post: PostChain,
+    /// Where the sessions of the loaded models run.
+    pub device: SessionDevice,
+    /// Threads each session uses for running an operation, and the cores they run on.
+    pub threads: SessionThreads,
+    /// Seed of the sampling of the loaded models, so that a prompt always renders the same
+    /// audio. None samples differently every time.
+    pub seed: Option<u64>,
+    /// Variations of a prompt the loaded models decode at the same time. Larger batches
+    /// need more memory.
+    pub batch_size: usize,
+    /// Smaller model that drafts the steps of the loaded built-in models, if any.
+    pub draft_model: Option<Model>,
+    /// Steps the draft model proposes before the loaded model checks them.
+    pub draft_steps: usize,
+    /// Instances of the loaded model, each with its own sessions, that jobs run on at the
+    /// same time. They are loaded as jobs need them, each one taking as much memory as the
+    /// first.
+    pub sessions: usize,
+}
+
+impl<S: Storage> MusicGenModelRegistry<S> {
+    pub fn entry(&self, model: Model) -> ModelEntry {
+        let mut installed = true;
+        let mut size_bytes = 0;
+        for (_, local_file) in remote_file_spec(model, self.use_split_decoder) {
+            match std::fs::metadata(self.storage.path_buf(local_file)) {
+                Ok(metadata) => size_bytes += metadata.len(),
+                Err(_) => installed = false,
             }
-            if matches!(model, Model::SmallFp16 | Model::MediumFp16) {
-                load!(f16)
+        }
+        ModelEntry {
+            name: model.name(),
+            display_name: model.to_string(),
+            installed,
+            size_bytes,
+            capabilities: ModelCapabilities {
+                max_secs: 30,
+                stereo: false,
+                melody: false,
+            },
+        }
+    }
+
+    /// Computes the version of an installed model from the files it is made of.
+    pub async fn version(&self, name: &str) -> anyhow::Result<ModelVersion> {
+        let (revision, mut files) =
+            if let Some(custom) = self.custom_models.iter().find(|m| m.name == name) {
+                let revision = custom.version.clone().unwrap_or("unversioned".to_string());
+                (revision, custom.all_files().cloned().collect::<Vec<_>>())
             } else {
-                load!(f32)
-            }
-        };
-        let audio_encodec = MusicGenAudioEncodec {
-            // last result is the audio encodec.
-            audio_encodec_decode: sessions.pop_front().unwrap(),
+                let model = Model::from_str(name, true).map_err(|err| anyhow!(err))?;
+                let spec = remote_file_spec(model, self.use_split_decoder);
+                // Local files are stored under a directory named after the revision.
+                let revision = spec[0].1.split('/').next().unwrap_or_default().to_string();
+                let files = spec
+                    .into_iter()
+                    .map(|(_, local_file)| self.storage.path_buf(local_file))
+                    .collect();
+                (revision, files)
+            };
+        if let Some(tokenizer) = self.tokenizers.get(name) {
+            files.push(tokenizer.clone());
+        }
+        let mut hasher = FileHasher::new(self.storage.clone()).await?;
+        let hash = hasher.hash_files(&files).await?;
+        Ok(ModelVersion {
+            name: name.to_string(),
+            revision,
+            hash,
+        })
+    }
+
+    /// Computes the version of an installed model, failing if it does not match its pin.
+    async fn pinned_version(&self, name: &str) -> anyhow::Result<ModelVersion> {
+        let version = self.version(name).await?;
+        match self.pins.get(name) {
+            Some(pin) if !version.hash.starts_with(pin.as_str()) => Err(anyhow!(
+                "{name} is pinned to version {pin}, but the installed one is {}",
+                version.short_hash()
+            )),
+            _ => Ok(version),
+        }
+    }
+
+    /// Applies `post` to the segments of extended renders, instead of the current
+    /// processing.
+    pub fn with_post_chain(self, post: &PostChain) -> Self {
+        Self {
+            post: post.clone(),
+            ..self
+        }
+    }
+
+    /// Downloads the model that drafts the steps of `model`, if any.
+    async fn draft(&self, model: Model) -> anyhow::Result<Option<Draft>> {
+        let Some(draft) = self
+            .draft_model
+            .filter(|draft| draft.name() != model.name())
+        else {
+            return Ok(None);
         };
+        if is_fp16(draft) != is_fp16(model) {
+            return Err(anyhow!(
+                "{draft} cannot draft for {model}, only models of the same precision can"
+            ));
+        }
+        let files = MusicGenFiles::download(
+            self.storage.clone(),
+            draft,
+            self.use_split_decoder,
+            self.force_download,
+        )
+        .await?;
+        Ok(Some(Draft {
+            files,
+            fp16: is_fp16(draft),
+            steps: self.draft_steps,
+        }))
+    }
 
-        Ok(MusicGenModels {
-            text_encoder,
-            decoder,
-            audio_encodec,
+    /// Runs jobs on up to `sessions` instances of `models`.
+    fn pooled(&self, models: ReloadableModels) -> Arc<dyn JobProcessor> {
+        let models = Arc::new(models);
+        if self.sessions <= 1 {
+            return models;
+        }
+        let first = models.clone();
+        Arc::new(SessionPool::new(models, self.sessions, move || {
+            Ok(Arc::new(first.fork()?))
+        }))
+    }
+
+    /// How extended renders are generated, giving them the configured intro and outro, and
+    /// an ending when they stop abruptly.
+    fn generation_config(&self) -> ExtendedGenerationConfig {
+        self.post.apply(ExtendedGenerationConfig {
+            ending: Some(EndingConfig::default()),
+            ..Default::default()
         })
     }
 }
 
+#[async_trait]
+impl<S: Storage> ModelRegistry for MusicGenModelRegistry<S> {
+    async fn list(&self) -> anyhow::Result<Vec<ModelEntry>> {
+        Ok(Model::value_variants()
+            .iter()
+            .map(|model| self.entry(*model))
+            .chain(self.custom_models.iter().map(|model| model.entry()))
+            .collect())
+    }
+
+    async fn load(&self, name: &str) -> anyhow::Result<Arc<dyn JobProcessor>> {
+        if let Err(err) = model_cache::record_usage(&self.storage, name).await {
+            warn!("Could not record the usage of {name}: {err}");
+        }
+        let tokenizer = self.tokenizers.get(name);
+        if let Some(custom) = self.custom_models.iter().find(|m| m.name == name) {
+            let mut files = custom.files.clone();
+            if let Some(tokenizer) = tokenizer {
+                files.tokenizer = tokenizer.clone();
+            }
+            let version = self.pinned_version(name).await?;
+            let mut models =
+                MusicGenModels::from_files(&files, custom.fp16, self.device, &self.threads)?;
+            models.version = Some(version);
+            models.seed = self.seed;
+            models.batch_size = self.batch_size;
+            let models = ReloadableModels::new(models, files, custom.fp16, None, self);
+            let default = self.generation_config();
+            let segment_duration = default
+                .segment_duration
+                .min(custom.capabilities.max_secs as f32);
+            let overlap_duration = default
+                .overlap_duration
+                .min((segment_duration / 2.0).floor());
+            let config = ExtendedGenerationConfig {
+                segment_duration,
+                overlap_duration,
+                crossfade_duration: default.crossfade_duration.min(overlap_duration),
+                ..default
+            };
+            let processor = ExtendedJobProcessor::new(
+                self.pooled(models),
+                config,
+                custom.capabilities.sample_rate,
+            )
+            .map_err(|err| anyhow!(err))?;
+            return Ok(Arc::new(processor));
+        }
+        let model = Model::from_str(name, true).map_err(|err| anyhow::anyhow!(err))?;
+        let mut files = MusicGenFiles::download(
+            self.storage.clone(),
+            model,
+            self.use_split_decoder,
+            self.force_download,
+        )
+        .await?;
+        if let Some(tokenizer) = tokenizer {
+            files.tokenizer = tokenizer.clone();
+        }
+        let version = self.pinned_version(name).await?;
+        let fp16 = is_fp16(model);
+        let draft = self.draft(model).await?;
+        let mut models = MusicGenModels::from_files(&files, fp16, self.device, &self.threads)?;
+        if let Some(draft) = &draft {
+            models = draft.apply(models, self.device, &self.threads)?;
+        }
+        models.version = Some(version);
+        models.seed = self.seed;
+        models.batch_size = self.batch_size;
+        let models = ReloadableModels::new(models, files, fp16, draft, self);
+        let processor =
+            ExtendedJobProcessor::new(self.pooled(models), self.generation_config(), SAMPLING_RATE)
+                .map_err(|err| anyhow::anyhow!(err))?;
+        Ok(Arc::new(processor))
+    }
+}
+
+/// Returns the list of (remote url, local file) pairs that compose a model. The
+/// order matters, as it is the order in which the files are loaded.
+pub fn remote_file_spec(
+    model: Model,
+    use_split_decoder: bool,
+) -> Vec<(&'static str, &'static str)> {
+    macro_rules! hf_url {
+        ($t: expr) => {
+            (
+                concat!(
+                    "https://huggingface.co/gabotechs/music_gen/resolve/main/",
+                    $t
+                ),
+                concat!("v1/", $t,),
+            )
+        };
+    }
+    match (model, use_split_decoder) {
+        (Model::Small, true) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_fp32/decoder_model.onnx"),
+            hf_url!("small_fp32/decoder_with_past_model.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallQuant, true) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_i8/decoder_model.onnx"),
+            hf_url!("small_i8/decoder_with_past_model.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallFp16, true) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp16/text_encoder.onnx"),
+            hf_url!("small_fp16/decoder_model.onnx"),
+            hf_url!("small_fp16/decoder_with_past_model.onnx"),
+            hf_url!("small_fp16/encodec_decode.onnx"),
+        ],
+        (Model::Medium, true) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_fp32/decoder_model.onnx"),
+            hf_url!("medium_fp32/decoder_with_past_model.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_fp32/decoder_model.onnx_data"),
+            hf_url!("medium_fp32/decoder_with_past_model.onnx_data"),
+        ],
+        (Model::MediumQuant, true) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_i8/decoder_model.onnx"),
+            hf_url!("medium_i8/decoder_with_past_model.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+        ],
+        (Model::MediumFp16, true) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp16/text_encoder.onnx"),
+            hf_url!("medium_fp16/decoder_model.onnx"),
+            hf_url!("medium_fp16/decoder_with_past_model.onnx"),
+            hf_url!("medium_fp16/encodec_decode.onnx"),
+        ],
+        (Model::Large, true) => vec![
+            hf_url!("large/config.json"),
+            hf_url!("large/tokenizer.json"),
+            hf_url!("large_fp32/text_encoder.onnx"),
+            hf_url!("large_fp32/decoder_model.onnx"),
+            hf_url!("large_fp32/decoder_with_past_model.onnx"),
+            hf_url!("large_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("large_fp32/decoder_model.onnx_data"),
+            hf_url!("large_fp32/decoder_with_past_model.onnx_data"),
+        ],
+        (Model::Small, false) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_fp32/decoder_model_merged.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallQuant, false) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_i8/decoder_model_merged.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallFp16, false) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp16/text_encoder.onnx"),
+            hf_url!("small_fp16/decoder_model_merged.onnx"),
+            hf_url!("small_fp16/encodec_decode.onnx"),
+        ],
+        (Model::Medium, false) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_fp32/decoder_model_merged.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_fp32/decoder_model_merged.onnx_data"),
+        ],
+        (Model::MediumQuant, false) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_i8/decoder_model_merged.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+        ],
+        (Model::MediumFp16, false) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp16/text_encoder.onnx"),
+            hf_url!("medium_fp16/decoder_model_merged.onnx"),
+            hf_url!("medium_fp16/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_fp16/decoder_model_merged.onnx_data"),
+        ],
+        (Model::Large, false) => vec![
+            hf_url!("large/config.json"),
+            hf_url!("large/tokenizer.json"),
+            hf_url!("large_fp32/text_encoder.onnx"),
+            hf_url!("large_fp32/decoder_model_merged.onnx"),
+            hf_url!("large_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("large_fp32/decoder_model_merged.onnx_data"),
+        ],
+    }
+}
+
 impl JobProcessor for MusicGenModels {
     fn process(
         &self,
         prompt: &str,
         secs: usize,
         on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
+    ) -> ort::Result<Vec<f32>> {
         let max_len = secs * INPUT_IDS_BATCH_PER_SECOND;
 
         let (lhs, am) = self.encode_text(prompt)?;
         let token_stream = self.generate_tokens(lhs, am, max_len)?;
 
-        let mut data = VecDeque::new();
+        let mut data = vec![];
+        let decoding = debug_span!("decoding").entered();
         while let Ok(tokens) = token_stream.recv() {
-            data.push_back(tokens?);
+            data.push(tokens?);
             let should_exit = on_progress(data.len() as f32, max_len as f32);
             if should_exit {
                 return Err(ort::Error::new("Aborted"));
             }
         }
+        drop(decoding);
 
         self.encode_audio(data)
     }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.stream(prompt, secs, self.seed, on_progress, sink)
+    }
+
+    fn process_seeded(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seed: u64,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.stream(prompt, secs, Some(seed), on_progress, sink)
+    }
+
+    /// Continues the decoding of `from` with the same decoder state if the prompt is the
+    /// same and there is room for the new frames, or from its last frames otherwise.
+    /// Decoders that cannot be driven one run at a time, like the split ones, start over.
+    fn process_continuing(
+        &self,
+        prompt: &str,
+        secs: usize,
+        from: Option<Continuation>,
+        context: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<Option<Continuation>> {
+        let Some(steps) = self.decoder.steps() else {
+            self.process_streaming(prompt, secs, on_progress, sink)?;
+            return Ok(None);
+        };
+        let frames = from.as_ref().map_or(&[][..], |from| from.frames());
+        let context = ((context * INPUT_IDS_BATCH_PER_SECOND as f32) as usize).min(frames.len());
+        let max_len = (secs * INPUT_IDS_BATCH_PER_SECOND).saturating_sub(context);
+        // The codec is given some frames before the context, so that its audio is the
+        // same as the end of the previous one.
+        let previous =
+            frames[frames.len().saturating_sub(context + STREAM_CONTEXT_FRAMES)..].to_vec();
+
+        let reused = from
+            .as_ref()
+            .is_some_and(|from| from.reusable(prompt, max_len));
+        let started = match reused {
+            true => None,
+            false => {
+                let (lhs, am) = self.encode_text(prompt)?;
+                Some(steps.start(&lhs, &am)?)
+            }
+        };
+        let (token_stream, decoding) =
+            continue_tokens(steps, prompt, started, from, context, max_len, self.seed);
+        self.stream_tokens(token_stream, previous, context, max_len, on_progress, sink)?;
+        decoding
+            .join()
+            .map_err(|_| ort::Error::new("The decoding thread panicked"))
+    }
+
+    fn process_variations(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seeds: &[u64],
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<Vec<f32>>> {
+        let max_len = secs * INPUT_IDS_BATCH_PER_SECOND;
+        let total = (max_len * seeds.len()) as f32;
+        let report = |done: usize| match on_progress(done as f32, total) {
+            true => Err(ort::Error::new("Aborted")),
+            false => Ok(()),
+        };
+        let batch_size = match self.decoder.decodes_batches() {
+            true => self.batch_size.max(1),
+            false => 1,
+        };
+
+        let mut variations = vec![];
+        for batch in seeds.chunks(batch_size) {
+            let (lhs, am) = self.encode_text(prompt)?;
+            let done = variations.len() * max_len;
+            let mut frames = vec![vec![]; batch.len()];
+            let decoding = debug_span!("decoding").entered();
+            if let [seed] = batch {
+                let token_stream = self
+                    .decoder
+                    .generate_tokens(lhs, am, max_len, Some(*seed))?;
+                while let Ok(tokens) = token_stream.recv() {
+                    frames[0].push(tokens?);
+                    report(done + frames[0].len())?;
+                }
+            } else {
+                let token_stream = self.decoder.generate_token_batch(lhs, am, max_len, batch)?;
+                while let Ok(tokens) = token_stream.recv() {
+                    for (frames, tokens) in frames.iter_mut().zip(tokens?) {
+                        frames.push(tokens);
+                    }
+                    report(done + frames[0].len() * batch.len())?;
+                }
+            }
+            drop(decoding);
+            for frames in frames {
+                variations.push(self.encode_audio(frames)?);
+            }
+        }
+        Ok(variations)
+    }
+
+    fn model_version(&self) -> Option<ModelVersion> {
+        self.version.clone()
+    }
+}
+
+/// [MusicGenModels] that are loaded again from their files when recovering from a failure,
+/// as some failures, like running out of GPU memory, leave the sessions in a broken state.
+/// After running out of GPU memory, they are loaded on the CPU instead.
+struct ReloadableModels {
+    /// None while reloading.
+    models: RwLock<Option<Arc<MusicGenModels>>>,
+    files: MusicGenFiles,
+    fp16: bool,
+    draft: Option<Draft>,
+    version: Option<ModelVersion>,
+    seed: Option<u64>,
+    batch_size: usize,
+    gpu: bool,
+    device: SessionDevice,
+    threads: SessionThreads,
+    cpu_fallback: AtomicBool,
+    /// Whether the sessions were not used since they were loaded, so there is nothing to
+    /// recover from.
+    fresh: AtomicBool,
+}
+
+impl ReloadableModels {
+    fn new<S: Storage>(
+        models: MusicGenModels,
+        files: MusicGenFiles,
+        fp16: bool,
+        draft: Option<Draft>,
+        registry: &MusicGenModelRegistry<S>,
+    ) -> Self {
+        Self {
+            version: models.version.clone(),
+            seed: models.seed,
+            batch_size: models.batch_size,
+            models: RwLock::new(Some(Arc::new(models))),
+            files,
+            fp16,
+            draft,
+            gpu: registry.gpu,
+            device: registry.device,
+            threads: registry.threads.clone(),
+            cpu_fallback: AtomicBool::new(false),
+            fresh: AtomicBool::new(true),
+        }
+    }
+
+    fn current(&self) -> ort::Result<Arc<MusicGenModels>> {
+        let terminated = (self.models.read().unwrap().as_ref())
+            .is_some_and(|models| models.terminated.load(Ordering::SeqCst));
+        if terminated {
+            self.reload()?;
+        }
+        self.fresh.store(false, Ordering::SeqCst);
+        match self.models.read().unwrap().as_ref() {
+            Some(models) => Ok(models.clone()),
+            None => Err(ort::Error::new("The model failed to be reloaded")),
+        }
+    }
+
+    fn reload(&self) -> ort::Result<()> {
+        let mut models = self.models.write().unwrap();
+        // Release the broken sessions before loading the new ones, so both do not need
+        // to fit in memory at the same time.
+        *models = None;
+        *models = Some(Arc::new(self.load()?));
+        self.fresh.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Another instance of the same models, with sessions of its own.
+    fn fork(&self) -> ort::Result<Self> {
+        Ok(Self {
+            models: RwLock::new(Some(Arc::new(self.load()?))),
+            files: self.files.clone(),
+            draft: self.draft.clone(),
+            version: self.version.clone(),
+            threads: self.threads.clone(),
+            cpu_fallback: AtomicBool::new(self.cpu_fallback.load(Ordering::SeqCst)),
+            fresh: AtomicBool::new(true),
+            ..*self
+        })
+    }
+
+    fn load(&self) -> ort::Result<MusicGenModels> {
+        let device = match self.cpu_fallback.load(Ordering::SeqCst) {
+            true => SessionDevice::Cpu,
+            false => self.device,
+        };
+        let mut reloaded =
+            MusicGenModels::from_files(&self.files, self.fp16, device, &self.threads)
+                .map_err(|err| ort::Error::new(err.to_string()))?;
+        if let Some(draft) = &self.draft {
+            reloaded = draft
+                .apply(reloaded, device, &self.threads)
+                .map_err(|err| ort::Error::new(err.to_string()))?;
+        }
+        reloaded.version = self.version.clone();
+        reloaded.seed = self.seed;
+        reloaded.batch_size = self.batch_size;
+        Ok(reloaded)
+    }
+
+    /// Reloads the models on the CPU if `err` means that the GPU ran out of memory,
+    /// returning whether it did.
+    fn fall_back_to_cpu(&self, err: &ort::Error) -> ort::Result<bool> {
+        if !self.gpu
+            || !is_out_of_memory(&err.to_string())
+            || self.cpu_fallback.swap(true, Ordering::SeqCst)
+        {
+            return Ok(false);
+        }
+        warn!("Ran out of GPU memory, the model will run on the CPU from now on: {err}");
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Streams the audio generated by `generate`, generating it again on the CPU if the
+    /// GPU ran out of memory before any audio was produced.
+    fn stream(
+        &self,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+        generate: impl Fn(
+            &MusicGenModels,
+            Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+            &mut dyn AudioSink,
+        ) -> ort::Result<()>,
+    ) -> ort::Result<()> {
+        let on_progress = Arc::from(on_progress);
+        let mut counting = CountingSink {
+            inner: sink,
+            pushed: 0,
+        };
+        let result = generate(
+            &*self.current()?,
+            forward_progress(&on_progress),
+            &mut counting,
+        );
+        match result {
+            // Starting again is only possible if no audio was produced yet. Otherwise,
+            // retrying is up to the caller, which will find the models on the CPU.
+            Err(err) if self.fall_back_to_cpu(&err)? && counting.pushed == 0 => {
+                generate(&*self.current()?, forward_progress(&on_progress), sink)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Passes an already boxed progress callback to several attempts of the same job.
+fn forward_progress(
+    on_progress: &Arc<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+) -> Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static> {
+    let on_progress = on_progress.clone();
+    Box::new(move |elapsed, total| on_progress(elapsed, total))
+}
+
+/// Counts the samples pushed into the wrapped sink.
+struct CountingSink<'a> {
+    inner: &'a mut dyn AudioSink,
+    pushed: usize,
+}
+
+impl AudioSink for CountingSink<'_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.pushed += chunk.len();
+        self.inner.push(chunk)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.inner.finalize()
+    }
+}
+
+impl JobProcessor for ReloadableModels {
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<f32>> {
+        let on_progress = Arc::from(on_progress);
+        match self
+            .current()?
+            .process(prompt, secs, forward_progress(&on_progress))
+        {
+            Err(err) if self.fall_back_to_cpu(&err)? => {
+                self.current()?
+                    .process(prompt, secs, forward_progress(&on_progress))
+            }
+            result => result,
+        }
+    }
+
+    fn process_streaming(
+        &self,
+        prompt: &str,
+        secs: usize,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.stream(on_progress, sink, |models, on_progress, sink| {
+            models.process_streaming(prompt, secs, on_progress, sink)
+        })
+    }
+
+    fn process_seeded(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seed: u64,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<()> {
+        self.stream(on_progress, sink, |models, on_progress, sink| {
+            models.process_seeded(prompt, secs, seed, on_progress, sink)
+        })
+    }
+
+    /// `from` is used up by the first attempt, so running out of GPU memory fails the
+    /// generation even before any audio was produced, for the caller to start over.
+    fn process_continuing(
+        &self,
+        prompt: &str,
+        secs: usize,
+        from: Option<Continuation>,
+        context: f32,
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+        sink: &mut dyn AudioSink,
+    ) -> ort::Result<Option<Continuation>> {
+        let result =
+            self.current()?
+                .process_continuing(prompt, secs, from, context, on_progress, sink);
+        if let Err(err) = &result {
+            self.fall_back_to_cpu(err)?;
+        }
+        result
+    }
+
+    fn process_variations(
+        &self,
+        prompt: &str,
+        secs: usize,
+        seeds: &[u64],
+        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
+    ) -> ort::Result<Vec<Vec<f32>>> {
+        let on_progress = Arc::from(on_progress);
+        match self.current()?.process_variations(
+            prompt,
+            secs,
+            seeds,
+            forward_progress(&on_progress),
+        ) {
+            Err(err) if self.fall_back_to_cpu(&err)? => self.current()?.process_variations(
+                prompt,
+                secs,
+                seeds,
+                forward_progress(&on_progress),
+            ),
+            result => result,
+        }
+    }
+
+    fn model_version(&self) -> Option<ModelVersion> {
+        self.version.clone()
+    }
+
+    fn recover(&self) -> ort::Result<()> {
+        if self.fresh.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.reload()
+    }
+
+    /// Terminates the current models, which are loaded again before the next job.
+    fn terminate(&self, _worker: ThreadId) {
+        if let Some(models) = self.models.read().unwrap().as_ref() {
+            models.terminate_runs();
+        }
+    }
 }
 
-async fn build_sessions(
-    files: impl IntoIterator<Item = PathBuf>,
-) -> anyhow::Result<VecDeque<Session>> {
-    let mut results = VecDeque::new();
-    for file in files {
-        if file.extension() != Some("onnx".as_ref()) {
-            continue;
+/// Loads a tokenizer JSON file configured the way the text encoder expects it.
+pub fn load_tokenizer(file: &Path) -> anyhow::Result<Tokenizer> {
+    let mut tokenizer = Tokenizer::from_file(file)
+        .map_err(|err| anyhow!("Could not load tokenizer {file:?}: {err}"))?;
+    tokenizer
+        .with_padding(None)
+        .with_truncation(None)
+        .map_err(|err| anyhow!("Could not configure tokenizer {file:?}: {err}"))?;
+    Ok(tokenizer)
+}
+
+/// Checks that all the tokens the tokenizer can produce are known by the text encoder,
+/// whose embedding table has `vocab_size` rows.
+fn check_vocab_size(tokenizer: &Tokenizer, vocab_size: Option<usize>) -> anyhow::Result<()> {
+    let Some(vocab_size) = vocab_size else {
+        warn!("The vocabulary size of the text encoder is unknown, so the tokenizer could not be checked against it");
+        return Ok(());
+    };
+    // The text encoder's embedding table is usually padded, so the tokenizer is
+    // allowed to have less entries, but never more.
+    let tokenizer_vocab_size = tokenizer.get_vocab_size(true);
+    if tokenizer_vocab_size > vocab_size {
+        return Err(anyhow!(
+            "it has a vocabulary of {tokenizer_vocab_size} tokens, but the text encoder only supports {vocab_size}"
+        ));
+    }
+    Ok(())
+}
+
+/// Builds an ONNX session, failing if the model does not declare the expected inputs and outputs.
+fn build_session(
+    file: &Path,
+    inputs: &[&str],
+    outputs: &[&str],
+    device: SessionDevice,
+    threads: &SessionThreads,
+) -> anyhow::Result<Session> {
+    let bar = spinner(format!("Loading {:?}...", file.file_name().unwrap_or_default()).as_str());
+    let mut builder = Session::builder()?.with_execution_providers(device.execution_providers())?;
+    if let Some(intra_threads) = threads.intra_threads() {
+        builder = builder.with_intra_threads(intra_threads)?;
+        if let Some(cpus) = threads.cpus.as_ref().filter(|_| intra_threads > 1) {
+            builder = builder.with_config_entry(
+                "session.intra_op_thread_affinities",
+                cpus.ort_affinities(intra_threads),
+            )?;
         }
-        let bar =
-            spinner(format!("Loading {:?}...", file.file_name().unwrap_or_default()).as_str());
+    }
+    let session = builder
+        .commit_from_file(file)
+        .map_err(|err| anyhow!("Could not load {file:?}: {err}"));
+    bar.finish_and_clear();
+    let session = session?;
 
-        let result = Session::builder()?.commit_from_file(file)?;
-        bar.finish_and_clear();
-        results.push_back(result);
+    for input in inputs {
+        if !session.inputs.iter().any(|i| i.name == *input) {
+            return Err(anyhow!("{file:?} is missing the expected input {input:?}"));
+        }
+    }
+    for output in outputs {
+        if !session.outputs.iter().any(|o| o.name == *output) {
+            return Err(anyhow!(
+                "{file:?} is missing the expected output {output:?}"
+            ));
+        }
     }
-    Ok(results)
+    Ok(session)
 }
 
 pub fn spinner(msg: impl Into<String>) -> ProgressBar {
@@ -312,3 +1320,40 @@ pub fn spinner(msg: impl Into<String>) -> ProgressBar {
     pb.set_message(msg.into());
     pb
 }
+
+#[cfg(test)]
+mod tests {
+    use tokenizers::models::wordlevel::WordLevel;
+
+    use super::*;
+
+    fn tokenizer(size: u32) -> Tokenizer {
+        let vocab = (0..size).map(|i| (format!("t{i}"), i)).collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("t0".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn validates_tokenizer_vocab_size() {
+        assert!(check_vocab_size(&tokenizer(10), Some(12)).is_ok());
+        assert!(check_vocab_size(&tokenizer(12), Some(12)).is_ok());
+        assert!(check_vocab_size(&tokenizer(10), None).is_ok());
+        let err = check_vocab_size(&tokenizer(13), Some(12)).unwrap_err();
+        assert!(err.to_string().contains("vocabulary of 13 tokens"));
+    }
+
+    #[test]
+    fn loads_tokenizer_files() -> anyhow::Result<()> {
+        let dir = PathBuf::from(format!("/tmp/musicgpt-tests/{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let file = dir.join("tokenizer.json");
+        tokenizer(5).save(&file, false).unwrap();
+        assert_eq!(load_tokenizer(&file)?.get_vocab_size(true), 5);
+        assert!(load_tokenizer(&dir.join("missing.json")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Timing of the stages of the generation, like text encoding, decoding or crossfading,
+//! taken from the spans around them. While profiling, every span is recorded, and
+//! the recording is written as a Chrome trace, which Perfetto, speedscope or
+//! chrome://tracing show as a flame graph.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{info, Subscriber};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// The recording, None while not profiling.
+static PROFILE: Mutex<Option<Profile>> = Mutex::new(None);
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Small number identifying the current thread in the trace.
+    static THREAD: u64 = NEXT_THREAD.fetch_add(1, Ordering::SeqCst);
+}
+
+struct Profile {
+    start: Instant,
+    events: Vec<TraceEvent>,
+}
+
+/// A complete event of the Chrome trace format, with its times in microseconds.
+#[derive(Serialize)]
+struct TraceEvent {
+    name: &'static str,
+    ph: &'static str,
+    ts: u64,
+    dur: u64,
+    pid: u32,
+    tid: u64,
+    args: TraceArgs,
+}
+
+#[derive(Serialize)]
+struct TraceArgs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segment: Option<u64>,
+}
+
+/// Kept in the extensions of each span while it is open.
+struct SpanTiming {
+    start: Instant,
+    segment: Option<u64>,
+}
+
+#[derive(Default)]
+struct SegmentVisitor(Option<u64>);
+
+impl Visit for SegmentVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "segment" {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+struct ProfileLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for ProfileLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if PROFILE.lock().unwrap().is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = SegmentVisitor::default();
+        attrs.record(&mut visitor);
+        // Stages inherit the segment of the span they run in.
+        let segment = visitor.0.or_else(|| {
+            span.parent()
+                .and_then(|parent| parent.extensions().get::<SpanTiming>()?.segment)
+        });
+        span.extensions_mut().insert(SpanTiming {
+            start: Instant::now(),
+            segment,
+        });
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(timing) = span.extensions_mut().remove::<SpanTiming>() else {
+            return;
+        };
+        let mut profile = PROFILE.lock().unwrap();
+        let Some(profile) = profile.as_mut() else {
+            return;
+        };
+        let micros = |duration: Duration| duration.as_micros() as u64;
+        profile.events.push(TraceEvent {
+            name: span.name(),
+            ph: "X",
+            ts: micros(timing.start.saturating_duration_since(profile.start)),
+            dur: micros(timing.start.elapsed()),
+            pid: std::process::id(),
+            tid: THREAD.with(|thread| *thread),
+            args: TraceArgs {
+                segment: timing.segment,
+            },
+        });
+    }
+}
+
+/// Layer that records the spans of MusicGPT while profiling.
+pub fn layer<S: Subscriber + for<'a> LookupSpan<'a>>() -> impl Layer<S> {
+    ProfileLayer.with_filter(filter_fn(|metadata| {
+        metadata.is_span() && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }))
+}
+
+/// Starts recording the spans.
+pub fn start() {
+    *PROFILE.lock().unwrap() = Some(Profile {
+        start: Instant::now(),
+        events: vec![],
+    });
+}
+
+/// Stops recording, writing the recorded spans to `path` as a Chrome trace, and logs the
+/// total time spent in each stage.
+pub fn finish(path: &Path) -> anyhow::Result<()> {
+    let Some(profile) = PROFILE.lock().unwrap().take() else {
+        return Ok(());
+    };
+    std::fs::write(path, trace(&profile.events)?)?;
+    let totals = totals(&profile.events)
+        .into_iter()
+        .map(|(name, total)| format!("{name} {:.2}s", total.as_secs_f32()))
+        .collect::<Vec<_>>();
+    info!("Profile written to {path:?}: {}", totals.join(", "));
+    Ok(())
+}
+
+fn trace(events: &[TraceEvent]) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&serde_json::json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+    }))
+}
+
+/// Time spent in each stage, which for nested stages includes the ones inside them.
+fn totals(events: &[TraceEvent]) -> BTreeMap<&'static str, Duration> {
+    let mut totals = BTreeMap::new();
+    for event in events {
+        *totals.entry(event.name).or_default() += Duration::from_micros(event.dur);
+    }
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn records_stages_with_their_segment() -> anyhow::Result<()> {
+        let subscriber = tracing_subscriber::registry().with(layer());
+        tracing::subscriber::with_default(subscriber, || {
+            // Spans are ignored while not profiling.
+            debug_span!("codec").in_scope(|| {});
+            start();
+            let segment = debug_span!("segment", segment = 2usize).entered();
+            debug_span!("decoding").in_scope(|| {
+                debug_span!("codec").in_scope(|| std::thread::sleep(Duration::from_millis(5)));
+            });
+            drop(segment);
+            debug_span!("export").in_scope(|| {});
+        });
+
+        let profile = PROFILE.lock().unwrap().take().unwrap();
+        let names = profile.events.iter().map(|e| e.name).collect::<Vec<_>>();
+        // Spans are recorded as they close, inner ones first.
+        assert_eq!(names, ["codec", "decoding", "segment", "export"]);
+        let segments = profile.events.iter().map(|e| e.args.segment);
+        assert!(segments.eq([Some(2), Some(2), Some(2), None]));
+        let totals = totals(&profile.events);
+        assert!(totals["decoding"] >= totals["codec"]);
+        assert!(totals["codec"] >= Duration::from_millis(5));
+
+        let trace: serde_json::Value = serde_json::from_slice(&trace(&profile.events)?)?;
+        assert_eq!(trace["traceEvents"][0]["name"], "codec");
+        assert_eq!(trace["traceEvents"][0]["args"]["segment"], 2);
+        assert_eq!(trace["traceEvents"][3]["args"], serde_json::json!({}));
+        Ok(())
+    }
+}
@@ -11,7 +11,6 @@ use tokio::io::AsyncWriteExt;
 
 use crate::storage::Storage;
 
-
 #[async_trait]
 pub trait StorageExt: Storage {
     async fn download_many<
@@ -156,7 +155,7 @@ fn io_err<E>(e: E) -> std::io::Error
 where
     E: Into<Box<dyn error::Error + Send + Sync>>,
 {
-    std::io::Error::new(std::io::ErrorKind::Other, e)
+    std::io::Error::other(e)
 }
 
 #[cfg(test)]
@@ -3,26 +3,46 @@ use regex::Regex;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 use std::fmt::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use tracing::warn;
 
+use crate::audio::audio_sink::{wav_file_sink, AudioSink};
+use crate::audio::extended_generation::AuditionVerdict;
+use crate::audio::pipeline::{StreamPipeline, DEFAULT_CAPACITY};
+use crate::audio::prompt_hints::PromptHints;
+use crate::audio::watermark::{WatermarkPayload, Watermarker};
+use crate::audio::wav;
 use crate::audio::{AudioManager, AudioStream};
-use crate::backend::JobProcessor;
+use crate::backend::{ArtworkProvider, ArtworkRequest, JobProcessor, Provenance};
+use crate::cli::SAMPLING_RATE;
 
 pub struct RunTerminalOptions {
     pub init_prompt: String,
-    pub init_secs: usize,
+    /// Seconds asked for with --secs. Otherwise, they are read from the prompt, defaulting
+    /// to [DEFAULT_SECS].
+    pub init_secs: Option<f32>,
     pub init_output: String,
     pub no_playback: bool,
     pub no_interactive: bool,
+    /// Asks whether to keep each segment of extended renders before stitching it.
+    pub audition: bool,
+    /// Embedded into the audio before it is written and played, if any.
+    pub watermark: Option<WatermarkPayload>,
+    /// Recorded in the metadata of the written files, if any.
+    pub license: Option<String>,
+    /// Generates the cover art embedded in the written files, if any.
+    pub artwork: Option<Box<dyn ArtworkProvider>>,
 }
 
+pub const DEFAULT_SECS: f32 = 10.0;
+
 pub async fn run_terminal_loop<T: JobProcessor>(
     root: PathBuf,
     processor: T,
     opts: RunTerminalOptions,
 ) -> anyhow::Result<()> {
-    let secs_re = Regex::new("--secs[ =](\\d+)")?;
+    let secs_re = Regex::new("--secs[ =](\\d+(?:\\.\\d+)?)")?;
     let output_re = Regex::new(r"--output[ =]([.a-zA-Z_-]+)")?;
 
     let audio_player = AudioManager::default();
@@ -31,7 +51,8 @@ pub async fn run_terminal_loop<T: JobProcessor>(
     #[allow(unused_variables)]
     let mut curr_stream: Option<AudioStream> = None;
     let mut prompt = opts.init_prompt;
-    let mut secs = opts.init_secs;
+    let mut secs = DEFAULT_SECS;
+    let mut explicit_secs = opts.init_secs;
     let mut output = opts.init_output;
 
     let mut rl = DefaultEditor::new()?;
@@ -45,7 +66,7 @@ pub async fn run_terminal_loop<T: JobProcessor>(
                 Err(ReadlineError::Eof) => return Ok(()),
                 Err(err) => return Err(anyhow::anyhow!(err)),
             };
-            secs = capture(&secs_re, &prompt).unwrap_or(secs);
+            explicit_secs = capture(&secs_re, &prompt);
             output = capture(&output_re, &prompt).unwrap_or(output);
         }
         if prompt.is_empty() {
@@ -57,8 +78,64 @@ pub async fn run_terminal_loop<T: JobProcessor>(
             return Ok(());
         }
 
+        // Durations in the prompt are used unless --secs is given, and echoed back along
+        // with the rest of what was read from it.
+        let mut hints = PromptHints::parse(&prompt);
+        if explicit_secs.is_some() {
+            hints.secs = None;
+        }
+        secs = explicit_secs.or(hints.secs).unwrap_or(secs);
+        if !hints.is_empty() {
+            match opts.no_interactive {
+                true => println!("Read from the prompt: {hints}"),
+                false => {
+                    let question = format!("Read from the prompt: {hints}. Generate it? [Y/n] ");
+                    let answer = rl.readline(&question).unwrap_or_default();
+                    if answer.trim().to_lowercase().starts_with('n') {
+                        prompt = "".into();
+                        continue;
+                    }
+                }
+            }
+        }
+
+        if !output.ends_with(".wav") {
+            output += ".wav";
+        }
+        // The output file is written while the audio is generated, next to where it goes
+        // so that a failed render does not leave a truncated file there.
+        let samples = (secs * SAMPLING_RATE as f32).round() as usize;
+        let partial = PartialFile(PathBuf::from(format!("{output}.part")));
+        let wav = wav_file_sink(&partial.0, SAMPLING_RATE as u32, samples)
+            .map_err(|err| anyhow::anyhow!(err))?;
+        let mut pipeline = StreamPipeline::new(DEFAULT_CAPACITY);
+        if let Some(payload) = opts.watermark {
+            let mut watermarker = Watermarker::new(payload);
+            pipeline = pipeline.stage(move |mut chunk| {
+                watermarker.apply(&mut chunk);
+                chunk
+            });
+        }
+        let (mut input, handle) = pipeline.spawn(wav);
+
+        for warning in processor
+            .estimate(secs.ceil() as usize)
+            .map(|e| e.warnings)
+            .unwrap_or_default()
+        {
+            warn!("{warning}");
+        }
         let bar = fixed_bar("Generating audio", 1);
-        let samples = processor.process(
+        let mut auditioner = TerminalAuditioner {
+            inner: &mut input,
+            dir: std::env::temp_dir().join("musicgpt-auditions"),
+            bar: bar.clone(),
+        };
+        let sink: &mut dyn AudioSink = match opts.audition {
+            true => &mut auditioner,
+            false => auditioner.inner,
+        };
+        let result = processor.process_exact(
             &prompt,
             secs,
             Box::new(move |elapsed, total| {
@@ -66,22 +143,44 @@ pub async fn run_terminal_loop<T: JobProcessor>(
                 bar.set_position(elapsed as u64);
                 false
             }),
-        )?;
+            sink,
+        );
+        // Dropping the input lets the pipeline finish even if the generation failed.
+        drop(input);
+        let written = handle.join().map_err(|err| anyhow::anyhow!(err));
+        result?;
+        written?;
+        let version = processor.model_version();
+        let provenance = Provenance::generated_now(version.as_ref(), &prompt, opts.license.clone());
+        wav::append_chunk_to_file(&partial.0, &provenance.wav_chunks())
+            .map_err(|err| anyhow::anyhow!(err))?;
+        if let Some(artwork) = &opts.artwork {
+            let request = ArtworkRequest {
+                prompt: prompt.clone(),
+                secs,
+                model: version.map(|version| format!("{} {}", version.name, version.revision)),
+                license: opts.license.clone(),
+            };
+            match artwork.artwork(&request).await {
+                Ok(cover) => {
+                    let chunk = wav::id3_chunk(&request.id3_tag(&cover));
+                    wav::append_chunk_to_file(&partial.0, &chunk)
+                        .map_err(|err| anyhow::anyhow!(err))?;
+                }
+                Err(err) => warn!("Could not generate the cover art: {err}"),
+            }
+        }
+
+        partial.persist(output.as_ref())?;
 
         // Last, play the audio.
         if !opts.no_playback {
-            let samples_copy = samples.clone();
-            let stream = audio_player.play_from_queue(samples_copy);
+            let stream = audio_player.play_file(output.as_ref());
             #[allow(unused_assignments)]
             if let Ok(stream) = stream {
                 curr_stream = Some(stream);
             }
         }
-        if !output.ends_with(".wav") {
-            output += ".wav";
-        }
-        let bytes = audio_player.to_wav(samples)?;
-        tokio::fs::write(&output, bytes).await?;
 
         prompt = "".into();
         if opts.no_interactive {
@@ -92,6 +191,73 @@ pub async fn run_terminal_loop<T: JobProcessor>(
     Ok(())
 }
 
+/// A file being written, which is removed unless it is renamed to its final path.
+struct PartialFile(PathBuf);
+
+impl PartialFile {
+    fn persist(self, path: &Path) -> anyhow::Result<()> {
+        std::fs::rename(&self.0, path)?;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        // Already gone once renamed.
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Writes each segment to a WAV file in `dir` for listening to it, and asks on the
+/// terminal whether to keep it. Anything but an answer starting with n keeps it.
+struct TerminalAuditioner<'a> {
+    inner: &'a mut dyn AudioSink,
+    dir: PathBuf,
+    bar: ProgressBar,
+}
+
+impl AudioSink for TerminalAuditioner<'_> {
+    fn push(&mut self, chunk: &[f32]) -> Result<(), String> {
+        self.inner.push(chunk)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn finalize(&mut self) -> Result<(), String> {
+        self.inner.finalize()
+    }
+
+    fn auditions(&self) -> bool {
+        true
+    }
+
+    fn audition(&mut self, segment: usize, audio: &[f32]) -> Result<AuditionVerdict, String> {
+        std::fs::create_dir_all(&self.dir).map_err(|err| err.to_string())?;
+        let path = self.dir.join(format!("segment-{}.wav", segment + 1));
+        let bytes = wav::encode_wav(audio.iter().copied(), SAMPLING_RATE as u32)
+            .map_err(|err| err.to_string())?;
+        std::fs::write(&path, bytes).map_err(|err| format!("Could not write {path:?}: {err}"))?;
+        let mut answer = String::new();
+        self.bar
+            .suspend(|| {
+                print!(
+                    "Segment {} written to {}, keep it? [Y/n] ",
+                    segment + 1,
+                    path.display()
+                );
+                let _ = std::io::Write::flush(&mut std::io::stdout());
+                std::io::stdin().read_line(&mut answer)
+            })
+            .map_err(|err| err.to_string())?;
+        match answer.trim().to_lowercase().starts_with('n') {
+            true => Ok(AuditionVerdict::Reject),
+            false => Ok(AuditionVerdict::Accept),
+        }
+    }
+}
+
 pub fn fixed_bar(prefix: impl Into<String>, len: usize) -> ProgressBar {
     let pb = ProgressBar::new(len as u64);
     pb.set_style(
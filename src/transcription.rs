@@ -0,0 +1,112 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use ort::session::Session;
+use ort::value::Tensor;
+
+use crate::audio::midi::{self, Activations, NoteEvent, KEYS};
+use crate::audio::resample::resample;
+
+/// Rate basic-pitch models take audio at.
+const MODEL_RATE: u32 = 22050;
+/// Samples in each window the model is run on, and in the hop between its frames.
+const WINDOW: usize = 43844;
+const MODEL_HOP: usize = 256;
+/// Frames at each side of a window that overlap with the next one, and are dropped
+/// because the model sees too little context around them.
+const OVERLAP_FRAMES: usize = 30;
+
+/// Transcribes audio into notes, with a basic-pitch model when there is one, and
+/// otherwise with the estimation in [midi::estimate_activations].
+pub struct Transcriber {
+    model: Option<Session>,
+}
+
+impl Transcriber {
+    /// Loads the ONNX export of a basic-pitch model, which takes a window of mono audio at
+    /// 22050Hz and outputs the note and onset activations of the 88 keys, in that order,
+    /// next to the pitch contour.
+    pub fn new(model: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(file) = model else {
+            return Ok(Self { model: None });
+        };
+        let session = Session::builder()?
+            .commit_from_file(file)
+            .map_err(|err| anyhow!("Could not load {file:?}: {err}"))?;
+        if session.inputs.len() != 1 || key_outputs(&session).len() != 2 {
+            return Err(anyhow!(
+                "{file:?} is not a basic-pitch model, it needs one input and two outputs of 88 keys"
+            ));
+        }
+        Ok(Self {
+            model: Some(session),
+        })
+    }
+
+    pub fn transcribe(&self, audio: &[f32], sample_rate: u32) -> anyhow::Result<Vec<NoteEvent>> {
+        let activations = match &self.model {
+            Some(session) => model_activations(session, audio, sample_rate)?,
+            None => midi::estimate_activations(audio, sample_rate),
+        };
+        Ok(midi::notes_from_activations(&activations))
+    }
+}
+
+/// Names of the outputs with a value for each key.
+fn key_outputs(session: &Session) -> Vec<String> {
+    session
+        .outputs
+        .iter()
+        .filter(|o| {
+            let dimensions = o.output_type.tensor_dimensions();
+            dimensions.and_then(|d| d.last()) == Some(&(KEYS as i64))
+        })
+        .map(|o| o.name.clone())
+        .collect()
+}
+
+/// Runs the model on overlapping windows, keeping the frames in the middle of each one.
+fn model_activations(
+    session: &Session,
+    audio: &[f32],
+    sample_rate: u32,
+) -> anyhow::Result<Activations> {
+    let mut audio = resample(audio, sample_rate, MODEL_RATE);
+    let frames = audio.len().div_ceil(MODEL_HOP);
+    let overlap = OVERLAP_FRAMES * MODEL_HOP;
+    let hop = WINDOW - overlap;
+    audio.splice(0..0, std::iter::repeat_n(0.0, overlap / 2));
+
+    let outputs = key_outputs(session);
+    let mut activations = Activations {
+        frame_rate: MODEL_RATE as f32 / MODEL_HOP as f32,
+        ..Default::default()
+    };
+    let mut start = 0;
+    while activations.notes.len() < frames {
+        let mut window = audio[start.min(audio.len())..(start + WINDOW).min(audio.len())].to_vec();
+        window.resize(WINDOW, 0.0);
+        let input = Tensor::from_array(([1, WINDOW, 1], window))?;
+        let result = session.run(ort::inputs![input]?)?;
+        let [notes, onsets] = [&outputs[0], &outputs[1]].map(|name| {
+            let (_, data) = result[name.as_str()].try_extract_raw_tensor::<f32>()?;
+            let keys: Vec<[f32; KEYS]> = data
+                .chunks_exact(KEYS)
+                .skip(OVERLAP_FRAMES / 2)
+                .map(|frame| frame.try_into().expect("Programming error"))
+                .collect();
+            Ok::<_, ort::Error>(keys)
+        });
+        let (notes, onsets) = (notes?, onsets?);
+        if notes.is_empty() {
+            return Err(anyhow!("The model output no frames for a window of audio"));
+        }
+        let keep = notes.len().saturating_sub(OVERLAP_FRAMES / 2).max(1);
+        activations.notes.extend(notes.into_iter().take(keep));
+        activations.onsets.extend(onsets.into_iter().take(keep));
+        start += hop;
+    }
+    activations.notes.truncate(frames);
+    activations.onsets.truncate(frames);
+    Ok(activations)
+}
@@ -0,0 +1,43 @@
+//! Bindings of the audio processing layer for web apps, so segments generated server-side
+//! can be stitched and post-processed in the browser. Build it with:
+//!
+//! ```sh
+//! cargo rustc --release --lib --target wasm32-unknown-unknown --no-default-features --crate-type cdylib
+//! wasm-bindgen --target web target/wasm32-unknown-unknown/release/musicgpt.wasm --out-dir pkg
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use crate::audio::extended_generation;
+use crate::audio::resample;
+use crate::audio::wav;
+
+/// Appends `next` to `previous`, crossfading them like extended generation does with
+/// consecutive segments.
+#[wasm_bindgen]
+pub fn crossfade(
+    previous: &[f32],
+    next: &[f32],
+    overlap_samples: usize,
+    crossfade_samples: usize,
+) -> Vec<f32> {
+    extended_generation::crossfade(previous, next, overlap_samples, crossfade_samples)
+}
+
+/// Converts the sample rate of a whole piece of audio.
+#[wasm_bindgen]
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    resample::resample(samples, from, to)
+}
+
+/// Encodes mono audio as a WAV file.
+#[wasm_bindgen(js_name = encodeWav)]
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>, JsError> {
+    Ok(wav::encode_wav(samples.iter().copied(), sample_rate)?)
+}
+
+/// Decodes a WAV file produced by the server or by [encode_wav].
+#[wasm_bindgen(js_name = decodeWav)]
+pub fn decode_wav(bytes: &[u8]) -> Result<Vec<f32>, JsError> {
+    wav::decode_wav(bytes).map_err(|err| JsError::new(&err))
+}
@@ -1,4 +1,5 @@
 import { useEffect, useMemo, useRef, useState } from "react";
+import { Link } from "react-router-dom";
 
 import ChatInput from "./components/ChatInput.tsx";
 import { useChat } from "./backend/useChat.ts";
@@ -52,7 +53,8 @@ function App () {
           <ToggleButton onClick={() => setDrawerOpen(true)}/>
         </div>
         <StatusIndicator className="m-2 w-fit"/>
-        <div className="w-1/3 flex flex-row justify-end">
+        <div className="w-1/3 flex flex-row justify-end items-center">
+          <Link to="/queue" className="mr-4 underline text-[var(--text-faded-color)]">Jobs</Link>
           <ThemeToggle className="mr-2" onToggle={toggleTheme} theme={theme}/>
         </div>
       </div>
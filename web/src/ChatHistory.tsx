@@ -3,6 +3,7 @@ import AudioGenerating from "./components/AudioGenerating.tsx";
 import AudioFailure from "./components/AudioFailure.tsx";
 import { AudioSuccess } from "./components/AudioSuccess.tsx";
 import { ChatMessage } from "./backend/useChat.ts";
+import { JOBS_URL, withToken } from "./backend/useBackend.ts";
 
 
 export interface ChatHistoryProps {
@@ -25,6 +26,8 @@ export function ChatHistory ({ messages, className = '' }: ChatHistoryProps) {
             className={'mb-8'}
             key={key}
             progress={msg.progress}
+            streamUrl={msg.progress > 0 ? withToken(`${JOBS_URL}/${msg.id}/stream.opus`) : undefined}
+            partialUrl={msg.progress > 0 ? withToken(`${JOBS_URL}/${msg.id}/audio?upto=now`) : undefined}
           />
         } else if (msg.error !== undefined) {
           return <AudioFailure
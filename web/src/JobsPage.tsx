@@ -0,0 +1,142 @@
+import { ReactNode } from "react";
+import { Link } from "react-router-dom";
+
+import { FILES_URL, JOBS_URL, withToken } from "./backend/useBackend.ts";
+import { Job, useJobs } from "./backend/useJobs.ts";
+import { JobQueuePosition } from "./backend/bindings.ts";
+import { StatusIndicator } from "./StatusIndicator.tsx";
+import ThemeToggle from "./components/ThemeToggle.tsx";
+import { useThemeToggle } from "./components/ThemeToggleHook.tsx";
+import { LoadingIcon } from "./Icons/LoadingIcon.tsx";
+
+function JobsPage () {
+  const { waiting, queued, running, finished, error, cancel, retry } = useJobs()
+  const { theme, toggleTheme } = useThemeToggle()
+
+  return (
+    <div
+      className={`min-h-screen w-full bg-[var(--background-color)] text-[var(--text-color)] ${theme === 'dark' ? 'dark' : ''}`}>
+      <div className="flex items-center justify-between px-4 py-2">
+        <Link to="/chats/new" className="w-1/3 underline text-[var(--text-faded-color)]">Back to chats</Link>
+        <StatusIndicator className="m-2 w-fit"/>
+        <div className="w-1/3 flex flex-row justify-end">
+          <ThemeToggle className="mr-2" onToggle={toggleTheme} theme={theme}/>
+        </div>
+      </div>
+      <div className="max-w-3xl mx-auto p-2 space-y-6">
+        {error !== undefined && <div className="text-red-500">{error}</div>}
+        <JobSection title="Running" jobs={running} empty="Nothing is being generated">
+          {job => (
+            <>
+              <SegmentProgress job={job}/>
+              <audio controls preload="none" src={withToken(`${JOBS_URL}/${job.manifest.id}/stream.opus`)} className="w-full"/>
+              <button type="button" className="underline" onClick={() => cancel(job.manifest)}>Cancel</button>
+            </>
+          )}
+        </JobSection>
+        <section className="space-y-2">
+          <h2 className="text-lg font-bold">Waiting ({waiting.length})</h2>
+          {waiting.length === 0 && <div className="text-[var(--text-faded-color)]">No job is waiting for the running one</div>}
+          {waiting.map(job => (
+            <div key={job.id} className="p-3 rounded-lg bg-[var(--card-background-color)] space-y-2">
+              <div className="flex justify-between">
+                <span className="font-semibold">{job.prompt || 'Untitled'}</span>
+                <span className="text-sm text-[var(--text-faded-color)]">{job.secs}s</span>
+              </div>
+              <div className="text-sm text-[var(--text-faded-color)]">
+                #{job.position} in line, {job.jobs_ahead} {job.jobs_ahead === 1 ? 'job' : 'jobs'} ahead{startsIn(job)}
+              </div>
+              <button type="button" className="underline" onClick={() => cancel(job)}>Cancel</button>
+            </div>
+          ))}
+        </section>
+        <JobSection title="Queued" jobs={queued} empty="No job is waiting to be resumed">
+          {job => (
+            <>
+              {job.manifest.checkpoint != null && (
+                <div className="text-sm text-[var(--text-faded-color)]">
+                  Resumes after {job.manifest.checkpoint.segments} segments
+                </div>
+              )}
+              <button type="button" className="underline" onClick={() => cancel(job.manifest)}>Cancel</button>
+            </>
+          )}
+        </JobSection>
+        <JobSection title="Finished" jobs={finished} empty="No job finished yet">
+          {job => (
+            <>
+              {job.manifest.status === 'Completed'
+                ? <audio controls preload="none" src={withToken(`${FILES_URL}/audios/${job.manifest.id}.wav`)} className="w-full"/>
+                : <div className="text-red-500">{job.manifest.error}</div>}
+              <div className="space-x-4">
+                <button type="button" className="underline" onClick={() => retry(job.manifest)}>Retry</button>
+                {job.manifest.status === 'Completed' && (
+                  <Link to={`/renders/${job.manifest.id}`} className="underline">Segments</Link>
+                )}
+              </div>
+            </>
+          )}
+        </JobSection>
+      </div>
+    </div>
+  )
+}
+
+// Rounded the way people say it, like "starts in ~7 minutes".
+function startsIn (job: JobQueuePosition): string {
+  if (job.starts_in_secs === null) return ''
+  const minutes = Math.round(job.starts_in_secs / 60)
+  if (minutes < 1) return ', starts in less than a minute'
+  if (minutes < 90) return `, starts in ~${minutes} ${minutes === 1 ? 'minute' : 'minutes'}`
+  return `, starts in ~${Math.round(minutes / 60)} hours`
+}
+
+interface JobSectionProps {
+  title: string
+  jobs: Job[]
+  empty: string
+  children: (job: Job) => ReactNode
+}
+
+function JobSection ({ title, jobs, empty, children }: JobSectionProps) {
+  return (
+    <section className="space-y-2">
+      <h2 className="text-lg font-bold">{title} ({jobs.length})</h2>
+      {jobs.length === 0 && <div className="text-[var(--text-faded-color)]">{empty}</div>}
+      {jobs.map(job => (
+        <div key={job.manifest.id} className="p-3 rounded-lg bg-[var(--card-background-color)] space-y-2">
+          <div className="flex justify-between">
+            <span className="font-semibold">{job.manifest.prompt || 'Untitled'}</span>
+            <span className="text-sm text-[var(--text-faded-color)]">
+              {job.manifest.secs}s · {job.manifest.model?.name ?? 'unknown model'} · {new Date(job.manifest.created_at).toLocaleString()}
+            </span>
+          </div>
+          {children(job)}
+        </div>
+      ))}
+    </section>
+  )
+}
+
+// Extended renders report their progress as a whole, the segment being generated is
+// derived from it and from the segments recorded in the manifest.
+function SegmentProgress ({ job }: { job: Job }) {
+  const progress = job.progress ?? 0
+  const segments = job.manifest.recipe?.segment_prompts ?? []
+  const total = Math.max(segments.length, 1)
+  const current = Math.min(Math.floor(progress * total), total - 1)
+  const percentProgress = Math.round(progress * 100)
+  return (
+    <div className="space-y-1">
+      <div className="flex items-center space-x-2 text-sm text-[var(--text-faded-color)]">
+        <LoadingIcon/>
+        <span>Segment {current + 1}/{total}{segments.length > 1 ? `: ${segments[current]}` : ''}</span>
+      </div>
+      <div className="w-full bg-gray-200 rounded-full h-2">
+        <div className="bg-blue-500 h-2 rounded-full" style={{ width: `${percentProgress}%` }}/>
+      </div>
+    </div>
+  )
+}
+
+export default JobsPage;
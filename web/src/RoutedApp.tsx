@@ -1,13 +1,18 @@
 import { BrowserRouter, Navigate, Route, Routes } from "react-router-dom";
 import App from "./App.tsx";
+import JobsPage from "./JobsPage.tsx";
+import TimelinePage from "./TimelinePage.tsx";
+import { BASE_PATH } from "./backend/useBackend.ts";
 
 function RoutedApp () {
   return (
-    <BrowserRouter>
+    <BrowserRouter basename={BASE_PATH}>
       <Routes>
         <Route path={'/'} element={<Navigate to="/chats/new"/>}/>
         <Route path={'/chats'} element={<Navigate to="/chats/new"/>}/>
         <Route path={'/chats/:chatId'} element={<App/>}/>
+        <Route path={'/queue'} element={<JobsPage/>}/>
+        <Route path={'/renders/:renderId'} element={<TimelinePage/>}/>
       </Routes>
     </BrowserRouter>
   )
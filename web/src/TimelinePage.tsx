@@ -0,0 +1,106 @@
+import { useState } from "react";
+import { Link, useParams } from "react-router-dom";
+
+import { FILES_URL, withToken } from "./backend/useBackend.ts";
+import { Segment, useRenderTimeline } from "./backend/useRenderTimeline.ts";
+import { StatusIndicator } from "./StatusIndicator.tsx";
+import ThemeToggle from "./components/ThemeToggle.tsx";
+import { useThemeToggle } from "./components/ThemeToggleHook.tsx";
+import { LoadingIcon } from "./Icons/LoadingIcon.tsx";
+
+function TimelinePage () {
+  const { renderId } = useParams()
+  const { manifest, segments, error, pending, edited, regenerate } = useRenderTimeline(renderId!)
+  const { theme, toggleTheme } = useThemeToggle()
+
+  return (
+    <div
+      className={`min-h-screen w-full bg-[var(--background-color)] text-[var(--text-color)] ${theme === 'dark' ? 'dark' : ''}`}>
+      <div className="flex items-center justify-between px-4 py-2">
+        <Link to="/queue" className="w-1/3 underline text-[var(--text-faded-color)]">Back to jobs</Link>
+        <StatusIndicator className="m-2 w-fit"/>
+        <div className="w-1/3 flex flex-row justify-end">
+          <ThemeToggle className="mr-2" onToggle={toggleTheme} theme={theme}/>
+        </div>
+      </div>
+      <div className="max-w-3xl mx-auto p-2 space-y-4">
+        {error !== undefined && <div className="text-red-500">{error}</div>}
+        {manifest !== undefined && (
+          <>
+            <div className="flex justify-between">
+              <span className="font-semibold">{manifest.prompt || 'Untitled'}</span>
+              <span className="text-sm text-[var(--text-faded-color)]">
+                {manifest.secs}s · {segments.length} segments
+              </span>
+            </div>
+            <audio controls preload="none" src={withToken(`${FILES_URL}/audios/${manifest.id}.wav`)} className="w-full"/>
+            {/* The segments overlap where they are crossfaded, so each gets the same share. */}
+            <div className="flex w-full h-3 rounded-full overflow-hidden">
+              {segments.map(segment => (
+                <div key={segment.index} className="flex-1 border-r last:border-r-0 border-[var(--background-color)] bg-blue-500"/>
+              ))}
+            </div>
+            {pending !== undefined && (
+              <div className="flex items-center space-x-2 text-sm text-[var(--text-faded-color)]">
+                <LoadingIcon/>
+                <span>Re-rendering, {Math.round(pending.progress * 100)}%</span>
+              </div>
+            )}
+            {edited !== undefined && (
+              <Link to={`/renders/${edited}`} className="underline">Open the re-rendered version</Link>
+            )}
+            {segments.map(segment => (
+              <SegmentEditor
+                key={segment.index}
+                segment={segment}
+                disabled={pending !== undefined || manifest.status !== 'Completed'}
+                onRegenerate={regenerate}/>
+            ))}
+          </>
+        )}
+      </div>
+    </div>
+  )
+}
+
+interface SegmentEditorProps {
+  segment: Segment
+  disabled: boolean
+  onRegenerate: (segment: number, prompt: string, seed: number | null) => void
+}
+
+function SegmentEditor ({ segment, disabled, onRegenerate }: SegmentEditorProps) {
+  const [prompt, setPrompt] = useState(segment.prompt)
+  const [seed, setSeed] = useState(segment.seed?.toString() ?? '')
+  const parsedSeed = seed.trim() === '' ? null : Number(seed)
+  const validSeed = parsedSeed === null || (Number.isSafeInteger(parsedSeed) && parsedSeed >= 0)
+
+  return (
+    <div className="p-3 rounded-lg bg-[var(--card-background-color)] space-y-2">
+      <div className="font-semibold">Segment {segment.index + 1}</div>
+      {segment.relpath !== undefined && (
+        <audio controls preload="none" src={withToken(`${FILES_URL}/${segment.relpath}`)} className="w-full"/>
+      )}
+      <input
+        className="w-full p-1 rounded bg-[var(--background-color)]"
+        value={prompt}
+        onChange={e => setPrompt(e.target.value)}/>
+      <div className="flex items-center space-x-2">
+        <input
+          className="w-40 p-1 rounded bg-[var(--background-color)]"
+          placeholder="Random seed"
+          value={seed}
+          onChange={e => setSeed(e.target.value)}/>
+        <button
+          type="button"
+          className="underline disabled:opacity-50"
+          disabled={disabled || !validSeed || prompt.trim() === ''}
+          onClick={() => onRegenerate(segment.index, prompt, parsedSeed)}>
+          Re-render segment
+        </button>
+      </div>
+    </div>
+  )
+}
+
+export default TimelinePage;
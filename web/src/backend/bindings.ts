@@ -14,21 +14,103 @@ export type AudioGenerationStart = { id: string; chat_id: string; prompt: string
 
 export type AudioGenerationError = { id: string; chat_id: string; error: string }
 
-export type GenerateAudioRequest = { id: string; chat_id: string; prompt: string; secs: number }
+export type AudioGenerationWarning = { id: string; chat_id: string; warning: string }
 
-export type GenerationMessage = { Start: AudioGenerationStart } | { Progress: AudioGenerationProgress } | { Error: AudioGenerationError } | { Result: AudioGenerationResult }
+export type AudioGenerationAudition = { id: string; chat_id: string; segment: number; relpath: string }
+
+export type GenerateAudioRequest = { id: string; chat_id: string; prompt: string;
+/**
+ * 0 reads the duration from the prompt, like "a three and a half minute track". The
+ * start of the render echoes the one used.
+ */
+secs: number;
+/**
+ * Holds back each segment of extended renders until an `AuditionVerdict` on it
+ * arrives.
+ */
+audition?: boolean }
+
+export type GenerationMessage = { Start: AudioGenerationStart } | { Progress: AudioGenerationProgress } | { Error: AudioGenerationError } | { Result: AudioGenerationResult } | { Warning: AudioGenerationWarning } | { Audition: AudioGenerationAudition }
 
 export type ChatEntry = { User: UserChatEntry } | { Ai: AiChatEntry }
 
 export type AudioGenerationProgress = { id: string; chat_id: string; progress: number }
 
-export type Info = { model: string; device: string }
+export type Info = { model: string; selection_reason: string; device: string }
 
-export type OutboundMsg = { Generation: GenerationMessage } | { Info: Info } | { Chat: [Chat, ChatEntry[]] } | { Chats: Chat[] } | { Error: string }
+export type OutboundMsg = { Generation: GenerationMessage } | { Info: Info } | { Chat: [Chat, ChatEntry[]] } | { Chats: Chat[] } | { Models: ModelEntry[] } | { Error: string }
 
-export type InboundMsg = { GenerateAudioNewChat: GenerateAudioRequest } | { GenerateAudio: GenerateAudioRequest } | { AbortGeneration: AbortGenerationRequest } | { GetChat: ChatRequest } | { SetChatMetadata: SetChatMetadataRequest } | { DelChat: ChatRequest }
+export type InboundMsg = { GenerateAudioNewChat: GenerateAudioRequest } | { GenerateAudio: GenerateAudioRequest } | { AbortGeneration: AbortGenerationRequest } | { RegenerateSegment: RegenerateSegmentRequest } | { Inpaint: InpaintRequest } | { AuditionVerdict: AuditionVerdictRequest } | { GetChat: ChatRequest } | { SetChatMetadata: SetChatMetadataRequest } | { DelChat: ChatRequest } | "ListModels" | { UseModel: UseModelRequest }
 
 export type ChatRequest = { chat_id: string }
 
 export type AbortGenerationRequest = { id: string; chat_id: string }
 
+/**
+ * Generates a finished render again as a new one, with one of its segments generated
+ * from another prompt or seed and the others reused as they were.
+ */
+export type RegenerateSegmentRequest = { id: string; chat_id: string; render: string; segment: number; prompt: string; seed: number | null }
+
+/**
+ * Generates a finished render again as a new one, with the audio between `start_secs`
+ * and `end_secs` generated anew and crossfaded back in.
+ */
+export type InpaintRequest = { id: string; chat_id: string; render: string; start_secs: number; end_secs: number; prompt: string | null }
+
+/**
+ * Keeps the segment of an auditioned render, or generates it again.
+ */
+export type AuditionVerdictRequest = { id: string; chat_id: string; segment: number; accept: boolean }
+
+export type UseModelRequest = { name: string }
+
+export type ModelEntry = { name: string; display_name: string; installed: boolean; size_bytes: number; capabilities: ModelCapabilities }
+
+export type ModelCapabilities = { max_secs: number; stereo: boolean; melody: boolean }
+
+/**
+ * Metadata about a render, stored next to its audio file, that allows knowing how
+ * the audio was produced.
+ */
+export type RenderManifest = { id: string; chat_id: string; prompt: string; secs: number; model: ModelVersion | null; created_at: number; status: RenderStatus; error: string | null; retries?: RenderRetry[]; gains?: RenderGain[]; checkpoint?: RenderCheckpoint | null; recipe?: RenderRecipe | null; segment_audio?: string[] }
+
+/**
+ * Everything besides the model and the prompt that shaped the audio of a render.
+ */
+export type RenderRecipe = { seed: number | null; tokenizer: string | null; segment_prompts: string[]; segment_seeds?: (number | null)[]; post: PostChain }
+
+/**
+ * Processing applied to the segments of extended renders, as set in the command line.
+ */
+export type PostChain = { intro_outro: string | null; noise_gate_db: number | null; noise_gate_release_ms: number; denoise: number | null; normalize_segments_db: number | null; headroom_db: number | null; end_on_downbeat?: number | null; segment_secs?: number | null; overlap_secs?: number | null; crossfade_secs?: number | null; continue_segments?: boolean }
+
+export type RenderCheckpoint = { segments: number; samples: number; relpath: string }
+
+export type RenderRetry = { segment: number; attempt: number; error: string }
+
+export type RenderGain = { segment: number; gain_db: number }
+
+export type RenderStatus = "Running" | "Completed" | "Failed" | "Pending"
+
+/**
+ * Identifies the exact model files used for generating audio.
+ */
+export type ModelVersion = { name: string; revision: string; hash: string }
+
+
+/**
+ * Where a job is in the queue, and when it is expected to start.
+ */
+export type JobQueuePosition = { id: string; chat_id: string; prompt: string; secs: number; position: number; jobs_ahead: number; starts_in_secs: number | null; estimated_start: number | null }
+
+export type MaintenanceStatus = { enabled: boolean; message: string | null }
+
+/**
+ * A job of any user, as seen by admins.
+ */
+export type AdminJob = { user: string | null; queue: JobQueuePosition }
+
+export type PrioritizeRequest = { position: number }
+
+export type DrainReport = { dropped: number }
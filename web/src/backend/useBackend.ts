@@ -2,9 +2,21 @@ import useWebSocket from "react-use-websocket";
 import { useCallback, useEffect, useState } from "react";
 import { InboundMsg, Info, OutboundMsg } from "./bindings.ts";
 
-const BACKEND_URL: string = import.meta.env.VITE_BACKEND_URL ?? window.location.origin
-export const WS_URL = `${BACKEND_URL.replace('http', 'ws')}/ws`
+export const BASE_PATH = window.MUSICGPT_BASE_PATH ?? ''
+const BACKEND_URL: string = import.meta.env.VITE_BACKEND_URL ?? `${window.location.origin}${BASE_PATH}`
+// Servers with API tokens are opened with ?token=..., which is remembered for later visits.
+const URL_TOKEN = new URLSearchParams(window.location.search).get('token')
+if (URL_TOKEN !== null) localStorage.setItem('apiToken', URL_TOKEN)
+const API_TOKEN = localStorage.getItem('apiToken')
+export const WS_URL = `${BACKEND_URL.replace('http', 'ws')}/ws${API_TOKEN !== null ? `?token=${encodeURIComponent(API_TOKEN)}` : ''}`
 export const FILES_URL = `${BACKEND_URL}/files`
+export const JOBS_URL = `${BACKEND_URL}/jobs`
+
+// The files and jobs routes need the API token as well, which audio elements can only send in the URL.
+export function withToken (url: string): string {
+  if (API_TOKEN === null) return url
+  return `${url}${url.includes('?') ? '&' : '?'}token=${encodeURIComponent(API_TOKEN)}`
+}
 
 export function useBackend () {
   const [info, setInfo] = useState<Info>()
@@ -1,7 +1,7 @@
 import { useEffect, useRef, useState } from "react";
 import { v4 as uuid } from "uuid";
 
-import { FILES_URL, useBackend } from "./useBackend.ts";
+import { FILES_URL, useBackend, withToken } from "./useBackend.ts";
 import {
   AudioGenerationError,
   AudioGenerationProgress,
@@ -200,5 +200,5 @@ function clamp (min: number, num: number, max: number): number {
 
 function relpathToUrl (relpath: string): string {
   if (!relpath.startsWith('/')) relpath = `/${relpath}`;
-  return FILES_URL + relpath
+  return withToken(FILES_URL + relpath)
 }
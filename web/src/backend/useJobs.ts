@@ -0,0 +1,77 @@
+import { useCallback, useEffect, useState } from "react";
+import { v4 as uuid } from "uuid";
+
+import { JOBS_URL, useBackend, withToken } from "./useBackend.ts";
+import { JobQueuePosition, RenderManifest } from "./bindings.ts";
+
+export interface Job {
+  manifest: RenderManifest
+  // Only known for running jobs, from the progress messages received since the page loaded.
+  progress?: number
+}
+
+// Estimates of when queued jobs start change as the running one progresses.
+const QUEUE_REFRESH_MS = 10_000
+
+export function useJobs () {
+  const [manifests, setManifests] = useState<RenderManifest[]>([])
+  const [queue, setQueue] = useState<JobQueuePosition[]>([])
+  const [progress, setProgress] = useState<Record<string, number>>({})
+  const [error, setError] = useState<string>()
+
+  const { send, last } = useBackend();
+
+  const refresh = useCallback(async () => {
+    try {
+      const [response, queueResponse] = await Promise.all([fetch(withToken(JOBS_URL)), fetch(withToken(`${JOBS_URL}/queue`))])
+      if (!response.ok) throw new Error(await response.text())
+      if (!queueResponse.ok) throw new Error(await queueResponse.text())
+      setManifests(await response.json())
+      setQueue(await queueResponse.json())
+      setError(undefined)
+    } catch (err) {
+      setError(`Could not load the jobs: ${err}`)
+    }
+  }, [])
+
+  useEffect(() => {
+    void refresh()
+    const interval = setInterval(() => void refresh(), QUEUE_REFRESH_MS)
+    return () => clearInterval(interval)
+  }, [refresh]);
+
+  useEffect(() => {
+    if (last == null || !('Generation' in last)) {
+      // do nothing
+    } else if ('Progress' in last.Generation) {
+      const msg = last.Generation.Progress
+      setProgress(prev => ({ ...prev, [msg.id]: msg.progress }))
+    } else if ('Start' in last.Generation || 'Result' in last.Generation || 'Error' in last.Generation) {
+      // The manifest of the job was just created or finished, and the queue moved.
+      void refresh()
+    }
+  }, [last, refresh])
+
+  const cancel = useCallback((job: { id: string, chat_id: string }) => {
+    send({ AbortGeneration: { id: job.id, chat_id: job.chat_id } })
+  }, [send])
+
+  // Queues the same prompt again, as a new message of the chat the job belongs to.
+  const retry = useCallback((job: RenderManifest) => {
+    send({ GenerateAudio: { id: uuid(), chat_id: job.chat_id, prompt: job.prompt, secs: job.secs } })
+  }, [send])
+
+  const jobs: Job[] = manifests.map(manifest => ({ manifest, progress: progress[manifest.id] }))
+  // Jobs waiting for the running one only get a manifest once they start.
+  const waiting = queue.filter(job => job.position > 0)
+  return {
+    waiting,
+    queued: jobs.filter(job => job.manifest.status === 'Pending' && !waiting.some(w => w.id === job.manifest.id)),
+    running: jobs.filter(job => job.manifest.status === 'Running'),
+    finished: jobs.filter(job => job.manifest.status === 'Completed' || job.manifest.status === 'Failed'),
+    error,
+    refresh,
+    cancel,
+    retry
+  }
+}
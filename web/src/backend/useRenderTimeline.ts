@@ -0,0 +1,75 @@
+import { useCallback, useEffect, useState } from "react";
+import { v4 as uuid } from "uuid";
+
+import { JOBS_URL, useBackend, withToken } from "./useBackend.ts";
+import { RenderManifest } from "./bindings.ts";
+
+export interface Segment {
+  index: number
+  prompt: string
+  seed: number | null
+  // The audio the segment was generated with, absent for renders of a single segment.
+  relpath?: string
+}
+
+export function useRenderTimeline (id: string) {
+  const [manifest, setManifest] = useState<RenderManifest>()
+  const [error, setError] = useState<string>()
+  // The render being generated with an edited segment, and how far it got.
+  const [pending, setPending] = useState<{ id: string, progress: number }>()
+  const [edited, setEdited] = useState<string>()
+
+  const { send, last } = useBackend();
+
+  useEffect(() => {
+    (async () => {
+      try {
+        const response = await fetch(withToken(`${JOBS_URL}/${id}`))
+        if (!response.ok) throw new Error(await response.text())
+        setManifest(await response.json())
+        setError(undefined)
+      } catch (err) {
+        setError(`Could not load the render: ${err}`)
+      }
+    })()
+  }, [id]);
+
+  useEffect(() => {
+    if (last == null || pending === undefined) {
+      // do nothing
+    } else if ('Error' in last) {
+      setError(last.Error)
+      setPending(undefined)
+    } else if (!('Generation' in last)) {
+      // do nothing
+    } else if ('Progress' in last.Generation && last.Generation.Progress.id === pending.id) {
+      setPending({ id: pending.id, progress: last.Generation.Progress.progress })
+    } else if ('Result' in last.Generation && last.Generation.Result.id === pending.id) {
+      setEdited(pending.id)
+      setPending(undefined)
+    } else if ('Error' in last.Generation && last.Generation.Error.id === pending.id) {
+      setError(last.Generation.Error.error)
+      setPending(undefined)
+    }
+  }, [last, pending])
+
+  // Renders the same prompt again as a new message of the chat, with only this segment
+  // generated again.
+  const regenerate = useCallback((segment: number, prompt: string, seed: number | null) => {
+    if (manifest === undefined) return
+    const id = uuid()
+    setError(undefined)
+    setEdited(undefined)
+    setPending({ id, progress: 0 })
+    send({ RegenerateSegment: { id, chat_id: manifest.chat_id, render: manifest.id, segment, prompt, seed } })
+  }, [manifest, send])
+
+  const prompts = manifest?.recipe?.segment_prompts ?? []
+  const segments: Segment[] = prompts.map((prompt, index) => ({
+    index,
+    prompt,
+    seed: manifest?.recipe?.segment_seeds?.[index] ?? manifest?.recipe?.seed ?? null,
+    relpath: manifest?.segment_audio?.[index]
+  }))
+  return { manifest, segments, error, pending, edited, regenerate }
+}
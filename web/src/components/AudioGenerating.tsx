@@ -4,9 +4,13 @@ import { LoadingIcon } from "../Icons/LoadingIcon.tsx";
 interface GeneratingAudioProps {
   className?: string;
   progress: number;
+  // Ogg/Opus stream of the audio generated so far.
+  streamUrl?: string;
+  // WAV file with the audio generated so far.
+  partialUrl?: string;
 }
 
-const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progress }) => {
+const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progress, streamUrl, partialUrl }) => {
   const percentProgress = Math.round(progress * 100)
   return (
     <div className={`space-y-2 ${className}`}>
@@ -21,6 +25,12 @@ const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progr
         />
       </div>
       <div className="text-right text-[var(--text-faded-color)] text-sm">{percentProgress}%</div>
+      {streamUrl !== undefined && <audio controls preload="none" src={streamUrl} className="w-full"/>}
+      {partialUrl !== undefined && (
+        <a href={partialUrl} download className="text-sm text-[var(--text-faded-color)] underline">
+          Download what's generated so far
+        </a>
+      )}
     </div>
   );
 };
@@ -1 +1,6 @@
 /// <reference types="vite/client" />
+
+interface Window {
+  // Set by the server, the path the app is served at when it's behind a reverse proxy.
+  MUSICGPT_BASE_PATH?: string
+}